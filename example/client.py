@@ -36,7 +36,12 @@ try:
 except FileNotFoundError:
     PROGRAM_ID_STR = "YOUR_PROGRAM_ID_HERE"  # Fallback if file doesn't exist
     program_id_loaded = False
+# Quote mint the program was initialized with (USDC shown)
+QUOTE_MINT_STR = "EPjFWdd5AufqSSqeM2qN1xzybapC8G4wEGGkZwyTDt1v"
 PDA_SEED = b"perps"
+CONFIG_SEED = b"config"
+MARKET_SEED = b"market"
+POSITION_SEED = b"position"
 PRECISION = 1_000_000_000  # 1e9 precision for prices
 
 # Instruction tags
@@ -44,6 +49,12 @@ INSTRUCTION_OPEN_POSITION = 0
 INSTRUCTION_UPDATE_FUNDING = 1
 INSTRUCTION_LIQUIDATE = 2
 INSTRUCTION_CLOSE_POSITION = 3
+INSTRUCTION_VERSION = 2  # payload layout version, written after the tag
+
+# Every program account starts with an 8-byte type tag
+DISCRIMINATOR_LEN = 8
+POSITION_DISCRIMINATOR = b"position"
+MARKET_STATE_DISCRIMINATOR = b"market\0\0"
 
 # Borsh schemas for data serialization/deserialization
 @dataclass
@@ -53,53 +64,69 @@ class Position:
     collateral: int   # u64
     last_funding_index: int  # i64
     entry_price: int  # u64
+    margin_mode: int  # u8
+    sub_account_id: int  # u16
 
     @classmethod
     def from_bytes(cls, data: bytes) -> 'Position':
-        """Deserialize Position from account data"""
-        if len(data) < 64:
+        """Deserialize Position from account data (tag, then the Pod body)"""
+        if len(data) < DISCRIMINATOR_LEN + 68:
             raise ValueError("Invalid position data length")
-        
-        owner = Pubkey(data[0:32])
-        base_amount = struct.unpack('<q', data[32:40])[0]  # i64
-        collateral = struct.unpack('<Q', data[40:48])[0]   # u64
-        last_funding_index = struct.unpack('<q', data[48:56])[0]  # i64
-        entry_price = struct.unpack('<Q', data[56:64])[0]  # u64
-        
-        return cls(owner, base_amount, collateral, last_funding_index, entry_price)
+        if data[:DISCRIMINATOR_LEN] != POSITION_DISCRIMINATOR:
+            raise ValueError("Not a position account")
+        body = data[DISCRIMINATOR_LEN:]
+        
+        owner = Pubkey(body[0:32])
+        base_amount = struct.unpack('<q', body[32:40])[0]  # i64
+        collateral = struct.unpack('<Q', body[40:48])[0]   # u64
+        last_funding_index = struct.unpack('<q', body[48:56])[0]  # i64
+        entry_price = struct.unpack('<Q', body[56:64])[0]  # u64
+        margin_mode = body[65]                             # u8 (64 is the layout version)
+        sub_account_id = struct.unpack('<H', body[66:68])[0]  # u16
+        
+        return cls(owner, base_amount, collateral, last_funding_index, entry_price,
+                   margin_mode, sub_account_id)
 
 @dataclass
 class MarketState:
     funding_index: int          # i64
     funding_rate_per_slot: int  # i64
     open_interest: int          # u64
-    bump: int                   # u8
     last_funding_slot: int      # u64
-    mark_price: int            # u64
+    mark_price: int             # u64
+    paused: bool                # u8
+    market_id: int              # u16
 
     @classmethod
     def from_bytes(cls, data: bytes) -> 'MarketState':
-        """Deserialize MarketState from account data"""
-        if len(data) < 41:
+        """Deserialize MarketState from account data (tag, then the Pod body)"""
+        if len(data) < DISCRIMINATOR_LEN + 86:
             raise ValueError("Invalid market state data length")
-        
-        funding_index = struct.unpack('<q', data[0:8])[0]        # i64
-        funding_rate_per_slot = struct.unpack('<q', data[8:16])[0]  # i64
-        open_interest = struct.unpack('<Q', data[16:24])[0]      # u64
-        bump = struct.unpack('<B', data[24:25])[0]               # u8
-        last_funding_slot = struct.unpack('<Q', data[25:33])[0]  # u64
-        mark_price = struct.unpack('<Q', data[33:41])[0]         # u64
-        
-        return cls(funding_index, funding_rate_per_slot, open_interest, 
-                  bump, last_funding_slot, mark_price)
+        if data[:DISCRIMINATOR_LEN] != MARKET_STATE_DISCRIMINATOR:
+            raise ValueError("Not a market state account")
+        body = data[DISCRIMINATOR_LEN:]
+        
+        funding_index = struct.unpack('<q', body[0:8])[0]        # i64
+        funding_rate_per_slot = struct.unpack('<q', body[8:16])[0]  # i64
+        open_interest = struct.unpack('<Q', body[16:24])[0]      # u64
+        last_funding_slot = struct.unpack('<Q', body[24:32])[0]  # u64
+        mark_price = struct.unpack('<Q', body[32:40])[0]         # u64
+        paused = body[81] != 0                                   # u8
+        market_id = struct.unpack('<H', body[84:86])[0]          # u16
+        
+        return cls(funding_index, funding_rate_per_slot, open_interest,
+                   last_funding_slot, mark_price, paused, market_id)
 
 class PerpetualsClient:
     """Python client for interacting with the Simple Perpetuals program"""
     
-    def __init__(self, rpc_url: str, payer: Keypair, program_id: str):
+    def __init__(self, rpc_url: str, payer: Keypair, program_id: str,
+                 quote_mint: Pubkey, market_id: int = 0):
         self.client = AsyncClient(rpc_url, commitment=Confirmed)
         self.payer = payer
         self.program_id = Pubkey.from_string(program_id)
+        self.quote_mint = quote_mint
+        self.market_id = market_id
         
     async def close(self):
         """Close the RPC client"""
@@ -109,33 +136,52 @@ class PerpetualsClient:
         """Get PDA for the program authority (vault)"""
         return Pubkey.find_program_address([PDA_SEED], self.program_id)
     
-    def get_position_address(self, user: Pubkey) -> Tuple[Pubkey, int]:
-        """Get PDA for a user's position account"""
-        return Pubkey.find_program_address([b"position", bytes(user)], self.program_id)
+    def get_config_address(self) -> Tuple[Pubkey, int]:
+        """Get PDA for the config account"""
+        return Pubkey.find_program_address([CONFIG_SEED], self.program_id)
     
     def get_market_state_address(self) -> Tuple[Pubkey, int]:
-        """Get PDA for the market state account"""
-        return Pubkey.find_program_address([b"market"], self.program_id)
+        """Get PDA for this client's market state account"""
+        return Pubkey.find_program_address(
+            [MARKET_SEED, struct.pack('<H', self.market_id)], self.program_id
+        )
+    
+    def get_position_address(self, user: Pubkey, sub_account_id: int = 0) -> Tuple[Pubkey, int]:
+        """Get PDA for a user's position account in this client's market"""
+        market_state_pda, _ = self.get_market_state_address()
+        return Pubkey.find_program_address(
+            [POSITION_SEED, bytes(market_state_pda), bytes(user), struct.pack('<H', sub_account_id)],
+            self.program_id,
+        )
     
     async def open_position(
         self,
         base_delta: int,        # Position size change (signed)
         collateral_delta: int,  # Additional collateral
-        entry_price: int,       # Entry price
-        user_token_account: Pubkey
+        price_limit: int,       # Worst acceptable fill price (0 = no limit)
+        user_token_account: Pubkey,
+        sub_account_id: int = 0
     ) -> str:
-        """Open or modify a position"""
+        """Open or modify a position
+        
+        Markets with an index oracle also expect the oracle (and its backups)
+        as trailing accounts.
+        """
         
         vault_pda, _ = self.get_program_authority()
-        position_pda, _ = self.get_position_address(self.payer.pubkey())
+        position_pda, _ = self.get_position_address(self.payer.pubkey(), sub_account_id)
         market_state_pda, _ = self.get_market_state_address()
+        config_pda, _ = self.get_config_address()
         
-        # Create instruction data
-        instruction_data = bytearray(25)
+        # Create instruction data (PerpsInstruction::OpenPosition: tag, version, Borsh fields)
+        instruction_data = bytearray(29)
         instruction_data[0] = INSTRUCTION_OPEN_POSITION
-        instruction_data[1:9] = struct.pack('<q', base_delta)      # i64
-        instruction_data[9:17] = struct.pack('<Q', collateral_delta)  # u64
-        instruction_data[17:25] = struct.pack('<Q', entry_price)   # u64
+        instruction_data[1] = INSTRUCTION_VERSION
+        instruction_data[2:10] = struct.pack('<q', base_delta)     # i64
+        instruction_data[10:18] = struct.pack('<Q', collateral_delta)  # u64
+        instruction_data[18:26] = struct.pack('<Q', price_limit)   # u64
+        instruction_data[26] = 0                                   # flags: u8
+        instruction_data[27:29] = struct.pack('<H', sub_account_id)  # sub_account_id: u16
         
         accounts = [
             AccountMeta(pubkey=self.payer.pubkey(), is_signer=True, is_writable=False),
@@ -147,6 +193,8 @@ class PerpetualsClient:
             AccountMeta(pubkey=SYSVAR_RENT_PUBKEY, is_signer=False, is_writable=False),
             AccountMeta(pubkey=SYSVAR_CLOCK_PUBKEY, is_signer=False, is_writable=False),
             AccountMeta(pubkey=SYS_PROGRAM_ID, is_signer=False, is_writable=False),
+            AccountMeta(pubkey=config_pda, is_signer=False, is_writable=False),
+            AccountMeta(pubkey=self.quote_mint, is_signer=False, is_writable=False),
         ]
         
         instruction = Instruction(
@@ -181,12 +229,14 @@ class PerpetualsClient:
         """Update funding rates (should be called periodically)"""
         
         market_state_pda, _ = self.get_market_state_address()
+        config_pda, _ = self.get_config_address()
         
-        instruction_data = bytes([INSTRUCTION_UPDATE_FUNDING])
+        instruction_data = bytes([INSTRUCTION_UPDATE_FUNDING, INSTRUCTION_VERSION])
         
         accounts = [
             AccountMeta(pubkey=market_state_pda, is_signer=False, is_writable=True),
             AccountMeta(pubkey=SYSVAR_CLOCK_PUBKEY, is_signer=False, is_writable=False),
+            AccountMeta(pubkey=config_pda, is_signer=False, is_writable=False),
         ]
         
         instruction = Instruction(
@@ -208,15 +258,17 @@ class PerpetualsClient:
     async def liquidate(
         self,
         position_owner: Pubkey,
-        liquidator_token_account: Pubkey
+        liquidator_token_account: Pubkey,
+        sub_account_id: int = 0
     ) -> str:
         """Liquidate an undercollateralized position"""
         
         vault_pda, _ = self.get_program_authority()
-        position_pda, _ = self.get_position_address(position_owner)
+        position_pda, _ = self.get_position_address(position_owner, sub_account_id)
         market_state_pda, _ = self.get_market_state_address()
+        config_pda, _ = self.get_config_address()
         
-        instruction_data = bytes([INSTRUCTION_LIQUIDATE])
+        instruction_data = bytes([INSTRUCTION_LIQUIDATE, INSTRUCTION_VERSION])
         
         accounts = [
             AccountMeta(pubkey=self.payer.pubkey(), is_signer=True, is_writable=False),
@@ -226,6 +278,8 @@ class PerpetualsClient:
             AccountMeta(pubkey=position_pda, is_signer=False, is_writable=True),
             AccountMeta(pubkey=market_state_pda, is_signer=False, is_writable=True),
             AccountMeta(pubkey=SYSVAR_CLOCK_PUBKEY, is_signer=False, is_writable=False),
+            AccountMeta(pubkey=config_pda, is_signer=False, is_writable=False),
+            AccountMeta(pubkey=self.quote_mint, is_signer=False, is_writable=False),
         ]
         
         instruction = Instruction(
@@ -244,14 +298,15 @@ class PerpetualsClient:
         
         return response['result']
     
-    async def close_position(self, user_token_account: Pubkey) -> str:
+    async def close_position(self, user_token_account: Pubkey, sub_account_id: int = 0) -> str:
         """Close a position voluntarily"""
         
         vault_pda, _ = self.get_program_authority()
-        position_pda, _ = self.get_position_address(self.payer.pubkey())
+        position_pda, _ = self.get_position_address(self.payer.pubkey(), sub_account_id)
         market_state_pda, _ = self.get_market_state_address()
+        config_pda, _ = self.get_config_address()
         
-        instruction_data = bytes([INSTRUCTION_CLOSE_POSITION])
+        instruction_data = bytes([INSTRUCTION_CLOSE_POSITION, INSTRUCTION_VERSION])
         
         accounts = [
             AccountMeta(pubkey=self.payer.pubkey(), is_signer=True, is_writable=False),
@@ -260,6 +315,8 @@ class PerpetualsClient:
             AccountMeta(pubkey=vault_pda, is_signer=False, is_writable=True),
             AccountMeta(pubkey=position_pda, is_signer=False, is_writable=True),
             AccountMeta(pubkey=market_state_pda, is_signer=False, is_writable=True),
+            AccountMeta(pubkey=config_pda, is_signer=False, is_writable=False),
+            AccountMeta(pubkey=self.quote_mint, is_signer=False, is_writable=False),
         ]
         
         instruction = Instruction(
@@ -278,10 +335,10 @@ class PerpetualsClient:
         
         return response['result']
     
-    async def get_position(self, user: Pubkey) -> Optional[Position]:
+    async def get_position(self, user: Pubkey, sub_account_id: int = 0) -> Optional[Position]:
         """Get position data for a user"""
         
-        position_pda, _ = self.get_position_address(user)
+        position_pda, _ = self.get_position_address(user, sub_account_id)
         
         try:
             response = await self.client.get_account_info(position_pda, commitment=Confirmed)
@@ -364,7 +421,7 @@ async def example_usage():
     payer = Keypair()
     
     # Initialize client
-    client = PerpetualsClient(rpc_url, payer, PROGRAM_ID_STR)
+    client = PerpetualsClient(rpc_url, payer, PROGRAM_ID_STR, Pubkey.from_string(QUOTE_MINT_STR))
     
     try:
         print(f"💰 Wallet: {payer.pubkey()}")
@@ -479,7 +536,7 @@ class TestPerpetualsClient:
     async def client(self):
         """Create a test client"""
         payer = Keypair()
-        client = PerpetualsClient(TEST_RPC_URL, payer, TEST_PROGRAM_ID)
+        client = PerpetualsClient(TEST_RPC_URL, payer, TEST_PROGRAM_ID, Keypair().pubkey())
         yield client
         await client.close()
     
@@ -509,6 +566,18 @@ class TestPerpetualsClient:
         assert isinstance(vault1, Pubkey)
         assert 0 <= bump1 <= 255
     
+    @pytest.mark.asyncio
+    async def test_position_pda_is_per_market_and_sub_account(self, client):
+        """Test that position PDAs depend on the market and sub-account"""
+        owner = Keypair().pubkey()
+        default, _ = client.get_position_address(owner)
+        
+        assert client.get_position_address(owner, 0)[0] == default
+        assert client.get_position_address(owner, 1)[0] != default
+        
+        client.market_id = 1
+        assert client.get_position_address(owner)[0] != default
+    
     def test_position_deserialization(self):
         """Test Position deserialization"""
         # Create mock position data
@@ -517,14 +586,19 @@ class TestPerpetualsClient:
         collateral = 150_000_000_000  # 150 units
         last_funding_index = 12345
         entry_price = 100_500_000_000  # $100.50
-        
-        # Pack data manually
-        data = bytearray(64)
-        data[0:32] = bytes(owner)
-        data[32:40] = struct.pack('<q', base_amount)
-        data[40:48] = struct.pack('<Q', collateral)
-        data[48:56] = struct.pack('<q', last_funding_index)
-        data[56:64] = struct.pack('<Q', entry_price)
+        sub_account_id = 3
+        
+        # Pack data manually: tag, then the Pod body
+        data = bytearray(DISCRIMINATOR_LEN + 69)
+        data[0:8] = POSITION_DISCRIMINATOR
+        data[8:40] = bytes(owner)
+        data[40:48] = struct.pack('<q', base_amount)
+        data[48:56] = struct.pack('<Q', collateral)
+        data[56:64] = struct.pack('<q', last_funding_index)
+        data[64:72] = struct.pack('<Q', entry_price)
+        data[72] = 15  # version
+        data[73] = 1   # margin_mode
+        data[74:76] = struct.pack('<H', sub_account_id)
         
         # Deserialize
         position = Position.from_bytes(bytes(data))
@@ -534,6 +608,13 @@ class TestPerpetualsClient:
         assert position.collateral == collateral
         assert position.last_funding_index == last_funding_index
         assert position.entry_price == entry_price
+        assert position.margin_mode == 1
+        assert position.sub_account_id == sub_account_id
+        
+        # Other account types are rejected
+        data[0:8] = MARKET_STATE_DISCRIMINATOR
+        with pytest.raises(ValueError):
+            Position.from_bytes(bytes(data))
     
     def test_market_state_deserialization(self):
         """Test MarketState deserialization"""
@@ -541,18 +622,20 @@ class TestPerpetualsClient:
         funding_index = -50000
         funding_rate_per_slot = 10000
         open_interest = 1000_000_000_000
-        bump = 254
         last_funding_slot = 12345678
         mark_price = 101_000_000_000  # $101
-        
-        # Pack data manually
-        data = bytearray(41)
-        data[0:8] = struct.pack('<q', funding_index)
-        data[8:16] = struct.pack('<q', funding_rate_per_slot)
-        data[16:24] = struct.pack('<Q', open_interest)
-        data[24:25] = struct.pack('<B', bump)
-        data[25:33] = struct.pack('<Q', last_funding_slot)
-        data[33:41] = struct.pack('<Q', mark_price)
+        market_id = 2
+        
+        # Pack data manually: tag, then the Pod body
+        data = bytearray(DISCRIMINATOR_LEN + 86)
+        data[0:8] = MARKET_STATE_DISCRIMINATOR
+        data[8:16] = struct.pack('<q', funding_index)
+        data[16:24] = struct.pack('<q', funding_rate_per_slot)
+        data[24:32] = struct.pack('<Q', open_interest)
+        data[32:40] = struct.pack('<Q', last_funding_slot)
+        data[40:48] = struct.pack('<Q', mark_price)
+        data[89] = 1  # paused
+        data[92:94] = struct.pack('<H', market_id)
         
         # Deserialize
         market_state = MarketState.from_bytes(bytes(data))
@@ -560,9 +643,10 @@ class TestPerpetualsClient:
         assert market_state.funding_index == funding_index
         assert market_state.funding_rate_per_slot == funding_rate_per_slot
         assert market_state.open_interest == open_interest
-        assert market_state.bump == bump
         assert market_state.last_funding_slot == last_funding_slot
         assert market_state.mark_price == mark_price
+        assert market_state.paused
+        assert market_state.market_id == market_id
 
 # Run demo if this file is executed directly
 if __name__ == "__main__":
@@ -0,0 +1,22 @@
+//! Arbitrary instruction data through the decoder and the dispatcher
+//!
+//! Anything that decodes must survive a round trip through the current
+//! versioned layout, and dispatching it without accounts must fail cleanly
+//! rather than panic.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use simple_perps::instruction::PerpsInstruction;
+use solana_program::pubkey::Pubkey;
+
+fuzz_target!(|data: &[u8]| {
+    simple_perps_fuzz::install_quiet_stubs();
+
+    if let Ok(instruction) = PerpsInstruction::unpack(data) {
+        assert_eq!(PerpsInstruction::unpack(&instruction.pack()), Ok(instruction));
+    }
+
+    let program_id = Pubkey::new_from_array([7; 32]);
+    assert!(simple_perps::process_instruction(&program_id, &[], data).is_err());
+});
@@ -0,0 +1,250 @@
+//! Sequences of trading instructions against an arbitrary market and position
+//!
+//! The accounts are built in memory with CPIs stubbed out, so every handler
+//! runs its parsing and math on fuzzer-chosen state. A failed instruction is
+//! rolled back like a failed transaction. After each one that succeeds:
+//! - open interest moves exactly with the position's |base_amount|
+//! - a closed position holds neither exposure nor collateral
+//! - a liquidated position has no exposure left
+
+#![no_main]
+
+use arbitrary::Arbitrary;
+use borsh::BorshSerialize;
+use libfuzzer_sys::fuzz_target;
+use simple_perps::{
+    instruction::PerpsInstruction, position_address, AccountType, Config, MarketState, Position,
+    CONFIG_SEED, DEFAULT_MAX_FUNDING_CRANK_REWARD, DEFAULT_MAX_FUNDING_RATE_PER_SLOT,
+    DEFAULT_MAX_FUNDING_SETTLEMENT_SHARE, DEFAULT_MAX_LEVERAGE,
+    DEFAULT_MAX_OPEN_INTEREST, DEFAULT_FUNDING_CRANK_REWARD, DEFAULT_INSURANCE_FUND_SHARE,
+    DEFAULT_REFERRAL_FEE_SHARE, OPEN_FLAG_REDUCE_ONLY, PDA_SEED, SPL_TOKEN_PROGRAM_ID, Versioned,
+};
+use solana_program::{
+    account_info::AccountInfo,
+    clock::Clock,
+    pubkey::Pubkey,
+    rent::Rent,
+    system_program,
+    sysvar::{self, Sysvar},
+};
+
+/// SPL Token mint size and decimals offset
+const MINT_LEN: usize = 82;
+const MINT_DECIMALS_OFFSET: usize = 44;
+
+/// SPL Token account size and state offset
+const TOKEN_ACCOUNT_LEN: usize = 165;
+const TOKEN_ACCOUNT_STATE_OFFSET: usize = 108;
+
+/// An initialized token account of `mint` held by `owner`
+fn token_account_data(mint: &Pubkey, owner: &Pubkey) -> Vec<u8> {
+    let mut data = vec![0u8; TOKEN_ACCOUNT_LEN];
+    data[0..32].copy_from_slice(mint.as_ref());
+    data[32..64].copy_from_slice(owner.as_ref());
+    data[TOKEN_ACCOUNT_STATE_OFFSET] = 1;
+    data
+}
+
+#[derive(Arbitrary, Debug)]
+struct Input {
+    mark_price: u64,
+    base_reserve: u64,
+    funding_index: i64,
+    /// Open interest held by positions other than the fuzzed one
+    other_open_interest: u64,
+    base_amount: i64,
+    collateral: u64,
+    entry_price: u64,
+    last_funding_index: i64,
+    min_collateral_ratio: u64,
+    liquidator_fee_bps: u16,
+    trading_fee: u32,
+    actions: Vec<Action>,
+}
+
+#[derive(Arbitrary, Debug)]
+enum Action {
+    Open { base_delta: i64, collateral_delta: u64, price_limit: u64, reduce_only: bool },
+    Close,
+    Liquidate,
+    UpdateFunding,
+    SettlePnl,
+    /// Raw instruction data with the open_position accounts
+    Raw(Vec<u8>),
+    AdvanceSlots(u16),
+}
+
+fuzz_target!(|input: Input| {
+    simple_perps_fuzz::install_quiet_stubs();
+    run(input);
+});
+
+fn run(input: Input) {
+    let program_id = Pubkey::new_from_array([7; 32]);
+    let user_key = Pubkey::new_from_array([1; 32]);
+    let keeper_key = Pubkey::new_from_array([2; 32]);
+    let market_key = Pubkey::new_from_array([3; 32]);
+    let (user_token_key, keeper_token_key, mint_key) =
+        (Pubkey::new_from_array([4; 32]), Pubkey::new_from_array([5; 32]), Pubkey::new_from_array([6; 32]));
+    let (vault_key, vault_bump) = Pubkey::find_program_address(&[PDA_SEED], &program_id);
+    let (config_key, config_bump) = Pubkey::find_program_address(&[CONFIG_SEED], &program_id);
+    let (position_key, position_bump) = position_address(&program_id, &market_key, &user_key, 0);
+    let (token_program_key, rent_key, clock_key, system_key) =
+        (SPL_TOKEN_PROGRAM_ID, sysvar::rent::id(), sysvar::clock::id(), system_program::id());
+
+    let mut market_data = vec![0u8; MarketState::SPACE];
+    let Ok(quote_reserve) = simple_perps::calculate_quote_reserve(input.base_reserve, input.mark_price) else {
+        return;
+    };
+    *MarketState::init(&mut market_data).unwrap() = MarketState {
+        funding_index: input.funding_index,
+        open_interest: input.other_open_interest.saturating_add(input.base_amount.unsigned_abs()),
+        mark_price: input.mark_price,
+        base_reserve: input.base_reserve,
+        quote_reserve,
+        max_leverage: DEFAULT_MAX_LEVERAGE,
+        max_open_interest: DEFAULT_MAX_OPEN_INTEREST,
+        bump: vault_bump,
+        version: MarketState::VERSION,
+        net_base_amount: input.base_amount,
+        base_decimals: 9,
+        quote_decimals: 9,
+        quote_mint: mint_key,
+        ..MarketState::default()
+    };
+
+    let mut position_data = vec![0u8; Position::SPACE];
+    *Position::init(&mut position_data).unwrap() = Position {
+        owner: user_key,
+        base_amount: input.base_amount,
+        collateral: input.collateral,
+        last_funding_index: input.last_funding_index,
+        entry_price: input.entry_price,
+        version: Position::VERSION,
+        bump: position_bump,
+        ..Position::default()
+    };
+
+    let config = Config {
+        admin: user_key,
+        min_collateral_ratio: input.min_collateral_ratio,
+        liquidator_fee_bps: u64::from(input.liquidator_fee_bps),
+        trading_fee: u64::from(input.trading_fee),
+        referral_fee_share: DEFAULT_REFERRAL_FEE_SHARE,
+        max_funding_rate_per_slot: DEFAULT_MAX_FUNDING_RATE_PER_SLOT,
+        bump: config_bump,
+        token_program: token_program_key,
+        quote_mint: mint_key,
+        funding_crank_reward: DEFAULT_FUNDING_CRANK_REWARD,
+        max_funding_crank_reward: DEFAULT_MAX_FUNDING_CRANK_REWARD,
+        insurance_fund_share: DEFAULT_INSURANCE_FUND_SHARE,
+        max_funding_settlement_share: DEFAULT_MAX_FUNDING_SETTLEMENT_SHARE,
+        vault_bump,
+        ..Config::default()
+    };
+    let mut config_data = vec![0u8; Config::SPACE];
+    config_data[..Config::DISCRIMINATOR.len()].copy_from_slice(&Config::DISCRIMINATOR);
+    config.serialize(&mut &mut config_data[Config::DISCRIMINATOR.len()..]).unwrap();
+
+    let mut mint_data = vec![0u8; MINT_LEN];
+    mint_data[MINT_DECIMALS_OFFSET] = 9;
+    let mut clock_data = vec![0u8; Clock::size_of()];
+    let mut rent_data = vec![0u8; Rent::size_of()];
+    let (mut empty_user, mut empty_keeper) = (vec![], vec![]);
+    let mut user_token_data = token_account_data(&mint_key, &user_key);
+    let mut keeper_token_data = token_account_data(&mint_key, &keeper_key);
+    let mut vault_data = token_account_data(&mint_key, &vault_key);
+    let (mut empty_token_program, mut empty_system) = (vec![], vec![]);
+
+    let mut lamports = [0u64; 13];
+    let mut lamports = lamports.iter_mut();
+    let mut l = || lamports.next().unwrap();
+
+    let (bpf_loader, sysvar_owner) = (solana_program::bpf_loader::id(), sysvar::id());
+    let user = AccountInfo::new(&user_key, true, true, l(), &mut empty_user, &system_key, false, 0);
+    let keeper = AccountInfo::new(&keeper_key, true, true, l(), &mut empty_keeper, &system_key, false, 0);
+    let token_program = AccountInfo::new(
+        &token_program_key, false, false, l(), &mut empty_token_program, &bpf_loader, true, 0,
+    );
+    let user_token = AccountInfo::new(
+        &user_token_key, false, true, l(), &mut user_token_data, &token_program_key, false, 0,
+    );
+    let keeper_token = AccountInfo::new(
+        &keeper_token_key, false, true, l(), &mut keeper_token_data, &token_program_key, false, 0,
+    );
+    let vault = AccountInfo::new(&vault_key, false, true, l(), &mut vault_data, &token_program_key, false, 0);
+    let position = AccountInfo::new(&position_key, false, true, l(), &mut position_data, &program_id, false, 0);
+    let market = AccountInfo::new(&market_key, false, true, l(), &mut market_data, &program_id, false, 0);
+    let mut rent = AccountInfo::new(&rent_key, false, false, l(), &mut rent_data, &sysvar_owner, false, 0);
+    let mut clock = AccountInfo::new(&clock_key, false, false, l(), &mut clock_data, &sysvar_owner, false, 0);
+    let system = AccountInfo::new(&system_key, false, false, l(), &mut empty_system, &bpf_loader, true, 0);
+    let config = AccountInfo::new(&config_key, false, false, l(), &mut config_data, &program_id, false, 0);
+    let mint = AccountInfo::new(&mint_key, false, false, l(), &mut mint_data, &token_program_key, false, 0);
+
+    Rent::default().to_account_info(&mut rent).unwrap();
+    let mut slot = 1u64;
+
+    for action in input.actions {
+        Clock { slot, ..Clock::default() }.to_account_info(&mut clock).unwrap();
+
+        let open_accounts = [
+            user.clone(), token_program.clone(), user_token.clone(), vault.clone(), position.clone(),
+            market.clone(), rent.clone(), clock.clone(), system.clone(), config.clone(), mint.clone(),
+        ];
+        let (instruction_data, accounts) = match action {
+            Action::Open { base_delta, collateral_delta, price_limit, reduce_only } => {
+                let flags = if reduce_only { OPEN_FLAG_REDUCE_ONLY } else { 0 };
+                let instruction = PerpsInstruction::OpenPosition {
+                    base_delta, collateral_delta, price_limit, flags, sub_account_id: 0,
+                };
+                (instruction.pack(), open_accounts.to_vec())
+            }
+            Action::Close => (PerpsInstruction::ClosePosition.pack(), vec![
+                user.clone(), token_program.clone(), user_token.clone(), vault.clone(), position.clone(),
+                market.clone(), config.clone(), mint.clone(),
+            ]),
+            Action::Liquidate => (PerpsInstruction::Liquidate.pack(), vec![
+                keeper.clone(), token_program.clone(), keeper_token.clone(), vault.clone(), position.clone(),
+                market.clone(), clock.clone(), config.clone(), mint.clone(),
+            ]),
+            Action::UpdateFunding => (
+                PerpsInstruction::UpdateFunding.pack(),
+                vec![market.clone(), clock.clone(), config.clone()],
+            ),
+            Action::SettlePnl => (
+                PerpsInstruction::SettlePnl.pack(),
+                vec![position.clone(), market.clone(), config.clone()],
+            ),
+            Action::Raw(data) => (data, open_accounts.to_vec()),
+            Action::AdvanceSlots(slots) => {
+                slot = slot.saturating_add(u64::from(slots));
+                continue;
+            }
+        };
+
+        let (position_before, market_before) = (position.data.borrow().to_vec(), market.data.borrow().to_vec());
+        let is_close = instruction_data.first() == Some(&3);
+        let is_liquidation = instruction_data.first() == Some(&2);
+
+        if simple_perps::process_instruction(&program_id, &accounts, &instruction_data).is_err() {
+            // A failed transaction leaves no trace
+            position.data.borrow_mut().copy_from_slice(&position_before);
+            market.data.borrow_mut().copy_from_slice(&market_before);
+            continue;
+        }
+
+        let (old_position, old_market) = (Position::load(&position_before).unwrap(), MarketState::load(&market_before).unwrap());
+        let (new_position, new_market) = (*Position::load(&position.data.borrow()).unwrap(), *MarketState::load(&market.data.borrow()).unwrap());
+
+        let size_change = i128::from(new_position.base_amount.unsigned_abs()) - i128::from(old_position.base_amount.unsigned_abs());
+        let open_interest_change = i128::from(new_market.open_interest) - i128::from(old_market.open_interest);
+        assert_eq!(open_interest_change, size_change, "open interest drifted from the position size");
+
+        if is_close {
+            assert_eq!((new_position.base_amount, new_position.collateral), (0, 0), "close left a balance");
+        }
+        if is_liquidation {
+            assert_eq!(new_position.base_amount, 0, "liquidation left exposure");
+        }
+    }
+}
@@ -0,0 +1,36 @@
+//! Shared setup for the fuzz targets
+
+use std::sync::Once;
+
+use solana_program::{
+    account_info::AccountInfo, entrypoint::ProgramResult, instruction::Instruction, program_stubs,
+};
+
+/// Host syscalls that drop program logs and treat every CPI as a successful no-op
+///
+/// Token transfers and account creation are outside what the targets check, and
+/// logging every `msg!` would dominate the run time.
+struct QuietStubs;
+
+impl program_stubs::SyscallStubs for QuietStubs {
+    fn sol_log(&self, _message: &str) {}
+
+    fn sol_log_data(&self, _fields: &[&[u8]]) {}
+
+    fn sol_invoke_signed(
+        &self,
+        _instruction: &Instruction,
+        _account_infos: &[AccountInfo],
+        _signers_seeds: &[&[&[u8]]],
+    ) -> ProgramResult {
+        Ok(())
+    }
+}
+
+/// Install `QuietStubs` once per process
+pub fn install_quiet_stubs() {
+    static INSTALL: Once = Once::new();
+    INSTALL.call_once(|| {
+        program_stubs::set_syscall_stubs(Box::new(QuietStubs));
+    });
+}
@@ -0,0 +1,31 @@
+#!/bin/bash
+
+# Solana Perpetuals IDL Generation Script
+# Extracts the program's IDL from the shank annotations in src/ so TypeScript
+# and Python clients can be generated instead of hand-encoding instructions
+
+set -e
+
+echo ""
+echo "=================================IDL=================================="
+echo ""
+
+# The program id is recorded in the IDL; default to the last deployment
+PROGRAM_ID=${1:-$(cat example/program_id.txt 2>/dev/null || true)}
+OUT_DIR=${2:-idl}
+
+if [ -z "$PROGRAM_ID" ]; then
+    echo "❌ No program id. Pass one, or run ./scripts/3_deploy.sh first."
+    exit 1
+fi
+
+if ! command -v shank &> /dev/null; then
+    echo "📦 Installing shank-cli..."
+    cargo install shank-cli --version 0.0.11 --locked
+fi
+
+echo "📝 Generating IDL for $PROGRAM_ID..."
+shank idl --crate-root . --out-dir "$OUT_DIR" --program-id "$PROGRAM_ID"
+
+echo "✅ IDL written to $OUT_DIR/simple_perps.json"
+echo "💡 Generate a TypeScript SDK with solita: npx @metaplex-foundation/solita"
@@ -0,0 +1,507 @@
+//! Permissioned trading
+//!
+//! A market with its `permissioned` flag set only lets a wallet create or grow
+//! positions through `open_position`, the order book or a backstop takeover when it
+//! is whitelisted: it holds a WhitelistEntry ([WHITELIST_SEED, wallet]) the admin
+//! created, or, when the market names an `access_mint`, a token account of that mint
+//! with a non-zero balance. Either is passed among the instruction's trailing
+//! accounts and recognized there, like user stats. Reductions are never gated, so a
+//! wallet removed from the list can still exit.
+//!
+//! Liquidations are permissionless by default. With the config's
+//! `restrict_liquidators` set, `liquidate` and `backstop_liquidate` only accept a
+//! liquidator listed in the admin's LiquidatorRegistry ([LIQUIDATOR_REGISTRY_SEED]),
+//! passed among their trailing accounts.
+//!
+//! The admin can also give one wallet its own max leverage in one market, e.g. a
+//! market maker with offsetting books elsewhere, with a LeverageOverride
+//! ([LEVERAGE_OVERRIDE_SEED, market_state, wallet]). `open_position`, order book
+//! fills and backstop takeovers apply it in place of the market's `max_leverage` when it is passed among the
+//! trailing accounts; the collateral ratio requirement is unchanged.
+
+use borsh::{BorshDeserialize, BorshSerialize};
+use shank::ShankAccount;
+use solana_program::{
+    account_info::{next_account_info, AccountInfo},
+    entrypoint::ProgramResult,
+    msg,
+    program::invoke_signed,
+    program_error::ProgramError,
+    pubkey::Pubkey,
+    system_instruction,
+    sysvar::{rent::Rent, Sysvar},
+};
+
+use crate::{
+    close_program_account, is_supported_token_program, load_account, load_admin_config, probe_account, store_account, AccountType,
+    Config, MarketState, DISCRIMINATOR_LEN, TOKEN_ACCOUNT_LEN, TOKEN_ACCOUNT_STATE_OFFSET,
+};
+
+/// Seed prefix for whitelist entry PDAs: [WHITELIST_SEED, wallet]
+pub const WHITELIST_SEED: &[u8] = b"whitelist";
+
+/// Seed of the liquidator registry PDA
+pub const LIQUIDATOR_REGISTRY_SEED: &[u8] = b"liquidator_registry";
+
+/// Seed prefix for leverage override PDAs: [LEVERAGE_OVERRIDE_SEED, market_state, wallet]
+pub const LEVERAGE_OVERRIDE_SEED: &[u8] = b"leverage_override";
+
+/// Most liquidators the registry can hold
+pub const MAX_LIQUIDATORS: usize = 16;
+
+/// Offset of the u64 balance in a token account (same for both token programs)
+const TOKEN_ACCOUNT_AMOUNT_OFFSET: usize = 64;
+
+/// The admin's approval for a wallet to trade in permissioned markets
+#[derive(BorshSerialize, BorshDeserialize, Debug, Default, Clone, PartialEq, Eq, ShankAccount)]
+pub struct WhitelistEntry {
+    /// Whitelisted wallet
+    pub wallet: Pubkey,
+    /// PDA bump for [WHITELIST_SEED, wallet]
+    pub bump: u8,
+}
+
+impl AccountType for WhitelistEntry {
+    const DISCRIMINATOR: [u8; DISCRIMINATOR_LEN] = *b"whitelst";
+    /// wallet + bump
+    const LEN: usize = 32 + 1;
+}
+
+/// Liquidators allowed to liquidate while the config restricts liquidations
+#[derive(BorshSerialize, BorshDeserialize, Debug, Default, Clone, PartialEq, Eq, ShankAccount)]
+pub struct LiquidatorRegistry {
+    /// Registered liquidators (at most MAX_LIQUIDATORS)
+    pub liquidators: Vec<Pubkey>,
+    /// PDA bump for [LIQUIDATOR_REGISTRY_SEED]
+    pub bump: u8,
+}
+
+impl AccountType for LiquidatorRegistry {
+    const DISCRIMINATOR: [u8; DISCRIMINATOR_LEN] = *b"liqregst";
+    /// vec length + MAX_LIQUIDATORS liquidators + bump
+    const LEN: usize = 4 + MAX_LIQUIDATORS * 32 + 1;
+}
+
+/// The admin's max leverage for one wallet in one market, in place of the market's
+#[derive(BorshSerialize, BorshDeserialize, Debug, Default, Clone, PartialEq, Eq, ShankAccount)]
+pub struct LeverageOverride {
+    /// Market state account the override applies in
+    pub market_state: Pubkey,
+    /// Wallet whose positions get the override
+    pub wallet: Pubkey,
+    /// Max leverage for the wallet's position increases (1e9 precision)
+    pub max_leverage: u64,
+    /// PDA bump for [LEVERAGE_OVERRIDE_SEED, market_state, wallet]
+    pub bump: u8,
+}
+
+impl AccountType for LeverageOverride {
+    const DISCRIMINATOR: [u8; DISCRIMINATOR_LEN] = *b"leverovr";
+    /// market_state + wallet + max_leverage + bump
+    const LEN: usize = 32 + 32 + 8 + 1;
+}
+
+/// Whitelist entry PDA of `wallet`
+pub fn whitelist_address(program_id: &Pubkey, wallet: &Pubkey) -> (Pubkey, u8) {
+    Pubkey::find_program_address(&[WHITELIST_SEED, wallet.as_ref()], program_id)
+}
+
+// ---------------------------------------------------------------------
+// 6️⃣3️⃣ Make a market permissioned or open (admin only)
+// ---------------------------------------------------------------------
+pub fn set_market_access(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    permissioned: bool,
+    access_mint: Pubkey,
+) -> ProgramResult {
+    // Accounts:
+    // 0. [signer] admin
+    // 1. [] config account
+    // 2. [writable] market state account
+    let accounts_iter = &mut accounts.iter();
+    let admin = next_account_info(accounts_iter)?;
+    let config_acc = next_account_info(accounts_iter)?;
+    let market_state_acc = next_account_info(accounts_iter)?;
+
+    load_admin_config(program_id, admin, config_acc)?;
+
+    if market_state_acc.owner != program_id {
+        msg!("Market state is not owned by the program");
+        return Err(ProgramError::IncorrectProgramId);
+    }
+
+    let mut market_state_data = market_state_acc.try_borrow_mut_data()?;
+    let market_state = MarketState::load_mut(&mut market_state_data)?;
+    market_state.permissioned = u8::from(permissioned);
+    market_state.access_mint = access_mint;
+
+    msg!("Market access set: permissioned={}, access_mint={}", permissioned, access_mint);
+
+    Ok(())
+}
+
+// ---------------------------------------------------------------------
+// 6️⃣4️⃣ Add a wallet to or remove it from the whitelist (admin only)
+// ---------------------------------------------------------------------
+pub fn set_whitelisted(program_id: &Pubkey, accounts: &[AccountInfo], wallet: Pubkey, whitelisted: bool) -> ProgramResult {
+    // Accounts:
+    // 0. [signer, writable] admin (pays for a new entry, receives a removed one's rent)
+    // 1. [] config account
+    // 2. [writable] whitelist entry (PDA: [WHITELIST_SEED, wallet])
+    // 3. [] rent sysvar
+    // 4. [] system program
+    let accounts_iter = &mut accounts.iter();
+    let admin = next_account_info(accounts_iter)?;
+    let config_acc = next_account_info(accounts_iter)?;
+    let entry_acc = next_account_info(accounts_iter)?;
+    let rent_sysvar = next_account_info(accounts_iter)?;
+    let system_program = next_account_info(accounts_iter)?;
+
+    load_admin_config(program_id, admin, config_acc)?;
+
+    let (expected_entry, bump) = whitelist_address(program_id, &wallet);
+    if *entry_acc.key != expected_entry {
+        msg!("Whitelist entry is not the correct PDA. Expected: {}, Got: {}", expected_entry, entry_acc.key);
+        return Err(ProgramError::InvalidArgument);
+    }
+
+    if !whitelisted {
+        if entry_acc.owner != program_id {
+            msg!("Wallet {} is not whitelisted", wallet);
+            return Err(ProgramError::InvalidArgument);
+        }
+        close_program_account(entry_acc, admin)?;
+        msg!("Removed {} from the whitelist", wallet);
+        return Ok(());
+    }
+
+    if !entry_acc.data_is_empty() {
+        msg!("Wallet {} is already whitelisted", wallet);
+        return Err(ProgramError::AccountAlreadyInitialized);
+    }
+
+    let rent = Rent::from_account_info(rent_sysvar)?;
+    invoke_signed(&system_instruction::create_account(
+        admin.key,
+        entry_acc.key,
+        rent.minimum_balance(WhitelistEntry::SPACE),
+        WhitelistEntry::SPACE as u64,
+        program_id,
+    ), &[
+        admin.clone(),
+        entry_acc.clone(),
+        system_program.clone(),
+    ], &[&[WHITELIST_SEED, wallet.as_ref(), &[bump]]])?;
+
+    store_account(&WhitelistEntry { wallet, bump }, &mut entry_acc.data.borrow_mut())?;
+
+    msg!("Added {} to the whitelist", wallet);
+
+    Ok(())
+}
+
+// ---------------------------------------------------------------------
+// 6️⃣5️⃣ Restrict liquidations to registered liquidators or open them (admin only)
+// ---------------------------------------------------------------------
+pub fn set_liquidator_allowlist(program_id: &Pubkey, accounts: &[AccountInfo], enabled: bool) -> ProgramResult {
+    // Accounts:
+    // 0. [signer] admin
+    // 1. [writable] config account
+    let accounts_iter = &mut accounts.iter();
+    let admin = next_account_info(accounts_iter)?;
+    let config_acc = next_account_info(accounts_iter)?;
+
+    let mut config = load_admin_config(program_id, admin, config_acc)?;
+    config.restrict_liquidators = enabled;
+    store_account(&config, &mut config_acc.data.borrow_mut())?;
+
+    msg!("Liquidator allowlist {}", if enabled { "enabled" } else { "disabled" });
+
+    Ok(())
+}
+
+// ---------------------------------------------------------------------
+// 6️⃣6️⃣ Add a liquidator to or remove it from the registry (admin only)
+// ---------------------------------------------------------------------
+pub fn set_liquidator(program_id: &Pubkey, accounts: &[AccountInfo], liquidator: Pubkey, allowed: bool) -> ProgramResult {
+    // Accounts:
+    // 0. [signer, writable] admin (pays for the registry on first use)
+    // 1. [] config account
+    // 2. [writable] liquidator registry (PDA: [LIQUIDATOR_REGISTRY_SEED])
+    // 3. [] rent sysvar
+    // 4. [] system program
+    let accounts_iter = &mut accounts.iter();
+    let admin = next_account_info(accounts_iter)?;
+    let config_acc = next_account_info(accounts_iter)?;
+    let registry_acc = next_account_info(accounts_iter)?;
+    let rent_sysvar = next_account_info(accounts_iter)?;
+    let system_program = next_account_info(accounts_iter)?;
+
+    load_admin_config(program_id, admin, config_acc)?;
+
+    let (expected_registry, bump) = liquidator_registry_address(program_id);
+    if *registry_acc.key != expected_registry {
+        msg!("Liquidator registry is not the correct PDA. Expected: {}, Got: {}", expected_registry, registry_acc.key);
+        return Err(ProgramError::InvalidArgument);
+    }
+
+    let mut registry = if registry_acc.data_is_empty() {
+        let rent = Rent::from_account_info(rent_sysvar)?;
+        invoke_signed(&system_instruction::create_account(
+            admin.key,
+            registry_acc.key,
+            rent.minimum_balance(LiquidatorRegistry::SPACE),
+            LiquidatorRegistry::SPACE as u64,
+            program_id,
+        ), &[
+            admin.clone(),
+            registry_acc.clone(),
+            system_program.clone(),
+        ], &[&[LIQUIDATOR_REGISTRY_SEED, &[bump]]])?;
+        LiquidatorRegistry { liquidators: Vec::new(), bump }
+    } else {
+        if registry_acc.owner != program_id {
+            msg!("Liquidator registry is not owned by the program");
+            return Err(ProgramError::IncorrectProgramId);
+        }
+        load_account::<LiquidatorRegistry>(&registry_acc.data.borrow())?
+    };
+
+    let listed = registry.liquidators.contains(&liquidator);
+    if allowed {
+        if listed {
+            msg!("Liquidator {} is already registered", liquidator);
+            return Err(ProgramError::InvalidArgument);
+        }
+        if registry.liquidators.len() >= MAX_LIQUIDATORS {
+            msg!("Liquidator registry is full ({} liquidators)", MAX_LIQUIDATORS);
+            return Err(ProgramError::InvalidArgument);
+        }
+        registry.liquidators.push(liquidator);
+    } else {
+        if !listed {
+            msg!("Liquidator {} is not registered", liquidator);
+            return Err(ProgramError::InvalidArgument);
+        }
+        registry.liquidators.retain(|registered| *registered != liquidator);
+    }
+    store_account(&registry, &mut registry_acc.data.borrow_mut())?;
+
+    msg!("{} liquidator {} ({} registered)", if allowed { "Registered" } else { "Removed" }, liquidator, registry.liquidators.len());
+
+    Ok(())
+}
+
+// ---------------------------------------------------------------------
+// 8️⃣4️⃣ Set or remove a wallet's max leverage override in a market (admin only)
+// ---------------------------------------------------------------------
+pub fn set_leverage_override(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    wallet: Pubkey,
+    max_leverage: u64,
+) -> ProgramResult {
+    // Accounts:
+    // 0. [signer, writable] admin (pays for a new override, receives a removed one's rent)
+    // 1. [] config account
+    // 2. [] market state account
+    // 3. [writable] leverage override (PDA: [LEVERAGE_OVERRIDE_SEED, market_state, wallet])
+    // 4. [] rent sysvar
+    // 5. [] system program
+    let accounts_iter = &mut accounts.iter();
+    let admin = next_account_info(accounts_iter)?;
+    let config_acc = next_account_info(accounts_iter)?;
+    let market_state_acc = next_account_info(accounts_iter)?;
+    let override_acc = next_account_info(accounts_iter)?;
+    let rent_sysvar = next_account_info(accounts_iter)?;
+    let system_program = next_account_info(accounts_iter)?;
+
+    load_admin_config(program_id, admin, config_acc)?;
+
+    if market_state_acc.owner != program_id {
+        msg!("Market state is not owned by the program");
+        return Err(ProgramError::IncorrectProgramId);
+    }
+    MarketState::load(&market_state_acc.data.borrow())?;
+
+    let (expected_override, bump) = leverage_override_address(program_id, market_state_acc.key, &wallet);
+    if *override_acc.key != expected_override {
+        msg!("Leverage override is not the correct PDA. Expected: {}, Got: {}", expected_override, override_acc.key);
+        return Err(ProgramError::InvalidArgument);
+    }
+
+    // Zero removes the override, returning the wallet to the market's max leverage
+    if max_leverage == 0 {
+        if override_acc.owner != program_id {
+            msg!("Wallet {} has no leverage override", wallet);
+            return Err(ProgramError::InvalidArgument);
+        }
+        close_program_account(override_acc, admin)?;
+        msg!("Removed the leverage override of {}", wallet);
+        return Ok(());
+    }
+
+    if override_acc.data_is_empty() {
+        let rent = Rent::from_account_info(rent_sysvar)?;
+        invoke_signed(&system_instruction::create_account(
+            admin.key,
+            override_acc.key,
+            rent.minimum_balance(LeverageOverride::SPACE),
+            LeverageOverride::SPACE as u64,
+            program_id,
+        ), &[
+            admin.clone(),
+            override_acc.clone(),
+            system_program.clone(),
+        ], &[&[LEVERAGE_OVERRIDE_SEED, market_state_acc.key.as_ref(), wallet.as_ref(), &[bump]]])?;
+    } else if override_acc.owner != program_id {
+        msg!("Leverage override is not owned by the program");
+        return Err(ProgramError::IncorrectProgramId);
+    }
+
+    let leverage_override = LeverageOverride { market_state: *market_state_acc.key, wallet, max_leverage, bump };
+    store_account(&leverage_override, &mut override_acc.data.borrow_mut())?;
+
+    msg!("Leverage override of {} set: max_leverage={}", wallet, max_leverage);
+
+    Ok(())
+}
+
+/// Leverage override PDA of `wallet` in the market at `market_state`
+pub fn leverage_override_address(program_id: &Pubkey, market_state: &Pubkey, wallet: &Pubkey) -> (Pubkey, u8) {
+    Pubkey::find_program_address(&[LEVERAGE_OVERRIDE_SEED, market_state.as_ref(), wallet.as_ref()], program_id)
+}
+
+/// Max leverage `wallet` may grow a position to in the market at `market_key`: that of its
+/// leverage override if one of `accounts` is it, the market's otherwise
+pub(crate) fn max_leverage_for(
+    program_id: &Pubkey,
+    market_key: &Pubkey,
+    market_state: &MarketState,
+    wallet: &Pubkey,
+    accounts: &[&AccountInfo],
+) -> u64 {
+    accounts
+        .iter()
+        .find_map(|account| load_leverage_override(program_id, market_key, wallet, account))
+        .map_or(market_state.max_leverage, |leverage_override| leverage_override.max_leverage)
+}
+
+/// Whether `account` is `wallet`'s leverage override in the market at `market_key`
+pub(crate) fn is_leverage_override(program_id: &Pubkey, market_key: &Pubkey, wallet: &Pubkey, account: &AccountInfo) -> bool {
+    load_leverage_override(program_id, market_key, wallet, account).is_some()
+}
+
+/// `account` decoded, if it is `wallet`'s leverage override in the market at `market_key`
+fn load_leverage_override(
+    program_id: &Pubkey,
+    market_key: &Pubkey,
+    wallet: &Pubkey,
+    account: &AccountInfo,
+) -> Option<LeverageOverride> {
+    if account.owner != program_id {
+        return None;
+    }
+
+    let leverage_override = probe_account::<LeverageOverride>(&account.try_borrow_data().ok()?)?;
+    let expected = Pubkey::create_program_address(
+        &[LEVERAGE_OVERRIDE_SEED, market_key.as_ref(), wallet.as_ref(), &[leverage_override.bump]],
+        program_id,
+    )
+    .ok()?;
+    (leverage_override.market_state == *market_key && leverage_override.wallet == *wallet && expected == *account.key)
+        .then_some(leverage_override)
+}
+
+/// Liquidator registry PDA
+pub fn liquidator_registry_address(program_id: &Pubkey) -> (Pubkey, u8) {
+    Pubkey::find_program_address(&[LIQUIDATOR_REGISTRY_SEED], program_id)
+}
+
+/// Reject a liquidation while the config restricts liquidators unless one of `accounts`
+/// is the liquidator registry and lists `liquidator`
+pub(crate) fn require_registered_liquidator(
+    program_id: &Pubkey,
+    config: &Config,
+    liquidator: &Pubkey,
+    accounts: &[&AccountInfo],
+) -> ProgramResult {
+    if !config.restrict_liquidators {
+        return Ok(());
+    }
+
+    let (registry_key, _) = liquidator_registry_address(program_id);
+    let registered = accounts
+        .iter()
+        .find(|account| account.owner == program_id && *account.key == registry_key)
+        .and_then(|account| probe_account::<LiquidatorRegistry>(&account.try_borrow_data().ok()?))
+        .is_some_and(|registry| registry.liquidators.contains(liquidator));
+    if !registered {
+        msg!("Liquidations are restricted: {} is not a registered liquidator", liquidator);
+        return Err(ProgramError::IllegalOwner);
+    }
+
+    Ok(())
+}
+
+/// Reject a trade that grows `wallet`'s position in a permissioned market unless one of
+/// `accounts` is the wallet's whitelist entry or a funded token account of the market's
+/// access mint
+pub(crate) fn require_whitelisted(
+    program_id: &Pubkey,
+    market_state: &MarketState,
+    wallet: &Pubkey,
+    accounts: &[&AccountInfo],
+) -> ProgramResult {
+    if !market_state.is_permissioned()
+        || accounts.iter().any(|account| is_access_account(program_id, market_state, wallet, account))
+    {
+        return Ok(());
+    }
+
+    msg!("Market is permissioned: {} needs a whitelist entry or an access token", wallet);
+    Err(ProgramError::IllegalOwner)
+}
+
+/// Whether `account` grants `wallet` access to a permissioned market
+pub(crate) fn is_access_account(
+    program_id: &Pubkey,
+    market_state: &MarketState,
+    wallet: &Pubkey,
+    account: &AccountInfo,
+) -> bool {
+    is_whitelist_entry(program_id, wallet, account)
+        || (market_state.access_mint != Pubkey::default() && holds_access_token(&market_state.access_mint, wallet, account))
+}
+
+/// Whether `account` is `wallet`'s whitelist entry
+fn is_whitelist_entry(program_id: &Pubkey, wallet: &Pubkey, account: &AccountInfo) -> bool {
+    account.owner == program_id
+        && account
+            .try_borrow_data()
+            .ok()
+            .and_then(|data| probe_account::<WhitelistEntry>(&data))
+            .is_some_and(|entry| {
+                entry.wallet == *wallet
+                    && Pubkey::create_program_address(&[WHITELIST_SEED, wallet.as_ref(), &[entry.bump]], program_id)
+                        .is_ok_and(|expected| expected == *account.key)
+            })
+}
+
+/// Whether `account` is an initialized token account of `mint` held by `wallet` with a
+/// non-zero balance
+pub(crate) fn holds_access_token(mint: &Pubkey, wallet: &Pubkey, account: &AccountInfo) -> bool {
+    if !is_supported_token_program(account.owner) {
+        return false;
+    }
+
+    let Ok(data) = account.try_borrow_data() else {
+        return false;
+    };
+    data.len() >= TOKEN_ACCOUNT_LEN
+        && data[TOKEN_ACCOUNT_STATE_OFFSET] != 0
+        && data[0..32] == mint.to_bytes()
+        && data[32..64] == wallet.to_bytes()
+        && data[TOKEN_ACCOUNT_AMOUNT_OFFSET..TOKEN_ACCOUNT_AMOUNT_OFFSET + 8] != [0; 8]
+}
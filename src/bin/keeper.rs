@@ -0,0 +1,46 @@
+//! Reference keeper bot for one market (`keeper` feature)
+//!
+//! ```text
+//! cargo run --features keeper --bin keeper -- \
+//!     <RPC_URL> <KEYPAIR_FILE> <PROGRAM_ID> <MARKET> <KEEPER_TOKEN_ACCOUNT> [FUNDING_SECS] [SCAN_SECS]
+//! ```
+//!
+//! Runs until killed; see `simple_perps::keeper` for what each loop does.
+
+use std::env;
+use std::process;
+use std::str::FromStr;
+use std::time::Duration;
+
+use simple_perps::keeper::{Keeper, KeeperConfig};
+use solana_client::rpc_client::RpcClient;
+use solana_sdk::{commitment_config::CommitmentConfig, pubkey::Pubkey, signature::read_keypair_file};
+
+const USAGE: &str =
+    "usage: keeper <RPC_URL> <KEYPAIR_FILE> <PROGRAM_ID> <MARKET> <KEEPER_TOKEN_ACCOUNT> [FUNDING_SECS] [SCAN_SECS]";
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    if !(5..=7).contains(&args.len()) {
+        exit_with(USAGE);
+    }
+
+    let pubkey = |arg: &str| Pubkey::from_str(arg).unwrap_or_else(|_| exit_with(&format!("invalid pubkey: {}", arg)));
+    let secs = |arg: Option<&String>, default: Duration| match arg {
+        Some(arg) => Duration::from_secs(arg.parse().unwrap_or_else(|_| exit_with(&format!("invalid seconds: {}", arg)))),
+        None => default,
+    };
+
+    let payer = read_keypair_file(&args[1]).unwrap_or_else(|err| exit_with(&format!("reading {}: {}", args[1], err)));
+    let mut config = KeeperConfig::new(pubkey(&args[2]), pubkey(&args[3]), pubkey(&args[4]));
+    config.funding_interval = secs(args.get(5), config.funding_interval);
+    config.liquidation_interval = secs(args.get(6), config.liquidation_interval);
+
+    let rpc = RpcClient::new_with_commitment(args[0].clone(), CommitmentConfig::confirmed());
+    Keeper::new(rpc, payer, config).run_until(|| false);
+}
+
+fn exit_with(message: &str) -> ! {
+    eprintln!("{}", message);
+    process::exit(2);
+}
@@ -0,0 +1,456 @@
+//! Off-chain helpers for keepers and indexers (`client` feature)
+//!
+//! Fetches and decodes positions, markets, market metrics, funding and price
+//! histories and the config over RPC, picks out the positions a price would
+//! make liquidatable and builds the `liquidate` transactions for them: the core
+//! loop of a liquidation bot. Also builds the other keeper instructions,
+//! `update_funding` and `flag_for_liquidation`, and checks that the vault still
+//! holds what every market, position, pool and user account is tracked as owning.
+//! Never compiled into the program.
+//!
+//! The liquidatable check runs the program's own math on copies of the
+//! accounts, but counts quote collateral only: a position also backed by
+//! collateral assets may be flagged and then rejected on-chain. Cross-margined
+//! positions are skipped, as their health depends on the whole user account.
+
+use std::fmt;
+
+use borsh::BorshDeserialize;
+
+use solana_account_decoder::UiAccountEncoding;
+use solana_client::{
+    client_error::ClientError as RpcError,
+    rpc_client::RpcClient,
+    rpc_config::{RpcAccountInfoConfig, RpcProgramAccountsConfig},
+    rpc_filter::{Memcmp, RpcFilterType},
+};
+use solana_program::{
+    hash::Hash,
+    instruction::{AccountMeta, Instruction},
+    program_error::ProgramError,
+    pubkey::Pubkey,
+    sysvar,
+};
+use solana_sdk::{
+    signature::{Keypair, Signer},
+    transaction::Transaction,
+};
+
+use crate::access::liquidator_registry_address;
+use crate::funding_history::{funding_history_address, FundingHistory};
+use crate::instruction::PerpsInstruction;
+use crate::market_metrics::{market_metrics_address, MarketMetrics};
+use crate::cross_margin::UserAccount;
+use crate::liquidity_pool::PoolState;
+use crate::math::{from_precision, PRECISION};
+use crate::price_history::{price_history_address, PriceHistory};
+use crate::{
+    calculate_isolated_liquidation_health, calculate_pending_funding, load_account, settle_liquidation_funding, vault_pda,
+    AccountType, Config, MarketState, Position, Referrer, CONFIG_SEED, POSITION_SEED,
+};
+
+/// Failure fetching or decoding program accounts
+#[derive(Debug)]
+pub enum ClientError {
+    /// The RPC request failed
+    Rpc(Box<RpcError>),
+    /// The account isn't a current program account of the expected type
+    Account(ProgramError),
+}
+
+impl fmt::Display for ClientError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ClientError::Rpc(err) => write!(f, "RPC error: {}", err),
+            ClientError::Account(err) => write!(f, "invalid account: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for ClientError {}
+
+impl From<RpcError> for ClientError {
+    fn from(err: RpcError) -> Self {
+        ClientError::Rpc(Box::new(err))
+    }
+}
+
+impl From<ProgramError> for ClientError {
+    fn from(err: ProgramError) -> Self {
+        ClientError::Account(err)
+    }
+}
+
+/// Decode a position account's data; outdated layouts are rejected until migrated
+pub fn decode_position(data: &[u8]) -> Result<Position, ProgramError> {
+    Position::load(data).copied()
+}
+
+/// Decode a market state account's data; outdated layouts are rejected until migrated
+pub fn decode_market_state(data: &[u8]) -> Result<MarketState, ProgramError> {
+    MarketState::load(data).copied()
+}
+
+/// Config PDA of the program
+pub fn config_address(program_id: &Pubkey) -> Pubkey {
+    Pubkey::find_program_address(&[CONFIG_SEED], program_id).0
+}
+
+/// Fetch the program's config
+pub fn fetch_config(rpc: &RpcClient, program_id: &Pubkey) -> Result<Config, ClientError> {
+    Ok(load_account::<Config>(&rpc.get_account_data(&config_address(program_id))?)?)
+}
+
+/// Fetch a market's state
+pub fn fetch_market_state(rpc: &RpcClient, market: &Pubkey) -> Result<MarketState, ClientError> {
+    Ok(decode_market_state(&rpc.get_account_data(market)?)?)
+}
+
+/// Fetch a market's cumulative metrics, for dashboards
+pub fn fetch_market_metrics(rpc: &RpcClient, program_id: &Pubkey, market: &Pubkey) -> Result<MarketMetrics, ClientError> {
+    let (metrics, _) = market_metrics_address(program_id, market);
+    Ok(load_account::<MarketMetrics>(&rpc.get_account_data(&metrics)?)?)
+}
+
+/// Fetch a market's funding rate history
+pub fn fetch_funding_history(rpc: &RpcClient, program_id: &Pubkey, market: &Pubkey) -> Result<FundingHistory, ClientError> {
+    let (history, _) = funding_history_address(program_id, market);
+    Ok(load_account::<FundingHistory>(&rpc.get_account_data(&history)?)?)
+}
+
+/// Fetch a market's hourly mark price candles
+pub fn fetch_price_history(rpc: &RpcClient, program_id: &Pubkey, market: &Pubkey) -> Result<PriceHistory, ClientError> {
+    let (history, _) = price_history_address(program_id, market);
+    Ok(load_account::<PriceHistory>(&rpc.get_account_data(&history)?)?)
+}
+
+/// Fetch every current-layout position in `market`, keyed by address. Positions don't
+/// record their market, so all of the program's positions are fetched and matched by PDA.
+pub fn fetch_positions(
+    rpc: &RpcClient,
+    program_id: &Pubkey,
+    market: &Pubkey,
+) -> Result<Vec<(Pubkey, Position)>, ClientError> {
+    Ok(fetch_program_accounts::<Position>(rpc, program_id)?
+        .into_iter()
+        .filter_map(|(key, data)| Some((key, decode_position(&data).ok()?)))
+        .filter(|(key, position)| is_market_position(program_id, key, market, position))
+        .collect())
+}
+
+/// Whether `key` is `position`'s PDA in `market` (checked quietly, unlike the program's
+/// `validate_position_address`, since most scanned positions belong to other markets)
+fn is_market_position(program_id: &Pubkey, key: &Pubkey, market: &Pubkey, position: &Position) -> bool {
+    Pubkey::create_program_address(
+        &[
+            POSITION_SEED,
+            market.as_ref(),
+            position.owner.as_ref(),
+            &position.sub_account_id.to_le_bytes(),
+            &[position.bump],
+        ],
+        program_id,
+    )
+    .is_ok_and(|expected| expected == *key)
+}
+
+/// An isolated position's collateral ratio and health factor as `check_health` would report
+/// them if the market's mark were `price`, counting only its quote collateral; none for flat
+/// and cross-margined positions
+pub fn liquidation_health(
+    position: &Position,
+    market_state: &MarketState,
+    config: &Config,
+    price: u64,
+) -> Result<Option<(u64, u64)>, ProgramError> {
+    if position.base_amount == 0 || position.is_cross_margin() {
+        return Ok(None);
+    }
+
+    let (mut position, mut market_state) = (*position, MarketState { mark_price: price, ..*market_state });
+    settle_liquidation_funding(&mut position, &mut market_state)?;
+    calculate_isolated_liquidation_health(&position, position.collateral, &market_state, config.min_collateral_ratio).map(Some)
+}
+
+/// Whether `liquidate` would accept an isolated position if the market's mark were `price`,
+/// counting only its quote collateral. A throttled market rejects a position partially
+/// liquidated in the current slot until `next_liquidation_slot`, and a market with a grace
+/// period one above its floor ratio until its grace period after `flag_for_liquidation`;
+/// this checks neither.
+pub fn is_liquidatable(
+    position: &Position,
+    market_state: &MarketState,
+    config: &Config,
+    price: u64,
+) -> Result<bool, ProgramError> {
+    Ok(liquidation_health(position, market_state, config, price)?.is_some_and(|(_, health_factor)| health_factor < PRECISION))
+}
+
+/// The positions among `positions` that would be liquidatable at `price`
+pub fn liquidatable_positions<'a>(
+    positions: &'a [(Pubkey, Position)],
+    market_state: &MarketState,
+    config: &Config,
+    price: u64,
+) -> Vec<&'a (Pubkey, Position)> {
+    positions
+        .iter()
+        .filter(|(_, position)| is_liquidatable(position, market_state, config, price).unwrap_or(false))
+        .collect()
+}
+
+/// The program's tracked quote holdings against the vault's balance
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VaultSolvency {
+    /// Quote tokens the vault holds
+    pub vault_balance: u64,
+    /// Quote tokens the program's accounts are tracked as owning (see `VaultHoldings::tracked_collateral`)
+    pub tracked_collateral: u64,
+}
+
+impl VaultSolvency {
+    /// Whether the vault covers everything tracked
+    pub fn is_solvent(&self) -> bool {
+        self.vault_balance >= self.tracked_collateral
+    }
+}
+
+/// A market and its positions, as fetched for `VaultHoldings`
+#[derive(Debug, Clone)]
+pub struct MarketHoldings {
+    /// Market state account address
+    pub market: Pubkey,
+    /// The market's state
+    pub market_state: MarketState,
+    /// The market's positions, keyed by address
+    pub positions: Vec<(Pubkey, Position)>,
+}
+
+/// Every current-layout program account that holds quote in the shared vault
+#[derive(Debug, Clone, Default)]
+pub struct VaultHoldings {
+    /// Every market, with its positions
+    pub markets: Vec<MarketHoldings>,
+    /// Every LP pool
+    pub pools: Vec<PoolState>,
+    /// Every cross-margin user account
+    pub user_accounts: Vec<UserAccount>,
+    /// Every referrer
+    pub referrers: Vec<Referrer>,
+}
+
+impl VaultHoldings {
+    /// Quote tracked as held in the vault (1e9 precision): every market's insurance fund and
+    /// fee pool, every pool's liquidity caught up on its market's realized PnL, every user
+    /// account's collateral, every referrer's claimable fees, and every position's quote
+    /// collateral, claimable funding and maker rebates net of the funding it has yet to settle.
+    ///
+    /// `update_funding` books the paying side's skew residual into the insurance fund before
+    /// the payers settle it, so pending funding is netted out of the positions that owe or are
+    /// owed it. An isolated position can't lose more than its collateral; a cross-margined
+    /// one settles against its user account, so its pending funding nets against the total.
+    pub fn tracked_collateral(&self) -> Result<u128, ProgramError> {
+        let mut tracked: i128 = 0;
+        for MarketHoldings { market_state, positions, .. } in &self.markets {
+            tracked += i128::from(market_state.insurance_fund) + i128::from(market_state.fee_pool);
+            for (_, position) in positions {
+                let held = i128::from(position.collateral) - i128::from(calculate_pending_funding(position, market_state)?);
+                let held = if position.is_cross_margin() { held } else { held.max(0) };
+                tracked += held + i128::from(position.claimable_funding) + i128::from(position.maker_rebates);
+            }
+        }
+        for pool in &self.pools {
+            let mut pool = pool.clone();
+            if let Some(market) = self.markets.iter().find(|market| market.market == pool.market) {
+                pool.sync(&market.market_state)?;
+            }
+            tracked += i128::from(pool.liquidity);
+        }
+        tracked += self.user_accounts.iter().map(|account| i128::from(account.collateral)).sum::<i128>();
+        tracked += self.referrers.iter().map(|referrer| i128::from(referrer.claimable_fees)).sum::<i128>();
+
+        Ok(u128::try_from(tracked).unwrap_or(0))
+    }
+}
+
+/// Raw data of every account of type `T` the program owns at its current size, keyed by address
+fn fetch_program_accounts<T: AccountType>(rpc: &RpcClient, program_id: &Pubkey) -> Result<Vec<(Pubkey, Vec<u8>)>, ClientError> {
+    let config = RpcProgramAccountsConfig {
+        filters: Some(vec![
+            RpcFilterType::DataSize(T::SPACE as u64),
+            RpcFilterType::Memcmp(Memcmp::new_raw_bytes(0, T::DISCRIMINATOR.to_vec())),
+        ]),
+        account_config: RpcAccountInfoConfig { encoding: Some(UiAccountEncoding::Base64), ..Default::default() },
+        ..Default::default()
+    };
+
+    Ok(rpc
+        .get_program_accounts_with_config(program_id, config)?
+        .into_iter()
+        .map(|(key, account)| (key, account.data))
+        .collect())
+}
+
+/// Decode fetched Borsh accounts of type `T`, skipping any that don't decode
+fn decode_accounts<T: BorshDeserialize + AccountType>(accounts: Vec<(Pubkey, Vec<u8>)>) -> Vec<T> {
+    accounts.into_iter().filter_map(|(_, data)| load_account::<T>(&data).ok()).collect()
+}
+
+/// Fetch every account holding quote in the program's vault. Positions don't record their
+/// market, so each is matched to one by PDA.
+pub fn fetch_vault_holdings(rpc: &RpcClient, program_id: &Pubkey) -> Result<VaultHoldings, ClientError> {
+    let positions: Vec<(Pubkey, Position)> = fetch_program_accounts::<Position>(rpc, program_id)?
+        .into_iter()
+        .filter_map(|(key, data)| Some((key, decode_position(&data).ok()?)))
+        .collect();
+    let markets = fetch_program_accounts::<MarketState>(rpc, program_id)?
+        .into_iter()
+        .filter_map(|(market, data)| {
+            let market_state = decode_market_state(&data).ok()?;
+            let positions = positions
+                .iter()
+                .filter(|(key, position)| is_market_position(program_id, key, &market, position))
+                .copied()
+                .collect();
+            Some(MarketHoldings { market, market_state, positions })
+        })
+        .collect();
+
+    Ok(VaultHoldings {
+        markets,
+        pools: decode_accounts(fetch_program_accounts::<PoolState>(rpc, program_id)?),
+        user_accounts: decode_accounts(fetch_program_accounts::<UserAccount>(rpc, program_id)?),
+        referrers: decode_accounts(fetch_program_accounts::<Referrer>(rpc, program_id)?),
+    })
+}
+
+/// Compare the vault's balance with everything the program tracks as held in it, across
+/// every market, pool, user account and referrer (see `VaultHoldings::tracked_collateral`)
+pub fn check_vault_solvency(rpc: &RpcClient, program_id: &Pubkey) -> Result<VaultSolvency, ClientError> {
+    let config = fetch_config(rpc, program_id)?;
+    let holdings = fetch_vault_holdings(rpc, program_id)?;
+    let vault = rpc.get_token_account_balance(&vault_pda(program_id, config.vault_bump)?)?;
+    let vault_balance = vault.amount.parse().map_err(|_| ProgramError::InvalidAccountData)?;
+    let tracked = u64::try_from(holdings.tracked_collateral()?).unwrap_or(u64::MAX);
+
+    Ok(VaultSolvency { vault_balance, tracked_collateral: from_precision(tracked, vault.decimals)? })
+}
+
+/// Oracles of the collateral assets `position` holds, in asset order
+fn held_asset_oracles(position: &Position, config: &Config) -> Vec<AccountMeta> {
+    config
+        .collateral_assets
+        .iter()
+        .zip(position.collateral_balances)
+        .filter(|(_, balance)| *balance > 0)
+        .map(|(asset, _)| AccountMeta::new_readonly(asset.oracle, false))
+        .collect()
+}
+
+/// `update_funding` instruction paying the keeper reward to `keeper_token_account`
+pub fn update_funding_instruction(
+    program_id: &Pubkey,
+    keeper_token_account: &Pubkey,
+    market: &Pubkey,
+    market_state: &MarketState,
+    config: &Config,
+) -> Result<Instruction, ProgramError> {
+    let accounts = vec![
+        AccountMeta::new(*market, false),
+        AccountMeta::new_readonly(sysvar::clock::id(), false),
+        AccountMeta::new_readonly(config_address(program_id), false),
+        AccountMeta::new_readonly(config.token_program, false),
+        AccountMeta::new(*keeper_token_account, false),
+        AccountMeta::new(vault_pda(program_id, market_state.bump)?, false),
+        AccountMeta::new_readonly(market_state.quote_mint, false),
+    ];
+
+    Ok(Instruction::new_with_bytes(*program_id, &PerpsInstruction::UpdateFunding.pack(), accounts))
+}
+
+/// `flag_for_liquidation` instruction for an isolated position, passing the oracles of the
+/// collateral assets it holds
+pub fn flag_for_liquidation_instruction(
+    program_id: &Pubkey,
+    position_key: &Pubkey,
+    position: &Position,
+    market: &Pubkey,
+    config: &Config,
+) -> Instruction {
+    let mut accounts = vec![
+        AccountMeta::new(*position_key, false),
+        AccountMeta::new_readonly(*market, false),
+        AccountMeta::new_readonly(sysvar::clock::id(), false),
+        AccountMeta::new_readonly(config_address(program_id), false),
+    ];
+    accounts.extend(held_asset_oracles(position, config));
+
+    Instruction::new_with_bytes(*program_id, &PerpsInstruction::FlagForLiquidation.pack(), accounts)
+}
+
+/// `liquidate` instruction for an isolated position, passing the oracles of the collateral
+/// assets it holds and, while liquidators are restricted, the liquidator registry
+#[allow(clippy::too_many_arguments)]
+pub fn liquidate_instruction(
+    program_id: &Pubkey,
+    liquidator: &Pubkey,
+    liquidator_token_account: &Pubkey,
+    position_key: &Pubkey,
+    position: &Position,
+    market: &Pubkey,
+    market_state: &MarketState,
+    config: &Config,
+) -> Result<Instruction, ProgramError> {
+    let mut accounts = vec![
+        AccountMeta::new_readonly(*liquidator, true),
+        AccountMeta::new_readonly(config.token_program, false),
+        AccountMeta::new(*liquidator_token_account, false),
+        AccountMeta::new(vault_pda(program_id, market_state.bump)?, false),
+        AccountMeta::new(*position_key, false),
+        AccountMeta::new(*market, false),
+        AccountMeta::new_readonly(sysvar::clock::id(), false),
+        AccountMeta::new_readonly(config_address(program_id), false),
+        AccountMeta::new_readonly(market_state.quote_mint, false),
+    ];
+    accounts.extend(held_asset_oracles(position, config));
+    if config.restrict_liquidators {
+        accounts.push(AccountMeta::new_readonly(liquidator_registry_address(program_id).0, false));
+    }
+
+    Ok(Instruction::new_with_bytes(*program_id, &PerpsInstruction::Liquidate.pack(), accounts))
+}
+
+/// One signed `liquidate` transaction per position, paid for by the liquidator
+#[allow(clippy::too_many_arguments)]
+pub fn liquidate_transactions(
+    program_id: &Pubkey,
+    liquidator: &Keypair,
+    liquidator_token_account: &Pubkey,
+    positions: &[&(Pubkey, Position)],
+    market: &Pubkey,
+    market_state: &MarketState,
+    config: &Config,
+    recent_blockhash: Hash,
+) -> Result<Vec<Transaction>, ProgramError> {
+    positions
+        .iter()
+        .map(|(position_key, position)| {
+            let liquidate = liquidate_instruction(
+                program_id,
+                &liquidator.pubkey(),
+                liquidator_token_account,
+                position_key,
+                position,
+                market,
+                market_state,
+                config,
+            )?;
+            Ok(Transaction::new_signed_with_payer(
+                &[liquidate],
+                Some(&liquidator.pubkey()),
+                &[liquidator],
+                recent_blockhash,
+            ))
+        })
+        .collect()
+}
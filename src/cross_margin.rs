@@ -0,0 +1,836 @@
+//! Cross-margin user accounts
+//!
+//! A UserAccount ([USER_ACCOUNT_SEED, owner]) holds quote collateral shared by
+//! several positions, possibly in different markets, alongside the default
+//! isolated mode where each position carries its own collateral. Margin is
+//! checked across every linked position at once: the account's equity
+//! (collateral plus the positions' unrealized PnL, net of pending funding) is
+//! compared against their combined notional. Markets with risk tiers raise the
+//! ratio the account must keep, weighting each position's required ratio by its
+//! notional.
+//!
+//! A position joins an account through `set_margin_mode` (or the cross-margin
+//! flag of `open_position` when it is created) and from then on settles funding
+//! and fees against the shared collateral.
+
+use borsh::{BorshDeserialize, BorshSerialize};
+use shank::ShankAccount;
+use solana_program::{
+    account_info::{next_account_info, AccountInfo},
+    clock::Clock,
+    entrypoint::ProgramResult,
+    msg,
+    program::{invoke, invoke_signed},
+    program_error::ProgramError,
+    pubkey::Pubkey,
+    system_instruction,
+    sysvar::{rent::Rent, Sysvar},
+};
+
+use crate::math::{self, PRECISION};
+use crate::{
+    calculate_pending_funding, calculate_unrealized_pnl, create_transfer_checked_instruction, load_account, load_config, release_deposits,
+    require_hold_elapsed, require_not_settlement_only, require_position_owner, require_vault_covers, store_account, validate_position_address, validate_quote_mint, validate_token_account, validate_vault, AccountType, MarketState, Position,
+    DISCRIMINATOR_LEN, MARGIN_MODE_CROSS, MARGIN_MODE_ISOLATED, PDA_SEED,
+};
+
+/// Seed prefix for user account PDAs: [USER_ACCOUNT_SEED, owner]
+pub const USER_ACCOUNT_SEED: &[u8] = b"user_account";
+
+/// Max number of positions a user account can back
+pub const MAX_CROSS_MARGIN_POSITIONS: usize = 8;
+
+/// A position backed by a user account and the market it trades in
+#[derive(BorshSerialize, BorshDeserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct CrossPosition {
+    /// Position account
+    pub position: Pubkey,
+    /// Market state account the position trades in
+    pub market: Pubkey,
+}
+
+impl CrossPosition {
+    /// Serialized size: position + market
+    pub const LEN: usize = 32 + 32;
+}
+
+/// Shared collateral backing a user's cross-margined positions
+#[derive(BorshSerialize, BorshDeserialize, Debug, Default, Clone, ShankAccount)]
+pub struct UserAccount {
+    /// Wallet that owns the account and its positions
+    pub owner: Pubkey,
+    /// Quote collateral shared by every linked position
+    pub collateral: u64,
+    /// Positions margined against `collateral`
+    pub positions: Vec<CrossPosition>,
+    /// PDA bump for [USER_ACCOUNT_SEED, owner]
+    pub bump: u8,
+}
+
+impl AccountType for UserAccount {
+    const DISCRIMINATOR: [u8; DISCRIMINATOR_LEN] = *b"useracct";
+    /// owner + collateral + vec length + MAX_CROSS_MARGIN_POSITIONS entries + bump
+    const LEN: usize = 32 + 8 + 4 + MAX_CROSS_MARGIN_POSITIONS * CrossPosition::LEN + 1;
+}
+
+/// A cross-margin account's equity and exposure, netted across every market
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct PortfolioHealth {
+    /// Collateral plus unrealized PnL, net of pending funding (quote token, saturating)
+    pub equity: i64,
+    /// Combined notional at each market's mark price (quote token, saturating)
+    pub notional: u64,
+    /// Equity over notional (1e9 precision, u64::MAX with no exposure, 0 at negative equity)
+    pub collateral_ratio: u64,
+    /// Index of the position with the lowest net PnL, if any has exposure
+    pub weakest: Option<usize>,
+}
+
+/// Net the collateral and every position's unrealized PnL into one portfolio
+///
+/// Equity is `collateral` plus each position's unrealized PnL minus the funding
+/// it owes since its last settlement; the ratio is equity over the combined
+/// notional at each market's mark price. The weakest position is the one whose
+/// PnL net of funding is lowest, the first one on ties.
+pub fn calculate_portfolio_health(
+    collateral: u64,
+    positions: &[(Position, MarketState)],
+) -> Result<PortfolioHealth, ProgramError> {
+    let mut equity = collateral as i128;
+    let mut notional: u128 = 0;
+    let mut weakest: Option<(usize, i128)> = None;
+
+    for (index, (position, market_state)) in positions.iter().enumerate() {
+        if position.base_amount == 0 {
+            continue;
+        }
+
+        let net_pnl = net_pnl(position, market_state)?;
+        equity = equity.checked_add(net_pnl).ok_or(ProgramError::InvalidArgument)?;
+        notional = notional
+            .checked_add(position_notional(position, market_state)?)
+            .ok_or(ProgramError::InvalidArgument)?;
+
+        if !matches!(weakest, Some((_, weakest_pnl)) if weakest_pnl <= net_pnl) {
+            weakest = Some((index, net_pnl));
+        }
+    }
+
+    let collateral_ratio = match notional {
+        0 => u64::MAX,
+        _ if equity <= 0 => 0,
+        notional => math::ratio(equity.unsigned_abs(), notional),
+    };
+
+    Ok(PortfolioHealth {
+        equity: equity.clamp(i64::MIN as i128, i64::MAX as i128) as i64,
+        notional: math::saturating_to_u64(notional),
+        collateral_ratio,
+        weakest: weakest.map(|(index, _)| index),
+    })
+}
+
+/// Collateral ratio of a cross-margin account (1e9 precision)
+///
+/// See [`calculate_portfolio_health`]. No exposure = perfect health, and
+/// negative equity reads as zero.
+pub fn calculate_cross_margin_health(
+    collateral: u64,
+    positions: &[(Position, MarketState)],
+) -> Result<u64, ProgramError> {
+    Ok(calculate_portfolio_health(collateral, positions)?.collateral_ratio)
+}
+
+/// Collateral ratio a cross-margin account must keep (1e9 precision)
+///
+/// Each position requires its market's ratio for its notional at the mark (see
+/// `MarketState::required_collateral_ratio`); the account requires their average
+/// weighted by notional, so equity must cover the sum of the positions'
+/// requirements. `min_collateral_ratio` with no exposure.
+pub fn calculate_required_collateral_ratio(
+    positions: &[(Position, MarketState)],
+    min_collateral_ratio: u64,
+) -> Result<u64, ProgramError> {
+    let mut notional: u128 = 0;
+    let mut requirement: u128 = 0;
+    for (position, market_state) in positions {
+        let position_notional = position_notional(position, market_state)?;
+        let required_ratio = market_state.required_collateral_ratio(math::saturating_to_u64(position_notional), min_collateral_ratio);
+        notional = notional.checked_add(position_notional).ok_or(ProgramError::InvalidArgument)?;
+        requirement = requirement
+            .checked_add(math::mul_div(position_notional, required_ratio.into(), PRECISION.into())?)
+            .ok_or(ProgramError::InvalidArgument)?;
+    }
+
+    if notional == 0 {
+        return Ok(min_collateral_ratio);
+    }
+
+    math::to_u64(math::mul_div(requirement, PRECISION.into(), notional)?)
+}
+
+/// Unrealized PnL of `position` at the mark, minus the funding it owes
+fn net_pnl(position: &Position, market_state: &MarketState) -> Result<i128, ProgramError> {
+    let unrealized_pnl = calculate_unrealized_pnl(position, market_state.mark_price)? as i128;
+    Ok(unrealized_pnl - i128::from(calculate_pending_funding(position, market_state)?))
+}
+
+/// |base_amount| * mark_price (1e9 precision)
+fn position_notional(position: &Position, market_state: &MarketState) -> Result<u128, ProgramError> {
+    math::mul_div(position.base_amount.unsigned_abs().into(), market_state.mark_price.into(), PRECISION.into())
+}
+
+/// A cross-margined position's user account, plus the other positions it backs
+///
+/// Handlers hold the traded position and its market mutably borrowed, so those
+/// are passed in at check time; every other linked position is read from the
+/// accounts that follow the user account.
+pub(crate) struct CrossMargin<'a, 'info> {
+    /// The backing account; funding, fees and penalties settle against its collateral
+    pub user_account: UserAccount,
+    user_account_acc: &'a AccountInfo<'info>,
+    /// Other linked positions; a `None` market is the traded position's own market
+    others: Vec<(Position, Option<MarketState>)>,
+}
+
+impl<'a, 'info> CrossMargin<'a, 'info> {
+    /// Load the user account backing `position` from the front of `accs`
+    ///
+    /// `accs` starts with the user account, followed by the position and market
+    /// state account of every other linked position, in the account's order.
+    /// With `link`, a position the account doesn't list yet is added to it.
+    /// Returns the context and the number of accounts it consumed.
+    pub(crate) fn load(
+        program_id: &Pubkey,
+        position_key: &Pubkey,
+        position: &Position,
+        market_key: &Pubkey,
+        accs: &[&'a AccountInfo<'info>],
+        link: bool,
+    ) -> Result<(Self, usize), ProgramError> {
+        let user_account_acc = *accs.first().ok_or_else(|| {
+            msg!("Missing user account for cross-margined position");
+            ProgramError::NotEnoughAccountKeys
+        })?;
+        let mut user_account = load_user_account(program_id, user_account_acc, &position.owner)?;
+
+        match user_account.positions.iter().find(|linked| linked.position == *position_key) {
+            Some(linked) if linked.market != *market_key => {
+                msg!("Position is cross-margined in market {}, not {}", linked.market, market_key);
+                return Err(ProgramError::InvalidArgument);
+            }
+            Some(_) => {}
+            None if link => link_position(&mut user_account, position_key, market_key)?,
+            None => {
+                msg!("Position {} is not linked to the user account", position_key);
+                return Err(ProgramError::InvalidArgument);
+            }
+        }
+
+        let mut others = Vec::with_capacity(user_account.positions.len());
+        let mut used = 1;
+        for linked in user_account.positions.iter().filter(|linked| linked.position != *position_key) {
+            let (position_acc, market_state_acc) = match accs.get(used..used + 2) {
+                Some(pair) => (pair[0], pair[1]),
+                None => {
+                    msg!("Missing position and market accounts for linked position {}", linked.position);
+                    return Err(ProgramError::NotEnoughAccountKeys);
+                }
+            };
+            used += 2;
+
+            others.push(load_linked_position(program_id, linked, position_acc, market_state_acc, Some(market_key))?);
+        }
+
+        Ok((Self { user_account, user_account_acc, others }, used))
+    }
+
+    /// Every linked position, with the traded one in its current state
+    fn positions(&self, position: &Position, market_state: &MarketState) -> Vec<(Position, MarketState)> {
+        self.others
+            .iter()
+            .map(|(other, other_market)| (*other, other_market.unwrap_or(*market_state)))
+            .chain(std::iter::once((*position, *market_state)))
+            .collect()
+    }
+
+    /// Reject a change that leaves the account below the ratio its positions require (at least
+    /// `min_collateral_ratio`), or above `max_leverage` when the position grew
+    pub(crate) fn validate(
+        &self,
+        position: &Position,
+        market_state: &MarketState,
+        min_collateral_ratio: u64,
+        max_leverage: u64,
+        is_reduction: bool,
+    ) -> ProgramResult {
+        let positions = self.positions(position, market_state);
+        let health = calculate_cross_margin_health(self.user_account.collateral, &positions)?;
+        let required_ratio = calculate_required_collateral_ratio(&positions, min_collateral_ratio)?;
+        if health < required_ratio {
+            msg!("Insufficient cross-margin ratio: {} < {}", health, required_ratio);
+            return Err(ProgramError::InsufficientFunds);
+        }
+
+        // Leverage (notional / equity) is the inverse of the collateral ratio
+        if !is_reduction {
+            let leverage = math::ratio(PRECISION.into(), health.into());
+            if leverage > max_leverage {
+                msg!("Cross-margin leverage too high: {} > {}", leverage, max_leverage);
+                return Err(ProgramError::InsufficientFunds);
+            }
+        }
+
+        msg!("Cross-margin ratio: {}", health);
+
+        Ok(())
+    }
+
+    /// Combined notional of every linked position at its market's mark, the traded one
+    /// in its current state (1e9 precision, saturating)
+    pub(crate) fn notional(&self, position: &Position, market_state: &MarketState) -> Result<u64, ProgramError> {
+        Ok(calculate_portfolio_health(self.user_account.collateral, &self.positions(position, market_state))?.notional)
+    }
+
+    /// Run a position-collateral update against the shared collateral instead
+    fn with_shared_collateral<R>(&mut self, position: &mut Position, update: impl FnOnce(&mut Position) -> R) -> R {
+        position.collateral = self.user_account.collateral;
+        let result = update(position);
+        self.user_account.collateral = position.collateral;
+        position.collateral = 0;
+        result
+    }
+
+    /// Settle the position's pending funding against the shared collateral, capped at
+    /// `max_funding_share` of it (0 = no cap)
+    pub(crate) fn apply_funding(
+        &mut self,
+        position: &mut Position,
+        market_state: &mut MarketState,
+        max_funding_share: u64,
+    ) -> ProgramResult {
+        self.with_shared_collateral(position, |position| crate::apply_funding(position, market_state, max_funding_share))
+    }
+
+    /// Apply a filled size change, realizing PnL against the shared collateral
+    pub(crate) fn apply_position_change(
+        &mut self,
+        position: &mut Position,
+        market_state: &mut MarketState,
+        base_delta: i64,
+        fill_price: u64,
+    ) -> ProgramResult {
+        self.with_shared_collateral(position, |position| {
+            crate::apply_position_change(position, market_state, base_delta, fill_price)
+        })
+    }
+
+    /// Realize PnL against the shared collateral
+    pub(crate) fn settle_realized_pnl(&mut self, position: &mut Position, market_state: &mut MarketState, pnl: i64) -> ProgramResult {
+        self.with_shared_collateral(position, |position| crate::settle_realized_pnl(position, market_state, pnl))
+    }
+
+    /// The account's cross-margin ratio at the mark prices and its health factor: the ratio
+    /// over the one its positions require (1e9 precision), below 1e9 when it is liquidatable.
+    /// When any market checks its mark price EMA, positions are also valued at their EMAs
+    /// and the better of the two factors counts.
+    pub(crate) fn liquidation_health(
+        &self,
+        position: &Position,
+        market_state: &MarketState,
+        min_collateral_ratio: u64,
+    ) -> Result<(u64, u64), ProgramError> {
+        let positions = self.positions(position, market_state);
+        let collateral_ratio = calculate_cross_margin_health(self.user_account.collateral, &positions)?;
+        let required_ratio = calculate_required_collateral_ratio(&positions, min_collateral_ratio)?;
+        let mut health_factor = math::ratio(collateral_ratio.into(), required_ratio.into());
+
+        if positions.iter().any(|(_, market)| market.liquidation_ema_price().is_some()) {
+            let ema_positions: Vec<(Position, MarketState)> = positions
+                .iter()
+                .map(|(other, market)| {
+                    let mark_price = market.liquidation_ema_price().unwrap_or(market.mark_price);
+                    (*other, MarketState { mark_price, ..*market })
+                })
+                .collect();
+            let ema_ratio = calculate_cross_margin_health(self.user_account.collateral, &ema_positions)?;
+            let ema_required_ratio = calculate_required_collateral_ratio(&ema_positions, min_collateral_ratio)?;
+            health_factor = health_factor.max(math::ratio(ema_ratio.into(), ema_required_ratio.into()));
+        }
+
+        Ok((collateral_ratio, health_factor))
+    }
+
+    /// Check the account is liquidatable and settle the position's funding
+    ///
+    /// Liquidation works through the account weakest position first: the
+    /// position must not have a higher net PnL than any other linked one.
+    /// Returns the account's collateral ratio.
+    pub(crate) fn check_liquidatable(
+        &mut self,
+        position: &mut Position,
+        market_state: &mut MarketState,
+        min_collateral_ratio: u64,
+    ) -> Result<u64, ProgramError> {
+        let (collateral_ratio, health_factor) = self.liquidation_health(position, market_state, min_collateral_ratio)?;
+        if health_factor >= PRECISION {
+            msg!("Account is not liquidatable. Cross-margin ratio: {}, health factor: {}", collateral_ratio, health_factor);
+            return Err(ProgramError::InvalidArgument);
+        }
+
+        let positions = self.positions(position, market_state);
+
+        // The traded position is last, so it only loses to a strictly weaker one
+        let position_pnl = net_pnl(position, market_state)?;
+        for (other, other_market) in &positions[..positions.len() - 1] {
+            if other.base_amount != 0 && net_pnl(other, other_market)? < position_pnl {
+                msg!("A weaker linked position must be liquidated first");
+                return Err(ProgramError::InvalidArgument);
+            }
+        }
+
+        // Socialized loss and funding owed beyond the collateral are bad debt, as for
+        // isolated liquidations
+        self.with_shared_collateral(position, |position| crate::apply_socialized_loss(position, market_state))?;
+        let funding = calculate_pending_funding(position, market_state)?;
+        if funding > 0 {
+            crate::charge_funding_shortfall(&mut self.user_account.collateral, market_state, funding.unsigned_abs())?;
+        } else {
+            self.with_shared_collateral(position, |position| crate::credit_funding(position, funding.unsigned_abs()))?;
+        }
+        position.last_funding_index = market_state.funding_index;
+        position.pending_funding = 0;
+
+        Ok(collateral_ratio)
+    }
+
+    /// Write the user account back
+    pub(crate) fn store(&self) -> ProgramResult {
+        store_account(&self.user_account, &mut self.user_account_acc.data.borrow_mut())
+    }
+}
+
+// ---------------------------------------------------------------------
+// 2️⃣7️⃣ Create a cross-margin user account
+// ---------------------------------------------------------------------
+pub fn create_user_account(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramResult {
+    // Accounts:
+    // 0. [signer, writable] owner (pays for the account)
+    // 1. [writable] user account (PDA: [USER_ACCOUNT_SEED, owner])
+    // 2. [] rent sysvar
+    // 3. [] system program
+    let accounts_iter = &mut accounts.iter();
+    let owner = next_account_info(accounts_iter)?;
+    let user_account_acc = next_account_info(accounts_iter)?;
+    let rent_sysvar = next_account_info(accounts_iter)?;
+    let system_program = next_account_info(accounts_iter)?;
+
+    if !owner.is_signer {
+        msg!("Owner must be signer");
+        return Err(ProgramError::MissingRequiredSignature);
+    }
+
+    let (expected_user_account, bump) =
+        Pubkey::find_program_address(&[USER_ACCOUNT_SEED, owner.key.as_ref()], program_id);
+    if *user_account_acc.key != expected_user_account {
+        msg!("User account is not the correct PDA. Expected: {}, Got: {}",
+             expected_user_account, user_account_acc.key);
+        return Err(ProgramError::InvalidArgument);
+    }
+
+    if !user_account_acc.data_is_empty() {
+        msg!("User account already exists");
+        return Err(ProgramError::AccountAlreadyInitialized);
+    }
+
+    let rent = Rent::from_account_info(rent_sysvar)?;
+    let create_user_account_ix = system_instruction::create_account(
+        owner.key,
+        user_account_acc.key,
+        rent.minimum_balance(UserAccount::SPACE),
+        UserAccount::SPACE as u64,
+        program_id,
+    );
+
+    invoke_signed(&create_user_account_ix, &[
+        owner.clone(),
+        user_account_acc.clone(),
+        system_program.clone(),
+    ], &[&[USER_ACCOUNT_SEED, owner.key.as_ref(), &[bump]]])?;
+
+    let user_account = UserAccount {
+        owner: *owner.key,
+        collateral: 0,
+        positions: Vec::new(),
+        bump,
+    };
+    store_account(&user_account, &mut user_account_acc.data.borrow_mut())?;
+
+    msg!("User account created for: {}", owner.key);
+
+    Ok(())
+}
+
+// ---------------------------------------------------------------------
+// 2️⃣8️⃣ Deposit quote collateral into a user account
+// ---------------------------------------------------------------------
+pub fn deposit_cross_collateral(program_id: &Pubkey, accounts: &[AccountInfo], amount: u64) -> ProgramResult {
+    // Accounts:
+    // 0. [signer] owner
+    // 1. [] token program
+    // 2. [writable] owner's quote token account
+    // 3. [writable] vault token account (PDA)
+    // 4. [writable] user account
+    // 5. [] config account
+    // 6. [] quote mint
+    let accounts_iter = &mut accounts.iter();
+    let owner = next_account_info(accounts_iter)?;
+    let token_program = next_account_info(accounts_iter)?;
+    let owner_token_acc = next_account_info(accounts_iter)?;
+    let vault = next_account_info(accounts_iter)?;
+    let user_account_acc = next_account_info(accounts_iter)?;
+    let config_acc = next_account_info(accounts_iter)?;
+    let quote_mint = next_account_info(accounts_iter)?;
+
+    if !owner.is_signer {
+        msg!("Owner must be signer");
+        return Err(ProgramError::MissingRequiredSignature);
+    }
+
+    let config = load_config(program_id, config_acc)?;
+    require_not_settlement_only(&config)?;
+    let quote_decimals = validate_quote_mint(&config, token_program, quote_mint)?;
+
+    validate_vault(program_id, token_program, vault, config.vault_bump, quote_mint.key)?;
+    validate_token_account(token_program.key, owner_token_acc, quote_mint.key, Some(owner.key))?;
+
+    let mut user_account = load_owned_user_account(program_id, user_account_acc, owner)?;
+
+    if amount == 0 {
+        msg!("Deposit amount must be non-zero");
+        return Err(ProgramError::InvalidArgument);
+    }
+
+    let transfer_ix = create_transfer_checked_instruction(
+        token_program.key,
+        owner_token_acc.key,
+        quote_mint.key,
+        vault.key,
+        owner.key,
+        amount,
+        quote_decimals,
+    )?;
+
+    invoke(&transfer_ix, &[
+        owner_token_acc.clone(),
+        quote_mint.clone(),
+        vault.clone(),
+        owner.clone(),
+        token_program.clone(),
+    ])?;
+
+    user_account.collateral = user_account
+        .collateral
+        .checked_add(math::to_precision(amount, quote_decimals)?)
+        .ok_or(ProgramError::InvalidArgument)?;
+    store_account(&user_account, &mut user_account_acc.data.borrow_mut())?;
+
+    msg!("Deposited {} cross collateral, balance {}", amount, user_account.collateral);
+
+    Ok(())
+}
+
+// ---------------------------------------------------------------------
+// 2️⃣9️⃣ Withdraw quote collateral from a user account
+// ---------------------------------------------------------------------
+pub fn withdraw_cross_collateral(program_id: &Pubkey, accounts: &[AccountInfo], amount: u64) -> ProgramResult {
+    // Accounts:
+    // 0. [signer] owner
+    // 1. [] token program
+    // 2. [writable] owner's quote token account
+    // 3. [writable] vault token account (PDA)
+    // 4. [writable] user account
+    // 5. [] config account
+    // 6. [] quote mint
+    // 7..N. [writable] position and market state account of each linked position, in account order
+    let accounts_iter = &mut accounts.iter();
+    let owner = next_account_info(accounts_iter)?;
+    let token_program = next_account_info(accounts_iter)?;
+    let owner_token_acc = next_account_info(accounts_iter)?;
+    let vault = next_account_info(accounts_iter)?;
+    let user_account_acc = next_account_info(accounts_iter)?;
+    let config_acc = next_account_info(accounts_iter)?;
+    let quote_mint = next_account_info(accounts_iter)?;
+    let linked_accs: Vec<&AccountInfo> = accounts_iter.collect();
+
+    if !owner.is_signer {
+        msg!("Owner must be signer");
+        return Err(ProgramError::MissingRequiredSignature);
+    }
+
+    let config = load_config(program_id, config_acc)?;
+    let quote_decimals = validate_quote_mint(&config, token_program, quote_mint)?;
+
+    let pda = validate_vault(program_id, token_program, vault, config.vault_bump, quote_mint.key)?;
+    let bump = config.vault_bump;
+    validate_token_account(token_program.key, owner_token_acc, quote_mint.key, None)?;
+
+    let mut user_account = load_owned_user_account(program_id, user_account_acc, owner)?;
+    user_account.collateral = user_account
+        .collateral
+        .checked_sub(math::to_precision(amount, quote_decimals)?)
+        .ok_or(ProgramError::InsufficientFunds)?;
+
+    // Every linked position must be past its hold, and the remaining collateral must still cover them
+    let positions = load_cross_positions(program_id, &user_account, &linked_accs)?;
+    let clock = Clock::get()?;
+    for (position, market_state) in &positions {
+        require_hold_elapsed(position, market_state, clock.slot)?;
+    }
+    let health = calculate_cross_margin_health(user_account.collateral, &positions)?;
+    let required_ratio = calculate_required_collateral_ratio(&positions, config.min_collateral_ratio)?;
+    if health < required_ratio {
+        msg!("Insufficient cross-margin ratio: {} < {}", health, required_ratio);
+        return Err(ProgramError::InsufficientFunds);
+    }
+
+    // The shared collateral backs every linked market, so each counts the whole withdrawal
+    // toward its limits
+    for accs in linked_accs.chunks_exact(2) {
+        let mut market_state_data = accs[1].try_borrow_mut_data()?;
+        let market_state = MarketState::load_mut(&mut market_state_data)?;
+        let mut position_data = accs[0].try_borrow_mut_data()?;
+        let position = Position::load_mut(&mut position_data)?;
+        market_state.record_withdrawal(position, math::to_precision(amount, quote_decimals)?, clock.slot, clock.epoch)?;
+    }
+
+    let seeds = &[PDA_SEED, &[bump]];
+    let signer_seeds = &[&seeds[..]];
+
+    let transfer_ix = create_transfer_checked_instruction(
+        token_program.key,
+        vault.key,
+        quote_mint.key,
+        owner_token_acc.key,
+        &pda,
+        amount,
+        quote_decimals,
+    )?;
+
+    invoke_signed(&transfer_ix, &[
+        vault.clone(),
+        quote_mint.clone(),
+        owner_token_acc.clone(),
+        vault.clone(), // PDA authority
+        token_program.clone(),
+    ], signer_seeds)?;
+    require_vault_covers(vault, user_account.collateral, quote_decimals)?;
+
+    store_account(&user_account, &mut user_account_acc.data.borrow_mut())?;
+
+    msg!("Withdrew {} cross collateral, balance {}", amount, user_account.collateral);
+
+    Ok(())
+}
+
+// ---------------------------------------------------------------------
+// 3️⃣0️⃣ Switch a flat position between isolated and cross margin
+// ---------------------------------------------------------------------
+pub fn set_margin_mode(program_id: &Pubkey, accounts: &[AccountInfo], margin_mode: u8) -> ProgramResult {
+    // Accounts:
+    // 0. [signer] owner
+    // 1. [writable] position account
+    // 2. [writable] market state account the position trades in
+    // 3. [writable] user account
+    let accounts_iter = &mut accounts.iter();
+    let owner = next_account_info(accounts_iter)?;
+    let position_acc = next_account_info(accounts_iter)?;
+    let market_state_acc = next_account_info(accounts_iter)?;
+    let user_account_acc = next_account_info(accounts_iter)?;
+
+    if margin_mode != MARGIN_MODE_ISOLATED && margin_mode != MARGIN_MODE_CROSS {
+        msg!("Invalid margin mode: {}", margin_mode);
+        return Err(ProgramError::InvalidInstructionData);
+    }
+
+    let mut user_account = load_owned_user_account(program_id, user_account_acc, owner)?;
+
+    if position_acc.owner != program_id || market_state_acc.owner != program_id {
+        msg!("Position and market state must be owned by the program");
+        return Err(ProgramError::IncorrectProgramId);
+    }
+
+    let mut market_state_data = market_state_acc.try_borrow_mut_data()?;
+    let market_state = MarketState::load_mut(&mut market_state_data)?;
+    let mut position_data = position_acc.try_borrow_mut_data()?;
+    let position = Position::load_mut(&mut position_data)?;
+    require_position_owner(position, owner.key)?;
+    validate_position_address(program_id, position_acc.key, market_state_acc.key, position)?;
+
+    if position.margin_mode == margin_mode {
+        msg!("Position is already in margin mode {}", margin_mode);
+        return Err(ProgramError::InvalidArgument);
+    }
+
+    // A flat position has no funding or PnL to carry across
+    if position.base_amount != 0 || position.collateral_balances.iter().any(|&balance| balance != 0) {
+        msg!("Margin mode can only change on a flat position without asset collateral");
+        return Err(ProgramError::InvalidArgument);
+    }
+
+    if margin_mode == MARGIN_MODE_CROSS {
+        link_position(&mut user_account, position_acc.key, market_state_acc.key)?;
+        user_account.collateral = user_account
+            .collateral
+            .checked_add(position.collateral)
+            .ok_or(ProgramError::InvalidArgument)?;
+        position.collateral = 0;
+        // The shared collateral isn't counted toward any one market's total
+        release_deposits(position, market_state);
+    } else {
+        user_account.positions.retain(|linked| linked.position != *position_acc.key);
+    }
+
+    position.margin_mode = margin_mode;
+    position.last_funding_index = market_state.funding_index;
+    store_account(&user_account, &mut user_account_acc.data.borrow_mut())?;
+
+    msg!("Position {} margin mode set to {}", position_acc.key, margin_mode);
+
+    Ok(())
+}
+
+/// Load a user account and check `owner` signed for it
+fn load_owned_user_account(
+    program_id: &Pubkey,
+    user_account_acc: &AccountInfo,
+    owner: &AccountInfo,
+) -> Result<UserAccount, ProgramError> {
+    if !owner.is_signer {
+        msg!("Owner must be signer");
+        return Err(ProgramError::MissingRequiredSignature);
+    }
+
+    load_user_account(program_id, user_account_acc, owner.key)
+}
+
+/// Load a user account and check it is `owner`'s PDA
+fn load_user_account(
+    program_id: &Pubkey,
+    user_account_acc: &AccountInfo,
+    owner: &Pubkey,
+) -> Result<UserAccount, ProgramError> {
+    let user_account = load_any_user_account(program_id, user_account_acc)?;
+    if user_account.owner != *owner {
+        msg!("User account owner mismatch. Expected: {}, Got: {}", user_account.owner, owner);
+        return Err(ProgramError::IllegalOwner);
+    }
+
+    Ok(user_account)
+}
+
+/// Load a user account and check it is its owner's PDA
+fn load_any_user_account(program_id: &Pubkey, user_account_acc: &AccountInfo) -> Result<UserAccount, ProgramError> {
+    if user_account_acc.owner != program_id {
+        msg!("User account is not owned by the program");
+        return Err(ProgramError::IncorrectProgramId);
+    }
+
+    let user_account = load_account::<UserAccount>(&user_account_acc.data.borrow())?;
+
+    let expected_user_account = Pubkey::create_program_address(
+        &[USER_ACCOUNT_SEED, user_account.owner.as_ref(), &[user_account.bump]],
+        program_id,
+    )?;
+    if *user_account_acc.key != expected_user_account {
+        msg!("User account is not the correct PDA. Expected: {}, Got: {}",
+             expected_user_account, user_account_acc.key);
+        return Err(ProgramError::InvalidArgument);
+    }
+
+    Ok(user_account)
+}
+
+/// Read a user account and every position it backs, for views
+///
+/// `linked_accs` hold the position and market state account of each linked
+/// position, in the order the account lists them.
+pub(crate) fn load_portfolio(
+    program_id: &Pubkey,
+    user_account_acc: &AccountInfo,
+    linked_accs: &[&AccountInfo],
+) -> Result<(UserAccount, Vec<(Position, MarketState)>), ProgramError> {
+    let user_account = load_any_user_account(program_id, user_account_acc)?;
+    let positions = load_cross_positions(program_id, &user_account, linked_accs)?;
+    Ok((user_account, positions))
+}
+
+/// Read the (position, market state) pair of every position linked to `user_account`
+///
+/// `linked_accs` must hold exactly one position and market state account per
+/// linked position, in the order the account lists them.
+fn load_cross_positions(
+    program_id: &Pubkey,
+    user_account: &UserAccount,
+    linked_accs: &[&AccountInfo],
+) -> Result<Vec<(Position, MarketState)>, ProgramError> {
+    if linked_accs.len() != user_account.positions.len() * 2 {
+        msg!("Expected position and market accounts for {} linked positions", user_account.positions.len());
+        return Err(ProgramError::NotEnoughAccountKeys);
+    }
+
+    user_account
+        .positions
+        .iter()
+        .zip(linked_accs.chunks_exact(2))
+        .map(|(linked, accs)| {
+            let (position, market_state) = load_linked_position(program_id, linked, accs[0], accs[1], None)?;
+            Ok((position, market_state.ok_or(ProgramError::InvalidArgument)?))
+        })
+        .collect()
+}
+
+/// Read a linked position and its market after checking the accounts match the link
+///
+/// The market is left unread (`None`) when it is `borrowed_market`, which the
+/// caller already holds mutably.
+fn load_linked_position(
+    program_id: &Pubkey,
+    linked: &CrossPosition,
+    position_acc: &AccountInfo,
+    market_state_acc: &AccountInfo,
+    borrowed_market: Option<&Pubkey>,
+) -> Result<(Position, Option<MarketState>), ProgramError> {
+    if *position_acc.key != linked.position || *market_state_acc.key != linked.market {
+        msg!("Linked position accounts out of order: expected {} in {}", linked.position, linked.market);
+        return Err(ProgramError::InvalidArgument);
+    }
+
+    if position_acc.owner != program_id || market_state_acc.owner != program_id {
+        msg!("Position and market state must be owned by the program");
+        return Err(ProgramError::IncorrectProgramId);
+    }
+
+    let position = *Position::load(&position_acc.try_borrow_data()?)?;
+    let market_state = match borrowed_market {
+        Some(market_key) if *market_key == linked.market => None,
+        _ => Some(*MarketState::load(&market_state_acc.try_borrow_data()?)?),
+    };
+
+    Ok((position, market_state))
+}
+
+/// Add a position to a user account's linked list
+fn link_position(user_account: &mut UserAccount, position_key: &Pubkey, market_key: &Pubkey) -> ProgramResult {
+    if user_account.positions.len() >= MAX_CROSS_MARGIN_POSITIONS {
+        msg!("User account already backs {} positions", MAX_CROSS_MARGIN_POSITIONS);
+        return Err(ProgramError::InvalidArgument);
+    }
+
+    user_account.positions.push(CrossPosition {
+        position: *position_key,
+        market: *market_key,
+    });
+
+    Ok(())
+}
@@ -0,0 +1,143 @@
+//! Program events
+//!
+//! Events are Borsh-encoded behind an 8-byte tag and written with
+//! `sol_log_data`, so indexers can decode them from the "Program data:" lines
+//! of a transaction's logs instead of parsing `msg!` text.
+
+use borsh::BorshSerialize;
+use solana_program::{log::sol_log_data, pubkey::Pubkey};
+
+use crate::DISCRIMINATOR_LEN;
+
+/// An event the program logs for off-chain consumers
+pub trait Event: BorshSerialize {
+    /// Tag written ahead of the encoded event
+    const DISCRIMINATOR: [u8; DISCRIMINATOR_LEN];
+}
+
+/// Log `event` as tag + Borsh body
+pub fn emit<T: Event>(event: &T) {
+    let mut data = T::DISCRIMINATOR.to_vec();
+    // Writing into a Vec cannot fail
+    event.serialize(&mut data).expect("event serialization");
+    sol_log_data(&[&data]);
+}
+
+/// A market's circuit breaker tripped and it is reduce-only until `reduce_only_until_slot`
+#[derive(BorshSerialize, Debug, Clone, PartialEq, Eq)]
+pub struct CircuitBreakerTripped {
+    /// Market state account
+    pub market: Pubkey,
+    /// Index price at the start of the observation window (1e9 precision)
+    pub reference_price: u64,
+    /// Index price that tripped the breaker (1e9 precision)
+    pub index_price: u64,
+    /// Relative move from the reference price (1e9 precision)
+    pub price_move: u64,
+    /// Slot the market accepts opens again
+    pub reduce_only_until_slot: u64,
+}
+
+impl Event for CircuitBreakerTripped {
+    const DISCRIMINATOR: [u8; DISCRIMINATOR_LEN] = *b"evbreak\0";
+}
+
+/// A position's losses or funding exceeded its collateral and the shortfall became bad debt
+#[derive(BorshSerialize, Debug, Clone, PartialEq, Eq)]
+pub struct BadDebtIncurred {
+    /// Market state account
+    pub market: Pubkey,
+    /// Position that left the shortfall
+    pub position: Pubkey,
+    /// Uncollected loss (quote token, 1e9 precision)
+    pub amount: u64,
+    /// Market insurance fund after covering what it could (quote token, 1e9 precision)
+    pub insurance_fund: u64,
+    /// Market bad debt the insurance fund has not covered (quote token, 1e9 precision)
+    pub outstanding_bad_debt: u64,
+}
+
+impl Event for BadDebtIncurred {
+    const DISCRIMINATOR: [u8; DISCRIMINATOR_LEN] = *b"evbaddbt";
+}
+
+/// Outstanding bad debt was paid down by the insurance fund and a haircut on winning positions
+#[derive(BorshSerialize, Debug, Clone, PartialEq, Eq)]
+pub struct LossSocialized {
+    /// Market state account
+    pub market: Pubkey,
+    /// Bad debt the insurance fund paid off (quote token, 1e9 precision)
+    pub insurance_covered: u64,
+    /// Bad debt charged to winning positions through the loss indices (quote token, 1e9 precision)
+    pub haircut: u64,
+    /// Market long_loss_index after the haircut (1e9 precision)
+    pub long_loss_index: u64,
+    /// Market short_loss_index after the haircut (1e9 precision)
+    pub short_loss_index: u64,
+    /// Market bad debt still outstanding (quote token, 1e9 precision)
+    pub outstanding_bad_debt: u64,
+}
+
+impl Event for LossSocialized {
+    const DISCRIMINATOR: [u8; DISCRIMINATOR_LEN] = *b"evsocial";
+}
+
+/// A position traded against the market's vAMM
+#[derive(BorshSerialize, Debug, Clone, PartialEq, Eq)]
+pub struct VammTrade {
+    /// Market state account
+    pub market: Pubkey,
+    /// Position that traded
+    pub position: Pubkey,
+    /// Signed size filled (1e9 precision)
+    pub base_delta: i64,
+    /// Average execution price, including price impact (1e9 precision)
+    pub fill_price: u64,
+    /// Price impact charged on top of the curve's slippage (1e9 precision)
+    pub price_impact: u64,
+    /// Mark price after the trade (1e9 precision)
+    pub mark_price: u64,
+}
+
+impl Event for VammTrade {
+    const DISCRIMINATOR: [u8; DISCRIMINATOR_LEN] = *b"evvtrade";
+}
+
+/// A position was flagged under-collateralized; unless it is topped up, or falls below the
+/// market's hard floor sooner, it can be liquidated from `liquidatable_slot`
+#[derive(BorshSerialize, Debug, Clone, PartialEq, Eq)]
+pub struct PositionFlagged {
+    /// Market state account
+    pub market: Pubkey,
+    /// Position flagged
+    pub position: Pubkey,
+    /// Position owner
+    pub owner: Pubkey,
+    /// Collateral ratio the position was flagged at (1e9 precision)
+    pub collateral_ratio: u64,
+    /// First slot the position can be liquidated in above the hard floor
+    pub liquidatable_slot: u64,
+}
+
+impl Event for PositionFlagged {
+    const DISCRIMINATOR: [u8; DISCRIMINATOR_LEN] = *b"evmgcall";
+}
+
+/// `check_health` measured a position as liquidations do
+#[derive(BorshSerialize, Debug, Clone, PartialEq, Eq)]
+pub struct HealthChecked {
+    /// Market state account
+    pub market: Pubkey,
+    /// Position measured
+    pub position: Pubkey,
+    /// Slot of the measurement
+    pub slot: u64,
+    /// Collateral ratio at the mark price, of the user account for cross-margined positions (1e9 precision)
+    pub collateral_ratio: u64,
+    /// Collateral ratio over the required ratio; below 1e9 the position is liquidatable (1e9 precision)
+    pub health_factor: u64,
+}
+
+impl Event for HealthChecked {
+    const DISCRIMINATOR: [u8; DISCRIMINATOR_LEN] = *b"evhealth";
+}
@@ -0,0 +1,198 @@
+//! Funding rate history
+//!
+//! Anyone can create a market's FundingHistory account ([FUNDING_HISTORY_SEED,
+//! market_state]): a fixed-size ring buffer of the last MAX_FUNDING_RECORDS
+//! funding settlements. `update_funding` appends to it when it is passed among
+//! its trailing accounts, where it is recognized by its PDA; once full, each
+//! settlement overwrites the oldest one.
+//!
+//! Every record carries the funding index, so the average rate between any two
+//! records, and from it a position's expected carry, is one subtraction away:
+//! UIs chart the records, and other programs can read `average_rate_per_slot`.
+
+use borsh::{BorshDeserialize, BorshSerialize};
+use shank::ShankAccount;
+use solana_program::{
+    account_info::{next_account_info, AccountInfo},
+    entrypoint::ProgramResult,
+    msg,
+    program::invoke_signed,
+    program_error::ProgramError,
+    pubkey::Pubkey,
+    system_instruction,
+    sysvar::{rent::Rent, Sysvar},
+};
+
+use crate::{load_account, probe_account, store_account, AccountType, MarketState, DISCRIMINATOR_LEN};
+
+/// Seed prefix for funding history PDAs: [FUNDING_HISTORY_SEED, market_state]
+pub const FUNDING_HISTORY_SEED: &[u8] = b"funding_history";
+
+/// Funding settlements a history keeps before overwriting the oldest
+pub const MAX_FUNDING_RECORDS: usize = 64;
+
+/// One `update_funding` settlement
+#[derive(BorshSerialize, BorshDeserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct FundingRecord {
+    /// Slot the funding was settled at
+    pub slot: u64,
+    /// Unix time the funding was settled at
+    pub timestamp: i64,
+    /// Funding rate applied per slot since the previous settlement, after the cap (signed, 1e9 precision)
+    pub funding_rate_per_slot: i64,
+    /// Rate the open interest skew called for before the cap (signed, 1e9 precision)
+    pub premium: i64,
+    /// Funding index after the settlement (scaled by 1e9)
+    pub funding_index: i64,
+}
+
+impl FundingRecord {
+    /// slot + timestamp + funding_rate_per_slot + premium + funding_index
+    pub const LEN: usize = 8 * 5;
+}
+
+/// The last MAX_FUNDING_RECORDS funding settlements of a market
+#[derive(BorshSerialize, BorshDeserialize, Debug, Default, Clone, PartialEq, Eq, ShankAccount)]
+pub struct FundingHistory {
+    /// Market state account the history belongs to
+    pub market: Pubkey,
+    /// Slot in `records` the next settlement overwrites once the buffer is full
+    pub next_index: u16,
+    /// Settlements in ring order: oldest at `next_index` once full, in push order until then
+    pub records: Vec<FundingRecord>,
+    /// PDA bump for [FUNDING_HISTORY_SEED, market_state]
+    pub bump: u8,
+}
+
+impl AccountType for FundingHistory {
+    const DISCRIMINATOR: [u8; DISCRIMINATOR_LEN] = *b"fundhist";
+    /// market + next_index + vec length + MAX_FUNDING_RECORDS records + bump
+    const LEN: usize = 32 + 2 + 4 + MAX_FUNDING_RECORDS * FundingRecord::LEN + 1;
+}
+
+impl FundingHistory {
+    /// Append `record`, overwriting the oldest one once the buffer is full
+    pub fn push(&mut self, record: FundingRecord) {
+        if self.records.len() < MAX_FUNDING_RECORDS {
+            self.records.push(record);
+        } else {
+            self.records[usize::from(self.next_index)] = record;
+        }
+        self.next_index = ((usize::from(self.next_index) + 1) % MAX_FUNDING_RECORDS) as u16;
+    }
+
+    /// Records from the oldest to the latest
+    pub fn records_oldest_first(&self) -> impl Iterator<Item = &FundingRecord> {
+        let split = if self.records.len() < MAX_FUNDING_RECORDS { 0 } else { usize::from(self.next_index) };
+        self.records[split..].iter().chain(&self.records[..split])
+    }
+
+    /// Average funding rate per slot over the recorded window (signed, 1e9 precision): the
+    /// funding index change between the oldest and the latest record over the slots between
+    /// them. None until two settlements at different slots are recorded.
+    pub fn average_rate_per_slot(&self) -> Option<i64> {
+        let oldest = self.records_oldest_first().next()?;
+        let latest = self.records_oldest_first().last()?;
+        let slots = latest.slot.checked_sub(oldest.slot).filter(|slots| *slots > 0)?;
+        let index_change = i128::from(latest.funding_index) - i128::from(oldest.funding_index);
+        i64::try_from(index_change / i128::from(slots)).ok()
+    }
+}
+
+/// Funding history PDA of `market_state`
+pub fn funding_history_address(program_id: &Pubkey, market_state: &Pubkey) -> (Pubkey, u8) {
+    Pubkey::find_program_address(&[FUNDING_HISTORY_SEED, market_state.as_ref()], program_id)
+}
+
+// ---------------------------------------------------------------------
+// 8️⃣9️⃣ Create a market's funding rate history (permissionless)
+// ---------------------------------------------------------------------
+pub fn create_funding_history(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramResult {
+    // Accounts:
+    // 0. [signer, writable] payer
+    // 1. [] market state account
+    // 2. [writable] funding history account (PDA: [FUNDING_HISTORY_SEED, market_state])
+    // 3. [] rent sysvar
+    // 4. [] system program
+    let accounts_iter = &mut accounts.iter();
+    let payer = next_account_info(accounts_iter)?;
+    let market_state_acc = next_account_info(accounts_iter)?;
+    let history_acc = next_account_info(accounts_iter)?;
+    let rent_sysvar = next_account_info(accounts_iter)?;
+    let system_program = next_account_info(accounts_iter)?;
+
+    if !payer.is_signer {
+        msg!("Payer must be signer");
+        return Err(ProgramError::MissingRequiredSignature);
+    }
+
+    if market_state_acc.owner != program_id {
+        msg!("Market state is not owned by the program");
+        return Err(ProgramError::IncorrectProgramId);
+    }
+    MarketState::load(&market_state_acc.data.borrow())?;
+
+    let (expected_history, bump) = funding_history_address(program_id, market_state_acc.key);
+    if *history_acc.key != expected_history {
+        msg!("Funding history account is not the correct PDA. Expected: {}, Got: {}", expected_history, history_acc.key);
+        return Err(ProgramError::InvalidArgument);
+    }
+
+    if !history_acc.data_is_empty() {
+        msg!("Funding history account already exists");
+        return Err(ProgramError::AccountAlreadyInitialized);
+    }
+
+    let rent = Rent::from_account_info(rent_sysvar)?;
+    invoke_signed(&system_instruction::create_account(
+        payer.key,
+        history_acc.key,
+        rent.minimum_balance(FundingHistory::SPACE),
+        FundingHistory::SPACE as u64,
+        program_id,
+    ), &[
+        payer.clone(),
+        history_acc.clone(),
+        system_program.clone(),
+    ], &[&[FUNDING_HISTORY_SEED, market_state_acc.key.as_ref(), &[bump]]])?;
+
+    let history = FundingHistory { market: *market_state_acc.key, bump, ..FundingHistory::default() };
+    store_account(&history, &mut history_acc.data.borrow_mut())?;
+
+    msg!("Created funding history for {}", market_state_acc.key);
+
+    Ok(())
+}
+
+/// The funding history of `market` among `accounts`, if it was passed
+pub(crate) fn find_funding_history<'a, 'info>(
+    program_id: &Pubkey,
+    market: &Pubkey,
+    accounts: &[&'a AccountInfo<'info>],
+) -> Option<&'a AccountInfo<'info>> {
+    accounts.iter().copied().find(|account| {
+        if account.owner != program_id {
+            return false;
+        }
+        // Re-derive the PDA from the stored bump rather than searching for it
+        let Some(history) = account
+            .try_borrow_data()
+            .ok()
+            .and_then(|data| probe_account::<FundingHistory>(&data))
+        else {
+            return false;
+        };
+        history.market == *market
+            && Pubkey::create_program_address(&[FUNDING_HISTORY_SEED, market.as_ref(), &[history.bump]], program_id)
+                .is_ok_and(|expected_history| expected_history == *account.key)
+    })
+}
+
+/// Append `record` to the history in `history_acc`
+pub(crate) fn record_funding_settlement(history_acc: &AccountInfo, record: FundingRecord) -> ProgramResult {
+    let mut history = load_account::<FundingHistory>(&history_acc.data.borrow())?;
+    history.push(record);
+    store_account(&history, &mut history_acc.data.borrow_mut())?;
+
+    Ok(())
+}
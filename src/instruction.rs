@@ -0,0 +1,1071 @@
+//! Program instructions
+//!
+//! `PerpsInstruction` is the program's wire format: Borsh writes the variant
+//! index as a leading u8, which is the instruction's tag, followed by its
+//! fields in little-endian order. `pack` inserts INSTRUCTION_VERSION after the
+//! tag and `unpack` decodes it, so both sides share one definition.
+//! Variants are only ever appended; reordering them renumbers the tags.
+//!
+//! When a variant's fields change, bump INSTRUCTION_VERSION and decode the old
+//! layout into the new variant in `decode_fields`, so integrators still sending
+//! it keep working. Payloads from before the version byte ([tag][fields]) are
+//! still accepted for instructions whose payload has a fixed size.
+//!
+//! Each variant's `#[account]` attributes list the fixed accounts its handler
+//! takes, which shank extracts into an IDL (`scripts/5_idl.sh`) for generated
+//! clients. Variable-length trailing accounts are only described by the handler.
+
+use borsh::{BorshDeserialize, BorshSerialize};
+use shank::ShankInstruction;
+use solana_program::{program_error::ProgramError, pubkey::Pubkey};
+
+use crate::orderbook::{OrderOp, OrderSide};
+use crate::staking::StakeDiscount;
+use crate::{FeeTier, RiskTier};
+use crate::trigger_orders::TriggerKind;
+
+/// Payload layout version written after the tag
+pub const INSTRUCTION_VERSION: u8 = 2;
+
+/// Tag of `UpdateParams`, whose version 1 layout is no longer accepted
+const UPDATE_PARAMS_TAG: u8 = 13;
+
+/// An instruction and its payload; see the handler named after each variant
+/// for its trailing accounts
+#[derive(BorshSerialize, BorshDeserialize, ShankInstruction, Debug, Clone, PartialEq, Eq)]
+pub enum PerpsInstruction {
+    /// 0. Open, resize or flip a position (initializes the market on first use)
+    #[account(0, signer, name = "user", desc = "User (position owner, or its delegate for an existing position)")]
+    #[account(1, name = "token_program", desc = "Token program")]
+    #[account(2, writable, name = "user_token_account", desc = "User's collateral token account (quote token, e.g., USDC)")]
+    #[account(3, writable, name = "vault", desc = "Vault token account (PDA‑owned)")]
+    #[account(4, writable, name = "position", desc = "Position account (PDA: [POSITION_SEED, market_state, user, sub_account_id])")]
+    #[account(5, writable, name = "market_state", desc = "Market state account (PDA‑derived)")]
+    #[account(6, name = "rent", desc = "Rent sysvar")]
+    #[account(7, name = "clock", desc = "Clock sysvar")]
+    #[account(8, name = "system_program", desc = "System program (for account creation)")]
+    #[account(9, name = "config", desc = "Config account")]
+    #[account(10, name = "quote_mint", desc = "Quote mint")]
+    #[account(11, optional, name = "index_oracle", desc = "The market's index oracle, then its backup oracles")]
+    OpenPosition {
+        /// Signed size change (market base units)
+        base_delta: i64,
+        /// Quote collateral to deposit (quote token)
+        collateral_delta: u64,
+        /// Worst acceptable average fill price (max for longs, min for shorts;
+        /// 0 = no limit). Seeds the vAMM price when the call creates the market.
+        price_limit: u64,
+        /// OPEN_FLAG_* bits
+        flags: u8,
+        /// Which of the owner's positions in the market to trade
+        sub_account_id: u16,
+    },
+    /// 1. Accrue funding since the last update
+    #[account(0, writable, name = "market_state", desc = "Market state PDA")]
+    #[account(1, name = "clock", desc = "Clock sysvar")]
+    #[account(2, name = "config", desc = "Config account")]
+    #[account(3, optional, name = "token_program", desc = "Token program")]
+    #[account(4, writable, optional, name = "keeper_token_account", desc = "Keeper's token account (quote token)")]
+    #[account(5, writable, optional, name = "vault", desc = "Vault token account (PDA‑owned)")]
+    #[account(6, optional, name = "quote_mint", desc = "Quote mint")]
+    #[account(7, writable, optional, name = "funding_history", desc = "The market's funding history (PDA: [FUNDING_HISTORY_SEED, market_state])")]
+    #[account(8, writable, optional, name = "price_history", desc = "The market's price history (PDA: [PRICE_HISTORY_SEED, market_state])")]
+    UpdateFunding,
+    /// 2. Liquidate an undercollateralized position through the vAMM
+    #[account(0, signer, name = "liquidator", desc = "Liquidator")]
+    #[account(1, name = "token_program", desc = "Token program")]
+    #[account(2, writable, name = "liquidator_token_account", desc = "Liquidator's token account (to receive the liquidator's share of the fee)")]
+    #[account(3, writable, name = "vault", desc = "Vault token account (PDA‑owned)")]
+    #[account(4, writable, name = "position", desc = "Position account to liquidate, in this market")]
+    #[account(5, writable, name = "market_state", desc = "Market state account")]
+    #[account(6, name = "clock", desc = "Clock sysvar")]
+    #[account(7, name = "config", desc = "Config account")]
+    #[account(8, name = "quote_mint", desc = "Quote mint")]
+    Liquidate,
+    /// 3. Close an isolated position and return its collateral
+    #[account(0, signer, name = "user", desc = "User (position owner)")]
+    #[account(1, name = "token_program", desc = "Token program")]
+    #[account(2, writable, name = "user_token_account", desc = "User's token account (to receive collateral)")]
+    #[account(3, writable, name = "vault", desc = "Vault token account (PDA‑owned)")]
+    #[account(4, writable, name = "position", desc = "Position account")]
+    #[account(5, writable, name = "market_state", desc = "Market state account")]
+    #[account(6, name = "config", desc = "Config account")]
+    #[account(7, name = "quote_mint", desc = "Quote mint")]
+    #[account(8, writable, optional, name = "user_stats", desc = "Owner's user stats account")]
+    #[account(9, writable, optional, name = "rewards_schedule", desc = "Rewards schedule")]
+    #[account(10, writable, optional, name = "user_rewards", desc = "Owner's user rewards account (with the schedule)")]
+    #[account(11, writable, optional, name = "market_metrics", desc = "Market metrics account (PDA: [MARKET_METRICS_SEED, market_state])")]
+    #[account(12, writable, optional, name = "price_history", desc = "The market's price history (PDA: [PRICE_HISTORY_SEED, market_state])")]
+    ClosePosition,
+    /// 4. Create the caller's referrer account
+    #[account(0, writable, signer, name = "referrer_owner", desc = "Referrer owner (pays for the account)")]
+    #[account(1, writable, name = "referrer", desc = "Referrer account (PDA: [REFERRER_SEED, owner])")]
+    #[account(2, name = "rent", desc = "Rent sysvar")]
+    #[account(3, name = "system_program", desc = "System program")]
+    RegisterReferrer,
+    /// 5. Withdraw accrued referral fees
+    #[account(0, signer, name = "referrer_owner", desc = "Referrer owner")]
+    #[account(1, name = "token_program", desc = "Token program")]
+    #[account(2, writable, name = "owner_token_account", desc = "Owner's token account (to receive the fees)")]
+    #[account(3, writable, name = "vault", desc = "Vault token account (PDA‑owned)")]
+    #[account(4, writable, name = "referrer", desc = "Referrer account")]
+    #[account(5, name = "config", desc = "Config account")]
+    #[account(6, name = "quote_mint", desc = "Quote mint")]
+    ClaimReferralFees,
+    /// 6. Place a resting limit order
+    #[account(0, writable, signer, name = "user", desc = "User (pays for the order book on first use)")]
+    #[account(1, name = "position", desc = "Position account (owned by user; writable for IOC orders)")]
+    #[account(2, name = "market_state", desc = "Market state account (writable for IOC orders)")]
+    #[account(3, writable, name = "orderbook", desc = "Order book account (PDA: [ORDERBOOK_SEED, market_state])")]
+    #[account(4, name = "rent", desc = "Rent sysvar")]
+    #[account(5, name = "clock", desc = "Clock sysvar")]
+    #[account(6, name = "system_program", desc = "System program")]
+    #[account(7, optional, name = "config", desc = "Config account")]
+    PlaceOrder {
+        /// Bid (long) or ask (short)
+        side: OrderSide,
+        /// Limit price (1e9 precision)
+        price: u64,
+        /// Order size (market base units)
+        base_amount: u64,
+        /// Bit 0 = post-only: reject if it would cross a resting order. Bit 1 = immediate-or-cancel:
+        /// match against crossing resting orders now and cancel the rest. At most one may be set.
+        flags: u8,
+    },
+    /// 7. Cancel a resting limit order
+    #[account(0, signer, name = "order_owner", desc = "Order owner")]
+    #[account(1, writable, name = "orderbook", desc = "Order book account")]
+    CancelOrder {
+        /// Id assigned when the order was placed
+        order_id: u64,
+    },
+    /// 8. Fill crossed orders against the vAMM
+    #[account(0, writable, name = "market_state", desc = "Market state account")]
+    #[account(1, writable, name = "orderbook", desc = "Order book account")]
+    #[account(2, name = "config", desc = "Config account")]
+    MatchOrders,
+    /// 9. Place a stop-loss / take-profit trigger
+    #[account(0, writable, signer, name = "user", desc = "User (position owner, pays for the trigger account)")]
+    #[account(1, name = "position", desc = "Position account")]
+    #[account(2, name = "market_state", desc = "Market state account")]
+    #[account(3, writable, name = "trigger_order", desc = "Trigger order account (PDA: [TRIGGER_SEED, position, trigger_id])")]
+    #[account(4, name = "rent", desc = "Rent sysvar")]
+    #[account(5, name = "system_program", desc = "System program")]
+    #[account(6, writable, name = "trigger_queue", desc = "Trigger queue account (PDA: [TRIGGER_QUEUE_SEED, market_state])")]
+    PlaceTriggerOrder {
+        /// Caller-chosen id, part of the trigger PDA
+        trigger_id: u64,
+        /// Stop-loss or take-profit
+        kind: TriggerKind,
+        /// Mark price that fires the trigger (1e9 precision)
+        trigger_price: u64,
+        /// Size to close (market base units; 0 = the whole position)
+        base_amount: u64,
+    },
+    /// 10. Cancel a trigger order
+    #[account(0, writable, signer, name = "trigger_owner", desc = "Trigger owner (receives the account's rent)")]
+    #[account(1, writable, name = "trigger_order", desc = "Trigger order account")]
+    #[account(2, writable, name = "trigger_queue", desc = "Trigger queue account of the trigger's market")]
+    #[account(3, writable, optional, name = "linked_trigger", desc = "Other order of the trigger's one-cancels-other pair (linked triggers only; left unlinked)")]
+    CancelTriggerOrder,
+    /// 11. Execute a trigger order whose price was reached
+    #[account(0, signer, name = "keeper", desc = "Keeper")]
+    #[account(1, name = "token_program", desc = "Token program")]
+    #[account(2, writable, name = "keeper_token_account", desc = "Keeper's token account (to receive the execution reward)")]
+    #[account(3, writable, name = "vault", desc = "Vault token account (PDA‑owned)")]
+    #[account(4, writable, name = "position", desc = "Position account")]
+    #[account(5, writable, name = "market_state", desc = "Market state account")]
+    #[account(6, writable, name = "trigger_order", desc = "Trigger order account")]
+    #[account(7, writable, name = "trigger_owner", desc = "Trigger owner (receives the trigger account's rent)")]
+    #[account(8, name = "config", desc = "Config account")]
+    #[account(9, name = "quote_mint", desc = "Quote mint")]
+    #[account(10, writable, name = "trigger_queue", desc = "Trigger queue account of the market")]
+    #[account(11, writable, optional, name = "linked_trigger", desc = "Other order of the trigger's one-cancels-other pair (linked triggers only; closed too)")]
+    ExecuteTriggerOrder,
+    /// 12. Create the program config
+    #[account(0, writable, signer, name = "payer", desc = "Payer (becomes the admin unless one is given)")]
+    #[account(1, writable, name = "config", desc = "Config account (PDA: [CONFIG_SEED])")]
+    #[account(2, name = "rent", desc = "Rent sysvar")]
+    #[account(3, name = "system_program", desc = "System program")]
+    #[account(4, name = "quote_mint", desc = "Quote mint (its owner becomes the configured token program)")]
+    #[account(5, name = "program_data", desc = "The program's ProgramData account (its upgrade authority must be the payer)")]
+    InitializeConfig {
+        /// Admin key; defaults to the payer
+        admin: Option<Pubkey>,
+    },
+    /// 13. Update the global risk parameters (admin)
+    #[account(0, signer, name = "admin", desc = "Admin")]
+    #[account(1, writable, name = "config", desc = "Config account")]
+    UpdateParams {
+        /// Minimum collateral ratio (1e9 precision)
+        min_collateral_ratio: u64,
+        /// Liquidation fee (basis points of the closed notional)
+        liquidator_fee_bps: u64,
+        /// Trading fee (1e9 precision)
+        trading_fee: u64,
+        /// Referrer share of the trading fee (1e9 precision)
+        referral_fee_share: u64,
+        /// Funding rate clamp (1e9 precision)
+        max_funding_rate_per_slot: u64,
+        /// funding_crank_reward and max_funding_crank_reward (quote amounts, 1e9 precision);
+        /// unchanged if absent
+        crank_reward: Option<(u64, u64)>,
+        /// Insurance fund share of liquidation fees (1e9 precision); unchanged if absent
+        insurance_fund_share: Option<u64>,
+        /// min_position_size (base amount) and dust_collateral (quote amount), both in 1e9
+        /// precision; unchanged if absent
+        dust_thresholds: Option<(u64, u64)>,
+        /// Share of a position's collateral payable in funding per settlement (1e9 precision;
+        /// 0 = uncapped); unchanged if absent
+        max_funding_settlement_share: Option<u64>,
+        /// Maker rebate of crossed order matches (1e9 precision; at most the trading fee);
+        /// unchanged if absent
+        maker_rebate: Option<u64>,
+        /// max_positions_per_user and max_user_notional (quote amount, 1e9 precision), each 0
+        /// for no limit; unchanged if absent
+        user_limits: Option<(u16, u64)>,
+    },
+    /// 14. Pause a market (admin)
+    #[account(0, signer, name = "admin", desc = "Admin")]
+    #[account(1, name = "config", desc = "Config account")]
+    #[account(2, writable, name = "market_state", desc = "Market state account")]
+    PauseMarket,
+    /// 15. Resume a paused market (admin)
+    #[account(0, signer, name = "admin", desc = "Admin")]
+    #[account(1, name = "config", desc = "Config account")]
+    #[account(2, writable, name = "market_state", desc = "Market state account")]
+    ResumeMarket,
+    /// 16. Propose a new admin (admin)
+    #[account(0, signer, name = "admin", desc = "Current admin")]
+    #[account(1, writable, name = "config", desc = "Config account")]
+    SetPendingAdmin {
+        /// Proposed admin; the default pubkey cancels a pending transfer
+        pending_admin: Pubkey,
+    },
+    /// 17. Accept a pending admin transfer
+    #[account(0, signer, name = "pending_admin", desc = "Pending admin")]
+    #[account(1, writable, name = "config", desc = "Config account")]
+    AcceptAdmin,
+    /// 18. Grow an account to the current layout
+    #[account(0, writable, signer, name = "payer", desc = "Payer (funds any extra rent)")]
+    #[account(1, writable, name = "account", desc = "Position, market state, config, LP pool, user stats or trigger order account to upgrade")]
+    #[account(2, name = "rent", desc = "Rent sysvar")]
+    #[account(3, name = "system_program", desc = "System program")]
+    #[account(4, optional, name = "config_or_market_state", desc = "Config account (market states predating the recorded quote mint only), or the position's market state account (positions predating loss socialization only)")]
+    MigrateAccount,
+    /// 19. View: collateral ratio of a position
+    #[account(0, name = "position", desc = "Position account")]
+    #[account(1, name = "market_state", desc = "Market state account")]
+    GetPositionHealth,
+    /// 20. View: unrealized PnL of a position
+    #[account(0, name = "position", desc = "Position account")]
+    #[account(1, name = "market_state", desc = "Market state account")]
+    GetUnrealizedPnl,
+    /// 21. Close a flat position account and reclaim its rent
+    #[account(0, writable, signer, name = "position_owner", desc = "Position owner (receives the rent)")]
+    #[account(1, writable, name = "position", desc = "Position account")]
+    ClosePositionAccount,
+    /// 22. Accept a new collateral asset (admin)
+    #[account(0, signer, name = "admin", desc = "Admin")]
+    #[account(1, writable, name = "config", desc = "Config account")]
+    #[account(2, name = "asset_vault", desc = "Asset vault token account (owned by the program's vault PDA)")]
+    AddCollateralAsset {
+        /// Asset mint
+        mint: Pubkey,
+        /// Pyth price account of the asset
+        oracle: Pubkey,
+        /// Share of the asset's value counted as collateral (1e9 precision)
+        weight: u64,
+        /// Mint decimals
+        decimals: u8,
+    },
+    /// 23. Deposit a collateral asset into a position
+    #[account(0, signer, name = "user", desc = "User (position owner)")]
+    #[account(1, name = "token_program", desc = "Token program")]
+    #[account(2, writable, name = "user_asset_token_account", desc = "User's token account for the asset")]
+    #[account(3, writable, name = "asset_vault", desc = "Asset vault token account")]
+    #[account(4, writable, name = "position", desc = "Position account")]
+    #[account(5, name = "config", desc = "Config account")]
+    #[account(6, name = "asset_mint", desc = "Asset mint")]
+    DepositCollateralAsset {
+        /// Index into the config's collateral assets
+        asset_index: u8,
+        /// Amount in the asset's native units
+        amount: u64,
+    },
+    /// 24. Withdraw a collateral asset from a position
+    #[account(0, signer, name = "user", desc = "User (position owner)")]
+    #[account(1, name = "token_program", desc = "Token program")]
+    #[account(2, writable, name = "user_asset_token_account", desc = "User's token account for the asset")]
+    #[account(3, writable, name = "asset_vault", desc = "Asset vault token account (PDA‑owned)")]
+    #[account(4, writable, name = "position", desc = "Position account")]
+    #[account(5, writable, name = "market_state", desc = "Market state account")]
+    #[account(6, name = "config", desc = "Config account")]
+    #[account(7, name = "clock", desc = "Clock sysvar")]
+    #[account(8, name = "asset_mint", desc = "Asset mint")]
+    #[account(9, name = "vault_authority", desc = "Vault authority (PDA: [PDA_SEED])")]
+    WithdrawCollateralAsset {
+        /// Index into the config's collateral assets
+        asset_index: u8,
+        /// Amount in the asset's native units
+        amount: u64,
+    },
+    /// 25. Set the quote mint (admin)
+    #[account(0, signer, name = "admin", desc = "Admin")]
+    #[account(1, writable, name = "config", desc = "Config account")]
+    #[account(2, name = "quote_mint", desc = "Quote mint (its owner becomes the configured token program)")]
+    SetQuoteMint,
+    /// 26. Wrap and deposit native SOL as collateral
+    #[account(0, writable, signer, name = "user", desc = "User (position owner, pays the lamports)")]
+    #[account(1, name = "token_program", desc = "Token program (SPL Token)")]
+    #[account(2, writable, name = "wsol_vault", desc = "WSOL asset vault token account")]
+    #[account(3, writable, name = "position", desc = "Position account")]
+    #[account(4, name = "config", desc = "Config account")]
+    #[account(5, name = "system_program", desc = "System program")]
+    DepositNativeSol {
+        /// Index of the wrapped SOL collateral asset
+        asset_index: u8,
+        /// Lamports to wrap
+        lamports: u64,
+    },
+    /// 27. Create the caller's cross-margin user account
+    #[account(0, writable, signer, name = "owner", desc = "Owner (pays for the account)")]
+    #[account(1, writable, name = "user_account", desc = "User account (PDA: [USER_ACCOUNT_SEED, owner])")]
+    #[account(2, name = "rent", desc = "Rent sysvar")]
+    #[account(3, name = "system_program", desc = "System program")]
+    CreateUserAccount,
+    /// 28. Deposit quote collateral into a user account
+    #[account(0, signer, name = "owner", desc = "Owner")]
+    #[account(1, name = "token_program", desc = "Token program")]
+    #[account(2, writable, name = "owner_quote_token_account", desc = "Owner's quote token account")]
+    #[account(3, writable, name = "vault", desc = "Vault token account (PDA)")]
+    #[account(4, writable, name = "user_account", desc = "User account")]
+    #[account(5, name = "config", desc = "Config account")]
+    #[account(6, name = "quote_mint", desc = "Quote mint")]
+    DepositCrossCollateral {
+        /// Quote token amount
+        amount: u64,
+    },
+    /// 29. Withdraw quote collateral from a user account
+    #[account(0, signer, name = "owner", desc = "Owner")]
+    #[account(1, name = "token_program", desc = "Token program")]
+    #[account(2, writable, name = "owner_quote_token_account", desc = "Owner's quote token account")]
+    #[account(3, writable, name = "vault", desc = "Vault token account (PDA)")]
+    #[account(4, writable, name = "user_account", desc = "User account")]
+    #[account(5, name = "config", desc = "Config account")]
+    #[account(6, name = "quote_mint", desc = "Quote mint")]
+    WithdrawCrossCollateral {
+        /// Quote token amount
+        amount: u64,
+    },
+    /// 30. Switch a flat position between isolated and cross margin
+    #[account(0, signer, name = "owner", desc = "Owner")]
+    #[account(1, writable, name = "position", desc = "Position account")]
+    #[account(2, writable, name = "market_state", desc = "Market state account the position trades in")]
+    #[account(3, writable, name = "user_account", desc = "User account")]
+    SetMarginMode {
+        /// MARGIN_MODE_ISOLATED or MARGIN_MODE_CROSS
+        margin_mode: u8,
+    },
+    /// 31. Approve or revoke a trading delegate for a position
+    #[account(0, signer, name = "position_owner", desc = "Position owner")]
+    #[account(1, writable, name = "position", desc = "Position account")]
+    ApproveDelegate {
+        /// Delegate key; the default pubkey revokes
+        delegate: Pubkey,
+    },
+    /// 32. Take over an undercollateralized position's exposure
+    #[account(0, signer, name = "liquidator", desc = "Liquidator")]
+    #[account(1, name = "token_program", desc = "Token program")]
+    #[account(2, writable, name = "liquidator_token_account", desc = "Liquidator's collateral token account (quote token)")]
+    #[account(3, writable, name = "vault", desc = "Vault token account (PDA‑owned)")]
+    #[account(4, writable, name = "position", desc = "Position account to liquidate, in this market")]
+    #[account(5, writable, name = "liquidator_position", desc = "Liquidator's flat, isolated position in the same market (PDA: [POSITION_SEED, market_state, liquidator, sub_account_id])")]
+    #[account(6, writable, name = "market_state", desc = "Market state account")]
+    #[account(7, name = "clock", desc = "Clock sysvar")]
+    #[account(8, name = "config", desc = "Config account")]
+    #[account(9, name = "quote_mint", desc = "Quote mint")]
+    BackstopLiquidate {
+        /// Collateral the liquidator posts to the inheriting position (quote token)
+        collateral_delta: u64,
+    },
+    /// 33. Realize a position's unrealized PnL at the mark price
+    #[account(0, writable, name = "position", desc = "Position account")]
+    #[account(1, writable, name = "market_state", desc = "Market state account the position trades in")]
+    #[account(2, name = "config", desc = "Config account")]
+    #[account(3, writable, optional, name = "user_account", desc = "Owner's user account")]
+    SettlePnl,
+    /// 34. Deposit quote liquidity into a market's pool
+    #[account(0, signer, name = "provider", desc = "Provider")]
+    #[account(1, name = "token_program", desc = "Token program")]
+    #[account(2, writable, name = "provider_quote_token_account", desc = "Provider's quote token account")]
+    #[account(3, writable, name = "vault", desc = "Vault token account (PDA; also the LP mint authority)")]
+    #[account(4, writable, name = "pool_state", desc = "Pool state")]
+    #[account(5, writable, name = "lp_share_mint", desc = "LP share mint")]
+    #[account(6, writable, name = "provider_lp_share_token_account", desc = "Provider's LP share token account")]
+    #[account(7, name = "market_state", desc = "Market state account the pool backs")]
+    #[account(8, name = "config", desc = "Config account")]
+    #[account(9, name = "quote_mint", desc = "Quote mint")]
+    DepositLiquidity {
+        /// Quote token amount
+        amount: u64,
+    },
+    /// 35. Burn LP shares and queue their withdrawal
+    #[account(0, writable, signer, name = "provider", desc = "Provider (pays for the request account)")]
+    #[account(1, name = "token_program", desc = "Token program")]
+    #[account(2, writable, name = "pool_state", desc = "Pool state")]
+    #[account(3, writable, name = "lp_share_mint", desc = "LP share mint")]
+    #[account(4, writable, name = "provider_lp_share_token_account", desc = "Provider's LP share token account")]
+    #[account(5, writable, name = "withdrawal_request", desc = "Withdrawal request (PDA: [WITHDRAWAL_SEED, pool, provider])")]
+    #[account(6, name = "market_state", desc = "Market state account the pool backs")]
+    #[account(7, name = "clock", desc = "Clock sysvar")]
+    #[account(8, name = "rent", desc = "Rent sysvar")]
+    #[account(9, name = "system_program", desc = "System program")]
+    RequestWithdrawal {
+        /// LP shares to burn
+        shares: u64,
+    },
+    /// 36. Create a market's LP pool
+    #[account(0, writable, signer, name = "payer", desc = "Payer")]
+    #[account(1, writable, name = "pool_state", desc = "Pool state (PDA: [POOL_SEED, market_state])")]
+    #[account(2, writable, name = "lp_share_mint", desc = "LP share mint (PDA: [LP_MINT_SEED, pool])")]
+    #[account(3, name = "market_state", desc = "Market state account")]
+    #[account(4, name = "token_program", desc = "Token program (the quote mint's)")]
+    #[account(5, name = "rent", desc = "Rent sysvar")]
+    #[account(6, name = "system_program", desc = "System program")]
+    #[account(7, name = "config", desc = "Config account")]
+    #[account(8, name = "quote_mint", desc = "Quote mint")]
+    CreatePool,
+    /// 37. View: NAV of a market's LP pool
+    #[account(0, name = "pool_state", desc = "Pool state")]
+    #[account(1, name = "market_state", desc = "Market state account the pool backs")]
+    GetPoolNav,
+    /// 38. Pay out a queued withdrawal after its cooldown
+    #[account(0, writable, signer, name = "provider", desc = "Provider (receives the request account's rent)")]
+    #[account(1, name = "token_program", desc = "Token program")]
+    #[account(2, writable, name = "provider_quote_token_account", desc = "Provider's quote token account")]
+    #[account(3, writable, name = "vault", desc = "Vault token account (PDA)")]
+    #[account(4, writable, name = "pool_state", desc = "Pool state")]
+    #[account(5, writable, name = "withdrawal_request", desc = "Withdrawal request")]
+    #[account(6, name = "market_state", desc = "Market state account the pool backs")]
+    #[account(7, name = "clock", desc = "Clock sysvar")]
+    #[account(8, name = "config", desc = "Config account")]
+    #[account(9, name = "quote_mint", desc = "Quote mint")]
+    ExecuteWithdrawal,
+    /// 39. Set a pool's withdrawal limits (admin)
+    #[account(0, signer, name = "admin", desc = "Admin")]
+    #[account(1, name = "config", desc = "Config account")]
+    #[account(2, writable, name = "pool_state", desc = "Pool state")]
+    SetPoolParams {
+        /// Slots between a request and its execution
+        withdrawal_cooldown_slots: u64,
+        /// Share of epoch-start liquidity withdrawable per epoch (1e9 precision)
+        max_epoch_withdrawal_share: u64,
+    },
+    /// 40. Set a market's index oracle and mark price band (admin)
+    #[account(0, signer, name = "admin", desc = "Admin")]
+    #[account(1, name = "config", desc = "Config account")]
+    #[account(2, writable, name = "market_state", desc = "Market state account")]
+    SetIndexOracle {
+        /// Pyth price account; the default pubkey removes the band
+        index_oracle: Pubkey,
+        /// Max mark deviation from the index (1e9 precision); DEFAULT_MARK_PRICE_BAND if absent
+        mark_price_band: Option<u64>,
+    },
+    /// 41. Observe the index price for the circuit breaker
+    #[account(0, writable, name = "market_state", desc = "Market state account")]
+    #[account(1, name = "index_oracle", desc = "The market's index oracle")]
+    #[account(2, name = "clock", desc = "Clock sysvar")]
+    CheckCircuitBreaker,
+    /// 42. Configure a market's circuit breaker (admin)
+    #[account(0, signer, name = "admin", desc = "Admin")]
+    #[account(1, name = "config", desc = "Config account")]
+    #[account(2, writable, name = "market_state", desc = "Market state account")]
+    SetCircuitBreaker {
+        /// Index move that trips the breaker (1e9 precision; 0 disables)
+        max_move: u64,
+        /// Slots the move is measured over
+        window_slots: u64,
+        /// Slots the market stays reduce-only
+        cooldown_slots: u64,
+    },
+    /// 43. Settle a market at its index price (admin)
+    #[account(0, signer, name = "admin", desc = "Admin")]
+    #[account(1, name = "config", desc = "Config account")]
+    #[account(2, writable, name = "market_state", desc = "Market state account")]
+    #[account(3, name = "index_oracle", desc = "The market's index oracle")]
+    #[account(4, name = "clock", desc = "Clock sysvar")]
+    SettleMarket,
+    /// 44. Close a position in a settled market
+    #[account(0, signer, name = "user", desc = "User (position owner)")]
+    #[account(1, name = "token_program", desc = "Token program")]
+    #[account(2, writable, name = "user_token_account", desc = "User's token account (to receive collateral)")]
+    #[account(3, writable, name = "vault", desc = "Vault token account (PDA‑owned)")]
+    #[account(4, writable, name = "position", desc = "Position account")]
+    #[account(5, writable, name = "market_state", desc = "Market state account")]
+    #[account(6, name = "config", desc = "Config account")]
+    #[account(7, name = "quote_mint", desc = "Quote mint")]
+    #[account(8, writable, optional, name = "user_account", desc = "Owner's user account")]
+    SettlePosition,
+    /// 45. Settle a dated market at its oracle TWAP once expired
+    #[account(0, writable, name = "market_state", desc = "Market state account")]
+    #[account(1, name = "index_oracle", desc = "The market's index oracle")]
+    #[account(2, name = "clock", desc = "Clock sysvar")]
+    ExpireMarket,
+    /// 46. Make a market a dated future (admin)
+    #[account(0, signer, name = "admin", desc = "Admin")]
+    #[account(1, name = "config", desc = "Config account")]
+    #[account(2, writable, name = "market_state", desc = "Market state account")]
+    #[account(3, name = "clock", desc = "Clock sysvar")]
+    SetMarketExpiry {
+        /// Expiry (unix seconds)
+        expiry_timestamp: i64,
+    },
+    /// 47. Create the caller's trading statistics account
+    #[account(0, writable, signer, name = "owner", desc = "Owner (pays for the account)")]
+    #[account(1, writable, name = "user_stats", desc = "User stats account (PDA: [USER_STATS_SEED, owner])")]
+    #[account(2, name = "rent", desc = "Rent sysvar")]
+    #[account(3, name = "system_program", desc = "System program")]
+    CreateUserStats,
+    /// 48. View: portfolio health of a cross-margin user account
+    #[account(0, name = "user", desc = "User account")]
+    ComputePortfolioHealth,
+    /// 49. View: liquidation price of an isolated position
+    #[account(0, name = "position", desc = "Position account")]
+    #[account(1, name = "market_state", desc = "Market state account")]
+    #[account(2, name = "config", desc = "Config account")]
+    GetLiquidationPrice,
+    /// 50. Create a market whose sizes are in units of 10^-base_decimals base tokens (admin only)
+    #[account(0, writable, signer, name = "admin", desc = "Admin (pays for the account)")]
+    #[account(1, writable, name = "market_state", desc = "Market state account (PDA: [MARKET_SEED, market_id])")]
+    #[account(2, name = "rent", desc = "Rent sysvar")]
+    #[account(3, name = "clock", desc = "Clock sysvar")]
+    #[account(4, name = "system_program", desc = "System program")]
+    #[account(5, writable, name = "config", desc = "Config account (counts the market)")]
+    #[account(6, name = "token_program", desc = "Token program")]
+    #[account(7, name = "quote_mint", desc = "Quote mint")]
+    InitializeMarket {
+        /// Id of the market, part of its PDA seeds
+        market_id: u16,
+        /// Starting vAMM price (1e9 precision)
+        initial_price: u64,
+        /// Decimals of the market's size unit (at most 9)
+        base_decimals: u8,
+    },
+    /// 51. Match crossed resting orders against each other (permissionless crank)
+    #[account(0, writable, name = "market_state", desc = "Market state account")]
+    #[account(1, writable, name = "orderbook", desc = "Order book account")]
+    #[account(2, name = "config", desc = "Config account")]
+    #[account(3, name = "token_program", desc = "Token program")]
+    #[account(4, writable, name = "cranker_token_account", desc = "Cranker's token account (to receive the fill rewards)")]
+    #[account(5, writable, name = "vault", desc = "Vault token account (PDA‑owned)")]
+    #[account(6, name = "quote_mint", desc = "Quote mint")]
+    CrankMatch {
+        /// Most fills to make in this call
+        max_fills: u8,
+    },
+    /// 52. Haircut winning positions' profits to pay off bad debt the insurance fund can't (permissionless crank)
+    #[account(0, writable, name = "market_state", desc = "Market state account")]
+    SocializeLoss,
+    /// 53. Move the vAMM price to the index price, paid from the fee pool (admin)
+    #[account(0, signer, name = "admin", desc = "Admin")]
+    #[account(1, name = "config", desc = "Config account")]
+    #[account(2, writable, name = "market_state", desc = "Market state account")]
+    #[account(3, name = "index_oracle", desc = "The market's index oracle")]
+    #[account(4, name = "clock", desc = "Clock sysvar")]
+    RepegVamm,
+    /// 54. Create or update the trading rewards schedule (admin)
+    #[account(0, writable, signer, name = "admin", desc = "Admin (pays for the schedule on creation)")]
+    #[account(1, name = "config", desc = "Config account")]
+    #[account(2, writable, name = "rewards_schedule", desc = "Rewards schedule (PDA: [REWARDS_SEED])")]
+    #[account(3, name = "reward_mint", desc = "Reward mint")]
+    #[account(4, name = "rewards_vault", desc = "Rewards vault (token account of the reward mint owned by the vault PDA)")]
+    #[account(5, name = "token_program", desc = "Token program (the reward mint's)")]
+    #[account(6, name = "rent", desc = "Rent sysvar")]
+    #[account(7, name = "system_program", desc = "System program")]
+    SetRewardsSchedule {
+        /// First slot whose trades earn rewards
+        start_slot: u64,
+        /// Trades from this slot on earn nothing
+        end_slot: u64,
+        /// Reward token base units per quote token of notional traded (1e9 precision)
+        reward_per_volume: u64,
+        /// Most reward tokens the schedule accrues in total (reward token base units)
+        total_emissions: u64,
+    },
+    /// 55. Create the caller's rewards account
+    #[account(0, writable, signer, name = "owner", desc = "Owner (pays for the account)")]
+    #[account(1, writable, name = "user_rewards", desc = "User rewards account (PDA: [USER_REWARDS_SEED, owner])")]
+    #[account(2, name = "rent", desc = "Rent sysvar")]
+    #[account(3, name = "system_program", desc = "System program")]
+    CreateUserRewards,
+    /// 56. Pay out the caller's accrued trading rewards
+    #[account(0, signer, name = "owner", desc = "Owner")]
+    #[account(1, writable, name = "user_rewards", desc = "User rewards account")]
+    #[account(2, name = "rewards_schedule", desc = "Rewards schedule")]
+    #[account(3, writable, name = "rewards_vault", desc = "Rewards vault")]
+    #[account(4, writable, name = "owner_reward_token_account", desc = "Owner's reward token account")]
+    #[account(5, name = "reward_mint", desc = "Reward mint")]
+    #[account(6, name = "token_program", desc = "Token program (the reward mint's)")]
+    #[account(7, name = "config", desc = "Config account")]
+    #[account(8, name = "vault_pda", desc = "Vault PDA (the rewards vault's authority)")]
+    ClaimRewards,
+    /// 57. Withdraw a position's accrued maker rebates
+    #[account(0, signer, name = "position_owner", desc = "Position owner")]
+    #[account(1, name = "token_program", desc = "Token program")]
+    #[account(2, writable, name = "owner_token_account", desc = "Owner's token account (to receive the rebates)")]
+    #[account(3, writable, name = "vault", desc = "Vault token account (PDA‑owned)")]
+    #[account(4, writable, name = "position", desc = "Position account")]
+    #[account(5, writable, name = "market_state", desc = "Market state account")]
+    #[account(6, name = "config", desc = "Config account")]
+    #[account(7, name = "quote_mint", desc = "Quote mint")]
+    ClaimMakerRebates,
+    /// 58. Replace the volume-based taker fee tiers (admin)
+    #[account(0, signer, name = "admin", desc = "Admin")]
+    #[account(1, writable, name = "config", desc = "Config account")]
+    SetFeeTiers {
+        /// Tiers in ascending order of min_volume, at most MAX_FEE_TIERS
+        fee_tiers: Vec<FeeTier>,
+    },
+    /// 59. Set the stake token and its trading fee discount schedule (admin)
+    #[account(0, signer, name = "admin", desc = "Admin")]
+    #[account(1, writable, name = "config", desc = "Config account")]
+    #[account(2, name = "stake_mint", desc = "Stake mint")]
+    #[account(3, name = "stake_vault", desc = "Stake vault (token account of the stake mint owned by the vault PDA)")]
+    #[account(4, name = "token_program", desc = "Token program (the stake mint's)")]
+    SetStakeDiscounts {
+        /// Discounts in ascending order of a non-zero min_stake, at most MAX_STAKE_DISCOUNTS
+        stake_discounts: Vec<StakeDiscount>,
+    },
+    /// 60. Stake protocol tokens for a trading fee discount
+    #[account(0, writable, signer, name = "owner", desc = "Owner (pays for the stake account on first use)")]
+    #[account(1, name = "token_program", desc = "Token program (the stake mint's)")]
+    #[account(2, writable, name = "owner_stake_token_account", desc = "Owner's stake token account")]
+    #[account(3, writable, name = "stake_vault", desc = "Stake vault")]
+    #[account(4, writable, name = "stake_account", desc = "Stake account (PDA: [STAKE_SEED, owner])")]
+    #[account(5, name = "config", desc = "Config account")]
+    #[account(6, name = "stake_mint", desc = "Stake mint")]
+    #[account(7, name = "rent", desc = "Rent sysvar")]
+    #[account(8, name = "system_program", desc = "System program")]
+    StakeTokens {
+        /// Stake token base units
+        amount: u64,
+    },
+    /// 61. Withdraw staked protocol tokens once their lockup has passed
+    #[account(0, signer, name = "owner", desc = "Owner")]
+    #[account(1, name = "token_program", desc = "Token program (the stake mint's)")]
+    #[account(2, writable, name = "owner_stake_token_account", desc = "Owner's stake token account")]
+    #[account(3, writable, name = "stake_vault", desc = "Stake vault")]
+    #[account(4, writable, name = "stake_account", desc = "Stake account")]
+    #[account(5, name = "config", desc = "Config account")]
+    #[account(6, name = "stake_mint", desc = "Stake mint")]
+    #[account(7, name = "vault_pda", desc = "Vault PDA (the stake vault's authority)")]
+    UnstakeTokens {
+        /// Stake token base units
+        amount: u64,
+    },
+    /// 62. Replace a market's position-size risk tiers (admin)
+    #[account(0, signer, name = "admin", desc = "Admin")]
+    #[account(1, name = "config", desc = "Config account")]
+    #[account(2, writable, name = "market_state", desc = "Market state account")]
+    SetRiskTiers {
+        /// Tiers in ascending order of min_notional, at most MAX_RISK_TIERS
+        risk_tiers: Vec<RiskTier>,
+    },
+    /// 63. Make a market permissioned or open (admin)
+    #[account(0, signer, name = "admin", desc = "Admin")]
+    #[account(1, name = "config", desc = "Config account")]
+    #[account(2, writable, name = "market_state", desc = "Market state account")]
+    SetMarketAccess {
+        /// Whether only whitelisted wallets and access mint holders may grow positions
+        permissioned: bool,
+        /// Mint whose holders also get access; the default pubkey = whitelist only
+        access_mint: Pubkey,
+    },
+    /// 64. Add a wallet to or remove it from the trading whitelist (admin)
+    #[account(0, writable, signer, name = "admin", desc = "Admin (pays for a new entry, receives a removed one's rent)")]
+    #[account(1, name = "config", desc = "Config account")]
+    #[account(2, writable, name = "whitelist_entry", desc = "Whitelist entry (PDA: [WHITELIST_SEED, wallet])")]
+    #[account(3, name = "rent", desc = "Rent sysvar")]
+    #[account(4, name = "system_program", desc = "System program")]
+    SetWhitelisted {
+        /// Wallet to add or remove
+        wallet: Pubkey,
+        /// true adds the wallet, false removes it
+        whitelisted: bool,
+    },
+    /// 65. Restrict liquidations to the liquidator registry, or make them permissionless (admin)
+    #[account(0, signer, name = "admin", desc = "Admin")]
+    #[account(1, writable, name = "config", desc = "Config account")]
+    SetLiquidatorAllowlist {
+        /// Whether only registered liquidators may liquidate
+        enabled: bool,
+    },
+    /// 66. Add a liquidator to or remove it from the liquidator registry (admin)
+    #[account(0, writable, signer, name = "admin", desc = "Admin (pays for the registry on first use)")]
+    #[account(1, name = "config", desc = "Config account")]
+    #[account(2, writable, name = "liquidator_registry", desc = "Liquidator registry (PDA: [LIQUIDATOR_REGISTRY_SEED])")]
+    #[account(3, name = "rent", desc = "Rent sysvar")]
+    #[account(4, name = "system_program", desc = "System program")]
+    SetLiquidator {
+        /// Liquidator to add or remove
+        liquidator: Pubkey,
+        /// true adds the liquidator, false removes it
+        allowed: bool,
+    },
+    /// 67. Cancel all of the caller's resting limit orders in a market
+    #[account(0, signer, name = "order_owner", desc = "Order owner")]
+    #[account(1, writable, name = "orderbook", desc = "Order book account")]
+    CancelAllOrders,
+    /// 68. Place and cancel up to MAX_BATCH_ORDER_OPS resting limit orders at once
+    #[account(0, writable, signer, name = "user", desc = "User (pays for the order book on first use)")]
+    #[account(1, name = "position", desc = "Position account placed orders fill (owned by user)")]
+    #[account(2, name = "market_state", desc = "Market state account")]
+    #[account(3, writable, name = "orderbook", desc = "Order book account (PDA: [ORDERBOOK_SEED, market_state])")]
+    #[account(4, name = "rent", desc = "Rent sysvar")]
+    #[account(5, name = "clock", desc = "Clock sysvar")]
+    #[account(6, name = "system_program", desc = "System program")]
+    BatchOrders {
+        /// Operations, applied in order; the batch fails as a whole
+        ops: Vec<OrderOp>,
+    },
+    /// 69. Liquidate every eligible isolated position among the trailing accounts
+    #[account(0, signer, name = "liquidator", desc = "Liquidator")]
+    #[account(1, name = "token_program", desc = "Token program")]
+    #[account(2, writable, name = "liquidator_token_account", desc = "Liquidator's token account (to receive the liquidator's share of the fees)")]
+    #[account(3, writable, name = "vault", desc = "Vault token account (PDA‑owned)")]
+    #[account(4, writable, name = "market_state", desc = "Market state account")]
+    #[account(5, name = "clock", desc = "Clock sysvar")]
+    #[account(6, name = "config", desc = "Config account")]
+    #[account(7, name = "quote_mint", desc = "Quote mint")]
+    LiquidateMany,
+    /// 70. Settle a position's pending funding (permissionless)
+    #[account(0, writable, name = "position", desc = "Position account")]
+    #[account(1, writable, name = "market_state", desc = "Market state account the position trades in")]
+    #[account(2, name = "config", desc = "Config account")]
+    #[account(3, writable, optional, name = "user_account", desc = "Owner's user account")]
+    SettleFunding,
+    /// 71. Set a market's mark price EMA half-life and whether liquidations check it (admin)
+    #[account(0, signer, name = "admin", desc = "Admin")]
+    #[account(1, name = "config", desc = "Config account")]
+    #[account(2, writable, name = "market_state", desc = "Market state account")]
+    #[account(3, name = "clock", desc = "Clock sysvar")]
+    SetMarkPriceEma {
+        /// Slots over which an old mark price's weight halves (0 = the EMA follows the mark)
+        half_life_slots: u64,
+        /// Whether a position must also be under-collateralized at the EMA to be liquidated
+        ema_liquidation: bool,
+    },
+    /// 72. Set the backup feeds aggregated with a market's index oracle (admin)
+    #[account(0, signer, name = "admin", desc = "Admin")]
+    #[account(1, name = "config", desc = "Config account")]
+    #[account(2, writable, name = "market_state", desc = "Market state account")]
+    SetBackupOracles {
+        /// At most MAX_ORACLE_FEEDS - 1 feeds, none the index oracle; empty removes them
+        backup_oracles: Vec<Pubkey>,
+    },
+    /// 73. Create a keeper-pushed price feed or change its price authority (admin)
+    #[account(0, writable, signer, name = "admin", desc = "Admin (pays for the feed on first use)")]
+    #[account(1, name = "config", desc = "Config account")]
+    #[account(2, writable, name = "price_feed", desc = "Price feed (PDA: [PRICE_FEED_SEED, feed_id])")]
+    #[account(3, name = "rent", desc = "Rent sysvar")]
+    #[account(4, name = "system_program", desc = "System program")]
+    SetPriceFeed {
+        /// Identifier the feed's PDA is derived from
+        feed_id: u64,
+        /// Key allowed to push the feed's prices
+        authority: Pubkey,
+    },
+    /// 74. Publish a price to a price feed (price authority)
+    #[account(0, signer, name = "price_authority", desc = "Price authority")]
+    #[account(1, writable, name = "price_feed", desc = "Price feed")]
+    #[account(2, name = "clock", desc = "Clock sysvar")]
+    PushPrice {
+        /// Price (1e9 precision)
+        price: u64,
+        /// Confidence interval (1e9 precision)
+        conf: u64,
+        /// Unix timestamp the price was observed at; must increase with every push
+        timestamp: i64,
+    },
+    /// 75. Set how long a market holds positions after they grow before they can be reduced (admin)
+    #[account(0, signer, name = "admin", desc = "Admin")]
+    #[account(1, name = "config", desc = "Config account")]
+    #[account(2, writable, name = "market_state", desc = "Market state account")]
+    SetMinHoldSlots {
+        /// Slots after an increase before the position can be reduced, closed or withdrawn
+        /// from (at most MAX_MIN_HOLD_SLOTS; 0 = only the slot of the increase)
+        min_hold_slots: u64,
+    },
+    /// 76. Set how much of a position one liquidation can close per slot (admin)
+    #[account(0, signer, name = "admin", desc = "Admin")]
+    #[account(1, name = "config", desc = "Config account")]
+    #[account(2, writable, name = "market_state", desc = "Market state account")]
+    SetMaxLiquidationShare {
+        /// Most of a position's base one liquidation closes, once per slot (1e9 precision,
+        /// at least MIN_LIQUIDATION_SHARE; 0 = the whole position)
+        max_liquidation_share: u64,
+    },
+    /// 77. Set a market's liquidation grace period for flagged positions and its hard floor (admin)
+    #[account(0, signer, name = "admin", desc = "Admin")]
+    #[account(1, name = "config", desc = "Config account")]
+    #[account(2, writable, name = "market_state", desc = "Market state account")]
+    SetLiquidationGrace {
+        /// Slots a position must have been flagged for before it can be liquidated (at most
+        /// MAX_LIQUIDATION_GRACE_SLOTS; 0 = liquidations need no flag)
+        grace_slots: u64,
+        /// Collateral ratio below which a position is liquidated at once (1e9 precision;
+        /// required with a grace period)
+        floor_ratio: u64,
+    },
+    /// 78. Flag an under-collateralized position, starting its liquidation grace period (permissionless)
+    #[account(0, writable, name = "position", desc = "Position account")]
+    #[account(1, name = "market_state", desc = "Market state account the position trades in")]
+    #[account(2, name = "clock", desc = "Clock sysvar")]
+    #[account(3, name = "config", desc = "Config account")]
+    FlagForLiquidation,
+    /// 79. View: health factor of a position as liquidations measure it
+    #[account(0, name = "position", desc = "Position account")]
+    #[account(1, name = "market_state", desc = "Market state account the position trades in")]
+    #[account(2, name = "clock", desc = "Clock sysvar")]
+    #[account(3, name = "config", desc = "Config account")]
+    CheckHealth,
+    /// 80. Set the treasury protocol fees are withdrawn to (admin)
+    #[account(0, signer, name = "admin", desc = "Admin")]
+    #[account(1, writable, name = "config", desc = "Config account")]
+    #[account(2, name = "treasury", desc = "Treasury token account (quote token)")]
+    SetTreasury,
+    /// 81. Withdraw protocol fees from a market's fee pool to the treasury (admin)
+    #[account(0, signer, name = "admin", desc = "Admin")]
+    #[account(1, name = "config", desc = "Config account")]
+    #[account(2, name = "token_program", desc = "Token program")]
+    #[account(3, writable, name = "vault", desc = "Vault token account (PDA‑owned)")]
+    #[account(4, writable, name = "market_state", desc = "Market state account")]
+    #[account(5, writable, name = "treasury", desc = "Treasury token account")]
+    #[account(6, name = "quote_mint", desc = "Quote mint")]
+    WithdrawFees {
+        /// Fees to withdraw (quote token)
+        amount: u64,
+    },
+    /// 82. Cap the collateral deposited into a market's isolated positions (admin)
+    #[account(0, signer, name = "admin", desc = "Admin")]
+    #[account(1, name = "config", desc = "Config account")]
+    #[account(2, writable, name = "market_state", desc = "Market state account")]
+    SetMaxTotalCollateral {
+        /// Cap on the market's total_collateral (quote token; 0 = no cap)
+        max_total_collateral: u64,
+    },
+    /// 83. Set a market's withdrawal cooldown and per-epoch payout cap (admin)
+    #[account(0, signer, name = "admin", desc = "Admin")]
+    #[account(1, name = "config", desc = "Config account")]
+    #[account(2, writable, name = "market_state", desc = "Market state account")]
+    SetWithdrawalLimits {
+        /// Slots a position waits after a payout before its next one (at most
+        /// MAX_WITHDRAWAL_COOLDOWN_SLOTS; 0 = no cooldown)
+        cooldown_slots: u64,
+        /// Most collateral paid out to position owners per epoch (quote token; 0 = no cap)
+        max_epoch_withdrawal: u64,
+    },
+    /// 84. Give a wallet its own max leverage in a market, or remove it (admin)
+    #[account(0, writable, signer, name = "admin", desc = "Admin (pays for a new override, receives a removed one's rent)")]
+    #[account(1, name = "config", desc = "Config account")]
+    #[account(2, name = "market_state", desc = "Market state account")]
+    #[account(3, writable, name = "leverage_override", desc = "Leverage override (PDA: [LEVERAGE_OVERRIDE_SEED, market_state, wallet])")]
+    #[account(4, name = "rent", desc = "Rent sysvar")]
+    #[account(5, name = "system_program", desc = "System program")]
+    SetLeverageOverride {
+        /// Wallet to give the override
+        wallet: Pubkey,
+        /// Max leverage for the wallet's position increases (1e9 precision; 0 = remove the override)
+        max_leverage: u64,
+    },
+    /// 85. Tie an isolated position to a program-minted NFT whose holder acts for it
+    #[account(0, writable, signer, name = "owner", desc = "Position owner (pays for the mint and token account)")]
+    #[account(1, name = "token_program", desc = "Token program")]
+    #[account(2, writable, name = "position", desc = "Position account")]
+    #[account(3, name = "market_state", desc = "Market state account")]
+    #[account(4, writable, name = "position_mint", desc = "Position mint (PDA: [POSITION_MINT_SEED, position])")]
+    #[account(5, writable, name = "position_holder", desc = "Owner's position token account (PDA: [POSITION_HOLDER_SEED, position])")]
+    #[account(6, name = "vault", desc = "Vault token account (PDA; mints the NFT)")]
+    #[account(7, name = "config", desc = "Config account")]
+    #[account(8, name = "quote_mint", desc = "Quote mint")]
+    #[account(9, name = "rent", desc = "Rent sysvar")]
+    #[account(10, name = "system_program", desc = "System program")]
+    TokenizePosition,
+    /// 86. Link a stop-loss and a take-profit of one position into a one-cancels-other pair
+    #[account(0, signer, name = "trigger_owner", desc = "Trigger owner")]
+    #[account(1, writable, name = "trigger_order", desc = "Trigger order account")]
+    #[account(2, writable, name = "linked_trigger", desc = "Trigger order account of the opposite kind on the same position")]
+    LinkTriggerOrders,
+    /// 87. Switch the program into or out of settlement-only mode: closes, withdrawals and liquidations only (admin)
+    #[account(0, signer, name = "admin", desc = "Admin")]
+    #[account(1, writable, name = "config", desc = "Config account")]
+    SetSettlementOnly {
+        /// Whether only closes, withdrawals and liquidations are processed
+        enabled: bool,
+    },
+    /// 88. Create a market's cumulative metrics account
+    #[account(0, writable, signer, name = "payer", desc = "Payer")]
+    #[account(1, name = "market_state", desc = "Market state account")]
+    #[account(2, writable, name = "market_metrics", desc = "Market metrics account (PDA: [MARKET_METRICS_SEED, market_state])")]
+    #[account(3, name = "rent", desc = "Rent sysvar")]
+    #[account(4, name = "system_program", desc = "System program")]
+    CreateMarketMetrics,
+    /// 89. Create a market's funding rate history
+    #[account(0, writable, signer, name = "payer", desc = "Payer")]
+    #[account(1, name = "market_state", desc = "Market state account")]
+    #[account(2, writable, name = "funding_history", desc = "Funding history account (PDA: [FUNDING_HISTORY_SEED, market_state])")]
+    #[account(3, name = "rent", desc = "Rent sysvar")]
+    #[account(4, name = "system_program", desc = "System program")]
+    CreateFundingHistory,
+    /// 90. Create a market's hourly mark price candles
+    #[account(0, writable, signer, name = "payer", desc = "Payer")]
+    #[account(1, name = "market_state", desc = "Market state account")]
+    #[account(2, writable, name = "price_history", desc = "Price history account (PDA: [PRICE_HISTORY_SEED, market_state])")]
+    #[account(3, name = "rent", desc = "Rent sysvar")]
+    #[account(4, name = "system_program", desc = "System program")]
+    CreatePriceHistory,
+    /// 91. Withdraw a position's claimable funding and choose whether later funding compounds
+    #[account(0, signer, name = "position_owner", desc = "Position owner, or the holder of a tokenized position's NFT")]
+    #[account(1, name = "token_program", desc = "Token program")]
+    #[account(2, writable, name = "owner_token_account", desc = "Token account to receive the funding (quote token, any owner)")]
+    #[account(3, writable, name = "vault", desc = "Vault token account (PDA‑owned)")]
+    #[account(4, writable, name = "position", desc = "Position account")]
+    #[account(5, writable, name = "market_state", desc = "Market state account")]
+    #[account(6, name = "config", desc = "Config account")]
+    #[account(7, name = "quote_mint", desc = "Quote mint")]
+    #[account(8, optional, name = "position_token_account", desc = "Signer's token account holding a tokenized position's NFT")]
+    ClaimFunding {
+        /// Whether funding received from now on is added to the collateral instead of accruing
+        auto_compound: bool,
+    },
+    /// 92. Open a position or grow it on its side; the same accounts as open_position
+    #[account(0, signer, name = "user", desc = "User (position owner, or its delegate for an existing position)")]
+    #[account(1, name = "token_program", desc = "Token program")]
+    #[account(2, writable, name = "user_token_account", desc = "User's collateral token account (quote token, e.g., USDC)")]
+    #[account(3, writable, name = "vault", desc = "Vault token account (PDA‑owned)")]
+    #[account(4, writable, name = "position", desc = "Position account (PDA: [POSITION_SEED, market_state, user, sub_account_id])")]
+    #[account(5, writable, name = "market_state", desc = "Market state account (PDA‑derived)")]
+    #[account(6, name = "rent", desc = "Rent sysvar")]
+    #[account(7, name = "clock", desc = "Clock sysvar")]
+    #[account(8, name = "system_program", desc = "System program (for account creation)")]
+    #[account(9, name = "config", desc = "Config account")]
+    #[account(10, name = "quote_mint", desc = "Quote mint")]
+    #[account(11, optional, name = "index_oracle", desc = "The market's index oracle, then its backup oracles")]
+    IncreasePosition {
+        /// Signed size change (market base units); must be on the position's side unless it is flat
+        base_delta: i64,
+        /// Quote collateral to deposit (quote token)
+        collateral_delta: u64,
+        /// Worst acceptable average fill price (max for longs, min for shorts; 0 = no limit)
+        price_limit: u64,
+        /// OPEN_FLAG_CROSS_MARGIN and OPEN_FLAG_SIMULATE bits
+        flags: u8,
+        /// Which of the owner's positions in the market to trade
+        sub_account_id: u16,
+    },
+    /// 93. Shrink a position without flipping it, realizing PnL on the part closed
+    ///
+    /// Takes the same accounts as open_position.
+    #[account(0, signer, name = "user", desc = "User (position owner, or its delegate for an existing position)")]
+    #[account(1, name = "token_program", desc = "Token program")]
+    #[account(2, writable, name = "user_token_account", desc = "User's collateral token account (quote token, e.g., USDC)")]
+    #[account(3, writable, name = "vault", desc = "Vault token account (PDA‑owned)")]
+    #[account(4, writable, name = "position", desc = "Position account (PDA: [POSITION_SEED, market_state, user, sub_account_id])")]
+    #[account(5, writable, name = "market_state", desc = "Market state account (PDA‑derived)")]
+    #[account(6, name = "rent", desc = "Rent sysvar")]
+    #[account(7, name = "clock", desc = "Clock sysvar")]
+    #[account(8, name = "system_program", desc = "System program (for account creation)")]
+    #[account(9, name = "config", desc = "Config account")]
+    #[account(10, name = "quote_mint", desc = "Quote mint")]
+    #[account(11, optional, name = "index_oracle", desc = "The market's index oracle, then its backup oracles")]
+    DecreasePosition {
+        /// Size to close (market base units), at most the position's
+        base_amount: u64,
+        /// Worst acceptable average fill price (min when closing longs, max when closing shorts;
+        /// 0 = no limit)
+        price_limit: u64,
+        /// OPEN_FLAG_SIMULATE bit
+        flags: u8,
+        /// Which of the owner's positions in the market to trade
+        sub_account_id: u16,
+    },
+    /// 94. Update a market's risk limits (admin)
+    #[account(0, signer, name = "admin", desc = "Admin")]
+    #[account(1, name = "config", desc = "Config account")]
+    #[account(2, writable, name = "market_state", desc = "Market state account")]
+    UpdateMarketParams {
+        /// Max leverage of the market (1e9 precision)
+        max_leverage: u64,
+        /// Open interest cap of the market (market base units)
+        max_open_interest: u64,
+        /// Size at which the vAMM price impact reaches 100% before its cap (market base units;
+        /// 0 = no impact); unchanged if absent
+        price_impact_depth: Option<u64>,
+        /// Skew fee and rebate of the market's vAMM trades (1e9 precision; 0 = none); unchanged
+        /// if absent
+        skew_fee: Option<u64>,
+    },
+}
+
+impl PerpsInstruction {
+    /// Instruction data: the tag, INSTRUCTION_VERSION, then the fields
+    pub fn pack(&self) -> Vec<u8> {
+        let mut data = self.try_to_vec().expect("instruction serialization");
+        data.insert(1, INSTRUCTION_VERSION);
+        data
+    }
+
+    /// Decode instruction data of any supported version, or a pre-version payload
+    /// of an instruction with a fixed-size payload
+    pub fn unpack(data: &[u8]) -> Result<Self, ProgramError> {
+        let (&tag, rest) = data.split_first().ok_or(ProgramError::InvalidInstructionData)?;
+        if let Some((&version, fields)) = rest.split_first() {
+            if let Some(instruction) = Self::decode_fields(tag, version, fields) {
+                return Ok(instruction);
+            }
+        }
+
+        // A fixed-size payload is one byte shorter without the version, so it can't be
+        // mistaken for a versioned one; variable-size payloads could, and must be versioned
+        match Self::try_from_slice(data) {
+            Ok(instruction) if instruction.has_fixed_size() => Ok(instruction),
+            _ => Err(ProgramError::InvalidInstructionData),
+        }
+    }
+
+    /// Decode `fields` as `version`'s layout of instruction `tag`. Layouts retired by
+    /// a version bump are matched here and upgraded to the current variant.
+    fn decode_fields(tag: u8, version: u8, fields: &[u8]) -> Option<Self> {
+        match version {
+            INSTRUCTION_VERSION => {
+                let mut data = Vec::with_capacity(1 + fields.len());
+                data.push(tag);
+                data.extend_from_slice(fields);
+                Self::try_from_slice(&data).ok()
+            }
+            // Version 1's update_params also set one market's limits and read global amounts
+            // in that market's units, so it is rejected rather than reinterpreted; every other
+            // layout is unchanged
+            1 if tag != UPDATE_PARAMS_TAG => Self::decode_fields(tag, INSTRUCTION_VERSION, fields),
+            _ => None,
+        }
+    }
+
+    /// Whether every payload of this instruction has the same length (no Option or
+    /// Vec fields); a variant gaining one must be added here
+    fn has_fixed_size(&self) -> bool {
+        !matches!(
+            self,
+            PerpsInstruction::InitializeConfig { .. }
+                | PerpsInstruction::UpdateParams { .. }
+                | PerpsInstruction::UpdateMarketParams { .. }
+                | PerpsInstruction::SetIndexOracle { .. }
+                | PerpsInstruction::SetFeeTiers { .. }
+                | PerpsInstruction::SetStakeDiscounts { .. }
+                | PerpsInstruction::SetRiskTiers { .. }
+                | PerpsInstruction::BatchOrders { .. }
+                | PerpsInstruction::SetBackupOracles { .. }
+        )
+    }
+}
@@ -0,0 +1,238 @@
+//! Reference keeper bot (`keeper` feature)
+//!
+//! Runs a market's two permissionless cranks against an RPC endpoint, each on
+//! its own interval: `update_funding`, collecting the keeper reward and recording
+//! the settlement and mark price in the market's funding and price histories
+//! when they exist, and a liquidation scan built on the `client` helpers. In
+//! markets with a liquidation grace period the scan flags under-collateralized
+//! positions first and liquidates them once the grace period is over, or at
+//! once below the market's floor ratio.
+//!
+//! A failed round is reported on stderr and retried at the next interval, so
+//! a dropped RPC call or one position the program rejects doesn't stop the
+//! bot. The scan inherits the client's limits: only isolated positions are
+//! checked, counting their quote collateral.
+
+use std::thread;
+use std::time::{Duration, Instant};
+
+use solana_client::rpc_client::RpcClient;
+use solana_program::{
+    instruction::{AccountMeta, Instruction},
+    program_error::ProgramError,
+    pubkey::Pubkey,
+};
+use solana_sdk::{
+    signature::{Keypair, Signature, Signer},
+    transaction::Transaction,
+};
+
+use crate::client::{
+    fetch_config, fetch_market_state, fetch_positions, flag_for_liquidation_instruction, liquidate_instruction,
+    liquidation_health, update_funding_instruction, ClientError,
+};
+use crate::funding_history::funding_history_address;
+use crate::math::PRECISION;
+use crate::price_history::price_history_address;
+use crate::{Config, MarketState, Position};
+
+/// Default time between `update_funding` cranks (about one 150-slot funding period)
+pub const DEFAULT_FUNDING_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Default time between liquidation scans
+pub const DEFAULT_LIQUIDATION_INTERVAL: Duration = Duration::from_secs(5);
+
+/// The market a keeper cranks and where its rewards go
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeeperConfig {
+    /// Perps program
+    pub program_id: Pubkey,
+    /// Market state account to crank
+    pub market: Pubkey,
+    /// Quote token account receiving funding rewards and liquidation fees
+    pub keeper_token_account: Pubkey,
+    /// Time between `update_funding` cranks
+    pub funding_interval: Duration,
+    /// Time between liquidation scans
+    pub liquidation_interval: Duration,
+}
+
+impl KeeperConfig {
+    /// Crank `market` at the default intervals
+    pub fn new(program_id: Pubkey, market: Pubkey, keeper_token_account: Pubkey) -> Self {
+        Self {
+            program_id,
+            market,
+            keeper_token_account,
+            funding_interval: DEFAULT_FUNDING_INTERVAL,
+            liquidation_interval: DEFAULT_LIQUIDATION_INTERVAL,
+        }
+    }
+}
+
+/// What a liquidation scan does with a position
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeeperAction {
+    /// Start its grace period with `flag_for_liquidation`
+    Flag,
+    /// Liquidate it
+    Liquidate,
+}
+
+/// Positions acted on by one liquidation scan
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct ScanReport {
+    /// Positions flagged for liquidation
+    pub flagged: usize,
+    /// Positions liquidated
+    pub liquidated: usize,
+    /// Transactions the program or the RPC node rejected
+    pub failed: usize,
+}
+
+/// What the scan should do with an isolated `position` at `slot`, at the market's mark price:
+/// nothing while it is healthy, throttled or in its grace period
+pub fn position_action(
+    position: &Position,
+    market_state: &MarketState,
+    config: &Config,
+    slot: u64,
+) -> Result<Option<KeeperAction>, ProgramError> {
+    let Some((collateral_ratio, health_factor)) = liquidation_health(position, market_state, config, market_state.mark_price)?
+    else {
+        return Ok(None);
+    };
+    if health_factor >= PRECISION || slot < position.next_liquidation_slot {
+        return Ok(None);
+    }
+
+    let grace_slots = market_state.liquidation_grace_slots;
+    if grace_slots == 0 || collateral_ratio < market_state.liquidation_floor_ratio {
+        return Ok(Some(KeeperAction::Liquidate));
+    }
+
+    Ok(match position.margin_call_slot {
+        0 => Some(KeeperAction::Flag),
+        flagged_slot if slot >= flagged_slot.saturating_add(grace_slots) => Some(KeeperAction::Liquidate),
+        _ => None,
+    })
+}
+
+/// A keeper signing and paying for its transactions with `payer`
+pub struct Keeper {
+    rpc: RpcClient,
+    payer: Keypair,
+    config: KeeperConfig,
+}
+
+impl Keeper {
+    /// Keeper of the market in `config`, reading and sending through `rpc`
+    pub fn new(rpc: RpcClient, payer: Keypair, config: KeeperConfig) -> Self {
+        Self { rpc, payer, config }
+    }
+
+    /// Send one `update_funding`; a second crank within the same slot earns nothing
+    pub fn crank_funding(&self) -> Result<Signature, ClientError> {
+        let config = fetch_config(&self.rpc, &self.config.program_id)?;
+        let market_state = fetch_market_state(&self.rpc, &self.config.market)?;
+        let mut update_funding = update_funding_instruction(
+            &self.config.program_id,
+            &self.config.keeper_token_account,
+            &self.config.market,
+            &market_state,
+            &config,
+        )?;
+        // Record the settlement and the mark price in the market's histories someone has created
+        let histories = [
+            funding_history_address(&self.config.program_id, &self.config.market).0,
+            price_history_address(&self.config.program_id, &self.config.market).0,
+        ];
+        for history in histories {
+            if self.rpc.get_account(&history).is_ok() {
+                update_funding.accounts.push(AccountMeta::new(history, false));
+            }
+        }
+        self.send(update_funding)
+    }
+
+    /// Fetch the market's positions and flag or liquidate each one `position_action` picks,
+    /// one transaction per position
+    pub fn scan_liquidations(&self) -> Result<ScanReport, ClientError> {
+        let program_id = &self.config.program_id;
+        let config = fetch_config(&self.rpc, program_id)?;
+        let market_state = fetch_market_state(&self.rpc, &self.config.market)?;
+        let positions = fetch_positions(&self.rpc, program_id, &self.config.market)?;
+        let slot = self.rpc.get_slot()?;
+
+        let mut report = ScanReport::default();
+        for (position_key, position) in &positions {
+            let Ok(Some(action)) = position_action(position, &market_state, &config, slot) else {
+                continue;
+            };
+            let instruction = match action {
+                KeeperAction::Flag => flag_for_liquidation_instruction(program_id, position_key, position, &self.config.market, &config),
+                KeeperAction::Liquidate => liquidate_instruction(
+                    program_id,
+                    &self.payer.pubkey(),
+                    &self.config.keeper_token_account,
+                    position_key,
+                    position,
+                    &self.config.market,
+                    &market_state,
+                    &config,
+                )?,
+            };
+
+            match self.send(instruction) {
+                Ok(signature) => {
+                    match action {
+                        KeeperAction::Flag => report.flagged += 1,
+                        KeeperAction::Liquidate => report.liquidated += 1,
+                    }
+                    println!("{:?} {}: {}", action, position_key, signature);
+                }
+                Err(err) => {
+                    report.failed += 1;
+                    eprintln!("{:?} {} failed: {}", action, position_key, err);
+                }
+            }
+        }
+
+        Ok(report)
+    }
+
+    /// Run both loops until `should_stop` returns true, checked between rounds
+    pub fn run_until(&self, mut should_stop: impl FnMut() -> bool) {
+        let (mut next_funding, mut next_scan) = (Instant::now(), Instant::now());
+        while !should_stop() {
+            let now = Instant::now();
+            if now >= next_funding {
+                match self.crank_funding() {
+                    Ok(signature) => println!("Funding updated: {}", signature),
+                    Err(err) => eprintln!("Funding crank failed: {}", err),
+                }
+                next_funding = now + self.config.funding_interval;
+            }
+            if now >= next_scan {
+                match self.scan_liquidations() {
+                    Ok(report) => println!(
+                        "Liquidation scan: {} flagged, {} liquidated, {} failed",
+                        report.flagged, report.liquidated, report.failed
+                    ),
+                    Err(err) => eprintln!("Liquidation scan failed: {}", err),
+                }
+                next_scan = now + self.config.liquidation_interval;
+            }
+
+            thread::sleep(next_funding.min(next_scan).saturating_duration_since(Instant::now()));
+        }
+    }
+
+    /// Sign `instruction` with the payer and send it, waiting for confirmation
+    fn send(&self, instruction: Instruction) -> Result<Signature, ClientError> {
+        let blockhash = self.rpc.get_latest_blockhash()?;
+        let transaction =
+            Transaction::new_signed_with_payer(&[instruction], Some(&self.payer.pubkey()), &[&self.payer], blockhash);
+        Ok(self.rpc.send_and_confirm_transaction(&transaction)?)
+    }
+}
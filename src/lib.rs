@@ -1,40 +1,105 @@
 #![allow(unexpected_cfgs)]
 
 use borsh::{BorshDeserialize, BorshSerialize};
+use bytemuck::{Pod, Zeroable};
+use shank::ShankAccount;
 use solana_program::{
     account_info::{next_account_info, AccountInfo},
+    bpf_loader_upgradeable,
     entrypoint,
     entrypoint::ProgramResult,
     instruction::{AccountMeta, Instruction},
     msg,
-    program::{invoke, invoke_signed},
+    program::{invoke, invoke_signed, set_return_data},
     program_error::ProgramError,
+    pubkey,
     pubkey::Pubkey,
     sysvar::{clock::Clock, rent::Rent, Sysvar},
     system_instruction,
+    system_program,
 };
 
+pub mod access;
+#[cfg(feature = "client")]
+pub mod client;
+pub mod cross_margin;
+pub mod events;
+pub mod funding_history;
+pub mod instruction;
+#[cfg(feature = "keeper")]
+pub mod keeper;
+pub mod liquidity_pool;
+pub mod market_metrics;
+pub mod math;
+pub mod oracle;
+pub mod orderbook;
+pub mod position_token;
+pub mod price_feed;
+pub mod price_history;
+pub mod rewards;
+pub mod staking;
+pub mod trigger_orders;
+pub mod user_stats;
+pub mod views;
+
+use cross_margin::CrossMargin;
+use math::PRECISION;
+use instruction::PerpsInstruction;
+
 // Suppress warnings for educational implementation
 #[allow(unused)]
-
 /// PDA seed for the program's authority (used for collateral vault)
 pub const PDA_SEED: &[u8] = b"perps";
 
-/// Helper function to create a SPL token transfer instruction
-fn create_transfer_instruction(
+/// Legacy SPL Token program
+pub const SPL_TOKEN_PROGRAM_ID: Pubkey = pubkey!("TokenkegQfeZyiNwAJbNbGKPFXCWuBvf9Ss623VQ5DA");
+
+/// Token-2022 program; shares the legacy instruction layout for the calls made here
+pub const TOKEN_2022_PROGRAM_ID: Pubkey = pubkey!("TokenzQdBNbLqP5VEhdkAS6EPFLC1PHnBqCXEpPxuEb");
+
+/// Wrapped SOL mint of the legacy SPL Token program
+pub const NATIVE_MINT: Pubkey = pubkey!("So11111111111111111111111111111111111111112");
+
+/// Offset of the decimals byte in a mint account (same for both token programs)
+const MINT_DECIMALS_OFFSET: usize = 44;
+
+/// Size of a token account without extensions; Token-2022 accounts with extensions are longer
+const TOKEN_ACCOUNT_LEN: usize = 165;
+
+/// Offset of the state byte in a token account (0 = uninitialized, 1 = initialized, 2 = frozen)
+const TOKEN_ACCOUNT_STATE_OFFSET: usize = 108;
+
+/// Offset of the u64 LE balance in a token account
+const TOKEN_ACCOUNT_AMOUNT_OFFSET: usize = 64;
+
+/// `UpgradeableLoaderState::ProgramData`'s tag in its bincode encoding
+const PROGRAM_DATA_STATE_TAG: u32 = 3;
+
+/// Length of a ProgramData account's header: state tag, deployment slot and upgrade authority
+const PROGRAM_DATA_METADATA_LEN: usize = 4 + 8 + 1 + 32;
+
+/// Helper function to create a token `TransferChecked` instruction
+///
+/// Token-2022 rejects the plain `Transfer` for mints with extensions such as transfer fees,
+/// so every transfer names the mint and its decimals.
+fn create_transfer_checked_instruction(
     token_program: &Pubkey,
     source: &Pubkey,
+    mint: &Pubkey,
     destination: &Pubkey,
     authority: &Pubkey,
     amount: u64,
+    decimals: u8,
 ) -> Result<Instruction, ProgramError> {
-    let mut data = vec![3]; // Transfer instruction discriminator
+    let mut data = vec![12]; // TransferChecked instruction discriminator
     data.extend_from_slice(&amount.to_le_bytes());
+    data.push(decimals);
 
     Ok(Instruction {
         program_id: *token_program,
         accounts: vec![
             AccountMeta::new(*source, false),
+            AccountMeta::new_readonly(*mint, false),
             AccountMeta::new(*destination, false),
             AccountMeta::new_readonly(*authority, true),
         ],
@@ -42,14 +107,230 @@ fn create_transfer_instruction(
     })
 }
 
-/// Minimum collateral ratio (150% = 1.5 * 1e9)
-pub const MIN_COLLATERAL_RATIO: u64 = 1_500_000_000;
+/// Size of a token mint account without extensions (same for both token programs)
+const MINT_LEN: usize = 82;
+
+/// Helper function to create a token `InitializeMint2` instruction with no freeze authority
+fn create_initialize_mint2_instruction(token_program: &Pubkey, mint: &Pubkey, mint_authority: &Pubkey, decimals: u8) -> Instruction {
+    let mut data = vec![20, decimals]; // InitializeMint2 instruction discriminator
+    data.extend_from_slice(mint_authority.as_ref());
+    data.push(0); // No freeze authority
+
+    Instruction {
+        program_id: *token_program,
+        accounts: vec![AccountMeta::new(*mint, false)],
+        data,
+    }
+}
+
+/// Helper function to create a token `InitializeAccount3` instruction
+fn create_initialize_account3_instruction(token_program: &Pubkey, account: &Pubkey, mint: &Pubkey, owner: &Pubkey) -> Instruction {
+    let mut data = vec![18]; // InitializeAccount3 instruction discriminator
+    data.extend_from_slice(owner.as_ref());
+
+    Instruction {
+        program_id: *token_program,
+        accounts: vec![AccountMeta::new(*account, false), AccountMeta::new_readonly(*mint, false)],
+        data,
+    }
+}
+
+/// Helper function to create a token `SetAuthority` instruction that removes a mint's mint
+/// authority, fixing its supply
+fn create_revoke_mint_authority_instruction(token_program: &Pubkey, mint: &Pubkey, authority: &Pubkey) -> Instruction {
+    Instruction {
+        program_id: *token_program,
+        accounts: vec![AccountMeta::new(*mint, false), AccountMeta::new_readonly(*authority, true)],
+        data: vec![6, 0, 0], // SetAuthority discriminator, AuthorityType::MintTokens, no new authority
+    }
+}
+
+/// Helper function to create a token `MintToChecked` instruction
+fn create_mint_to_checked_instruction(
+    token_program: &Pubkey,
+    mint: &Pubkey,
+    destination: &Pubkey,
+    authority: &Pubkey,
+    amount: u64,
+    decimals: u8,
+) -> Instruction {
+    let mut data = vec![14]; // MintToChecked instruction discriminator
+    data.extend_from_slice(&amount.to_le_bytes());
+    data.push(decimals);
+
+    Instruction {
+        program_id: *token_program,
+        accounts: vec![
+            AccountMeta::new(*mint, false),
+            AccountMeta::new(*destination, false),
+            AccountMeta::new_readonly(*authority, true),
+        ],
+        data,
+    }
+}
+
+/// Helper function to create a token `BurnChecked` instruction
+fn create_burn_checked_instruction(
+    token_program: &Pubkey,
+    account: &Pubkey,
+    mint: &Pubkey,
+    owner: &Pubkey,
+    amount: u64,
+    decimals: u8,
+) -> Instruction {
+    let mut data = vec![15]; // BurnChecked instruction discriminator
+    data.extend_from_slice(&amount.to_le_bytes());
+    data.push(decimals);
+
+    Instruction {
+        program_id: *token_program,
+        accounts: vec![
+            AccountMeta::new(*account, false),
+            AccountMeta::new(*mint, false),
+            AccountMeta::new_readonly(*owner, true),
+        ],
+        data,
+    }
+}
+
+/// Helper function to create a token `SyncNative` instruction, which credits lamports sent to a
+/// wSOL account to its token balance
+fn create_sync_native_instruction(token_program: &Pubkey, account: &Pubkey) -> Instruction {
+    Instruction {
+        program_id: *token_program,
+        accounts: vec![AccountMeta::new(*account, false)],
+        data: vec![17], // SyncNative instruction discriminator
+    }
+}
+
+/// Whether `program_id` is one of the token programs vaults may live under
+pub fn is_supported_token_program(program_id: &Pubkey) -> bool {
+    *program_id == SPL_TOKEN_PROGRAM_ID || *program_id == TOKEN_2022_PROGRAM_ID
+}
+
+/// Seed for the global Config PDA
+pub const CONFIG_SEED: &[u8] = b"config";
+
+/// Default minimum collateral ratio (150% = 1.5 * 1e9)
+pub const DEFAULT_MIN_COLLATERAL_RATIO: u64 = 1_500_000_000;
+
+/// Default liquidation fee, in basis points of the closed notional (1%)
+pub const DEFAULT_LIQUIDATOR_FEE_BPS: u64 = 100;
+
+/// Default share of the liquidation fee kept by the market's insurance fund (25% = 0.25 * 1e9)
+pub const DEFAULT_INSURANCE_FUND_SHARE: u64 = 250_000_000;
 
-/// Liquidation penalty (10% = 0.1 * 1e9)
-pub const LIQUIDATION_PENALTY: u64 = 100_000_000;
+/// Basis points in 100%
+pub const BPS_DENOMINATOR: u64 = 10_000;
+
+/// Default trading fee charged on the notional of each position change (0.1% = 0.001 * 1e9)
+pub const DEFAULT_TRADING_FEE: u64 = 1_000_000;
+
+/// Default share of the trading fee credited to the referrer (20% = 0.2 * 1e9)
+pub const DEFAULT_REFERRAL_FEE_SHARE: u64 = 200_000_000;
+
+/// Funding rate per slot of a market with open interest on one side only (1e9 precision)
+pub const FUNDING_SKEW_RATE_PER_SLOT: i64 = 20_000;
+
+/// Default cap on |funding_rate_per_slot| (1e9 precision)
+pub const DEFAULT_MAX_FUNDING_RATE_PER_SLOT: u64 = 50_000;
+
+/// Slots per funding period, the unit the update_funding keeper reward accrues in (~1 minute)
+pub const FUNDING_PERIOD_SLOTS: u64 = 150;
+
+/// Default keeper reward per elapsed funding period (quote token base units)
+pub const DEFAULT_FUNDING_CRANK_REWARD: u64 = 10_000;
+
+/// Default cap on the keeper reward paid by a single update_funding (quote token base units)
+pub const DEFAULT_MAX_FUNDING_CRANK_REWARD: u64 = 100_000;
+
+/// Default smallest non-zero |base_amount| a position may be left with (0.001 units, 1e9 precision)
+pub const DEFAULT_MIN_POSITION_SIZE: u64 = 1_000_000;
+
+/// Default collateral below which a position that goes flat is swept into the insurance fund
+/// (0.001 quote, 1e9 precision)
+pub const DEFAULT_DUST_COLLATERAL: u64 = 1_000_000;
+
+/// Default cap on the funding a position pays in one settlement, as a share of its
+/// collateral (10%, 1e9 precision); the rest is carried forward
+pub const DEFAULT_MAX_FUNDING_SETTLEMENT_SHARE: u64 = 100_000_000;
+
+/// Default rebate paid to makers of crossed order matches (0.02%, 1e9 precision)
+pub const DEFAULT_MAKER_REBATE: u64 = 200_000;
+
+/// Cap on the price impact charged on a single vAMM fill (10%, 1e9 precision)
+pub const MAX_PRICE_IMPACT: u64 = 100_000_000;
+
+/// Max number of non-quote collateral mints the program accepts
+pub const MAX_COLLATERAL_ASSETS: usize = 4;
+
+/// Max number of volume-based taker fee tiers in the config
+pub const MAX_FEE_TIERS: usize = 4;
+
+/// Max number of position-size risk tiers per market
+pub const MAX_RISK_TIERS: usize = 4;
+
+/// Seed prefix for market state PDAs: [MARKET_SEED, market_id]
+pub const MARKET_SEED: &[u8] = b"market";
+
+/// Seed prefix for position PDAs: [POSITION_SEED, market_state, owner, sub_account_id]
+pub const POSITION_SEED: &[u8] = b"position";
+
+/// Seed prefix for referrer PDAs: [REFERRER_SEED, owner]
+pub const REFERRER_SEED: &[u8] = b"referrer";
+
+/// Max leverage (notional / collateral) a new market starts with (10x = 10 * 1e9)
+pub const DEFAULT_MAX_LEVERAGE: u64 = 10_000_000_000;
+
+/// Open interest cap a new market starts with (100,000 units, 1e9 precision)
+pub const DEFAULT_MAX_OPEN_INTEREST: u64 = 100_000_000_000_000;
+
+/// open_position flag: only accept changes that shrink |base_amount| without flipping sign
+pub const OPEN_FLAG_REDUCE_ONLY: u8 = 0b0000_0001;
+
+/// open_position flag: a position created by this call is cross-margined by the owner's user account
+pub const OPEN_FLAG_CROSS_MARGIN: u8 = 0b0000_0010;
+
+/// open_position flag: dry run on copies of the accounts, returning the expected fill, fees
+/// and post-trade health instead of writing anything
+pub const OPEN_FLAG_SIMULATE: u8 = 0b0000_0100;
+
+/// Position margin mode: the position's own collateral backs it (the default)
+pub const MARGIN_MODE_ISOLATED: u8 = 0;
+
+/// Position margin mode: the owner's cross-margin user account backs it
+pub const MARGIN_MODE_CROSS: u8 = 1;
+
+/// Virtual base reserve the vAMM is seeded with (1,000,000 units, 1e9 precision)
+pub const DEFAULT_VAMM_BASE_RESERVE: u64 = 1_000_000_000_000_000;
+
+/// Default max deviation of fills and the mark price from the index oracle (2%, 1e9 precision)
+pub const DEFAULT_MARK_PRICE_BAND: u64 = 20_000_000;
+
+/// Default index move within the circuit breaker window that trips it (10%, 1e9 precision)
+pub const DEFAULT_BREAKER_MAX_MOVE: u64 = 100_000_000;
+
+/// Default circuit breaker observation window (~1 minute)
+pub const DEFAULT_BREAKER_WINDOW_SLOTS: u64 = 150;
+
+/// Default time a tripped market stays reduce-only (~10 minutes)
+pub const DEFAULT_BREAKER_COOLDOWN_SLOTS: u64 = 1_500;
+
+/// Longest hold the admin can require after a position grows (~1 hour)
+pub const MAX_MIN_HOLD_SLOTS: u64 = 9_000;
+
+/// Smallest share of a position a throttled liquidation may close per slot (10%, 1e9 precision)
+pub const MIN_LIQUIDATION_SHARE: u64 = 100_000_000;
+
+/// Longest liquidation grace period the admin can give flagged positions (~10 minutes)
+pub const MAX_LIQUIDATION_GRACE_SLOTS: u64 = 1_500;
+
+/// Longest withdrawal cooldown the admin can impose on a market's positions (~1 day)
+pub const MAX_WITHDRAWAL_COOLDOWN_SLOTS: u64 = 216_000;
 
 /// Data stored in a user's position account
-#[derive(BorshSerialize, BorshDeserialize, Debug, Default, Clone)]
+#[repr(C)]
+#[derive(Pod, Zeroable, Debug, Default, Clone, Copy, PartialEq, Eq)]
 pub struct Position {
     /// Owner of the position
     pub owner: Pubkey,
@@ -61,10 +342,59 @@ pub struct Position {
     pub last_funding_index: i64,
     /// Entry price when position was opened (1e9 precision)
     pub entry_price: u64,
+    /// Layout version, bumped by migrate_account
+    pub version: u8,
+    /// MARGIN_MODE_ISOLATED or MARGIN_MODE_CROSS
+    pub margin_mode: u8,
+    /// Sub-account id, part of the PDA seeds
+    pub sub_account_id: u16,
+    /// PDA bump for [POSITION_SEED, market_state, owner, sub_account_id]
+    pub bump: u8,
+    /// Keeps the layout 8-byte aligned with no implicit padding
+    pub _padding: [u8; 3],
+    /// Deposits of each Config collateral asset, by asset index (native token units)
+    pub collateral_balances: [u64; MAX_COLLATERAL_ASSETS],
+    /// Wallet allowed to trade the position but not withdraw from it (default pubkey = none)
+    pub delegate: Pubkey,
+    /// Lifetime PnL realized on reductions, closes, liquidations and settlements (quote token)
+    pub realized_pnl: i64,
+    /// Lifetime funding received (positive) or paid (negative) (quote token)
+    pub cumulative_funding: i64,
+    /// Funding owed beyond the per-settlement cap, carried to later settlements (quote token)
+    pub pending_funding: i64,
+    /// Market long_loss_index at the last settlement
+    pub last_long_loss_index: u64,
+    /// Market short_loss_index at the last settlement
+    pub last_short_loss_index: u64,
+    /// Maker rebates earned by the position's resting orders, not yet claimed (quote token)
+    pub maker_rebates: u64,
+    /// Slot of the last open_position change that grew or flipped the position; reductions,
+    /// closes and withdrawals wait out the market's hold after it
+    pub last_modified_slot: u64,
+    /// First slot a throttled liquidation that left the position open allows the next one in
+    pub next_liquidation_slot: u64,
+    /// Slot flag_for_liquidation found the position under-collateralized in, starting its
+    /// market's liquidation grace period (0 = not flagged)
+    pub margin_call_slot: u64,
+    /// Slot collateral was last paid out to the owner by a close or settlement; the next
+    /// payout waits out the market's withdrawal cooldown after it (0 = never)
+    pub last_withdrawal_slot: u64,
+    /// NFT whose holder acts for the position instead of `owner` (default pubkey = not tokenized)
+    pub position_mint: Pubkey,
+    /// Funding received at settlements and not yet withdrawn with claim_funding (quote token)
+    pub claimable_funding: u64,
+    /// Non-zero to add received funding to the collateral instead, as set by claim_funding
+    pub auto_compound_funding: u8,
+    /// Keeps the layout 8-byte aligned with no implicit padding
+    pub _funding_padding: [u8; 7],
+    /// Quote collateral deposited while isolated and still counted in the market's
+    /// `total_collateral`, released once the collateral has left the position (quote token)
+    pub deposited_collateral: u64,
 }
 
 /// Global state for the market (single‑asset example)
-#[derive(BorshSerialize, BorshDeserialize, Debug, Default, Clone)]
+#[repr(C)]
+#[derive(Pod, Zeroable, Debug, Default, Clone, Copy, PartialEq, Eq)]
 pub struct MarketState {
     /// Index that accumulates funding payments (scaled by 1e9)
     pub funding_index: i64,
@@ -72,638 +402,6329 @@ pub struct MarketState {
     pub funding_rate_per_slot: i64,
     /// Total open interest (sum of |base_amount|)
     pub open_interest: u64,
-    /// PDA bump for authority
-    pub bump: u8,
     /// Last update slot for funding
     pub last_funding_slot: u64,
     /// Current mark price (1e9 precision) - in production use oracle
     pub mark_price: u64,
+    /// Trading fees retained by the protocol, net of referral shares (quote token)
+    pub fee_pool: u64,
+    /// vAMM virtual base reserve (1e9 precision)
+    pub base_reserve: u64,
+    /// vAMM virtual quote reserve (1e9 precision)
+    pub quote_reserve: u64,
+    /// Max notional / collateral for positions that grow (1e9 precision)
+    pub max_leverage: u64,
+    /// Cap on open_interest; trades that would grow OI past it are rejected
+    pub max_open_interest: u64,
+    /// PDA bump for authority
+    pub bump: u8,
+    /// Non-zero while paused: only position reductions, closes and liquidations are allowed
+    pub paused: u8,
+    /// Layout version, bumped by migrate_account
+    pub version: u8,
+    /// PDA bump for [MARKET_SEED, market_id] (0 for markets created before they were PDAs)
+    pub market_bump: u8,
+    /// Id the admin created the market under, part of the PDA seeds
+    pub market_id: u16,
+    /// Keeps the layout 8-byte aligned with no implicit padding
+    pub _padding: [u8; 2],
+    /// Liquidation fees retained to cover liquidator rewards on underwater positions (quote token)
+    pub insurance_fund: u64,
+    /// Trader profits realized out of the vault into collateral (quote token)
+    pub total_realized_profit: u64,
+    /// Trader losses realized out of collateral into the vault (quote token)
+    pub total_realized_loss: u64,
+    /// Losses and funding that exceeded the trader's collateral and went uncollected, in
+    /// total (quote token)
+    pub bad_debt: u64,
+    /// Sum of every position's signed base_amount
+    pub net_base_amount: i64,
+    /// Sum of every position's signed base_amount * entry_price (quote token)
+    pub net_entry_quote: i64,
+    /// Pyth feed opens are checked against (default pubkey = no band)
+    pub index_oracle: Pubkey,
+    /// Max deviation of open fills and the mark price from the index price (1e9 precision)
+    pub mark_price_band: u64,
+    /// Index move within the breaker window that trips it (1e9 precision, 0 = disabled)
+    pub breaker_max_move: u64,
+    /// Slots an index reference price is compared against before it is reset
+    pub breaker_window_slots: u64,
+    /// Slots a tripped market stays reduce-only
+    pub breaker_cooldown_slots: u64,
+    /// Index price at the start of the current breaker window (1e9 precision)
+    pub breaker_reference_price: u64,
+    /// Slot the current breaker window started
+    pub breaker_window_start_slot: u64,
+    /// Only reductions are allowed before this slot
+    pub reduce_only_until_slot: u64,
+    /// Price positions settle at after the market is wound down (0 = still trading)
+    pub settlement_price: u64,
+    /// Unix time a dated futures market stops accepting opens (0 = perpetual)
+    pub expiry_timestamp: i64,
+    /// Decimals of the size unit instructions use (one base token = 10^base_decimals)
+    pub base_decimals: u8,
+    /// Decimals of the quote mint, which instruction amounts and transfers use
+    pub quote_decimals: u8,
+    /// Keeps the layout 8-byte aligned with no implicit padding
+    pub _decimals_padding: [u8; 6],
+    /// Quote mint the market's collateral, payouts and vault are denominated in
+    pub quote_mint: Pubkey,
+    /// Part of `bad_debt` the insurance fund could not cover, still owed to the vault (quote token)
+    pub outstanding_bad_debt: u64,
+    /// Sum of every long position's base_amount
+    pub long_base_amount: i64,
+    /// Sum of every long position's base_amount * entry_price (quote token)
+    pub long_entry_quote: i64,
+    /// Cumulative socialized loss charged per unit of long size (1e9 precision)
+    pub long_loss_index: u64,
+    /// Cumulative socialized loss charged per unit of short size (1e9 precision)
+    pub short_loss_index: u64,
+    /// Bad debt haircut from winning positions' profits, in total (quote token)
+    pub socialized_loss: u64,
+    /// Size at which a vAMM fill pays 100% price impact before the cap; impact grows with
+    /// the square of size / depth (1e9 precision, 0 = no impact)
+    pub price_impact_depth: u64,
+    /// Sum of every long position's base_amount
+    pub long_open_interest: u64,
+    /// Sum of every short position's |base_amount|
+    pub short_open_interest: u64,
+    /// Fee charged on the notional a vAMM trade adds to the net skew, and rebated on the
+    /// notional it removes (1e9 precision, 0 = none)
+    pub skew_fee: u64,
+    /// Higher collateral ratios required of large positions, ascending by `min_notional`;
+    /// unused entries are zeroed
+    pub risk_tiers: [RiskTier; MAX_RISK_TIERS],
+    /// Mint whose holders may trade a permissioned market besides whitelisted wallets
+    /// (default pubkey = whitelist only)
+    pub access_mint: Pubkey,
+    /// Non-zero when only whitelisted wallets and access mint holders may grow positions
+    pub permissioned: u8,
+    /// Keeps the layout 8-byte aligned with no implicit padding
+    pub _access_padding: [u8; 7],
+    /// Exponential moving average of the mark price (1e9 precision, 0 = not sampled yet)
+    pub mark_price_ema: u64,
+    /// Slot the EMA was last sampled in
+    pub ema_last_slot: u64,
+    /// Slots over which an old mark price's weight in the EMA halves (0 = EMA follows the mark)
+    pub ema_half_life_slots: u64,
+    /// Non-zero when a position must also be under-collateralized at the EMA to be liquidated
+    pub ema_liquidation: u8,
+    /// Keeps the layout 8-byte aligned with no implicit padding
+    pub _ema_padding: [u8; 7],
+    /// Feeds whose prices are aggregated with `index_oracle`'s into a median (unused = default)
+    pub backup_oracles: [Pubkey; oracle::MAX_ORACLE_FEEDS - 1],
+    /// Slots after a position grows before it can be reduced, closed or withdrawn from; the
+    /// slot of the increase itself is always held (0 = that slot only)
+    pub min_hold_slots: u64,
+    /// Most of a position's base one liquidation closes, once per slot (1e9 precision; 0 = the
+    /// whole position)
+    pub max_liquidation_share: u64,
+    /// Slots a position must have been flagged for before it can be liquidated (0 = no flag needed)
+    pub liquidation_grace_slots: u64,
+    /// Collateral ratio below which a position is liquidated without waiting out the grace
+    /// period (1e9 precision)
+    pub liquidation_floor_ratio: u64,
+    /// Cap on `total_collateral`; deposits that would exceed it are rejected (quote token,
+    /// 1e9 precision; 0 = no cap)
+    pub max_total_collateral: u64,
+    /// Quote collateral deposited into the market's isolated positions that are still holding
+    /// collateral: the sum of their `deposited_collateral` (quote token, 1e9 precision)
+    pub total_collateral: u64,
+    /// Slots a position must wait after a payout before its next one (0 = no cooldown)
+    pub withdrawal_cooldown_slots: u64,
+    /// Most collateral paid out to position owners per epoch (quote token, 1e9 precision;
+    /// 0 = no cap)
+    pub max_epoch_withdrawal: u64,
+    /// Epoch `epoch_withdrawn` counts payouts for
+    pub withdrawal_epoch: u64,
+    /// Collateral paid out to position owners during `withdrawal_epoch` (quote token, 1e9 precision)
+    pub epoch_withdrawn: u64,
+    /// Funding owed by the long side across update_funding settlements (negative = received;
+    /// quote token, 1e9 precision)
+    pub long_funding: i64,
+    /// Funding owed by the short side across update_funding settlements (negative = received;
+    /// quote token, 1e9 precision)
+    pub short_funding: i64,
+    /// Funding the paying side owed beyond what the receiving side was owed, moved into the
+    /// insurance fund (quote token, 1e9 precision)
+    pub funding_residual: u64,
 }
 
-// ---------------------------------------------------------------------
-// Program entrypoint
-// ---------------------------------------------------------------------
-entrypoint!(process_instruction);
+/// A collateral ratio required of positions from a notional size on
+#[repr(C)]
+#[derive(Pod, Zeroable, BorshSerialize, BorshDeserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct RiskTier {
+    /// Position notional at the mark price that falls in the tier (1e9 precision)
+    pub min_notional: u64,
+    /// Collateral ratio required in the tier, for opens and against liquidation (1e9 precision)
+    pub min_collateral_ratio: u64,
+}
 
-pub fn process_instruction(
-    program_id: &Pubkey,
-    accounts: &[AccountInfo],
-    instruction_data: &[u8],
-) -> ProgramResult {
-    // First byte = instruction tag
-    let (tag, rest) = instruction_data
-        .split_first()
-        .ok_or(ProgramError::InvalidInstructionData)?;
-
-    match tag {
-        0 => open_position(program_id, accounts, rest),
-        1 => update_funding(program_id, accounts),
-        2 => liquidate(program_id, accounts),
-        3 => close_position(program_id, accounts),
-        _ => {
-            msg!("Invalid instruction tag: {}", tag);
-            Err(ProgramError::InvalidInstructionData)
-        }
+impl RiskTier {
+    /// Serialized size: min_notional + min_collateral_ratio
+    pub const LEN: usize = 8 + 8;
+}
+
+/// Length of the type tag that prefixes every program account
+pub const DISCRIMINATOR_LEN: usize = 8;
+
+/// A program-owned account type, stored as an 8-byte type tag followed by its body
+pub trait AccountType {
+    /// Type tag written at initialization and checked on every load
+    const DISCRIMINATOR: [u8; DISCRIMINATOR_LEN];
+    /// Size of the account body that follows the tag
+    const LEN: usize;
+    /// Bytes to allocate for the account: tag + body
+    const SPACE: usize = DISCRIMINATOR_LEN + Self::LEN;
+}
+
+/// A zero-copy account whose layout carries a version byte
+pub trait Versioned: AccountType {
+    /// Layout version written by this build of the program
+    const VERSION: u8;
+
+    /// Layout version stored in the account
+    fn version(&self) -> u8;
+}
+
+impl AccountType for Position {
+    const DISCRIMINATOR: [u8; DISCRIMINATOR_LEN] = *b"position";
+    /// owner + base_amount + collateral + last_funding_index + entry_price + version + margin_mode
+    /// + sub_account_id + padding + collateral_balances + delegate + realized_pnl + cumulative_funding
+    /// + pending_funding + last_long_loss_index + last_short_loss_index + maker_rebates + last_modified_slot
+    /// + next_liquidation_slot + margin_call_slot + last_withdrawal_slot + position_mint + claimable_funding
+    /// + auto_compound_funding + padding + deposited_collateral
+    const LEN: usize = 32 + 8 + 8 + 8 + 8 + 1 + 1 + 2 + 4 + 8 * MAX_COLLATERAL_ASSETS + 32 + 8 + 8 + 8 + 8 * 2 + 8 + 8 + 8 + 8 + 8 + 32
+        + 8 + 1 + 7 + 8;
+}
+
+impl AccountType for MarketState {
+    const DISCRIMINATOR: [u8; DISCRIMINATOR_LEN] = *b"market\0\0";
+    /// ten 8-byte fields + bump + paused + version + padding + insurance_fund
+    /// + total_realized_profit + total_realized_loss + bad_debt + net_base_amount + net_entry_quote
+    /// + index_oracle + mark_price_band + six circuit breaker fields + settlement_price + expiry_timestamp
+    /// + base_decimals + quote_decimals + padding + quote_mint + outstanding_bad_debt
+    /// + long_base_amount + long_entry_quote + two loss indices + socialized_loss + price_impact_depth
+    /// + long_open_interest + short_open_interest + skew_fee + MAX_RISK_TIERS risk tiers
+    /// + access_mint + permissioned + padding + mark_price_ema + ema_last_slot + ema_half_life_slots
+    /// + ema_liquidation + padding + backup_oracles + min_hold_slots + max_liquidation_share
+    /// + liquidation_grace_slots + liquidation_floor_ratio + max_total_collateral + total_collateral
+    /// + withdrawal_cooldown_slots + max_epoch_withdrawal + withdrawal_epoch + epoch_withdrawn
+    /// + long_funding + short_funding + funding_residual
+    const LEN: usize = 8 * 10 + 1 + 1 + 1 + 5 + 8 + 8 * 3 + 8 * 2 + 32 + 8 + 8 * 6 + 8 + 8 + 1 + 1 + 6 + 32 + 8 + 8 * 5 + 8 + 8 * 2 + 8
+        + MAX_RISK_TIERS * RiskTier::LEN + 32 + 1 + 7 + 8 * 3 + 1 + 7
+        + (oracle::MAX_ORACLE_FEEDS - 1) * 32 + 8 + 8 + 8 * 2 + 8 * 2 + 8 * 4 + 8 * 3;
+}
+
+// New fields are only ever appended, so migrate_account can grow older layouts in place
+impl Versioned for Position {
+    const VERSION: u8 = 15;
+
+    fn version(&self) -> u8 {
+        self.version
     }
 }
 
-// ---------------------------------------------------------------------
-// 0️⃣ Open / modify a position
-// ---------------------------------------------------------------------
-pub fn open_position(
-    program_id: &Pubkey,
-    accounts: &[AccountInfo],
-    data: &[u8],
-) -> ProgramResult {
-    // Accounts:
-    // 0. [signer] user
-    // 1. [] token program
-    // 2. [writable] user's collateral token account (quote token, e.g., USDC)
-    // 3. [writable] vault token account (PDA‑owned)
-    // 4. [writable] position account (PDA‑derived)
-    // 5. [writable] market state account (PDA‑derived)
-    // 6. [] rent sysvar
-    // 7. [] clock sysvar
-    // 8. [] system program (for account creation)
-    let accounts_iter = &mut accounts.iter();
-    let user = next_account_info(accounts_iter)?;
-    let token_program = next_account_info(accounts_iter)?;
-    let user_collateral = next_account_info(accounts_iter)?;
-    let vault = next_account_info(accounts_iter)?;
-    let position_acc = next_account_info(accounts_iter)?;
-    let market_state_acc = next_account_info(accounts_iter)?;
-    let rent_sysvar = next_account_info(accounts_iter)?;
-    let clock_sysvar = next_account_info(accounts_iter)?;
-    let system_program = next_account_info(accounts_iter)?;
+impl Versioned for MarketState {
+    const VERSION: u8 = 25;
 
-    // Ensure user is signer
-    if !user.is_signer {
-        msg!("User must be signer");
-        return Err(ProgramError::MissingRequiredSignature);
+    fn version(&self) -> u8 {
+        self.version
     }
+}
 
-    // Decode instruction payload:
-    if data.len() < 24 {
-        msg!("Insufficient instruction data");
-        return Err(ProgramError::InvalidInstructionData);
+impl Position {
+    /// Borrow a position in place from account data
+    pub fn load(data: &[u8]) -> Result<&Self, ProgramError> {
+        check_version(load_pod(data)?)
     }
 
-    let base_delta = i64::from_le_bytes(data[0..8].try_into().unwrap());
-    let collateral_delta = u64::from_le_bytes(data[8..16].try_into().unwrap());
-    let entry_price = u64::from_le_bytes(data[16..24].try_into().unwrap());
+    /// Mutably borrow a position in place from account data
+    pub fn load_mut(data: &mut [u8]) -> Result<&mut Self, ProgramError> {
+        check_version_mut(load_pod_mut(data)?)
+    }
 
-    msg!("Opening position: base_delta={}, collateral_delta={}, entry_price={}", 
-         base_delta, collateral_delta, entry_price);
+    /// Tag freshly allocated account data as a position and borrow it
+    pub fn init(data: &mut [u8]) -> Result<&mut Self, ProgramError> {
+        init_pod(data)
+    }
 
-    // Derive PDA authority
-    let (pda, bump) = Pubkey::find_program_address(&[PDA_SEED], program_id);
-    
-    // Verify vault is the correct PDA
-    if *vault.key != pda {
-        msg!("Vault account is not the correct PDA. Expected: {}, Got: {}", pda, vault.key);
-        return Err(ProgramError::InvalidArgument);
+    /// Whether the position is margined by a user account rather than its own collateral
+    pub fn is_cross_margin(&self) -> bool {
+        self.margin_mode == MARGIN_MODE_CROSS
     }
 
-    let clock = Clock::from_account_info(clock_sysvar)?;
-    let rent = Rent::from_account_info(rent_sysvar)?;
+    /// Whether the position is tied to an NFT whose holder acts for it
+    pub fn is_tokenized(&self) -> bool {
+        self.position_mint != Pubkey::default()
+    }
 
-    // ---------- Initialize market state if empty ----------
-    if market_state_acc.data_is_empty() {
-        let required_lamports = rent.minimum_balance(std::mem::size_of::<MarketState>());
-        
-        let create_market_ix = system_instruction::create_account(
-            user.key,
-            market_state_acc.key,
-            required_lamports,
-            std::mem::size_of::<MarketState>() as u64,
-            program_id,
-        );
+    /// Whether `signer` owns the position outright; once it is tokenized, nobody does
+    pub fn is_owner(&self, signer: &Pubkey) -> bool {
+        self.owner == *signer && !self.is_tokenized()
+    }
 
-        invoke(&create_market_ix, &[
-            user.clone(),
-            market_state_acc.clone(),
-            system_program.clone(),
-        ])?;
+    /// Whether `signer` may open or reduce the position: its owner or approved delegate
+    pub fn can_trade(&self, signer: &Pubkey) -> bool {
+        self.is_owner(signer) || (self.delegate != Pubkey::default() && self.delegate == *signer)
+    }
+}
 
-        let market_state = MarketState {
-            funding_index: 0,
-            funding_rate_per_slot: 0,
-            open_interest: 0,
-            bump,
-            last_funding_slot: clock.slot,
-            mark_price: entry_price, // Initialize with entry price
-        };
-        market_state.serialize(&mut *market_state_acc.data.borrow_mut())?;
-        msg!("Initialized market state");
+impl MarketState {
+    /// Borrow market state in place from account data
+    pub fn load(data: &[u8]) -> Result<&Self, ProgramError> {
+        check_version(load_pod(data)?)
     }
 
-    // ---------- Initialize position if empty ----------
-    if position_acc.data_is_empty() {
-        let required_lamports = rent.minimum_balance(std::mem::size_of::<Position>());
-        
-        let create_position_ix = system_instruction::create_account(
-            user.key,
-            position_acc.key,
-            required_lamports,
-            std::mem::size_of::<Position>() as u64,
-            program_id,
-        );
+    /// Mutably borrow market state in place from account data
+    pub fn load_mut(data: &mut [u8]) -> Result<&mut Self, ProgramError> {
+        check_version_mut(load_pod_mut(data)?)
+    }
 
-        invoke(&create_position_ix, &[
-            user.clone(),
-            position_acc.clone(),
-            system_program.clone(),
-        ])?;
+    /// Tag freshly allocated account data as market state and borrow it
+    pub fn init(data: &mut [u8]) -> Result<&mut Self, ProgramError> {
+        init_pod(data)
+    }
 
-        let position = Position {
-            owner: *user.key,
-            base_amount: 0,
-            collateral: 0,
-            last_funding_index: 0,
-            entry_price: 0,
-        };
-        position.serialize(&mut *position_acc.data.borrow_mut())?;
-        msg!("Initialized position account for user: {}", user.key);
+    /// Size in the market's base units (`base_decimals`) in 1e9 precision
+    pub fn size_to_precision(&self, size: i64) -> Result<i64, ProgramError> {
+        math::to_precision_signed(size, self.base_decimals)
     }
 
-    // ---------- Load mutable structs ----------
-    #[allow(unused_mut)]
-    let mut market_state = MarketState::try_from_slice(&market_state_acc.data.borrow())?;
-    #[allow(unused_mut)]
-    let mut position = Position::try_from_slice(&position_acc.data.borrow())?;
+    /// Quote token amount (`quote_decimals`) in 1e9 precision
+    pub fn quote_to_precision(&self, amount: u64) -> Result<u64, ProgramError> {
+        math::to_precision(amount, self.quote_decimals)
+    }
 
-    // Verify position owner
-    if position.owner != *user.key {
-        msg!("Position owner mismatch. Expected: {}, Got: {}", user.key, position.owner);
-        return Err(ProgramError::IllegalOwner);
+    /// Quote the market holds in the vault for itself: its insurance fund and fee pool (1e9 precision)
+    pub fn reserves(&self) -> u64 {
+        self.insurance_fund.saturating_add(self.fee_pool)
     }
 
-    // ---------- Transfer collateral from user to vault ----------
-    if collateral_delta > 0 {
-        let transfer_ix = create_transfer_instruction(
-            token_program.key,
-            user_collateral.key,
-            vault.key,
-            user.key,
-            collateral_delta,
-        )?;
+    /// 1e9-precision quote value as a quote token amount, rounded down
+    pub fn quote_from_precision(&self, value: u64) -> Result<u64, ProgramError> {
+        math::from_precision(value, self.quote_decimals)
+    }
 
-        invoke(&transfer_ix, &[
-            user_collateral.clone(),
-            vault.clone(),
-            user.clone(),
-            token_program.clone(),
-        ])?;
-        
-        position.collateral = position
-            .collateral
-            .checked_add(collateral_delta)
-            .ok_or(ProgramError::InvalidArgument)?;
-        
-        msg!("Transferred {} collateral to vault", collateral_delta);
+    /// Collateral ratio a position of `notional` (1e9 precision) must keep: the highest of
+    /// `min_collateral_ratio` and the ratios of the risk tiers its notional reaches
+    pub fn required_collateral_ratio(&self, notional: u64, min_collateral_ratio: u64) -> u64 {
+        self.risk_tiers
+            .iter()
+            .filter(|tier| notional >= tier.min_notional)
+            .map(|tier| tier.min_collateral_ratio)
+            .fold(min_collateral_ratio, u64::max)
     }
 
-    // ---------- Apply pending funding before position update ----------
-    let funding_delta = market_state.funding_index
-        .checked_sub(position.last_funding_index)
-        .ok_or(ProgramError::InvalidArgument)?;
+    /// Fold the mark price held since the last sample into the EMA at `slot`; the first
+    /// sample, or a zero half-life, sets the EMA to the mark
+    pub fn update_mark_price_ema(&mut self, slot: u64) -> ProgramResult {
+        if self.mark_price_ema == 0 || self.ema_half_life_slots == 0 {
+            self.mark_price_ema = self.mark_price;
+        } else if slot > self.ema_last_slot {
+            let decay = calculate_ema_decay(slot - self.ema_last_slot, self.ema_half_life_slots);
+            let deviation = i128::from(self.mark_price_ema) - i128::from(self.mark_price);
+            let ema = i128::from(self.mark_price) + math::mul_div_signed(deviation, decay.into(), PRECISION.into())?;
+            self.mark_price_ema = math::to_u64(ema.try_into().map_err(|_| ProgramError::InvalidArgument)?)?;
+        }
+        self.ema_last_slot = slot;
 
-    if funding_delta != 0 && position.base_amount != 0 {
-        // Funding payment = base_amount * funding_delta / 1e9
-        let funding_payment = ((position.base_amount as i128)
-            .checked_mul(funding_delta as i128)
-            .ok_or(ProgramError::InvalidArgument)?)
-            .checked_div(1_000_000_000i128)
-            .ok_or(ProgramError::InvalidAccountData)?;
+        Ok(())
+    }
 
-        if funding_payment > 0 {
-            // User owes funding → deduct from collateral
-            position.collateral = position
-                .collateral
-                .checked_sub(funding_payment as u64)
-                .ok_or(ProgramError::InsufficientFunds)?;
-            msg!("Applied funding payment: -{}", funding_payment);
-        } else if funding_payment < 0 {
-            // User receives funding → add to collateral
-            position.collateral = position
-                .collateral
-                .checked_add((-funding_payment) as u64)
-                .ok_or(ProgramError::InvalidArgument)?;
-            msg!("Received funding payment: +{}", -funding_payment);
-        }
+    /// The EMA liquidation checks also hold positions to, when enabled and sampled
+    pub fn liquidation_ema_price(&self) -> Option<u64> {
+        (self.ema_liquidation != 0 && self.mark_price_ema != 0).then_some(self.mark_price_ema)
     }
-    position.last_funding_index = market_state.funding_index;
 
-    // ---------- Update position ----------
-    let old_base_amount = position.base_amount;
-    position.base_amount = position
-        .base_amount
-        .checked_add(base_delta)
-        .ok_or(ProgramError::InvalidArgument)?;
+    /// Whether the market only accepts reductions
+    pub fn is_paused(&self) -> bool {
+        self.paused != 0
+    }
 
-    // Update entry price for new position or position increase
-    if old_base_amount == 0 || (old_base_amount > 0 && base_delta > 0) || (old_base_amount < 0 && base_delta < 0) {
-        position.entry_price = entry_price;
+    /// Whether only whitelisted wallets may grow positions
+    pub fn is_permissioned(&self) -> bool {
+        self.permissioned != 0
     }
 
-    // Update open interest
-    let old_oi_contribution = old_base_amount.abs() as u64;
-    let new_oi_contribution = position.base_amount.abs() as u64;
-    
-    market_state.open_interest = market_state
-        .open_interest
-        .checked_sub(old_oi_contribution)
-        .ok_or(ProgramError::InvalidArgument)?
-        .checked_add(new_oi_contribution)
-        .ok_or(ProgramError::InvalidArgument)?;
+    /// Whether opens are banded around an index oracle
+    pub fn has_index_oracle(&self) -> bool {
+        self.index_oracle != Pubkey::default()
+    }
 
-    // Update mark price
-    market_state.mark_price = entry_price;
+    /// The index oracle followed by the configured backups, the order their accounts are passed in
+    pub fn index_oracles(&self) -> Vec<Pubkey> {
+        std::iter::once(self.index_oracle)
+            .chain(self.backup_oracles.iter().copied().filter(|oracle| *oracle != Pubkey::default()))
+            .collect()
+    }
 
-    // ---------- Validate collateral ratio ----------
-    if position.base_amount != 0 {
-        let position_value = (position.base_amount.abs() as u64)
-            .checked_mul(market_state.mark_price)
-            .ok_or(ProgramError::InvalidArgument)?
-            .checked_div(1_000_000_000)
-            .ok_or(ProgramError::InvalidAccountData)?;
-
-        let collateral_ratio = if position_value > 0 {
-            position.collateral
-                .checked_mul(1_000_000_000)
-                .ok_or(ProgramError::InvalidArgument)?
-                .checked_div(position_value)
-                .ok_or(ProgramError::InvalidAccountData)?
-        } else {
-            u64::MAX
-        };
+    /// Whether the market has been wound down and positions can only be settled
+    pub fn is_settled(&self) -> bool {
+        self.settlement_price != 0
+    }
 
-        if collateral_ratio < MIN_COLLATERAL_RATIO {
-            msg!("Insufficient collateral ratio: {} < {}", collateral_ratio, MIN_COLLATERAL_RATIO);
-            return Err(ProgramError::InsufficientFunds);
-        }
-        
-        msg!("Collateral ratio: {}", collateral_ratio);
+    /// Whether the market is a dated future rather than a perpetual
+    pub fn is_dated(&self) -> bool {
+        self.expiry_timestamp != 0
     }
 
-    // ---------- Persist changes ----------
-    position.serialize(&mut *position_acc.data.borrow_mut())?;
-    market_state.serialize(&mut *market_state_acc.data.borrow_mut())?;
+    /// Whether a dated market has reached its expiry at `unix_timestamp`
+    pub fn is_expired(&self, unix_timestamp: i64) -> bool {
+        self.is_dated() && unix_timestamp >= self.expiry_timestamp
+    }
 
-    msg!("Position updated successfully: base={}, collateral={}, open_interest={}", 
-         position.base_amount, position.collateral, market_state.open_interest);
-    
-    Ok(())
-}
+    /// Whether only reductions are allowed at `slot`: paused, or cooling down from a circuit breaker
+    pub fn is_reduce_only(&self, slot: u64) -> bool {
+        self.is_paused() || slot < self.reduce_only_until_slot
+    }
 
-// ---------------------------------------------------------------------
+    /// Feed an index price into the circuit breaker. Returns the relative move when it
+    /// trips, which makes the market reduce-only for `breaker_cooldown_slots`.
+    pub fn observe_index_price(&mut self, index_price: u64, slot: u64) -> Option<u64> {
+        if self.breaker_max_move == 0 {
+            return None;
+        }
+
+        let window_expired = slot.saturating_sub(self.breaker_window_start_slot) > self.breaker_window_slots;
+        if self.breaker_reference_price == 0 || window_expired {
+            self.breaker_reference_price = index_price;
+            self.breaker_window_start_slot = slot;
+            return None;
+        }
+
+        let price_move = math::ratio(
+            self.breaker_reference_price.abs_diff(index_price).into(),
+            self.breaker_reference_price.into(),
+        );
+        if price_move <= self.breaker_max_move {
+            return None;
+        }
+
+        // Start a fresh window from the new price so the cooldown isn't re-armed by the same move
+        self.reduce_only_until_slot = slot.saturating_add(self.breaker_cooldown_slots);
+        self.breaker_reference_price = index_price;
+        self.breaker_window_start_slot = slot;
+        Some(price_move)
+    }
+
+    /// Count `amount` of collateral (1e9 precision) deposited into an isolated position,
+    /// rejecting it if it takes `total_collateral` past `max_total_collateral`; see
+    /// `count_deposit` for the position's side
+    pub fn deposit_collateral(&mut self, amount: u64) -> ProgramResult {
+        let total_collateral = self.total_collateral
+            .checked_add(amount)
+            .ok_or(ProgramError::InvalidArgument)?;
+        if self.max_total_collateral != 0 && total_collateral > self.max_total_collateral {
+            msg!("Deposit of {} would take the market's collateral to {}, past its cap of {}",
+                 amount, total_collateral, self.max_total_collateral);
+            return Err(ProgramError::InvalidArgument);
+        }
+        self.total_collateral = total_collateral;
+        Ok(())
+    }
+
+    /// Stop counting `amount` of a position's deposits (1e9 precision); see `release_deposits`
+    pub fn withdraw_collateral(&mut self, amount: u64) {
+        self.total_collateral = self.total_collateral.saturating_sub(amount);
+    }
+
+    /// Book a funding index move of `funding_increment` against each side's open base: the
+    /// paying side owes more than the receiving side is owed whenever the market is skewed,
+    /// and that residual goes to the insurance fund. Returns the residual.
+    ///
+    /// The fund is credited now, ahead of the payers settling their funding; until they do it's
+    /// backed by their pending funding, which `client::VaultHoldings` nets out of their collateral.
+    pub fn accrue_side_funding(&mut self, funding_increment: i64) -> Result<u64, ProgramError> {
+        let short_base_amount = self.net_base_amount
+            .checked_sub(self.long_base_amount)
+            .ok_or(ProgramError::InvalidArgument)?;
+        let long_owed = calculate_funding_payment(self.long_base_amount, funding_increment)?;
+        let short_owed = calculate_funding_payment(short_base_amount, funding_increment)?;
+        self.long_funding = self.long_funding
+            .checked_add(long_owed)
+            .ok_or(ProgramError::InvalidArgument)?;
+        self.short_funding = self.short_funding
+            .checked_add(short_owed)
+            .ok_or(ProgramError::InvalidArgument)?;
+
+        // The rate follows the skew, so the heavier side pays and the sum is never negative
+        // short of rounding; a negative one is a payout nobody owes, booked as bad debt
+        let residual = long_owed.checked_add(short_owed).ok_or(ProgramError::InvalidArgument)?;
+        if residual < 0 {
+            record_bad_debt(self, residual.unsigned_abs())?;
+            return Ok(0);
+        }
+        let residual = residual.unsigned_abs();
+        self.insurance_fund = self.insurance_fund
+            .checked_add(residual)
+            .ok_or(ProgramError::InvalidArgument)?;
+        self.funding_residual = self.funding_residual
+            .checked_add(residual)
+            .ok_or(ProgramError::InvalidArgument)?;
+
+        Ok(residual)
+    }
+
+    /// Count a payout of `amount` of collateral (1e9 precision) to `position`'s owner at `slot`
+    /// of `epoch`, rejecting it within the position's withdrawal cooldown or past the epoch's
+    /// cap. The first payout of an epoch always fits, so a position larger than the cap can
+    /// still be closed.
+    pub fn record_withdrawal(&mut self, position: &mut Position, amount: u64, slot: u64, epoch: u64) -> ProgramResult {
+        if amount == 0 {
+            return Ok(());
+        }
+
+        let unlock_slot = position.last_withdrawal_slot.saturating_add(self.withdrawal_cooldown_slots);
+        if position.last_withdrawal_slot != 0 && slot < unlock_slot {
+            msg!("Position last withdrew at slot {} and cools down until slot {} (current slot {})",
+                 position.last_withdrawal_slot, unlock_slot, slot);
+            return Err(ProgramError::InvalidArgument);
+        }
+
+        if epoch != self.withdrawal_epoch {
+            self.withdrawal_epoch = epoch;
+            self.epoch_withdrawn = 0;
+        }
+        let epoch_withdrawn = self.epoch_withdrawn.saturating_add(amount);
+        if self.max_epoch_withdrawal != 0 && self.epoch_withdrawn != 0 && epoch_withdrawn > self.max_epoch_withdrawal {
+            msg!("Withdrawal of {} exceeds the {} left under epoch {}'s cap; retry next epoch",
+                 amount, self.max_epoch_withdrawal.saturating_sub(self.epoch_withdrawn), epoch);
+            return Err(ProgramError::InvalidArgument);
+        }
+
+        self.epoch_withdrawn = epoch_withdrawn;
+        position.last_withdrawal_slot = slot;
+        Ok(())
+    }
+}
+
+// The explicit sizes are what accounts are allocated with, so they must match the layouts
+const _: () = assert!(Position::LEN == std::mem::size_of::<Position>());
+const _: () = assert!(MarketState::LEN == std::mem::size_of::<MarketState>());
+
+/// Check the type tag of an account's data and that it holds `T::SPACE` bytes, and return
+/// the body that follows the tag
+fn account_body<T: AccountType>(data: &[u8]) -> Result<&[u8], ProgramError> {
+    match data.get(..DISCRIMINATOR_LEN) {
+        Some(tag) if tag == T::DISCRIMINATOR => {}
+        _ => {
+            msg!("Account discriminator mismatch: expected {:?}", T::DISCRIMINATOR);
+            return Err(ProgramError::InvalidAccountData);
+        }
+    }
+    check_account_len::<T>(data.len())?;
+
+    Ok(&data[DISCRIMINATOR_LEN..])
+}
+
+/// Reject account data shorter than `T`'s allocation, e.g. an older layout not yet migrated
+fn check_account_len<T: AccountType>(data_len: usize) -> ProgramResult {
+    if data_len < T::SPACE {
+        msg!("Account data is {} bytes, short of the {} of its layout: run migrate_account if it is an older one",
+             data_len, T::SPACE);
+        return Err(ProgramError::AccountDataTooSmall);
+    }
+
+    Ok(())
+}
+
+/// Mutable counterpart of `account_body`
+fn account_body_mut<T: AccountType>(data: &mut [u8]) -> Result<&mut [u8], ProgramError> {
+    account_body::<T>(data)?;
+    Ok(&mut data[DISCRIMINATOR_LEN..])
+}
+
+/// Reinterpret a tagged account's body as `T`
+fn load_pod<T: Pod + AccountType>(data: &[u8]) -> Result<&T, ProgramError> {
+    bytemuck::try_from_bytes(&account_body::<T>(data)?[..T::LEN]).map_err(|_| ProgramError::InvalidAccountData)
+}
+
+/// Mutably reinterpret a tagged account's body as `T`
+fn load_pod_mut<T: Pod + AccountType>(data: &mut [u8]) -> Result<&mut T, ProgramError> {
+    bytemuck::try_from_bytes_mut(&mut account_body_mut::<T>(data)?[..T::LEN]).map_err(|_| ProgramError::InvalidAccountData)
+}
+
+/// Write `T`'s type tag into freshly allocated data and borrow the body
+fn init_pod<T: Pod + AccountType>(data: &mut [u8]) -> Result<&mut T, ProgramError> {
+    check_account_len::<T>(data.len())?;
+    data[..DISCRIMINATOR_LEN].copy_from_slice(&T::DISCRIMINATOR);
+    load_pod_mut(data)
+}
+
+/// Reject accounts still in an older layout; they must go through migrate_account first
+fn check_version<T: Versioned>(account: &T) -> Result<&T, ProgramError> {
+    if account.version() != T::VERSION {
+        msg!("Account layout v{} is outdated (current v{}): run migrate_account",
+             account.version(), T::VERSION);
+        return Err(ProgramError::InvalidAccountData);
+    }
+    Ok(account)
+}
+
+/// Mutable counterpart of `check_version`
+fn check_version_mut<T: Versioned>(account: &mut T) -> Result<&mut T, ProgramError> {
+    check_version(&*account)?;
+    Ok(account)
+}
+
+/// Deserialize a tagged Borsh account; any unused tail of the allocation is ignored
+fn load_account<T: BorshDeserialize + AccountType>(data: &[u8]) -> Result<T, ProgramError> {
+    Ok(T::deserialize(&mut account_body::<T>(data)?)?)
+}
+
+/// Deserialize a Borsh account if its data carries `T`'s tag. For probing optional
+/// accounts, where a mismatch is expected: it isn't logged, as logging costs compute
+fn probe_account<T: BorshDeserialize + AccountType>(data: &[u8]) -> Option<T> {
+    if data.get(..DISCRIMINATOR_LEN) != Some(&T::DISCRIMINATOR[..]) {
+        return None;
+    }
+    load_account(data).ok()
+}
+
+/// Serialize a Borsh account behind its type tag
+fn store_account<T: BorshSerialize + AccountType>(value: &T, data: &mut [u8]) -> ProgramResult {
+    check_account_len::<T>(data.len())?;
+
+    let (tag, mut body) = data.split_at_mut(DISCRIMINATOR_LEN);
+    tag.copy_from_slice(&T::DISCRIMINATOR);
+    value.serialize(&mut body)?;
+    Ok(())
+}
+
+/// Outcome of trading against the vAMM constant-product curve
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VammFill {
+    /// Quote paid (longs) or received (shorts) for the trade
+    pub quote_amount: u64,
+    /// Average execution price (1e9 precision)
+    pub fill_price: u64,
+    /// Base reserve after the trade
+    pub new_base_reserve: u64,
+    /// Quote reserve after the trade
+    pub new_quote_reserve: u64,
+    /// Price impact charged on top of the curve's slippage (1e9 precision)
+    pub price_impact: u64,
+}
+
+/// Referral account that earns a share of the trading fees of referred trades
+#[derive(BorshSerialize, BorshDeserialize, Debug, Default, Clone, ShankAccount)]
+pub struct Referrer {
+    /// Wallet that registered the referral account and can claim its fees
+    pub owner: Pubkey,
+    /// Fees credited but not yet claimed (quote token)
+    pub claimable_fees: u64,
+    /// Lifetime fees credited to this referrer (quote token)
+    pub total_fees_earned: u64,
+    /// PDA bump for [REFERRER_SEED, owner]
+    pub bump: u8,
+}
+
+impl AccountType for Referrer {
+    const DISCRIMINATOR: [u8; DISCRIMINATOR_LEN] = *b"referrer";
+    /// owner + claimable_fees + total_fees_earned + bump
+    const LEN: usize = 32 + 8 + 8 + 1;
+}
+
+/// Global program configuration: the admin authority and risk parameters
+#[derive(BorshSerialize, BorshDeserialize, Debug, Default, Clone, ShankAccount)]
+pub struct Config {
+    /// Authority allowed to update parameters (any pubkey, e.g. a multisig vault)
+    pub admin: Pubkey,
+    /// Proposed admin awaiting accept_admin (default pubkey = none)
+    pub pending_admin: Pubkey,
+    /// Minimum collateral ratio for opens and liquidation threshold (1e9 precision)
+    pub min_collateral_ratio: u64,
+    /// Liquidation fee charged on the closed notional, in basis points
+    pub liquidator_fee_bps: u64,
+    /// Fee on the notional of each position change (1e9 precision)
+    pub trading_fee: u64,
+    /// Share of the trading fee credited to referrers (1e9 precision)
+    pub referral_fee_share: u64,
+    /// Cap on |funding_rate_per_slot| (1e9 precision)
+    pub max_funding_rate_per_slot: u64,
+    /// PDA bump for [CONFIG_SEED]
+    pub bump: u8,
+    /// Non-quote collateral mints; a position's collateral_balances are indexed by this list
+    pub collateral_assets: Vec<CollateralAsset>,
+    /// Token program that owns the quote mint and vault (SPL Token or Token-2022)
+    pub token_program: Pubkey,
+    /// Quote (collateral) mint; default pubkey until set on a migrated config
+    pub quote_mint: Pubkey,
+    /// Keeper reward per elapsed funding period, paid from the market fee pool (quote token)
+    pub funding_crank_reward: u64,
+    /// Cap on the keeper reward paid by a single update_funding (quote token)
+    pub max_funding_crank_reward: u64,
+    /// Share of each liquidation fee kept by the insurance fund (1e9 precision)
+    pub insurance_fund_share: u64,
+    /// Smallest non-zero |base_amount| a trade may leave (1e9 precision, 0 = no minimum)
+    pub min_position_size: u64,
+    /// Collateral a position that goes flat may keep before it is swept into the
+    /// insurance fund (1e9 precision, 0 = never swept)
+    pub dust_collateral: u64,
+    /// PDA bump for [PDA_SEED], the vault and authority of every program token account
+    pub vault_bump: u8,
+    /// Most funding a position pays in one settlement, as a share of its collateral
+    /// (1e9 precision, 0 = no cap); the excess is carried in `pending_funding`
+    pub max_funding_settlement_share: u64,
+    /// Rebate paid to the resting (maker) side of a crossed order match out of the taker's
+    /// trading fee, instead of a maker fee (1e9 precision, at most `trading_fee`)
+    pub maker_rebate: u64,
+    /// Taker fee tiers by rolling 30-day volume, ascending by `min_volume`; traders below
+    /// the first tier, or without a UserStats account, pay `trading_fee`
+    pub fee_tiers: Vec<FeeTier>,
+    /// Protocol token staked for fee discounts (default pubkey = staking not set up)
+    pub stake_mint: Pubkey,
+    /// Token account of `stake_mint`, owned by the vault PDA, holding all stakes
+    pub stake_vault: Pubkey,
+    /// Trading fee discounts by staked balance, ascending by `min_stake`
+    pub stake_discounts: Vec<staking::StakeDiscount>,
+    /// Positions a wallet may grow per market: sub-account ids from this one on only
+    /// reduce (0 = no limit)
+    pub max_positions_per_user: u16,
+    /// Largest notional at the mark a position, or a cross-margin account across its
+    /// positions, may grow to (1e9 precision, 0 = no limit)
+    pub max_user_notional: u64,
+    /// Only liquidators in the LiquidatorRegistry may liquidate (false = permissionless)
+    pub restrict_liquidators: bool,
+    /// Quote token account withdraw_fees pays protocol fees into (default pubkey = not set)
+    pub treasury: Pubkey,
+    /// One past the highest market id created: every market sits at `market_address(id)` for an
+    /// id below it, and ids the admin skipped have no market
+    pub market_count: u32,
+    /// Incident switch: only closes, withdrawals and liquidations are processed while set
+    pub settlement_only: bool,
+}
+
+impl Config {
+    /// Trading fee of a trader with `rolling_volume` of 30-day notional (1e9 precision):
+    /// that of the highest tier the volume reaches, else `trading_fee`
+    pub fn taker_fee(&self, rolling_volume: u64) -> u64 {
+        self.fee_tiers
+            .iter()
+            .rev()
+            .find(|tier| rolling_volume >= tier.min_volume)
+            .map_or(self.trading_fee, |tier| tier.trading_fee)
+    }
+
+    /// Share of the trading fee waived for a wallet with `staked` tokens (1e9 precision):
+    /// that of the highest schedule entry the stake reaches, else none
+    pub fn stake_discount(&self, staked: u64) -> u64 {
+        self.stake_discounts
+            .iter()
+            .rev()
+            .find(|entry| staked >= entry.min_stake)
+            .map_or(0, |entry| entry.discount)
+    }
+}
+
+/// A trading fee that applies from a rolling 30-day volume on
+#[derive(BorshSerialize, BorshDeserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct FeeTier {
+    /// 30-day notional traded that qualifies for the tier (1e9 precision)
+    pub min_volume: u64,
+    /// Trading fee charged in the tier (1e9 precision)
+    pub trading_fee: u64,
+}
+
+impl FeeTier {
+    /// Serialized size: min_volume + trading_fee
+    pub const LEN: usize = 8 + 8;
+}
+
+/// A non-quote collateral mint and how much of its oracle value counts as collateral
+#[derive(BorshSerialize, BorshDeserialize, Debug, Default, Clone, PartialEq, Eq)]
+pub struct CollateralAsset {
+    /// Token mint
+    pub mint: Pubkey,
+    /// Program-owned token account holding deposits of this mint
+    pub vault: Pubkey,
+    /// Pyth price account valuing one whole token in quote units
+    pub oracle: Pubkey,
+    /// Share of the oracle value counted as collateral, i.e. 1 - haircut (1e9 precision)
+    pub weight: u64,
+    /// Mint decimals
+    pub decimals: u8,
+}
+
+impl CollateralAsset {
+    /// Serialized size: mint + vault + oracle + weight + decimals
+    pub const LEN: usize = 32 + 32 + 32 + 8 + 1;
+}
+
+impl AccountType for Config {
+    const DISCRIMINATOR: [u8; DISCRIMINATOR_LEN] = *b"config\0\0";
+    /// admin + pending_admin + five u64 parameters + bump + vec length + MAX_COLLATERAL_ASSETS assets
+    /// + token_program + quote_mint + two u64 crank reward parameters + insurance_fund_share
+    /// + min_position_size + dust_collateral + vault_bump + max_funding_settlement_share + maker_rebate
+    /// + vec length + MAX_FEE_TIERS fee tiers + stake_mint + stake_vault + vec length + MAX_STAKE_DISCOUNTS discounts
+    /// + max_positions_per_user + max_user_notional + restrict_liquidators + treasury + market_count + settlement_only
+    const LEN: usize = 32 + 32 + 8 * 5 + 1 + 4 + MAX_COLLATERAL_ASSETS * CollateralAsset::LEN + 32 + 32 + 8 * 3 + 8 * 2 + 1 + 8 + 8
+        + 4 + MAX_FEE_TIERS * FeeTier::LEN + 32 + 32 + 4 + staking::MAX_STAKE_DISCOUNTS * staking::StakeDiscount::LEN + 2 + 8 + 1
+        + 32 + 4 + 1;
+}
+
+// ---------------------------------------------------------------------
+// Program entrypoint
+// ---------------------------------------------------------------------
+entrypoint!(process_instruction);
+
+pub fn process_instruction(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    instruction_data: &[u8],
+) -> ProgramResult {
+    // The first byte is the instruction tag, then the payload version; see `PerpsInstruction`
+    let instruction = PerpsInstruction::unpack(instruction_data).map_err(|_| {
+        msg!("Invalid instruction data: tag {:?}, {} bytes", instruction_data.first(), instruction_data.len());
+        ProgramError::InvalidInstructionData
+    })?;
+
+    match instruction {
+        PerpsInstruction::OpenPosition { base_delta, collateral_delta, price_limit, flags, sub_account_id } => {
+            open_position(program_id, accounts, base_delta, collateral_delta, price_limit, flags, sub_account_id)
+        }
+        PerpsInstruction::IncreasePosition { base_delta, collateral_delta, price_limit, flags, sub_account_id } => {
+            increase_position(program_id, accounts, base_delta, collateral_delta, price_limit, flags, sub_account_id)
+        }
+        PerpsInstruction::DecreasePosition { base_amount, price_limit, flags, sub_account_id } => {
+            decrease_position(program_id, accounts, base_amount, price_limit, flags, sub_account_id)
+        }
+        PerpsInstruction::UpdateFunding => update_funding(program_id, accounts),
+        PerpsInstruction::Liquidate => liquidate(program_id, accounts),
+        PerpsInstruction::ClosePosition => close_position(program_id, accounts),
+        PerpsInstruction::RegisterReferrer => register_referrer(program_id, accounts),
+        PerpsInstruction::ClaimReferralFees => claim_referral_fees(program_id, accounts),
+        PerpsInstruction::PlaceOrder { side, price, base_amount, flags } => {
+            orderbook::place_order(program_id, accounts, side, price, base_amount, flags)
+        }
+        PerpsInstruction::CancelOrder { order_id } => orderbook::cancel_order(program_id, accounts, order_id),
+        PerpsInstruction::MatchOrders => orderbook::match_orders(program_id, accounts),
+        PerpsInstruction::PlaceTriggerOrder { trigger_id, kind, trigger_price, base_amount } => {
+            trigger_orders::place_trigger_order(program_id, accounts, trigger_id, kind, trigger_price, base_amount)
+        }
+        PerpsInstruction::CancelTriggerOrder => trigger_orders::cancel_trigger_order(program_id, accounts),
+        PerpsInstruction::ExecuteTriggerOrder => trigger_orders::execute_trigger_order(program_id, accounts),
+        PerpsInstruction::LinkTriggerOrders => trigger_orders::link_trigger_orders(program_id, accounts),
+        PerpsInstruction::SetSettlementOnly { enabled } => set_settlement_only(program_id, accounts, enabled),
+        PerpsInstruction::CreateMarketMetrics => market_metrics::create_market_metrics(program_id, accounts),
+        PerpsInstruction::CreateFundingHistory => funding_history::create_funding_history(program_id, accounts),
+        PerpsInstruction::CreatePriceHistory => price_history::create_price_history(program_id, accounts),
+        PerpsInstruction::ClaimFunding { auto_compound } => claim_funding(program_id, accounts, auto_compound),
+        PerpsInstruction::InitializeConfig { admin } => initialize_config(program_id, accounts, admin),
+        PerpsInstruction::UpdateParams {
+            min_collateral_ratio,
+            liquidator_fee_bps,
+            trading_fee,
+            referral_fee_share,
+            max_funding_rate_per_slot,
+            crank_reward,
+            insurance_fund_share,
+            dust_thresholds,
+            max_funding_settlement_share,
+            maker_rebate,
+            user_limits,
+        } => update_params(
+            program_id,
+            accounts,
+            min_collateral_ratio,
+            liquidator_fee_bps,
+            trading_fee,
+            referral_fee_share,
+            max_funding_rate_per_slot,
+            crank_reward,
+            insurance_fund_share,
+            dust_thresholds,
+            max_funding_settlement_share,
+            maker_rebate,
+            user_limits,
+        ),
+        PerpsInstruction::UpdateMarketParams { max_leverage, max_open_interest, price_impact_depth, skew_fee } => {
+            update_market_params(program_id, accounts, max_leverage, max_open_interest, price_impact_depth, skew_fee)
+        }
+        PerpsInstruction::PauseMarket => set_market_paused(program_id, accounts, true),
+        PerpsInstruction::ResumeMarket => set_market_paused(program_id, accounts, false),
+        PerpsInstruction::SetPendingAdmin { pending_admin } => set_pending_admin(program_id, accounts, pending_admin),
+        PerpsInstruction::AcceptAdmin => accept_admin(program_id, accounts),
+        PerpsInstruction::MigrateAccount => migrate_account(program_id, accounts),
+        PerpsInstruction::GetPositionHealth => views::get_position_health(program_id, accounts),
+        PerpsInstruction::GetUnrealizedPnl => views::get_unrealized_pnl(program_id, accounts),
+        PerpsInstruction::ClosePositionAccount => close_position_account(program_id, accounts),
+        PerpsInstruction::AddCollateralAsset { mint, oracle, weight, decimals } => {
+            add_collateral_asset(program_id, accounts, mint, oracle, weight, decimals)
+        }
+        PerpsInstruction::DepositCollateralAsset { asset_index, amount } => {
+            deposit_collateral_asset(program_id, accounts, asset_index, amount)
+        }
+        PerpsInstruction::WithdrawCollateralAsset { asset_index, amount } => {
+            withdraw_collateral_asset(program_id, accounts, asset_index, amount)
+        }
+        PerpsInstruction::SetQuoteMint => set_quote_mint(program_id, accounts),
+        PerpsInstruction::DepositNativeSol { asset_index, lamports } => {
+            deposit_native_sol(program_id, accounts, asset_index, lamports)
+        }
+        PerpsInstruction::CreateUserAccount => cross_margin::create_user_account(program_id, accounts),
+        PerpsInstruction::DepositCrossCollateral { amount } => {
+            cross_margin::deposit_cross_collateral(program_id, accounts, amount)
+        }
+        PerpsInstruction::WithdrawCrossCollateral { amount } => {
+            cross_margin::withdraw_cross_collateral(program_id, accounts, amount)
+        }
+        PerpsInstruction::SetMarginMode { margin_mode } => cross_margin::set_margin_mode(program_id, accounts, margin_mode),
+        PerpsInstruction::ApproveDelegate { delegate } => approve_delegate(program_id, accounts, delegate),
+        PerpsInstruction::BackstopLiquidate { collateral_delta } => {
+            backstop_liquidate(program_id, accounts, collateral_delta)
+        }
+        PerpsInstruction::SettlePnl => settle_pnl(program_id, accounts),
+        PerpsInstruction::DepositLiquidity { amount } => liquidity_pool::deposit_liquidity(program_id, accounts, amount),
+        PerpsInstruction::RequestWithdrawal { shares } => liquidity_pool::request_withdrawal(program_id, accounts, shares),
+        PerpsInstruction::CreatePool => liquidity_pool::create_pool(program_id, accounts),
+        PerpsInstruction::GetPoolNav => views::get_pool_nav(program_id, accounts),
+        PerpsInstruction::ExecuteWithdrawal => liquidity_pool::execute_withdrawal(program_id, accounts),
+        PerpsInstruction::SetPoolParams { withdrawal_cooldown_slots, max_epoch_withdrawal_share } => {
+            liquidity_pool::set_pool_params(program_id, accounts, withdrawal_cooldown_slots, max_epoch_withdrawal_share)
+        }
+        PerpsInstruction::SetIndexOracle { index_oracle, mark_price_band } => {
+            set_index_oracle(program_id, accounts, index_oracle, mark_price_band)
+        }
+        PerpsInstruction::CheckCircuitBreaker => check_circuit_breaker(program_id, accounts),
+        PerpsInstruction::SetCircuitBreaker { max_move, window_slots, cooldown_slots } => {
+            set_circuit_breaker(program_id, accounts, max_move, window_slots, cooldown_slots)
+        }
+        PerpsInstruction::SettleMarket => settle_market(program_id, accounts),
+        PerpsInstruction::SettlePosition => settle_position(program_id, accounts),
+        PerpsInstruction::ExpireMarket => expire_market(program_id, accounts),
+        PerpsInstruction::SetMarketExpiry { expiry_timestamp } => set_market_expiry(program_id, accounts, expiry_timestamp),
+        PerpsInstruction::CreateUserStats => user_stats::create_user_stats(program_id, accounts),
+        PerpsInstruction::ComputePortfolioHealth => views::compute_portfolio_health(program_id, accounts),
+        PerpsInstruction::GetLiquidationPrice => views::get_liquidation_price(program_id, accounts),
+        PerpsInstruction::InitializeMarket { market_id, initial_price, base_decimals } => {
+            initialize_market(program_id, accounts, market_id, initial_price, base_decimals)
+        }
+        PerpsInstruction::CrankMatch { max_fills } => orderbook::crank_match(program_id, accounts, max_fills),
+        PerpsInstruction::SocializeLoss => socialize_loss(program_id, accounts),
+        PerpsInstruction::RepegVamm => repeg_vamm(program_id, accounts),
+        PerpsInstruction::SetRewardsSchedule { start_slot, end_slot, reward_per_volume, total_emissions } => {
+            rewards::set_rewards_schedule(program_id, accounts, start_slot, end_slot, reward_per_volume, total_emissions)
+        }
+        PerpsInstruction::CreateUserRewards => rewards::create_user_rewards(program_id, accounts),
+        PerpsInstruction::ClaimRewards => rewards::claim_rewards(program_id, accounts),
+        PerpsInstruction::ClaimMakerRebates => orderbook::claim_maker_rebates(program_id, accounts),
+        PerpsInstruction::SetFeeTiers { fee_tiers } => set_fee_tiers(program_id, accounts, fee_tiers),
+        PerpsInstruction::SetStakeDiscounts { stake_discounts } => {
+            staking::set_stake_discounts(program_id, accounts, stake_discounts)
+        }
+        PerpsInstruction::StakeTokens { amount } => staking::stake_tokens(program_id, accounts, amount),
+        PerpsInstruction::UnstakeTokens { amount } => staking::unstake_tokens(program_id, accounts, amount),
+        PerpsInstruction::SetRiskTiers { risk_tiers } => set_risk_tiers(program_id, accounts, risk_tiers),
+        PerpsInstruction::SetMarketAccess { permissioned, access_mint } => {
+            access::set_market_access(program_id, accounts, permissioned, access_mint)
+        }
+        PerpsInstruction::TokenizePosition => position_token::tokenize_position(program_id, accounts),
+        PerpsInstruction::SetLeverageOverride { wallet, max_leverage } => {
+            access::set_leverage_override(program_id, accounts, wallet, max_leverage)
+        }
+        PerpsInstruction::SetWhitelisted { wallet, whitelisted } => {
+            access::set_whitelisted(program_id, accounts, wallet, whitelisted)
+        }
+        PerpsInstruction::SetLiquidatorAllowlist { enabled } => {
+            access::set_liquidator_allowlist(program_id, accounts, enabled)
+        }
+        PerpsInstruction::SetLiquidator { liquidator, allowed } => {
+            access::set_liquidator(program_id, accounts, liquidator, allowed)
+        }
+        PerpsInstruction::CancelAllOrders => orderbook::cancel_all_orders(program_id, accounts),
+        PerpsInstruction::BatchOrders { ops } => orderbook::batch_orders(program_id, accounts, ops),
+        PerpsInstruction::LiquidateMany => liquidate_many(program_id, accounts),
+        PerpsInstruction::SettleFunding => settle_funding_for(program_id, accounts),
+        PerpsInstruction::SetMarkPriceEma { half_life_slots, ema_liquidation } => {
+            set_mark_price_ema(program_id, accounts, half_life_slots, ema_liquidation)
+        }
+        PerpsInstruction::SetBackupOracles { backup_oracles } => {
+            set_backup_oracles(program_id, accounts, backup_oracles)
+        }
+        PerpsInstruction::SetPriceFeed { feed_id, authority } => {
+            price_feed::set_price_feed(program_id, accounts, feed_id, authority)
+        }
+        PerpsInstruction::PushPrice { price, conf, timestamp } => {
+            price_feed::push_price(program_id, accounts, price, conf, timestamp)
+        }
+        PerpsInstruction::SetMinHoldSlots { min_hold_slots } => set_min_hold_slots(program_id, accounts, min_hold_slots),
+        PerpsInstruction::SetMaxLiquidationShare { max_liquidation_share } => {
+            set_max_liquidation_share(program_id, accounts, max_liquidation_share)
+        }
+        PerpsInstruction::SetLiquidationGrace { grace_slots, floor_ratio } => {
+            set_liquidation_grace(program_id, accounts, grace_slots, floor_ratio)
+        }
+        PerpsInstruction::FlagForLiquidation => flag_for_liquidation(program_id, accounts),
+        PerpsInstruction::CheckHealth => views::check_health(program_id, accounts),
+        PerpsInstruction::SetTreasury => set_treasury(program_id, accounts),
+        PerpsInstruction::WithdrawFees { amount } => withdraw_fees(program_id, accounts, amount),
+        PerpsInstruction::SetMaxTotalCollateral { max_total_collateral } => {
+            set_max_total_collateral(program_id, accounts, max_total_collateral)
+        }
+        PerpsInstruction::SetWithdrawalLimits { cooldown_slots, max_epoch_withdrawal } => {
+            set_withdrawal_limits(program_id, accounts, cooldown_slots, max_epoch_withdrawal)
+        }
+    }
+}
+
+/// The instruction a position change came through, which sets the changes it accepts
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum PositionChange {
+    /// open_position: opens, grows, reduces or flips; reduce-only when flagged
+    Any,
+    /// increase_position: opens from flat or grows on the position's side
+    Increase,
+    /// decrease_position: shrinks an open position without flipping it
+    Decrease,
+}
+
+// ---------------------------------------------------------------------
+// 0️⃣ Open / modify a position
+// ---------------------------------------------------------------------
+pub fn open_position(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    base_delta: i64,
+    collateral_delta: u64,
+    price_limit: u64,
+    flags: u8,
+    sub_account_id: u16,
+) -> ProgramResult {
+    change_position(program_id, accounts, base_delta, collateral_delta, price_limit, flags, sub_account_id, PositionChange::Any)
+}
+
+// ---------------------------------------------------------------------
+// 9️⃣2️⃣ Open a position or grow it on its side
+// ---------------------------------------------------------------------
+pub fn increase_position(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    base_delta: i64,
+    collateral_delta: u64,
+    price_limit: u64,
+    flags: u8,
+    sub_account_id: u16,
+) -> ProgramResult {
+    // Accounts: as open_position
+    if base_delta == 0 {
+        msg!("increase_position needs a size; deposit collateral with open_position");
+        return Err(ProgramError::InvalidArgument);
+    }
+    if flags & OPEN_FLAG_REDUCE_ONLY != 0 {
+        msg!("increase_position can't be reduce-only; use decrease_position");
+        return Err(ProgramError::InvalidInstructionData);
+    }
+
+    change_position(program_id, accounts, base_delta, collateral_delta, price_limit, flags, sub_account_id, PositionChange::Increase)
+}
+
+// ---------------------------------------------------------------------
+// 9️⃣3️⃣ Shrink a position without flipping it, realizing PnL on the part closed
+// ---------------------------------------------------------------------
+pub fn decrease_position(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    base_amount: u64,
+    price_limit: u64,
+    flags: u8,
+    sub_account_id: u16,
+) -> ProgramResult {
+    // Accounts: as open_position; the position must already exist
+    if base_amount == 0 {
+        msg!("decrease_position needs a size");
+        return Err(ProgramError::InvalidArgument);
+    }
+    if flags & !OPEN_FLAG_SIMULATE != 0 {
+        msg!("decrease_position only takes OPEN_FLAG_SIMULATE: {:#010b}", flags);
+        return Err(ProgramError::InvalidInstructionData);
+    }
+    // The direction is the position's; the handler signs the size once it is loaded
+    let base_amount = i64::try_from(base_amount).map_err(|_| ProgramError::InvalidArgument)?;
+
+    change_position(
+        program_id,
+        accounts,
+        base_amount,
+        0,
+        price_limit,
+        flags | OPEN_FLAG_REDUCE_ONLY,
+        sub_account_id,
+        PositionChange::Decrease,
+    )
+}
+
+/// Apply a position change through the vAMM; `change` sets which changes are accepted
+#[allow(clippy::too_many_arguments)]
+fn change_position(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    base_delta: i64,
+    collateral_delta: u64,
+    price_limit: u64,
+    flags: u8,
+    sub_account_id: u16,
+    change: PositionChange,
+) -> ProgramResult {
+    // Accounts:
+    // 0. [signer] user (position owner, or its delegate for an existing position)
+    // 1. [] token program
+    // 2. [writable] user's collateral token account (quote token, e.g., USDC)
+    // 3. [writable] vault token account (PDA‑owned)
+    // 4. [writable] position account (PDA: [POSITION_SEED, market_state, user, sub_account_id])
+    // 5. [writable] market state account (PDA‑derived)
+    // 6. [] rent sysvar
+    // 7. [] clock sysvar
+    // 8. [] system program (for account creation)
+    // 9. [] config account
+    // 10. [] quote mint
+    // Markets with an index oracle (shifts the accounts below by one per feed):
+    //   11. [] the market's index oracle, then its backup oracles
+    // Isolated positions:
+    //   11..11+k. [] oracle of each collateral asset the position holds, in asset order
+    // Cross-margined positions:
+    //   11. [writable] owner's user account
+    //   12..12+2k. [] position and market state account of every other linked position
+    // Then, in either order:
+    //   [writable, optional] referrer account credited with a share of the fee
+    //   [writable, optional] position owner's user stats account
+    //   [writable, optional] market metrics account
+    //   [writable, optional] the market's price history
+    //   [writable, optional] rewards schedule and position owner's user rewards account
+    //   [] signer's token account holding a tokenized position's NFT
+    //
+    // With OPEN_FLAG_SIMULATE nothing is written, no account needs to be writable and a new
+    // position isn't created. Returns: fill price, trading fee, skew fee (negative for a
+    // rebate), then the post-trade collateral (the user account's for cross-margined
+    // positions), collateral ratio and health factor as in `check_health`, each 8 bytes LE
+    let accounts_iter = &mut accounts.iter();
+    let user = next_account_info(accounts_iter)?;
+    let token_program = next_account_info(accounts_iter)?;
+    let user_collateral = next_account_info(accounts_iter)?;
+    let vault = next_account_info(accounts_iter)?;
+    let position_acc = next_account_info(accounts_iter)?;
+    let market_state_acc = next_account_info(accounts_iter)?;
+    let rent_sysvar = next_account_info(accounts_iter)?;
+    let clock_sysvar = next_account_info(accounts_iter)?;
+    let system_program = next_account_info(accounts_iter)?;
+    let config_acc = next_account_info(accounts_iter)?;
+    let quote_mint = next_account_info(accounts_iter)?;
+    let remaining_accs: Vec<&AccountInfo> = accounts_iter.collect();
+
+    // Ensure user is signer
+    if !user.is_signer {
+        msg!("User must be signer");
+        return Err(ProgramError::MissingRequiredSignature);
+    }
+
+    // price_limit is the worst acceptable average fill price (max for longs,
+    // min for shorts; 0 = no limit)
+    if flags & !(OPEN_FLAG_REDUCE_ONLY | OPEN_FLAG_CROSS_MARGIN | OPEN_FLAG_SIMULATE) != 0 {
+        msg!("Unknown open_position flags: {:#010b}", flags);
+        return Err(ProgramError::InvalidInstructionData);
+    }
+    // A simulation runs every check and all the math on copies of the accounts
+    let simulate = flags & OPEN_FLAG_SIMULATE != 0;
+
+    msg!("Opening position: base_delta={}, collateral_delta={}, price_limit={}, flags={:#010b}", 
+         base_delta, collateral_delta, price_limit, flags);
+
+    let config = load_config(program_id, config_acc)?;
+    require_not_settlement_only(&config)?;
+    let quote_decimals = validate_quote_mint(&config, token_program, quote_mint)?;
+    let clock = Clock::from_account_info(clock_sysvar)?;
+    let rent = Rent::from_account_info(rent_sysvar)?;
+
+    // The signer's collateral account must hold the quote mint; the vault is checked once the market is loaded
+    validate_token_account(token_program.key, user_collateral, quote_mint.key, Some(user.key))?;
+
+    // Markets are created by the admin with initialize_market, never on first use
+    if market_state_acc.owner != program_id {
+        msg!("Market state is not owned by the program; create it with initialize_market");
+        return Err(ProgramError::IncorrectProgramId);
+    }
+
+    // ---------- Initialize position if empty ----------
+    let position_created_here = position_acc.data_is_empty();
+    if position_created_here && change == PositionChange::Decrease {
+        msg!("No position to decrease");
+        return Err(ProgramError::UninitializedAccount);
+    }
+    let mut simulated_position = None;
+    if position_created_here {
+        let (expected_position, position_bump) =
+            position_address(program_id, market_state_acc.key, user.key, sub_account_id);
+        if *position_acc.key != expected_position {
+            msg!("Position account is not the correct PDA for sub-account {}. Expected: {}, Got: {}",
+                 sub_account_id, expected_position, position_acc.key);
+            return Err(ProgramError::InvalidArgument);
+        }
+
+        let new_position = Position {
+            owner: *user.key,
+            base_amount: 0,
+            collateral: 0,
+            last_funding_index: 0,
+            entry_price: 0,
+            version: Position::VERSION,
+            margin_mode: if flags & OPEN_FLAG_CROSS_MARGIN != 0 { MARGIN_MODE_CROSS } else { MARGIN_MODE_ISOLATED },
+            sub_account_id,
+            bump: position_bump,
+            ..Position::default()
+        };
+
+        if simulate {
+            simulated_position = Some(new_position);
+        } else {
+            // The owner pays for the account; a caller program whose owner PDA carries data,
+            // and so can't pay, funds the address beforehand
+            create_program_account(program_id, user, position_acc, system_program, &rent, Position::SPACE, &[
+                POSITION_SEED,
+                market_state_acc.key.as_ref(),
+                user.key.as_ref(),
+                &sub_account_id.to_le_bytes(),
+                &[position_bump],
+            ])?;
+
+            *Position::init(&mut position_acc.try_borrow_mut_data()?)? = new_position;
+            msg!("Initialized position account for user: {}, sub-account: {}", user.key, sub_account_id);
+        }
+    } else if simulate {
+        // A real call would fail to write an account the program doesn't own
+        if position_acc.owner != program_id {
+            msg!("Position is not owned by the program");
+            return Err(ProgramError::IncorrectProgramId);
+        }
+        simulated_position = Some(*Position::load(&position_acc.try_borrow_data()?)?);
+    }
+
+    // ---------- Borrow account data in place, or copy it for a simulation ----------
+    let mut simulated_market =
+        if simulate { Some(*MarketState::load(&market_state_acc.try_borrow_data()?)?) } else { None };
+    let mut market_state_data = market_state_acc.try_borrow_mut_data()?;
+    let market_state = match simulated_market.as_mut() {
+        Some(market_state) => market_state,
+        None => MarketState::load_mut(&mut market_state_data)?,
+    };
+    require_active(market_state)?;
+    validate_market_vault(program_id, token_program, vault, market_state, quote_mint)?;
+    let mut position_data = position_acc.try_borrow_mut_data()?;
+    let position = match simulated_position.as_mut() {
+        Some(position) => position,
+        None => Position::load_mut(&mut position_data)?,
+    };
+    // An existing position must trade in this market, or its PnL would be realized at another market's mark
+    validate_position_address(program_id, position_acc.key, market_state_acc.key, position)?;
+
+    // A decrease trades against the position's side; there is no side to trade against when flat
+    let base_delta = match change {
+        PositionChange::Decrease if position.base_amount == 0 => {
+            msg!("Position is flat: nothing to decrease");
+            return Err(ProgramError::InvalidArgument);
+        }
+        PositionChange::Decrease => -position.base_amount.signum() * base_delta,
+        PositionChange::Any | PositionChange::Increase => base_delta,
+    };
+
+    // Sizes arrive in the market's base units; the rest of the handler works in 1e9 precision
+    let base_delta = market_state.size_to_precision(base_delta)?;
+    let base_delta = apply_min_position_size(position.base_amount, base_delta, config.min_position_size)?;
+
+    // Verify the signer is the position owner or its delegate, or holds its NFT
+    if !position.can_trade(user.key) && !position_token::holds_position_token(position, user.key, &remaining_accs) {
+        msg!("Signer {} is neither the owner ({}), the delegate nor the token holder of the position", user.key, position.owner);
+        return Err(ProgramError::IllegalOwner);
+    }
+
+    if position.sub_account_id != sub_account_id {
+        msg!("Sub-account mismatch. Position: {}, instruction: {}", position.sub_account_id, sub_account_id);
+        return Err(ProgramError::InvalidArgument);
+    }
+
+    let is_reduction = is_reducing_change(position.base_amount, base_delta);
+    if flags & OPEN_FLAG_REDUCE_ONLY != 0 && !is_reduction {
+        msg!("Reduce-only change rejected: base_amount={}, base_delta={}",
+             position.base_amount, base_delta);
+        return Err(ProgramError::InvalidArgument);
+    }
+    if change == PositionChange::Increase && !is_growing_change(position.base_amount, base_delta) {
+        msg!("Increase must grow the position on its side: base_amount={}, base_delta={}",
+             position.base_amount, base_delta);
+        return Err(ProgramError::InvalidArgument);
+    }
+
+    // A wallet's positions in a market are its sub-accounts, so capping the ids caps their number
+    if !is_reduction && config.max_positions_per_user != 0 && sub_account_id >= config.max_positions_per_user {
+        msg!("Sub-account {} exceeds the limit of {} positions per wallet in a market",
+             sub_account_id, config.max_positions_per_user);
+        return Err(ProgramError::InvalidArgument);
+    }
+
+    // The market's index oracle and backups, if it has them, precede the other remaining accounts
+    let (index_price, remaining_accs) = if market_state.has_index_oracle() {
+        let (index, rest) = read_index_price(market_state, &remaining_accs, clock.slot)?;
+        if simulate {
+            market_state.observe_index_price(index.price, clock.slot);
+        } else {
+            observe_circuit_breaker(market_state_acc.key, market_state, index.price, clock.slot);
+        }
+        (Some(index.price), rest)
+    } else {
+        (None, &remaining_accs[..])
+    };
+
+    if market_state.is_reduce_only(clock.slot) && !is_reduction {
+        msg!("Market is paused or cooling down from a circuit breaker: only reductions are allowed");
+        return Err(ProgramError::InvalidArgument);
+    }
+
+    if market_state.is_expired(clock.unix_timestamp) && !is_reduction {
+        msg!("Market expired at {}: only reductions are allowed", market_state.expiry_timestamp);
+        return Err(ProgramError::InvalidArgument);
+    }
+
+    if base_delta != 0 && is_reduction {
+        require_hold_elapsed(position, market_state, clock.slot)?;
+    }
+
+    if flags & OPEN_FLAG_CROSS_MARGIN != 0 && !position.is_cross_margin() {
+        msg!("Existing positions change margin mode with set_margin_mode");
+        return Err(ProgramError::InvalidArgument);
+    }
+
+    // Oracles for an isolated position's collateral assets, or the user account and other
+    // linked positions of a cross-margined one, come first; then the optional referrer
+    let (mut cross_margin, oracle_accs, referrer_accs) = if position.is_cross_margin() {
+        let (cross_margin, used) = CrossMargin::load(
+            program_id,
+            position_acc.key,
+            position,
+            market_state_acc.key,
+            remaining_accs,
+            position_created_here,
+        )?;
+        (Some(cross_margin), &remaining_accs[..0], &remaining_accs[used..])
+    } else {
+        let asset_count = held_asset_count(position);
+        if remaining_accs.len() < asset_count {
+            msg!("Missing oracle accounts for {} collateral assets", asset_count);
+            return Err(ProgramError::NotEnoughAccountKeys);
+        }
+        let (oracle_accs, referrer_accs) = remaining_accs.split_at(asset_count);
+        (None, oracle_accs, referrer_accs)
+    };
+    let stats_acc = user_stats::find_user_stats(program_id, &position.owner, referrer_accs);
+    let stake_acc = staking::find_stake(program_id, &position.owner, referrer_accs);
+    let metrics_acc = market_metrics::find_market_metrics(program_id, market_state_acc.key, referrer_accs);
+    let price_history_acc = price_history::find_price_history(program_id, market_state_acc.key, referrer_accs);
+    let referrer_acc = referrer_accs
+        .iter()
+        .copied()
+        .find(|account| {
+            !matches!(stats_acc, Some(stats_acc) if stats_acc.key == account.key)
+                && !matches!(metrics_acc, Some(metrics_acc) if metrics_acc.key == account.key)
+                && !matches!(price_history_acc, Some(history_acc) if history_acc.key == account.key)
+                && !matches!(stake_acc, Some(stake_acc) if stake_acc.key == account.key)
+                && !rewards::is_rewards_account(program_id, &position.owner, account)
+                && !access::is_access_account(program_id, market_state, &position.owner, account)
+                && !access::is_leverage_override(program_id, market_state_acc.key, &position.owner, account)
+                && !position_token::is_position_token_account(position, account)
+        });
+    // New positions are gated too, so an empty one can't be created to grow through orders
+    if !is_reduction || position_created_here {
+        access::require_whitelisted(program_id, market_state, &position.owner, referrer_accs)?;
+    }
+
+    // ---------- Transfer collateral from user to vault ----------
+    if collateral_delta > 0 {
+        if simulate {
+            let balance = token_account_amount(user_collateral)?;
+            if balance < collateral_delta {
+                msg!("Collateral token account holds {}, less than the {} deposited", balance, collateral_delta);
+                return Err(ProgramError::InsufficientFunds);
+            }
+        } else {
+            let transfer_ix = create_transfer_checked_instruction(
+                token_program.key,
+                user_collateral.key,
+                quote_mint.key,
+                vault.key,
+                user.key,
+                collateral_delta,
+                quote_decimals,
+            )?;
+
+            invoke(&transfer_ix, &[
+                user_collateral.clone(),
+                quote_mint.clone(),
+                vault.clone(),
+                user.clone(),
+                token_program.clone(),
+            ])?;
+            msg!("Transferred {} collateral to vault", collateral_delta);
+        }
+
+        // Cross-margined deposits go to the shared collateral, which no one market counts
+        let deposit = market_state.quote_to_precision(collateral_delta)?;
+        let collateral = match cross_margin.as_mut() {
+            Some(cross_margin) => &mut cross_margin.user_account.collateral,
+            None => {
+                count_deposit(position, market_state, deposit)?;
+                &mut position.collateral
+            }
+        };
+        *collateral = collateral
+            .checked_add(deposit)
+            .ok_or(ProgramError::InvalidArgument)?;
+    }
+
+    // ---------- Apply pending funding before position update ----------
+    let bad_debt_before = market_state.bad_debt;
+    match cross_margin.as_mut() {
+        Some(cross_margin) => cross_margin.apply_funding(position, market_state, config.max_funding_settlement_share)?,
+        None => apply_funding(position, market_state, config.max_funding_settlement_share)?,
+    }
+
+    // ---------- Execute against the vAMM ----------
+    let mut fill_price = market_state.mark_price;
+    if base_delta != 0 {
+        let fill = execute_vamm_trade(market_state, base_delta)?;
+        if !simulate {
+            emit_vamm_trade(market_state_acc.key, position_acc.key, base_delta, &fill, market_state);
+        }
+
+        let breaches_limit = (base_delta > 0 && fill.fill_price > price_limit)
+            || (base_delta < 0 && fill.fill_price < price_limit);
+        if price_limit > 0 && breaches_limit {
+            msg!("Fill price {} breaches price limit {}", fill.fill_price, price_limit);
+            return Err(ProgramError::InvalidArgument);
+        }
+
+        // Opens must fill near the index, and no trade here can drag the mark outside the band
+        if let Some(index_price) = index_price {
+            if !is_reduction && !is_within_price_band(fill.fill_price, index_price, market_state.mark_price_band) {
+                msg!("Fill price {} is outside the {} band around index price {}",
+                     fill.fill_price, market_state.mark_price_band, index_price);
+                return Err(ProgramError::InvalidArgument);
+            }
+            market_state.mark_price = clamp_to_price_band(market_state.mark_price, index_price, market_state.mark_price_band);
+        }
+
+        fill_price = fill.fill_price;
+    }
+
+    // ---------- Update position, realizing PnL on any reduction ----------
+    match cross_margin.as_mut() {
+        Some(cross_margin) => cross_margin.apply_position_change(position, market_state, base_delta, fill_price)?,
+        None => apply_position_change(position, market_state, base_delta, fill_price)?,
+    }
+    if !simulate {
+        emit_bad_debt(market_state_acc.key, position_acc.key, market_state, bad_debt_before);
+    }
+    if !is_reduction {
+        position.last_modified_slot = clock.slot;
+    }
+
+    // ---------- Charge or rebate the skew fee ----------
+    let collateral = match cross_margin.as_mut() {
+        Some(cross_margin) => &mut cross_margin.user_account.collateral,
+        None => &mut position.collateral,
+    };
+    let skew_fee = charge_skew_fee(collateral, market_state, base_delta, fill_price)?;
+
+    // ---------- Charge trading fee, at the owner's volume tier less any stake discount ----------
+    // The owner's stats are decoded once: they set the fee tier, then record the trade
+    let mut stats = stats_acc
+        .map(|stats_acc| load_account::<user_stats::UserStats>(&stats_acc.data.borrow()))
+        .transpose()?;
+    let mut fee_rate = match &stats {
+        Some(stats) => config.taker_fee(stats.rolling_volume(clock.unix_timestamp)),
+        None => config.trading_fee,
+    };
+    if let Some(stake_acc) = stake_acc {
+        let stake = load_account::<staking::StakeAccount>(&stake_acc.data.borrow())?;
+        let discount = math::mul_scaled(fee_rate, config.stake_discount(stake.staked))?;
+        fee_rate = fee_rate.saturating_sub(discount);
+    }
+    let trading_fee = calculate_trading_fee(base_delta, fill_price, fee_rate)?;
+    if trading_fee > 0 {
+        let collateral = match cross_margin.as_mut() {
+            Some(cross_margin) => &mut cross_margin.user_account.collateral,
+            None => &mut position.collateral,
+        };
+        *collateral = collateral
+            .checked_sub(trading_fee)
+            .ok_or(ProgramError::InsufficientFunds)?;
+
+        let mut protocol_fee = trading_fee;
+        if let Some(referrer_acc) = referrer_acc {
+            let mut referrer = load_referrer(program_id, referrer_acc)?;
+            if referrer.owner == position.owner {
+                msg!("Cannot refer your own trades");
+                return Err(ProgramError::InvalidArgument);
+            }
+
+            let referral_share = calculate_referral_share(trading_fee, config.referral_fee_share)?;
+            referrer.claimable_fees = referrer
+                .claimable_fees
+                .checked_add(referral_share)
+                .ok_or(ProgramError::InvalidArgument)?;
+            referrer.total_fees_earned = referrer
+                .total_fees_earned
+                .checked_add(referral_share)
+                .ok_or(ProgramError::InvalidArgument)?;
+            if referral_share > 0 && !simulate {
+                store_account(&referrer, &mut referrer_acc.data.borrow_mut())?;
+            }
+
+            protocol_fee = protocol_fee
+                .checked_sub(referral_share)
+                .ok_or(ProgramError::InvalidArgument)?;
+            msg!("Credited referral fee: {} to {}", referral_share, referrer.owner);
+        }
+
+        market_state.fee_pool = market_state
+            .fee_pool
+            .checked_add(protocol_fee)
+            .ok_or(ProgramError::InvalidArgument)?;
+        msg!("Charged trading fee: {}", trading_fee);
+    }
+
+    if base_delta != 0 && !simulate {
+        let notional = calculate_notional(base_delta, fill_price)?;
+        if let (Some(stats_acc), Some(stats)) = (stats_acc, stats.as_mut()) {
+            stats.record_trade(notional, trading_fee, clock.unix_timestamp);
+            store_account(stats, &mut stats_acc.data.borrow_mut())?;
+        }
+        if let Some(metrics_acc) = metrics_acc {
+            let mut metrics = load_account::<market_metrics::MarketMetrics>(&metrics_acc.data.borrow())?;
+            metrics.record_trade(notional, trading_fee, market_state.open_interest);
+            store_account(&metrics, &mut metrics_acc.data.borrow_mut())?;
+        }
+        if let Some(price_history_acc) = price_history_acc {
+            price_history::record_price(price_history_acc, market_state.mark_price, clock.unix_timestamp)?;
+        }
+        rewards::accrue_trading_rewards(program_id, &position.owner, referrer_accs, notional)?;
+    }
+
+    // ---------- Sweep dust collateral left by a full close ----------
+    if base_delta != 0 {
+        sweep_dust_collateral(position, market_state, config.dust_collateral)?;
+    }
+
+    // ---------- Validate collateral ratio, leverage and the notional cap ----------
+    if !is_reduction {
+        let notional = match &cross_margin {
+            Some(cross_margin) => cross_margin.notional(position, market_state)?,
+            None => calculate_notional(position.base_amount, market_state.mark_price)?,
+        };
+        validate_user_notional(notional, config.max_user_notional)?;
+    }
+    let max_leverage = access::max_leverage_for(program_id, market_state_acc.key, market_state, &position.owner, referrer_accs);
+    let margin_collateral = if let Some(cross_margin) = &cross_margin {
+        cross_margin.validate(position, market_state, config.min_collateral_ratio, max_leverage, is_reduction)?;
+        if !simulate {
+            cross_margin.store()?;
+        }
+        cross_margin.user_account.collateral
+    } else {
+        let asset_collateral = calculate_weighted_asset_collateral(position, &config, oracle_accs, clock.slot)?;
+        let margin_position = Position {
+            collateral: position.collateral
+                .checked_add(asset_collateral)
+                .ok_or(ProgramError::InvalidArgument)?,
+            ..*position
+        };
+        validate_collateral_ratio(&margin_position, market_state, config.min_collateral_ratio)?;
+        if !is_reduction {
+            validate_leverage(&margin_position, market_state, max_leverage)?;
+        }
+        margin_position.collateral
+    };
+
+    // Having passed the margin check, the position has answered any margin call
+    position.margin_call_slot = 0;
+
+    if simulate {
+        let (collateral_ratio, health_factor) = match &cross_margin {
+            _ if position.base_amount == 0 => (u64::MAX, u64::MAX),
+            Some(cross_margin) => cross_margin.liquidation_health(position, market_state, config.min_collateral_ratio)?,
+            None => calculate_isolated_liquidation_health(position, margin_collateral, market_state, config.min_collateral_ratio)?,
+        };
+        let collateral = cross_margin.as_ref().map_or(position.collateral, |cross_margin| cross_margin.user_account.collateral);
+        set_return_data(&[
+            fill_price.to_le_bytes(),
+            trading_fee.to_le_bytes(),
+            skew_fee.to_le_bytes(),
+            collateral.to_le_bytes(),
+            collateral_ratio.to_le_bytes(),
+            health_factor.to_le_bytes(),
+        ].concat());
+
+        msg!("Simulated: fill_price={}, trading_fee={}, skew_fee={}, collateral={}, health_factor={}",
+             fill_price, trading_fee, skew_fee, collateral, health_factor);
+        return Ok(());
+    }
+
+    msg!("Position updated successfully: base={}, collateral={}, open_interest={}", 
+         position.base_amount, position.collateral, market_state.open_interest);
+    
+    Ok(())
+}
+
+// ---------------------------------------------------------------------
 // 1️⃣ Update funding index (called periodically, e.g., every slot)
 // ---------------------------------------------------------------------
-pub fn update_funding(_program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramResult {
+pub fn update_funding(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramResult {
+    // Accounts:
+    // 0. [writable] market state PDA
+    // 1. [] clock sysvar
+    // 2. [] config account
+    // Optional, to collect the keeper reward:
+    // 3. [] token program
+    // 4. [writable] keeper's token account (quote token)
+    // 5. [writable] vault token account (PDA‑owned)
+    // 6. [] quote mint
+    // Then, in either order:
+    //   [writable, optional] the market's funding history
+    //   [writable, optional] the market's price history
+    let accounts_iter = &mut accounts.iter();
+    let market_state_acc = next_account_info(accounts_iter)?;
+    let clock_sysvar = next_account_info(accounts_iter)?;
+    let config_acc = next_account_info(accounts_iter)?;
+    let mut reward_accs: Vec<&AccountInfo> = accounts_iter.collect();
+    let history_acc = funding_history::find_funding_history(program_id, market_state_acc.key, &reward_accs);
+    let price_history_acc = price_history::find_price_history(program_id, market_state_acc.key, &reward_accs);
+    for history in [history_acc, price_history_acc].into_iter().flatten() {
+        reward_accs.retain(|account| account.key != history.key);
+    }
+
+    if market_state_acc.owner != program_id {
+        msg!("Market state is not owned by the program");
+        return Err(ProgramError::IncorrectProgramId);
+    }
+
+    let config = load_config(program_id, config_acc)?;
+    require_not_settlement_only(&config)?;
+    let clock = Clock::from_account_info(clock_sysvar)?;
+    let mut market_state_data = market_state_acc.try_borrow_mut_data()?;
+    let market_state = MarketState::load_mut(&mut market_state_data)?;
+    require_active(market_state)?;
+
+    if market_state.is_dated() {
+        msg!("Dated futures converge to the index at expiry and don't pay funding");
+        return Err(ProgramError::InvalidArgument);
+    }
+
+    // Calculate slots elapsed since last funding update
+    let slots_elapsed = clock.slot
+        .checked_sub(market_state.last_funding_slot)
+        .ok_or(ProgramError::InvalidAccountData)?;
+
+    // Idempotent within a slot: repeat calls change nothing and earn nothing
+    if slots_elapsed == 0 {
+        msg!("No slots elapsed since last funding update");
+        return Ok(());
+    }
+
+    // Longs pay shorts in proportion to how long-heavy the market is, and vice versa
+    let funding_rate = calculate_skew_funding_rate(market_state.long_open_interest, market_state.short_open_interest)?;
+
+    // Clamp to the configured funding cap
+    let max_rate = i64::try_from(config.max_funding_rate_per_slot).unwrap_or(i64::MAX);
+    market_state.funding_rate_per_slot = funding_rate.clamp(-max_rate, max_rate);
+
+    // Accumulate funding index
+    let funding_increment = market_state.funding_rate_per_slot
+        .checked_mul(slots_elapsed as i64)
+        .ok_or(ProgramError::InvalidArgument)?;
+        
+    market_state.funding_index = market_state.funding_index
+        .checked_add(funding_increment)
+        .ok_or(ProgramError::InvalidArgument)?;
+    let funding_residual = market_state.accrue_side_funding(funding_increment)?;
+
+    market_state.last_funding_slot = clock.slot;
+    market_state.update_mark_price_ema(clock.slot)?;
+
+    if let Some(history_acc) = history_acc {
+        funding_history::record_funding_settlement(history_acc, funding_history::FundingRecord {
+            slot: clock.slot,
+            timestamp: clock.unix_timestamp,
+            funding_rate_per_slot: market_state.funding_rate_per_slot,
+            premium: funding_rate,
+            funding_index: market_state.funding_index,
+        })?;
+    }
+    if let Some(price_history_acc) = price_history_acc {
+        price_history::record_price(price_history_acc, market_state.mark_price, clock.unix_timestamp)?;
+    }
+
+    msg!("Funding updated: rate_per_slot={}, index={}, slots_elapsed={}, residual_to_insurance={}", 
+         market_state.funding_rate_per_slot, market_state.funding_index, slots_elapsed, funding_residual);
+
+    // ---------- Keeper reward ----------
+    let [token_program, keeper_token_acc, vault, quote_mint] = match reward_accs.as_slice() {
+        [] => return Ok(()),
+        [a, b, c, d] => [*a, *b, *c, *d],
+        _ => {
+            msg!("Keeper reward needs token program, keeper token account, vault and quote mint");
+            return Err(ProgramError::NotEnoughAccountKeys);
+        }
+    };
+
+    let reward = calculate_funding_crank_reward(
+        slots_elapsed,
+        config.funding_crank_reward,
+        config.max_funding_crank_reward,
+        market_state.fee_pool,
+    );
+    if reward == 0 {
+        return Ok(());
+    }
+
+    let quote_decimals = validate_quote_mint(&config, token_program, quote_mint)?;
+    let (pda, bump) = validate_market_vault(program_id, token_program, vault, market_state, quote_mint)?;
+    validate_token_account(token_program.key, keeper_token_acc, quote_mint.key, None)?;
+
+    market_state.fee_pool -= reward;
+
+    let transfer_ix = create_transfer_checked_instruction(
+        token_program.key,
+        vault.key,
+        quote_mint.key,
+        keeper_token_acc.key,
+        &pda,
+        market_state.quote_from_precision(reward)?,
+        quote_decimals,
+    )?;
+
+    invoke_signed(&transfer_ix, &[
+        vault.clone(),
+        quote_mint.clone(),
+        keeper_token_acc.clone(),
+        vault.clone(), // PDA authority
+        token_program.clone(),
+    ], &[&[PDA_SEED, &[bump]]])?;
+    require_vault_covers(vault, market_state.reserves(), quote_decimals)?;
+
+    msg!("Keeper reward paid: {}", reward);
+
+    Ok(())
+}
+
+// ---------------------------------------------------------------------
+// 2️⃣ Liquidate an undercollateralized position
+// ---------------------------------------------------------------------
+pub fn liquidate(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramResult {
+    // Accounts:
+    // 0. [signer] liquidator
+    // 1. [] token program
+    // 2. [writable] liquidator's token account (to receive the liquidator's share of the fee)
+    // 3. [writable] vault token account (PDA‑owned)
+    // 4. [writable] position account to liquidate
+    // 5. [writable] market state account
+    // 6. [] clock sysvar
+    // 7. [] config account
+    // 8. [] quote mint
+    // Isolated positions:
+    //   9..N. [] oracle of each collateral asset the position holds
+    // Cross-margined positions:
+    //   9. [writable] owner's user account
+    //   10..N. [] position and market state account of every other linked position
+    // Then:
+    //   [writable, optional] position owner's user stats account
+    //   [writable, optional] market metrics account
+    //   [writable, optional] the market's price history
+    //   [] liquidator registry (required while liquidators are restricted)
+    let accounts_iter = &mut accounts.iter();
+    let liquidator = next_account_info(accounts_iter)?;
+    let token_program = next_account_info(accounts_iter)?;
+    let liquidator_token_acc = next_account_info(accounts_iter)?;
+    let vault = next_account_info(accounts_iter)?;
+    let position_acc = next_account_info(accounts_iter)?;
+    let market_state_acc = next_account_info(accounts_iter)?;
+    let clock_sysvar = next_account_info(accounts_iter)?;
+    let config_acc = next_account_info(accounts_iter)?;
+    let quote_mint = next_account_info(accounts_iter)?;
+    let remaining_accs: Vec<&AccountInfo> = accounts_iter.collect();
+
+    if !liquidator.is_signer {
+        msg!("Liquidator must be signer");
+        return Err(ProgramError::MissingRequiredSignature);
+    }
+
+    let config = load_config(program_id, config_acc)?;
+    access::require_registered_liquidator(program_id, &config, liquidator.key, &remaining_accs)?;
+    let quote_decimals = validate_quote_mint(&config, token_program, quote_mint)?;
+    validate_token_account(token_program.key, liquidator_token_acc, quote_mint.key, None)?;
+    let clock = Clock::from_account_info(clock_sysvar)?;
+    let mut market_state_data = market_state_acc.try_borrow_mut_data()?;
+    let market_state = MarketState::load_mut(&mut market_state_data)?;
+    require_active(market_state)?;
+    let (pda, bump) = validate_market_vault(program_id, token_program, vault, market_state, quote_mint)?;
+    let mut position_data = position_acc.try_borrow_mut_data()?;
+    let position = Position::load_mut(&mut position_data)?;
+
+    let liquidation = liquidate_through_vamm(
+        program_id, position_acc.key, position, market_state_acc.key, market_state, &config, &remaining_accs, clock.slot,
+    )?;
+    let fee = liquidation.fee;
+    price_history::record_price_if_passed(
+        program_id, market_state_acc.key, &remaining_accs, market_state.mark_price, clock.unix_timestamp,
+    )?;
+
+    // Transfer the liquidator's share of the fee
+    pay_liquidator_reward(
+        token_program, vault, quote_mint, liquidator_token_acc, &pda, bump, market_state, fee.liquidator_reward, quote_decimals,
+    )?;
+
+    msg!("Position liquidated: liquidator_reward={} ({} from insurance), insurance_fee={}, remaining_collateral={}, ratio_was={}", 
+         fee.liquidator_reward, fee.from_insurance, fee.insurance_fee, liquidation.remaining_collateral, liquidation.collateral_ratio);
+    
+    Ok(())
+}
+
+// ---------------------------------------------------------------------
+// 6️⃣9️⃣ Liquidate every eligible position of a batch
+// ---------------------------------------------------------------------
+pub fn liquidate_many(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramResult {
+    // Accounts:
+    // 0. [signer] liquidator
+    // 1. [] token program
+    // 2. [writable] liquidator's token account (to receive the liquidator's share of the fees)
+    // 3. [writable] vault token account (PDA‑owned)
+    // 4. [writable] market state account
+    // 5. [] clock sysvar
+    // 6. [] config account
+    // 7. [] quote mint
+    // 8..N. in any order:
+    //   [writable] isolated position accounts in the market to liquidate if eligible
+    //   [] oracle of each collateral asset those positions hold
+    //   [writable, optional] position owners' user stats accounts
+    //   [writable, optional] market metrics account
+    //   [writable, optional] the market's price history
+    //   [] liquidator registry (required while liquidators are restricted)
+    let accounts_iter = &mut accounts.iter();
+    let liquidator = next_account_info(accounts_iter)?;
+    let token_program = next_account_info(accounts_iter)?;
+    let liquidator_token_acc = next_account_info(accounts_iter)?;
+    let vault = next_account_info(accounts_iter)?;
+    let market_state_acc = next_account_info(accounts_iter)?;
+    let clock_sysvar = next_account_info(accounts_iter)?;
+    let config_acc = next_account_info(accounts_iter)?;
+    let quote_mint = next_account_info(accounts_iter)?;
+    let remaining_accs: Vec<&AccountInfo> = accounts_iter.collect();
+
+    if !liquidator.is_signer {
+        msg!("Liquidator must be signer");
+        return Err(ProgramError::MissingRequiredSignature);
+    }
+
+    if market_state_acc.owner != program_id {
+        msg!("Market state is not owned by the program");
+        return Err(ProgramError::IncorrectProgramId);
+    }
+
+    let config = load_config(program_id, config_acc)?;
+    access::require_registered_liquidator(program_id, &config, liquidator.key, &remaining_accs)?;
+    let quote_decimals = validate_quote_mint(&config, token_program, quote_mint)?;
+    validate_token_account(token_program.key, liquidator_token_acc, quote_mint.key, None)?;
+    let clock = Clock::from_account_info(clock_sysvar)?;
+    let mut market_state_data = market_state_acc.try_borrow_mut_data()?;
+    let market_state = MarketState::load_mut(&mut market_state_data)?;
+    require_active(market_state)?;
+    let (pda, bump) = validate_market_vault(program_id, token_program, vault, market_state, quote_mint)?;
+
+    let mut liquidated = 0u32;
+    let mut total_reward = 0u64;
+    for position_acc in remaining_accs.iter().filter(|acc| acc.owner == program_id && acc.key != market_state_acc.key) {
+        // Other program accounts (user stats, the registry) are passed alongside the positions
+        let mut position_data = position_acc.try_borrow_mut_data()?;
+        let Ok(position) = Position::load_mut(&mut position_data) else {
+            continue;
+        };
+        if position.is_cross_margin()
+            || position.base_amount == 0
+            || validate_position_address(program_id, position_acc.key, market_state_acc.key, position).is_err()
+        {
+            continue;
+        }
+
+        // Check on copies so an ineligible position is skipped without settling anything
+        let (mut trial_position, mut trial_market_state) = (*position, *market_state);
+        let eligible = check_liquidatable(
+            program_id, position_acc.key, &mut trial_position, market_state_acc.key, &mut trial_market_state,
+            &config, &remaining_accs, clock.slot,
+        )
+        .and_then(|(_, collateral_ratio)| {
+            require_grace_elapsed(&trial_position, &trial_market_state, collateral_ratio, clock.slot)
+        });
+        if eligible.is_err() {
+            continue;
+        }
+
+        let liquidation = liquidate_through_vamm(
+            program_id, position_acc.key, position, market_state_acc.key, market_state, &config, &remaining_accs, clock.slot,
+        )?;
+        total_reward = total_reward
+            .checked_add(liquidation.fee.liquidator_reward)
+            .ok_or(ProgramError::InvalidArgument)?;
+        liquidated += 1;
+        msg!("Position {} liquidated: liquidator_reward={}, ratio_was={}",
+             position_acc.key, liquidation.fee.liquidator_reward, liquidation.collateral_ratio);
+    }
+
+    if liquidated == 0 {
+        msg!("No position in the batch is liquidatable");
+        return Err(ProgramError::InvalidArgument);
+    }
+    price_history::record_price_if_passed(
+        program_id, market_state_acc.key, &remaining_accs, market_state.mark_price, clock.unix_timestamp,
+    )?;
+
+    // One transfer pays the liquidator's share of every fee
+    pay_liquidator_reward(
+        token_program, vault, quote_mint, liquidator_token_acc, &pda, bump, market_state, total_reward, quote_decimals,
+    )?;
+
+    msg!("Liquidated {} positions: liquidator_reward={}", liquidated, total_reward);
+
+    Ok(())
+}
+
+/// A completed liquidation
+struct Liquidation {
+    /// Fee split; the caller pays out `liquidator_reward`
+    fee: LiquidationFee,
+    /// Collateral ratio the position was liquidated at
+    collateral_ratio: u64,
+    /// Collateral left to the position, or to its user account
+    remaining_collateral: u64,
+}
+
+/// Liquidate `position` if it is eligible: unwind it, or the market's share of it per slot,
+/// through the vAMM, charge the liquidation fee on what closed and keep the insurance fund's share. The liquidator's share is left in the vault for
+/// the caller to pay out.
+#[allow(clippy::too_many_arguments)]
+fn liquidate_through_vamm<'a, 'info>(
+    program_id: &Pubkey,
+    position_key: &Pubkey,
+    position: &mut Position,
+    market_key: &Pubkey,
+    market_state: &mut MarketState,
+    config: &Config,
+    remaining_accs: &[&'a AccountInfo<'info>],
+    slot: u64,
+) -> Result<Liquidation, ProgramError> {
+    let bad_debt_before = market_state.bad_debt;
+    let (mut cross_margin, collateral_ratio) = check_liquidatable(
+        program_id, position_key, position, market_key, market_state, config, remaining_accs, slot,
+    )?;
+    require_grace_elapsed(position, market_state, collateral_ratio, slot)?;
+
+    // Unwind the position through the vAMM, or only the market's share of it per slot,
+    // realizing its PnL before the fee is charged
+    let base_delta = liquidation_base_delta(position.base_amount, market_state.max_liquidation_share)?;
+    let base_delta = apply_min_position_size(position.base_amount, base_delta, config.min_position_size)?;
+
+    // The fee is charged on the closed notional, so it does not shrink with the remaining collateral
+    let closed_notional = calculate_notional(base_delta, market_state.mark_price)?;
+
+    let fill = execute_vamm_trade(market_state, base_delta)?;
+    emit_vamm_trade(market_key, position_key, base_delta, &fill, market_state);
+    match cross_margin.as_mut() {
+        Some(cross_margin) => cross_margin.apply_position_change(position, market_state, base_delta, fill.fill_price)?,
+        None => apply_position_change(position, market_state, base_delta, fill.fill_price)?,
+    }
+    emit_bad_debt(market_key, position_key, market_state, bad_debt_before);
+
+    let fee = calculate_position_liquidation_fee(closed_notional, position, market_state, cross_margin.as_ref(), config)?;
+
+    let remaining_collateral = clear_liquidated_position(position, market_state, cross_margin.as_mut(), &fee, config.dust_collateral)?;
+    if position.base_amount != 0 {
+        position.next_liquidation_slot = slot.saturating_add(1);
+        msg!("Position partially liquidated: {} base left", position.base_amount);
+    }
+    user_stats::update_user_stats(program_id, &position.owner, remaining_accs, |stats| {
+        stats.record_liquidation(closed_notional, fee.collateral_charged())
+    })?;
+    market_metrics::update_market_metrics(program_id, market_key, remaining_accs, |metrics| {
+        metrics.record_liquidation(closed_notional, fee.collateral_charged(), market_state.open_interest)
+    })?;
+
+    Ok(Liquidation { fee, collateral_ratio, remaining_collateral })
+}
+
+/// Reject liquidating a position in a market with a liquidation grace period unless it was
+/// flagged at least `liquidation_grace_slots` ago, or its collateral ratio is below the
+/// market's hard floor
+pub(crate) fn require_grace_elapsed(position: &Position, market_state: &MarketState, collateral_ratio: u64, slot: u64) -> ProgramResult {
+    if market_state.liquidation_grace_slots == 0 || collateral_ratio < market_state.liquidation_floor_ratio {
+        return Ok(());
+    }
+
+    if position.margin_call_slot == 0 {
+        msg!("Position must be flagged for liquidation first. Collateral ratio: {} >= floor {}",
+             collateral_ratio, market_state.liquidation_floor_ratio);
+        return Err(ProgramError::InvalidArgument);
+    }
+
+    let liquidatable_slot = position.margin_call_slot.saturating_add(market_state.liquidation_grace_slots);
+    if slot < liquidatable_slot {
+        msg!("Position was flagged at slot {} and is in its grace period until slot {} (current slot {})",
+             position.margin_call_slot, liquidatable_slot, slot);
+        return Err(ProgramError::InvalidArgument);
+    }
+
+    Ok(())
+}
+
+/// Base change that liquidates `base_amount`: all of it, or `max_share` of it (1e9 precision)
+/// when the market throttles liquidations. A share too small to close any base closes it all.
+pub fn liquidation_base_delta(base_amount: i64, max_share: u64) -> Result<i64, ProgramError> {
+    let size = base_amount.unsigned_abs();
+    let closed = if max_share == 0 || max_share >= PRECISION {
+        size
+    } else {
+        math::mul_scaled(size, max_share)?
+    };
+    let closed = i64::try_from(if closed == 0 { size } else { closed }).map_err(|_| ProgramError::InvalidArgument)?;
+
+    Ok(if base_amount > 0 { -closed } else { closed })
+}
+
+/// Transfer `reward` (1e9 precision) from the vault to the liquidator's token account
+#[allow(clippy::too_many_arguments)]
+fn pay_liquidator_reward<'info>(
+    token_program: &AccountInfo<'info>,
+    vault: &AccountInfo<'info>,
+    quote_mint: &AccountInfo<'info>,
+    liquidator_token_acc: &AccountInfo<'info>,
+    pda: &Pubkey,
+    bump: u8,
+    market_state: &MarketState,
+    reward: u64,
+    quote_decimals: u8,
+) -> ProgramResult {
+    if reward == 0 {
+        return Ok(());
+    }
+
+    let transfer_ix = create_transfer_checked_instruction(
+        token_program.key,
+        vault.key,
+        quote_mint.key,
+        liquidator_token_acc.key,
+        pda,
+        market_state.quote_from_precision(reward)?,
+        quote_decimals,
+    )?;
+
+    invoke_signed(&transfer_ix, &[
+        vault.clone(),
+        quote_mint.clone(),
+        liquidator_token_acc.clone(),
+        vault.clone(), // PDA authority
+        token_program.clone(),
+    ], &[&[PDA_SEED, &[bump]]])?;
+
+    require_vault_covers(vault, market_state.reserves(), quote_decimals)
+}
+
+// ---------------------------------------------------------------------
+// 3️⃣ Close position (voluntary)
+// ---------------------------------------------------------------------
+pub fn close_position(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramResult {
+    // Accounts:
+    // 0. [signer] user (position owner, or the holder of a tokenized position's NFT)
+    // 1. [] token program
+    // 2. [writable] user's token account (to receive collateral)
+    // 3. [writable] vault token account (PDA‑owned)
+    // 4. [writable] position account
+    // 5. [writable] market state account
+    // 6. [] config account
+    // 7. [] quote mint
+    // 8. [writable, optional] owner's user stats account
+    // 9. [writable, optional] rewards schedule
+    // 10. [writable, optional] owner's user rewards account (with the schedule)
+    // 11. [writable, optional] market metrics account
+    // 12. [writable, optional] the market's price history
+    // Tokenized positions: [] signer's token account holding the position's NFT, among the above
+    let accounts_iter = &mut accounts.iter();
+    let user = next_account_info(accounts_iter)?;
+    let token_program = next_account_info(accounts_iter)?;
+    let user_token_acc = next_account_info(accounts_iter)?;
+    let vault = next_account_info(accounts_iter)?;
+    let position_acc = next_account_info(accounts_iter)?;
+    let market_state_acc = next_account_info(accounts_iter)?;
+    let config_acc = next_account_info(accounts_iter)?;
+    let quote_mint = next_account_info(accounts_iter)?;
+    let stats_accs: Vec<&AccountInfo> = accounts_iter.collect();
+
+    if !user.is_signer {
+        return Err(ProgramError::MissingRequiredSignature);
+    }
+
+    let config = load_config(program_id, config_acc)?;
+    let quote_decimals = validate_quote_mint(&config, token_program, quote_mint)?;
+    validate_token_account(token_program.key, user_token_acc, quote_mint.key, None)?;
+
+    let mut market_state_data = market_state_acc.try_borrow_mut_data()?;
+    let market_state = MarketState::load_mut(&mut market_state_data)?;
+    require_active(market_state)?;
+    let (pda, bump) = validate_market_vault(program_id, token_program, vault, market_state, quote_mint)?;
+    let mut position_data = position_acc.try_borrow_mut_data()?;
+    let position = Position::load_mut(&mut position_data)?;
+    validate_position_address(program_id, position_acc.key, market_state_acc.key, position)?;
+
+    // Verify ownership: the owner, or the holder of a tokenized position's NFT
+    position_token::require_position_authority(position, user.key, &stats_accs)?;
+
+    require_isolated(position)?;
+
+    if position.base_amount == 0 && position.collateral == 0 && position.pending_funding == 0 {
+        msg!("Position already closed");
+        return Ok(());
+    }
+
+    let clock = Clock::get()?;
+    require_hold_elapsed(position, market_state, clock.slot)?;
+
+    // Apply any socialized loss and pending funding; what the collateral can't pay is bad debt
+    let bad_debt_before = market_state.bad_debt;
+    apply_socialized_loss(position, market_state)?;
+    let funding_payment = calculate_pending_funding(position, market_state)?;
+    if funding_payment != 0 {
+        if funding_payment > 0 {
+            charge_funding_shortfall(&mut position.collateral, market_state, funding_payment.unsigned_abs())?;
+        } else {
+            credit_funding(position, funding_payment.unsigned_abs())?;
+        }
+        record_funding(position, funding_payment)?;
+    }
+
+    // Unwind the position through the vAMM, realizing its PnL into the collateral
+    if position.base_amount != 0 {
+        let base_delta = position.base_amount
+            .checked_neg()
+            .ok_or(ProgramError::InvalidArgument)?;
+        let fill = execute_vamm_trade(market_state, base_delta)?;
+        emit_vamm_trade(market_state_acc.key, position_acc.key, base_delta, &fill, market_state);
+        apply_position_change(position, market_state, base_delta, fill.fill_price)?;
+
+        let notional = calculate_notional(base_delta, fill.fill_price)?;
+        user_stats::update_user_stats(program_id, &position.owner, &stats_accs, |stats| {
+            stats.record_trade(notional, 0, clock.unix_timestamp)
+        })?;
+        market_metrics::update_market_metrics(program_id, market_state_acc.key, &stats_accs, |metrics| {
+            metrics.record_trade(notional, 0, market_state.open_interest)
+        })?;
+        price_history::record_price_if_passed(
+            program_id, market_state_acc.key, &stats_accs, market_state.mark_price, clock.unix_timestamp,
+        )?;
+        rewards::accrue_trading_rewards(program_id, &position.owner, &stats_accs, notional)?;
+    }
+    emit_bad_debt(market_state_acc.key, position_acc.key, market_state, bad_debt_before);
+
+    // Transfer remaining collateral to user, within the market's withdrawal limits
+    market_state.record_withdrawal(position, position.collateral, clock.slot, clock.epoch)?;
+    if position.collateral > 0 {
+        let seeds = &[PDA_SEED, &[bump]];
+        let signer_seeds = &[&seeds[..]];
+
+        let transfer_ix = create_transfer_checked_instruction(
+            token_program.key,
+            vault.key,
+            quote_mint.key,
+            user_token_acc.key,
+            &pda,
+            market_state.quote_from_precision(position.collateral)?,
+            quote_decimals,
+        )?;
+
+        invoke_signed(&transfer_ix, &[
+            vault.clone(),
+            quote_mint.clone(),
+            user_token_acc.clone(),
+            vault.clone(), // PDA authority
+            token_program.clone(),
+        ], signer_seeds)?;
+        require_vault_covers(vault, market_state.reserves(), quote_decimals)?;
+    }
+
+    let returned_collateral = position.collateral;
+    release_deposits(position, market_state);
+
+    // Clear the position
+    position.base_amount = 0;
+    position.collateral = 0;
+    position.entry_price = 0;
+    position.last_funding_index = market_state.funding_index;
+    position.pending_funding = 0;
+
+    msg!("Position closed: returned_collateral={}, new_open_interest={}", 
+         returned_collateral, market_state.open_interest);
+    
+    Ok(())
+}
+
+// ---------------------------------------------------------------------
+// 4️⃣ Register a referrer account
+// ---------------------------------------------------------------------
+pub fn register_referrer(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramResult {
+    // Accounts:
+    // 0. [signer, writable] referrer owner (pays for the account)
+    // 1. [writable] referrer account (PDA: [REFERRER_SEED, owner])
+    // 2. [] rent sysvar
+    // 3. [] system program
+    let accounts_iter = &mut accounts.iter();
+    let owner = next_account_info(accounts_iter)?;
+    let referrer_acc = next_account_info(accounts_iter)?;
+    let rent_sysvar = next_account_info(accounts_iter)?;
+    let system_program = next_account_info(accounts_iter)?;
+
+    if !owner.is_signer {
+        msg!("Referrer owner must be signer");
+        return Err(ProgramError::MissingRequiredSignature);
+    }
+
+    let (expected_referrer, bump) =
+        Pubkey::find_program_address(&[REFERRER_SEED, owner.key.as_ref()], program_id);
+    if *referrer_acc.key != expected_referrer {
+        msg!("Referrer account is not the correct PDA. Expected: {}, Got: {}",
+             expected_referrer, referrer_acc.key);
+        return Err(ProgramError::InvalidArgument);
+    }
+
+    if !referrer_acc.data_is_empty() {
+        msg!("Referrer already registered");
+        return Err(ProgramError::AccountAlreadyInitialized);
+    }
+
+    let rent = Rent::from_account_info(rent_sysvar)?;
+    let create_referrer_ix = system_instruction::create_account(
+        owner.key,
+        referrer_acc.key,
+        rent.minimum_balance(Referrer::SPACE),
+        Referrer::SPACE as u64,
+        program_id,
+    );
+
+    invoke_signed(&create_referrer_ix, &[
+        owner.clone(),
+        referrer_acc.clone(),
+        system_program.clone(),
+    ], &[&[REFERRER_SEED, owner.key.as_ref(), &[bump]]])?;
+
+    let referrer = Referrer {
+        owner: *owner.key,
+        claimable_fees: 0,
+        total_fees_earned: 0,
+        bump,
+    };
+    store_account(&referrer, &mut referrer_acc.data.borrow_mut())?;
+
+    msg!("Registered referrer: {}", owner.key);
+
+    Ok(())
+}
+
+// ---------------------------------------------------------------------
+// 5️⃣ Claim accumulated referral fees
+// ---------------------------------------------------------------------
+pub fn claim_referral_fees(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramResult {
+    // Accounts:
+    // 0. [signer] referrer owner
+    // 1. [] token program
+    // 2. [writable] owner's token account (to receive the fees)
+    // 3. [writable] vault token account (PDA‑owned)
+    // 4. [writable] referrer account
+    // 5. [] config account
+    // 6. [] quote mint
+    let accounts_iter = &mut accounts.iter();
+    let owner = next_account_info(accounts_iter)?;
+    let token_program = next_account_info(accounts_iter)?;
+    let owner_token_acc = next_account_info(accounts_iter)?;
+    let vault = next_account_info(accounts_iter)?;
+    let referrer_acc = next_account_info(accounts_iter)?;
+    let config_acc = next_account_info(accounts_iter)?;
+    let quote_mint = next_account_info(accounts_iter)?;
+
+    if !owner.is_signer {
+        msg!("Referrer owner must be signer");
+        return Err(ProgramError::MissingRequiredSignature);
+    }
+
+    let config = load_config(program_id, config_acc)?;
+    let quote_decimals = validate_quote_mint(&config, token_program, quote_mint)?;
+
+    let mut referrer = load_referrer(program_id, referrer_acc)?;
+    if referrer.owner != *owner.key {
+        msg!("Referrer owner mismatch. Expected: {}, Got: {}", referrer.owner, owner.key);
+        return Err(ProgramError::IllegalOwner);
+    }
+
+    let pda = validate_vault(program_id, token_program, vault, config.vault_bump, quote_mint.key)?;
+    let bump = config.vault_bump;
+    validate_token_account(token_program.key, owner_token_acc, quote_mint.key, None)?;
+
+    let claimed = referrer.claimable_fees;
+    if claimed == 0 {
+        msg!("No referral fees to claim");
+        return Ok(());
+    }
+
+    let seeds = &[PDA_SEED, &[bump]];
+    let signer_seeds = &[&seeds[..]];
+
+    let transfer_ix = create_transfer_checked_instruction(
+        token_program.key,
+        vault.key,
+        quote_mint.key,
+        owner_token_acc.key,
+        &pda,
+        math::from_precision(claimed, quote_decimals)?,
+        quote_decimals,
+    )?;
+
+    invoke_signed(&transfer_ix, &[
+        vault.clone(),
+        quote_mint.clone(),
+        owner_token_acc.clone(),
+        vault.clone(), // PDA authority
+        token_program.clone(),
+    ], signer_seeds)?;
+
+    referrer.claimable_fees = 0;
+    store_account(&referrer, &mut referrer_acc.data.borrow_mut())?;
+
+    msg!("Referral fees claimed: {}", claimed);
+
+    Ok(())
+}
+
+/// Check a position can be liquidated and settle its pending funding
+///
+/// `remaining_accs` are the oracles of an isolated position's collateral assets, or
+/// the user account and linked positions of a cross-margined one. Returns the loaded
+/// cross margin, if any, and the collateral ratio that made the position liquidatable.
+#[allow(clippy::too_many_arguments)]
+fn check_liquidatable<'a, 'info>(
+    program_id: &Pubkey,
+    position_key: &Pubkey,
+    position: &mut Position,
+    market_key: &Pubkey,
+    market_state: &mut MarketState,
+    config: &Config,
+    remaining_accs: &[&'a AccountInfo<'info>],
+    slot: u64,
+) -> Result<(Option<CrossMargin<'a, 'info>>, u64), ProgramError> {
+    // The position must trade in this market, or another market's mark could make it look underwater
+    validate_position_address(program_id, position_key, market_key, position)?;
+
+    // Verify position exists and has exposure
+    if position.base_amount == 0 {
+        msg!("Position has no exposure to liquidate");
+        return Err(ProgramError::InvalidArgument);
+    }
+
+    if slot < position.next_liquidation_slot {
+        msg!("Position was already partially liquidated this slot; next liquidation from slot {}",
+             position.next_liquidation_slot);
+        return Err(ProgramError::InvalidArgument);
+    }
+
+    let (mut cross_margin, oracle_accs) = if position.is_cross_margin() {
+        let (cross_margin, _) = CrossMargin::load(program_id, position_key, position, market_key, remaining_accs, false)?;
+        (Some(cross_margin), &remaining_accs[..0])
+    } else {
+        (None, remaining_accs)
+    };
+
+    // Cross-margined positions are liquidated on the health of their whole user account
+    let collateral_ratio = if let Some(cross_margin) = cross_margin.as_mut() {
+        cross_margin.check_liquidatable(position, market_state, config.min_collateral_ratio)?
+    } else {
+        let (collateral_ratio, health_factor) = isolated_liquidation_health(position, market_state, config, oracle_accs, slot)?;
+        if health_factor >= PRECISION {
+            msg!("Position is not liquidatable. Collateral ratio: {}, health factor: {}", collateral_ratio, health_factor);
+            return Err(ProgramError::InvalidArgument);
+        }
+
+        collateral_ratio
+    };
+
+    Ok((cross_margin, collateral_ratio))
+}
+
+/// Settle an isolated position's socialized loss and pending funding as a liquidation would,
+/// then measure its health with its weighted asset collateral. Returns the collateral ratio
+/// at the mark price and the health factor (see `calculate_isolated_liquidation_health`).
+pub(crate) fn isolated_liquidation_health(
+    position: &mut Position,
+    market_state: &mut MarketState,
+    config: &Config,
+    oracle_accs: &[&AccountInfo],
+    slot: u64,
+) -> Result<(u64, u64), ProgramError> {
+    settle_liquidation_funding(position, market_state)?;
+
+    let asset_collateral = calculate_weighted_asset_collateral(position, config, oracle_accs, slot)?;
+    let total_collateral = position.collateral
+        .checked_add(asset_collateral)
+        .ok_or(ProgramError::InvalidArgument)?;
+
+    calculate_isolated_liquidation_health(position, total_collateral, market_state, config.min_collateral_ratio)
+}
+
+/// Apply an isolated position's socialized loss and pending funding ahead of a liquidation
+/// check; funding it can't pay doesn't fail, that makes it more liquidatable
+pub(crate) fn settle_liquidation_funding(position: &mut Position, market_state: &mut MarketState) -> ProgramResult {
+    apply_socialized_loss(position, market_state)?;
+    let funding_payment = calculate_pending_funding(position, market_state)?;
+    if funding_payment > 0 {
+        charge_funding_shortfall(&mut position.collateral, market_state, funding_payment.unsigned_abs())?;
+    } else {
+        credit_funding(position, funding_payment.unsigned_abs())?;
+    }
+    position.last_funding_index = market_state.funding_index;
+    position.pending_funding = 0;
+
+    Ok(())
+}
+
+/// Liquidation fee on the notional a liquidation closed, funded from the position's
+/// collateral or, for a cross-margined position, its user account's, after PnL is realized
+fn calculate_position_liquidation_fee(
+    closed_notional: u64,
+    position: &Position,
+    market_state: &MarketState,
+    cross_margin: Option<&CrossMargin>,
+    config: &Config,
+) -> Result<LiquidationFee, ProgramError> {
+    let available_collateral = match cross_margin {
+        Some(cross_margin) => cross_margin.user_account.collateral,
+        None => position.collateral,
+    };
+    calculate_liquidation_fee(
+        closed_notional,
+        available_collateral,
+        market_state.insurance_fund,
+        config.liquidator_fee_bps,
+        config.insurance_fund_share,
+    )
+}
+
+/// Charge a liquidated position's collateral the liquidation fee, book the insurance fund's
+/// share and clear its entry price once it is flat. Returns the collateral left behind;
+/// isolated dust below `dust_collateral` is swept into the insurance fund.
+fn clear_liquidated_position(
+    position: &mut Position,
+    market_state: &mut MarketState,
+    cross_margin: Option<&mut CrossMargin>,
+    fee: &LiquidationFee,
+    dust_collateral: u64,
+) -> Result<u64, ProgramError> {
+    // Keep the insurance share; any reward shortfall was drawn from the fund
+    market_state.insurance_fund = market_state.insurance_fund
+        .checked_add(fee.insurance_fee)
+        .and_then(|fund| fund.checked_sub(fee.from_insurance))
+        .ok_or(ProgramError::InvalidArgument)?;
+
+    if position.base_amount == 0 {
+        position.entry_price = 0;
+        position.margin_call_slot = 0;
+    }
+    let charged = fee.collateral_charged();
+    match cross_margin {
+        Some(cross_margin) => {
+            cross_margin.user_account.collateral = cross_margin.user_account.collateral
+                .saturating_sub(charged);
+            cross_margin.store()?;
+            Ok(cross_margin.user_account.collateral)
+        }
+        None => {
+            position.collateral = position.collateral
+                .saturating_sub(charged);
+            sweep_dust_collateral(position, market_state, dust_collateral)?;
+            Ok(position.collateral)
+        }
+    }
+}
+
+/// Sweep the collateral left on an isolated position that just went flat into the insurance
+/// fund when it is below `dust_collateral`; returns the amount swept
+pub(crate) fn sweep_dust_collateral(position: &mut Position, market_state: &mut MarketState, dust_collateral: u64) -> Result<u64, ProgramError> {
+    if position.base_amount != 0 || position.is_cross_margin() {
+        return Ok(0);
+    }
+
+    let swept = if position.collateral < dust_collateral { position.collateral } else { 0 };
+    if swept > 0 {
+        market_state.insurance_fund = market_state.insurance_fund
+            .checked_add(swept)
+            .ok_or(ProgramError::InvalidArgument)?;
+        position.collateral = 0;
+        msg!("Swept {} dust collateral into the insurance fund", swept);
+    }
+
+    // A flat position left without collateral no longer counts toward the market's total
+    if position.collateral == 0 {
+        release_deposits(position, market_state);
+    }
+
+    Ok(swept)
+}
+
+/// Count `amount` of quote collateral deposited into isolated `position` toward its market's
+/// `total_collateral`, rejecting it past the market's cap
+pub(crate) fn count_deposit(position: &mut Position, market_state: &mut MarketState, amount: u64) -> ProgramResult {
+    market_state.deposit_collateral(amount)?;
+    position.deposited_collateral = position.deposited_collateral
+        .checked_add(amount)
+        .ok_or(ProgramError::InvalidArgument)?;
+
+    Ok(())
+}
+
+/// Stop counting `position`'s deposits toward its market's `total_collateral`, once its
+/// collateral has been paid out, moved to cross margin or used up by losses and fees
+pub(crate) fn release_deposits(position: &mut Position, market_state: &mut MarketState) {
+    market_state.withdraw_collateral(position.deposited_collateral);
+    position.deposited_collateral = 0;
+}
+
+/// Settle pending funding between the market's funding index and the position, after
+/// charging any socialized loss. Funding the position receives is credited in full (see
+/// `credit_funding`); funding it pays is capped at `max_funding_share` of its collateral
+/// (1e9 precision, 0 = no cap) and the rest is carried forward in `pending_funding`.
+fn apply_funding(position: &mut Position, market_state: &mut MarketState, max_funding_share: u64) -> ProgramResult {
+    apply_socialized_loss(position, market_state)?;
+
+    let funding_owed = calculate_pending_funding(position, market_state)?;
+    let funding_payment = if funding_owed > 0 && max_funding_share > 0 {
+        let cap = math::mul_scaled(position.collateral, max_funding_share)?;
+        funding_owed.min(i64::try_from(cap).unwrap_or(i64::MAX))
+    } else {
+        funding_owed
+    };
+    position.pending_funding = funding_owed - funding_payment;
+    if position.pending_funding != 0 {
+        msg!("Funding carried forward: {}", position.pending_funding);
+    }
+
+    if funding_payment != 0 {
+        if funding_payment > 0 {
+            // User owes funding → deduct from collateral
+            position.collateral = position
+                .collateral
+                .checked_sub(funding_payment.unsigned_abs())
+                .ok_or(ProgramError::InsufficientFunds)?;
+            msg!("Applied funding payment: -{}", funding_payment);
+        } else {
+            // User receives funding → claimable, or collateral when compounding
+            credit_funding(position, funding_payment.unsigned_abs())?;
+            msg!("Received funding payment: +{}", funding_payment.unsigned_abs());
+        }
+        record_funding(position, funding_payment)?;
+    }
+    position.last_funding_index = market_state.funding_index;
+
+    Ok(())
+}
+
+/// Charge the socialized loss a position owes as a realized loss and bring its loss index
+/// snapshots up to date; what its collateral can't pay is bad debt again
+fn apply_socialized_loss(position: &mut Position, market_state: &mut MarketState) -> ProgramResult {
+    let loss = calculate_pending_socialized_loss(position, market_state)?;
+    position.last_long_loss_index = market_state.long_loss_index;
+    position.last_short_loss_index = market_state.short_loss_index;
+    if loss > 0 {
+        msg!("Socialized loss charged: {}", loss);
+        settle_realized_pnl(position, market_state, -i64::try_from(loss).map_err(|_| ProgramError::InvalidArgument)?)?;
+    }
+
+    Ok(())
+}
+
+/// Credit `amount` of funding the position received to its claimable balance, or to its
+/// collateral while it auto-compounds funding
+fn credit_funding(position: &mut Position, amount: u64) -> ProgramResult {
+    let balance = if position.auto_compound_funding != 0 {
+        &mut position.collateral
+    } else {
+        &mut position.claimable_funding
+    };
+    *balance = balance.checked_add(amount).ok_or(ProgramError::InvalidArgument)?;
+
+    Ok(())
+}
+
+/// Add a funding payment (positive = paid by the position) to its lifetime funding total
+fn record_funding(position: &mut Position, funding_payment: i64) -> ProgramResult {
+    position.cumulative_funding = position.cumulative_funding
+        .checked_sub(funding_payment)
+        .ok_or(ProgramError::InvalidArgument)?;
+
+    Ok(())
+}
+
+/// Apply a filled size change to the position and the market's open interest,
+/// realizing the PnL of any part of the position it closes
+fn apply_position_change(
+    position: &mut Position,
+    market_state: &mut MarketState,
+    base_delta: i64,
+    fill_price: u64,
+) -> ProgramResult {
+    let realized_pnl = calculate_realized_pnl(position.base_amount, position.entry_price, base_delta, fill_price)?;
+    settle_realized_pnl(position, market_state, realized_pnl)?;
+    track_exposure(market_state, position, false)?;
+
+    let old_base_amount = position.base_amount;
+    position.base_amount = position
+        .base_amount
+        .checked_add(base_delta)
+        .ok_or(ProgramError::InvalidArgument)?;
+
+    // New positions and the remainder of a flip enter at the fill; increases average into the
+    // existing entry; reductions keep it, their PnL having been realized above
+    let flipped = position.base_amount != 0 && (old_base_amount > 0) != (position.base_amount > 0);
+    if old_base_amount == 0 || flipped {
+        position.entry_price = fill_price;
+    } else if (old_base_amount > 0) == (base_delta > 0) {
+        position.entry_price = calculate_average_entry_price(old_base_amount, position.entry_price, base_delta, fill_price)?;
+    }
+    track_exposure(market_state, position, true)?;
+
+    // Update open interest
+    let old_oi_contribution = old_base_amount.unsigned_abs();
+    let new_oi_contribution = position.base_amount.unsigned_abs();
+    
+    let new_open_interest = market_state
+        .open_interest
+        .checked_sub(old_oi_contribution)
+        .ok_or(ProgramError::InvalidArgument)?
+        .checked_add(new_oi_contribution)
+        .ok_or(ProgramError::InvalidArgument)?;
+
+    if exceeds_open_interest_cap(market_state.open_interest, new_open_interest, market_state.max_open_interest) {
+        msg!("Open interest cap exceeded: {} > {}", new_open_interest, market_state.max_open_interest);
+        return Err(ProgramError::InvalidArgument);
+    }
+    market_state.open_interest = new_open_interest;
+
+    // Update each side's open interest
+    let (long_removed, short_removed) = split_open_interest(old_base_amount);
+    let (long_added, short_added) = split_open_interest(position.base_amount);
+    market_state.long_open_interest = market_state.long_open_interest
+        .checked_sub(long_removed)
+        .and_then(|oi| oi.checked_add(long_added))
+        .ok_or(ProgramError::InvalidArgument)?;
+    market_state.short_open_interest = market_state.short_open_interest
+        .checked_sub(short_removed)
+        .and_then(|oi| oi.checked_add(short_added))
+        .ok_or(ProgramError::InvalidArgument)?;
+
+    Ok(())
+}
+
+/// A position's contribution to the (long, short) open interest
+fn split_open_interest(base_amount: i64) -> (u64, u64) {
+    if base_amount > 0 {
+        (base_amount.unsigned_abs(), 0)
+    } else {
+        (0, base_amount.unsigned_abs())
+    }
+}
+
+/// Add (or remove) a position's exposure to the market's aggregates used to value
+/// traders' unrealized PnL as a whole
+fn track_exposure(market_state: &mut MarketState, position: &Position, add: bool) -> ProgramResult {
+    let entry_quote = math::mul_scaled_signed(position.base_amount.into(), position.entry_price.into())?;
+    let (base, entry_quote) = if add {
+        (position.base_amount, entry_quote)
+    } else {
+        (-position.base_amount, -entry_quote)
+    };
+
+    market_state.net_base_amount = market_state.net_base_amount
+        .checked_add(base)
+        .ok_or(ProgramError::InvalidArgument)?;
+    market_state.net_entry_quote = market_state.net_entry_quote
+        .checked_add(entry_quote)
+        .ok_or(ProgramError::InvalidArgument)?;
+    if position.base_amount > 0 {
+        market_state.long_base_amount = market_state.long_base_amount
+            .checked_add(base)
+            .ok_or(ProgramError::InvalidArgument)?;
+        market_state.long_entry_quote = market_state.long_entry_quote
+            .checked_add(entry_quote)
+            .ok_or(ProgramError::InvalidArgument)?;
+    }
+
+    Ok(())
+}
+
+/// Move realized PnL between the vault and the position's collateral. Losses beyond
+/// the collateral are recorded as the market's bad debt; what the insurance fund covers
+/// of it reaches the vault as a collected loss.
+fn settle_realized_pnl(position: &mut Position, market_state: &mut MarketState, pnl: i64) -> ProgramResult {
+    position.realized_pnl = position.realized_pnl
+        .checked_add(pnl)
+        .ok_or(ProgramError::InvalidArgument)?;
+
+    if pnl > 0 {
+        position.collateral = position.collateral
+            .checked_add(pnl as u64)
+            .ok_or(ProgramError::InvalidArgument)?;
+        market_state.total_realized_profit = market_state.total_realized_profit
+            .checked_add(pnl as u64)
+            .ok_or(ProgramError::InvalidArgument)?;
+        msg!("Realized profit: +{}", pnl);
+    } else if pnl < 0 {
+        let loss = pnl.unsigned_abs();
+        let collected = loss.min(position.collateral);
+        position.collateral -= collected;
+        let covered = record_bad_debt(market_state, loss - collected)?;
+        market_state.total_realized_loss = market_state.total_realized_loss
+            .checked_add(collected + covered)
+            .ok_or(ProgramError::InvalidArgument)?;
+        msg!("Realized loss: -{} (uncollected {})", loss, loss - collected);
+    }
+
+    Ok(())
+}
+
+/// Book a loss the trader's collateral could not cover. The insurance fund pays off what
+/// it can and the rest is added to `outstanding_bad_debt`. Returns the amount covered.
+fn record_bad_debt(market_state: &mut MarketState, shortfall: u64) -> Result<u64, ProgramError> {
+    if shortfall == 0 {
+        return Ok(0);
+    }
+
+    let covered = shortfall.min(market_state.insurance_fund);
+    market_state.insurance_fund -= covered;
+    market_state.bad_debt = market_state.bad_debt
+        .checked_add(shortfall)
+        .ok_or(ProgramError::InvalidArgument)?;
+    market_state.outstanding_bad_debt = market_state.outstanding_bad_debt
+        .checked_add(shortfall - covered)
+        .ok_or(ProgramError::InvalidArgument)?;
+    msg!("Bad debt incurred: {} ({} covered by the insurance fund)", shortfall, covered);
+
+    Ok(covered)
+}
+
+/// Charge funding owed by a position whose collateral may not cover it, booking the
+/// shortfall as bad debt
+fn charge_funding_shortfall(collateral: &mut u64, market_state: &mut MarketState, funding_owed: u64) -> ProgramResult {
+    let collected = funding_owed.min(*collateral);
+    *collateral -= collected;
+    record_bad_debt(market_state, funding_owed - collected)?;
+
+    Ok(())
+}
+
+/// Log a BadDebtIncurred event for the bad debt `position_key` left in the market since its
+/// cumulative `bad_debt` was `bad_debt_before`
+fn emit_bad_debt(market_key: &Pubkey, position_key: &Pubkey, market_state: &MarketState, bad_debt_before: u64) {
+    let amount = market_state.bad_debt.saturating_sub(bad_debt_before);
+    if amount > 0 {
+        events::emit(&events::BadDebtIncurred {
+            market: *market_key,
+            position: *position_key,
+            amount,
+            insurance_fund: market_state.insurance_fund,
+            outstanding_bad_debt: market_state.outstanding_bad_debt,
+        });
+    }
+}
+
+/// Reject a position whose collateral ratio is below what its size requires: `min_collateral_ratio`,
+/// or the market's risk tier for its notional at the mark price when that is higher
+///
+/// The ratio is measured as liquidate measures it, on collateral marked to market with the
+/// position's unrealized PnL and net of the funding it has pending, so an underwater position
+/// can't add size against collateral it has already lost. Funding it's owed only counts when
+/// it compounds into the collateral; otherwise it's paid out as claimable funding.
+fn validate_collateral_ratio(position: &Position, market_state: &MarketState, min_collateral_ratio: u64) -> ProgramResult {
+    if position.base_amount == 0 {
+        return Ok(());
+    }
+
+    let pending_funding = calculate_pending_funding(position, market_state)?;
+    let collateral = if pending_funding > 0 {
+        position.collateral.saturating_sub(pending_funding.unsigned_abs())
+    } else if position.auto_compound_funding != 0 {
+        position.collateral
+            .checked_add(pending_funding.unsigned_abs())
+            .ok_or(ProgramError::InvalidArgument)?
+    } else {
+        position.collateral
+    };
+    let (collateral_ratio, required_ratio) = calculate_isolated_collateral_ratios(
+        position,
+        collateral,
+        market_state,
+        min_collateral_ratio,
+        market_state.mark_price,
+    )?;
+    if collateral_ratio < required_ratio {
+        msg!("Insufficient collateral ratio: {} < {}", collateral_ratio, required_ratio);
+        return Err(ProgramError::InsufficientFunds);
+    }
+
+    msg!("Collateral ratio: {}", collateral_ratio);
+
+    Ok(())
+}
+
+/// Reject a position, or cross-margin account, that grew past `max_user_notional` (0 = no limit)
+fn validate_user_notional(notional: u64, max_user_notional: u64) -> ProgramResult {
+    if max_user_notional != 0 && notional > max_user_notional {
+        msg!("Notional {} exceeds the per-account cap {}", notional, max_user_notional);
+        return Err(ProgramError::InvalidArgument);
+    }
+
+    Ok(())
+}
+
+/// Reject a position whose leverage exceeds `max_leverage`: the market's, or the owner's override
+fn validate_leverage(position: &Position, market_state: &MarketState, max_leverage: u64) -> ProgramResult {
+    let leverage = calculate_leverage(position, market_state.mark_price)?;
+    if leverage > max_leverage {
+        msg!("Leverage too high: {} > {}", leverage, max_leverage);
+        return Err(ProgramError::InsufficientFunds);
+    }
+
+    Ok(())
+}
+
+/// Reject trading, funding and liquidation in a market that has been settled
+fn require_active(market_state: &MarketState) -> ProgramResult {
+    if market_state.is_settled() {
+        msg!("Market is settled at {}: positions can only be closed with settle_position",
+             market_state.settlement_price);
+        return Err(ProgramError::InvalidArgument);
+    }
+
+    Ok(())
+}
+
+/// Reject instructions that open or grow exposure, or add funds, while the program is settlement-only
+fn require_not_settlement_only(config: &Config) -> ProgramResult {
+    if config.settlement_only {
+        msg!("Program is in settlement-only mode: only closes, withdrawals and liquidations are processed");
+        return Err(ProgramError::InvalidArgument);
+    }
+
+    Ok(())
+}
+
+/// Reject cross-margined positions in paths that only margin a position's own collateral
+fn require_isolated(position: &Position) -> ProgramResult {
+    if position.is_cross_margin() {
+        msg!("Not supported for cross-margined positions; switch to isolated margin first");
+        return Err(ProgramError::InvalidArgument);
+    }
+
+    Ok(())
+}
+
+/// Reject an owner-only instruction unless `owner` owns the position outright
+pub(crate) fn require_position_owner(position: &Position, owner: &Pubkey) -> ProgramResult {
+    if position.is_tokenized() {
+        msg!("Position is tokenized: only the holder of {} acts for it", position.position_mint);
+        return Err(ProgramError::IllegalOwner);
+    }
+    if position.owner != *owner {
+        msg!("Position owner mismatch. Expected: {}, Got: {}", owner, position.owner);
+        return Err(ProgramError::IllegalOwner);
+    }
+
+    Ok(())
+}
+
+/// Reject reducing, closing or withdrawing from a position in the slot it last grew in or
+/// within the market's min_hold_slots after, so a trade can't be opened and unwound around
+/// a mark price it moved within one slot
+pub(crate) fn require_hold_elapsed(position: &Position, market_state: &MarketState, slot: u64) -> ProgramResult {
+    let unlock_slot = position.last_modified_slot.saturating_add(market_state.min_hold_slots.max(1));
+    if slot < unlock_slot {
+        msg!("Position grew at slot {} and is held until slot {} (current slot {})",
+             position.last_modified_slot, unlock_slot, slot);
+        return Err(ProgramError::InvalidArgument);
+    }
+
+    Ok(())
+}
+
+/// Median index price of the market's index oracle and backups, whose accounts lead
+/// `oracle_accs` in `index_oracles` order; returns it with the accounts after them
+fn read_index_price<'a, 'b, 'info>(
+    market_state: &MarketState,
+    oracle_accs: &'a [&'b AccountInfo<'info>],
+    slot: u64,
+) -> Result<(oracle::OraclePrice, &'a [&'b AccountInfo<'info>]), ProgramError> {
+    let oracles = market_state.index_oracles();
+    let index = oracle::read_median_price(oracle_accs, &oracles, slot)?;
+    Ok((index, &oracle_accs[oracles.len()..]))
+}
+
+/// Feed `index_price` to the market's circuit breaker, logging an event if it trips
+fn observe_circuit_breaker(market_key: &Pubkey, market_state: &mut MarketState, index_price: u64, slot: u64) {
+    let reference_price = market_state.breaker_reference_price;
+    if let Some(price_move) = market_state.observe_index_price(index_price, slot) {
+        msg!("Circuit breaker tripped: index moved {} from {} to {}; reduce-only until slot {}",
+             price_move, reference_price, index_price, market_state.reduce_only_until_slot);
+        events::emit(&events::CircuitBreakerTripped {
+            market: *market_key,
+            reference_price,
+            index_price,
+            price_move,
+            reduce_only_until_slot: market_state.reduce_only_until_slot,
+        });
+    }
+}
+
+/// Trade `base_delta` against the market's vAMM, moving its reserves and mark price
+fn execute_vamm_trade(market_state: &mut MarketState, base_delta: i64) -> Result<VammFill, ProgramError> {
+    // The EMA weighs the mark by how long it held, so it is sampled before the trade moves it
+    market_state.update_mark_price_ema(Clock::get()?.slot)?;
+    let price_before = market_state.mark_price;
+    let fill = calculate_market_fill(market_state, base_delta)?;
+
+    market_state.base_reserve = fill.new_base_reserve;
+    market_state.quote_reserve = fill.new_quote_reserve;
+    market_state.mark_price = calculate_vamm_price(fill.new_base_reserve, fill.new_quote_reserve)?;
+
+    msg!("vAMM fill: size={}, price={}, slippage={}, price_impact={}, new_mark_price={}",
+         base_delta, fill.fill_price, calculate_slippage(price_before, fill.fill_price)?,
+         fill.price_impact, market_state.mark_price);
+
+    Ok(fill)
+}
+
+/// Log a VammTrade event for a fill `position_key` took from the market's vAMM
+fn emit_vamm_trade(market_key: &Pubkey, position_key: &Pubkey, base_delta: i64, fill: &VammFill, market_state: &MarketState) {
+    events::emit(&events::VammTrade {
+        market: *market_key,
+        position: *position_key,
+        base_delta,
+        fill_price: fill.fill_price,
+        price_impact: fill.price_impact,
+        mark_price: market_state.mark_price,
+    });
+}
+
+// ---------------------------------------------------------------------
+// 1️⃣2️⃣ Initialize the global config (once)
+// ---------------------------------------------------------------------
+pub fn initialize_config(program_id: &Pubkey, accounts: &[AccountInfo], admin: Option<Pubkey>) -> ProgramResult {
+    // Accounts:
+    // 0. [signer, writable] payer (becomes the admin unless one is given)
+    // 1. [writable] config account (PDA: [CONFIG_SEED])
+    // 2. [] rent sysvar
+    // 3. [] system program
+    // 4. [] quote mint (its owner becomes the configured token program)
+    // 5. [] the program's ProgramData account (its upgrade authority must be the payer)
+    let accounts_iter = &mut accounts.iter();
+    let payer = next_account_info(accounts_iter)?;
+    let config_acc = next_account_info(accounts_iter)?;
+    let rent_sysvar = next_account_info(accounts_iter)?;
+    let system_program = next_account_info(accounts_iter)?;
+    let quote_mint = next_account_info(accounts_iter)?;
+    let program_data = next_account_info(accounts_iter)?;
+
+    if !payer.is_signer {
+        msg!("Payer must be signer");
+        return Err(ProgramError::MissingRequiredSignature);
+    }
+
+    // Only whoever can upgrade the program can create its config, so no one can front-run
+    // the deployment and name themselves admin
+    require_upgrade_authority(program_id, program_data, payer.key)?;
+
+    read_mint_decimals(quote_mint)?;
+
+    // The admin defaults to the payer
+    let admin = admin.unwrap_or(*payer.key);
+
+    let (expected_config, bump) = Pubkey::find_program_address(&[CONFIG_SEED], program_id);
+    if *config_acc.key != expected_config {
+        msg!("Config account is not the correct PDA. Expected: {}, Got: {}",
+             expected_config, config_acc.key);
+        return Err(ProgramError::InvalidArgument);
+    }
+
+    if !config_acc.data_is_empty() {
+        msg!("Config already initialized");
+        return Err(ProgramError::AccountAlreadyInitialized);
+    }
+
+    let rent = Rent::from_account_info(rent_sysvar)?;
+    let create_config_ix = system_instruction::create_account(
+        payer.key,
+        config_acc.key,
+        rent.minimum_balance(Config::SPACE),
+        Config::SPACE as u64,
+        program_id,
+    );
+
+    invoke_signed(&create_config_ix, &[
+        payer.clone(),
+        config_acc.clone(),
+        system_program.clone(),
+    ], &[&[CONFIG_SEED, &[bump]]])?;
+
+    let config = Config {
+        admin,
+        pending_admin: Pubkey::default(),
+        min_collateral_ratio: DEFAULT_MIN_COLLATERAL_RATIO,
+        liquidator_fee_bps: DEFAULT_LIQUIDATOR_FEE_BPS,
+        trading_fee: DEFAULT_TRADING_FEE,
+        referral_fee_share: DEFAULT_REFERRAL_FEE_SHARE,
+        max_funding_rate_per_slot: DEFAULT_MAX_FUNDING_RATE_PER_SLOT,
+        bump,
+        collateral_assets: Vec::new(),
+        token_program: *quote_mint.owner,
+        quote_mint: *quote_mint.key,
+        funding_crank_reward: DEFAULT_FUNDING_CRANK_REWARD,
+        max_funding_crank_reward: DEFAULT_MAX_FUNDING_CRANK_REWARD,
+        insurance_fund_share: DEFAULT_INSURANCE_FUND_SHARE,
+        min_position_size: DEFAULT_MIN_POSITION_SIZE,
+        dust_collateral: DEFAULT_DUST_COLLATERAL,
+        vault_bump: Pubkey::find_program_address(&[PDA_SEED], program_id).1,
+        max_funding_settlement_share: DEFAULT_MAX_FUNDING_SETTLEMENT_SHARE,
+        maker_rebate: DEFAULT_MAKER_REBATE,
+        fee_tiers: Vec::new(),
+        stake_mint: Pubkey::default(),
+        stake_vault: Pubkey::default(),
+        stake_discounts: Vec::new(),
+        max_positions_per_user: 0,
+        max_user_notional: 0,
+        restrict_liquidators: false,
+        treasury: Pubkey::default(),
+        market_count: 0,
+        settlement_only: false,
+    };
+    store_account(&config, &mut config_acc.data.borrow_mut())?;
+
+    msg!("Initialized config with admin: {}, quote mint: {} (token program {})",
+         admin, quote_mint.key, quote_mint.owner);
+
+    Ok(())
+}
+
+// ---------------------------------------------------------------------
+// 1️⃣3️⃣ Update the global risk parameters (admin only)
+// ---------------------------------------------------------------------
+#[allow(clippy::too_many_arguments)]
+pub fn update_params(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    min_collateral_ratio: u64,
+    liquidator_fee_bps: u64,
+    trading_fee: u64,
+    referral_fee_share: u64,
+    max_funding_rate_per_slot: u64,
+    crank_reward: Option<(u64, u64)>,
+    insurance_fund_share: Option<u64>,
+    dust_thresholds: Option<(u64, u64)>,
+    max_funding_settlement_share: Option<u64>,
+    maker_rebate: Option<u64>,
+    user_limits: Option<(u16, u64)>,
+) -> ProgramResult {
+    // Accounts:
+    // 0. [signer] admin
+    // 1. [writable] config account
+    //
+    // The config is shared by every market, so its amounts are taken in 1e9 precision
+    // rather than any one market's units
+    let accounts_iter = &mut accounts.iter();
+    let admin = next_account_info(accounts_iter)?;
+    let config_acc = next_account_info(accounts_iter)?;
+
+    let mut config = load_admin_config(program_id, admin, config_acc)?;
+
+    if min_collateral_ratio == 0 {
+        msg!("Collateral ratio must be non-zero");
+        return Err(ProgramError::InvalidArgument);
+    }
+    if liquidator_fee_bps > BPS_DENOMINATOR
+        || referral_fee_share > PRECISION
+        || trading_fee > PRECISION
+        || insurance_fund_share.is_some_and(|share| share > PRECISION)
+        || max_funding_settlement_share.is_some_and(|share| share > PRECISION)
+    {
+        msg!("Liquidation fee, trading fee and shares must not exceed 100%");
+        return Err(ProgramError::InvalidArgument);
+    }
+
+    config.min_collateral_ratio = min_collateral_ratio;
+    config.liquidator_fee_bps = liquidator_fee_bps;
+    config.trading_fee = trading_fee;
+    if let Some(maker_rebate) = maker_rebate {
+        config.maker_rebate = maker_rebate;
+    }
+    // Rebates are paid out of the taker's fee
+    if config.maker_rebate > config.trading_fee {
+        msg!("Maker rebate {} exceeds the trading fee {}", config.maker_rebate, trading_fee);
+        return Err(ProgramError::InvalidArgument);
+    }
+    config.referral_fee_share = referral_fee_share;
+    config.max_funding_rate_per_slot = max_funding_rate_per_slot;
+    if let Some((funding_crank_reward, max_funding_crank_reward)) = crank_reward {
+        config.funding_crank_reward = funding_crank_reward;
+        config.max_funding_crank_reward = max_funding_crank_reward;
+    }
+    if let Some(insurance_fund_share) = insurance_fund_share {
+        config.insurance_fund_share = insurance_fund_share;
+    }
+    if let Some((min_position_size, dust_collateral)) = dust_thresholds {
+        config.min_position_size = min_position_size;
+        config.dust_collateral = dust_collateral;
+    }
+    if let Some(max_funding_settlement_share) = max_funding_settlement_share {
+        config.max_funding_settlement_share = max_funding_settlement_share;
+    }
+    if let Some((max_positions_per_user, max_user_notional)) = user_limits {
+        config.max_positions_per_user = max_positions_per_user;
+        config.max_user_notional = max_user_notional;
+    }
+
+    // Persist changes
+    store_account(&config, &mut config_acc.data.borrow_mut())?;
+
+    msg!("Params updated: min_collateral_ratio={}, liquidator_fee_bps={}, trading_fee={}, referral_fee_share={}, max_funding_rate_per_slot={}",
+         min_collateral_ratio, liquidator_fee_bps, trading_fee, referral_fee_share, max_funding_rate_per_slot);
+    if let Some((funding_crank_reward, max_funding_crank_reward)) = crank_reward {
+        msg!("Crank reward updated: per_period={}, max={}", funding_crank_reward, max_funding_crank_reward);
+    }
+    if let Some(insurance_fund_share) = insurance_fund_share {
+        msg!("Insurance fund share updated: {}", insurance_fund_share);
+    }
+    if let Some((min_position_size, dust_collateral)) = dust_thresholds {
+        msg!("Dust thresholds updated: min_position_size={}, dust_collateral={}", min_position_size, dust_collateral);
+    }
+    if let Some(max_funding_settlement_share) = max_funding_settlement_share {
+        msg!("Max funding settlement share updated: {}", max_funding_settlement_share);
+    }
+    if let Some(maker_rebate) = maker_rebate {
+        msg!("Maker rebate updated: {}", maker_rebate);
+    }
+    if let Some((max_positions_per_user, max_user_notional)) = user_limits {
+        msg!("User limits updated: max_positions_per_user={}, max_user_notional={}", max_positions_per_user, max_user_notional);
+    }
+
+    Ok(())
+}
+
+// ---------------------------------------------------------------------
+// 9️⃣4️⃣ Update a market's risk limits (admin only)
+// ---------------------------------------------------------------------
+pub fn update_market_params(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    max_leverage: u64,
+    max_open_interest: u64,
+    price_impact_depth: Option<u64>,
+    skew_fee: Option<u64>,
+) -> ProgramResult {
+    // Accounts:
+    // 0. [signer] admin
+    // 1. [] config account
+    // 2. [writable] market state account
+    let accounts_iter = &mut accounts.iter();
+    let admin = next_account_info(accounts_iter)?;
+    let config_acc = next_account_info(accounts_iter)?;
+    let market_state_acc = next_account_info(accounts_iter)?;
+
+    load_admin_config(program_id, admin, config_acc)?;
+
+    if market_state_acc.owner != program_id {
+        msg!("Market state is not owned by the program");
+        return Err(ProgramError::IncorrectProgramId);
+    }
+
+    if max_leverage == 0 {
+        msg!("Leverage limit must be non-zero");
+        return Err(ProgramError::InvalidArgument);
+    }
+    if skew_fee.is_some_and(|fee| fee > PRECISION) {
+        msg!("Skew fee must not exceed 100%");
+        return Err(ProgramError::InvalidArgument);
+    }
+
+    let mut market_state_data = market_state_acc.try_borrow_mut_data()?;
+    let market_state = MarketState::load_mut(&mut market_state_data)?;
+
+    market_state.max_leverage = max_leverage;
+    market_state.max_open_interest = math::to_precision(max_open_interest, market_state.base_decimals)?;
+    if let Some(price_impact_depth) = price_impact_depth {
+        market_state.price_impact_depth = math::to_precision(price_impact_depth, market_state.base_decimals)?;
+    }
+    if let Some(skew_fee) = skew_fee {
+        market_state.skew_fee = skew_fee;
+    }
+
+    msg!("Market params updated: max_leverage={}, max_open_interest={}", max_leverage, max_open_interest);
+    if let Some(price_impact_depth) = price_impact_depth {
+        msg!("Price impact depth updated: {}", price_impact_depth);
+    }
+    if let Some(skew_fee) = skew_fee {
+        msg!("Skew fee updated: {}", skew_fee);
+    }
+
+    Ok(())
+}
+
+// ---------------------------------------------------------------------
+// 1️⃣4️⃣ / 1️⃣5️⃣ Pause or resume a market (admin only)
+// ---------------------------------------------------------------------
+pub fn set_market_paused(program_id: &Pubkey, accounts: &[AccountInfo], paused: bool) -> ProgramResult {
+    // Accounts:
+    // 0. [signer] admin
+    // 1. [] config account
+    // 2. [writable] market state account
+    let accounts_iter = &mut accounts.iter();
+    let admin = next_account_info(accounts_iter)?;
+    let config_acc = next_account_info(accounts_iter)?;
+    let market_state_acc = next_account_info(accounts_iter)?;
+
+    load_admin_config(program_id, admin, config_acc)?;
+
+    if market_state_acc.owner != program_id {
+        msg!("Market state is not owned by the program");
+        return Err(ProgramError::IncorrectProgramId);
+    }
+
+    let mut market_state_data = market_state_acc.try_borrow_mut_data()?;
+    let market_state = MarketState::load_mut(&mut market_state_data)?;
+    if market_state.is_paused() == paused {
+        msg!("Market already {}", if paused { "paused" } else { "active" });
+        return Err(ProgramError::InvalidArgument);
+    }
+
+    market_state.paused = u8::from(paused);
+
+    msg!("Market {}", if paused { "paused" } else { "resumed" });
+
+    Ok(())
+}
+
+// ---------------------------------------------------------------------
+// 1️⃣6️⃣ Propose a new admin (admin only)
+// ---------------------------------------------------------------------
+pub fn set_pending_admin(program_id: &Pubkey, accounts: &[AccountInfo], pending_admin: Pubkey) -> ProgramResult {
+    // Accounts:
+    // 0. [signer] current admin
+    // 1. [writable] config account
+    let accounts_iter = &mut accounts.iter();
+    let admin = next_account_info(accounts_iter)?;
+    let config_acc = next_account_info(accounts_iter)?;
+
+    let mut config = load_admin_config(program_id, admin, config_acc)?;
+
+    config.pending_admin = pending_admin;
+    store_account(&config, &mut config_acc.data.borrow_mut())?;
+
+    if pending_admin == Pubkey::default() {
+        msg!("Pending admin cleared");
+    } else {
+        msg!("Pending admin set: {}", pending_admin);
+    }
+
+    Ok(())
+}
+
+// ---------------------------------------------------------------------
+// 1️⃣7️⃣ Accept a pending admin transfer
+// ---------------------------------------------------------------------
+pub fn accept_admin(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramResult {
+    // Accounts:
+    // 0. [signer] pending admin
+    // 1. [writable] config account
+    let accounts_iter = &mut accounts.iter();
+    let new_admin = next_account_info(accounts_iter)?;
+    let config_acc = next_account_info(accounts_iter)?;
+
+    if !new_admin.is_signer {
+        msg!("Pending admin must be signer");
+        return Err(ProgramError::MissingRequiredSignature);
+    }
+
+    let mut config = load_config(program_id, config_acc)?;
+    if config.pending_admin == Pubkey::default() || config.pending_admin != *new_admin.key {
+        msg!("Signer is not the pending admin. Expected: {}, Got: {}",
+             config.pending_admin, new_admin.key);
+        return Err(ProgramError::IllegalOwner);
+    }
+
+    let previous_admin = config.admin;
+    config.admin = config.pending_admin;
+    config.pending_admin = Pubkey::default();
+    store_account(&config, &mut config_acc.data.borrow_mut())?;
+
+    msg!("Admin transferred: {} -> {}", previous_admin, config.admin);
+
+    Ok(())
+}
+
+// ---------------------------------------------------------------------
+// 1️⃣8️⃣ Upgrade a position or market state account to the current layout
+// ---------------------------------------------------------------------
+pub fn migrate_account(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramResult {
+    // Accounts:
+    // 0. [signer, writable] payer (funds any extra rent)
+    // 1. [writable] position, market state, config, LP pool, user stats or trigger order account to upgrade
+    // 2. [] rent sysvar
+    // 3. [] system program
+    // 4. [] config account (market states predating the recorded quote mint only), or the
+    //    position's market state account (positions predating loss socialization only)
+    let accounts_iter = &mut accounts.iter();
+    let payer = next_account_info(accounts_iter)?;
+    let account = next_account_info(accounts_iter)?;
+    let rent_sysvar = next_account_info(accounts_iter)?;
+    let system_program = next_account_info(accounts_iter)?;
+    let lookup_acc = next_account_info(accounts_iter).ok();
+
+    if !payer.is_signer {
+        msg!("Payer must be signer");
+        return Err(ProgramError::MissingRequiredSignature);
+    }
+
+    if account.owner != program_id {
+        msg!("Account is not owned by the program");
+        return Err(ProgramError::IncorrectProgramId);
+    }
+
+    let tag = account.data.borrow()
+        .get(..DISCRIMINATOR_LEN)
+        .ok_or(ProgramError::InvalidAccountData)?
+        .to_vec();
+    let is_position = tag == Position::DISCRIMINATOR;
+    let is_config = tag == Config::DISCRIMINATOR;
+    let is_pool = tag == liquidity_pool::PoolState::DISCRIMINATOR;
+    let is_user_stats = tag == user_stats::UserStats::DISCRIMINATOR;
+    let is_trigger = tag == trigger_orders::TriggerOrder::DISCRIMINATOR;
+    let space = if is_position {
+        Position::SPACE
+    } else if tag == MarketState::DISCRIMINATOR {
+        MarketState::SPACE
+    } else if is_config {
+        Config::SPACE
+    } else if is_pool {
+        liquidity_pool::PoolState::SPACE
+    } else if is_user_stats {
+        user_stats::UserStats::SPACE
+    } else if is_trigger {
+        trigger_orders::TriggerOrder::SPACE
+    } else {
+        msg!("Only position, market state, config, LP pool, user stats and trigger order accounts can be migrated");
+        return Err(ProgramError::InvalidAccountData);
+    };
+
+    // Config, pools, user stats and trigger orders are Borsh-encoded and unversioned: growing
+    // them is the whole migration
+    if (is_config || is_pool || is_user_stats || is_trigger) && account.data_len() >= space {
+        msg!("Account already at the current layout");
+        return Err(ProgramError::InvalidArgument);
+    }
+
+    // Grow older, shorter layouts; the new bytes are zeroed so added fields start at 0
+    if account.data_len() < space {
+        let rent = Rent::from_account_info(rent_sysvar)?;
+        let top_up = rent.minimum_balance(space).saturating_sub(account.lamports());
+        if top_up > 0 {
+            invoke(&system_instruction::transfer(payer.key, account.key, top_up), &[
+                payer.clone(),
+                account.clone(),
+                system_program.clone(),
+            ])?;
+        }
+        account.realloc(space, true)?;
+        msg!("Reallocated account to {} bytes", space);
+    }
+
+    if is_config {
+        // Zeroed tail bytes decode as an empty collateral asset list; the vault bump is
+        // looked up once here so no later instruction has to
+        let mut config = load_account::<Config>(&account.data.borrow())?;
+        config.vault_bump = Pubkey::find_program_address(&[PDA_SEED], program_id).1;
+        store_account(&config, &mut account.data.borrow_mut())?;
+        msg!("Migrated config {} to {} bytes", account.key, space);
+        return Ok(());
+    }
+
+    if is_pool {
+        // Pools predating withdrawal limits get the defaults rather than a zero cap
+        let mut pool = load_account::<liquidity_pool::PoolState>(&account.data.borrow())?;
+        pool.withdrawal_cooldown_slots = liquidity_pool::DEFAULT_WITHDRAWAL_COOLDOWN_SLOTS;
+        pool.max_epoch_withdrawal_share = liquidity_pool::DEFAULT_MAX_EPOCH_WITHDRAWAL_SHARE;
+        store_account(&pool, &mut account.data.borrow_mut())?;
+        msg!("Migrated LP pool {} to {} bytes", account.key, space);
+        return Ok(());
+    }
+
+    if is_user_stats {
+        // Zeroed tail bytes start the rolling volume windows empty
+        msg!("Migrated user stats {} to {} bytes", account.key, space);
+        return Ok(());
+    }
+
+    if is_trigger {
+        // Zeroed tail bytes leave the trigger unlinked
+        msg!("Migrated trigger order {} to {} bytes", account.key, space);
+        return Ok(());
+    }
+
+    let mut data = account.try_borrow_mut_data()?;
+    let (from_version, to_version) = if is_position {
+        let position = load_pod_mut::<Position>(&mut data)?;
+        let from_version = position.version;
+        // Positions predating the stored bump look it up once, here, and positions predating
+        // loss socialization start from the market's current loss indices
+        if from_version < 7 {
+            let Some(market_state_acc) = lookup_acc else {
+                msg!("Migrating a position needs its market state account");
+                return Err(ProgramError::NotEnoughAccountKeys);
+            };
+            let (expected_position, bump) =
+                position_address(program_id, market_state_acc.key, &position.owner, position.sub_account_id);
+            if *account.key != expected_position {
+                msg!("Position does not belong to market {}", market_state_acc.key);
+                return Err(ProgramError::InvalidArgument);
+            }
+            if from_version < 5 {
+                position.bump = bump;
+            }
+            let market_state_data = market_state_acc.try_borrow_data()?;
+            let market_state = MarketState::load(&market_state_data)?;
+            position.last_long_loss_index = market_state.long_loss_index;
+            position.last_short_loss_index = market_state.short_loss_index;
+        }
+        // Positions predating per-position deposits count the collateral they hold, which the
+        // market's total counted when it was deposited
+        if from_version < 15 && !position.is_cross_margin() {
+            position.deposited_collateral = position.collateral;
+        }
+        position.version = Position::VERSION;
+        (from_version, Position::VERSION)
+    } else {
+        let market_state = load_pod_mut::<MarketState>(&mut data)?;
+        let from_version = market_state.version;
+        // Markets predating per-market decimals traded in 1e9 units of a 9-decimal quote mint
+        if from_version < 9 {
+            market_state.base_decimals = math::PRECISION_DECIMALS;
+            market_state.quote_decimals = math::PRECISION_DECIMALS;
+        }
+        // Markets predating the recorded quote mint all used the global one
+        if from_version < 10 {
+            let Some(config_acc) = lookup_acc else {
+                msg!("Migrating a market state needs the config account for its quote mint");
+                return Err(ProgramError::NotEnoughAccountKeys);
+            };
+            let config = load_config(program_id, config_acc)?;
+            if config.quote_mint == Pubkey::default() {
+                msg!("Quote mint not configured: run set_quote_mint");
+                return Err(ProgramError::UninitializedAccount);
+            }
+            market_state.quote_mint = config.quote_mint;
+        }
+        // Bad debt recorded before the insurance fund covered any of it is all outstanding
+        if from_version < 11 {
+            market_state.outstanding_bad_debt = market_state.bad_debt;
+        }
+        // Long exposure is split out of the net aggregates exactly for size; existing longs'
+        // entry value is taken at the mark price
+        if from_version < 12 {
+            let long_base_amount = (i128::from(market_state.open_interest) + i128::from(market_state.net_base_amount)) / 2;
+            market_state.long_base_amount = math::to_i64(long_base_amount)?;
+            market_state.long_entry_quote =
+                math::mul_scaled_signed(long_base_amount, market_state.mark_price.into())?;
+        }
+        // Each side's open interest is split out of the total and the net size
+        if from_version < 14 {
+            let long_open_interest = (i128::from(market_state.open_interest) + i128::from(market_state.net_base_amount)) / 2;
+            market_state.long_open_interest = u64::try_from(long_open_interest).map_err(|_| ProgramError::InvalidAccountData)?;
+            market_state.short_open_interest = market_state.open_interest
+                .checked_sub(market_state.long_open_interest)
+                .ok_or(ProgramError::InvalidAccountData)?;
+        }
+        market_state.version = MarketState::VERSION;
+        (from_version, MarketState::VERSION)
+    };
+
+    if from_version >= to_version {
+        msg!("Account already at layout v{}", from_version);
+        return Err(ProgramError::InvalidArgument);
+    }
+
+    msg!("Migrated account {} from layout v{} to v{}", account.key, from_version, to_version);
+
+    Ok(())
+}
+
+// ---------------------------------------------------------------------
+// 2️⃣1️⃣ Close an empty position account and reclaim its rent
+// ---------------------------------------------------------------------
+pub fn close_position_account(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramResult {
+    // Accounts:
+    // 0. [signer, writable] position owner (receives the rent)
+    // 1. [writable] position account
+    let accounts_iter = &mut accounts.iter();
+    let owner = next_account_info(accounts_iter)?;
+    let position_acc = next_account_info(accounts_iter)?;
+
+    if !owner.is_signer {
+        msg!("Owner must be signer");
+        return Err(ProgramError::MissingRequiredSignature);
+    }
+
+    if position_acc.owner != program_id {
+        msg!("Position is not owned by the program");
+        return Err(ProgramError::IncorrectProgramId);
+    }
+
+    {
+        let position_data = position_acc.try_borrow_data()?;
+        let position = Position::load(&position_data)?;
+        require_position_owner(position, owner.key)?;
+
+        require_isolated(position)?;
+
+        if position.base_amount != 0 || position.collateral != 0 || held_asset_count(position) > 0 {
+            msg!("Position must be closed and all collateral withdrawn first: base={}, collateral={}",
+                 position.base_amount, position.collateral);
+            return Err(ProgramError::InvalidArgument);
+        }
+        if position.maker_rebates != 0 {
+            msg!("Claim the position's {} of maker rebates first", position.maker_rebates);
+            return Err(ProgramError::InvalidArgument);
+        }
+        if position.claimable_funding != 0 {
+            msg!("Claim the position's {} of funding first", position.claimable_funding);
+            return Err(ProgramError::InvalidArgument);
+        }
+        // Funding carried past the settlement cap is still owed, and close_position settles it
+        if position.pending_funding != 0 {
+            msg!("Settle the position's {} of pending funding with close_position first", position.pending_funding);
+            return Err(ProgramError::InvalidArgument);
+        }
+    }
+
+    let reclaimed = position_acc.lamports();
+    close_program_account(position_acc, owner)?;
+
+    msg!("Position account closed: reclaimed {} lamports", reclaimed);
+
+    Ok(())
+}
+
+// ---------------------------------------------------------------------
+// 2️⃣2️⃣ Register a non-quote collateral asset (admin only)
+// ---------------------------------------------------------------------
+pub fn add_collateral_asset(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    mint: Pubkey,
+    oracle: Pubkey,
+    weight: u64,
+    decimals: u8,
+) -> ProgramResult {
+    // Accounts:
+    // 0. [signer] admin
+    // 1. [writable] config account
+    // 2. [] asset vault token account (owned by the program's vault PDA)
+    let accounts_iter = &mut accounts.iter();
+    let admin = next_account_info(accounts_iter)?;
+    let config_acc = next_account_info(accounts_iter)?;
+    let asset_vault = next_account_info(accounts_iter)?;
+
+    let mut config = load_admin_config(program_id, admin, config_acc)?;
+
+    if weight > PRECISION {
+        msg!("Collateral weight must not exceed 100%: {}", weight);
+        return Err(ProgramError::InvalidArgument);
+    }
+
+    if config.collateral_assets.len() >= MAX_COLLATERAL_ASSETS {
+        msg!("Collateral asset list is full ({})", MAX_COLLATERAL_ASSETS);
+        return Err(ProgramError::InvalidArgument);
+    }
+
+    if config.collateral_assets.iter().any(|asset| asset.mint == mint) {
+        msg!("Collateral asset {} already registered", mint);
+        return Err(ProgramError::InvalidArgument);
+    }
+
+    if !is_supported_token_program(asset_vault.owner) {
+        msg!("Asset vault must be owned by SPL Token or Token-2022, got {}", asset_vault.owner);
+        return Err(ProgramError::IncorrectProgramId);
+    }
+
+    let vault_authority = vault_pda(program_id, config.vault_bump)?;
+    validate_token_account(asset_vault.owner, asset_vault, &mint, Some(&vault_authority))?;
+
+    config.collateral_assets.push(CollateralAsset {
+        mint,
+        vault: *asset_vault.key,
+        oracle,
+        weight,
+        decimals,
+    });
+    store_account(&config, &mut config_acc.data.borrow_mut())?;
+
+    msg!("Collateral asset {} added at index {}: weight={}, oracle={}",
+         mint, config.collateral_assets.len() - 1, weight, oracle);
+
+    Ok(())
+}
+
+// ---------------------------------------------------------------------
+// 2️⃣3️⃣ Deposit a non-quote collateral asset into a position
+// ---------------------------------------------------------------------
+pub fn deposit_collateral_asset(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    asset_index: u8,
+    amount: u64,
+) -> ProgramResult {
+    // Accounts:
+    // 0. [signer] user (position owner)
+    // 1. [] token program
+    // 2. [writable] user's token account for the asset
+    // 3. [writable] asset vault token account
+    // 4. [writable] position account
+    // 5. [] config account
+    // 6. [] asset mint
+    let accounts_iter = &mut accounts.iter();
+    let user = next_account_info(accounts_iter)?;
+    let token_program = next_account_info(accounts_iter)?;
+    let user_token_acc = next_account_info(accounts_iter)?;
+    let asset_vault = next_account_info(accounts_iter)?;
+    let position_acc = next_account_info(accounts_iter)?;
+    let config_acc = next_account_info(accounts_iter)?;
+    let asset_mint = next_account_info(accounts_iter)?;
+
+    if !user.is_signer {
+        msg!("User must be signer");
+        return Err(ProgramError::MissingRequiredSignature);
+    }
+
+    let asset_index = asset_index as usize;
+
+    let config = load_config(program_id, config_acc)?;
+    require_not_settlement_only(&config)?;
+    let asset = collateral_asset(&config, asset_index, asset_vault)?;
+    validate_mint(token_program, asset_mint, &asset.mint)?;
+    validate_token_account(token_program.key, user_token_acc, &asset.mint, Some(user.key))?;
+
+    if position_acc.owner != program_id {
+        msg!("Position is not owned by the program");
+        return Err(ProgramError::IncorrectProgramId);
+    }
+
+    let mut position_data = position_acc.try_borrow_mut_data()?;
+    let position = Position::load_mut(&mut position_data)?;
+    require_position_owner(position, user.key)?;
+
+    require_isolated(position)?;
+
+    if amount == 0 {
+        msg!("Deposit amount must be non-zero");
+        return Err(ProgramError::InvalidArgument);
+    }
+
+    let transfer_ix = create_transfer_checked_instruction(
+        token_program.key,
+        user_token_acc.key,
+        asset_mint.key,
+        asset_vault.key,
+        user.key,
+        amount,
+        asset.decimals,
+    )?;
+
+    invoke(&transfer_ix, &[
+        user_token_acc.clone(),
+        asset_mint.clone(),
+        asset_vault.clone(),
+        user.clone(),
+        token_program.clone(),
+    ])?;
+
+    position.collateral_balances[asset_index] = position.collateral_balances[asset_index]
+        .checked_add(amount)
+        .ok_or(ProgramError::InvalidArgument)?;
+
+    msg!("Deposited {} of collateral asset {} ({})", amount, asset_index, asset.mint);
+
+    Ok(())
+}
+
+// ---------------------------------------------------------------------
+// 2️⃣4️⃣ Withdraw a non-quote collateral asset from a position
+// ---------------------------------------------------------------------
+pub fn withdraw_collateral_asset(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    asset_index: u8,
+    amount: u64,
+) -> ProgramResult {
+    // Accounts:
+    // 0. [signer] user (position owner)
+    // 1. [] token program
+    // 2. [writable] user's token account for the asset
+    // 3. [writable] asset vault token account (PDA‑owned)
+    // 4. [writable] position account
+    // 5. [writable] market state account
+    // 6. [] config account
+    // 7. [] clock sysvar
+    // 8. [] asset mint
+    // 9. [] vault authority (PDA: [PDA_SEED])
+    // 10..N. [] oracle of the withdrawn asset, and with open exposure of each other collateral asset
+    //        the position holds
+    let accounts_iter = &mut accounts.iter();
+    let user = next_account_info(accounts_iter)?;
+    let token_program = next_account_info(accounts_iter)?;
+    let user_token_acc = next_account_info(accounts_iter)?;
+    let asset_vault = next_account_info(accounts_iter)?;
+    let position_acc = next_account_info(accounts_iter)?;
+    let market_state_acc = next_account_info(accounts_iter)?;
+    let config_acc = next_account_info(accounts_iter)?;
+    let clock_sysvar = next_account_info(accounts_iter)?;
+    let asset_mint = next_account_info(accounts_iter)?;
+    let vault_authority = next_account_info(accounts_iter)?;
+    let oracle_accs: Vec<&AccountInfo> = accounts_iter.collect();
+
+    if !user.is_signer {
+        msg!("User must be signer");
+        return Err(ProgramError::MissingRequiredSignature);
+    }
+
+    let asset_index = asset_index as usize;
+
+    let config = load_config(program_id, config_acc)?;
+    let asset = collateral_asset(&config, asset_index, asset_vault)?;
+    validate_mint(token_program, asset_mint, &asset.mint)?;
+    validate_token_account(token_program.key, user_token_acc, &asset.mint, None)?;
+    let clock = Clock::from_account_info(clock_sysvar)?;
+
+    if position_acc.owner != program_id || market_state_acc.owner != program_id {
+        msg!("Position and market state must be owned by the program");
+        return Err(ProgramError::IncorrectProgramId);
+    }
+
+    let mut market_state_data = market_state_acc.try_borrow_mut_data()?;
+    let market_state = MarketState::load_mut(&mut market_state_data)?;
+    let mut position_data = position_acc.try_borrow_mut_data()?;
+    let position = Position::load_mut(&mut position_data)?;
+    require_position_owner(position, user.key)?;
+    validate_position_address(program_id, position_acc.key, market_state_acc.key, position)?;
+
+    position.collateral_balances[asset_index] = position.collateral_balances[asset_index]
+        .checked_sub(amount)
+        .ok_or(ProgramError::InsufficientFunds)?;
+
+    // With open exposure the position must be past its hold, and the remaining collateral
+    // must still cover the margin requirement
+    if position.base_amount != 0 {
+        require_hold_elapsed(position, market_state, clock.slot)?;
+        let asset_collateral = calculate_weighted_asset_collateral(position, &config, &oracle_accs, clock.slot)?;
+        let margin_position = Position {
+            collateral: position.collateral
+                .checked_add(asset_collateral)
+                .ok_or(ProgramError::InvalidArgument)?,
+            ..*position
+        };
+        validate_collateral_ratio(&margin_position, market_state, config.min_collateral_ratio)?;
+    }
+
+    // The withdrawal counts toward the market's limits at the asset's unweighted quote value
+    let oracle_acc = oracle_accs.iter().find(|acc| *acc.key == asset.oracle).ok_or_else(|| {
+        msg!("Missing oracle {} for collateral asset {}", asset.oracle, asset_index);
+        ProgramError::NotEnoughAccountKeys
+    })?;
+    let oracle_price = oracle::read_oracle_price(oracle_acc, &asset.oracle, clock.slot)?;
+    let value = calculate_asset_collateral_value(amount, asset.decimals, oracle_price.price, PRECISION)?;
+    market_state.record_withdrawal(position, value, clock.slot, clock.epoch)?;
+
+    let bump = market_state.bump;
+    let pda = vault_pda(program_id, bump)?;
+    if *vault_authority.key != pda {
+        msg!("Vault authority is not the correct PDA. Expected: {}, Got: {}", pda, vault_authority.key);
+        return Err(ProgramError::InvalidArgument);
+    }
+    let seeds = &[PDA_SEED, &[bump]];
+    let signer_seeds = &[&seeds[..]];
+
+    // Asset vaults are separate token accounts, so the PDA signs as their owner
+    let transfer_ix = create_transfer_checked_instruction(
+        token_program.key,
+        asset_vault.key,
+        asset_mint.key,
+        user_token_acc.key,
+        &pda,
+        amount,
+        asset.decimals,
+    )?;
+
+    invoke_signed(&transfer_ix, &[
+        asset_vault.clone(),
+        asset_mint.clone(),
+        user_token_acc.clone(),
+        vault_authority.clone(),
+        token_program.clone(),
+    ], signer_seeds)?;
+
+    msg!("Withdrew {} of collateral asset {} ({})", amount, asset_index, asset.mint);
+
+    Ok(())
+}
+
+// ---------------------------------------------------------------------
+// 2️⃣5️⃣ Set the quote mint on a config migrated from before it was recorded (admin only)
+// ---------------------------------------------------------------------
+pub fn set_quote_mint(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramResult {
+    // Accounts:
+    // 0. [signer] admin
+    // 1. [writable] config account
+    // 2. [] quote mint (its owner becomes the configured token program)
+    let accounts_iter = &mut accounts.iter();
+    let admin = next_account_info(accounts_iter)?;
+    let config_acc = next_account_info(accounts_iter)?;
+    let quote_mint = next_account_info(accounts_iter)?;
+
+    let mut config = load_admin_config(program_id, admin, config_acc)?;
+
+    // Existing positions are denominated in the quote mint, so it can only be set once
+    if config.quote_mint != Pubkey::default() {
+        msg!("Quote mint already set: {}", config.quote_mint);
+        return Err(ProgramError::AccountAlreadyInitialized);
+    }
+
+    read_mint_decimals(quote_mint)?;
+
+    config.token_program = *quote_mint.owner;
+    config.quote_mint = *quote_mint.key;
+    store_account(&config, &mut config_acc.data.borrow_mut())?;
+
+    msg!("Quote mint set: {} (token program {})", quote_mint.key, quote_mint.owner);
+
+    Ok(())
+}
+
+// ---------------------------------------------------------------------
+// 2️⃣6️⃣ Deposit native SOL as wrapped SOL collateral
+// ---------------------------------------------------------------------
+pub fn deposit_native_sol(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    asset_index: u8,
+    lamports: u64,
+) -> ProgramResult {
+    // Accounts:
+    // 0. [signer, writable] user (position owner, pays the lamports)
+    // 1. [] token program (SPL Token)
+    // 2. [writable] wSOL asset vault token account
+    // 3. [writable] position account
+    // 4. [] config account
+    // 5. [] system program
+    let accounts_iter = &mut accounts.iter();
+    let user = next_account_info(accounts_iter)?;
+    let token_program = next_account_info(accounts_iter)?;
+    let asset_vault = next_account_info(accounts_iter)?;
+    let position_acc = next_account_info(accounts_iter)?;
+    let config_acc = next_account_info(accounts_iter)?;
+    let system_program = next_account_info(accounts_iter)?;
+
+    if !user.is_signer {
+        msg!("User must be signer");
+        return Err(ProgramError::MissingRequiredSignature);
+    }
+
+    let asset_index = asset_index as usize;
+
+    let config = load_config(program_id, config_acc)?;
+    require_not_settlement_only(&config)?;
+    let asset = collateral_asset(&config, asset_index, asset_vault)?;
+    if asset.mint != NATIVE_MINT {
+        msg!("Collateral asset {} is not wrapped SOL: {}", asset_index, asset.mint);
+        return Err(ProgramError::InvalidArgument);
+    }
+
+    if *token_program.key != SPL_TOKEN_PROGRAM_ID || asset_vault.owner != token_program.key {
+        msg!("Wrapped SOL vault must be an SPL Token account");
+        return Err(ProgramError::IncorrectProgramId);
+    }
+
+    if position_acc.owner != program_id {
+        msg!("Position is not owned by the program");
+        return Err(ProgramError::IncorrectProgramId);
+    }
+
+    let mut position_data = position_acc.try_borrow_mut_data()?;
+    let position = Position::load_mut(&mut position_data)?;
+    require_position_owner(position, user.key)?;
+
+    require_isolated(position)?;
+
+    if lamports == 0 {
+        msg!("Deposit amount must be non-zero");
+        return Err(ProgramError::InvalidArgument);
+    }
+
+    // Lamports sent straight to the vault become wSOL once the token program syncs its balance,
+    // so no temporary token account is needed
+    invoke(
+        &system_instruction::transfer(user.key, asset_vault.key, lamports),
+        &[user.clone(), asset_vault.clone(), system_program.clone()],
+    )?;
+
+    invoke(
+        &create_sync_native_instruction(token_program.key, asset_vault.key),
+        &[asset_vault.clone(), token_program.clone()],
+    )?;
+
+    position.collateral_balances[asset_index] = position.collateral_balances[asset_index]
+        .checked_add(lamports)
+        .ok_or(ProgramError::InvalidArgument)?;
+
+    msg!("Wrapped and deposited {} lamports as collateral asset {}", lamports, asset_index);
+
+    Ok(())
+}
+
+// ---------------------------------------------------------------------
+// 3️⃣1️⃣ Approve or revoke a trading delegate for a position
+// ---------------------------------------------------------------------
+pub fn approve_delegate(program_id: &Pubkey, accounts: &[AccountInfo], delegate: Pubkey) -> ProgramResult {
+    // Accounts:
+    // 0. [signer] position owner
+    // 1. [writable] position account
+    let accounts_iter = &mut accounts.iter();
+    let owner = next_account_info(accounts_iter)?;
+    let position_acc = next_account_info(accounts_iter)?;
+
+    if !owner.is_signer {
+        msg!("Owner must be signer");
+        return Err(ProgramError::MissingRequiredSignature);
+    }
+
+    if position_acc.owner != program_id {
+        msg!("Position is not owned by the program");
+        return Err(ProgramError::IncorrectProgramId);
+    }
+
+    let mut position_data = position_acc.try_borrow_mut_data()?;
+    let position = Position::load_mut(&mut position_data)?;
+    require_position_owner(position, owner.key)?;
+
+    position.delegate = delegate;
+
+    if delegate == Pubkey::default() {
+        msg!("Position delegate revoked");
+    } else {
+        msg!("Position delegate approved: {}", delegate);
+    }
+
+    Ok(())
+}
+
+// ---------------------------------------------------------------------
+// 3️⃣2️⃣ Backstop liquidation: the liquidator takes over the position
+// ---------------------------------------------------------------------
+pub fn backstop_liquidate(program_id: &Pubkey, accounts: &[AccountInfo], collateral_delta: u64) -> ProgramResult {
+    // Accounts:
+    // 0. [signer] liquidator
+    // 1. [] token program
+    // 2. [writable] liquidator's collateral token account (quote token)
+    // 3. [writable] vault token account (PDA‑owned)
+    // 4. [writable] position account to liquidate
+    // 5. [writable] liquidator's flat, isolated position in the same market
+    //    (PDA: [POSITION_SEED, market_state, liquidator, sub_account_id])
+    // 6. [writable] market state account
+    // 7. [] clock sysvar
+    // 8. [] config account
+    // 9. [] quote mint
+    // Isolated positions:
+    //   10..N. [] oracle of each collateral asset the liquidated position holds
+    // Cross-margined positions:
+    //   10. [writable] owner's user account
+    //   11..N. [] position and market state account of every other linked position
+    // Then:
+    //   [writable, optional] liquidated position owner's user stats account
+    //   [writable, optional] market metrics account
+    //   [] liquidator registry (required while liquidators are restricted)
+    //   [] liquidator's whitelist entry or access token (permissioned markets)
+    //   [] liquidator's leverage override in this market (optional)
+    let accounts_iter = &mut accounts.iter();
+    let liquidator = next_account_info(accounts_iter)?;
+    let token_program = next_account_info(accounts_iter)?;
+    let liquidator_collateral = next_account_info(accounts_iter)?;
+    let vault = next_account_info(accounts_iter)?;
+    let position_acc = next_account_info(accounts_iter)?;
+    let backstop_position_acc = next_account_info(accounts_iter)?;
+    let market_state_acc = next_account_info(accounts_iter)?;
+    let clock_sysvar = next_account_info(accounts_iter)?;
+    let config_acc = next_account_info(accounts_iter)?;
+    let quote_mint = next_account_info(accounts_iter)?;
+    let remaining_accs: Vec<&AccountInfo> = accounts_iter.collect();
+
+    if !liquidator.is_signer {
+        msg!("Liquidator must be signer");
+        return Err(ProgramError::MissingRequiredSignature);
+    }
+
+    for account in [position_acc, backstop_position_acc, market_state_acc] {
+        if account.owner != program_id {
+            msg!("Account {} is not owned by the program", account.key);
+            return Err(ProgramError::IncorrectProgramId);
+        }
+    }
+    if position_acc.key == backstop_position_acc.key {
+        msg!("A position cannot take over itself");
+        return Err(ProgramError::InvalidArgument);
+    }
+
+    let config = load_config(program_id, config_acc)?;
+    access::require_registered_liquidator(program_id, &config, liquidator.key, &remaining_accs)?;
+    let quote_decimals = validate_quote_mint(&config, token_program, quote_mint)?;
+    validate_token_account(token_program.key, liquidator_collateral, quote_mint.key, Some(liquidator.key))?;
+    let clock = Clock::from_account_info(clock_sysvar)?;
+    let mut market_state_data = market_state_acc.try_borrow_mut_data()?;
+    let market_state = MarketState::load_mut(&mut market_state_data)?;
+    require_active(market_state)?;
+    validate_market_vault(program_id, token_program, vault, market_state, quote_mint)?;
+    let mut position_data = position_acc.try_borrow_mut_data()?;
+    let position = Position::load_mut(&mut position_data)?;
+    let mut backstop_data = backstop_position_acc.try_borrow_mut_data()?;
+    let backstop = Position::load_mut(&mut backstop_data)?;
+
+    // The liquidated position must trade in this market, so it is taken over at this market's mark
+    validate_position_address(program_id, position_acc.key, market_state_acc.key, position)?;
+
+    // The taking-over position must be the liquidator's own, flat, in this market
+    if backstop.owner != *liquidator.key {
+        msg!("Backstop position owner mismatch. Expected: {}, Got: {}", liquidator.key, backstop.owner);
+        return Err(ProgramError::IllegalOwner);
+    }
+    validate_position_address(program_id, backstop_position_acc.key, market_state_acc.key, backstop)?;
+    require_isolated(backstop)?;
+    if backstop.base_amount != 0 {
+        msg!("Backstop position must be flat");
+        return Err(ProgramError::InvalidArgument);
+    }
+
+    // Taking the position over opens one for the liquidator, so it is gated like any other open
+    if config.max_positions_per_user != 0 && backstop.sub_account_id >= config.max_positions_per_user {
+        msg!("Sub-account {} exceeds the limit of {} positions per wallet in a market",
+             backstop.sub_account_id, config.max_positions_per_user);
+        return Err(ProgramError::InvalidArgument);
+    }
+    if market_state.is_reduce_only(clock.slot) {
+        msg!("Market is paused or cooling down from a circuit breaker: positions can't be taken over");
+        return Err(ProgramError::InvalidArgument);
+    }
+    if market_state.is_expired(clock.unix_timestamp) {
+        msg!("Market expired at {}: positions can't be taken over", market_state.expiry_timestamp);
+        return Err(ProgramError::InvalidArgument);
+    }
+    access::require_whitelisted(program_id, market_state, liquidator.key, &remaining_accs)?;
+
+    let bad_debt_before = market_state.bad_debt;
+    let (mut cross_margin, collateral_ratio) = check_liquidatable(
+        program_id, position_acc.key, position, market_state_acc.key, market_state, &config, &remaining_accs, clock.slot,
+    )?;
+    require_grace_elapsed(position, market_state, collateral_ratio, clock.slot)?;
+
+    // ---------- Close the liquidated position, or the market's share of it per slot, at the mark price ----------
+    let base_delta = liquidation_base_delta(position.base_amount, market_state.max_liquidation_share)?;
+    let base_delta = apply_min_position_size(position.base_amount, base_delta, config.min_position_size)?;
+    let base_amount = base_delta.checked_neg().ok_or(ProgramError::InvalidArgument)?;
+    apply_min_position_size(0, base_amount, config.min_position_size)?;
+    let closed_notional = calculate_notional(base_delta, market_state.mark_price)?;
+    let mark_price = market_state.mark_price;
+    match cross_margin.as_mut() {
+        Some(cross_margin) => cross_margin.apply_position_change(position, market_state, base_delta, mark_price)?,
+        None => apply_position_change(position, market_state, base_delta, mark_price)?,
+    }
+    emit_bad_debt(market_state_acc.key, position_acc.key, market_state, bad_debt_before);
+
+    let fee = calculate_position_liquidation_fee(closed_notional, position, market_state, cross_margin.as_ref(), &config)?;
+
+    // ---------- Liquidator posts their own collateral ----------
+    if collateral_delta > 0 {
+        let transfer_ix = create_transfer_checked_instruction(
+            token_program.key,
+            liquidator_collateral.key,
+            quote_mint.key,
+            vault.key,
+            liquidator.key,
+            collateral_delta,
+            quote_decimals,
+        )?;
+
+        invoke(&transfer_ix, &[
+            liquidator_collateral.clone(),
+            quote_mint.clone(),
+            vault.clone(),
+            liquidator.clone(),
+            token_program.clone(),
+        ])?;
+
+        let deposit = market_state.quote_to_precision(collateral_delta)?;
+        count_deposit(backstop, market_state, deposit)?;
+        backstop.collateral = backstop.collateral
+            .checked_add(deposit)
+            .ok_or(ProgramError::InvalidArgument)?;
+    }
+
+    // ---------- Hand the exposure over ----------
+    // The liquidator's share of the fee is paid as a better entry than the mark price.
+    // Open interest ends unchanged: the same exposure just changes hands.
+    apply_funding(backstop, market_state, config.max_funding_settlement_share)?;
+    let entry_price = calculate_backstop_entry_price(base_amount, mark_price, fee.liquidator_reward)?;
+    apply_position_change(backstop, market_state, base_amount, entry_price)?;
+    backstop.last_modified_slot = clock.slot;
+
+    validate_user_notional(calculate_notional(backstop.base_amount, mark_price)?, config.max_user_notional)?;
+    validate_collateral_ratio(backstop, market_state, config.min_collateral_ratio)?;
+    let max_leverage = access::max_leverage_for(program_id, market_state_acc.key, market_state, liquidator.key, &remaining_accs);
+    validate_leverage(backstop, market_state, max_leverage)?;
+
+    let remaining_collateral = clear_liquidated_position(position, market_state, cross_margin.as_mut(), &fee, config.dust_collateral)?;
+    if position.base_amount != 0 {
+        position.next_liquidation_slot = clock.slot.saturating_add(1);
+        msg!("Position partially taken over: {} base left", position.base_amount);
+    }
+    user_stats::update_user_stats(program_id, &position.owner, &remaining_accs, |stats| {
+        stats.record_liquidation(closed_notional, fee.collateral_charged())
+    })?;
+    market_metrics::update_market_metrics(program_id, market_state_acc.key, &remaining_accs, |metrics| {
+        metrics.record_liquidation(closed_notional, fee.collateral_charged(), market_state.open_interest)
+    })?;
+
+    msg!("Position taken over: base_amount={}, entry_price={}, liquidator_discount={} ({} from insurance), insurance_fee={}, remaining_collateral={}, ratio_was={}",
+         base_amount, backstop.entry_price, fee.liquidator_reward, fee.from_insurance, fee.insurance_fee,
+         remaining_collateral, collateral_ratio);
+
+    Ok(())
+}
+
+// ---------------------------------------------------------------------
+// 3️⃣3️⃣ Settle a position's unrealized PnL against the vault (permissionless)
+// ---------------------------------------------------------------------
+pub fn settle_pnl(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramResult {
+    // Accounts:
+    // 0. [writable] position account
+    // 1. [writable] market state account the position trades in
+    // 2. [] config account
+    // Cross-margined positions:
+    //   3. [writable] owner's user account
+    //   4..N. [] position and market state account of every other linked position
+    let accounts_iter = &mut accounts.iter();
+    let position_acc = next_account_info(accounts_iter)?;
+    let market_state_acc = next_account_info(accounts_iter)?;
+    let config_acc = next_account_info(accounts_iter)?;
+    let remaining_accs: Vec<&AccountInfo> = accounts_iter.collect();
+
+    let config = load_config(program_id, config_acc)?;
+    require_not_settlement_only(&config)?;
+
+    for account in [position_acc, market_state_acc] {
+        if account.owner != program_id {
+            msg!("Account {} is not owned by the program", account.key);
+            return Err(ProgramError::IncorrectProgramId);
+        }
+    }
+
+    let mut market_state_data = market_state_acc.try_borrow_mut_data()?;
+    let market_state = MarketState::load_mut(&mut market_state_data)?;
+    require_active(market_state)?;
+    let mut position_data = position_acc.try_borrow_mut_data()?;
+    let position = Position::load_mut(&mut position_data)?;
+
+    // PnL is realized at this market's mark price, so the position must trade in it
+    validate_position_address(program_id, position_acc.key, market_state_acc.key, position)?;
+
+    let mut cross_margin = if position.is_cross_margin() {
+        let (cross_margin, _) =
+            CrossMargin::load(program_id, position_acc.key, position, market_state_acc.key, &remaining_accs, false)?;
+        Some(cross_margin)
+    } else {
+        None
+    };
+
+    // Settle funding first so the PnL lands on up-to-date collateral
+    let bad_debt_before = market_state.bad_debt;
+    let pnl = calculate_unrealized_pnl(position, market_state.mark_price)?;
+    match cross_margin.as_mut() {
+        Some(cross_margin) => {
+            cross_margin.apply_funding(position, market_state, config.max_funding_settlement_share)?;
+            cross_margin.settle_realized_pnl(position, market_state, pnl)?;
+            cross_margin.store()?;
+        }
+        None => {
+            apply_funding(position, market_state, config.max_funding_settlement_share)?;
+            settle_realized_pnl(position, market_state, pnl)?;
+        }
+    }
+    emit_bad_debt(market_state_acc.key, position_acc.key, market_state, bad_debt_before);
+
+    // The position keeps its size; its remaining exposure now starts at the mark price
+    if position.base_amount != 0 {
+        track_exposure(market_state, position, false)?;
+        position.entry_price = market_state.mark_price;
+        track_exposure(market_state, position, true)?;
+    }
+
+    msg!("PnL settled: pnl={}, entry_price={}", pnl, position.entry_price);
+
+    Ok(())
+}
+
+// ---------------------------------------------------------------------
+// 7️⃣0️⃣ Settle a position's pending funding (permissionless crank)
+// ---------------------------------------------------------------------
+pub fn settle_funding_for(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramResult {
+    // Accounts:
+    // 0. [writable] position account
+    // 1. [writable] market state account the position trades in
+    // 2. [] config account
+    // Cross-margined positions:
+    //   3. [writable] owner's user account
+    //   4..N. [] position and market state account of every other linked position
+    let accounts_iter = &mut accounts.iter();
+    let position_acc = next_account_info(accounts_iter)?;
+    let market_state_acc = next_account_info(accounts_iter)?;
+    let config_acc = next_account_info(accounts_iter)?;
+    let remaining_accs: Vec<&AccountInfo> = accounts_iter.collect();
+
+    let config = load_config(program_id, config_acc)?;
+    require_not_settlement_only(&config)?;
+
+    for account in [position_acc, market_state_acc] {
+        if account.owner != program_id {
+            msg!("Account {} is not owned by the program", account.key);
+            return Err(ProgramError::IncorrectProgramId);
+        }
+    }
+
+    let mut market_state_data = market_state_acc.try_borrow_mut_data()?;
+    let market_state = MarketState::load_mut(&mut market_state_data)?;
+    let mut position_data = position_acc.try_borrow_mut_data()?;
+    let position = Position::load_mut(&mut position_data)?;
+
+    // Funding is settled against this market's index, so the position must trade in it
+    validate_position_address(program_id, position_acc.key, market_state_acc.key, position)?;
+
+    // Dormant positions keep reporting stale collateral until their funding is settled, so
+    // anyone may settle it; the same cap as on the owner's own trades applies
+    let bad_debt_before = market_state.bad_debt;
+    if position.is_cross_margin() {
+        let (mut cross_margin, _) =
+            CrossMargin::load(program_id, position_acc.key, position, market_state_acc.key, &remaining_accs, false)?;
+        cross_margin.apply_funding(position, market_state, config.max_funding_settlement_share)?;
+        cross_margin.store()?;
+    } else {
+        apply_funding(position, market_state, config.max_funding_settlement_share)?;
+    }
+    emit_bad_debt(market_state_acc.key, position_acc.key, market_state, bad_debt_before);
+
+    msg!("Funding settled: cumulative_funding={}, pending_funding={}, last_funding_index={}",
+         position.cumulative_funding, position.pending_funding, position.last_funding_index);
+
+    Ok(())
+}
+
+// ---------------------------------------------------------------------
+// 9️⃣1️⃣ Withdraw a position's claimable funding
+// ---------------------------------------------------------------------
+pub fn claim_funding(program_id: &Pubkey, accounts: &[AccountInfo], auto_compound: bool) -> ProgramResult {
+    // Accounts:
+    // 0. [signer] position owner, or the holder of a tokenized position's NFT
+    // 1. [] token program
+    // 2. [writable] token account to receive the funding
+    // 3. [writable] vault token account (PDA‑owned)
+    // 4. [writable] position account
+    // 5. [writable] market state account
+    // 6. [] config account
+    // 7. [] quote mint
+    // 8. [] signer's token account holding a tokenized position's NFT (required for the holder)
+    let accounts_iter = &mut accounts.iter();
+    let owner = next_account_info(accounts_iter)?;
+    let token_program = next_account_info(accounts_iter)?;
+    let owner_token_acc = next_account_info(accounts_iter)?;
+    let vault = next_account_info(accounts_iter)?;
+    let position_acc = next_account_info(accounts_iter)?;
+    let market_state_acc = next_account_info(accounts_iter)?;
+    let config_acc = next_account_info(accounts_iter)?;
+    let quote_mint = next_account_info(accounts_iter)?;
+    let remaining_accs: Vec<&AccountInfo> = accounts_iter.collect();
+
+    if !owner.is_signer {
+        msg!("Position owner must be signer");
+        return Err(ProgramError::MissingRequiredSignature);
+    }
+
+    if position_acc.owner != program_id || market_state_acc.owner != program_id {
+        msg!("Position and market state must be owned by the program");
+        return Err(ProgramError::IncorrectProgramId);
+    }
+
+    let config = load_config(program_id, config_acc)?;
+    let quote_decimals = validate_quote_mint(&config, token_program, quote_mint)?;
+    let mut market_state_data = market_state_acc.try_borrow_mut_data()?;
+    let market_state = MarketState::load_mut(&mut market_state_data)?;
+    let (pda, bump) = validate_market_vault(program_id, token_program, vault, market_state, quote_mint)?;
+    validate_token_account(token_program.key, owner_token_acc, quote_mint.key, None)?;
+
+    let mut position_data = position_acc.try_borrow_mut_data()?;
+    let position = Position::load_mut(&mut position_data)?;
+    // The owner, or the holder of a tokenized position's NFT, paid to any quote account they name
+    position_token::require_position_authority(position, owner.key, &remaining_accs)?;
+    validate_position_address(program_id, position_acc.key, market_state_acc.key, position)?;
+
+    // Funding already claimable is paid out either way; the flag only steers later settlements
+    position.auto_compound_funding = u8::from(auto_compound);
+    let claimed = position.claimable_funding;
+    if claimed > 0 {
+        let clock = Clock::get()?;
+        market_state.record_withdrawal(position, claimed, clock.slot, clock.epoch)?;
+
+        let transfer_ix = create_transfer_checked_instruction(
+            token_program.key,
+            vault.key,
+            quote_mint.key,
+            owner_token_acc.key,
+            &pda,
+            market_state.quote_from_precision(claimed)?,
+            quote_decimals,
+        )?;
+
+        invoke_signed(&transfer_ix, &[
+            vault.clone(),
+            quote_mint.clone(),
+            owner_token_acc.clone(),
+            vault.clone(), // PDA authority
+            token_program.clone(),
+        ], &[&[PDA_SEED, &[bump]]])?;
+        require_vault_covers(vault, market_state.reserves(), quote_decimals)?;
+
+        position.claimable_funding = 0;
+    }
+
+    msg!("Funding claimed: {}, auto_compound={}", claimed, auto_compound);
+
+    Ok(())
+}
+
+// ---------------------------------------------------------------------
+// 4️⃣0️⃣ Set a market's index oracle and mark price band (admin only)
+// ---------------------------------------------------------------------
+pub fn set_index_oracle(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    index_oracle: Pubkey,
+    mark_price_band: Option<u64>,
+) -> ProgramResult {
+    // Accounts:
+    // 0. [signer] admin
+    // 1. [] config account
+    // 2. [writable] market state account
+    let accounts_iter = &mut accounts.iter();
+    let admin = next_account_info(accounts_iter)?;
+    let config_acc = next_account_info(accounts_iter)?;
+    let market_state_acc = next_account_info(accounts_iter)?;
+
+    load_admin_config(program_id, admin, config_acc)?;
+
+    if market_state_acc.owner != program_id {
+        msg!("Market state is not owned by the program");
+        return Err(ProgramError::IncorrectProgramId);
+    }
+
+    let mark_price_band = mark_price_band.unwrap_or(DEFAULT_MARK_PRICE_BAND);
+
+    if mark_price_band == 0 || mark_price_band > PRECISION {
+        msg!("Mark price band must be in (0, 100%]: {}", mark_price_band);
+        return Err(ProgramError::InvalidArgument);
+    }
+
+    let mut market_state_data = market_state_acc.try_borrow_mut_data()?;
+    let market_state = MarketState::load_mut(&mut market_state_data)?;
+    if index_oracle != Pubkey::default() && market_state.backup_oracles.contains(&index_oracle) {
+        msg!("Index oracle {} is already one of the market's backup oracles", index_oracle);
+        return Err(ProgramError::InvalidArgument);
+    }
+    market_state.index_oracle = index_oracle;
+    market_state.mark_price_band = mark_price_band;
+
+    msg!("Index oracle set: oracle={}, mark_price_band={}", index_oracle, mark_price_band);
+
+    Ok(())
+}
+
+// ---------------------------------------------------------------------
+// 7️⃣2️⃣ Set the backup feeds of a market's index price (admin only)
+// ---------------------------------------------------------------------
+pub fn set_backup_oracles(program_id: &Pubkey, accounts: &[AccountInfo], backup_oracles: Vec<Pubkey>) -> ProgramResult {
+    // Accounts:
+    // 0. [signer] admin
+    // 1. [] config account
+    // 2. [writable] market state account
+    let accounts_iter = &mut accounts.iter();
+    let admin = next_account_info(accounts_iter)?;
+    let config_acc = next_account_info(accounts_iter)?;
+    let market_state_acc = next_account_info(accounts_iter)?;
+
+    load_admin_config(program_id, admin, config_acc)?;
+
+    if market_state_acc.owner != program_id {
+        msg!("Market state is not owned by the program");
+        return Err(ProgramError::IncorrectProgramId);
+    }
+
+    let mut market_state_data = market_state_acc.try_borrow_mut_data()?;
+    let market_state = MarketState::load_mut(&mut market_state_data)?;
+    if !market_state.has_index_oracle() {
+        msg!("Market has no index oracle to back up: run set_index_oracle first");
+        return Err(ProgramError::InvalidArgument);
+    }
+
+    if backup_oracles.len() >= oracle::MAX_ORACLE_FEEDS {
+        msg!("At most {} backup oracles, got {}", oracle::MAX_ORACLE_FEEDS - 1, backup_oracles.len());
+        return Err(ProgramError::InvalidArgument);
+    }
+
+    // A feed counted twice would outvote the others in the median
+    let mut feeds = vec![market_state.index_oracle];
+    for oracle in &backup_oracles {
+        if *oracle == Pubkey::default() || feeds.contains(oracle) {
+            msg!("Backup oracle {} is unset or already a feed of the market", oracle);
+            return Err(ProgramError::InvalidArgument);
+        }
+        feeds.push(*oracle);
+    }
+
+    market_state.backup_oracles = [Pubkey::default(); oracle::MAX_ORACLE_FEEDS - 1];
+    market_state.backup_oracles[..backup_oracles.len()].copy_from_slice(&backup_oracles);
+
+    msg!("Set {} backup oracles", backup_oracles.len());
+
+    Ok(())
+}
+
+// ---------------------------------------------------------------------
+// 7️⃣5️⃣ Set how long a market holds positions after they grow (admin only)
+// ---------------------------------------------------------------------
+pub fn set_min_hold_slots(program_id: &Pubkey, accounts: &[AccountInfo], min_hold_slots: u64) -> ProgramResult {
+    // Accounts:
+    // 0. [signer] admin
+    // 1. [] config account
+    // 2. [writable] market state account
+    let accounts_iter = &mut accounts.iter();
+    let admin = next_account_info(accounts_iter)?;
+    let config_acc = next_account_info(accounts_iter)?;
+    let market_state_acc = next_account_info(accounts_iter)?;
+
+    load_admin_config(program_id, admin, config_acc)?;
+
+    if market_state_acc.owner != program_id {
+        msg!("Market state is not owned by the program");
+        return Err(ProgramError::IncorrectProgramId);
+    }
+
+    // Traders must always be able to get out of a position within a bounded time
+    if min_hold_slots > MAX_MIN_HOLD_SLOTS {
+        msg!("Hold of {} slots exceeds the maximum of {}", min_hold_slots, MAX_MIN_HOLD_SLOTS);
+        return Err(ProgramError::InvalidArgument);
+    }
+
+    let mut market_state_data = market_state_acc.try_borrow_mut_data()?;
+    let market_state = MarketState::load_mut(&mut market_state_data)?;
+    market_state.min_hold_slots = min_hold_slots;
+
+    msg!("Min hold set: {} slots", min_hold_slots);
+
+    Ok(())
+}
+
+// ---------------------------------------------------------------------
+// 7️⃣6️⃣ Set how much of a position one liquidation can close per slot (admin only)
+// ---------------------------------------------------------------------
+pub fn set_max_liquidation_share(program_id: &Pubkey, accounts: &[AccountInfo], max_liquidation_share: u64) -> ProgramResult {
+    // Accounts:
+    // 0. [signer] admin
+    // 1. [] config account
+    // 2. [writable] market state account
+    let accounts_iter = &mut accounts.iter();
+    let admin = next_account_info(accounts_iter)?;
+    let config_acc = next_account_info(accounts_iter)?;
+    let market_state_acc = next_account_info(accounts_iter)?;
+
+    load_admin_config(program_id, admin, config_acc)?;
+
+    if market_state_acc.owner != program_id {
+        msg!("Market state is not owned by the program");
+        return Err(ProgramError::IncorrectProgramId);
+    }
+
+    // An unhealthy position must still unwind within a handful of slots
+    if max_liquidation_share != 0 && !(MIN_LIQUIDATION_SHARE..=PRECISION).contains(&max_liquidation_share) {
+        msg!("Liquidation share {} must be 0 or between {} and {}", max_liquidation_share, MIN_LIQUIDATION_SHARE, PRECISION);
+        return Err(ProgramError::InvalidArgument);
+    }
+
+    let mut market_state_data = market_state_acc.try_borrow_mut_data()?;
+    let market_state = MarketState::load_mut(&mut market_state_data)?;
+    market_state.max_liquidation_share = max_liquidation_share;
+
+    msg!("Max liquidation share set: {}", max_liquidation_share);
+
+    Ok(())
+}
+
+// ---------------------------------------------------------------------
+// 7️⃣7️⃣ Set a market's liquidation grace period and hard floor (admin only)
+// ---------------------------------------------------------------------
+pub fn set_liquidation_grace(program_id: &Pubkey, accounts: &[AccountInfo], grace_slots: u64, floor_ratio: u64) -> ProgramResult {
+    // Accounts:
+    // 0. [signer] admin
+    // 1. [] config account
+    // 2. [writable] market state account
+    let accounts_iter = &mut accounts.iter();
+    let admin = next_account_info(accounts_iter)?;
+    let config_acc = next_account_info(accounts_iter)?;
+    let market_state_acc = next_account_info(accounts_iter)?;
+
+    load_admin_config(program_id, admin, config_acc)?;
+
+    if market_state_acc.owner != program_id {
+        msg!("Market state is not owned by the program");
+        return Err(ProgramError::IncorrectProgramId);
+    }
+
+    if grace_slots > MAX_LIQUIDATION_GRACE_SLOTS {
+        msg!("Grace period of {} slots exceeds the maximum of {}", grace_slots, MAX_LIQUIDATION_GRACE_SLOTS);
+        return Err(ProgramError::InvalidArgument);
+    }
+
+    // Without a floor, a collapsing position would run into bad debt for the whole grace period
+    if grace_slots > 0 && floor_ratio == 0 {
+        msg!("A grace period needs a hard floor ratio");
+        return Err(ProgramError::InvalidArgument);
+    }
+
+    let mut market_state_data = market_state_acc.try_borrow_mut_data()?;
+    let market_state = MarketState::load_mut(&mut market_state_data)?;
+    market_state.liquidation_grace_slots = grace_slots;
+    market_state.liquidation_floor_ratio = floor_ratio;
+
+    msg!("Liquidation grace set: {} slots above a ratio of {}", grace_slots, floor_ratio);
+
+    Ok(())
+}
+
+// ---------------------------------------------------------------------
+// 7️⃣8️⃣ Flag an under-collateralized position, starting its grace period (permissionless)
+// ---------------------------------------------------------------------
+pub fn flag_for_liquidation(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramResult {
+    // Accounts:
+    // 0. [writable] position account
+    // 1. [] market state account the position trades in
+    // 2. [] clock sysvar
+    // 3. [] config account
+    // Isolated positions:
+    //   4..N. [] oracle of each collateral asset the position holds
+    // Cross-margined positions:
+    //   4. [] owner's user account
+    //   5..N. [] position and market state account of every other linked position
+    let accounts_iter = &mut accounts.iter();
+    let position_acc = next_account_info(accounts_iter)?;
+    let market_state_acc = next_account_info(accounts_iter)?;
+    let clock_sysvar = next_account_info(accounts_iter)?;
+    let config_acc = next_account_info(accounts_iter)?;
+    let remaining_accs: Vec<&AccountInfo> = accounts_iter.collect();
+
+    let config = load_config(program_id, config_acc)?;
+
+    for account in [position_acc, market_state_acc] {
+        if account.owner != program_id {
+            msg!("Account {} is not owned by the program", account.key);
+            return Err(ProgramError::IncorrectProgramId);
+        }
+    }
+
+    let clock = Clock::from_account_info(clock_sysvar)?;
+    let market_state = *MarketState::load(&market_state_acc.data.borrow())?;
+    let mut position_data = position_acc.try_borrow_mut_data()?;
+    let position = Position::load_mut(&mut position_data)?;
+    validate_position_address(program_id, position_acc.key, market_state_acc.key, position)?;
+
+    if position.margin_call_slot != 0 {
+        msg!("Position was already flagged at slot {}", position.margin_call_slot);
+        return Err(ProgramError::InvalidArgument);
+    }
+
+    // Checked on copies, so flagging settles nothing
+    let (mut trial_position, mut trial_market_state) = (*position, market_state);
+    let (_, collateral_ratio) = check_liquidatable(
+        program_id, position_acc.key, &mut trial_position, market_state_acc.key, &mut trial_market_state,
+        &config, &remaining_accs, clock.slot,
+    )?;
+
+    position.margin_call_slot = clock.slot;
+    let liquidatable_slot = clock.slot.saturating_add(market_state.liquidation_grace_slots);
+    events::emit(&events::PositionFlagged {
+        market: *market_state_acc.key,
+        position: *position_acc.key,
+        owner: position.owner,
+        collateral_ratio,
+        liquidatable_slot,
+    });
+
+    msg!("Position flagged for liquidation: ratio={}, liquidatable from slot {}", collateral_ratio, liquidatable_slot);
+
+    Ok(())
+}
+
+// ---------------------------------------------------------------------
+// 8️⃣0️⃣ Set the treasury protocol fees are withdrawn to (admin only)
+// ---------------------------------------------------------------------
+pub fn set_treasury(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramResult {
+    // Accounts:
+    // 0. [signer] admin
+    // 1. [writable] config account
+    // 2. [] treasury token account (quote token)
+    let accounts_iter = &mut accounts.iter();
+    let admin = next_account_info(accounts_iter)?;
+    let config_acc = next_account_info(accounts_iter)?;
+    let treasury = next_account_info(accounts_iter)?;
+
+    let mut config = load_admin_config(program_id, admin, config_acc)?;
+    if config.quote_mint == Pubkey::default() {
+        msg!("Quote mint not configured: run set_quote_mint");
+        return Err(ProgramError::UninitializedAccount);
+    }
+    validate_token_account(&config.token_program, treasury, &config.quote_mint, None)?;
+
+    config.treasury = *treasury.key;
+    store_account(&config, &mut config_acc.data.borrow_mut())?;
+
+    msg!("Treasury set: {}", treasury.key);
+
+    Ok(())
+}
+
+// ---------------------------------------------------------------------
+// 8️⃣1️⃣ Withdraw protocol fees from a market's fee pool to the treasury (admin only)
+// ---------------------------------------------------------------------
+pub fn withdraw_fees(program_id: &Pubkey, accounts: &[AccountInfo], amount: u64) -> ProgramResult {
+    // Accounts:
+    // 0. [signer] admin
+    // 1. [] config account
+    // 2. [] token program
+    // 3. [writable] vault token account (PDA‑owned)
+    // 4. [writable] market state account
+    // 5. [writable] treasury token account
+    // 6. [] quote mint
+    let accounts_iter = &mut accounts.iter();
+    let admin = next_account_info(accounts_iter)?;
+    let config_acc = next_account_info(accounts_iter)?;
+    let token_program = next_account_info(accounts_iter)?;
+    let vault = next_account_info(accounts_iter)?;
+    let market_state_acc = next_account_info(accounts_iter)?;
+    let treasury = next_account_info(accounts_iter)?;
+    let quote_mint = next_account_info(accounts_iter)?;
+
+    let config = load_admin_config(program_id, admin, config_acc)?;
+    let quote_decimals = validate_quote_mint(&config, token_program, quote_mint)?;
+
+    if config.treasury == Pubkey::default() {
+        msg!("Treasury not configured: run set_treasury");
+        return Err(ProgramError::UninitializedAccount);
+    }
+    if *treasury.key != config.treasury {
+        msg!("Treasury mismatch. Expected: {}, Got: {}", config.treasury, treasury.key);
+        return Err(ProgramError::InvalidArgument);
+    }
+
+    if market_state_acc.owner != program_id {
+        msg!("Market state is not owned by the program");
+        return Err(ProgramError::IncorrectProgramId);
+    }
+
+    let mut market_state_data = market_state_acc.try_borrow_mut_data()?;
+    let market_state = MarketState::load_mut(&mut market_state_data)?;
+    let (pda, bump) = validate_market_vault(program_id, token_program, vault, market_state, quote_mint)?;
+
+    // The fee pool also pays keeper rewards and repegs, so the admin picks how much to take
+    let withdrawn = market_state.quote_to_precision(amount)?;
+    if amount == 0 || withdrawn > market_state.fee_pool {
+        msg!("Withdrawal of {} must be non-zero and within the fee pool of {}", withdrawn, market_state.fee_pool);
+        return Err(ProgramError::InsufficientFunds);
+    }
+    market_state.fee_pool -= withdrawn;
+
+    let transfer_ix = create_transfer_checked_instruction(
+        token_program.key,
+        vault.key,
+        quote_mint.key,
+        treasury.key,
+        &pda,
+        amount,
+        quote_decimals,
+    )?;
+
+    invoke_signed(&transfer_ix, &[
+        vault.clone(),
+        quote_mint.clone(),
+        treasury.clone(),
+        vault.clone(), // PDA authority
+        token_program.clone(),
+    ], &[&[PDA_SEED, &[bump]]])?;
+    require_vault_covers(vault, market_state.reserves(), quote_decimals)?;
+
+    msg!("Withdrew {} protocol fees to the treasury; fee pool now {}", amount, market_state.fee_pool);
+
+    Ok(())
+}
+
+// ---------------------------------------------------------------------
+// 8️⃣2️⃣ Cap the collateral deposited into a market's positions (admin only)
+// ---------------------------------------------------------------------
+pub fn set_max_total_collateral(program_id: &Pubkey, accounts: &[AccountInfo], max_total_collateral: u64) -> ProgramResult {
+    // Accounts:
+    // 0. [signer] admin
+    // 1. [] config account
+    // 2. [writable] market state account
+    let accounts_iter = &mut accounts.iter();
+    let admin = next_account_info(accounts_iter)?;
+    let config_acc = next_account_info(accounts_iter)?;
+    let market_state_acc = next_account_info(accounts_iter)?;
+
+    load_admin_config(program_id, admin, config_acc)?;
+
+    if market_state_acc.owner != program_id {
+        msg!("Market state is not owned by the program");
+        return Err(ProgramError::IncorrectProgramId);
+    }
+
+    // A cap below the current total only blocks new deposits; nothing is returned
+    let mut market_state_data = market_state_acc.try_borrow_mut_data()?;
+    let market_state = MarketState::load_mut(&mut market_state_data)?;
+    market_state.max_total_collateral = market_state.quote_to_precision(max_total_collateral)?;
+
+    msg!("Max total collateral set: {} (currently {})", market_state.max_total_collateral, market_state.total_collateral);
+
+    Ok(())
+}
+
+// ---------------------------------------------------------------------
+// 8️⃣3️⃣ Set a market's withdrawal cooldown and per-epoch payout cap (admin only)
+// ---------------------------------------------------------------------
+pub fn set_withdrawal_limits(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    cooldown_slots: u64,
+    max_epoch_withdrawal: u64,
+) -> ProgramResult {
+    // Accounts:
+    // 0. [signer] admin
+    // 1. [] config account
+    // 2. [writable] market state account
+    let accounts_iter = &mut accounts.iter();
+    let admin = next_account_info(accounts_iter)?;
+    let config_acc = next_account_info(accounts_iter)?;
+    let market_state_acc = next_account_info(accounts_iter)?;
+
+    load_admin_config(program_id, admin, config_acc)?;
+
+    if market_state_acc.owner != program_id {
+        msg!("Market state is not owned by the program");
+        return Err(ProgramError::IncorrectProgramId);
+    }
+
+    // Bounded so the admin can delay payouts but not lock collateral away
+    if cooldown_slots > MAX_WITHDRAWAL_COOLDOWN_SLOTS {
+        msg!("Withdrawal cooldown {} exceeds the maximum of {} slots", cooldown_slots, MAX_WITHDRAWAL_COOLDOWN_SLOTS);
+        return Err(ProgramError::InvalidArgument);
+    }
+
+    let mut market_state_data = market_state_acc.try_borrow_mut_data()?;
+    let market_state = MarketState::load_mut(&mut market_state_data)?;
+    market_state.withdrawal_cooldown_slots = cooldown_slots;
+    market_state.max_epoch_withdrawal = market_state.quote_to_precision(max_epoch_withdrawal)?;
+
+    msg!("Withdrawal limits set: cooldown_slots={}, max_epoch_withdrawal={}",
+         cooldown_slots, market_state.max_epoch_withdrawal);
+
+    Ok(())
+}
+
+// ---------------------------------------------------------------------
+// 8️⃣7️⃣ Switch the program into or out of settlement-only mode (admin only)
+// ---------------------------------------------------------------------
+pub fn set_settlement_only(program_id: &Pubkey, accounts: &[AccountInfo], enabled: bool) -> ProgramResult {
+    // Accounts:
+    // 0. [signer] admin
+    // 1. [writable] config account
+    let accounts_iter = &mut accounts.iter();
+    let admin = next_account_info(accounts_iter)?;
+    let config_acc = next_account_info(accounts_iter)?;
+
+    let mut config = load_admin_config(program_id, admin, config_acc)?;
+    config.settlement_only = enabled;
+    store_account(&config, &mut config_acc.data.borrow_mut())?;
+
+    msg!("Settlement-only mode {}", if enabled { "enabled" } else { "disabled" });
+
+    Ok(())
+}
+
+// ---------------------------------------------------------------------
+// 4️⃣1️⃣ Feed the index price to a market's circuit breaker (permissionless crank)
+// ---------------------------------------------------------------------
+pub fn check_circuit_breaker(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramResult {
+    // Accounts:
+    // 0. [writable] market state account
+    // 1. [] the market's index oracle
+    // 2. [] clock sysvar
+    // 3..N. [] the market's backup oracles
+    let accounts_iter = &mut accounts.iter();
+    let market_state_acc = next_account_info(accounts_iter)?;
+    let oracle_acc = next_account_info(accounts_iter)?;
+    let clock_sysvar = next_account_info(accounts_iter)?;
+    let oracle_accs: Vec<&AccountInfo> = std::iter::once(oracle_acc).chain(accounts_iter).collect();
+
+    if market_state_acc.owner != program_id {
+        msg!("Market state is not owned by the program");
+        return Err(ProgramError::IncorrectProgramId);
+    }
+
+    let mut market_state_data = market_state_acc.try_borrow_mut_data()?;
+    let market_state = MarketState::load_mut(&mut market_state_data)?;
+    if !market_state.has_index_oracle() {
+        msg!("Market has no index oracle");
+        return Err(ProgramError::InvalidArgument);
+    }
+
+    let clock = Clock::from_account_info(clock_sysvar)?;
+    let (index, _) = read_index_price(market_state, &oracle_accs, clock.slot)?;
+    observe_circuit_breaker(market_state_acc.key, market_state, index.price, clock.slot);
+
+    msg!("Circuit breaker checked: index_price={}, reference_price={}, reduce_only_until_slot={}",
+         index.price, market_state.breaker_reference_price, market_state.reduce_only_until_slot);
+
+    Ok(())
+}
+
+// ---------------------------------------------------------------------
+// 4️⃣2️⃣ Configure a market's circuit breaker (admin only)
+// ---------------------------------------------------------------------
+pub fn set_circuit_breaker(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    max_move: u64,
+    window_slots: u64,
+    cooldown_slots: u64,
+) -> ProgramResult {
+    // Accounts:
+    // 0. [signer] admin
+    // 1. [] config account
+    // 2. [writable] market state account
+    let accounts_iter = &mut accounts.iter();
+    let admin = next_account_info(accounts_iter)?;
+    let config_acc = next_account_info(accounts_iter)?;
+    let market_state_acc = next_account_info(accounts_iter)?;
+
+    load_admin_config(program_id, admin, config_acc)?;
+
+    if market_state_acc.owner != program_id {
+        msg!("Market state is not owned by the program");
+        return Err(ProgramError::IncorrectProgramId);
+    }
+
+    if max_move > 0 && (window_slots == 0 || cooldown_slots == 0) {
+        msg!("An enabled circuit breaker needs a non-zero window and cooldown");
+        return Err(ProgramError::InvalidArgument);
+    }
+
+    let mut market_state_data = market_state_acc.try_borrow_mut_data()?;
+    let market_state = MarketState::load_mut(&mut market_state_data)?;
+    market_state.breaker_max_move = max_move;
+    market_state.breaker_window_slots = window_slots;
+    market_state.breaker_cooldown_slots = cooldown_slots;
+    // Restart observation under the new thresholds
+    market_state.breaker_reference_price = 0;
+
+    msg!("Circuit breaker set: max_move={}, window_slots={}, cooldown_slots={}",
+         max_move, window_slots, cooldown_slots);
+
+    Ok(())
+}
+
+// ---------------------------------------------------------------------
+// 4️⃣3️⃣ Wind down a market at its index price (admin only)
+// ---------------------------------------------------------------------
+pub fn settle_market(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramResult {
+    // Accounts:
+    // 0. [signer] admin
+    // 1. [] config account
+    // 2. [writable] market state account
+    // 3. [] the market's index oracle
+    // 4. [] clock sysvar
+    // 5..N. [] the market's backup oracles
+    let accounts_iter = &mut accounts.iter();
+    let admin = next_account_info(accounts_iter)?;
+    let config_acc = next_account_info(accounts_iter)?;
+    let market_state_acc = next_account_info(accounts_iter)?;
+    let oracle_acc = next_account_info(accounts_iter)?;
+    let clock_sysvar = next_account_info(accounts_iter)?;
+    let oracle_accs: Vec<&AccountInfo> = std::iter::once(oracle_acc).chain(accounts_iter).collect();
+
+    load_admin_config(program_id, admin, config_acc)?;
+
+    if market_state_acc.owner != program_id {
+        msg!("Market state is not owned by the program");
+        return Err(ProgramError::IncorrectProgramId);
+    }
+
+    let mut market_state_data = market_state_acc.try_borrow_mut_data()?;
+    let market_state = MarketState::load_mut(&mut market_state_data)?;
+    require_active(market_state)?;
+
+    if !market_state.has_index_oracle() {
+        msg!("Market has no index oracle to settle at: run set_index_oracle first");
+        return Err(ProgramError::InvalidArgument);
+    }
+
+    let clock = Clock::from_account_info(clock_sysvar)?;
+    let (index, _) = read_index_price(market_state, &oracle_accs, clock.slot)?;
+
+    // Funding stops here; positions still owe or receive what accrued up to now
+    market_state.settlement_price = index.price;
+    market_state.mark_price = index.price;
+    market_state.funding_rate_per_slot = 0;
+
+    msg!("Market {} settled at {}: open_interest={}", market_state_acc.key, index.price, market_state.open_interest);
+
+    Ok(())
+}
+
+// ---------------------------------------------------------------------
+// 4️⃣4️⃣ Close a position in a settled market at the settlement price
+// ---------------------------------------------------------------------
+pub fn settle_position(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramResult {
+    // Accounts:
+    // 0. [signer] user (position owner, or the holder of a tokenized position's NFT)
+    // 1. [] token program
+    // 2. [writable] user's token account (to receive collateral)
+    // 3. [writable] vault token account (PDA‑owned)
+    // 4. [writable] position account
+    // 5. [writable] market state account
+    // 6. [] config account
+    // 7. [] quote mint
+    // Cross-margined positions (PnL stays in the shared collateral; nothing is paid out):
+    //   8. [writable] owner's user account
+    //   9..N. [] position and market state account of every other linked position
+    // Tokenized positions:
+    //   8. [] signer's token account holding the position's NFT
+    let accounts_iter = &mut accounts.iter();
+    let user = next_account_info(accounts_iter)?;
+    let token_program = next_account_info(accounts_iter)?;
+    let user_token_acc = next_account_info(accounts_iter)?;
+    let vault = next_account_info(accounts_iter)?;
+    let position_acc = next_account_info(accounts_iter)?;
+    let market_state_acc = next_account_info(accounts_iter)?;
+    let config_acc = next_account_info(accounts_iter)?;
+    let quote_mint = next_account_info(accounts_iter)?;
+    let remaining_accs: Vec<&AccountInfo> = accounts_iter.collect();
+
+    if !user.is_signer {
+        msg!("User must be signer");
+        return Err(ProgramError::MissingRequiredSignature);
+    }
+
+    for account in [position_acc, market_state_acc] {
+        if account.owner != program_id {
+            msg!("Account {} is not owned by the program", account.key);
+            return Err(ProgramError::IncorrectProgramId);
+        }
+    }
+
+    let config = load_config(program_id, config_acc)?;
+    let quote_decimals = validate_quote_mint(&config, token_program, quote_mint)?;
+    validate_token_account(token_program.key, user_token_acc, quote_mint.key, None)?;
+
+    let mut market_state_data = market_state_acc.try_borrow_mut_data()?;
+    let market_state = MarketState::load_mut(&mut market_state_data)?;
+    let (pda, bump) = validate_market_vault(program_id, token_program, vault, market_state, quote_mint)?;
+    let mut position_data = position_acc.try_borrow_mut_data()?;
+    let position = Position::load_mut(&mut position_data)?;
+
+    if !market_state.is_settled() {
+        msg!("Market is still trading: use close_position");
+        return Err(ProgramError::InvalidArgument);
+    }
+
+    position_token::require_position_authority(position, user.key, &remaining_accs)?;
+
+    // The settlement price is this market's, so the position must trade in it
+    validate_position_address(program_id, position_acc.key, market_state_acc.key, position)?;
+
+    let settlement_price = market_state.settlement_price;
+    let bad_debt_before = market_state.bad_debt;
+    let base_delta = position.base_amount
+        .checked_neg()
+        .ok_or(ProgramError::InvalidArgument)?;
+
+    if position.is_cross_margin() {
+        let (mut cross_margin, _) =
+            CrossMargin::load(program_id, position_acc.key, position, market_state_acc.key, &remaining_accs, false)?;
+        // Settlement is final, so all funding owed is collected
+        cross_margin.apply_funding(position, market_state, 0)?;
+        if base_delta != 0 {
+            cross_margin.apply_position_change(position, market_state, base_delta, settlement_price)?;
+        }
+        cross_margin.store()?;
+        emit_bad_debt(market_state_acc.key, position_acc.key, market_state, bad_debt_before);
+
+        msg!("Cross-margined position settled at {}: shared collateral={}",
+             settlement_price, cross_margin.user_account.collateral);
+        return Ok(());
+    }
+
+    apply_funding(position, market_state, 0)?;
+    if base_delta != 0 {
+        apply_position_change(position, market_state, base_delta, settlement_price)?;
+    }
+    emit_bad_debt(market_state_acc.key, position_acc.key, market_state, bad_debt_before);
+
+    let returned_collateral = position.collateral;
+    let clock = Clock::get()?;
+    market_state.record_withdrawal(position, returned_collateral, clock.slot, clock.epoch)?;
+    release_deposits(position, market_state);
+    if returned_collateral > 0 {
+        let transfer_ix = create_transfer_checked_instruction(
+            token_program.key,
+            vault.key,
+            quote_mint.key,
+            user_token_acc.key,
+            &pda,
+            market_state.quote_from_precision(returned_collateral)?,
+            quote_decimals,
+        )?;
+
+        invoke_signed(&transfer_ix, &[
+            vault.clone(),
+            quote_mint.clone(),
+            user_token_acc.clone(),
+            vault.clone(), // PDA authority
+            token_program.clone(),
+        ], &[&[PDA_SEED, &[bump]]])?;
+        require_vault_covers(vault, market_state.reserves(), quote_decimals)?;
+    }
+
+    position.collateral = 0;
+    position.entry_price = 0;
+
+    msg!("Position settled at {}: returned_collateral={}, remaining_open_interest={}",
+         settlement_price, returned_collateral, market_state.open_interest);
+
+    Ok(())
+}
+
+// ---------------------------------------------------------------------
+// 4️⃣5️⃣ Settle an expired dated market at the oracle TWAP (permissionless)
+// ---------------------------------------------------------------------
+pub fn expire_market(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramResult {
+    // Accounts:
+    // 0. [writable] market state account
+    // 1. [] the market's index oracle
+    // 2. [] clock sysvar
+    let accounts_iter = &mut accounts.iter();
+    let market_state_acc = next_account_info(accounts_iter)?;
+    let oracle_acc = next_account_info(accounts_iter)?;
+    let clock_sysvar = next_account_info(accounts_iter)?;
+
+    if market_state_acc.owner != program_id {
+        msg!("Market state is not owned by the program");
+        return Err(ProgramError::IncorrectProgramId);
+    }
+
+    let mut market_state_data = market_state_acc.try_borrow_mut_data()?;
+    let market_state = MarketState::load_mut(&mut market_state_data)?;
+    require_active(market_state)?;
+
+    let clock = Clock::from_account_info(clock_sysvar)?;
+    if !market_state.is_expired(clock.unix_timestamp) {
+        msg!("Market is not a dated future past its expiry: expiry={}, now={}",
+             market_state.expiry_timestamp, clock.unix_timestamp);
+        return Err(ProgramError::InvalidArgument);
+    }
+
+    if !market_state.has_index_oracle() {
+        msg!("Market has no index oracle to settle at: run set_index_oracle first");
+        return Err(ProgramError::InvalidArgument);
+    }
+
+    let twap = oracle::read_oracle_twap(oracle_acc, &market_state.index_oracle, clock.slot)?;
+    market_state.settlement_price = twap;
+    market_state.mark_price = twap;
+
+    msg!("Market {} expired and settled at TWAP {}: open_interest={}",
+         market_state_acc.key, twap, market_state.open_interest);
+
+    Ok(())
+}
+
+// ---------------------------------------------------------------------
+// 4️⃣6️⃣ Make a market a dated future expiring at a timestamp (admin only)
+// ---------------------------------------------------------------------
+pub fn set_market_expiry(program_id: &Pubkey, accounts: &[AccountInfo], expiry_timestamp: i64) -> ProgramResult {
+    // Accounts:
+    // 0. [signer] admin
+    // 1. [] config account
+    // 2. [writable] market state account
+    // 3. [] clock sysvar
+    let accounts_iter = &mut accounts.iter();
+    let admin = next_account_info(accounts_iter)?;
+    let config_acc = next_account_info(accounts_iter)?;
+    let market_state_acc = next_account_info(accounts_iter)?;
+    let clock_sysvar = next_account_info(accounts_iter)?;
+
+    load_admin_config(program_id, admin, config_acc)?;
+
+    if market_state_acc.owner != program_id {
+        msg!("Market state is not owned by the program");
+        return Err(ProgramError::IncorrectProgramId);
+    }
+
+    let mut market_state_data = market_state_acc.try_borrow_mut_data()?;
+    let market_state = MarketState::load_mut(&mut market_state_data)?;
+    require_active(market_state)?;
+
+    let clock = Clock::from_account_info(clock_sysvar)?;
+    if expiry_timestamp <= clock.unix_timestamp || market_state.is_expired(clock.unix_timestamp) {
+        msg!("Expiry must be in the future and the market not already expired: expiry={}, now={}",
+             expiry_timestamp, clock.unix_timestamp);
+        return Err(ProgramError::InvalidArgument);
+    }
+
+    // Funding stops accruing; pending funding is still applied to positions
+    market_state.expiry_timestamp = expiry_timestamp;
+    market_state.funding_rate_per_slot = 0;
+
+    msg!("Market {} expires at {}", market_state_acc.key, expiry_timestamp);
+
+    Ok(())
+}
+
+// ---------------------------------------------------------------------
+// 5️⃣0️⃣ Create a market with its own size unit (admin only)
+// ---------------------------------------------------------------------
+pub fn initialize_market(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    market_id: u16,
+    initial_price: u64,
+    base_decimals: u8,
+) -> ProgramResult {
+    // Accounts:
+    // 0. [signer, writable] admin (pays for the account)
+    // 1. [writable] market state account (PDA: [MARKET_SEED, market_id])
+    // 2. [] rent sysvar
+    // 3. [] clock sysvar
+    // 4. [] system program
+    // 5. [writable] config account (counts the market)
+    // 6. [] token program
+    // 7. [] quote mint
+    let accounts_iter = &mut accounts.iter();
+    let admin = next_account_info(accounts_iter)?;
+    let market_state_acc = next_account_info(accounts_iter)?;
+    let rent_sysvar = next_account_info(accounts_iter)?;
+    let clock_sysvar = next_account_info(accounts_iter)?;
+    let system_program = next_account_info(accounts_iter)?;
+    let config_acc = next_account_info(accounts_iter)?;
+    let token_program = next_account_info(accounts_iter)?;
+    let quote_mint = next_account_info(accounts_iter)?;
+
+    let mut config = load_admin_config(program_id, admin, config_acc)?;
+    let quote_decimals = validate_quote_mint(&config, token_program, quote_mint)?;
+
+    let (expected_market, market_bump) = market_address(program_id, market_id);
+    if *market_state_acc.key != expected_market {
+        msg!("Market state is not the correct PDA for market {}. Expected: {}, Got: {}",
+             market_id, expected_market, market_state_acc.key);
+        return Err(ProgramError::InvalidArgument);
+    }
+
+    if !market_state_acc.data_is_empty() {
+        msg!("Market state account is already initialized");
+        return Err(ProgramError::AccountAlreadyInitialized);
+    }
+
+    if initial_price == 0 {
+        msg!("An initial price is required to seed the vAMM");
+        return Err(ProgramError::InvalidArgument);
+    }
+
+    // Amounts are normalized up to 1e9 precision, so finer units would lose digits
+    if base_decimals > math::PRECISION_DECIMALS || quote_decimals > math::PRECISION_DECIMALS {
+        msg!("Base and quote decimals must be at most {}: base={}, quote={}",
+             math::PRECISION_DECIMALS, base_decimals, quote_decimals);
+        return Err(ProgramError::InvalidArgument);
+    }
+
+    let clock = Clock::from_account_info(clock_sysvar)?;
+    let rent = Rent::from_account_info(rent_sysvar)?;
+
+    invoke_signed(
+        &system_instruction::create_account(
+            admin.key,
+            market_state_acc.key,
+            rent.minimum_balance(MarketState::SPACE),
+            MarketState::SPACE as u64,
+            program_id,
+        ),
+        &[admin.clone(), market_state_acc.clone(), system_program.clone()],
+        &[&[MARKET_SEED, &market_id.to_le_bytes(), &[market_bump]]],
+    )?;
+
+    *MarketState::init(&mut market_state_acc.try_borrow_mut_data()?)? = MarketState {
+        last_funding_slot: clock.slot,
+        mark_price: initial_price,
+        base_reserve: DEFAULT_VAMM_BASE_RESERVE,
+        quote_reserve: calculate_quote_reserve(DEFAULT_VAMM_BASE_RESERVE, initial_price)?,
+        max_leverage: DEFAULT_MAX_LEVERAGE,
+        max_open_interest: DEFAULT_MAX_OPEN_INTEREST,
+        bump: config.vault_bump,
+        version: MarketState::VERSION,
+        market_bump,
+        market_id,
+        mark_price_band: DEFAULT_MARK_PRICE_BAND,
+        breaker_max_move: DEFAULT_BREAKER_MAX_MOVE,
+        breaker_window_slots: DEFAULT_BREAKER_WINDOW_SLOTS,
+        breaker_cooldown_slots: DEFAULT_BREAKER_COOLDOWN_SLOTS,
+        base_decimals,
+        quote_decimals,
+        quote_mint: *quote_mint.key,
+        ..MarketState::default()
+    };
+
+    config.market_count = config.market_count.max(u32::from(market_id) + 1);
+    store_account(&config, &mut config_acc.data.borrow_mut())?;
+
+    msg!("Initialized market {} at {}: price={}, base_decimals={}, quote_decimals={}",
+         market_id, market_state_acc.key, initial_price, base_decimals, quote_decimals);
+
+    Ok(())
+}
+
+// ---------------------------------------------------------------------
+// 5️⃣2️⃣ Haircut winning positions to pay off outstanding bad debt (permissionless crank)
+// ---------------------------------------------------------------------
+pub fn socialize_loss(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramResult {
+    // Accounts:
+    // 0. [writable] market state account
+    let accounts_iter = &mut accounts.iter();
+    let market_state_acc = next_account_info(accounts_iter)?;
+
+    if market_state_acc.owner != program_id {
+        msg!("Market state is not owned by the program");
+        return Err(ProgramError::IncorrectProgramId);
+    }
+
+    let mut market_state_data = market_state_acc.try_borrow_mut_data()?;
+    let market_state = MarketState::load_mut(&mut market_state_data)?;
+    if market_state.outstanding_bad_debt == 0 {
+        msg!("Market has no outstanding bad debt");
+        return Err(ProgramError::InvalidArgument);
+    }
+
+    // The insurance fund may have been refilled since the debt was incurred; it pays first
+    let covered = market_state.outstanding_bad_debt.min(market_state.insurance_fund);
+    market_state.insurance_fund -= covered;
+    market_state.outstanding_bad_debt -= covered;
+    market_state.total_realized_loss = market_state.total_realized_loss
+        .checked_add(covered)
+        .ok_or(ProgramError::InvalidArgument)?;
+
+    let haircut = socialize_outstanding_bad_debt(market_state)?;
+    events::emit(&events::LossSocialized {
+        market: *market_state_acc.key,
+        insurance_covered: covered,
+        haircut,
+        long_loss_index: market_state.long_loss_index,
+        short_loss_index: market_state.short_loss_index,
+        outstanding_bad_debt: market_state.outstanding_bad_debt,
+    });
+
+    msg!("Loss socialized: insurance_covered={}, haircut={}, outstanding_bad_debt={}",
+         covered, haircut, market_state.outstanding_bad_debt);
+
+    Ok(())
+}
+
+// ---------------------------------------------------------------------
+// 5️⃣3️⃣ Repeg the vAMM to the index price (admin only)
+// ---------------------------------------------------------------------
+pub fn repeg_vamm(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramResult {
+    // Accounts:
+    // 0. [signer] admin
+    // 1. [] config account
+    // 2. [writable] market state account
+    // 3. [] the market's index oracle
+    // 4. [] clock sysvar
+    // 5..N. [] the market's backup oracles
+    let accounts_iter = &mut accounts.iter();
+    let admin = next_account_info(accounts_iter)?;
+    let config_acc = next_account_info(accounts_iter)?;
+    let market_state_acc = next_account_info(accounts_iter)?;
+    let oracle_acc = next_account_info(accounts_iter)?;
+    let clock_sysvar = next_account_info(accounts_iter)?;
+    let oracle_accs: Vec<&AccountInfo> = std::iter::once(oracle_acc).chain(accounts_iter).collect();
+
+    load_admin_config(program_id, admin, config_acc)?;
+
+    if market_state_acc.owner != program_id {
+        msg!("Market state is not owned by the program");
+        return Err(ProgramError::IncorrectProgramId);
+    }
+
+    let mut market_state_data = market_state_acc.try_borrow_mut_data()?;
+    let market_state = MarketState::load_mut(&mut market_state_data)?;
+    if !market_state.has_index_oracle() {
+        msg!("Market has no index oracle");
+        return Err(ProgramError::InvalidArgument);
+    }
+
+    let clock = Clock::from_account_info(clock_sysvar)?;
+    let (index, _) = read_index_price(market_state, &oracle_accs, clock.slot)?;
+
+    // Traders' unrealized PnL moves with the curve price: the fee pool pays for the move
+    // in their favour and keeps what they lose
+    let spot_price = calculate_vamm_price(market_state.base_reserve, market_state.quote_reserve)?;
+    let target_price = calculate_repeg_price(spot_price, index.price, market_state.net_base_amount, market_state.fee_pool)?;
+    if target_price == spot_price {
+        msg!("vAMM already at {}: nothing to repeg", spot_price);
+        return Err(ProgramError::InvalidArgument);
+    }
+
+    market_state.quote_reserve = calculate_quote_reserve(market_state.base_reserve, target_price)?;
+    market_state.mark_price = calculate_vamm_price(market_state.base_reserve, market_state.quote_reserve)?;
+    let repeg_cost = calculate_repeg_cost(market_state.net_base_amount, spot_price, market_state.mark_price)?;
+    market_state.fee_pool = if repeg_cost > 0 {
+        market_state.fee_pool.saturating_sub(repeg_cost.unsigned_abs())
+    } else {
+        market_state.fee_pool
+            .checked_add(repeg_cost.unsigned_abs())
+            .ok_or(ProgramError::InvalidArgument)?
+    };
+
+    msg!("vAMM repegged: price {} -> {} (index {}), cost={}, fee_pool={}",
+         spot_price, market_state.mark_price, index.price, repeg_cost, market_state.fee_pool);
+
+    Ok(())
+}
+
+// ---------------------------------------------------------------------
+// 5️⃣8️⃣ Replace the volume-based taker fee tiers (admin only)
+// ---------------------------------------------------------------------
+pub fn set_fee_tiers(program_id: &Pubkey, accounts: &[AccountInfo], fee_tiers: Vec<FeeTier>) -> ProgramResult {
     // Accounts:
-    // 0. [writable] market state PDA
-    // 1. [] clock sysvar
+    // 0. [signer] admin
+    // 1. [writable] config account
+    let accounts_iter = &mut accounts.iter();
+    let admin = next_account_info(accounts_iter)?;
+    let config_acc = next_account_info(accounts_iter)?;
+
+    let mut config = load_admin_config(program_id, admin, config_acc)?;
+
+    if fee_tiers.len() > MAX_FEE_TIERS {
+        msg!("At most {} fee tiers, got {}", MAX_FEE_TIERS, fee_tiers.len());
+        return Err(ProgramError::InvalidArgument);
+    }
+
+    if fee_tiers.windows(2).any(|pair| pair[1].min_volume <= pair[0].min_volume) {
+        msg!("Fee tiers must be in ascending order of min_volume");
+        return Err(ProgramError::InvalidArgument);
+    }
+
+    if let Some(tier) = fee_tiers.iter().find(|tier| tier.trading_fee > PRECISION) {
+        msg!("Tier trading fee must not exceed 100%: {}", tier.trading_fee);
+        return Err(ProgramError::InvalidArgument);
+    }
+
+    config.fee_tiers = fee_tiers;
+    store_account(&config, &mut config_acc.data.borrow_mut())?;
+
+    for tier in &config.fee_tiers {
+        msg!("Fee tier: min_volume={}, trading_fee={}", tier.min_volume, tier.trading_fee);
+    }
+    msg!("Set {} fee tiers", config.fee_tiers.len());
+
+    Ok(())
+}
+
+// ---------------------------------------------------------------------
+// 6️⃣2️⃣ Replace a market's position-size risk tiers (admin only)
+// ---------------------------------------------------------------------
+pub fn set_risk_tiers(program_id: &Pubkey, accounts: &[AccountInfo], risk_tiers: Vec<RiskTier>) -> ProgramResult {
+    // Accounts:
+    // 0. [signer] admin
+    // 1. [] config account
+    // 2. [writable] market state account
+    let accounts_iter = &mut accounts.iter();
+    let admin = next_account_info(accounts_iter)?;
+    let config_acc = next_account_info(accounts_iter)?;
+    let market_state_acc = next_account_info(accounts_iter)?;
+
+    load_admin_config(program_id, admin, config_acc)?;
+
+    if market_state_acc.owner != program_id {
+        msg!("Market state is not owned by the program");
+        return Err(ProgramError::IncorrectProgramId);
+    }
+
+    if risk_tiers.len() > MAX_RISK_TIERS {
+        msg!("At most {} risk tiers, got {}", MAX_RISK_TIERS, risk_tiers.len());
+        return Err(ProgramError::InvalidArgument);
+    }
+
+    if risk_tiers.first().is_some_and(|tier| tier.min_notional == 0)
+        || risk_tiers.windows(2).any(|pair| pair[1].min_notional <= pair[0].min_notional)
+    {
+        msg!("Risk tiers must be in ascending order of a non-zero min_notional");
+        return Err(ProgramError::InvalidArgument);
+    }
+
+    // Larger positions never need less margin than smaller ones
+    if risk_tiers.first().is_some_and(|tier| tier.min_collateral_ratio == 0)
+        || risk_tiers.windows(2).any(|pair| pair[1].min_collateral_ratio < pair[0].min_collateral_ratio)
+    {
+        msg!("Risk tier collateral ratios must be non-zero and must not decrease");
+        return Err(ProgramError::InvalidArgument);
+    }
+
+    let mut market_state_data = market_state_acc.try_borrow_mut_data()?;
+    let market_state = MarketState::load_mut(&mut market_state_data)?;
+    market_state.risk_tiers = [RiskTier::default(); MAX_RISK_TIERS];
+    market_state.risk_tiers[..risk_tiers.len()].copy_from_slice(&risk_tiers);
+
+    for tier in &risk_tiers {
+        msg!("Risk tier: min_notional={}, min_collateral_ratio={}", tier.min_notional, tier.min_collateral_ratio);
+    }
+    msg!("Set {} risk tiers", risk_tiers.len());
+
+    Ok(())
+}
+
+// ---------------------------------------------------------------------
+// 7️⃣1️⃣ Configure a market's mark price EMA (admin only)
+// ---------------------------------------------------------------------
+pub fn set_mark_price_ema(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    half_life_slots: u64,
+    ema_liquidation: bool,
+) -> ProgramResult {
+    // Accounts:
+    // 0. [signer] admin
+    // 1. [] config account
+    // 2. [writable] market state account
+    // 3. [] clock sysvar
     let accounts_iter = &mut accounts.iter();
+    let admin = next_account_info(accounts_iter)?;
+    let config_acc = next_account_info(accounts_iter)?;
     let market_state_acc = next_account_info(accounts_iter)?;
     let clock_sysvar = next_account_info(accounts_iter)?;
 
+    load_admin_config(program_id, admin, config_acc)?;
+
+    if market_state_acc.owner != program_id {
+        msg!("Market state is not owned by the program");
+        return Err(ProgramError::IncorrectProgramId);
+    }
+
+    // An EMA that follows the mark would dampen nothing
+    if ema_liquidation && half_life_slots == 0 {
+        msg!("EMA liquidation checks need a non-zero half-life");
+        return Err(ProgramError::InvalidArgument);
+    }
+
     let clock = Clock::from_account_info(clock_sysvar)?;
-    let mut market_state = MarketState::try_from_slice(&market_state_acc.data.borrow())?;
+    let mut market_state_data = market_state_acc.try_borrow_mut_data()?;
+    let market_state = MarketState::load_mut(&mut market_state_data)?;
 
-    // Calculate slots elapsed since last funding update
-    let slots_elapsed = clock.slot
-        .checked_sub(market_state.last_funding_slot)
-        .ok_or(ProgramError::InvalidAccountData)?;
+    // Sample at the old half-life first so the new one only weighs prices from here on
+    market_state.update_mark_price_ema(clock.slot)?;
+    market_state.ema_half_life_slots = half_life_slots;
+    market_state.ema_liquidation = u8::from(ema_liquidation);
 
-    if slots_elapsed == 0 {
-        msg!("No slots elapsed since last funding update");
-        return Ok(());
+    msg!("Mark price EMA set: half_life_slots={}, ema_liquidation={}, ema={}",
+         half_life_slots, ema_liquidation, market_state.mark_price_ema);
+
+    Ok(())
+}
+
+/// Spread the market's outstanding bad debt over the unrealized profits of winning positions
+/// at the mark price: each side in profit takes a share proportional to its profit, charged
+/// per unit of size through its loss index. The haircut never exceeds the profits; returns it.
+fn socialize_outstanding_bad_debt(market_state: &mut MarketState) -> Result<u64, ProgramError> {
+    let (long_pnl, short_pnl) = calculate_side_pnl(market_state, market_state.mark_price)?;
+    let long_profit = u64::try_from(long_pnl).unwrap_or(0);
+    let short_profit = u64::try_from(short_pnl).unwrap_or(0);
+    let total_profit = u128::from(long_profit) + u128::from(short_profit);
+    if total_profit == 0 {
+        return Ok(0);
     }
 
-    // Simple funding rate calculation:
-    // - Base rate: 0.01% per slot (10_000 per slot when scaled by 1e9)
-    // - Adjusted by open interest imbalance (in practice, use more sophisticated model)
-    let base_rate = 10_000i64; // 0.01% * 1e9 = 10_000
-    
-    // In production, this would consider:
-    // - Interest rate differentials
-    // - Long/short imbalance
-    // - Market volatility
-    // - External funding rates
-    market_state.funding_rate_per_slot = if market_state.open_interest > 1_000_000_000 {
-        base_rate.checked_mul(2).ok_or(ProgramError::InvalidArgument)?  // Higher rate for higher OI
-    } else {
-        base_rate
-    };
+    let haircut = math::to_u64(u128::from(market_state.outstanding_bad_debt).min(total_profit))?;
+    let short_base_amount = market_state.net_base_amount
+        .checked_sub(market_state.long_base_amount)
+        .ok_or(ProgramError::InvalidArgument)?;
+    let mut charged = 0;
+    for (profit, base_amount, loss_index) in [
+        (long_profit, market_state.long_base_amount, &mut market_state.long_loss_index),
+        (short_profit, short_base_amount, &mut market_state.short_loss_index),
+    ] {
+        if profit == 0 || base_amount == 0 {
+            continue;
+        }
+        // Rounded down, so positions are never charged more than the debt
+        let share = math::mul_div(haircut.into(), profit.into(), total_profit)?;
+        let index_delta = math::to_u64(math::mul_div(share, PRECISION.into(), base_amount.unsigned_abs().into())?)?;
+        *loss_index = loss_index
+            .checked_add(index_delta)
+            .ok_or(ProgramError::InvalidArgument)?;
+        charged += math::to_u64(share)?;
+    }
 
-    // Accumulate funding index
-    let funding_increment = market_state.funding_rate_per_slot
-        .checked_mul(slots_elapsed as i64)
+    market_state.outstanding_bad_debt -= charged;
+    market_state.socialized_loss = market_state.socialized_loss
+        .checked_add(charged)
         .ok_or(ProgramError::InvalidArgument)?;
-        
-    market_state.funding_index = market_state.funding_index
-        .checked_add(funding_increment)
+
+    Ok(charged)
+}
+
+/// Look up a registered collateral asset and check `asset_vault` is its vault
+fn collateral_asset<'a>(
+    config: &'a Config,
+    asset_index: usize,
+    asset_vault: &AccountInfo,
+) -> Result<&'a CollateralAsset, ProgramError> {
+    let asset = config.collateral_assets.get(asset_index).ok_or_else(|| {
+        msg!("Unknown collateral asset index: {}", asset_index);
+        ProgramError::InvalidArgument
+    })?;
+
+    if asset.vault != *asset_vault.key {
+        msg!("Asset vault mismatch. Expected: {}, Got: {}", asset.vault, asset_vault.key);
+        return Err(ProgramError::InvalidArgument);
+    }
+
+    Ok(asset)
+}
+
+/// Number of collateral assets a position holds a non-zero balance of
+fn held_asset_count(position: &Position) -> usize {
+    position.collateral_balances.iter().filter(|balance| **balance > 0).count()
+}
+
+/// Weighted quote value of a position's non-quote collateral
+///
+/// `oracle_accs` must contain the oracle of every asset the position holds;
+/// they are matched by key, so order doesn't matter.
+fn calculate_weighted_asset_collateral(
+    position: &Position,
+    config: &Config,
+    oracle_accs: &[&AccountInfo],
+    current_slot: u64,
+) -> Result<u64, ProgramError> {
+    let mut total = 0u64;
+    for (index, balance) in position.collateral_balances.iter().enumerate() {
+        if *balance == 0 {
+            continue;
+        }
+
+        let asset = config.collateral_assets.get(index).ok_or(ProgramError::InvalidAccountData)?;
+        let oracle_acc = oracle_accs.iter().find(|acc| *acc.key == asset.oracle).ok_or_else(|| {
+            msg!("Missing oracle {} for collateral asset {}", asset.oracle, index);
+            ProgramError::NotEnoughAccountKeys
+        })?;
+
+        let oracle_price = oracle::read_oracle_price(oracle_acc, &asset.oracle, current_slot)?;
+        let value = calculate_asset_collateral_value(*balance, asset.decimals, oracle_price.price, asset.weight)?;
+        total = total.checked_add(value).ok_or(ProgramError::InvalidArgument)?;
+    }
+
+    Ok(total)
+}
+
+/// Create a program-owned PDA of `space` bytes funded by `payer`. An address that
+/// already holds lamports is topped up to rent exemption, then allocated and assigned,
+/// so a caller program whose signer can't fund a system transfer (a PDA carrying data)
+/// can send the rent ahead
+fn create_program_account<'a>(
+    program_id: &Pubkey,
+    payer: &AccountInfo<'a>,
+    account: &AccountInfo<'a>,
+    system_program: &AccountInfo<'a>,
+    rent: &Rent,
+    space: usize,
+    seeds: &[&[u8]],
+) -> ProgramResult {
+    let required_lamports = rent.minimum_balance(space);
+    if account.lamports() == 0 {
+        let create_ix = system_instruction::create_account(
+            payer.key,
+            account.key,
+            required_lamports,
+            space as u64,
+            program_id,
+        );
+        return invoke_signed(&create_ix, &[payer.clone(), account.clone(), system_program.clone()], &[seeds]);
+    }
+
+    let shortfall = required_lamports.saturating_sub(account.lamports());
+    if shortfall > 0 {
+        invoke(
+            &system_instruction::transfer(payer.key, account.key, shortfall),
+            &[payer.clone(), account.clone(), system_program.clone()],
+        )?;
+    }
+    invoke_signed(
+        &system_instruction::allocate(account.key, space as u64),
+        &[account.clone(), system_program.clone()],
+        &[seeds],
+    )?;
+    invoke_signed(
+        &system_instruction::assign(account.key, program_id),
+        &[account.clone(), system_program.clone()],
+        &[seeds],
+    )
+}
+
+/// Close a program account: move its lamports to `recipient`, drop its data and
+/// hand it back to the system program
+fn close_program_account(account: &AccountInfo, recipient: &AccountInfo) -> ProgramResult {
+    let lamports = account.lamports();
+    **recipient.try_borrow_mut_lamports()? = recipient
+        .lamports()
+        .checked_add(lamports)
         .ok_or(ProgramError::InvalidArgument)?;
+    **account.try_borrow_mut_lamports()? = 0;
+
+    account.data.borrow_mut().fill(0);
+    account.realloc(0, false)?;
+    account.assign(&system_program::id());
+
+    Ok(())
+}
+
+/// Read a mint's decimals after checking it belongs to a supported token program
+fn read_mint_decimals(mint: &AccountInfo) -> Result<u8, ProgramError> {
+    if !is_supported_token_program(mint.owner) {
+        msg!("Mint {} is not owned by SPL Token or Token-2022: {}", mint.key, mint.owner);
+        return Err(ProgramError::IncorrectProgramId);
+    }
+
+    let mint_data = mint.try_borrow_data()?;
+    mint_data.get(MINT_DECIMALS_OFFSET).copied().ok_or_else(|| {
+        msg!("Mint account data too short");
+        ProgramError::InvalidAccountData
+    })
+}
+
+/// Check `mint` is `expected_mint` and is owned by `token_program`; returns its decimals
+fn validate_mint(token_program: &AccountInfo, mint: &AccountInfo, expected_mint: &Pubkey) -> Result<u8, ProgramError> {
+    if mint.key != expected_mint {
+        msg!("Mint mismatch. Expected: {}, Got: {}", expected_mint, mint.key);
+        return Err(ProgramError::InvalidArgument);
+    }
+
+    if mint.owner != token_program.key {
+        msg!("Mint {} is owned by {}, not token program {}", mint.key, mint.owner, token_program.key);
+        return Err(ProgramError::IncorrectProgramId);
+    }
+
+    read_mint_decimals(mint)
+}
+
+/// Check `token_account` is an initialized `token_program` account of `mint`, held by
+/// `authority` when one is given
+///
+/// Token account layout (shared by Token-2022): mint at 0..32, owner at 32..64.
+pub(crate) fn validate_token_account(
+    token_program: &Pubkey,
+    token_account: &AccountInfo,
+    mint: &Pubkey,
+    authority: Option<&Pubkey>,
+) -> ProgramResult {
+    if token_account.owner != token_program {
+        msg!("Token account {} is owned by {}, not token program {}",
+             token_account.key, token_account.owner, token_program);
+        return Err(ProgramError::IncorrectProgramId);
+    }
+
+    let data = token_account.try_borrow_data()?;
+    if data.len() < TOKEN_ACCOUNT_LEN || data[TOKEN_ACCOUNT_STATE_OFFSET] == 0 {
+        msg!("Account {} is not an initialized token account", token_account.key);
+        return Err(ProgramError::InvalidAccountData);
+    }
+
+    if data[0..32] != mint.to_bytes() {
+        msg!("Token account {} does not hold mint {}", token_account.key, mint);
+        return Err(ProgramError::InvalidAccountData);
+    }
+
+    if let Some(authority) = authority {
+        if data[32..64] != authority.to_bytes() {
+            msg!("Token account {} is not owned by {}", token_account.key, authority);
+            return Err(ProgramError::IllegalOwner);
+        }
+    }
+
+    Ok(())
+}
+
+/// Balance of a token account already checked with `validate_token_account`
+fn token_account_amount(token_account: &AccountInfo) -> Result<u64, ProgramError> {
+    let data = token_account.try_borrow_data()?;
+    let amount = data
+        .get(TOKEN_ACCOUNT_AMOUNT_OFFSET..TOKEN_ACCOUNT_AMOUNT_OFFSET + 8)
+        .ok_or(ProgramError::InvalidAccountData)?;
+    Ok(u64::from_le_bytes(amount.try_into().map_err(|_| ProgramError::InvalidAccountData)?))
+}
+
+/// Check `program_data` is the ProgramData account of `program_id` under the upgradeable
+/// loader and names `authority` as the upgrade authority
+///
+/// ProgramData layout (bincode `UpgradeableLoaderState::ProgramData`): state tag (u32) at 0..4,
+/// deployment slot (u64) at 4..12, upgrade authority as an `Option<Pubkey>` at 12..45.
+fn require_upgrade_authority(program_id: &Pubkey, program_data: &AccountInfo, authority: &Pubkey) -> ProgramResult {
+    let (expected, _) = Pubkey::find_program_address(&[program_id.as_ref()], &bpf_loader_upgradeable::id());
+    if *program_data.key != expected || *program_data.owner != bpf_loader_upgradeable::id() {
+        msg!("Program data account is not the program's. Expected: {}, Got: {}", expected, program_data.key);
+        return Err(ProgramError::InvalidArgument);
+    }
+
+    let data = program_data.try_borrow_data()?;
+    if data.len() < PROGRAM_DATA_METADATA_LEN || data[0..4] != PROGRAM_DATA_STATE_TAG.to_le_bytes() {
+        msg!("Account {} is not a ProgramData account", program_data.key);
+        return Err(ProgramError::InvalidAccountData);
+    }
+
+    if data[12] == 0 || data[13..45] != authority.to_bytes() {
+        msg!("{} is not the program's upgrade authority", authority);
+        return Err(ProgramError::IllegalOwner);
+    }
+
+    Ok(())
+}
+
+/// Check the vault still covers `tracked` (1e9 precision) after paying out of it: the balances
+/// the paying instruction can see, so a payout can never dip into them. The vault-wide total
+/// across every market, pool and user account is checked off-chain by `client::check_vault_solvency`.
+pub(crate) fn require_vault_covers(vault: &AccountInfo, tracked: u64, quote_decimals: u8) -> ProgramResult {
+    let balance = token_account_amount(vault)?;
+    let tracked = math::from_precision(tracked, quote_decimals)?;
+    if balance < tracked {
+        msg!("Vault holds {} after the payout, less than the {} tracked in it", balance, tracked);
+        return Err(ProgramError::InsufficientFunds);
+    }
+
+    Ok(())
+}
+
+/// Vault PDA for a stored `vault_bump`; also the authority of the vault and every program token account
+pub(crate) fn vault_pda(program_id: &Pubkey, vault_bump: u8) -> Result<Pubkey, ProgramError> {
+    Ok(Pubkey::create_program_address(&[PDA_SEED, &[vault_bump]], program_id)?)
+}
+
+/// Check `vault` is the program's quote vault: the token account at the vault PDA, holding
+/// `quote_mint` and owned by the PDA itself. Returns the PDA for signing.
+pub(crate) fn validate_vault(
+    program_id: &Pubkey,
+    token_program: &AccountInfo,
+    vault: &AccountInfo,
+    vault_bump: u8,
+    quote_mint: &Pubkey,
+) -> Result<Pubkey, ProgramError> {
+    let pda = vault_pda(program_id, vault_bump)?;
+    if *vault.key != pda {
+        msg!("Vault account is not the correct PDA. Expected: {}, Got: {}", pda, vault.key);
+        return Err(ProgramError::InvalidArgument);
+    }
+
+    validate_token_account(token_program.key, vault, quote_mint, Some(&pda))?;
+
+    Ok(pda)
+}
+
+/// Check the vault and quote mint of a transfer against the market's recorded quote mint and
+/// vault bump. Returns the vault PDA and its bump for signing.
+pub(crate) fn validate_market_vault(
+    program_id: &Pubkey,
+    token_program: &AccountInfo,
+    vault: &AccountInfo,
+    market_state: &MarketState,
+    quote_mint: &AccountInfo,
+) -> Result<(Pubkey, u8), ProgramError> {
+    if market_state.quote_mint != *quote_mint.key {
+        msg!("Market quote mint mismatch. Expected: {}, Got: {}", market_state.quote_mint, quote_mint.key);
+        return Err(ProgramError::InvalidArgument);
+    }
+
+    let pda = validate_vault(program_id, token_program, vault, market_state.bump, quote_mint.key)?;
+
+    Ok((pda, market_state.bump))
+}
+
+/// Check the token program and mint of a quote transfer against the config; returns the quote decimals
+fn validate_quote_mint(config: &Config, token_program: &AccountInfo, mint: &AccountInfo) -> Result<u8, ProgramError> {
+    if config.quote_mint == Pubkey::default() {
+        msg!("Quote mint not configured: run set_quote_mint");
+        return Err(ProgramError::UninitializedAccount);
+    }
+
+    if *token_program.key != config.token_program {
+        msg!("Token program mismatch. Expected: {}, Got: {}", config.token_program, token_program.key);
+        return Err(ProgramError::IncorrectProgramId);
+    }
+
+    validate_mint(token_program, mint, &config.quote_mint)
+}
+
+/// Load the global config and check `admin` is its signing admin
+fn load_admin_config(program_id: &Pubkey, admin: &AccountInfo, config_acc: &AccountInfo) -> Result<Config, ProgramError> {
+    if !admin.is_signer {
+        msg!("Admin must be signer");
+        return Err(ProgramError::MissingRequiredSignature);
+    }
+
+    let config = load_config(program_id, config_acc)?;
+    if config.admin != *admin.key {
+        msg!("Admin mismatch. Expected: {}, Got: {}", config.admin, admin.key);
+        return Err(ProgramError::IllegalOwner);
+    }
+
+    Ok(config)
+}
+
+/// Load the global config, checking it is program-owned and sits at its PDA
+fn load_config(program_id: &Pubkey, config_acc: &AccountInfo) -> Result<Config, ProgramError> {
+    if config_acc.owner != program_id {
+        msg!("Config account is not owned by the program");
+        return Err(ProgramError::IncorrectProgramId);
+    }
+
+    let config = load_account::<Config>(&config_acc.data.borrow())?;
+    let expected_config = Pubkey::create_program_address(&[CONFIG_SEED, &[config.bump]], program_id)?;
+    if *config_acc.key != expected_config {
+        msg!("Config account is not the correct PDA. Expected: {}, Got: {}",
+             expected_config, config_acc.key);
+        return Err(ProgramError::InvalidArgument);
+    }
+
+    Ok(config)
+}
+
+/// Load a referrer account, checking it is program-owned and sits at its PDA
+fn load_referrer(program_id: &Pubkey, referrer_acc: &AccountInfo) -> Result<Referrer, ProgramError> {
+    if referrer_acc.owner != program_id {
+        msg!("Referrer account is not owned by the program");
+        return Err(ProgramError::IncorrectProgramId);
+    }
+
+    let referrer = load_account::<Referrer>(&referrer_acc.data.borrow())?;
+    let expected_referrer = Pubkey::create_program_address(
+        &[REFERRER_SEED, referrer.owner.as_ref(), &[referrer.bump]],
+        program_id,
+    )?;
+    if *referrer_acc.key != expected_referrer {
+        msg!("Referrer account is not the correct PDA. Expected: {}, Got: {}",
+             expected_referrer, referrer_acc.key);
+        return Err(ProgramError::InvalidArgument);
+    }
+
+    Ok(referrer)
+}
+
+// ---------------------------------------------------------------------
+// Helper functions for testing and client integration
+// ---------------------------------------------------------------------
+
+/// Market state PDA of the market created under `market_id`
+pub fn market_address(program_id: &Pubkey, market_id: u16) -> (Pubkey, u8) {
+    Pubkey::find_program_address(&[MARKET_SEED, &market_id.to_le_bytes()], program_id)
+}
+
+/// Position PDA of `owner`'s `sub_account_id` in the market at `market_state`
+pub fn position_address(program_id: &Pubkey, market_state: &Pubkey, owner: &Pubkey, sub_account_id: u16) -> (Pubkey, u8) {
+    Pubkey::find_program_address(
+        &[POSITION_SEED, market_state.as_ref(), owner.as_ref(), &sub_account_id.to_le_bytes()],
+        program_id,
+    )
+}
+
+/// Check `position_acc` is `position`'s PDA in the market at `market_state`, re-derived from its stored bump
+pub(crate) fn validate_position_address(
+    program_id: &Pubkey,
+    position_acc: &Pubkey,
+    market_state: &Pubkey,
+    position: &Position,
+) -> ProgramResult {
+    let expected_position = Pubkey::create_program_address(
+        &[
+            POSITION_SEED,
+            market_state.as_ref(),
+            position.owner.as_ref(),
+            &position.sub_account_id.to_le_bytes(),
+            &[position.bump],
+        ],
+        program_id,
+    )?;
+    if *position_acc != expected_position {
+        msg!("Position does not belong to market {}. Expected: {}, Got: {}", market_state, expected_position, position_acc);
+        return Err(ProgramError::InvalidArgument);
+    }
+
+    Ok(())
+}
+
+/// Calculate position leverage: notional / collateral (1e9 precision)
+pub fn calculate_leverage(position: &Position, mark_price: u64) -> Result<u64, ProgramError> {
+    if position.base_amount == 0 {
+        return Ok(0);
+    }
+    if position.collateral == 0 {
+        return Ok(u64::MAX);
+    }
+
+    let position_value = math::mul_div(position.base_amount.unsigned_abs().into(), mark_price.into(), PRECISION.into())?;
+
+    Ok(math::ratio(position_value, position.collateral.into()))
+}
+
+/// Whether moving open interest from `old` to `new` grows it past `max_open_interest`
+///
+/// Trades that reduce open interest are always allowed, even above the cap,
+/// so a lowered cap never traps existing positions.
+pub fn exceeds_open_interest_cap(old_open_interest: u64, new_open_interest: u64, max_open_interest: u64) -> bool {
+    new_open_interest > old_open_interest && new_open_interest > max_open_interest
+}
+
+/// Quote value (1e9 precision) counted as collateral for `balance` native units of an
+/// asset with `decimals`, priced at `price` per whole token and haircut to `weight`
+pub fn calculate_asset_collateral_value(
+    balance: u64,
+    decimals: u8,
+    price: u64,
+    weight: u64,
+) -> Result<u64, ProgramError> {
+    let unit = 10u128.checked_pow(decimals as u32).ok_or(ProgramError::InvalidArgument)?;
+    let value = math::mul_div(balance.into(), price.into(), unit)?;
+
+    math::to_u64(math::mul_div(value, weight.into(), PRECISION.into())?)
+}
+
+/// Whether applying `base_delta` shrinks |base_amount| without flipping its sign
+pub fn is_reducing_change(base_amount: i64, base_delta: i64) -> bool {
+    if base_delta == 0 {
+        return true;
+    }
+
+    // Must trade against the existing position and by no more than its size
+    base_amount.signum() == -base_delta.signum()
+        && base_delta.unsigned_abs() <= base_amount.unsigned_abs()
+}
+
+/// Whether applying a non-zero `base_delta` grows |base_amount| on its side (any side when flat)
+pub fn is_growing_change(base_amount: i64, base_delta: i64) -> bool {
+    base_delta != 0 && (base_amount == 0 || base_amount.signum() == base_delta.signum())
+}
+
+/// `base_delta` adjusted so a trade never leaves a position smaller than `min_position_size`
+///
+/// A reduction that would leave less closes the whole position instead, so the book doesn't
+/// collect crumbs too small to be worth liquidating; an open or flip into one is rejected.
+pub fn apply_min_position_size(base_amount: i64, base_delta: i64, min_position_size: u64) -> Result<i64, ProgramError> {
+    let new_base_amount = base_amount.checked_add(base_delta).ok_or(ProgramError::InvalidArgument)?;
+    if base_delta == 0 || new_base_amount == 0 || new_base_amount.unsigned_abs() >= min_position_size {
+        return Ok(base_delta);
+    }
+
+    if is_reducing_change(base_amount, base_delta) {
+        msg!("Reduction would leave {} below the minimum size {}: closing the position", new_base_amount, min_position_size);
+        return base_amount.checked_neg().ok_or(ProgramError::InvalidArgument);
+    }
 
-    market_state.last_funding_slot = clock.slot;
+    msg!("Position size {} would be below the minimum {}", new_base_amount, min_position_size);
+    Err(ProgramError::InvalidArgument)
+}
 
-    // Persist changes
-    market_state.serialize(&mut *market_state_acc.data.borrow_mut())?;
+/// Calculate the vAMM spot price: quote_reserve / base_reserve (1e9 precision)
+pub fn calculate_vamm_price(base_reserve: u64, quote_reserve: u64) -> Result<u64, ProgramError> {
+    if base_reserve == 0 {
+        return Err(ProgramError::InvalidAccountData);
+    }
 
-    msg!("Funding updated: rate_per_slot={}, index={}, slots_elapsed={}", 
-         market_state.funding_rate_per_slot, market_state.funding_index, slots_elapsed);
-    
-    Ok(())
+    math::div_scaled(quote_reserve, base_reserve)
 }
 
-// ---------------------------------------------------------------------
-// 2️⃣ Liquidate an undercollateralized position
-// ---------------------------------------------------------------------
-pub fn liquidate(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramResult {
-    // Accounts:
-    // 0. [signer] liquidator
-    // 1. [] token program
-    // 2. [writable] liquidator's token account (to receive liquidation reward)
-    // 3. [writable] vault token account (PDA‑owned)
-    // 4. [writable] position account to liquidate
-    // 5. [writable] market state account
-    // 6. [] clock sysvar
-    let accounts_iter = &mut accounts.iter();
-    let liquidator = next_account_info(accounts_iter)?;
-    let token_program = next_account_info(accounts_iter)?;
-    let liquidator_token_acc = next_account_info(accounts_iter)?;
-    let vault = next_account_info(accounts_iter)?;
-    let position_acc = next_account_info(accounts_iter)?;
-    let market_state_acc = next_account_info(accounts_iter)?;
-    let clock_sysvar = next_account_info(accounts_iter)?;
+/// Calculate the quote reserve that prices `base_reserve` at `price` (1e9 precision)
+pub fn calculate_quote_reserve(base_reserve: u64, price: u64) -> Result<u64, ProgramError> {
+    math::mul_scaled(base_reserve, price)
+}
 
-    if !liquidator.is_signer {
-        msg!("Liquidator must be signer");
-        return Err(ProgramError::MissingRequiredSignature);
+/// Calculate the fill for trading `base_delta` against a constant-product vAMM
+///
+/// Longs remove base from the pool and pay quote in; shorts add base and take
+/// quote out. The new quote reserve is rounded up so rounding always favours
+/// the pool.
+pub fn calculate_vamm_fill(base_reserve: u64, quote_reserve: u64, base_delta: i64) -> Result<VammFill, ProgramError> {
+    if base_delta == 0 {
+        return Err(ProgramError::InvalidArgument);
     }
 
-    let _clock = Clock::from_account_info(clock_sysvar)?;
-    let mut market_state = MarketState::try_from_slice(&market_state_acc.data.borrow())?;
-    let mut position = Position::try_from_slice(&position_acc.data.borrow())?;
+    let size = base_delta.unsigned_abs();
 
-    // Verify position exists and has exposure
-    if position.base_amount == 0 {
-        msg!("Position has no exposure to liquidate");
-        return Err(ProgramError::InvalidArgument);
+    let new_base_reserve = if base_delta > 0 {
+        base_reserve.checked_sub(size).filter(|reserve| *reserve > 0)
+    } else {
+        base_reserve.checked_add(size)
     }
+    .ok_or_else(|| {
+        msg!("Insufficient vAMM liquidity for size {}", base_delta);
+        ProgramError::InsufficientFunds
+    })?;
 
-    // Apply any pending funding
-    let funding_delta = market_state.funding_index
-        .checked_sub(position.last_funding_index)
-        .ok_or(ProgramError::InvalidArgument)?;
+    // k = base_reserve * quote_reserve is preserved
+    let new_quote_reserve =
+        math::to_u64(math::mul_div_ceil(base_reserve.into(), quote_reserve.into(), new_base_reserve.into())?)?;
 
-    if funding_delta != 0 {
-        let funding_payment = ((position.base_amount as i128)
-            .checked_mul(funding_delta as i128)
-            .ok_or(ProgramError::InvalidArgument)?)
-            .checked_div(1_000_000_000i128)
-            .ok_or(ProgramError::InvalidAccountData)?;
+    let quote_amount = if base_delta > 0 {
+        new_quote_reserve.checked_sub(quote_reserve)
+    } else {
+        quote_reserve.checked_sub(new_quote_reserve)
+    }
+    .ok_or(ProgramError::InvalidAccountData)?;
 
-        if funding_payment > 0 {
-            position.collateral = position
-                .collateral
-                .checked_sub(funding_payment as u64)
-                .unwrap_or(0); // Don't fail if insufficient, that makes it more liquidatable
-        } else {
-            position.collateral = position
-                .collateral
-                .checked_add((-funding_payment) as u64)
-                .ok_or(ProgramError::InvalidArgument)?;
-        }
+    Ok(VammFill {
+        quote_amount,
+        fill_price: math::div_scaled(quote_amount, size)?,
+        new_base_reserve,
+        new_quote_reserve,
+        price_impact: 0,
+    })
+}
+
+/// Funding rate per slot for the market's skew: `FUNDING_SKEW_RATE_PER_SLOT * (long - short) /
+/// (long + short)`. Positive when longs outweigh shorts, so longs pay; 0 with no open interest.
+pub fn calculate_skew_funding_rate(long_open_interest: u64, short_open_interest: u64) -> Result<i64, ProgramError> {
+    let total = i128::from(long_open_interest) + i128::from(short_open_interest);
+    if total == 0 {
+        return Ok(0);
     }
-    position.last_funding_index = market_state.funding_index;
+    let skew = i128::from(long_open_interest) - i128::from(short_open_interest);
+    math::to_i64(i128::from(FUNDING_SKEW_RATE_PER_SLOT) * skew / total)
+}
 
-    // Calculate position value and current PnL
-    let position_size = position.base_amount.abs() as u64;
-    let position_value = position_size
-        .checked_mul(market_state.mark_price)
-        .ok_or(ProgramError::InvalidArgument)?
-        .checked_div(1_000_000_000)
-        .ok_or(ProgramError::InvalidAccountData)?;
+/// Cost to the protocol of moving the vAMM price from `old_price` to `new_price`: the change in
+/// traders' unrealized PnL, `net_base_amount * (new_price - old_price)` (negative = a gain)
+pub fn calculate_repeg_cost(net_base_amount: i64, old_price: u64, new_price: u64) -> Result<i64, ProgramError> {
+    math::mul_scaled_signed(net_base_amount.into(), i128::from(new_price) - i128::from(old_price))
+}
 
-    // Calculate unrealized PnL
-    let _entry_value = position_size
-        .checked_mul(position.entry_price)
-        .ok_or(ProgramError::InvalidArgument)?
-        .checked_div(1_000_000_000)
-        .ok_or(ProgramError::InvalidAccountData)?;
+/// Price a repeg moves the vAMM to: `target_price`, or as far toward it as `fee_pool` can pay
+/// for when the move favours traders' net position
+pub fn calculate_repeg_price(spot_price: u64, target_price: u64, net_base_amount: i64, fee_pool: u64) -> Result<u64, ProgramError> {
+    if calculate_repeg_cost(net_base_amount, spot_price, target_price)? <= i64::try_from(fee_pool).unwrap_or(i64::MAX) {
+        return Ok(target_price);
+    }
 
-    let unrealized_pnl = if position.base_amount > 0 {
-        // Long position: PnL = (mark_price - entry_price) * size
-        (market_state.mark_price as i64)
-            .checked_sub(position.entry_price as i64)
-            .ok_or(ProgramError::InvalidArgument)?
-            .checked_mul(position_size as i64)
-            .ok_or(ProgramError::InvalidArgument)?
-            .checked_div(1_000_000_000)
-            .ok_or(ProgramError::InvalidAccountData)?
+    // A positive cost implies a non-zero net position
+    let max_move = math::to_u64(math::mul_div(fee_pool.into(), PRECISION.into(), net_base_amount.unsigned_abs().into())?)?;
+    Ok(if target_price > spot_price {
+        spot_price.saturating_add(max_move)
     } else {
-        // Short position: PnL = (entry_price - mark_price) * size
-        (position.entry_price as i64)
-            .checked_sub(market_state.mark_price as i64)
-            .ok_or(ProgramError::InvalidArgument)?
-            .checked_mul(position_size as i64)
-            .ok_or(ProgramError::InvalidArgument)?
-            .checked_div(1_000_000_000)
-            .ok_or(ProgramError::InvalidAccountData)?
-    };
+        spot_price.saturating_sub(max_move)
+    })
+}
 
-    // Calculate effective collateral (including unrealized PnL)
-    let effective_collateral = if unrealized_pnl >= 0 {
-        position.collateral
-            .checked_add(unrealized_pnl as u64)
-            .ok_or(ProgramError::InvalidArgument)?
+/// Calculate the fill for trading `base_delta` in a market: the vAMM curve fill plus the
+/// market's price impact
+pub fn calculate_market_fill(market_state: &MarketState, base_delta: i64) -> Result<VammFill, ProgramError> {
+    let fill = calculate_vamm_fill(market_state.base_reserve, market_state.quote_reserve, base_delta)?;
+    apply_price_impact(fill, base_delta, market_state.price_impact_depth)
+}
+
+/// Charge price impact on a curve fill: `(size / impact_depth)^2` of the fill price, capped
+/// at MAX_PRICE_IMPACT, added for longs and taken off for shorts. The reserves are not
+/// touched; impact only worsens the execution price. A zero depth charges none.
+pub fn apply_price_impact(fill: VammFill, base_delta: i64, impact_depth: u64) -> Result<VammFill, ProgramError> {
+    if impact_depth == 0 {
+        return Ok(fill);
+    }
+
+    let size_ratio = math::mul_div(base_delta.unsigned_abs().into(), PRECISION.into(), impact_depth.into())?;
+    let price_impact = if size_ratio >= PRECISION.into() {
+        MAX_PRICE_IMPACT
     } else {
-        position.collateral
-            .checked_sub((-unrealized_pnl) as u64)
-            .unwrap_or(0)
+        math::to_u64(math::mul_div(size_ratio, size_ratio, PRECISION.into())?)?.min(MAX_PRICE_IMPACT)
     };
 
-    // Check if position is liquidatable
-    let collateral_ratio = if position_value > 0 {
-        effective_collateral
-            .checked_mul(1_000_000_000)
-            .ok_or(ProgramError::InvalidArgument)?
-            .checked_div(position_value)
-            .ok_or(ProgramError::InvalidAccountData)?
+    let price_premium = math::mul_scaled(fill.fill_price, price_impact)?;
+    let quote_premium = math::mul_scaled(fill.quote_amount, price_impact)?;
+    let (fill_price, quote_amount) = if base_delta > 0 {
+        (fill.fill_price.checked_add(price_premium), fill.quote_amount.checked_add(quote_premium))
     } else {
-        u64::MAX
+        (fill.fill_price.checked_sub(price_premium), fill.quote_amount.checked_sub(quote_premium))
     };
 
-    if collateral_ratio >= MIN_COLLATERAL_RATIO {
-        msg!("Position is not liquidatable. Collateral ratio: {} >= {}", 
-             collateral_ratio, MIN_COLLATERAL_RATIO);
-        return Err(ProgramError::InvalidArgument);
+    Ok(VammFill {
+        quote_amount: quote_amount.ok_or(ProgramError::InvalidArgument)?,
+        fill_price: fill_price.ok_or(ProgramError::InvalidArgument)?,
+        price_impact,
+        ..fill
+    })
+}
+
+/// Weight an EMA keeps on its old value after `elapsed_slots` with a half-life of
+/// `half_life_slots` (1e9 precision): halved per whole half-life, interpolated linearly
+/// in between
+pub fn calculate_ema_decay(elapsed_slots: u64, half_life_slots: u64) -> u64 {
+    let halvings = elapsed_slots / half_life_slots;
+    if halvings >= u64::from(u64::BITS) {
+        return 0;
     }
 
-    // Calculate liquidation penalty
-    let penalty_amount = position.collateral
-        .checked_mul(LIQUIDATION_PENALTY)
-        .ok_or(ProgramError::InvalidArgument)?
-        .checked_div(1_000_000_000)
-        .ok_or(ProgramError::InvalidAccountData)?;
+    let weight = PRECISION >> halvings;
+    let remainder = u128::from(elapsed_slots % half_life_slots);
+    let lost = u128::from(weight) * remainder / (2 * u128::from(half_life_slots));
+    weight - lost as u64
+}
 
-    // Derive PDA for signing
-    let (pda, bump) = Pubkey::find_program_address(&[PDA_SEED], program_id);
-    let seeds = &[PDA_SEED, &[bump]];
-    let signer_seeds = &[&seeds[..]];
+/// Calculate slippage of a fill relative to the pre-trade price (1e9 = 100%)
+pub fn calculate_slippage(price_before: u64, fill_price: u64) -> Result<u64, ProgramError> {
+    if price_before == 0 {
+        return Ok(0);
+    }
 
-    // Transfer liquidation reward to liquidator
-    if penalty_amount > 0 {
-        let transfer_ix = create_transfer_instruction(
-            token_program.key,
-            vault.key,
-            liquidator_token_acc.key,
-            &pda,
-            penalty_amount,
-        )?;
+    Ok(math::ratio(price_before.abs_diff(fill_price).into(), price_before.into()))
+}
 
-        invoke_signed(&transfer_ix, &[
-            vault.clone(),
-            liquidator_token_acc.clone(),
-            vault.clone(), // PDA authority
-            token_program.clone(),
-        ], signer_seeds)?;
+/// Size-weighted entry price after adding `base_delta` at `fill_price` to a same-side position
+pub fn calculate_average_entry_price(base_amount: i64, entry_price: u64, base_delta: i64, fill_price: u64) -> Result<u64, ProgramError> {
+    let old_size = base_amount.unsigned_abs() as u128;
+    let added_size = base_delta.unsigned_abs() as u128;
+    let total_size = old_size + added_size;
+    if total_size == 0 {
+        return Ok(fill_price);
     }
 
-    // Update market state
-    market_state.open_interest = market_state.open_interest
-        .checked_sub(position_size)
+    let total_quote = old_size
+        .checked_mul(entry_price as u128)
+        .and_then(|quote| quote.checked_add(added_size.checked_mul(fill_price as u128)?))
         .ok_or(ProgramError::InvalidArgument)?;
 
-    // Clear the position
-    position.base_amount = 0;
-    position.collateral = position.collateral
-        .checked_sub(penalty_amount)
-        .unwrap_or(0);
-    position.entry_price = 0;
-
-    // Persist changes
-    position.serialize(&mut *position_acc.data.borrow_mut())?;
-    market_state.serialize(&mut *market_state_acc.data.borrow_mut())?;
+    u64::try_from(total_quote / total_size).map_err(|_| ProgramError::InvalidArgument)
+}
 
-    msg!("Position liquidated: penalty={}, remaining_collateral={}, ratio_was={}", 
-         penalty_amount, position.collateral, collateral_ratio);
-    
-    Ok(())
+/// Bounds `band` (1e9 precision) either side of `index_price`
+pub fn calculate_price_band(index_price: u64, band: u64) -> (u64, u64) {
+    let width = math::mul_scaled(index_price, band).unwrap_or(u64::MAX);
+    (index_price.saturating_sub(width), index_price.saturating_add(width))
 }
 
-// ---------------------------------------------------------------------
-// 3️⃣ Close position (voluntary)
-// ---------------------------------------------------------------------
-pub fn close_position(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramResult {
-    // Accounts:
-    // 0. [signer] user (position owner)
-    // 1. [] token program
-    // 2. [writable] user's token account (to receive collateral)
-    // 3. [writable] vault token account (PDA‑owned)
-    // 4. [writable] position account
-    // 5. [writable] market state account
-    let accounts_iter = &mut accounts.iter();
-    let user = next_account_info(accounts_iter)?;
-    let token_program = next_account_info(accounts_iter)?;
-    let user_token_acc = next_account_info(accounts_iter)?;
-    let vault = next_account_info(accounts_iter)?;
-    let position_acc = next_account_info(accounts_iter)?;
-    let market_state_acc = next_account_info(accounts_iter)?;
+/// Whether `price` deviates from `index_price` by at most `band`
+pub fn is_within_price_band(price: u64, index_price: u64, band: u64) -> bool {
+    let (lower, upper) = calculate_price_band(index_price, band);
+    (lower..=upper).contains(&price)
+}
 
-    if !user.is_signer {
-        return Err(ProgramError::MissingRequiredSignature);
-    }
+/// `price` pulled back inside the band around `index_price`
+pub fn clamp_to_price_band(price: u64, index_price: u64, band: u64) -> u64 {
+    let (lower, upper) = calculate_price_band(index_price, band);
+    price.clamp(lower, upper)
+}
 
-    let mut market_state = MarketState::try_from_slice(&market_state_acc.data.borrow())?;
-    let mut position = Position::try_from_slice(&position_acc.data.borrow())?;
+/// Calculate the quote notional of `base_amount` at `price` (1e9 precision)
+pub fn calculate_notional(base_amount: i64, price: u64) -> Result<u64, ProgramError> {
+    math::mul_scaled(base_amount.unsigned_abs(), price)
+}
 
-    // Verify ownership
-    if position.owner != *user.key {
-        return Err(ProgramError::IllegalOwner);
-    }
+/// Calculate the trading fee owed on a position change of `base_delta` at `price`
+pub fn calculate_trading_fee(base_delta: i64, price: u64, fee_rate: u64) -> Result<u64, ProgramError> {
+    let notional = math::mul_div(base_delta.unsigned_abs().into(), price.into(), PRECISION.into())?;
 
-    if position.base_amount == 0 && position.collateral == 0 {
-        msg!("Position already closed");
-        return Ok(());
-    }
+    math::to_u64(math::mul_div(notional, fee_rate.into(), PRECISION.into())?)
+}
 
-    // Apply any pending funding
-    let funding_delta = market_state.funding_index
-        .checked_sub(position.last_funding_index)
-        .ok_or(ProgramError::InvalidArgument)?;
+/// Signed skew fee on a trade of `base_delta` at `price` that moves the net skew away from
+/// `skew_before`: `fee_rate` of the notional that grows the skew, less `fee_rate` of the notional
+/// that shrinks it. Positive = a surcharge, negative = a rebate.
+pub fn calculate_skew_fee(skew_before: i64, base_delta: i64, price: u64, fee_rate: u64) -> Result<i64, ProgramError> {
+    let size = base_delta.unsigned_abs();
+    let reducing = if (skew_before > 0) != (base_delta > 0) {
+        size.min(skew_before.unsigned_abs())
+    } else {
+        0
+    };
+    let increasing = size - reducing;
 
-    if funding_delta != 0 && position.base_amount != 0 {
-        let funding_payment = ((position.base_amount as i128)
-            .checked_mul(funding_delta as i128)
-            .ok_or(ProgramError::InvalidArgument)?)
-            .checked_div(1_000_000_000i128)
-            .ok_or(ProgramError::InvalidAccountData)?;
+    let surcharge = math::mul_scaled(math::mul_scaled(increasing, price)?, fee_rate)?;
+    let rebate = math::mul_scaled(math::mul_scaled(reducing, price)?, fee_rate)?;
+    math::to_i64(i128::from(surcharge) - i128::from(rebate))
+}
 
-        if funding_payment > 0 {
-            position.collateral = position
-                .collateral
-                .checked_sub(funding_payment as u64)
-                .unwrap_or(0);
-        } else {
-            position.collateral = position
-                .collateral
-                .checked_add((-funding_payment) as u64)
-                .ok_or(ProgramError::InvalidArgument)?;
-        }
+/// Charge a vAMM trade of `base_delta` at `price` the market's skew fee, or pay its rebate,
+/// after the trade has been applied to the market's aggregates. Surcharges are capped at the
+/// collateral and go to the fee pool; rebates are paid out of the fee pool as far as it can.
+/// Returns the amount moved (negative = a rebate).
+fn charge_skew_fee(collateral: &mut u64, market_state: &mut MarketState, base_delta: i64, price: u64) -> Result<i64, ProgramError> {
+    if market_state.skew_fee == 0 || base_delta == 0 {
+        return Ok(0);
     }
+    let skew_before = market_state.net_base_amount
+        .checked_sub(base_delta)
+        .ok_or(ProgramError::InvalidArgument)?;
+    let skew_fee = calculate_skew_fee(skew_before, base_delta, price, market_state.skew_fee)?;
 
-    // Update market state
-    if position.base_amount != 0 {
-        market_state.open_interest = market_state.open_interest
-            .checked_sub(position.base_amount.abs() as u64)
+    let moved = if skew_fee >= 0 {
+        let surcharge = skew_fee.unsigned_abs().min(*collateral);
+        *collateral -= surcharge;
+        market_state.fee_pool = market_state.fee_pool
+            .checked_add(surcharge)
             .ok_or(ProgramError::InvalidArgument)?;
+        msg!("Charged skew fee: {}", surcharge);
+        math::to_i64(surcharge.into())?
+    } else {
+        let rebate = skew_fee.unsigned_abs().min(market_state.fee_pool);
+        market_state.fee_pool -= rebate;
+        *collateral = collateral
+            .checked_add(rebate)
+            .ok_or(ProgramError::InvalidArgument)?;
+        msg!("Paid skew rebate: {}", rebate);
+        -math::to_i64(rebate.into())?
+    };
+
+    Ok(moved)
+}
+
+/// Calculate the update_funding keeper reward: `reward_per_period` per elapsed
+/// funding period (pro rata for partial periods), capped at `max_reward` and at
+/// what the market's fee pool can pay
+pub fn calculate_funding_crank_reward(slots_elapsed: u64, reward_per_period: u64, max_reward: u64, fee_pool: u64) -> u64 {
+    let accrued = (slots_elapsed as u128)
+        .saturating_mul(reward_per_period as u128)
+        / FUNDING_PERIOD_SLOTS as u128;
+
+    u64::try_from(accrued).unwrap_or(u64::MAX).min(max_reward).min(fee_pool)
+}
+
+/// Entry price for a backstop liquidator taking over `base_amount` at `mark_price`,
+/// discounted so the position is worth `liquidator_reward` more than at the mark
+/// (a lower entry for longs, a higher one for shorts)
+pub fn calculate_backstop_entry_price(base_amount: i64, mark_price: u64, liquidator_reward: u64) -> Result<u64, ProgramError> {
+    let size = base_amount.unsigned_abs();
+    if size == 0 {
+        return Ok(mark_price);
     }
 
-    // Transfer remaining collateral to user
-    if position.collateral > 0 {
-        let (pda, bump) = Pubkey::find_program_address(&[PDA_SEED], program_id);
-        let seeds = &[PDA_SEED, &[bump]];
-        let signer_seeds = &[&seeds[..]];
+    let discount = math::div_scaled(liquidator_reward, size)?;
 
-        let transfer_ix = create_transfer_instruction(
-            token_program.key,
-            vault.key,
-            user_token_acc.key,
-            &pda,
-            position.collateral,
-        )?;
+    if base_amount > 0 {
+        Ok(mark_price.saturating_sub(discount))
+    } else {
+        mark_price.checked_add(discount).ok_or(ProgramError::InvalidArgument)
+    }
+}
 
-        invoke_signed(&transfer_ix, &[
-            vault.clone(),
-            user_token_acc.clone(),
-            vault.clone(), // PDA authority
-            token_program.clone(),
-        ], signer_seeds)?;
+/// How a liquidation fee is split and funded
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct LiquidationFee {
+    /// Paid out to the liquidator
+    pub liquidator_reward: u64,
+    /// Part of the reward the insurance fund covered because collateral ran short
+    pub from_insurance: u64,
+    /// Retained by the insurance fund
+    pub insurance_fee: u64,
+}
+
+impl LiquidationFee {
+    /// Collateral taken from the liquidated position or user account
+    pub fn collateral_charged(&self) -> u64 {
+        self.liquidator_reward - self.from_insurance + self.insurance_fee
     }
+}
 
-    let returned_collateral = position.collateral;
+/// Split a liquidation fee of `liquidator_fee_bps` of `notional` between the liquidator and
+/// the insurance fund. The liquidator's share is paid from collateral first, then from the
+/// insurance fund; the insurance share only comes out of collateral left after that.
+pub fn calculate_liquidation_fee(
+    notional: u64,
+    collateral: u64,
+    insurance_fund: u64,
+    liquidator_fee_bps: u64,
+    insurance_fund_share: u64,
+) -> Result<LiquidationFee, ProgramError> {
+    let fee = math::mul_div(notional.into(), liquidator_fee_bps.into(), BPS_DENOMINATOR.into())?;
+    let insurance_target = math::mul_div(fee, insurance_fund_share.min(PRECISION).into(), PRECISION.into())?;
+    let liquidator_target = math::to_u64(fee - insurance_target)?;
+    let insurance_target = math::to_u64(insurance_target)?;
 
-    // Clear the position
-    position.base_amount = 0;
-    position.collateral = 0;
-    position.entry_price = 0;
-    position.last_funding_index = market_state.funding_index;
+    let from_collateral = liquidator_target.min(collateral);
+    let from_insurance = (liquidator_target - from_collateral).min(insurance_fund);
 
-    // Persist changes
-    position.serialize(&mut *position_acc.data.borrow_mut())?;
-    market_state.serialize(&mut *market_state_acc.data.borrow_mut())?;
+    Ok(LiquidationFee {
+        liquidator_reward: from_collateral + from_insurance,
+        from_insurance,
+        insurance_fee: insurance_target.min(collateral - from_collateral),
+    })
+}
 
-    msg!("Position closed: returned_collateral={}, new_open_interest={}", 
-         returned_collateral, market_state.open_interest);
-    
-    Ok(())
+/// Calculate the referrer's share of a trading fee
+pub fn calculate_referral_share(trading_fee: u64, referral_fee_share: u64) -> Result<u64, ProgramError> {
+    math::mul_scaled(trading_fee, referral_fee_share)
 }
 
-// ---------------------------------------------------------------------
-// Helper functions for testing and client integration
-// ---------------------------------------------------------------------
+/// Calculate the PnL realized by applying `base_delta` at `fill_price` to a position of
+/// `base_amount` entered at `entry_price`: only the part of the position that the change
+/// closes realizes PnL, so increases realize nothing
+pub fn calculate_realized_pnl(base_amount: i64, entry_price: u64, base_delta: i64, fill_price: u64) -> Result<i64, ProgramError> {
+    if base_amount == 0 || base_delta == 0 || (base_amount > 0) == (base_delta > 0) {
+        return Ok(0);
+    }
+
+    let closed = i128::from(base_delta.unsigned_abs().min(base_amount.unsigned_abs())) * i128::from(base_amount.signum());
+    calculate_pnl(closed, entry_price, fill_price)
+}
+
+/// PnL of a signed size entered at `entry_price` and valued at `price`:
+/// size * (price - entry_price) / 1e9, rounded toward zero
+fn calculate_pnl(base_amount: i128, entry_price: u64, price: u64) -> Result<i64, ProgramError> {
+    math::mul_scaled_signed(base_amount, i128::from(price) - i128::from(entry_price))
+}
+
+/// Calculate the unrealized PnL of all of a market's traders together from its
+/// net_base_amount and net_entry_quote aggregates (quote token)
+pub fn calculate_total_unrealized_pnl(net_base_amount: i64, net_entry_quote: i64, mark_price: u64) -> Result<i64, ProgramError> {
+    let mark_quote = math::mul_div_signed(net_base_amount.into(), mark_price.into(), PRECISION.into())?;
+
+    math::to_i64(mark_quote - i128::from(net_entry_quote))
+}
 
 /// Calculate position health (collateral ratio)
 pub fn calculate_position_health(position: &Position, mark_price: u64) -> Result<u64, ProgramError> {
@@ -711,21 +6732,58 @@ pub fn calculate_position_health(position: &Position, mark_price: u64) -> Result
         return Ok(u64::MAX); // No position = perfect health
     }
 
-    let position_value = (position.base_amount.abs() as u64)
-        .checked_mul(mark_price)
-        .ok_or(ProgramError::InvalidArgument)?
-        .checked_div(1_000_000_000)
-        .ok_or(ProgramError::InvalidAccountData)?;
+    // A dust position worth nothing at the mark is also perfectly healthy
+    let position_value = math::mul_div(position.base_amount.unsigned_abs().into(), mark_price.into(), PRECISION.into())?;
+    Ok(math::ratio(position.collateral.into(), position_value))
+}
 
-    if position_value == 0 {
-        return Ok(u64::MAX);
+/// Collateral ratio of an isolated position backed by `total_collateral` at `price`, counting
+/// its unrealized PnL there, and the ratio its notional requires
+pub fn calculate_isolated_collateral_ratios(
+    position: &Position,
+    total_collateral: u64,
+    market_state: &MarketState,
+    min_collateral_ratio: u64,
+    price: u64,
+) -> Result<(u64, u64), ProgramError> {
+    let position_value = calculate_notional(position.base_amount, price)?;
+    let unrealized_pnl = calculate_unrealized_pnl(position, price)?;
+    let effective_collateral = if unrealized_pnl >= 0 {
+        total_collateral
+            .checked_add(unrealized_pnl.unsigned_abs())
+            .ok_or(ProgramError::InvalidArgument)?
+    } else {
+        total_collateral.saturating_sub(unrealized_pnl.unsigned_abs())
+    };
+
+    Ok((
+        math::ratio(effective_collateral.into(), position_value.into()),
+        market_state.required_collateral_ratio(position_value, min_collateral_ratio),
+    ))
+}
+
+/// An isolated position's collateral ratio at the mark price and its health factor: the
+/// collateral ratio over the ratio its size requires (1e9 precision), below 1e9 when it is
+/// liquidatable. Markets that also check their mark price EMA take the better of the two
+/// factors, so a wick in the mark alone doesn't liquidate.
+pub fn calculate_isolated_liquidation_health(
+    position: &Position,
+    total_collateral: u64,
+    market_state: &MarketState,
+    min_collateral_ratio: u64,
+) -> Result<(u64, u64), ProgramError> {
+    let health_at = |price: u64| {
+        calculate_isolated_collateral_ratios(position, total_collateral, market_state, min_collateral_ratio, price)
+    };
+
+    let (collateral_ratio, required_ratio) = health_at(market_state.mark_price)?;
+    let mut health_factor = math::ratio(collateral_ratio.into(), required_ratio.into());
+    if let Some(ema_price) = market_state.liquidation_ema_price() {
+        let (ema_ratio, ema_required_ratio) = health_at(ema_price)?;
+        health_factor = health_factor.max(math::ratio(ema_ratio.into(), ema_required_ratio.into()));
     }
 
-    position.collateral
-        .checked_mul(1_000_000_000)
-        .ok_or(ProgramError::InvalidArgument)?
-        .checked_div(position_value)
-        .ok_or(ProgramError::InvalidAccountData)
+    Ok((collateral_ratio, health_factor))
 }
 
 /// Calculate unrealized PnL for a position
@@ -734,29 +6792,102 @@ pub fn calculate_unrealized_pnl(position: &Position, mark_price: u64) -> Result<
         return Ok(0);
     }
 
-    let position_size = position.base_amount.abs() as u64;
-    
-    let pnl = if position.base_amount > 0 {
-        // Long position: PnL = (mark_price - entry_price) * size / 1e9
-        (mark_price as i64)
-            .checked_sub(position.entry_price as i64)
-            .ok_or(ProgramError::InvalidArgument)?
-            .checked_mul(position_size as i64)
-            .ok_or(ProgramError::InvalidArgument)?
-            .checked_div(1_000_000_000)
-            .ok_or(ProgramError::InvalidAccountData)?
+    // Longs gain as the mark rises above entry, shorts as it falls below
+    calculate_pnl(position.base_amount.into(), position.entry_price, mark_price)
+}
+
+/// Funding owed by a position of `base_amount` after the funding index moved by
+/// `funding_delta` (negative = received; quote token, rounded toward zero)
+pub fn calculate_funding_payment(base_amount: i64, funding_delta: i64) -> Result<i64, ProgramError> {
+    math::mul_scaled_signed(base_amount.into(), funding_delta.into())
+}
+
+/// Funding `position` owes since its last settlement plus any carried forward (negative = received)
+pub fn calculate_pending_funding(position: &Position, market_state: &MarketState) -> Result<i64, ProgramError> {
+    let funding_delta = market_state.funding_index
+        .checked_sub(position.last_funding_index)
+        .ok_or(ProgramError::InvalidArgument)?;
+
+    calculate_funding_payment(position.base_amount, funding_delta)?
+        .checked_add(position.pending_funding)
+        .ok_or(ProgramError::InvalidArgument)
+}
+
+/// Socialized loss `position` owes since its last settlement: its size times the growth of
+/// its side's loss index
+pub fn calculate_pending_socialized_loss(position: &Position, market_state: &MarketState) -> Result<u64, ProgramError> {
+    let index_delta = if position.base_amount > 0 {
+        market_state.long_loss_index.saturating_sub(position.last_long_loss_index)
     } else {
-        // Short position: PnL = (entry_price - mark_price) * size / 1e9
-        (position.entry_price as i64)
-            .checked_sub(mark_price as i64)
-            .ok_or(ProgramError::InvalidArgument)?
-            .checked_mul(position_size as i64)
-            .ok_or(ProgramError::InvalidArgument)?
-            .checked_div(1_000_000_000)
-            .ok_or(ProgramError::InvalidAccountData)?
+        market_state.short_loss_index.saturating_sub(position.last_short_loss_index)
+    };
+
+    math::mul_scaled(position.base_amount.unsigned_abs(), index_delta)
+}
+
+/// Unrealized PnL of all longs and of all shorts at `mark_price`, from the market's aggregates
+pub fn calculate_side_pnl(market_state: &MarketState, mark_price: u64) -> Result<(i64, i64), ProgramError> {
+    let short_base_amount = market_state.net_base_amount
+        .checked_sub(market_state.long_base_amount)
+        .ok_or(ProgramError::InvalidArgument)?;
+    let short_entry_quote = market_state.net_entry_quote
+        .checked_sub(market_state.long_entry_quote)
+        .ok_or(ProgramError::InvalidArgument)?;
+
+    let long_pnl = math::mul_scaled_signed(market_state.long_base_amount.into(), mark_price.into())?
+        .checked_sub(market_state.long_entry_quote)
+        .ok_or(ProgramError::InvalidArgument)?;
+    let short_pnl = math::mul_scaled_signed(short_base_amount.into(), mark_price.into())?
+        .checked_sub(short_entry_quote)
+        .ok_or(ProgramError::InvalidArgument)?;
+
+    Ok((long_pnl, short_pnl))
+}
+
+/// Mark price at which an isolated position's collateral ratio reaches `min_collateral_ratio`
+///
+/// Mirrors `liquidate`: pending funding comes out of the quote collateral first
+/// (flooring at zero) and unrealized PnL is counted at the mark. Asset collateral
+/// is not counted, so positions holding some are liquidated later than this.
+/// Shorts are liquidated above the returned price. Longs are liquidated below it
+/// while `min_collateral_ratio` is under 100% and above it while it is over, since
+/// the ratio of an overcollateralized long falls as the price rises. `None` when
+/// the position is flat or no positive price crosses the minimum.
+pub fn calculate_liquidation_price(
+    position: &Position,
+    market_state: &MarketState,
+    min_collateral_ratio: u64,
+) -> Result<Option<u64>, ProgramError> {
+    if position.base_amount == 0 {
+        return Ok(None);
+    }
+
+    let funding_payment = calculate_pending_funding(position, market_state)?;
+    let collateral = (i128::from(position.collateral) - i128::from(funding_payment)).max(0);
+
+    // ratio(P) = (collateral ± size * (P - entry)) / (size * P) solved for ratio = min:
+    // long:  P = (collateral - size * entry) / (size * (min - 1))
+    // short: P = (collateral + size * entry) / (size * (min + 1))
+    let precision = i128::from(PRECISION);
+    let size = i128::from(position.base_amount.unsigned_abs());
+    let entry_quote = math::mul_div_signed(size, position.entry_price.into(), precision)?;
+    let (equity_at_zero, ratio_offset) = if position.base_amount > 0 {
+        (collateral - entry_quote, i128::from(min_collateral_ratio) - precision)
+    } else {
+        (collateral + entry_quote, i128::from(min_collateral_ratio) + precision)
     };
 
-    Ok(pnl)
+    let denominator = size.checked_mul(ratio_offset).ok_or(ProgramError::InvalidArgument)?;
+    if denominator == 0 {
+        return Ok(None);
+    }
+    let price = math::mul_div_signed(equity_at_zero, precision * precision, denominator)?;
+
+    if price <= 0 {
+        return Ok(None);
+    }
+
+    Ok(Some(math::saturating_to_u64(price.unsigned_abs())))
 }
 
 #[cfg(test)]
@@ -0,0 +1,683 @@
+//! Liquidity provider pool
+//!
+//! Each market can have a PoolState ([POOL_SEED, market_state]) holding quote
+//! liquidity from LPs, who own it pro rata through the pool's LP share mint
+//! ([LP_MINT_SEED, pool], minted and burned by the vault PDA). The pool is the
+//! counterparty to the market's traders: profits they realize are paid out of
+//! its liquidity and losses they realize are added to it.
+//!
+//! Trades settle PnL through MarketState's realized profit and loss totals, so
+//! the pool catches up on them whenever it is touched (`PoolState::sync`)
+//! instead of being passed to every trading instruction. Shares are priced at
+//! the pool's NAV: its liquidity minus the traders' unrealized PnL.
+//!
+//! Withdrawals are two-step so an LP can't exit just ahead of a large trader
+//! win: `request_withdrawal` burns the shares and records their value, and
+//! `execute_withdrawal` pays the lesser of that and their value at the time,
+//! after the pool's cooldown and within its per-epoch withdrawal cap.
+
+use borsh::{BorshDeserialize, BorshSerialize};
+use shank::ShankAccount;
+use solana_program::{
+    account_info::{next_account_info, AccountInfo},
+    entrypoint::ProgramResult,
+    msg,
+    program::{invoke, invoke_signed},
+    program_error::ProgramError,
+    pubkey::Pubkey,
+    system_instruction,
+    sysvar::{clock::Clock, rent::Rent, Sysvar},
+};
+
+use crate::math::{self, PRECISION};
+use crate::{
+    calculate_total_unrealized_pnl, close_program_account, create_burn_checked_instruction, create_initialize_mint2_instruction,
+    create_mint_to_checked_instruction, create_transfer_checked_instruction, load_account, load_config,
+    load_admin_config, require_not_settlement_only, require_vault_covers, store_account, validate_mint, validate_quote_mint,
+    validate_market_vault, validate_token_account, vault_pda, AccountType, MarketState, DISCRIMINATOR_LEN, MINT_LEN, PDA_SEED,
+};
+
+/// Seed prefix for pool PDAs: [POOL_SEED, market_state]
+pub const POOL_SEED: &[u8] = b"pool";
+
+/// Seed prefix for LP share mint PDAs: [LP_MINT_SEED, pool]
+pub const LP_MINT_SEED: &[u8] = b"lp_mint";
+
+/// Seed prefix for withdrawal request PDAs: [WITHDRAWAL_SEED, pool, provider]
+pub const WITHDRAWAL_SEED: &[u8] = b"lp_withdrawal";
+
+/// Default slots between requesting and executing a withdrawal (~1 day)
+pub const DEFAULT_WITHDRAWAL_COOLDOWN_SLOTS: u64 = 216_000;
+
+/// Default share of the pool's liquidity that can be withdrawn per epoch (25%, 1e9 precision)
+pub const DEFAULT_MAX_EPOCH_WITHDRAWAL_SHARE: u64 = 250_000_000;
+
+/// A market's LP pool, the counterparty to its traders
+#[derive(BorshSerialize, BorshDeserialize, Debug, Default, Clone, PartialEq, Eq, ShankAccount)]
+pub struct PoolState {
+    /// Market state account the pool backs
+    pub market: Pubkey,
+    /// Pool value: deposits plus trader losses minus trader profits (quote token)
+    pub liquidity: u64,
+    /// Total LP shares outstanding (the LP mint's supply)
+    pub lp_shares: u64,
+    /// Market's total_realized_profit already charged to the pool
+    pub synced_realized_profit: u64,
+    /// Market's total_realized_loss already credited to the pool
+    pub synced_realized_loss: u64,
+    /// PDA bump for [POOL_SEED, market]
+    pub bump: u8,
+    /// SPL mint of the pool's LP shares (PDA: [LP_MINT_SEED, pool])
+    pub lp_mint: Pubkey,
+    /// Slots a withdrawal request waits before it can be executed
+    pub withdrawal_cooldown_slots: u64,
+    /// Share of the liquidity at an epoch's start withdrawable during it (1e9 precision)
+    pub max_epoch_withdrawal_share: u64,
+    /// Epoch the withdrawal cap is currently tracking
+    pub withdrawal_epoch: u64,
+    /// Liquidity when `withdrawal_epoch` was first touched
+    pub epoch_start_liquidity: u64,
+    /// Liquidity paid out to withdrawals during `withdrawal_epoch`
+    pub epoch_withdrawn: u64,
+    /// Shares burned by pending withdrawal requests; still counted in `lp_shares`
+    pub queued_shares: u64,
+}
+
+impl AccountType for PoolState {
+    const DISCRIMINATOR: [u8; DISCRIMINATOR_LEN] = *b"lppool\0\0";
+    /// market + four u64 fields + bump + lp_mint + six withdrawal u64 fields
+    const LEN: usize = 32 + 8 * 4 + 1 + 32 + 8 * 6;
+}
+
+/// A provider's pending withdrawal: shares already burned, awaiting payout
+#[derive(BorshSerialize, BorshDeserialize, Debug, Default, Clone, PartialEq, Eq, ShankAccount)]
+pub struct WithdrawalRequest {
+    /// Provider who burned the shares and receives the payout
+    pub provider: Pubkey,
+    /// Pool the shares were redeemed from
+    pub pool: Pubkey,
+    /// Shares burned
+    pub shares: u64,
+    /// Their value at the pool's NAV when requested; the payout never exceeds it
+    pub value: u64,
+    /// First slot the withdrawal can be executed
+    pub unlock_slot: u64,
+    /// PDA bump for [WITHDRAWAL_SEED, pool, provider]
+    pub bump: u8,
+}
+
+impl AccountType for WithdrawalRequest {
+    const DISCRIMINATOR: [u8; DISCRIMINATOR_LEN] = *b"lpwdreq\0";
+    /// provider + pool + three u64 fields + bump
+    const LEN: usize = 32 + 32 + 8 * 3 + 1;
+}
+
+impl PoolState {
+    /// Apply the trader PnL the market realized since the last sync. A pool that
+    /// owes more than it holds is emptied rather than going negative.
+    pub fn sync(&mut self, market_state: &MarketState) -> Result<(), ProgramError> {
+        let profit = market_state.total_realized_profit
+            .checked_sub(self.synced_realized_profit)
+            .ok_or(ProgramError::InvalidAccountData)?;
+        let loss = market_state.total_realized_loss
+            .checked_sub(self.synced_realized_loss)
+            .ok_or(ProgramError::InvalidAccountData)?;
+
+        let liquidity = (self.liquidity as i128) + (loss as i128) - (profit as i128);
+        self.liquidity = u64::try_from(liquidity.max(0)).unwrap_or(u64::MAX);
+        self.synced_realized_profit = market_state.total_realized_profit;
+        self.synced_realized_loss = market_state.total_realized_loss;
+
+        Ok(())
+    }
+
+    /// Net asset value: synced liquidity minus what the market's traders would
+    /// realize closing at the mark price, floored at zero
+    pub fn nav(&self, market_state: &MarketState) -> Result<u64, ProgramError> {
+        let trader_pnl = calculate_total_unrealized_pnl(
+            market_state.net_base_amount,
+            market_state.net_entry_quote,
+            market_state.mark_price,
+        )?;
+        let nav = (self.liquidity as i128) - trader_pnl as i128;
+
+        Ok(u64::try_from(nav.max(0)).unwrap_or(u64::MAX))
+    }
+
+    /// Start tracking a new epoch's withdrawals from the current liquidity
+    pub fn roll_withdrawal_epoch(&mut self, epoch: u64) {
+        if epoch != self.withdrawal_epoch {
+            self.withdrawal_epoch = epoch;
+            self.epoch_start_liquidity = self.liquidity;
+            self.epoch_withdrawn = 0;
+        }
+    }
+
+    /// Liquidity still withdrawable in the current epoch
+    pub fn epoch_withdrawal_remaining(&self) -> u64 {
+        let cap = math::mul_scaled(self.epoch_start_liquidity, self.max_epoch_withdrawal_share).unwrap_or(u64::MAX);
+
+        cap.saturating_sub(self.epoch_withdrawn)
+    }
+}
+
+/// Shares minted for depositing `amount` into a pool worth `nav` with
+/// `lp_shares` outstanding; the first deposit (or one into an emptied pool) mints 1:1
+pub fn calculate_lp_shares_for_deposit(amount: u64, nav: u64, lp_shares: u64) -> Result<u64, ProgramError> {
+    if lp_shares == 0 || nav == 0 {
+        return Ok(amount);
+    }
+
+    math::to_u64(math::mul_div(amount.into(), lp_shares.into(), nav.into())?)
+}
+
+/// Quote tokens paid for redeeming `shares` of a pool worth `nav` with
+/// `lp_shares` outstanding
+pub fn calculate_lp_withdrawal(shares: u64, nav: u64, lp_shares: u64) -> Result<u64, ProgramError> {
+    if shares > lp_shares {
+        return Err(ProgramError::InsufficientFunds);
+    }
+    if lp_shares == 0 {
+        return Ok(0);
+    }
+
+    math::to_u64(math::mul_div(shares.into(), nav.into(), lp_shares.into())?)
+}
+
+// ---------------------------------------------------------------------
+// 3️⃣6️⃣ Create a market's LP pool and its share mint
+// ---------------------------------------------------------------------
+pub fn create_pool(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramResult {
+    // Accounts:
+    // 0. [signer, writable] payer
+    // 1. [writable] pool state (PDA: [POOL_SEED, market_state])
+    // 2. [writable] LP share mint (PDA: [LP_MINT_SEED, pool])
+    // 3. [] market state account
+    // 4. [] token program (the quote mint's)
+    // 5. [] rent sysvar
+    // 6. [] system program
+    // 7. [] config account
+    // 8. [] quote mint
+    let accounts_iter = &mut accounts.iter();
+    let payer = next_account_info(accounts_iter)?;
+    let pool_acc = next_account_info(accounts_iter)?;
+    let lp_mint = next_account_info(accounts_iter)?;
+    let market_state_acc = next_account_info(accounts_iter)?;
+    let token_program = next_account_info(accounts_iter)?;
+    let rent_sysvar = next_account_info(accounts_iter)?;
+    let system_program = next_account_info(accounts_iter)?;
+    let config_acc = next_account_info(accounts_iter)?;
+    let quote_mint = next_account_info(accounts_iter)?;
+
+    if !payer.is_signer {
+        msg!("Payer must be signer");
+        return Err(ProgramError::MissingRequiredSignature);
+    }
+
+    let config = load_config(program_id, config_acc)?;
+    validate_quote_mint(&config, token_program, quote_mint)?;
+
+    if market_state_acc.owner != program_id {
+        msg!("Market state is not owned by the program");
+        return Err(ProgramError::IncorrectProgramId);
+    }
+    let market_state = *MarketState::load(&market_state_acc.data.borrow())?;
+
+    let (expected_pool, pool_bump) =
+        Pubkey::find_program_address(&[POOL_SEED, market_state_acc.key.as_ref()], program_id);
+    if *pool_acc.key != expected_pool {
+        msg!("Pool is not the correct PDA. Expected: {}, Got: {}", expected_pool, pool_acc.key);
+        return Err(ProgramError::InvalidArgument);
+    }
+    let (expected_mint, mint_bump) = Pubkey::find_program_address(&[LP_MINT_SEED, pool_acc.key.as_ref()], program_id);
+    if *lp_mint.key != expected_mint {
+        msg!("LP mint is not the correct PDA. Expected: {}, Got: {}", expected_mint, lp_mint.key);
+        return Err(ProgramError::InvalidArgument);
+    }
+
+    if !pool_acc.data_is_empty() {
+        msg!("Pool already exists");
+        return Err(ProgramError::AccountAlreadyInitialized);
+    }
+
+    let rent = Rent::from_account_info(rent_sysvar)?;
+    invoke_signed(&system_instruction::create_account(
+        payer.key,
+        pool_acc.key,
+        rent.minimum_balance(PoolState::SPACE),
+        PoolState::SPACE as u64,
+        program_id,
+    ), &[
+        payer.clone(),
+        pool_acc.clone(),
+        system_program.clone(),
+    ], &[&[POOL_SEED, market_state_acc.key.as_ref(), &[pool_bump]]])?;
+
+    // The mint lives under the quote mint's token program, with the vault PDA as its authority
+    invoke_signed(&system_instruction::create_account(
+        payer.key,
+        lp_mint.key,
+        rent.minimum_balance(MINT_LEN),
+        MINT_LEN as u64,
+        token_program.key,
+    ), &[
+        payer.clone(),
+        lp_mint.clone(),
+        system_program.clone(),
+    ], &[&[LP_MINT_SEED, pool_acc.key.as_ref(), &[mint_bump]]])?;
+
+    // Shares are minted in 1e9 precision whatever the quote mint's decimals
+    let vault_authority = vault_pda(program_id, market_state.bump)?;
+    invoke(
+        &create_initialize_mint2_instruction(token_program.key, lp_mint.key, &vault_authority, math::PRECISION_DECIMALS),
+        &[lp_mint.clone(), token_program.clone()],
+    )?;
+
+    // PnL realized before the pool existed is not the pool's
+    let pool = PoolState {
+        market: *market_state_acc.key,
+        synced_realized_profit: market_state.total_realized_profit,
+        synced_realized_loss: market_state.total_realized_loss,
+        bump: pool_bump,
+        lp_mint: *lp_mint.key,
+        withdrawal_cooldown_slots: DEFAULT_WITHDRAWAL_COOLDOWN_SLOTS,
+        max_epoch_withdrawal_share: DEFAULT_MAX_EPOCH_WITHDRAWAL_SHARE,
+        ..PoolState::default()
+    };
+    store_account(&pool, &mut pool_acc.data.borrow_mut())?;
+
+    msg!("Created LP pool for market {} with share mint {}", market_state_acc.key, lp_mint.key);
+
+    Ok(())
+}
+
+// ---------------------------------------------------------------------
+// 3️⃣4️⃣ Deposit liquidity into a market's LP pool for minted shares
+// ---------------------------------------------------------------------
+pub fn deposit_liquidity(program_id: &Pubkey, accounts: &[AccountInfo], amount: u64) -> ProgramResult {
+    // Accounts:
+    // 0. [signer] provider
+    // 1. [] token program
+    // 2. [writable] provider's quote token account
+    // 3. [writable] vault token account (PDA; also the LP mint authority)
+    // 4. [writable] pool state
+    // 5. [writable] LP share mint
+    // 6. [writable] provider's LP share token account
+    // 7. [] market state account the pool backs
+    // 8. [] config account
+    // 9. [] quote mint
+    let accounts_iter = &mut accounts.iter();
+    let provider = next_account_info(accounts_iter)?;
+    let token_program = next_account_info(accounts_iter)?;
+    let provider_token_acc = next_account_info(accounts_iter)?;
+    let vault = next_account_info(accounts_iter)?;
+    let pool_acc = next_account_info(accounts_iter)?;
+    let lp_mint = next_account_info(accounts_iter)?;
+    let provider_lp_acc = next_account_info(accounts_iter)?;
+    let market_state_acc = next_account_info(accounts_iter)?;
+    let config_acc = next_account_info(accounts_iter)?;
+    let quote_mint = next_account_info(accounts_iter)?;
+
+    if !provider.is_signer {
+        msg!("Provider must be signer");
+        return Err(ProgramError::MissingRequiredSignature);
+    }
+
+    if amount == 0 {
+        msg!("Deposit amount must be non-zero");
+        return Err(ProgramError::InvalidArgument);
+    }
+
+    let config = load_config(program_id, config_acc)?;
+    require_not_settlement_only(&config)?;
+    let quote_decimals = validate_quote_mint(&config, token_program, quote_mint)?;
+    validate_token_account(token_program.key, provider_token_acc, quote_mint.key, Some(provider.key))?;
+
+    let (mut pool, market_state) = load_pool(program_id, pool_acc, market_state_acc)?;
+    let (pda, bump) = validate_market_vault(program_id, token_program, vault, &market_state, quote_mint)?;
+    let lp_decimals = validate_mint(token_program, lp_mint, &pool.lp_mint)?;
+    validate_token_account(token_program.key, provider_lp_acc, &pool.lp_mint, None)?;
+
+    // Price shares off the pool's NAV after the latest trader PnL
+    pool.sync(&market_state)?;
+    let liquidity = market_state.quote_to_precision(amount)?;
+    let shares = calculate_lp_shares_for_deposit(liquidity, pool.nav(&market_state)?, pool.lp_shares)?;
+    if shares == 0 {
+        msg!("Deposit too small to mint a share");
+        return Err(ProgramError::InvalidArgument);
+    }
+
+    let transfer_ix = create_transfer_checked_instruction(
+        token_program.key,
+        provider_token_acc.key,
+        quote_mint.key,
+        vault.key,
+        provider.key,
+        amount,
+        quote_decimals,
+    )?;
+
+    invoke(&transfer_ix, &[
+        provider_token_acc.clone(),
+        quote_mint.clone(),
+        vault.clone(),
+        provider.clone(),
+        token_program.clone(),
+    ])?;
+
+    let mint_ix = create_mint_to_checked_instruction(
+        token_program.key,
+        lp_mint.key,
+        provider_lp_acc.key,
+        &pda,
+        shares,
+        lp_decimals,
+    );
+
+    invoke_signed(&mint_ix, &[
+        lp_mint.clone(),
+        provider_lp_acc.clone(),
+        vault.clone(), // PDA authority
+        token_program.clone(),
+    ], &[&[PDA_SEED, &[bump]]])?;
+
+    pool.liquidity = pool.liquidity
+        .checked_add(liquidity)
+        .ok_or(ProgramError::InvalidArgument)?;
+    pool.lp_shares = pool.lp_shares
+        .checked_add(shares)
+        .ok_or(ProgramError::InvalidArgument)?;
+    store_account(&pool, &mut pool_acc.data.borrow_mut())?;
+
+    msg!("Deposited {} liquidity for {} shares; pool liquidity={}, shares={}",
+         amount, shares, pool.liquidity, pool.lp_shares);
+
+    Ok(())
+}
+
+// ---------------------------------------------------------------------
+// 3️⃣5️⃣ Request a withdrawal by burning LP shares
+// ---------------------------------------------------------------------
+pub fn request_withdrawal(program_id: &Pubkey, accounts: &[AccountInfo], shares: u64) -> ProgramResult {
+    // Accounts:
+    // 0. [signer, writable] provider (pays for the request account)
+    // 1. [] token program
+    // 2. [writable] pool state
+    // 3. [writable] LP share mint
+    // 4. [writable] provider's LP share token account
+    // 5. [writable] withdrawal request (PDA: [WITHDRAWAL_SEED, pool, provider])
+    // 6. [] market state account the pool backs
+    // 7. [] clock sysvar
+    // 8. [] rent sysvar
+    // 9. [] system program
+    let accounts_iter = &mut accounts.iter();
+    let provider = next_account_info(accounts_iter)?;
+    let token_program = next_account_info(accounts_iter)?;
+    let pool_acc = next_account_info(accounts_iter)?;
+    let lp_mint = next_account_info(accounts_iter)?;
+    let provider_lp_acc = next_account_info(accounts_iter)?;
+    let request_acc = next_account_info(accounts_iter)?;
+    let market_state_acc = next_account_info(accounts_iter)?;
+    let clock_sysvar = next_account_info(accounts_iter)?;
+    let rent_sysvar = next_account_info(accounts_iter)?;
+    let system_program = next_account_info(accounts_iter)?;
+
+    if !provider.is_signer {
+        msg!("Provider must be signer");
+        return Err(ProgramError::MissingRequiredSignature);
+    }
+
+    if shares == 0 {
+        msg!("Share amount must be non-zero");
+        return Err(ProgramError::InvalidArgument);
+    }
+
+    let (mut pool, market_state) = load_pool(program_id, pool_acc, market_state_acc)?;
+    let lp_decimals = validate_mint(token_program, lp_mint, &pool.lp_mint)?;
+    validate_token_account(token_program.key, provider_lp_acc, &pool.lp_mint, Some(provider.key))?;
+
+    let (expected_request, request_bump) = Pubkey::find_program_address(
+        &[WITHDRAWAL_SEED, pool_acc.key.as_ref(), provider.key.as_ref()],
+        program_id,
+    );
+    if *request_acc.key != expected_request {
+        msg!("Withdrawal request is not the correct PDA. Expected: {}, Got: {}", expected_request, request_acc.key);
+        return Err(ProgramError::InvalidArgument);
+    }
+    if !request_acc.data_is_empty() {
+        msg!("A withdrawal request is already pending; execute it first");
+        return Err(ProgramError::AccountAlreadyInitialized);
+    }
+
+    pool.sync(&market_state)?;
+    let value = calculate_lp_withdrawal(shares, pool.nav(&market_state)?, pool.lp_shares)?;
+
+    // Burning fails unless the provider holds the shares
+    let burn_ix = create_burn_checked_instruction(
+        token_program.key,
+        provider_lp_acc.key,
+        lp_mint.key,
+        provider.key,
+        shares,
+        lp_decimals,
+    );
+
+    invoke(&burn_ix, &[
+        provider_lp_acc.clone(),
+        lp_mint.clone(),
+        provider.clone(),
+        token_program.clone(),
+    ])?;
+
+    let rent = Rent::from_account_info(rent_sysvar)?;
+    invoke_signed(&system_instruction::create_account(
+        provider.key,
+        request_acc.key,
+        rent.minimum_balance(WithdrawalRequest::SPACE),
+        WithdrawalRequest::SPACE as u64,
+        program_id,
+    ), &[
+        provider.clone(),
+        request_acc.clone(),
+        system_program.clone(),
+    ], &[&[WITHDRAWAL_SEED, pool_acc.key.as_ref(), provider.key.as_ref(), &[request_bump]]])?;
+
+    let clock = Clock::from_account_info(clock_sysvar)?;
+    let request = WithdrawalRequest {
+        provider: *provider.key,
+        pool: *pool_acc.key,
+        shares,
+        value,
+        unlock_slot: clock.slot.saturating_add(pool.withdrawal_cooldown_slots),
+        bump: request_bump,
+    };
+    store_account(&request, &mut request_acc.data.borrow_mut())?;
+
+    // The burned shares keep their claim on the pool until the request is paid out
+    pool.queued_shares = pool.queued_shares
+        .checked_add(shares)
+        .ok_or(ProgramError::InvalidArgument)?;
+    store_account(&pool, &mut pool_acc.data.borrow_mut())?;
+
+    msg!("Requested withdrawal of {} shares worth {}; executable from slot {}",
+         shares, value, request.unlock_slot);
+
+    Ok(())
+}
+
+// ---------------------------------------------------------------------
+// 3️⃣8️⃣ Pay out a withdrawal request after its cooldown
+// ---------------------------------------------------------------------
+pub fn execute_withdrawal(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramResult {
+    // Accounts:
+    // 0. [signer, writable] provider (receives the request account's rent)
+    // 1. [] token program
+    // 2. [writable] provider's quote token account
+    // 3. [writable] vault token account (PDA)
+    // 4. [writable] pool state
+    // 5. [writable] withdrawal request
+    // 6. [] market state account the pool backs
+    // 7. [] clock sysvar
+    // 8. [] config account
+    // 9. [] quote mint
+    let accounts_iter = &mut accounts.iter();
+    let provider = next_account_info(accounts_iter)?;
+    let token_program = next_account_info(accounts_iter)?;
+    let provider_token_acc = next_account_info(accounts_iter)?;
+    let vault = next_account_info(accounts_iter)?;
+    let pool_acc = next_account_info(accounts_iter)?;
+    let request_acc = next_account_info(accounts_iter)?;
+    let market_state_acc = next_account_info(accounts_iter)?;
+    let clock_sysvar = next_account_info(accounts_iter)?;
+    let config_acc = next_account_info(accounts_iter)?;
+    let quote_mint = next_account_info(accounts_iter)?;
+
+    if !provider.is_signer {
+        msg!("Provider must be signer");
+        return Err(ProgramError::MissingRequiredSignature);
+    }
+
+    let config = load_config(program_id, config_acc)?;
+    let quote_decimals = validate_quote_mint(&config, token_program, quote_mint)?;
+    validate_token_account(token_program.key, provider_token_acc, quote_mint.key, None)?;
+
+    let (mut pool, market_state) = load_pool(program_id, pool_acc, market_state_acc)?;
+    let (pda, bump) = validate_market_vault(program_id, token_program, vault, &market_state, quote_mint)?;
+
+    if request_acc.owner != program_id {
+        msg!("Withdrawal request is not owned by the program");
+        return Err(ProgramError::IncorrectProgramId);
+    }
+    let request = load_account::<WithdrawalRequest>(&request_acc.data.borrow())?;
+    if request.provider != *provider.key || request.pool != *pool_acc.key {
+        msg!("Withdrawal request belongs to provider {} in pool {}", request.provider, request.pool);
+        return Err(ProgramError::IllegalOwner);
+    }
+
+    let clock = Clock::from_account_info(clock_sysvar)?;
+    if clock.slot < request.unlock_slot {
+        msg!("Withdrawal is cooling down until slot {} (current {})", request.unlock_slot, clock.slot);
+        return Err(ProgramError::InvalidArgument);
+    }
+
+    // Pay the value at request time unless the shares have lost value since
+    pool.sync(&market_state)?;
+    let current_value = calculate_lp_withdrawal(request.shares, pool.nav(&market_state)?, pool.lp_shares)?;
+    let amount = request.value.min(current_value);
+
+    // NAV counts traders' unrealized losses, but only realized ones are in the pool yet
+    if amount > pool.liquidity {
+        msg!("Withdrawal of {} exceeds realized pool liquidity {}; settle trader PnL first", amount, pool.liquidity);
+        return Err(ProgramError::InsufficientFunds);
+    }
+
+    pool.roll_withdrawal_epoch(clock.epoch);
+    let remaining = pool.epoch_withdrawal_remaining();
+    if amount > remaining {
+        msg!("Withdrawal of {} exceeds the {} left under epoch {}'s cap; retry next epoch",
+             amount, remaining, pool.withdrawal_epoch);
+        return Err(ProgramError::InsufficientFunds);
+    }
+
+    pool.liquidity -= amount;
+    pool.lp_shares -= request.shares;
+    pool.queued_shares = pool.queued_shares.saturating_sub(request.shares);
+    pool.epoch_withdrawn += amount;
+    store_account(&pool, &mut pool_acc.data.borrow_mut())?;
+
+    close_program_account(request_acc, provider)?;
+
+    if amount > 0 {
+        let transfer_ix = create_transfer_checked_instruction(
+            token_program.key,
+            vault.key,
+            quote_mint.key,
+            provider_token_acc.key,
+            &pda,
+            market_state.quote_from_precision(amount)?,
+            quote_decimals,
+        )?;
+
+        invoke_signed(&transfer_ix, &[
+            vault.clone(),
+            quote_mint.clone(),
+            provider_token_acc.clone(),
+            vault.clone(), // PDA authority
+            token_program.clone(),
+        ], &[&[PDA_SEED, &[bump]]])?;
+        require_vault_covers(vault, market_state.reserves().saturating_add(pool.liquidity), quote_decimals)?;
+    }
+
+    msg!("Withdrew {} liquidity for {} shares (requested value {}); pool liquidity={}, shares={}",
+         amount, request.shares, request.value, pool.liquidity, pool.lp_shares);
+
+    Ok(())
+}
+
+// ---------------------------------------------------------------------
+// 3️⃣9️⃣ Set a pool's withdrawal cooldown and per-epoch cap (admin only)
+// ---------------------------------------------------------------------
+pub fn set_pool_params(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    withdrawal_cooldown_slots: u64,
+    max_epoch_withdrawal_share: u64,
+) -> ProgramResult {
+    // Accounts:
+    // 0. [signer] admin
+    // 1. [] config account
+    // 2. [writable] pool state
+    let accounts_iter = &mut accounts.iter();
+    let admin = next_account_info(accounts_iter)?;
+    let config_acc = next_account_info(accounts_iter)?;
+    let pool_acc = next_account_info(accounts_iter)?;
+
+    load_admin_config(program_id, admin, config_acc)?;
+
+    if max_epoch_withdrawal_share == 0 || max_epoch_withdrawal_share > PRECISION {
+        msg!("Epoch withdrawal share must be in (0, 100%]: {}", max_epoch_withdrawal_share);
+        return Err(ProgramError::InvalidArgument);
+    }
+
+    if pool_acc.owner != program_id {
+        msg!("Pool is not owned by the program");
+        return Err(ProgramError::IncorrectProgramId);
+    }
+    let mut pool = load_account::<PoolState>(&pool_acc.data.borrow())?;
+    pool.withdrawal_cooldown_slots = withdrawal_cooldown_slots;
+    pool.max_epoch_withdrawal_share = max_epoch_withdrawal_share;
+    store_account(&pool, &mut pool_acc.data.borrow_mut())?;
+
+    msg!("Pool {} params updated: cooldown={} slots, max epoch withdrawal share={}",
+         pool_acc.key, withdrawal_cooldown_slots, max_epoch_withdrawal_share);
+
+    Ok(())
+}
+
+/// Load a pool and a copy of the market state it backs, checking the pool is the market's PDA
+pub(crate) fn load_pool(
+    program_id: &Pubkey,
+    pool_acc: &AccountInfo,
+    market_state_acc: &AccountInfo,
+) -> Result<(PoolState, MarketState), ProgramError> {
+    if pool_acc.owner != program_id || market_state_acc.owner != program_id {
+        msg!("Pool and market state must be owned by the program");
+        return Err(ProgramError::IncorrectProgramId);
+    }
+
+    let pool = load_account::<PoolState>(&pool_acc.data.borrow())?;
+    if pool.market != *market_state_acc.key {
+        msg!("Pool market mismatch. Expected: {}, Got: {}", market_state_acc.key, pool.market);
+        return Err(ProgramError::InvalidArgument);
+    }
+
+    let expected_pool = Pubkey::create_program_address(&[POOL_SEED, pool.market.as_ref(), &[pool.bump]], program_id)?;
+    if *pool_acc.key != expected_pool {
+        msg!("Pool is not the correct PDA. Expected: {}, Got: {}", expected_pool, pool_acc.key);
+        return Err(ProgramError::InvalidArgument);
+    }
+
+    let market_state = *MarketState::load(&market_state_acc.data.borrow())?;
+
+    Ok((pool, market_state))
+}
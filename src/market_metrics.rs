@@ -0,0 +1,175 @@
+//! Per-market cumulative metrics
+//!
+//! Anyone can create a market's MarketMetrics account ([MARKET_METRICS_SEED,
+//! market_state]), which accumulates the market's total volume, fees and
+//! liquidations and its peak open interest, so dashboards can read aggregate
+//! stats with one account fetch instead of indexing every transaction.
+//!
+//! Like user stats, the handlers that update it only do so when it is passed
+//! among their trailing accounts, where it is recognized by its PDA: a counter
+//! bump and a Borsh round trip, no extra checks. Metrics only cover what
+//! happened while the account existed and was passed.
+
+use borsh::{BorshDeserialize, BorshSerialize};
+use shank::ShankAccount;
+use solana_program::{
+    account_info::{next_account_info, AccountInfo},
+    entrypoint::ProgramResult,
+    msg,
+    program::invoke_signed,
+    program_error::ProgramError,
+    pubkey::Pubkey,
+    system_instruction,
+    sysvar::{rent::Rent, Sysvar},
+};
+
+use crate::{load_account, probe_account, store_account, AccountType, MarketState, DISCRIMINATOR_LEN};
+
+/// Seed prefix for market metrics PDAs: [MARKET_METRICS_SEED, market_state]
+pub const MARKET_METRICS_SEED: &[u8] = b"market_metrics";
+
+/// A market's cumulative trading statistics
+#[derive(BorshSerialize, BorshDeserialize, Debug, Default, Clone, PartialEq, Eq, ShankAccount)]
+pub struct MarketMetrics {
+    /// Market state account the metrics cover
+    pub market: Pubkey,
+    /// Notional traded, including liquidations (1e9 precision)
+    pub total_volume: u64,
+    /// Trading and liquidation fees charged (1e9 precision)
+    pub total_fees: u64,
+    /// Liquidations, partial ones and backstop takeovers included
+    pub total_liquidations: u64,
+    /// Highest open interest recorded after a trade (sum of |base_amount|)
+    pub peak_open_interest: u64,
+    /// PDA bump for [MARKET_METRICS_SEED, market_state]
+    pub bump: u8,
+}
+
+impl AccountType for MarketMetrics {
+    const DISCRIMINATOR: [u8; DISCRIMINATOR_LEN] = *b"mktmetrc";
+    /// market + four u64 counters + bump
+    const LEN: usize = 32 + 8 * 4 + 1;
+}
+
+impl MarketMetrics {
+    /// Count a trade of `notional` that paid `fee` and left `open_interest` open. Metrics
+    /// saturate rather than fail a trade.
+    pub fn record_trade(&mut self, notional: u64, fee: u64, open_interest: u64) {
+        self.total_volume = self.total_volume.saturating_add(notional);
+        self.total_fees = self.total_fees.saturating_add(fee);
+        self.peak_open_interest = self.peak_open_interest.max(open_interest);
+    }
+
+    /// Count a liquidation that closed `notional` and charged `fee`
+    pub fn record_liquidation(&mut self, notional: u64, fee: u64, open_interest: u64) {
+        self.record_trade(notional, fee, open_interest);
+        self.total_liquidations = self.total_liquidations.saturating_add(1);
+    }
+}
+
+/// Market metrics PDA of `market_state`
+pub fn market_metrics_address(program_id: &Pubkey, market_state: &Pubkey) -> (Pubkey, u8) {
+    Pubkey::find_program_address(&[MARKET_METRICS_SEED, market_state.as_ref()], program_id)
+}
+
+// ---------------------------------------------------------------------
+// 8️⃣8️⃣ Create a market's cumulative metrics account (permissionless)
+// ---------------------------------------------------------------------
+pub fn create_market_metrics(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramResult {
+    // Accounts:
+    // 0. [signer, writable] payer
+    // 1. [] market state account
+    // 2. [writable] market metrics account (PDA: [MARKET_METRICS_SEED, market_state])
+    // 3. [] rent sysvar
+    // 4. [] system program
+    let accounts_iter = &mut accounts.iter();
+    let payer = next_account_info(accounts_iter)?;
+    let market_state_acc = next_account_info(accounts_iter)?;
+    let metrics_acc = next_account_info(accounts_iter)?;
+    let rent_sysvar = next_account_info(accounts_iter)?;
+    let system_program = next_account_info(accounts_iter)?;
+
+    if !payer.is_signer {
+        msg!("Payer must be signer");
+        return Err(ProgramError::MissingRequiredSignature);
+    }
+
+    if market_state_acc.owner != program_id {
+        msg!("Market state is not owned by the program");
+        return Err(ProgramError::IncorrectProgramId);
+    }
+    MarketState::load(&market_state_acc.data.borrow())?;
+
+    let (expected_metrics, bump) = market_metrics_address(program_id, market_state_acc.key);
+    if *metrics_acc.key != expected_metrics {
+        msg!("Market metrics account is not the correct PDA. Expected: {}, Got: {}", expected_metrics, metrics_acc.key);
+        return Err(ProgramError::InvalidArgument);
+    }
+
+    if !metrics_acc.data_is_empty() {
+        msg!("Market metrics account already exists");
+        return Err(ProgramError::AccountAlreadyInitialized);
+    }
+
+    let rent = Rent::from_account_info(rent_sysvar)?;
+    invoke_signed(&system_instruction::create_account(
+        payer.key,
+        metrics_acc.key,
+        rent.minimum_balance(MarketMetrics::SPACE),
+        MarketMetrics::SPACE as u64,
+        program_id,
+    ), &[
+        payer.clone(),
+        metrics_acc.clone(),
+        system_program.clone(),
+    ], &[&[MARKET_METRICS_SEED, market_state_acc.key.as_ref(), &[bump]]])?;
+
+    let metrics = MarketMetrics { market: *market_state_acc.key, bump, ..MarketMetrics::default() };
+    store_account(&metrics, &mut metrics_acc.data.borrow_mut())?;
+
+    msg!("Created market metrics account for {}", market_state_acc.key);
+
+    Ok(())
+}
+
+/// The metrics account of `market` among `accounts`, if it was passed
+pub(crate) fn find_market_metrics<'a, 'info>(
+    program_id: &Pubkey,
+    market: &Pubkey,
+    accounts: &[&'a AccountInfo<'info>],
+) -> Option<&'a AccountInfo<'info>> {
+    accounts.iter().copied().find(|account| {
+        if account.owner != program_id {
+            return false;
+        }
+        // Re-derive the PDA from the stored bump rather than searching for it
+        let Some(metrics) = account
+            .try_borrow_data()
+            .ok()
+            .and_then(|data| probe_account::<MarketMetrics>(&data))
+        else {
+            return false;
+        };
+        metrics.market == *market
+            && Pubkey::create_program_address(&[MARKET_METRICS_SEED, market.as_ref(), &[metrics.bump]], program_id)
+                .is_ok_and(|expected_metrics| expected_metrics == *account.key)
+    })
+}
+
+/// Apply `update` to the metrics of `market` if the account was passed among `accounts`
+pub(crate) fn update_market_metrics(
+    program_id: &Pubkey,
+    market: &Pubkey,
+    accounts: &[&AccountInfo],
+    update: impl FnOnce(&mut MarketMetrics),
+) -> ProgramResult {
+    let Some(metrics_acc) = find_market_metrics(program_id, market, accounts) else {
+        return Ok(());
+    };
+
+    let mut metrics = load_account::<MarketMetrics>(&metrics_acc.data.borrow())?;
+    update(&mut metrics);
+    store_account(&metrics, &mut metrics_acc.data.borrow_mut())?;
+
+    Ok(())
+}
@@ -0,0 +1,108 @@
+//! Fixed-point arithmetic
+//!
+//! Prices, sizes, rates and ratios are integers scaled by PRECISION (1e9).
+//! Every product is formed in u128 / i128 before it is divided, so only a
+//! result that does not fit its type can fail, and results are narrowed back
+//! with checked casts. Division rounds toward zero (down for unsigned values)
+//! unless the function says otherwise.
+//!
+//! Token amounts enter and leave in their mint's native units and are
+//! normalized to PRECISION at that boundary with `to_precision` /
+//! `from_precision`, so the formulas never see a mint's decimals.
+//!
+//! Errors: a result that does not fit is `InvalidArgument` and a zero
+//! denominator is `InvalidAccountData`, since it comes from account state
+//! (an empty reserve, pool or position).
+
+use solana_program::program_error::ProgramError;
+
+/// Fixed-point scale of prices, sizes, rates and ratios (1.0 = 1e9)
+pub const PRECISION: u64 = 1_000_000_000;
+
+/// Decimals of PRECISION: the most a normalized mint may have
+pub const PRECISION_DECIMALS: u8 = 9;
+
+/// `a * b / denominator`, rounded down
+pub fn mul_div(a: u128, b: u128, denominator: u128) -> Result<u128, ProgramError> {
+    let product = a.checked_mul(b).ok_or(ProgramError::InvalidArgument)?;
+    product.checked_div(denominator).ok_or(ProgramError::InvalidAccountData)
+}
+
+/// `a * b / denominator`, rounded up
+pub fn mul_div_ceil(a: u128, b: u128, denominator: u128) -> Result<u128, ProgramError> {
+    if denominator == 0 {
+        return Err(ProgramError::InvalidAccountData);
+    }
+
+    let product = a.checked_mul(b).ok_or(ProgramError::InvalidArgument)?;
+    Ok(product.div_ceil(denominator))
+}
+
+/// Signed `a * b / denominator`, rounded toward zero
+pub fn mul_div_signed(a: i128, b: i128, denominator: i128) -> Result<i128, ProgramError> {
+    let product = a.checked_mul(b).ok_or(ProgramError::InvalidArgument)?;
+    match denominator {
+        0 => Err(ProgramError::InvalidAccountData),
+        denominator => product.checked_div(denominator).ok_or(ProgramError::InvalidArgument),
+    }
+}
+
+/// `a * b / 1e9`: a fixed-point product, e.g. size * price = notional
+pub fn mul_scaled(a: u64, b: u64) -> Result<u64, ProgramError> {
+    to_u64(mul_div(a.into(), b.into(), PRECISION.into())?)
+}
+
+/// Signed `a * b / 1e9`, rounded toward zero, e.g. size * funding index = funding payment
+pub fn mul_scaled_signed(a: i128, b: i128) -> Result<i64, ProgramError> {
+    to_i64(mul_div_signed(a, b, PRECISION.into())?)
+}
+
+/// `a * 1e9 / b`: a fixed-point quotient, e.g. quote / base = price
+pub fn div_scaled(a: u64, b: u64) -> Result<u64, ProgramError> {
+    to_u64(mul_div(a.into(), PRECISION.into(), b.into())?)
+}
+
+/// `numerator / denominator` in 1e9 precision, saturating at u64::MAX, which
+/// also stands for a zero denominator (e.g. a collateral ratio with no exposure)
+pub fn ratio(numerator: u128, denominator: u128) -> u64 {
+    mul_div(numerator, PRECISION.into(), denominator).map_or(u64::MAX, saturating_to_u64)
+}
+
+/// Native units of a token with `decimals` per unit of PRECISION
+fn precision_factor(decimals: u8) -> Result<u64, ProgramError> {
+    PRECISION_DECIMALS
+        .checked_sub(decimals)
+        .map(|shift| 10u64.pow(shift.into()))
+        .ok_or(ProgramError::InvalidArgument)
+}
+
+/// `amount` native units of a token with `decimals` in 1e9 precision (exact)
+pub fn to_precision(amount: u64, decimals: u8) -> Result<u64, ProgramError> {
+    amount.checked_mul(precision_factor(decimals)?).ok_or(ProgramError::InvalidArgument)
+}
+
+/// Signed counterpart of `to_precision`, e.g. for a size change
+pub fn to_precision_signed(amount: i64, decimals: u8) -> Result<i64, ProgramError> {
+    let factor = i64::try_from(precision_factor(decimals)?).map_err(|_| ProgramError::InvalidArgument)?;
+    amount.checked_mul(factor).ok_or(ProgramError::InvalidArgument)
+}
+
+/// `value` in 1e9 precision as native units of a token with `decimals`, rounded down
+pub fn from_precision(value: u64, decimals: u8) -> Result<u64, ProgramError> {
+    Ok(value / precision_factor(decimals)?)
+}
+
+/// Narrow to u64, rejecting values that do not fit
+pub fn to_u64(value: u128) -> Result<u64, ProgramError> {
+    u64::try_from(value).map_err(|_| ProgramError::InvalidArgument)
+}
+
+/// Narrow to i64, rejecting values that do not fit
+pub fn to_i64(value: i128) -> Result<i64, ProgramError> {
+    i64::try_from(value).map_err(|_| ProgramError::InvalidArgument)
+}
+
+/// Narrow to u64, saturating at u64::MAX
+pub fn saturating_to_u64(value: u128) -> u64 {
+    u64::try_from(value).unwrap_or(u64::MAX)
+}
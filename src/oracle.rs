@@ -0,0 +1,222 @@
+//! Oracle price reader
+//!
+//! Reads the aggregate price straight out of a Pyth v2 price account (no SDK
+//! dependency) and normalizes it to the program's 1e9 precision. Prices that
+//! are not in the Trading state or were last published too many slots ago are
+//! rejected. The account's time-weighted EMA price is read the same way for
+//! settling dated futures. Keeper-pushed PriceFeed accounts (see `price_feed`)
+//! are accepted wherever a Pyth account is, told apart by their type tag.
+//!
+//! A market's index can be backed by up to MAX_ORACLE_FEEDS feeds. Their median
+//! valid price is used, so a single stale or broken feed neither halts nor
+//! distorts the market.
+
+use solana_program::{account_info::AccountInfo, msg, program_error::ProgramError, pubkey::Pubkey};
+
+use crate::price_feed;
+
+/// Magic number at the start of every Pyth account
+pub const PYTH_MAGIC: u32 = 0xa1b2_c3d4;
+
+/// Pyth account type tag for price accounts
+pub const PYTH_PRICE_ACCOUNT_TYPE: u32 = 3;
+
+/// Pyth aggregate status meaning the price is currently trading
+pub const PYTH_STATUS_TRADING: u32 = 1;
+
+/// Max slots since the aggregate price was published before it is considered stale
+pub const MAX_ORACLE_STALENESS_SLOTS: u64 = 25;
+
+/// Most feeds a market's index price can be aggregated from: its index oracle and two backups
+pub const MAX_ORACLE_FEEDS: usize = 3;
+
+// Byte offsets into a Pyth v2 price account
+const MAGIC_OFFSET: usize = 0;
+const ACCOUNT_TYPE_OFFSET: usize = 8;
+const EXPO_OFFSET: usize = 20;
+const EMA_PRICE_OFFSET: usize = 48;
+const AGG_PRICE_OFFSET: usize = 208;
+const AGG_CONF_OFFSET: usize = 216;
+const AGG_STATUS_OFFSET: usize = 224;
+const AGG_PUB_SLOT_OFFSET: usize = 232;
+const PRICE_ACCOUNT_MIN_LEN: usize = 240;
+
+/// An oracle price normalized to 1e9 precision
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OraclePrice {
+    /// Aggregate price (1e9 precision)
+    pub price: u64,
+    /// Aggregate confidence interval (1e9 precision)
+    pub conf: u64,
+    /// Slot the aggregate was published in
+    pub publish_slot: u64,
+}
+
+/// Read the price from `oracle_acc`, checking it is the expected feed
+pub fn read_oracle_price(
+    oracle_acc: &AccountInfo,
+    expected_oracle: &Pubkey,
+    current_slot: u64,
+) -> Result<OraclePrice, ProgramError> {
+    if oracle_acc.key != expected_oracle {
+        msg!("Oracle mismatch. Expected: {}, Got: {}", expected_oracle, oracle_acc.key);
+        return Err(ProgramError::InvalidArgument);
+    }
+
+    parse_oracle_price(&oracle_acc.try_borrow_data()?, current_slot)
+}
+
+/// Parse a price feed or Pyth v2 price account, whichever `data` holds
+pub fn parse_oracle_price(data: &[u8], current_slot: u64) -> Result<OraclePrice, ProgramError> {
+    if price_feed::is_price_feed(data) {
+        return price_feed::parse_price_feed(data, current_slot);
+    }
+
+    parse_pyth_price(data, current_slot)
+}
+
+/// Read every feed in `expected_oracles` from `oracle_accs`, passed in the same order, and
+/// take the median of the valid prices. Feeds that are stale or not trading are skipped;
+/// fails if none is valid.
+pub fn read_median_price(
+    oracle_accs: &[&AccountInfo],
+    expected_oracles: &[Pubkey],
+    current_slot: u64,
+) -> Result<OraclePrice, ProgramError> {
+    if oracle_accs.len() < expected_oracles.len() {
+        msg!("Expected {} oracle accounts, got {}", expected_oracles.len(), oracle_accs.len());
+        return Err(ProgramError::NotEnoughAccountKeys);
+    }
+
+    let mut prices = Vec::with_capacity(expected_oracles.len());
+    for (oracle_acc, expected_oracle) in oracle_accs.iter().zip(expected_oracles) {
+        if oracle_acc.key != expected_oracle {
+            msg!("Oracle mismatch. Expected: {}, Got: {}", expected_oracle, oracle_acc.key);
+            return Err(ProgramError::InvalidArgument);
+        }
+
+        match parse_oracle_price(&oracle_acc.try_borrow_data()?, current_slot) {
+            Ok(price) => prices.push(price),
+            Err(_) => msg!("Skipping oracle {}", oracle_acc.key),
+        }
+    }
+
+    calculate_median_price(&mut prices).ok_or_else(|| {
+        msg!("No valid oracle price among {} feeds", expected_oracles.len());
+        ProgramError::InvalidAccountData
+    })
+}
+
+/// Median of `prices`, sorting them. An even count averages the middle two, keeping the
+/// wider confidence and older publish slot; `None` if empty.
+pub fn calculate_median_price(prices: &mut [OraclePrice]) -> Option<OraclePrice> {
+    prices.sort_unstable_by_key(|price| price.price);
+    let middle = prices.len() / 2;
+    match prices.len() {
+        0 => None,
+        len if len % 2 == 1 => Some(prices[middle]),
+        _ => {
+            let (low, high) = (prices[middle - 1], prices[middle]);
+            Some(OraclePrice {
+                price: low.price + (high.price - low.price) / 2,
+                conf: low.conf.max(high.conf),
+                publish_slot: low.publish_slot.min(high.publish_slot),
+            })
+        }
+    }
+}
+
+/// Parse a Pyth v2 price account's aggregate price
+pub fn parse_pyth_price(data: &[u8], current_slot: u64) -> Result<OraclePrice, ProgramError> {
+    if data.len() < PRICE_ACCOUNT_MIN_LEN
+        || read_u32(data, MAGIC_OFFSET) != PYTH_MAGIC
+        || read_u32(data, ACCOUNT_TYPE_OFFSET) != PYTH_PRICE_ACCOUNT_TYPE
+    {
+        msg!("Oracle account is not a Pyth price account");
+        return Err(ProgramError::InvalidAccountData);
+    }
+
+    let status = read_u32(data, AGG_STATUS_OFFSET);
+    if status != PYTH_STATUS_TRADING {
+        msg!("Oracle price is not trading (status {})", status);
+        return Err(ProgramError::InvalidAccountData);
+    }
+
+    let publish_slot = read_u64(data, AGG_PUB_SLOT_OFFSET);
+    if current_slot.saturating_sub(publish_slot) > MAX_ORACLE_STALENESS_SLOTS {
+        msg!("Oracle price is stale: published at slot {}, now {}", publish_slot, current_slot);
+        return Err(ProgramError::InvalidAccountData);
+    }
+
+    let raw_price = read_u64(data, AGG_PRICE_OFFSET) as i64;
+    if raw_price <= 0 {
+        msg!("Oracle price is not positive: {}", raw_price);
+        return Err(ProgramError::InvalidAccountData);
+    }
+
+    let expo = read_u32(data, EXPO_OFFSET) as i32;
+    Ok(OraclePrice {
+        price: scale_to_precision(raw_price as u64, expo)?,
+        conf: scale_to_precision(read_u64(data, AGG_CONF_OFFSET), expo)?,
+        publish_slot,
+    })
+}
+
+/// Read the time-weighted EMA price from `oracle_acc`, with the same feed,
+/// status and staleness checks as `read_oracle_price`
+pub fn read_oracle_twap(
+    oracle_acc: &AccountInfo,
+    expected_oracle: &Pubkey,
+    current_slot: u64,
+) -> Result<u64, ProgramError> {
+    if oracle_acc.key != expected_oracle {
+        msg!("Oracle mismatch. Expected: {}, Got: {}", expected_oracle, oracle_acc.key);
+        return Err(ProgramError::InvalidArgument);
+    }
+
+    let data = oracle_acc.try_borrow_data()?;
+    // A pushed feed keeps no average; its settlement price is the latest push
+    if price_feed::is_price_feed(&data) {
+        return Ok(price_feed::parse_price_feed(&data, current_slot)?.price);
+    }
+
+    parse_pyth_twap(&data, current_slot)
+}
+
+/// Parse a Pyth v2 price account's EMA price, its time-weighted average
+pub fn parse_pyth_twap(data: &[u8], current_slot: u64) -> Result<u64, ProgramError> {
+    // Validates the account, status and staleness
+    parse_pyth_price(data, current_slot)?;
+
+    let raw_twap = read_u64(data, EMA_PRICE_OFFSET) as i64;
+    if raw_twap <= 0 {
+        msg!("Oracle TWAP is not positive: {}", raw_twap);
+        return Err(ProgramError::InvalidAccountData);
+    }
+
+    scale_to_precision(raw_twap as u64, read_u32(data, EXPO_OFFSET) as i32)
+}
+
+/// Rescale `value * 10^expo` to 1e9 precision
+pub fn scale_to_precision(value: u64, expo: i32) -> Result<u64, ProgramError> {
+    let shift = expo.checked_add(9).ok_or(ProgramError::InvalidAccountData)?;
+    let factor = 10u128
+        .checked_pow(shift.unsigned_abs())
+        .ok_or(ProgramError::InvalidAccountData)?;
+
+    let scaled = if shift >= 0 {
+        (value as u128).checked_mul(factor).ok_or(ProgramError::InvalidAccountData)?
+    } else {
+        (value as u128) / factor
+    };
+
+    u64::try_from(scaled).map_err(|_| ProgramError::InvalidAccountData)
+}
+
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+fn read_u64(data: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(data[offset..offset + 8].try_into().unwrap())
+}
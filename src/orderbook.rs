@@ -0,0 +1,1283 @@
+//! Per-market limit order book
+//!
+//! Resting limit orders live in a fixed-capacity OrderBook PDA. Placing an
+//! order doesn't lock collateral; instead the permissionless `match_orders`
+//! crank fills any order whose limit price the vAMM has reached, running the
+//! same funding, fee and margin checks as `open_position` against the owner's
+//! position. Orders that would leave the position undercollateralized are
+//! dropped from the book.
+//!
+//! Bids and asks that cross each other are matched peer to peer by the
+//! permissionless `crank_match` crank, at the older (maker) order's price and
+//! without touching the vAMM; the cranker earns a reward per fill. Only the
+//! newer (taker) order pays the trading fee, and the maker earns a rebate out of
+//! it that accrues on its position until `claim_maker_rebates` pays it out.
+
+use borsh::{BorshDeserialize, BorshSerialize};
+use shank::ShankAccount;
+use solana_program::{
+    account_info::{next_account_info, AccountInfo},
+    entrypoint::ProgramResult,
+    msg,
+    program::invoke_signed,
+    program_error::ProgramError,
+    pubkey::Pubkey,
+    system_instruction,
+    sysvar::{clock::Clock, rent::Rent, Sysvar},
+};
+
+use crate::{
+    apply_funding, apply_min_position_size, apply_position_change, calculate_market_fill, calculate_notional, clamp_to_price_band, calculate_trading_fee, charge_skew_fee,
+    create_transfer_checked_instruction, emit_bad_debt, emit_vamm_trade, execute_vamm_trade, is_reducing_change, is_within_price_band, load_config, read_index_price, validate_collateral_ratio, validate_market_vault,
+    validate_position_address, validate_quote_mint, validate_token_account,
+    load_account, require_active, require_hold_elapsed, require_isolated, require_not_settlement_only, require_position_owner, require_vault_covers, store_account, sweep_dust_collateral, validate_leverage, validate_user_notional, AccountType, Config, MarketState, Position, VammFill,
+    DISCRIMINATOR_LEN, PDA_SEED,
+};
+use crate::{access, math};
+
+/// Seed prefix for order book PDAs: [ORDERBOOK_SEED, market_state]
+pub const ORDERBOOK_SEED: &[u8] = b"orderbook";
+
+/// Maximum number of resting orders per market
+pub const MAX_ORDERS: usize = 32;
+
+/// place_order flag: reject the order if it would cross a resting order
+pub const ORDER_FLAG_POST_ONLY: u8 = 1 << 0;
+
+/// place_order flag: match what crosses the book right away and cancel the rest
+pub const ORDER_FLAG_IMMEDIATE_OR_CANCEL: u8 = 1 << 1;
+
+/// Most operations a single batch_orders may carry
+pub const MAX_BATCH_ORDER_OPS: usize = 16;
+
+/// Reward paid to the cranker per crossed fill, out of the market fee pool (0.01 quote units, 1e9 precision)
+pub const MATCH_FILL_REWARD: u64 = 10_000_000;
+
+/// Side of a resting order
+#[derive(BorshSerialize, BorshDeserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OrderSide {
+    /// Buy (go long) at or below the limit price
+    Bid,
+    /// Sell (go short) at or above the limit price
+    Ask,
+}
+
+impl OrderSide {
+    /// The side this one trades against
+    pub fn opposite(self) -> Self {
+        match self {
+            OrderSide::Bid => OrderSide::Ask,
+            OrderSide::Ask => OrderSide::Bid,
+        }
+    }
+}
+
+/// One operation of a batch_orders instruction, applied in order
+#[derive(BorshSerialize, BorshDeserialize, Debug, Clone, PartialEq, Eq)]
+pub enum OrderOp {
+    /// Rest a limit order, as place_order; only the post-only flag is accepted
+    Place {
+        /// Bid (long) or ask (short)
+        side: OrderSide,
+        /// Limit price (1e9 precision)
+        price: u64,
+        /// Order size (market base units)
+        base_amount: u64,
+        /// ORDER_FLAG_POST_ONLY or 0
+        flags: u8,
+    },
+    /// Remove one of the caller's resting orders, as cancel_order
+    Cancel {
+        /// Id assigned when the order was placed
+        order_id: u64,
+    },
+}
+
+/// A resting limit order
+#[derive(BorshSerialize, BorshDeserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Order {
+    /// Unique (per book) order id
+    pub order_id: u64,
+    /// Wallet that placed the order
+    pub owner: Pubkey,
+    /// Position account the fill is applied to
+    pub position: Pubkey,
+    /// Bid or ask
+    pub side: OrderSide,
+    /// Limit price for the average fill (1e9 precision)
+    pub price: u64,
+    /// Unfilled size (1e9 precision)
+    pub base_amount: u64,
+    /// Slot the order was placed in
+    pub placed_slot: u64,
+}
+
+impl Order {
+    /// Serialized size: order_id + owner + position + side + price + base_amount + placed_slot
+    pub const LEN: usize = 8 + 32 + 32 + 1 + 8 + 8 + 8;
+
+    /// Signed size change the order applies to its position
+    pub fn base_delta(&self) -> Result<i64, ProgramError> {
+        let size = i64::try_from(self.base_amount).map_err(|_| ProgramError::InvalidArgument)?;
+        Ok(match self.side {
+            OrderSide::Bid => size,
+            OrderSide::Ask => -size,
+        })
+    }
+
+    /// Whether an average fill at `fill_price` satisfies the order's limit
+    pub fn is_marketable(&self, fill_price: u64) -> bool {
+        match self.side {
+            OrderSide::Bid => fill_price <= self.price,
+            OrderSide::Ask => fill_price >= self.price,
+        }
+    }
+
+    /// Whether this order and `other` are on opposite sides with overlapping prices
+    pub fn crosses(&self, other: &Order) -> bool {
+        match (self.side, other.side) {
+            (OrderSide::Bid, OrderSide::Ask) => self.price >= other.price,
+            (OrderSide::Ask, OrderSide::Bid) => self.price <= other.price,
+            _ => false,
+        }
+    }
+}
+
+/// Resting orders for a single market
+#[derive(BorshSerialize, BorshDeserialize, Debug, Default, Clone, ShankAccount)]
+pub struct OrderBook {
+    /// Market state account this book belongs to
+    pub market: Pubkey,
+    /// Id assigned to the next placed order
+    pub next_order_id: u64,
+    /// Resting orders, oldest first
+    pub orders: Vec<Order>,
+    /// PDA bump for [ORDERBOOK_SEED, market]
+    pub bump: u8,
+}
+
+impl AccountType for OrderBook {
+    const DISCRIMINATOR: [u8; DISCRIMINATOR_LEN] = *b"orderbk\0";
+    /// market + next_order_id + vec length + MAX_ORDERS orders + bump
+    const LEN: usize = 32 + 8 + 4 + MAX_ORDERS * Order::LEN + 1;
+}
+
+impl OrderBook {
+    /// Deserialize from account data; the unused tail of the allocation is ignored
+    pub fn load(data: &[u8]) -> Result<Self, ProgramError> {
+        load_account(data)
+    }
+
+    /// Index of the best resting order on `side`. Price comes first, then time:
+    /// among equal prices the lower (older) order id wins.
+    pub fn best_order(&self, side: OrderSide) -> Option<usize> {
+        self.orders
+            .iter()
+            .enumerate()
+            .filter(|(_, order)| order.side == side)
+            .min_by_key(|(_, order)| match side {
+                OrderSide::Bid => (u64::MAX - order.price, order.order_id),
+                OrderSide::Ask => (order.price, order.order_id),
+            })
+            .map(|(index, _)| index)
+    }
+
+    /// Indices of the best bid and best ask if they cross
+    pub fn best_crossed_pair(&self) -> Option<(usize, usize)> {
+        let (bid, ask) = (self.best_order(OrderSide::Bid)?, self.best_order(OrderSide::Ask)?);
+        self.orders[bid].crosses(&self.orders[ask]).then_some((bid, ask))
+    }
+
+    /// Reject a post-only `order` that would cross the best resting order on the other side
+    fn check_post_only(&self, order: &Order) -> ProgramResult {
+        let crossed = self
+            .best_order(order.side.opposite())
+            .map(|index| &self.orders[index])
+            .filter(|resting| order.crosses(resting));
+        if let Some(resting) = crossed {
+            msg!("Post-only order would cross resting order {}", resting.order_id);
+            return Err(ProgramError::InvalidArgument);
+        }
+        Ok(())
+    }
+
+    /// Assign the next order id
+    fn take_order_id(&mut self) -> Result<u64, ProgramError> {
+        let order_id = self.next_order_id;
+        self.next_order_id = order_id.checked_add(1).ok_or(ProgramError::InvalidArgument)?;
+        Ok(order_id)
+    }
+
+    /// Add `order` to the book
+    fn rest(&mut self, order: Order) -> ProgramResult {
+        if self.orders.len() >= MAX_ORDERS {
+            msg!("Order book is full ({} orders)", MAX_ORDERS);
+            return Err(ProgramError::AccountDataTooSmall);
+        }
+
+        msg!("Order placed: id={}, side={:?}, price={}, size={}",
+             order.order_id, order.side, order.price, order.base_amount);
+        self.orders.push(order);
+        Ok(())
+    }
+
+    /// Remove `owner`'s resting order `order_id`
+    fn cancel(&mut self, owner: &Pubkey, order_id: u64) -> ProgramResult {
+        let index = self
+            .orders
+            .iter()
+            .position(|order| order.order_id == order_id)
+            .ok_or_else(|| {
+                msg!("Order {} not found", order_id);
+                ProgramError::InvalidArgument
+            })?;
+
+        if self.orders[index].owner != *owner {
+            msg!("Order owner mismatch. Expected: {}, Got: {}", self.orders[index].owner, owner);
+            return Err(ProgramError::IllegalOwner);
+        }
+
+        self.orders.remove(index);
+        msg!("Order cancelled: id={}", order_id);
+        Ok(())
+    }
+
+    /// Remove every resting order placed by `owner`, returning how many were removed
+    pub fn cancel_all(&mut self, owner: &Pubkey) -> usize {
+        let before = self.orders.len();
+        self.orders.retain(|order| order.owner != *owner);
+        before - self.orders.len()
+    }
+}
+
+// ---------------------------------------------------------------------
+// 6️⃣ Place a resting limit order
+// ---------------------------------------------------------------------
+pub fn place_order(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    side: OrderSide,
+    price: u64,
+    base_amount: u64,
+    flags: u8,
+) -> ProgramResult {
+    // Accounts:
+    // 0. [signer, writable] user (pays for the order book on first use)
+    // 1. [] position account (owned by user; writable for IOC orders)
+    // 2. [] market state account (writable for IOC orders)
+    // 3. [writable] order book account (PDA: [ORDERBOOK_SEED, market_state])
+    // 4. [] rent sysvar
+    // 5. [] clock sysvar
+    // 6. [] system program
+    // Resting orders:
+    // 7. [] owner's whitelist entry or access token (permissioned markets, for an order that grows the position)
+    // IOC orders:
+    // 7. [] config account
+    // 8..N. [writable] position accounts of the resting orders to match against, and [] the
+    //       whitelist entries or access tokens and leverage overrides of owners whose positions
+    //       the fills grow
+    let accounts_iter = &mut accounts.iter();
+    let user = next_account_info(accounts_iter)?;
+    let position_acc = next_account_info(accounts_iter)?;
+    let market_state_acc = next_account_info(accounts_iter)?;
+    let orderbook_acc = next_account_info(accounts_iter)?;
+    let rent_sysvar = next_account_info(accounts_iter)?;
+    let clock_sysvar = next_account_info(accounts_iter)?;
+    let system_program = next_account_info(accounts_iter)?;
+
+    let post_only = flags & ORDER_FLAG_POST_ONLY != 0;
+    let immediate_or_cancel = flags & ORDER_FLAG_IMMEDIATE_OR_CANCEL != 0;
+    if flags & !(ORDER_FLAG_POST_ONLY | ORDER_FLAG_IMMEDIATE_OR_CANCEL) != 0 || (post_only && immediate_or_cancel) {
+        msg!("Invalid order flags {:#04b}: post-only and IOC are exclusive", flags);
+        return Err(ProgramError::InvalidArgument);
+    }
+
+    let base_decimals = validate_order_accounts(program_id, user, position_acc, market_state_acc)?;
+    let base_amount = validate_order_size(price, base_amount, base_decimals)?;
+
+    // ---------- Initialize order book if empty ----------
+    if orderbook_acc.data_is_empty() {
+        // Nothing rests yet, so there is nothing to match and nothing to create a book for
+        if immediate_or_cancel {
+            msg!("No resting orders; IOC order cancelled");
+            return Ok(());
+        }
+
+        create_orderbook(program_id, user, market_state_acc, orderbook_acc, rent_sysvar, system_program)?;
+    }
+
+    let mut orderbook = load_orderbook(program_id, market_state_acc.key, orderbook_acc)?;
+
+    let clock = Clock::from_account_info(clock_sysvar)?;
+    let mut order = Order {
+        order_id: orderbook.take_order_id()?,
+        owner: *user.key,
+        position: *position_acc.key,
+        side,
+        price,
+        base_amount,
+        placed_slot: clock.slot,
+    };
+
+    // Makers quoting the book don't want to take liquidity by accident
+    if post_only {
+        orderbook.check_post_only(&order)?;
+    }
+
+    if immediate_or_cancel {
+        let config_acc = next_account_info(accounts_iter)?;
+        let trailing_accs: Vec<&AccountInfo> = accounts_iter.collect();
+        let config = load_config(program_id, config_acc)?;
+        require_not_settlement_only(&config)?;
+        let mut market_state_data = market_state_acc.try_borrow_mut_data()?;
+        let market_state = MarketState::load_mut(&mut market_state_data)?;
+        require_active(market_state)?;
+        let reduce_only = market_state.is_reduce_only(clock.slot) || market_state.is_expired(clock.unix_timestamp);
+        let context = FillContext {
+            program_id,
+            market_key: market_state_acc.key,
+            config: &config,
+            access_accs: &trailing_accs,
+            slot: clock.slot,
+        };
+
+        let size = order.base_amount;
+        while order.base_amount > 0 {
+            let Some(index) = orderbook.best_order(side.opposite()) else {
+                break;
+            };
+            let resting = orderbook.orders[index].clone();
+            if !order.crosses(&resting) {
+                break;
+            }
+
+            // As in crank_match, the newer order of a self-match is cancelled: here, the rest of this one
+            if resting.position == order.position {
+                msg!("IOC order would match its own resting order {}", resting.order_id);
+                break;
+            }
+
+            let Some(resting_acc) = trailing_accs.iter().find(|acc| *acc.key == resting.position).copied() else {
+                msg!("Position for resting order {} was not supplied", resting.order_id);
+                break;
+            };
+            if resting_acc.owner != program_id {
+                msg!("Dropping order {}: its position account was closed", resting.order_id);
+                orderbook.orders.remove(index);
+                continue;
+            }
+
+            // The resting order is the maker and sets the price
+            let fill_size = order.base_amount.min(resting.base_amount);
+            let (bid, ask) = match side {
+                OrderSide::Bid => ((&order, position_acc), (&resting, resting_acc)),
+                OrderSide::Ask => ((&resting, resting_acc), (&order, position_acc)),
+            };
+            match match_crossed_orders(&context, market_state, bid, ask, fill_size, resting.price, reduce_only)? {
+                CrossedFill::Filled => {
+                    order.base_amount -= fill_size;
+                    orderbook.orders[index].base_amount -= fill_size;
+                    if orderbook.orders[index].base_amount == 0 {
+                        orderbook.orders.remove(index);
+                    }
+                }
+                CrossedFill::Rejected(rejected) if rejected != side => {
+                    msg!("Dropping order {}", resting.order_id);
+                    orderbook.orders.remove(index);
+                }
+                CrossedFill::Waiting | CrossedFill::Rejected(_) => break,
+            }
+        }
+
+        store_account(&orderbook, &mut orderbook_acc.data.borrow_mut())?;
+
+        msg!("IOC order {}: side={:?}, price={}, filled={}, cancelled={}",
+             order.order_id, side, price, size - order.base_amount, order.base_amount);
+
+        return Ok(());
+    }
+
+    let access_accs: Vec<&AccountInfo> = accounts_iter.collect();
+    require_order_access(program_id, position_acc, market_state_acc, &order, &access_accs)?;
+    orderbook.rest(order)?;
+    store_account(&orderbook, &mut orderbook_acc.data.borrow_mut())?;
+
+    Ok(())
+}
+
+// ---------------------------------------------------------------------
+// 7️⃣ Cancel a resting limit order
+// ---------------------------------------------------------------------
+pub fn cancel_order(program_id: &Pubkey, accounts: &[AccountInfo], order_id: u64) -> ProgramResult {
+    // Accounts:
+    // 0. [signer] order owner
+    // 1. [writable] order book account
+    let accounts_iter = &mut accounts.iter();
+    let user = next_account_info(accounts_iter)?;
+    let orderbook_acc = next_account_info(accounts_iter)?;
+
+    if !user.is_signer {
+        msg!("User must be signer");
+        return Err(ProgramError::MissingRequiredSignature);
+    }
+
+    if orderbook_acc.owner != program_id {
+        msg!("Order book is not owned by the program");
+        return Err(ProgramError::IncorrectProgramId);
+    }
+
+    let mut orderbook = OrderBook::load(&orderbook_acc.data.borrow())?;
+    orderbook.cancel(user.key, order_id)?;
+    store_account(&orderbook, &mut orderbook_acc.data.borrow_mut())?;
+
+    Ok(())
+}
+
+// ---------------------------------------------------------------------
+// 6️⃣7️⃣ Cancel all of a user's resting orders in a market
+// ---------------------------------------------------------------------
+pub fn cancel_all_orders(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramResult {
+    // Accounts:
+    // 0. [signer] order owner
+    // 1. [writable] order book account
+    let accounts_iter = &mut accounts.iter();
+    let user = next_account_info(accounts_iter)?;
+    let orderbook_acc = next_account_info(accounts_iter)?;
+
+    if !user.is_signer {
+        msg!("User must be signer");
+        return Err(ProgramError::MissingRequiredSignature);
+    }
+
+    if orderbook_acc.owner != program_id {
+        msg!("Order book is not owned by the program");
+        return Err(ProgramError::IncorrectProgramId);
+    }
+
+    let mut orderbook = OrderBook::load(&orderbook_acc.data.borrow())?;
+    let cancelled = orderbook.cancel_all(user.key);
+    store_account(&orderbook, &mut orderbook_acc.data.borrow_mut())?;
+
+    msg!("Cancelled {} orders of {}", cancelled, user.key);
+
+    Ok(())
+}
+
+// ---------------------------------------------------------------------
+// 6️⃣8️⃣ Place and cancel several resting orders at once
+// ---------------------------------------------------------------------
+pub fn batch_orders(program_id: &Pubkey, accounts: &[AccountInfo], ops: Vec<OrderOp>) -> ProgramResult {
+    // Accounts:
+    // 0. [signer, writable] user (pays for the order book on first use)
+    // 1. [] position account placed orders fill (owned by user)
+    // 2. [] market state account
+    // 3. [writable] order book account (PDA: [ORDERBOOK_SEED, market_state])
+    // 4. [] rent sysvar
+    // 5. [] clock sysvar
+    // 6. [] system program
+    // 7. [] owner's whitelist entry or access token (permissioned markets, for orders that grow the position)
+    let accounts_iter = &mut accounts.iter();
+    let user = next_account_info(accounts_iter)?;
+    let position_acc = next_account_info(accounts_iter)?;
+    let market_state_acc = next_account_info(accounts_iter)?;
+    let orderbook_acc = next_account_info(accounts_iter)?;
+    let rent_sysvar = next_account_info(accounts_iter)?;
+    let clock_sysvar = next_account_info(accounts_iter)?;
+    let system_program = next_account_info(accounts_iter)?;
+    let access_accs: Vec<&AccountInfo> = accounts_iter.collect();
+
+    if ops.is_empty() || ops.len() > MAX_BATCH_ORDER_OPS {
+        msg!("A batch carries 1 to {} operations, got {}", MAX_BATCH_ORDER_OPS, ops.len());
+        return Err(ProgramError::InvalidArgument);
+    }
+
+    let base_decimals = validate_order_accounts(program_id, user, position_acc, market_state_acc)?;
+
+    if orderbook_acc.data_is_empty() {
+        create_orderbook(program_id, user, market_state_acc, orderbook_acc, rent_sysvar, system_program)?;
+    }
+
+    let mut orderbook = load_orderbook(program_id, market_state_acc.key, orderbook_acc)?;
+    let clock = Clock::from_account_info(clock_sysvar)?;
+
+    // Operations apply in order, so a batch can cancel stale quotes to make room for new ones;
+    // any failure reverts the whole batch
+    for op in &ops {
+        match *op {
+            OrderOp::Place { side, price, base_amount, flags } => {
+                if flags & !ORDER_FLAG_POST_ONLY != 0 {
+                    msg!("Invalid batch order flags {:#04b}: only post-only is supported", flags);
+                    return Err(ProgramError::InvalidArgument);
+                }
+
+                let order = Order {
+                    order_id: orderbook.take_order_id()?,
+                    owner: *user.key,
+                    position: *position_acc.key,
+                    side,
+                    price,
+                    base_amount: validate_order_size(price, base_amount, base_decimals)?,
+                    placed_slot: clock.slot,
+                };
+                if flags & ORDER_FLAG_POST_ONLY != 0 {
+                    orderbook.check_post_only(&order)?;
+                }
+                require_order_access(program_id, position_acc, market_state_acc, &order, &access_accs)?;
+                orderbook.rest(order)?;
+            }
+            OrderOp::Cancel { order_id } => orderbook.cancel(user.key, order_id)?,
+        }
+    }
+
+    store_account(&orderbook, &mut orderbook_acc.data.borrow_mut())?;
+
+    msg!("Applied {} order operations", ops.len());
+
+    Ok(())
+}
+
+/// Check the signer owns `position_acc`, an isolated position in the market at
+/// `market_state_acc`, and return the market's base decimals
+fn validate_order_accounts(
+    program_id: &Pubkey,
+    user: &AccountInfo,
+    position_acc: &AccountInfo,
+    market_state_acc: &AccountInfo,
+) -> Result<u8, ProgramError> {
+    if !user.is_signer {
+        msg!("User must be signer");
+        return Err(ProgramError::MissingRequiredSignature);
+    }
+
+    if position_acc.owner != program_id || market_state_acc.owner != program_id {
+        msg!("Position and market state must be owned by the program");
+        return Err(ProgramError::IncorrectProgramId);
+    }
+
+    let position_data = position_acc.try_borrow_data()?;
+    let position = Position::load(&position_data)?;
+    require_position_owner(position, user.key)?;
+    validate_position_address(program_id, position_acc.key, market_state_acc.key, position)?;
+
+    require_isolated(position)?;
+
+    Ok(MarketState::load(&market_state_acc.try_borrow_data()?)?.base_decimals)
+}
+
+/// In a permissioned market, check the owner of an order that would grow its position is
+/// whitelisted before the order rests. Fills are checked again, as the position may have
+/// changed by then.
+fn require_order_access(
+    program_id: &Pubkey,
+    position_acc: &AccountInfo,
+    market_state_acc: &AccountInfo,
+    order: &Order,
+    access_accs: &[&AccountInfo],
+) -> ProgramResult {
+    let base_amount = Position::load(&position_acc.try_borrow_data()?)?.base_amount;
+    if is_reducing_change(base_amount, order.base_delta()?) {
+        return Ok(());
+    }
+
+    let market_state_data = market_state_acc.try_borrow_data()?;
+    access::require_whitelisted(program_id, MarketState::load(&market_state_data)?, &order.owner, access_accs)
+}
+
+/// Order size in 1e9 precision from `base_amount` in the market's base units; price and
+/// size must be non-zero
+fn validate_order_size(price: u64, base_amount: u64, base_decimals: u8) -> Result<u64, ProgramError> {
+    if price == 0 || base_amount == 0 {
+        msg!("Order price and size must be non-zero");
+        return Err(ProgramError::InvalidArgument);
+    }
+
+    let base_amount = math::to_precision(base_amount, base_decimals)?;
+    if base_amount > i64::MAX as u64 {
+        msg!("Order size {} is too large", base_amount);
+        return Err(ProgramError::InvalidArgument);
+    }
+
+    Ok(base_amount)
+}
+
+/// Create a market's empty order book at its PDA, paid by `payer`
+fn create_orderbook<'info>(
+    program_id: &Pubkey,
+    payer: &AccountInfo<'info>,
+    market_state_acc: &AccountInfo<'info>,
+    orderbook_acc: &AccountInfo<'info>,
+    rent_sysvar: &AccountInfo<'info>,
+    system_program: &AccountInfo<'info>,
+) -> ProgramResult {
+    let (expected_orderbook, bump) = Pubkey::find_program_address(
+        &[ORDERBOOK_SEED, market_state_acc.key.as_ref()],
+        program_id,
+    );
+    if *orderbook_acc.key != expected_orderbook {
+        msg!("Order book is not the correct PDA. Expected: {}, Got: {}",
+             expected_orderbook, orderbook_acc.key);
+        return Err(ProgramError::InvalidArgument);
+    }
+
+    let rent = Rent::from_account_info(rent_sysvar)?;
+    let create_orderbook_ix = system_instruction::create_account(
+        payer.key,
+        orderbook_acc.key,
+        rent.minimum_balance(OrderBook::SPACE),
+        OrderBook::SPACE as u64,
+        program_id,
+    );
+
+    invoke_signed(&create_orderbook_ix, &[
+        payer.clone(),
+        orderbook_acc.clone(),
+        system_program.clone(),
+    ], &[&[ORDERBOOK_SEED, market_state_acc.key.as_ref(), &[bump]]])?;
+
+    let orderbook = OrderBook {
+        market: *market_state_acc.key,
+        next_order_id: 0,
+        orders: Vec::new(),
+        bump,
+    };
+    store_account(&orderbook, &mut orderbook_acc.data.borrow_mut())?;
+    msg!("Initialized order book for market: {}", market_state_acc.key);
+
+    Ok(())
+}
+
+/// Load `market`'s order book, checking it sits at its PDA
+fn load_orderbook(program_id: &Pubkey, market: &Pubkey, orderbook_acc: &AccountInfo) -> Result<OrderBook, ProgramError> {
+    let orderbook = OrderBook::load(&orderbook_acc.data.borrow())?;
+    let expected_orderbook = Pubkey::create_program_address(
+        &[ORDERBOOK_SEED, market.as_ref(), &[orderbook.bump]],
+        program_id,
+    )?;
+    if *orderbook_acc.key != expected_orderbook {
+        msg!("Order book is not the correct PDA. Expected: {}, Got: {}",
+             expected_orderbook, orderbook_acc.key);
+        return Err(ProgramError::InvalidArgument);
+    }
+
+    Ok(orderbook)
+}
+
+// ---------------------------------------------------------------------
+// 8️⃣ Match resting orders against the vAMM (permissionless crank)
+// ---------------------------------------------------------------------
+pub fn match_orders(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramResult {
+    // Accounts:
+    // 0. [writable] market state account
+    // 1. [writable] order book account
+    // 2. [] config account
+    // Markets with an index oracle (shifts the accounts below by one per feed):
+    //   3. [] the market's index oracle, then its backup oracles
+    // 3..N. [writable] position accounts of the orders to fill, and [] the whitelist entries
+    //       or access tokens (permissioned markets) and leverage overrides of owners whose
+    //       positions the fills grow
+    let accounts_iter = &mut accounts.iter();
+    let market_state_acc = next_account_info(accounts_iter)?;
+    let orderbook_acc = next_account_info(accounts_iter)?;
+    let config_acc = next_account_info(accounts_iter)?;
+    let remaining_accs: Vec<&AccountInfo> = accounts_iter.collect();
+
+    let config = load_config(program_id, config_acc)?;
+    require_not_settlement_only(&config)?;
+    // Read via syscall: the account list has no room for the clock ahead of the positions
+    let clock = Clock::get()?;
+
+    if market_state_acc.owner != program_id || orderbook_acc.owner != program_id {
+        msg!("Market state and order book must be owned by the program");
+        return Err(ProgramError::IncorrectProgramId);
+    }
+
+    let mut market_state_data = market_state_acc.try_borrow_mut_data()?;
+    let market_state = MarketState::load_mut(&mut market_state_data)?;
+    require_active(market_state)?;
+    let mut orderbook = OrderBook::load(&orderbook_acc.data.borrow())?;
+
+    if orderbook.market != *market_state_acc.key {
+        msg!("Order book does not belong to market {}", market_state_acc.key);
+        return Err(ProgramError::InvalidArgument);
+    }
+
+    // The market's index oracle and backups, if it has them, precede the positions
+    let (index_price, position_accs) = if market_state.has_index_oracle() {
+        let (index, rest) = read_index_price(market_state, &remaining_accs, clock.slot)?;
+        (Some(index.price), rest)
+    } else {
+        (None, &remaining_accs[..])
+    };
+
+    let context = FillContext {
+        program_id,
+        market_key: market_state_acc.key,
+        config: &config,
+        access_accs: position_accs,
+        slot: clock.slot,
+    };
+    let mut filled = 0usize;
+    let mut dropped = 0usize;
+    let mut index = 0usize;
+    while index < orderbook.orders.len() {
+        let order = orderbook.orders[index].clone();
+
+        // Quote the fill without touching state; skip orders the vAMM hasn't reached
+        let base_delta = order.base_delta()?;
+        let quote = match calculate_market_fill(market_state, base_delta) {
+            Ok(quote) if order.is_marketable(quote.fill_price) => quote,
+            _ => {
+                index += 1;
+                continue;
+            }
+        };
+
+        // Orders whose position wasn't supplied stay on the book for a later crank
+        let position_acc = match position_accs.iter().find(|acc| *acc.key == order.position) {
+            Some(position_acc) if position_acc.owner == program_id => *position_acc,
+            Some(_) => {
+                msg!("Dropping order {}: its position account was closed", order.order_id);
+                orderbook.orders.remove(index);
+                dropped += 1;
+                continue;
+            }
+            None => {
+                index += 1;
+                continue;
+            }
+        };
+
+        let mut position_data = position_acc.try_borrow_mut_data()?;
+        let position = Position::load_mut(&mut position_data)?;
+        if position.owner != order.owner {
+            msg!("Dropping order {}: position owner changed", order.order_id);
+            orderbook.orders.remove(index);
+            dropped += 1;
+            continue;
+        }
+
+        if position.is_cross_margin() {
+            msg!("Dropping order {}: position switched to cross margin", order.order_id);
+            orderbook.orders.remove(index);
+            dropped += 1;
+            continue;
+        }
+
+        // While paused, cooling down or expired, orders that would grow the position wait (or never fill)
+        let reduce_only = market_state.is_reduce_only(clock.slot) || market_state.is_expired(clock.unix_timestamp);
+        if reduce_only && !is_reducing_change(position.base_amount, base_delta) {
+            index += 1;
+            continue;
+        }
+
+        // As in open_position, fills that grow a position must land near the index; others wait
+        if let Some(index_price) = index_price {
+            if !is_reducing_change(position.base_amount, base_delta)
+                && !is_within_price_band(quote.fill_price, index_price, market_state.mark_price_band)
+            {
+                index += 1;
+                continue;
+            }
+        }
+
+        // Apply the fill to copies so a failed margin check leaves state untouched
+        let mut next_market_state = *market_state;
+        let mut next_position = *position;
+        match fill_order(&context, &order, &mut next_position, &mut next_market_state, index_price) {
+            Ok((base_delta, fill)) => {
+                let bad_debt_before = market_state.bad_debt;
+                *market_state = next_market_state;
+                *position = next_position;
+                emit_vamm_trade(market_state_acc.key, position_acc.key, base_delta, &fill, market_state);
+                emit_bad_debt(market_state_acc.key, position_acc.key, market_state, bad_debt_before);
+                filled += 1;
+                msg!("Order filled: id={}, price={}, size={}",
+                     order.order_id, quote.fill_price, base_delta);
+            }
+            Err(err) => {
+                msg!("Dropping order {}: fill failed ({:?})", order.order_id, err);
+                dropped += 1;
+            }
+        }
+        orderbook.orders.remove(index);
+    }
+
+    if filled == 0 && dropped == 0 {
+        msg!("No marketable orders");
+        return Ok(());
+    }
+
+    // Persist changes
+    store_account(&orderbook, &mut orderbook_acc.data.borrow_mut())?;
+
+    msg!("Order matching complete: filled={}, dropped={}, resting={}",
+         filled, dropped, orderbook.orders.len());
+
+    Ok(())
+}
+
+// ---------------------------------------------------------------------
+// 5️⃣1️⃣ Match crossed resting orders against each other (permissionless crank)
+// ---------------------------------------------------------------------
+pub fn crank_match(program_id: &Pubkey, accounts: &[AccountInfo], max_fills: u8) -> ProgramResult {
+    // Accounts:
+    // 0. [writable] market state account
+    // 1. [writable] order book account
+    // 2. [] config account
+    // 3. [] token program
+    // 4. [writable] cranker's token account (to receive the fill rewards)
+    // 5. [writable] vault token account (PDA‑owned)
+    // 6. [] quote mint
+    // 7..N. [writable] position accounts of the orders to fill, and [] the whitelist entries
+    //       or access tokens (permissioned markets) and leverage overrides of owners whose
+    //       positions the fills grow
+    let accounts_iter = &mut accounts.iter();
+    let market_state_acc = next_account_info(accounts_iter)?;
+    let orderbook_acc = next_account_info(accounts_iter)?;
+    let config_acc = next_account_info(accounts_iter)?;
+    let token_program = next_account_info(accounts_iter)?;
+    let cranker_token_acc = next_account_info(accounts_iter)?;
+    let vault = next_account_info(accounts_iter)?;
+    let quote_mint = next_account_info(accounts_iter)?;
+    let position_accs: Vec<&AccountInfo> = accounts_iter.collect();
+
+    if max_fills == 0 {
+        msg!("max_fills must be non-zero");
+        return Err(ProgramError::InvalidArgument);
+    }
+
+    let config = load_config(program_id, config_acc)?;
+    require_not_settlement_only(&config)?;
+    let quote_decimals = validate_quote_mint(&config, token_program, quote_mint)?;
+    // Read via syscall: the account list has no room for the clock ahead of the positions
+    let clock = Clock::get()?;
+
+    if market_state_acc.owner != program_id || orderbook_acc.owner != program_id {
+        msg!("Market state and order book must be owned by the program");
+        return Err(ProgramError::IncorrectProgramId);
+    }
+
+    let mut market_state_data = market_state_acc.try_borrow_mut_data()?;
+    let market_state = MarketState::load_mut(&mut market_state_data)?;
+    require_active(market_state)?;
+    let (pda, bump) = validate_market_vault(program_id, token_program, vault, market_state, quote_mint)?;
+    validate_token_account(token_program.key, cranker_token_acc, quote_mint.key, None)?;
+    let mut orderbook = OrderBook::load(&orderbook_acc.data.borrow())?;
+
+    if orderbook.market != *market_state_acc.key {
+        msg!("Order book does not belong to market {}", market_state_acc.key);
+        return Err(ProgramError::InvalidArgument);
+    }
+
+    let reduce_only = market_state.is_reduce_only(clock.slot) || market_state.is_expired(clock.unix_timestamp);
+    let context = FillContext {
+        program_id,
+        market_key: market_state_acc.key,
+        config: &config,
+        access_accs: &position_accs,
+        slot: clock.slot,
+    };
+    let mut filled = 0u8;
+    let mut dropped = 0usize;
+    while filled < max_fills {
+        let Some((bid_index, ask_index)) = orderbook.best_crossed_pair() else {
+            break;
+        };
+        let (bid, ask) = (orderbook.orders[bid_index].clone(), orderbook.orders[ask_index].clone());
+
+        // Never match a position against itself: the newer order is cancelled
+        if bid.position == ask.position {
+            let newer = if bid.order_id > ask.order_id { bid_index } else { ask_index };
+            msg!("Dropping order {}: it would match its own position", orderbook.orders[newer].order_id);
+            orderbook.orders.remove(newer);
+            dropped += 1;
+            continue;
+        }
+
+        // Stop rather than skip when a position is missing, so price-time priority holds
+        let find_position = |order: &Order| position_accs.iter().find(|acc| *acc.key == order.position).copied();
+        let (Some(bid_acc), Some(ask_acc)) = (find_position(&bid), find_position(&ask)) else {
+            msg!("Positions for orders {} and {} were not supplied", bid.order_id, ask.order_id);
+            break;
+        };
+
+        // The position account of an order can be closed under it; such orders never fill
+        if let Some(index) = [(bid_acc, bid_index), (ask_acc, ask_index)]
+            .into_iter()
+            .find_map(|(acc, index)| (acc.owner != program_id).then_some(index))
+        {
+            msg!("Dropping order {}: its position account was closed", orderbook.orders[index].order_id);
+            orderbook.orders.remove(index);
+            dropped += 1;
+            continue;
+        }
+
+        // The older order is the maker and sets the price
+        let fill_price = if bid.order_id < ask.order_id { bid.price } else { ask.price };
+        let size = bid.base_amount.min(ask.base_amount);
+
+        // A side that can't take the fill is dropped; the other keeps resting
+        match match_crossed_orders(
+            &context, market_state, (&bid, bid_acc), (&ask, ask_acc), size, fill_price, reduce_only,
+        )? {
+            CrossedFill::Filled => {}
+            CrossedFill::Waiting => {
+                msg!("Market is reduce-only; orders {} and {} wait", bid.order_id, ask.order_id);
+                break;
+            }
+            CrossedFill::Rejected(side) => {
+                let index = if side == OrderSide::Bid { bid_index } else { ask_index };
+                msg!("Dropping order {}", orderbook.orders[index].order_id);
+                orderbook.orders.remove(index);
+                dropped += 1;
+                continue;
+            }
+        }
+        filled += 1;
+
+        // Remove filled orders from the back so the other index stays valid
+        orderbook.orders[bid_index].base_amount -= size;
+        orderbook.orders[ask_index].base_amount -= size;
+        for index in [bid_index.max(ask_index), bid_index.min(ask_index)] {
+            if orderbook.orders[index].base_amount == 0 {
+                orderbook.orders.remove(index);
+            }
+        }
+    }
+
+    if filled == 0 && dropped == 0 {
+        msg!("No crossed orders");
+        return Ok(());
+    }
+
+    // Persist changes
+    store_account(&orderbook, &mut orderbook_acc.data.borrow_mut())?;
+
+    // ---------- Cranker reward ----------
+    let reward = MATCH_FILL_REWARD
+        .saturating_mul(filled.into())
+        .min(market_state.fee_pool);
+    if reward > 0 {
+        market_state.fee_pool -= reward;
+
+        let transfer_ix = create_transfer_checked_instruction(
+            token_program.key,
+            vault.key,
+            quote_mint.key,
+            cranker_token_acc.key,
+            &pda,
+            market_state.quote_from_precision(reward)?,
+            quote_decimals,
+        )?;
+
+        invoke_signed(&transfer_ix, &[
+            vault.clone(),
+            quote_mint.clone(),
+            cranker_token_acc.clone(),
+            vault.clone(), // PDA authority
+            token_program.clone(),
+        ], &[&[PDA_SEED, &[bump]]])?;
+        require_vault_covers(vault, market_state.reserves(), quote_decimals)?;
+    }
+
+    msg!("Crossed matching complete: filled={}, dropped={}, resting={}, cranker_reward={}",
+         filled, dropped, orderbook.orders.len(), reward);
+
+    Ok(())
+}
+
+// ---------------------------------------------------------------------
+// 5️⃣7️⃣ Withdraw the maker rebates a position's resting orders earned
+// ---------------------------------------------------------------------
+pub fn claim_maker_rebates(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramResult {
+    // Accounts:
+    // 0. [signer] position owner
+    // 1. [] token program
+    // 2. [writable] owner's token account (to receive the rebates)
+    // 3. [writable] vault token account (PDA‑owned)
+    // 4. [writable] position account
+    // 5. [writable] market state account
+    // 6. [] config account
+    // 7. [] quote mint
+    let accounts_iter = &mut accounts.iter();
+    let owner = next_account_info(accounts_iter)?;
+    let token_program = next_account_info(accounts_iter)?;
+    let owner_token_acc = next_account_info(accounts_iter)?;
+    let vault = next_account_info(accounts_iter)?;
+    let position_acc = next_account_info(accounts_iter)?;
+    let market_state_acc = next_account_info(accounts_iter)?;
+    let config_acc = next_account_info(accounts_iter)?;
+    let quote_mint = next_account_info(accounts_iter)?;
+
+    if !owner.is_signer {
+        msg!("Position owner must be signer");
+        return Err(ProgramError::MissingRequiredSignature);
+    }
+
+    if position_acc.owner != program_id || market_state_acc.owner != program_id {
+        msg!("Position and market state must be owned by the program");
+        return Err(ProgramError::IncorrectProgramId);
+    }
+
+    let config = load_config(program_id, config_acc)?;
+    let quote_decimals = validate_quote_mint(&config, token_program, quote_mint)?;
+    let mut market_state_data = market_state_acc.try_borrow_mut_data()?;
+    let market_state = MarketState::load_mut(&mut market_state_data)?;
+    let (pda, bump) = validate_market_vault(program_id, token_program, vault, market_state, quote_mint)?;
+    validate_token_account(token_program.key, owner_token_acc, quote_mint.key, None)?;
+
+    let mut position_data = position_acc.try_borrow_mut_data()?;
+    let position = Position::load_mut(&mut position_data)?;
+    require_position_owner(position, owner.key)?;
+    validate_position_address(program_id, position_acc.key, market_state_acc.key, position)?;
+
+    let claimed = position.maker_rebates;
+    if claimed == 0 {
+        msg!("No maker rebates to claim");
+        return Ok(());
+    }
+    let clock = Clock::get()?;
+    market_state.record_withdrawal(position, claimed, clock.slot, clock.epoch)?;
+
+    let transfer_ix = create_transfer_checked_instruction(
+        token_program.key,
+        vault.key,
+        quote_mint.key,
+        owner_token_acc.key,
+        &pda,
+        market_state.quote_from_precision(claimed)?,
+        quote_decimals,
+    )?;
+
+    invoke_signed(&transfer_ix, &[
+        vault.clone(),
+        quote_mint.clone(),
+        owner_token_acc.clone(),
+        vault.clone(), // PDA authority
+        token_program.clone(),
+    ], &[&[PDA_SEED, &[bump]]])?;
+    require_vault_covers(vault, market_state.reserves(), quote_decimals)?;
+
+    position.maker_rebates = 0;
+
+    msg!("Maker rebates claimed: {}", claimed);
+
+    Ok(())
+}
+
+/// The program, market and accounts every fill of an instruction is checked against
+struct FillContext<'a, 'info> {
+    program_id: &'a Pubkey,
+    market_key: &'a Pubkey,
+    config: &'a Config,
+    /// Trailing accounts searched for the whitelist entries or access tokens and leverage
+    /// overrides of owners whose positions the fills grow
+    access_accs: &'a [&'a AccountInfo<'info>],
+    /// Slot fills start or are checked against a position's hold in
+    slot: u64,
+}
+
+/// Outcome of matching a crossed bid and ask
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum CrossedFill {
+    /// Both positions took the fill
+    Filled,
+    /// The market is reduce-only and the fill would grow a position
+    Waiting,
+    /// The order on this side can't take the fill
+    Rejected(OrderSide),
+}
+
+/// Fill `size` between a crossed bid and ask at `fill_price`. The fill is applied to
+/// copies first, so market state and both positions change only if both sides pass.
+fn match_crossed_orders(
+    context: &FillContext,
+    market_state: &mut MarketState,
+    (bid, bid_acc): (&Order, &AccountInfo),
+    (ask, ask_acc): (&Order, &AccountInfo),
+    size: u64,
+    fill_price: u64,
+    reduce_only: bool,
+) -> Result<CrossedFill, ProgramError> {
+    let size = i64::try_from(size).map_err(|_| ProgramError::InvalidArgument)?;
+    let mut bid_data = bid_acc.try_borrow_mut_data()?;
+    let mut ask_data = ask_acc.try_borrow_mut_data()?;
+    let bid_position = Position::load_mut(&mut bid_data)?;
+    let ask_position = Position::load_mut(&mut ask_data)?;
+
+    // While paused, cooling down or expired, a match that grows either position waits
+    if reduce_only
+        && !(is_reducing_change(bid_position.base_amount, size) && is_reducing_change(ask_position.base_amount, -size))
+    {
+        return Ok(CrossedFill::Waiting);
+    }
+
+    let mut next_market_state = *market_state;
+    let mut next_bid_position = *bid_position;
+    let mut next_ask_position = *ask_position;
+    let (mut bid_bad_debt, mut ask_bad_debt) = (0, 0);
+    // The older order is the maker
+    let bid_is_maker = bid.order_id < ask.order_id;
+    let role = |is_maker| if is_maker { FillRole::Maker } else { FillRole::Taker };
+    for (order, position_key, position, base_delta, role, bad_debt) in [
+        (bid, bid_acc.key, &mut next_bid_position, size, role(bid_is_maker), &mut bid_bad_debt),
+        (ask, ask_acc.key, &mut next_ask_position, -size, role(!bid_is_maker), &mut ask_bad_debt),
+    ] {
+        let bad_debt_before = next_market_state.bad_debt;
+        if let Err(err) = fill_crossed_order(
+            context, position_key, order, position, &mut next_market_state, base_delta, fill_price, role,
+        ) {
+            msg!("Order {} can't take the fill ({:?})", order.order_id, err);
+            return Ok(CrossedFill::Rejected(order.side));
+        }
+        *bad_debt = next_market_state.bad_debt - bad_debt_before;
+    }
+
+    // The maker's rebate comes out of the fee the taker just paid into the pool
+    let rebate = calculate_trading_fee(size, fill_price, context.config.maker_rebate)?;
+    let maker_position = if bid_is_maker { &mut next_bid_position } else { &mut next_ask_position };
+    next_market_state.fee_pool = next_market_state.fee_pool
+        .checked_sub(rebate)
+        .ok_or(ProgramError::InvalidArgument)?;
+    maker_position.maker_rebates = maker_position.maker_rebates
+        .checked_add(rebate)
+        .ok_or(ProgramError::InvalidArgument)?;
+
+    *market_state = next_market_state;
+    *bid_position = next_bid_position;
+    *ask_position = next_ask_position;
+    // Each side's event reports only the bad debt its own fill left
+    for (position_key, bad_debt) in [(bid_acc.key, bid_bad_debt), (ask_acc.key, ask_bad_debt)] {
+        emit_bad_debt(context.market_key, position_key, market_state, market_state.bad_debt - bad_debt);
+    }
+    msg!("Orders matched: bid={}, ask={}, price={}, size={}, maker_rebate={}",
+         bid.order_id, ask.order_id, fill_price, size, rebate);
+
+    Ok(CrossedFill::Filled)
+}
+
+/// Apply one side of a crossed match to the order's position at the maker's price
+#[allow(clippy::too_many_arguments)]
+fn fill_crossed_order(
+    context: &FillContext,
+    position_key: &Pubkey,
+    order: &Order,
+    position: &mut Position,
+    market_state: &mut MarketState,
+    base_delta: i64,
+    fill_price: u64,
+    role: FillRole,
+) -> ProgramResult {
+    if position.owner != order.owner {
+        msg!("Position owner changed since order {} was placed", order.order_id);
+        return Err(ProgramError::IllegalOwner);
+    }
+    validate_position_address(context.program_id, position_key, context.market_key, position)?;
+    require_isolated(position)?;
+
+    // Both sides must trade the same size, so a fill that would leave dust is refused
+    if apply_min_position_size(position.base_amount, base_delta, context.config.min_position_size)? != base_delta {
+        msg!("Fill would leave order {}'s position below the minimum size", order.order_id);
+        return Err(ProgramError::InvalidArgument);
+    }
+
+    settle_fill(context, position, market_state, base_delta, fill_price, role)
+}
+
+/// Execute a marketable order against the vAMM and apply it to the owner's position.
+/// With an `index_price`, a fill that grows the position must land in the mark price band
+/// and the mark is clamped to it. Returns the size filled and the vAMM fill.
+fn fill_order(
+    context: &FillContext,
+    order: &Order,
+    position: &mut Position,
+    market_state: &mut MarketState,
+    index_price: Option<u64>,
+) -> Result<(i64, VammFill), ProgramError> {
+    let base_delta = apply_min_position_size(position.base_amount, order.base_delta()?, context.config.min_position_size)?;
+
+    let fill = execute_vamm_trade(market_state, base_delta)?;
+    if !order.is_marketable(fill.fill_price) {
+        return Err(ProgramError::InvalidArgument);
+    }
+
+    if let Some(index_price) = index_price {
+        if !is_reducing_change(position.base_amount, base_delta)
+            && !is_within_price_band(fill.fill_price, index_price, market_state.mark_price_band)
+        {
+            msg!("Fill price {} is outside the {} band around index price {}",
+                 fill.fill_price, market_state.mark_price_band, index_price);
+            return Err(ProgramError::InvalidArgument);
+        }
+        market_state.mark_price = clamp_to_price_band(market_state.mark_price, index_price, market_state.mark_price_band);
+    }
+
+    settle_fill(context, position, market_state, base_delta, fill.fill_price, FillRole::Vamm)?;
+
+    Ok((base_delta, fill))
+}
+
+/// Which side of a fill a position is on, which decides the fees it pays
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum FillRole {
+    /// Filled against the vAMM: pays the skew fee and the trading fee
+    Vamm,
+    /// The newer order of a crossed match: pays the trading fee. The two sides' trades
+    /// cancel out in the skew, so neither pays the skew fee.
+    Taker,
+    /// The older order of a crossed match: pays no fee and earns the maker rebate
+    Maker,
+}
+
+/// Apply a filled size change to a position: funding, PnL, the fees of its role and the
+/// access and margin checks
+fn settle_fill(
+    context: &FillContext,
+    position: &mut Position,
+    market_state: &mut MarketState,
+    base_delta: i64,
+    fill_price: u64,
+    role: FillRole,
+) -> ProgramResult {
+    let config = context.config;
+    let is_reduction = is_reducing_change(position.base_amount, base_delta);
+    // As with open_position, only fills that grow a position need the owner whitelisted,
+    // and fills that reduce one wait out the hold the last growth started
+    if !is_reduction {
+        access::require_whitelisted(context.program_id, market_state, &position.owner, context.access_accs)?;
+    } else if base_delta != 0 {
+        require_hold_elapsed(position, market_state, context.slot)?;
+    }
+
+    apply_funding(position, market_state, config.max_funding_settlement_share)?;
+    apply_position_change(position, market_state, base_delta, fill_price)?;
+    if !is_reduction {
+        position.last_modified_slot = context.slot;
+    }
+    if role == FillRole::Vamm {
+        charge_skew_fee(&mut position.collateral, market_state, base_delta, fill_price)?;
+    }
+
+    let fee_rate = if role == FillRole::Maker { 0 } else { config.trading_fee };
+    let trading_fee = calculate_trading_fee(base_delta, fill_price, fee_rate)?;
+    position.collateral = position
+        .collateral
+        .checked_sub(trading_fee)
+        .ok_or(ProgramError::InsufficientFunds)?;
+    market_state.fee_pool = market_state
+        .fee_pool
+        .checked_add(trading_fee)
+        .ok_or(ProgramError::InvalidArgument)?;
+    sweep_dust_collateral(position, market_state, config.dust_collateral)?;
+
+    validate_collateral_ratio(position, market_state, config.min_collateral_ratio)?;
+    if !is_reduction {
+        validate_user_notional(calculate_notional(position.base_amount, market_state.mark_price)?, config.max_user_notional)?;
+        let max_leverage = access::max_leverage_for(
+            context.program_id, context.market_key, market_state, &position.owner, context.access_accs,
+        );
+        validate_leverage(position, market_state, max_leverage)?;
+    }
+
+    Ok(())
+}
@@ -0,0 +1,192 @@
+//! Positions held as NFTs
+//!
+//! `tokenize_position` ties an isolated position to a program-minted NFT: a
+//! 0-decimal mint ([POSITION_MINT_SEED, position]) whose single token goes to a
+//! token account the program opens for the owner ([POSITION_HOLDER_SEED,
+//! position]), after which the mint authority is dropped. From then on whoever
+//! holds the token acts for the position in `open_position`, `close_position`
+//! and `settle_position`, passing their token account among the trailing
+//! accounts, and is paid to any quote token account they name. Transferring the
+//! token transfers the position, so other programs can wrap positions in their
+//! own products. The tie is permanent.
+//!
+//! The wallet that opened the position keeps no rights over it: every other
+//! owner-only instruction rejects a tokenized position. The position keeps its
+//! PDA, so `owner` still names that wallet, and the user stats, rewards and
+//! referral credit of its trades stay with it.
+
+use solana_program::{
+    account_info::{next_account_info, AccountInfo},
+    entrypoint::ProgramResult,
+    msg,
+    program::{invoke, invoke_signed},
+    program_error::ProgramError,
+    pubkey::Pubkey,
+    sysvar::{rent::Rent, Sysvar},
+};
+
+use crate::access::holds_access_token;
+use crate::{
+    create_initialize_account3_instruction, create_initialize_mint2_instruction, create_mint_to_checked_instruction,
+    create_program_account, create_revoke_mint_authority_instruction, load_config, require_isolated, require_not_settlement_only, require_position_owner,
+    validate_market_vault, validate_position_address, MarketState, Position, MINT_LEN, PDA_SEED, TOKEN_ACCOUNT_LEN,
+};
+
+/// Seed prefix for position NFT mint PDAs: [POSITION_MINT_SEED, position]
+pub const POSITION_MINT_SEED: &[u8] = b"position_mint";
+
+/// Seed prefix for the token account a position's NFT is minted into: [POSITION_HOLDER_SEED, position]
+pub const POSITION_HOLDER_SEED: &[u8] = b"position_holder";
+
+/// NFT mint PDA of the position at `position`
+pub fn position_mint_address(program_id: &Pubkey, position: &Pubkey) -> (Pubkey, u8) {
+    Pubkey::find_program_address(&[POSITION_MINT_SEED, position.as_ref()], program_id)
+}
+
+/// PDA of the token account the NFT of the position at `position` is minted into
+pub fn position_holder_address(program_id: &Pubkey, position: &Pubkey) -> (Pubkey, u8) {
+    Pubkey::find_program_address(&[POSITION_HOLDER_SEED, position.as_ref()], program_id)
+}
+
+// ---------------------------------------------------------------------
+// 8️⃣5️⃣ Tie a position to a program-minted NFT
+// ---------------------------------------------------------------------
+pub fn tokenize_position(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramResult {
+    // Accounts:
+    // 0. [signer, writable] owner (pays for the mint and token account)
+    // 1. [] token program (the config's)
+    // 2. [writable] position account
+    // 3. [] market state account
+    // 4. [writable] position mint (PDA: [POSITION_MINT_SEED, position])
+    // 5. [writable] owner's position token account (PDA: [POSITION_HOLDER_SEED, position])
+    // 6. [] vault token account (PDA; mints the NFT)
+    // 7. [] config account
+    // 8. [] quote mint
+    // 9. [] rent sysvar
+    // 10. [] system program
+    let accounts_iter = &mut accounts.iter();
+    let owner = next_account_info(accounts_iter)?;
+    let token_program = next_account_info(accounts_iter)?;
+    let position_acc = next_account_info(accounts_iter)?;
+    let market_state_acc = next_account_info(accounts_iter)?;
+    let position_mint = next_account_info(accounts_iter)?;
+    let holder_acc = next_account_info(accounts_iter)?;
+    let vault = next_account_info(accounts_iter)?;
+    let config_acc = next_account_info(accounts_iter)?;
+    let quote_mint = next_account_info(accounts_iter)?;
+    let rent_sysvar = next_account_info(accounts_iter)?;
+    let system_program = next_account_info(accounts_iter)?;
+
+    if !owner.is_signer {
+        msg!("Owner must be signer");
+        return Err(ProgramError::MissingRequiredSignature);
+    }
+
+    let config = load_config(program_id, config_acc)?;
+    require_not_settlement_only(&config)?;
+    if *token_program.key != config.token_program {
+        msg!("Token program mismatch. Expected: {}, Got: {}", config.token_program, token_program.key);
+        return Err(ProgramError::IncorrectProgramId);
+    }
+
+    if position_acc.owner != program_id || market_state_acc.owner != program_id {
+        msg!("Position and market state must be owned by the program");
+        return Err(ProgramError::IncorrectProgramId);
+    }
+
+    let market_state = *MarketState::load(&market_state_acc.try_borrow_data()?)?;
+    let (pda, bump) = validate_market_vault(program_id, token_program, vault, &market_state, quote_mint)?;
+    let mut position_data = position_acc.try_borrow_mut_data()?;
+    let position = Position::load_mut(&mut position_data)?;
+    require_position_owner(position, owner.key)?;
+    validate_position_address(program_id, position_acc.key, market_state_acc.key, position)?;
+
+    // Whatever the NFT can't act on stays out: the shared collateral of cross margin,
+    // collateral assets, which only the owner can withdraw, and a delegate
+    require_isolated(position)?;
+    if position.collateral_balances.iter().any(|balance| *balance > 0) {
+        msg!("Withdraw the position's collateral assets before tokenizing it");
+        return Err(ProgramError::InvalidArgument);
+    }
+    if position.delegate != Pubkey::default() {
+        msg!("Revoke the position's delegate before tokenizing it");
+        return Err(ProgramError::InvalidArgument);
+    }
+
+    let (expected_mint, mint_bump) = position_mint_address(program_id, position_acc.key);
+    if *position_mint.key != expected_mint {
+        msg!("Position mint is not the correct PDA. Expected: {}, Got: {}", expected_mint, position_mint.key);
+        return Err(ProgramError::InvalidArgument);
+    }
+    let (expected_holder, holder_bump) = position_holder_address(program_id, position_acc.key);
+    if *holder_acc.key != expected_holder {
+        msg!("Position token account is not the correct PDA. Expected: {}, Got: {}", expected_holder, holder_acc.key);
+        return Err(ProgramError::InvalidArgument);
+    }
+
+    // Both accounts live under the token program; the vault PDA mints the one token
+    let rent = Rent::from_account_info(rent_sysvar)?;
+    create_program_account(token_program.key, owner, position_mint, system_program, &rent, MINT_LEN, &[
+        POSITION_MINT_SEED,
+        position_acc.key.as_ref(),
+        &[mint_bump],
+    ])?;
+    invoke(
+        &create_initialize_mint2_instruction(token_program.key, position_mint.key, &pda, 0),
+        &[position_mint.clone(), token_program.clone()],
+    )?;
+    create_program_account(token_program.key, owner, holder_acc, system_program, &rent, TOKEN_ACCOUNT_LEN, &[
+        POSITION_HOLDER_SEED,
+        position_acc.key.as_ref(),
+        &[holder_bump],
+    ])?;
+    invoke(
+        &create_initialize_account3_instruction(token_program.key, holder_acc.key, position_mint.key, owner.key),
+        &[holder_acc.clone(), position_mint.clone(), token_program.clone()],
+    )?;
+
+    let signer_seeds: &[&[u8]] = &[PDA_SEED, &[bump]];
+    invoke_signed(
+        &create_mint_to_checked_instruction(token_program.key, position_mint.key, holder_acc.key, &pda, 1, 0),
+        &[position_mint.clone(), holder_acc.clone(), vault.clone(), token_program.clone()],
+        &[signer_seeds],
+    )?;
+    invoke_signed(
+        &create_revoke_mint_authority_instruction(token_program.key, position_mint.key, &pda),
+        &[position_mint.clone(), vault.clone(), token_program.clone()],
+        &[signer_seeds],
+    )?;
+
+    position.position_mint = *position_mint.key;
+
+    msg!("Position {} tokenized: mint={}, holder account={}", position_acc.key, position_mint.key, holder_acc.key);
+
+    Ok(())
+}
+
+/// Whether `account` is a token account of `position`'s NFT (holder unchecked)
+pub(crate) fn is_position_token_account(position: &Position, account: &AccountInfo) -> bool {
+    position.is_tokenized()
+        && account
+            .try_borrow_data()
+            .is_ok_and(|data| data.len() >= TOKEN_ACCOUNT_LEN && data[0..32] == position.position_mint.to_bytes())
+}
+
+/// Whether one of `accounts` is `signer`'s token account holding `position`'s NFT
+pub(crate) fn holds_position_token(position: &Position, signer: &Pubkey, accounts: &[&AccountInfo]) -> bool {
+    position.is_tokenized() && accounts.iter().any(|account| holds_access_token(&position.position_mint, signer, account))
+}
+
+/// Reject a close or settlement unless `signer` owns the position outright or, once it is
+/// tokenized, passes their token account holding its NFT among `accounts`
+pub(crate) fn require_position_authority(position: &Position, signer: &Pubkey, accounts: &[&AccountInfo]) -> ProgramResult {
+    if !position.is_tokenized() {
+        return require_position_owner(position, signer);
+    }
+    if !holds_position_token(position, signer, accounts) {
+        msg!("Position is tokenized: {} must pass its token account holding {}", signer, position.position_mint);
+        return Err(ProgramError::IllegalOwner);
+    }
+
+    Ok(())
+}
@@ -0,0 +1,195 @@
+//! Keeper-pushed price feeds
+//!
+//! For assets with no on-chain oracle, the admin creates a PriceFeed
+//! ([PRICE_FEED_SEED, feed_id]) naming a price authority, a keeper key that
+//! publishes prices with `push_price`. A feed's address can then be set anywhere
+//! a Pyth price account is accepted (index oracle, backup oracles, collateral
+//! asset oracles): the oracle reader recognizes the account by its type tag and
+//! applies the same staleness check, counted from the slot of the last push.
+
+use borsh::{BorshDeserialize, BorshSerialize};
+use shank::ShankAccount;
+use solana_program::{
+    account_info::{next_account_info, AccountInfo},
+    clock::Clock,
+    entrypoint::ProgramResult,
+    msg,
+    program::invoke_signed,
+    program_error::ProgramError,
+    pubkey::Pubkey,
+    system_instruction,
+    sysvar::{rent::Rent, Sysvar},
+};
+
+use crate::oracle::{OraclePrice, MAX_ORACLE_STALENESS_SLOTS};
+use crate::{load_account, load_admin_config, store_account, AccountType, DISCRIMINATOR_LEN};
+
+/// Seed prefix for price feed PDAs: [PRICE_FEED_SEED, feed_id (u64 LE)]
+pub const PRICE_FEED_SEED: &[u8] = b"price_feed";
+
+/// Most seconds a pushed price may have been observed before the push lands
+pub const MAX_PUSH_DELAY_SECS: i64 = 10;
+
+/// A price published by the feed's price authority
+#[derive(BorshSerialize, BorshDeserialize, Debug, Default, Clone, PartialEq, Eq, ShankAccount)]
+pub struct PriceFeed {
+    /// Identifier the PDA is derived from
+    pub feed_id: u64,
+    /// Key allowed to push prices
+    pub authority: Pubkey,
+    /// Last pushed price (1e9 precision; 0 = never pushed)
+    pub price: u64,
+    /// Confidence interval of the last price (1e9 precision)
+    pub conf: u64,
+    /// Unix timestamp the authority observed the last price at
+    pub timestamp: i64,
+    /// Slot the last price was pushed in
+    pub publish_slot: u64,
+    /// PDA bump for [PRICE_FEED_SEED, feed_id]
+    pub bump: u8,
+}
+
+impl AccountType for PriceFeed {
+    const DISCRIMINATOR: [u8; DISCRIMINATOR_LEN] = *b"pricefed";
+    /// feed_id + authority + price + conf + timestamp + publish_slot + bump
+    const LEN: usize = 8 + 32 + 8 + 8 + 8 + 8 + 1;
+}
+
+/// Price feed PDA of `feed_id`
+pub fn price_feed_address(program_id: &Pubkey, feed_id: u64) -> (Pubkey, u8) {
+    Pubkey::find_program_address(&[PRICE_FEED_SEED, &feed_id.to_le_bytes()], program_id)
+}
+
+/// Whether `data` holds a price feed rather than a Pyth account
+pub fn is_price_feed(data: &[u8]) -> bool {
+    data.starts_with(&PriceFeed::DISCRIMINATOR)
+}
+
+/// Parse a price feed's last pushed price, rejecting feeds never pushed to or not
+/// pushed to within MAX_ORACLE_STALENESS_SLOTS
+pub fn parse_price_feed(data: &[u8], current_slot: u64) -> Result<OraclePrice, ProgramError> {
+    let feed = load_account::<PriceFeed>(data)?;
+    if feed.price == 0 {
+        msg!("Price feed {} has no price yet", feed.feed_id);
+        return Err(ProgramError::InvalidAccountData);
+    }
+
+    if current_slot.saturating_sub(feed.publish_slot) > MAX_ORACLE_STALENESS_SLOTS {
+        msg!("Price feed {} is stale: pushed at slot {}, now {}", feed.feed_id, feed.publish_slot, current_slot);
+        return Err(ProgramError::InvalidAccountData);
+    }
+
+    Ok(OraclePrice { price: feed.price, conf: feed.conf, publish_slot: feed.publish_slot })
+}
+
+// ---------------------------------------------------------------------
+// 7️⃣3️⃣ Create a price feed or change its price authority (admin only)
+// ---------------------------------------------------------------------
+pub fn set_price_feed(program_id: &Pubkey, accounts: &[AccountInfo], feed_id: u64, authority: Pubkey) -> ProgramResult {
+    // Accounts:
+    // 0. [signer, writable] admin (pays for the feed on first use)
+    // 1. [] config account
+    // 2. [writable] price feed (PDA: [PRICE_FEED_SEED, feed_id])
+    // 3. [] rent sysvar
+    // 4. [] system program
+    let accounts_iter = &mut accounts.iter();
+    let admin = next_account_info(accounts_iter)?;
+    let config_acc = next_account_info(accounts_iter)?;
+    let feed_acc = next_account_info(accounts_iter)?;
+    let rent_sysvar = next_account_info(accounts_iter)?;
+    let system_program = next_account_info(accounts_iter)?;
+
+    load_admin_config(program_id, admin, config_acc)?;
+
+    let (expected_feed, bump) = price_feed_address(program_id, feed_id);
+    if *feed_acc.key != expected_feed {
+        msg!("Price feed is not the correct PDA. Expected: {}, Got: {}", expected_feed, feed_acc.key);
+        return Err(ProgramError::InvalidArgument);
+    }
+
+    let mut feed = if feed_acc.data_is_empty() {
+        let rent = Rent::from_account_info(rent_sysvar)?;
+        invoke_signed(&system_instruction::create_account(
+            admin.key,
+            feed_acc.key,
+            rent.minimum_balance(PriceFeed::SPACE),
+            PriceFeed::SPACE as u64,
+            program_id,
+        ), &[
+            admin.clone(),
+            feed_acc.clone(),
+            system_program.clone(),
+        ], &[&[PRICE_FEED_SEED, &feed_id.to_le_bytes(), &[bump]]])?;
+        PriceFeed { feed_id, bump, ..Default::default() }
+    } else {
+        if feed_acc.owner != program_id {
+            msg!("Price feed is not owned by the program");
+            return Err(ProgramError::IncorrectProgramId);
+        }
+        load_account::<PriceFeed>(&feed_acc.data.borrow())?
+    };
+
+    feed.authority = authority;
+    store_account(&feed, &mut feed_acc.data.borrow_mut())?;
+
+    msg!("Price feed {} authority set: {}", feed_id, authority);
+
+    Ok(())
+}
+
+// ---------------------------------------------------------------------
+// 7️⃣4️⃣ Publish a price to a feed (price authority only)
+// ---------------------------------------------------------------------
+pub fn push_price(program_id: &Pubkey, accounts: &[AccountInfo], price: u64, conf: u64, timestamp: i64) -> ProgramResult {
+    // Accounts:
+    // 0. [signer] price authority
+    // 1. [writable] price feed
+    // 2. [] clock sysvar
+    let accounts_iter = &mut accounts.iter();
+    let authority = next_account_info(accounts_iter)?;
+    let feed_acc = next_account_info(accounts_iter)?;
+    let clock_sysvar = next_account_info(accounts_iter)?;
+
+    if !authority.is_signer {
+        msg!("Price authority must be signer");
+        return Err(ProgramError::MissingRequiredSignature);
+    }
+
+    if feed_acc.owner != program_id {
+        msg!("Price feed is not owned by the program");
+        return Err(ProgramError::IncorrectProgramId);
+    }
+
+    let mut feed = load_account::<PriceFeed>(&feed_acc.data.borrow())?;
+    if feed.authority != *authority.key {
+        msg!("Price authority mismatch. Expected: {}, Got: {}", feed.authority, authority.key);
+        return Err(ProgramError::IllegalOwner);
+    }
+
+    if price == 0 {
+        msg!("Price must be positive");
+        return Err(ProgramError::InvalidArgument);
+    }
+
+    // Observations must arrive in order and promptly, so a delayed or replayed
+    // push can't roll the price back
+    let clock = Clock::from_account_info(clock_sysvar)?;
+    if timestamp <= feed.timestamp || timestamp > clock.unix_timestamp {
+        msg!("Price timestamp {} must be after {} and not after {}", timestamp, feed.timestamp, clock.unix_timestamp);
+        return Err(ProgramError::InvalidArgument);
+    }
+    if clock.unix_timestamp - timestamp > MAX_PUSH_DELAY_SECS {
+        msg!("Price observed at {} is more than {}s old", timestamp, MAX_PUSH_DELAY_SECS);
+        return Err(ProgramError::InvalidArgument);
+    }
+
+    feed.price = price;
+    feed.conf = conf;
+    feed.timestamp = timestamp;
+    feed.publish_slot = clock.slot;
+    store_account(&feed, &mut feed_acc.data.borrow_mut())?;
+
+    msg!("Price feed {} pushed: price={}, conf={}, timestamp={}", feed.feed_id, price, conf, timestamp);
+
+    Ok(())
+}
@@ -0,0 +1,231 @@
+//! Hourly mark price candles
+//!
+//! Anyone can create a market's PriceHistory account ([PRICE_HISTORY_SEED,
+//! market_state]): a fixed-size ring buffer of the last MAX_CANDLES hourly
+//! open/high/low/close candles of the mark price, so lightweight frontends can
+//! chart a market without an indexer.
+//!
+//! Candles are built opportunistically: `open_position`, `close_position`,
+//! `liquidate`, `liquidate_many` and `update_funding` record the mark price when
+//! the history is passed among their trailing accounts, where it is recognized
+//! by its PDA. An hour nobody touched has no candle, and a candle's open is the
+//! first price recorded in its hour.
+
+use borsh::{BorshDeserialize, BorshSerialize};
+use shank::ShankAccount;
+use solana_program::{
+    account_info::{next_account_info, AccountInfo},
+    entrypoint::ProgramResult,
+    msg,
+    program::invoke_signed,
+    program_error::ProgramError,
+    pubkey::Pubkey,
+    system_instruction,
+    sysvar::{rent::Rent, Sysvar},
+};
+
+use crate::{load_account, probe_account, store_account, AccountType, MarketState, DISCRIMINATOR_LEN};
+
+/// Seed prefix for price history PDAs: [PRICE_HISTORY_SEED, market_state]
+pub const PRICE_HISTORY_SEED: &[u8] = b"price_history";
+
+/// Candles a history keeps before overwriting the oldest (two days of hours)
+pub const MAX_CANDLES: usize = 48;
+
+/// Length of a candle (one hour, in seconds)
+pub const CANDLE_SECONDS: i64 = 3_600;
+
+/// Mark price range over one hour (1e9 precision)
+#[derive(BorshSerialize, BorshDeserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Candle {
+    /// Unix time the hour starts at
+    pub start_timestamp: i64,
+    /// First mark price recorded in the hour
+    pub open: u64,
+    /// Highest mark price recorded in the hour
+    pub high: u64,
+    /// Lowest mark price recorded in the hour
+    pub low: u64,
+    /// Latest mark price recorded in the hour
+    pub close: u64,
+}
+
+impl Candle {
+    /// start_timestamp + open + high + low + close
+    pub const LEN: usize = 8 * 5;
+}
+
+/// The last MAX_CANDLES hourly candles of a market's mark price
+#[derive(BorshSerialize, BorshDeserialize, Debug, Default, Clone, PartialEq, Eq, ShankAccount)]
+pub struct PriceHistory {
+    /// Market state account the history belongs to
+    pub market: Pubkey,
+    /// Slot in `candles` the next hour overwrites once the buffer is full
+    pub next_index: u16,
+    /// Candles in ring order: oldest at `next_index` once full, in push order until then
+    pub candles: Vec<Candle>,
+    /// PDA bump for [PRICE_HISTORY_SEED, market_state]
+    pub bump: u8,
+}
+
+impl AccountType for PriceHistory {
+    const DISCRIMINATOR: [u8; DISCRIMINATOR_LEN] = *b"pricehst";
+    /// market + next_index + vec length + MAX_CANDLES candles + bump
+    const LEN: usize = 32 + 2 + 4 + MAX_CANDLES * Candle::LEN + 1;
+}
+
+impl PriceHistory {
+    /// Record `price` at unix time `now`: extend the latest candle within its hour, or start
+    /// the next one, overwriting the oldest once the buffer is full. Prices timestamped before
+    /// the latest candle are ignored.
+    pub fn record(&mut self, price: u64, now: i64) {
+        let start_timestamp = now - now.rem_euclid(CANDLE_SECONDS);
+        if let Some(latest) = self.latest_mut() {
+            if start_timestamp < latest.start_timestamp {
+                return;
+            }
+            if start_timestamp == latest.start_timestamp {
+                latest.high = latest.high.max(price);
+                latest.low = latest.low.min(price);
+                latest.close = price;
+                return;
+            }
+        }
+
+        let candle = Candle { start_timestamp, open: price, high: price, low: price, close: price };
+        if self.candles.len() < MAX_CANDLES {
+            self.candles.push(candle);
+        } else {
+            self.candles[usize::from(self.next_index)] = candle;
+        }
+        self.next_index = ((usize::from(self.next_index) + 1) % MAX_CANDLES) as u16;
+    }
+
+    /// Candles from the oldest to the latest
+    pub fn candles_oldest_first(&self) -> impl Iterator<Item = &Candle> {
+        let split = if self.candles.len() < MAX_CANDLES { 0 } else { usize::from(self.next_index) };
+        self.candles[split..].iter().chain(&self.candles[..split])
+    }
+
+    /// The candle of the most recent hour recorded
+    fn latest_mut(&mut self) -> Option<&mut Candle> {
+        let index = if self.candles.len() < MAX_CANDLES {
+            self.candles.len().checked_sub(1)?
+        } else {
+            (usize::from(self.next_index) + MAX_CANDLES - 1) % MAX_CANDLES
+        };
+        self.candles.get_mut(index)
+    }
+}
+
+/// Price history PDA of `market_state`
+pub fn price_history_address(program_id: &Pubkey, market_state: &Pubkey) -> (Pubkey, u8) {
+    Pubkey::find_program_address(&[PRICE_HISTORY_SEED, market_state.as_ref()], program_id)
+}
+
+// ---------------------------------------------------------------------
+// 9️⃣0️⃣ Create a market's hourly price candles (permissionless)
+// ---------------------------------------------------------------------
+pub fn create_price_history(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramResult {
+    // Accounts:
+    // 0. [signer, writable] payer
+    // 1. [] market state account
+    // 2. [writable] price history account (PDA: [PRICE_HISTORY_SEED, market_state])
+    // 3. [] rent sysvar
+    // 4. [] system program
+    let accounts_iter = &mut accounts.iter();
+    let payer = next_account_info(accounts_iter)?;
+    let market_state_acc = next_account_info(accounts_iter)?;
+    let history_acc = next_account_info(accounts_iter)?;
+    let rent_sysvar = next_account_info(accounts_iter)?;
+    let system_program = next_account_info(accounts_iter)?;
+
+    if !payer.is_signer {
+        msg!("Payer must be signer");
+        return Err(ProgramError::MissingRequiredSignature);
+    }
+
+    if market_state_acc.owner != program_id {
+        msg!("Market state is not owned by the program");
+        return Err(ProgramError::IncorrectProgramId);
+    }
+    MarketState::load(&market_state_acc.data.borrow())?;
+
+    let (expected_history, bump) = price_history_address(program_id, market_state_acc.key);
+    if *history_acc.key != expected_history {
+        msg!("Price history account is not the correct PDA. Expected: {}, Got: {}", expected_history, history_acc.key);
+        return Err(ProgramError::InvalidArgument);
+    }
+
+    if !history_acc.data_is_empty() {
+        msg!("Price history account already exists");
+        return Err(ProgramError::AccountAlreadyInitialized);
+    }
+
+    let rent = Rent::from_account_info(rent_sysvar)?;
+    invoke_signed(&system_instruction::create_account(
+        payer.key,
+        history_acc.key,
+        rent.minimum_balance(PriceHistory::SPACE),
+        PriceHistory::SPACE as u64,
+        program_id,
+    ), &[
+        payer.clone(),
+        history_acc.clone(),
+        system_program.clone(),
+    ], &[&[PRICE_HISTORY_SEED, market_state_acc.key.as_ref(), &[bump]]])?;
+
+    let history = PriceHistory { market: *market_state_acc.key, bump, ..PriceHistory::default() };
+    store_account(&history, &mut history_acc.data.borrow_mut())?;
+
+    msg!("Created price history for {}", market_state_acc.key);
+
+    Ok(())
+}
+
+/// The price history of `market` among `accounts`, if it was passed
+pub(crate) fn find_price_history<'a, 'info>(
+    program_id: &Pubkey,
+    market: &Pubkey,
+    accounts: &[&'a AccountInfo<'info>],
+) -> Option<&'a AccountInfo<'info>> {
+    accounts.iter().copied().find(|account| {
+        if account.owner != program_id {
+            return false;
+        }
+        // Re-derive the PDA from the stored bump rather than searching for it
+        let Some(history) = account
+            .try_borrow_data()
+            .ok()
+            .and_then(|data| probe_account::<PriceHistory>(&data))
+        else {
+            return false;
+        };
+        history.market == *market
+            && Pubkey::create_program_address(&[PRICE_HISTORY_SEED, market.as_ref(), &[history.bump]], program_id)
+                .is_ok_and(|expected_history| expected_history == *account.key)
+    })
+}
+
+/// Record `price` at unix time `now` in the history in `history_acc`
+pub(crate) fn record_price(history_acc: &AccountInfo, price: u64, now: i64) -> ProgramResult {
+    let mut history = load_account::<PriceHistory>(&history_acc.data.borrow())?;
+    history.record(price, now);
+    store_account(&history, &mut history_acc.data.borrow_mut())?;
+
+    Ok(())
+}
+
+/// Record `price` in the history of `market` if it was passed among `accounts`
+pub(crate) fn record_price_if_passed(
+    program_id: &Pubkey,
+    market: &Pubkey,
+    accounts: &[&AccountInfo],
+    price: u64,
+    now: i64,
+) -> ProgramResult {
+    match find_price_history(program_id, market, accounts) {
+        Some(history_acc) => record_price(history_acc, price, now),
+        None => Ok(()),
+    }
+}
@@ -0,0 +1,432 @@
+//! Liquidity mining rewards
+//!
+//! The admin funds a rewards vault (any token account of the reward mint owned
+//! by the vault PDA) and sets a RewardsSchedule ([REWARDS_SEED]): traders earn
+//! `reward_per_volume` reward tokens per quote token of notional they trade
+//! between its start and end slots, until `total_emissions` is used up.
+//!
+//! A wallet opts in with a UserRewards account ([USER_REWARDS_SEED, owner]).
+//! Like user stats, opens and closes accrue to it when it is passed among their
+//! trailing accounts together with the (writable) schedule; both are recognized
+//! by their PDAs. `claim_rewards` pays the accrued rewards out of the vault.
+
+use borsh::{BorshDeserialize, BorshSerialize};
+use shank::ShankAccount;
+use solana_program::{
+    account_info::{next_account_info, AccountInfo},
+    clock::Clock,
+    entrypoint::ProgramResult,
+    msg,
+    program::invoke_signed,
+    program_error::ProgramError,
+    pubkey::Pubkey,
+    system_instruction,
+    sysvar::{rent::Rent, Sysvar},
+};
+
+use crate::math;
+use crate::{
+    create_transfer_checked_instruction, is_supported_token_program, load_account, load_admin_config, load_config, probe_account,
+    store_account, validate_mint, validate_token_account, vault_pda, AccountType, DISCRIMINATOR_LEN, PDA_SEED,
+};
+
+/// Seed of the rewards schedule PDA: [REWARDS_SEED]
+pub const REWARDS_SEED: &[u8] = b"rewards";
+
+/// Seed prefix for user rewards PDAs: [USER_REWARDS_SEED, owner]
+pub const USER_REWARDS_SEED: &[u8] = b"user_rewards";
+
+/// Trading rewards emissions: a rate per unit of volume over a slot window, within a budget
+#[derive(BorshSerialize, BorshDeserialize, Debug, Default, Clone, PartialEq, Eq, ShankAccount)]
+pub struct RewardsSchedule {
+    /// Mint rewards are paid in
+    pub reward_mint: Pubkey,
+    /// Token account of `reward_mint`, owned by the vault PDA, rewards are paid from
+    pub rewards_vault: Pubkey,
+    /// First slot whose trades earn rewards
+    pub start_slot: u64,
+    /// Trades from this slot on earn nothing
+    pub end_slot: u64,
+    /// Reward token base units earned per quote token of notional traded (1e9 precision)
+    pub reward_per_volume: u64,
+    /// Most reward tokens the schedule ever accrues (reward token base units)
+    pub total_emissions: u64,
+    /// Reward tokens accrued to traders so far (reward token base units)
+    pub emitted: u64,
+    /// PDA bump for [REWARDS_SEED]
+    pub bump: u8,
+}
+
+impl AccountType for RewardsSchedule {
+    const DISCRIMINATOR: [u8; DISCRIMINATOR_LEN] = *b"rwdsched";
+    /// reward_mint + rewards_vault + five u64 fields + bump
+    const LEN: usize = 32 + 32 + 8 * 5 + 1;
+}
+
+/// A wallet's trading rewards
+#[derive(BorshSerialize, BorshDeserialize, Debug, Default, Clone, PartialEq, Eq, ShankAccount)]
+pub struct UserRewards {
+    /// Wallet that earns and claims the rewards
+    pub owner: Pubkey,
+    /// Rewards earned and not yet claimed (reward token base units)
+    pub accrued: u64,
+    /// Rewards claimed, in total (reward token base units)
+    pub claimed: u64,
+    /// PDA bump for [USER_REWARDS_SEED, owner]
+    pub bump: u8,
+}
+
+impl AccountType for UserRewards {
+    const DISCRIMINATOR: [u8; DISCRIMINATOR_LEN] = *b"usrrwds\0";
+    /// owner + two u64 fields + bump
+    const LEN: usize = 32 + 8 * 2 + 1;
+}
+
+impl RewardsSchedule {
+    /// Rewards earned by trading `notional` (1e9 precision) at `slot`: nothing outside the
+    /// schedule's window, and never more than its remaining emissions
+    pub fn reward_for_volume(&self, notional: u64, slot: u64) -> Result<u64, ProgramError> {
+        if slot < self.start_slot || slot >= self.end_slot {
+            return Ok(0);
+        }
+        // The product is in 1e9 precision; rewards are paid in whole base units
+        let reward = math::from_precision(math::mul_scaled(notional, self.reward_per_volume)?, 0)?;
+
+        Ok(reward.min(self.total_emissions.saturating_sub(self.emitted)))
+    }
+}
+
+/// User rewards PDA of `owner`
+pub fn user_rewards_address(program_id: &Pubkey, owner: &Pubkey) -> (Pubkey, u8) {
+    Pubkey::find_program_address(&[USER_REWARDS_SEED, owner.as_ref()], program_id)
+}
+
+// ---------------------------------------------------------------------
+// 5️⃣4️⃣ Create or update the trading rewards schedule (admin only)
+// ---------------------------------------------------------------------
+pub fn set_rewards_schedule(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    start_slot: u64,
+    end_slot: u64,
+    reward_per_volume: u64,
+    total_emissions: u64,
+) -> ProgramResult {
+    // Accounts:
+    // 0. [signer, writable] admin (pays for the schedule on creation)
+    // 1. [] config account
+    // 2. [writable] rewards schedule (PDA: [REWARDS_SEED])
+    // 3. [] reward mint
+    // 4. [] rewards vault (token account of the reward mint owned by the vault PDA)
+    // 5. [] token program (the reward mint's)
+    // 6. [] rent sysvar
+    // 7. [] system program
+    let accounts_iter = &mut accounts.iter();
+    let admin = next_account_info(accounts_iter)?;
+    let config_acc = next_account_info(accounts_iter)?;
+    let schedule_acc = next_account_info(accounts_iter)?;
+    let reward_mint = next_account_info(accounts_iter)?;
+    let rewards_vault = next_account_info(accounts_iter)?;
+    let token_program = next_account_info(accounts_iter)?;
+    let rent_sysvar = next_account_info(accounts_iter)?;
+    let system_program = next_account_info(accounts_iter)?;
+
+    let config = load_admin_config(program_id, admin, config_acc)?;
+
+    if start_slot >= end_slot {
+        msg!("Rewards window must end after it starts");
+        return Err(ProgramError::InvalidArgument);
+    }
+
+    let (expected_schedule, bump) = Pubkey::find_program_address(&[REWARDS_SEED], program_id);
+    if *schedule_acc.key != expected_schedule {
+        msg!("Rewards schedule is not the correct PDA. Expected: {}, Got: {}", expected_schedule, schedule_acc.key);
+        return Err(ProgramError::InvalidArgument);
+    }
+
+    let mut schedule = if schedule_acc.data_is_empty() {
+        if !is_supported_token_program(token_program.key) {
+            msg!("Unsupported token program: {}", token_program.key);
+            return Err(ProgramError::IncorrectProgramId);
+        }
+        validate_mint(token_program, reward_mint, reward_mint.key)?;
+        let authority = vault_pda(program_id, config.vault_bump)?;
+        validate_token_account(token_program.key, rewards_vault, reward_mint.key, Some(&authority))?;
+
+        let rent = Rent::from_account_info(rent_sysvar)?;
+        invoke_signed(&system_instruction::create_account(
+            admin.key,
+            schedule_acc.key,
+            rent.minimum_balance(RewardsSchedule::SPACE),
+            RewardsSchedule::SPACE as u64,
+            program_id,
+        ), &[
+            admin.clone(),
+            schedule_acc.clone(),
+            system_program.clone(),
+        ], &[&[REWARDS_SEED, &[bump]]])?;
+
+        RewardsSchedule {
+            reward_mint: *reward_mint.key,
+            rewards_vault: *rewards_vault.key,
+            bump,
+            ..RewardsSchedule::default()
+        }
+    } else {
+        let schedule = load_schedule(program_id, schedule_acc)?;
+        if schedule.reward_mint != *reward_mint.key || schedule.rewards_vault != *rewards_vault.key {
+            msg!("The reward mint and vault of an existing schedule can't change");
+            return Err(ProgramError::InvalidArgument);
+        }
+        schedule
+    };
+
+    if total_emissions < schedule.emitted {
+        msg!("Total emissions {} are below the {} already emitted", total_emissions, schedule.emitted);
+        return Err(ProgramError::InvalidArgument);
+    }
+
+    schedule.start_slot = start_slot;
+    schedule.end_slot = end_slot;
+    schedule.reward_per_volume = reward_per_volume;
+    schedule.total_emissions = total_emissions;
+    store_account(&schedule, &mut schedule_acc.data.borrow_mut())?;
+
+    msg!("Rewards schedule set: slots {}..{}, reward_per_volume={}, total_emissions={}, emitted={}",
+         start_slot, end_slot, reward_per_volume, total_emissions, schedule.emitted);
+
+    Ok(())
+}
+
+// ---------------------------------------------------------------------
+// 5️⃣5️⃣ Create a wallet's rewards account
+// ---------------------------------------------------------------------
+pub fn create_user_rewards(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramResult {
+    // Accounts:
+    // 0. [signer, writable] owner (pays for the account)
+    // 1. [writable] user rewards account (PDA: [USER_REWARDS_SEED, owner])
+    // 2. [] rent sysvar
+    // 3. [] system program
+    let accounts_iter = &mut accounts.iter();
+    let owner = next_account_info(accounts_iter)?;
+    let rewards_acc = next_account_info(accounts_iter)?;
+    let rent_sysvar = next_account_info(accounts_iter)?;
+    let system_program = next_account_info(accounts_iter)?;
+
+    if !owner.is_signer {
+        msg!("Owner must be signer");
+        return Err(ProgramError::MissingRequiredSignature);
+    }
+
+    let (expected_rewards, bump) = user_rewards_address(program_id, owner.key);
+    if *rewards_acc.key != expected_rewards {
+        msg!("User rewards account is not the correct PDA. Expected: {}, Got: {}", expected_rewards, rewards_acc.key);
+        return Err(ProgramError::InvalidArgument);
+    }
+
+    if !rewards_acc.data_is_empty() {
+        msg!("User rewards account already exists");
+        return Err(ProgramError::AccountAlreadyInitialized);
+    }
+
+    let rent = Rent::from_account_info(rent_sysvar)?;
+    invoke_signed(&system_instruction::create_account(
+        owner.key,
+        rewards_acc.key,
+        rent.minimum_balance(UserRewards::SPACE),
+        UserRewards::SPACE as u64,
+        program_id,
+    ), &[
+        owner.clone(),
+        rewards_acc.clone(),
+        system_program.clone(),
+    ], &[&[USER_REWARDS_SEED, owner.key.as_ref(), &[bump]]])?;
+
+    let rewards = UserRewards { owner: *owner.key, bump, ..UserRewards::default() };
+    store_account(&rewards, &mut rewards_acc.data.borrow_mut())?;
+
+    msg!("Created user rewards account for {}", owner.key);
+
+    Ok(())
+}
+
+// ---------------------------------------------------------------------
+// 5️⃣6️⃣ Pay out a wallet's accrued trading rewards
+// ---------------------------------------------------------------------
+pub fn claim_rewards(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramResult {
+    // Accounts:
+    // 0. [signer] owner
+    // 1. [writable] user rewards account
+    // 2. [] rewards schedule
+    // 3. [writable] rewards vault
+    // 4. [writable] owner's reward token account
+    // 5. [] reward mint
+    // 6. [] token program (the reward mint's)
+    // 7. [] config account
+    // 8. [] vault PDA (the rewards vault's authority)
+    let accounts_iter = &mut accounts.iter();
+    let owner = next_account_info(accounts_iter)?;
+    let rewards_acc = next_account_info(accounts_iter)?;
+    let schedule_acc = next_account_info(accounts_iter)?;
+    let rewards_vault = next_account_info(accounts_iter)?;
+    let owner_token_acc = next_account_info(accounts_iter)?;
+    let reward_mint = next_account_info(accounts_iter)?;
+    let token_program = next_account_info(accounts_iter)?;
+    let config_acc = next_account_info(accounts_iter)?;
+    let vault_authority = next_account_info(accounts_iter)?;
+
+    if !owner.is_signer {
+        msg!("Owner must be signer");
+        return Err(ProgramError::MissingRequiredSignature);
+    }
+
+    let config = load_config(program_id, config_acc)?;
+    let schedule = load_schedule(program_id, schedule_acc)?;
+    let mut rewards = load_user_rewards(program_id, rewards_acc)?;
+    if rewards.owner != *owner.key {
+        msg!("User rewards owner mismatch. Expected: {}, Got: {}", rewards.owner, owner.key);
+        return Err(ProgramError::IllegalOwner);
+    }
+
+    if *rewards_vault.key != schedule.rewards_vault {
+        msg!("Rewards vault mismatch. Expected: {}, Got: {}", schedule.rewards_vault, rewards_vault.key);
+        return Err(ProgramError::InvalidArgument);
+    }
+    let decimals = validate_mint(token_program, reward_mint, &schedule.reward_mint)?;
+    let pda = vault_pda(program_id, config.vault_bump)?;
+    if *vault_authority.key != pda {
+        msg!("Vault authority is not the vault PDA. Expected: {}, Got: {}", pda, vault_authority.key);
+        return Err(ProgramError::InvalidArgument);
+    }
+    validate_token_account(token_program.key, rewards_vault, reward_mint.key, Some(&pda))?;
+    validate_token_account(token_program.key, owner_token_acc, reward_mint.key, None)?;
+
+    let claimed = rewards.accrued;
+    if claimed == 0 {
+        msg!("No rewards to claim");
+        return Ok(());
+    }
+
+    let seeds = &[PDA_SEED, &[config.vault_bump]];
+    let signer_seeds = &[&seeds[..]];
+
+    let transfer_ix = create_transfer_checked_instruction(
+        token_program.key,
+        rewards_vault.key,
+        reward_mint.key,
+        owner_token_acc.key,
+        &pda,
+        claimed,
+        decimals,
+    )?;
+
+    invoke_signed(&transfer_ix, &[
+        rewards_vault.clone(),
+        reward_mint.clone(),
+        owner_token_acc.clone(),
+        vault_authority.clone(),
+        token_program.clone(),
+    ], signer_seeds)?;
+
+    rewards.accrued = 0;
+    rewards.claimed = rewards.claimed.saturating_add(claimed);
+    store_account(&rewards, &mut rewards_acc.data.borrow_mut())?;
+
+    msg!("Rewards claimed: {}", claimed);
+
+    Ok(())
+}
+
+/// Accrue `owner`'s rewards for a trade of `notional` (1e9 precision) if the rewards
+/// schedule and the owner's user rewards account were both passed among `accounts`
+pub(crate) fn accrue_trading_rewards(
+    program_id: &Pubkey,
+    owner: &Pubkey,
+    accounts: &[&AccountInfo],
+    notional: u64,
+) -> ProgramResult {
+    let Some(schedule_acc) = accounts.iter().copied().find(|account| is_schedule(program_id, account)) else {
+        return Ok(());
+    };
+    let Some(rewards_acc) = accounts.iter().copied().find(|account| is_user_rewards(program_id, owner, account)) else {
+        return Ok(());
+    };
+
+    let mut schedule = load_account::<RewardsSchedule>(&schedule_acc.data.borrow())?;
+    let reward = schedule.reward_for_volume(notional, Clock::get()?.slot)?;
+    if reward == 0 {
+        return Ok(());
+    }
+
+    let mut rewards = load_account::<UserRewards>(&rewards_acc.data.borrow())?;
+    rewards.accrued = rewards.accrued
+        .checked_add(reward)
+        .ok_or(ProgramError::InvalidArgument)?;
+    schedule.emitted = schedule.emitted
+        .checked_add(reward)
+        .ok_or(ProgramError::InvalidArgument)?;
+    store_account(&rewards, &mut rewards_acc.data.borrow_mut())?;
+    store_account(&schedule, &mut schedule_acc.data.borrow_mut())?;
+
+    msg!("Accrued trading rewards: {}", reward);
+
+    Ok(())
+}
+
+/// Whether `account` is the rewards schedule or `owner`'s user rewards account
+pub(crate) fn is_rewards_account(program_id: &Pubkey, owner: &Pubkey, account: &AccountInfo) -> bool {
+    is_schedule(program_id, account) || is_user_rewards(program_id, owner, account)
+}
+
+/// Whether `account` is the program's rewards schedule
+fn is_schedule(program_id: &Pubkey, account: &AccountInfo) -> bool {
+    account.owner == program_id
+        && account
+            .try_borrow_data()
+            .ok()
+            .and_then(|data| probe_account::<RewardsSchedule>(&data))
+            .is_some_and(|schedule| {
+                Pubkey::create_program_address(&[REWARDS_SEED, &[schedule.bump]], program_id)
+                    .is_ok_and(|expected| expected == *account.key)
+            })
+}
+
+/// Whether `account` is `owner`'s user rewards account
+fn is_user_rewards(program_id: &Pubkey, owner: &Pubkey, account: &AccountInfo) -> bool {
+    account.owner == program_id
+        && account
+            .try_borrow_data()
+            .ok()
+            .and_then(|data| probe_account::<UserRewards>(&data))
+            .is_some_and(|rewards| {
+                rewards.owner == *owner
+                    && Pubkey::create_program_address(&[USER_REWARDS_SEED, owner.as_ref(), &[rewards.bump]], program_id)
+                        .is_ok_and(|expected| expected == *account.key)
+            })
+}
+
+/// Load the rewards schedule, checking its owner and address
+fn load_schedule(program_id: &Pubkey, schedule_acc: &AccountInfo) -> Result<RewardsSchedule, ProgramError> {
+    if !is_schedule(program_id, schedule_acc) {
+        msg!("Account {} is not the rewards schedule", schedule_acc.key);
+        return Err(ProgramError::InvalidAccountData);
+    }
+
+    load_account::<RewardsSchedule>(&schedule_acc.data.borrow())
+}
+
+/// Load a user rewards account, checking its owner and address
+fn load_user_rewards(program_id: &Pubkey, rewards_acc: &AccountInfo) -> Result<UserRewards, ProgramError> {
+    if rewards_acc.owner != program_id {
+        msg!("User rewards account is not owned by the program");
+        return Err(ProgramError::IncorrectProgramId);
+    }
+
+    let rewards = load_account::<UserRewards>(&rewards_acc.data.borrow())?;
+    if !is_user_rewards(program_id, &rewards.owner, rewards_acc) {
+        msg!("User rewards account is not the correct PDA");
+        return Err(ProgramError::InvalidArgument);
+    }
+
+    Ok(rewards)
+}
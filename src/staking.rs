@@ -0,0 +1,377 @@
+//! Protocol token staking for trading fee discounts
+//!
+//! The admin names a stake mint and vault (a token account of the mint owned by
+//! the vault PDA) and a discount schedule in the config with
+//! `set_stake_discounts`. A wallet stakes into its StakeAccount ([STAKE_SEED,
+//! owner]); `open_position` takes the discount of the highest schedule entry its
+//! staked balance reaches off the trading fee when the account is passed among
+//! its trailing accounts, where it is recognized by its PDA. Stakes are locked
+//! for STAKE_LOCKUP_SLOTS after the last deposit, so a stake can't be borrowed
+//! for a single trade.
+
+use borsh::{BorshDeserialize, BorshSerialize};
+use shank::ShankAccount;
+use solana_program::{
+    account_info::{next_account_info, AccountInfo},
+    clock::Clock,
+    entrypoint::ProgramResult,
+    msg,
+    program::{invoke, invoke_signed},
+    program_error::ProgramError,
+    pubkey::Pubkey,
+    system_instruction,
+    sysvar::{rent::Rent, Sysvar},
+};
+
+use crate::math::PRECISION;
+use crate::{
+    create_transfer_checked_instruction, is_supported_token_program, load_account, load_admin_config, load_config, probe_account,
+    require_not_settlement_only, store_account, validate_mint, validate_token_account, vault_pda, AccountType, Config, DISCRIMINATOR_LEN, PDA_SEED,
+};
+
+/// Seed prefix for stake account PDAs: [STAKE_SEED, owner]
+pub const STAKE_SEED: &[u8] = b"stake";
+
+/// Max number of entries in the stake discount schedule
+pub const MAX_STAKE_DISCOUNTS: usize = 4;
+
+/// Slots a stake stays locked after its last deposit (~1 day)
+pub const STAKE_LOCKUP_SLOTS: u64 = 216_000;
+
+/// A trading fee discount that applies from a staked balance on
+#[derive(BorshSerialize, BorshDeserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct StakeDiscount {
+    /// Staked balance that qualifies for the discount (stake token base units)
+    pub min_stake: u64,
+    /// Share of the trading fee waived (1e9 precision)
+    pub discount: u64,
+}
+
+impl StakeDiscount {
+    /// Serialized size: min_stake + discount
+    pub const LEN: usize = 8 + 8;
+}
+
+/// A wallet's staked protocol tokens
+#[derive(BorshSerialize, BorshDeserialize, Debug, Default, Clone, PartialEq, Eq, ShankAccount)]
+pub struct StakeAccount {
+    /// Wallet that staked the tokens and can unstake them
+    pub owner: Pubkey,
+    /// Tokens staked (stake token base units)
+    pub staked: u64,
+    /// Slot of the last deposit; unstaking opens STAKE_LOCKUP_SLOTS later
+    pub last_stake_slot: u64,
+    /// PDA bump for [STAKE_SEED, owner]
+    pub bump: u8,
+}
+
+impl AccountType for StakeAccount {
+    const DISCRIMINATOR: [u8; DISCRIMINATOR_LEN] = *b"stake\0\0\0";
+    /// owner + staked + last_stake_slot + bump
+    const LEN: usize = 32 + 8 + 8 + 1;
+}
+
+/// Stake account PDA of `owner`
+pub fn stake_address(program_id: &Pubkey, owner: &Pubkey) -> (Pubkey, u8) {
+    Pubkey::find_program_address(&[STAKE_SEED, owner.as_ref()], program_id)
+}
+
+// ---------------------------------------------------------------------
+// 5️⃣9️⃣ Set the stake token and fee discount schedule (admin only)
+// ---------------------------------------------------------------------
+pub fn set_stake_discounts(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    stake_discounts: Vec<StakeDiscount>,
+) -> ProgramResult {
+    // Accounts:
+    // 0. [signer] admin
+    // 1. [writable] config account
+    // 2. [] stake mint
+    // 3. [] stake vault (token account of the stake mint owned by the vault PDA)
+    // 4. [] token program (the stake mint's)
+    let accounts_iter = &mut accounts.iter();
+    let admin = next_account_info(accounts_iter)?;
+    let config_acc = next_account_info(accounts_iter)?;
+    let stake_mint = next_account_info(accounts_iter)?;
+    let stake_vault = next_account_info(accounts_iter)?;
+    let token_program = next_account_info(accounts_iter)?;
+
+    let mut config = load_admin_config(program_id, admin, config_acc)?;
+
+    if stake_discounts.len() > MAX_STAKE_DISCOUNTS {
+        msg!("At most {} stake discounts, got {}", MAX_STAKE_DISCOUNTS, stake_discounts.len());
+        return Err(ProgramError::InvalidArgument);
+    }
+
+    if stake_discounts.first().is_some_and(|entry| entry.min_stake == 0)
+        || stake_discounts.windows(2).any(|pair| pair[1].min_stake <= pair[0].min_stake)
+    {
+        msg!("Stake discounts must be in ascending order of a non-zero min_stake");
+        return Err(ProgramError::InvalidArgument);
+    }
+
+    if let Some(entry) = stake_discounts.iter().find(|entry| entry.discount > PRECISION) {
+        msg!("Stake discount must not exceed 100%: {}", entry.discount);
+        return Err(ProgramError::InvalidArgument);
+    }
+
+    // The stake token is fixed once chosen: existing stakes are held in its vault
+    if config.stake_mint == Pubkey::default() {
+        if !is_supported_token_program(token_program.key) {
+            msg!("Unsupported token program: {}", token_program.key);
+            return Err(ProgramError::IncorrectProgramId);
+        }
+        validate_mint(token_program, stake_mint, stake_mint.key)?;
+        let authority = vault_pda(program_id, config.vault_bump)?;
+        validate_token_account(token_program.key, stake_vault, stake_mint.key, Some(&authority))?;
+
+        config.stake_mint = *stake_mint.key;
+        config.stake_vault = *stake_vault.key;
+    } else if config.stake_mint != *stake_mint.key || config.stake_vault != *stake_vault.key {
+        msg!("The stake mint and vault can't change once set");
+        return Err(ProgramError::InvalidArgument);
+    }
+
+    config.stake_discounts = stake_discounts;
+    store_account(&config, &mut config_acc.data.borrow_mut())?;
+
+    for entry in &config.stake_discounts {
+        msg!("Stake discount: min_stake={}, discount={}", entry.min_stake, entry.discount);
+    }
+    msg!("Set {} stake discounts for stake mint {}", config.stake_discounts.len(), config.stake_mint);
+
+    Ok(())
+}
+
+// ---------------------------------------------------------------------
+// 6️⃣0️⃣ Stake protocol tokens
+// ---------------------------------------------------------------------
+pub fn stake_tokens(program_id: &Pubkey, accounts: &[AccountInfo], amount: u64) -> ProgramResult {
+    // Accounts:
+    // 0. [signer, writable] owner (pays for the stake account on first use)
+    // 1. [] token program (the stake mint's)
+    // 2. [writable] owner's stake token account
+    // 3. [writable] stake vault
+    // 4. [writable] stake account (PDA: [STAKE_SEED, owner])
+    // 5. [] config account
+    // 6. [] stake mint
+    // 7. [] rent sysvar
+    // 8. [] system program
+    let accounts_iter = &mut accounts.iter();
+    let owner = next_account_info(accounts_iter)?;
+    let token_program = next_account_info(accounts_iter)?;
+    let owner_token_acc = next_account_info(accounts_iter)?;
+    let stake_vault = next_account_info(accounts_iter)?;
+    let stake_acc = next_account_info(accounts_iter)?;
+    let config_acc = next_account_info(accounts_iter)?;
+    let stake_mint = next_account_info(accounts_iter)?;
+    let rent_sysvar = next_account_info(accounts_iter)?;
+    let system_program = next_account_info(accounts_iter)?;
+
+    if !owner.is_signer {
+        msg!("Owner must be signer");
+        return Err(ProgramError::MissingRequiredSignature);
+    }
+
+    if amount == 0 {
+        msg!("Stake amount must be positive");
+        return Err(ProgramError::InvalidArgument);
+    }
+
+    let config = load_config(program_id, config_acc)?;
+    require_not_settlement_only(&config)?;
+    let decimals = validate_stake_token_accounts(program_id, &config, token_program, stake_mint, stake_vault)?;
+    validate_token_account(token_program.key, owner_token_acc, stake_mint.key, None)?;
+
+    let (expected_stake, bump) = stake_address(program_id, owner.key);
+    if *stake_acc.key != expected_stake {
+        msg!("Stake account is not the correct PDA. Expected: {}, Got: {}", expected_stake, stake_acc.key);
+        return Err(ProgramError::InvalidArgument);
+    }
+
+    let mut stake = if stake_acc.data_is_empty() {
+        let rent = Rent::from_account_info(rent_sysvar)?;
+        invoke_signed(&system_instruction::create_account(
+            owner.key,
+            stake_acc.key,
+            rent.minimum_balance(StakeAccount::SPACE),
+            StakeAccount::SPACE as u64,
+            program_id,
+        ), &[
+            owner.clone(),
+            stake_acc.clone(),
+            system_program.clone(),
+        ], &[&[STAKE_SEED, owner.key.as_ref(), &[bump]]])?;
+
+        StakeAccount { owner: *owner.key, bump, ..StakeAccount::default() }
+    } else {
+        load_stake(program_id, owner.key, stake_acc)?
+    };
+
+    let transfer_ix = create_transfer_checked_instruction(
+        token_program.key,
+        owner_token_acc.key,
+        stake_mint.key,
+        stake_vault.key,
+        owner.key,
+        amount,
+        decimals,
+    )?;
+
+    invoke(&transfer_ix, &[
+        owner_token_acc.clone(),
+        stake_mint.clone(),
+        stake_vault.clone(),
+        owner.clone(),
+        token_program.clone(),
+    ])?;
+
+    stake.staked = stake.staked
+        .checked_add(amount)
+        .ok_or(ProgramError::InvalidArgument)?;
+    stake.last_stake_slot = Clock::get()?.slot;
+    store_account(&stake, &mut stake_acc.data.borrow_mut())?;
+
+    msg!("Staked {}: total staked {}, locked until slot {}",
+         amount, stake.staked, stake.last_stake_slot.saturating_add(STAKE_LOCKUP_SLOTS));
+
+    Ok(())
+}
+
+// ---------------------------------------------------------------------
+// 6️⃣1️⃣ Unstake protocol tokens
+// ---------------------------------------------------------------------
+pub fn unstake_tokens(program_id: &Pubkey, accounts: &[AccountInfo], amount: u64) -> ProgramResult {
+    // Accounts:
+    // 0. [signer] owner
+    // 1. [] token program (the stake mint's)
+    // 2. [writable] owner's stake token account
+    // 3. [writable] stake vault
+    // 4. [writable] stake account
+    // 5. [] config account
+    // 6. [] stake mint
+    // 7. [] vault PDA (the stake vault's authority)
+    let accounts_iter = &mut accounts.iter();
+    let owner = next_account_info(accounts_iter)?;
+    let token_program = next_account_info(accounts_iter)?;
+    let owner_token_acc = next_account_info(accounts_iter)?;
+    let stake_vault = next_account_info(accounts_iter)?;
+    let stake_acc = next_account_info(accounts_iter)?;
+    let config_acc = next_account_info(accounts_iter)?;
+    let stake_mint = next_account_info(accounts_iter)?;
+    let vault_authority = next_account_info(accounts_iter)?;
+
+    if !owner.is_signer {
+        msg!("Owner must be signer");
+        return Err(ProgramError::MissingRequiredSignature);
+    }
+
+    let config = load_config(program_id, config_acc)?;
+    let decimals = validate_stake_token_accounts(program_id, &config, token_program, stake_mint, stake_vault)?;
+    validate_token_account(token_program.key, owner_token_acc, stake_mint.key, None)?;
+    let pda = vault_pda(program_id, config.vault_bump)?;
+    if *vault_authority.key != pda {
+        msg!("Vault authority is not the vault PDA. Expected: {}, Got: {}", pda, vault_authority.key);
+        return Err(ProgramError::InvalidArgument);
+    }
+
+    let mut stake = load_stake(program_id, owner.key, stake_acc)?;
+    if amount == 0 || amount > stake.staked {
+        msg!("Unstake amount must be between 1 and the {} staked", stake.staked);
+        return Err(ProgramError::InvalidArgument);
+    }
+
+    let unlock_slot = stake.last_stake_slot.saturating_add(STAKE_LOCKUP_SLOTS);
+    let slot = Clock::get()?.slot;
+    if slot < unlock_slot {
+        msg!("Stake is locked until slot {} (current slot {})", unlock_slot, slot);
+        return Err(ProgramError::InvalidArgument);
+    }
+
+    let seeds = &[PDA_SEED, &[config.vault_bump]];
+    let signer_seeds = &[&seeds[..]];
+
+    let transfer_ix = create_transfer_checked_instruction(
+        token_program.key,
+        stake_vault.key,
+        stake_mint.key,
+        owner_token_acc.key,
+        &pda,
+        amount,
+        decimals,
+    )?;
+
+    invoke_signed(&transfer_ix, &[
+        stake_vault.clone(),
+        stake_mint.clone(),
+        owner_token_acc.clone(),
+        vault_authority.clone(),
+        token_program.clone(),
+    ], signer_seeds)?;
+
+    stake.staked -= amount;
+    store_account(&stake, &mut stake_acc.data.borrow_mut())?;
+
+    msg!("Unstaked {}: total staked {}", amount, stake.staked);
+
+    Ok(())
+}
+
+/// `owner`'s stake account among `accounts`, if it was passed
+pub(crate) fn find_stake<'a, 'info>(
+    program_id: &Pubkey,
+    owner: &Pubkey,
+    accounts: &[&'a AccountInfo<'info>],
+) -> Option<&'a AccountInfo<'info>> {
+    accounts.iter().copied().find(|account| is_stake_account(program_id, owner, account))
+}
+
+/// Whether `account` is `owner`'s stake account
+pub(crate) fn is_stake_account(program_id: &Pubkey, owner: &Pubkey, account: &AccountInfo) -> bool {
+    account.owner == program_id
+        && account
+            .try_borrow_data()
+            .ok()
+            .and_then(|data| probe_account::<StakeAccount>(&data))
+            .is_some_and(|stake| {
+                stake.owner == *owner
+                    && Pubkey::create_program_address(&[STAKE_SEED, owner.as_ref(), &[stake.bump]], program_id)
+                        .is_ok_and(|expected| expected == *account.key)
+            })
+}
+
+/// Load `owner`'s stake account, checking its owner and address
+fn load_stake(program_id: &Pubkey, owner: &Pubkey, stake_acc: &AccountInfo) -> Result<StakeAccount, ProgramError> {
+    if !is_stake_account(program_id, owner, stake_acc) {
+        msg!("Account {} is not the stake account of {}", stake_acc.key, owner);
+        return Err(ProgramError::InvalidAccountData);
+    }
+
+    load_account::<StakeAccount>(&stake_acc.data.borrow())
+}
+
+/// Check the stake mint and vault are the config's, returning the mint's decimals
+fn validate_stake_token_accounts(
+    program_id: &Pubkey,
+    config: &Config,
+    token_program: &AccountInfo,
+    stake_mint: &AccountInfo,
+    stake_vault: &AccountInfo,
+) -> Result<u8, ProgramError> {
+    if config.stake_mint == Pubkey::default() {
+        msg!("Staking is not set up");
+        return Err(ProgramError::InvalidArgument);
+    }
+
+    if *stake_vault.key != config.stake_vault {
+        msg!("Stake vault mismatch. Expected: {}, Got: {}", config.stake_vault, stake_vault.key);
+        return Err(ProgramError::InvalidArgument);
+    }
+
+    let decimals = validate_mint(token_program, stake_mint, &config.stake_mint)?;
+    let authority = vault_pda(program_id, config.vault_bump)?;
+    validate_token_account(token_program.key, stake_vault, stake_mint.key, Some(&authority))?;
+
+    Ok(decimals)
+}
@@ -1,24 +1,41 @@
-use solana_program::{
-    account_info::AccountInfo,
-    clock::Clock,
-    program_error::ProgramError,
-    pubkey::Pubkey,
-    rent::Rent,
-    sysvar::Sysvar,
+use solana_program::pubkey::Pubkey;
+use crate::{
+    Position, MarketState, calculate_position_health, calculate_unrealized_pnl,
+    calculate_trading_fee, calculate_referral_share, calculate_vamm_price,
+    calculate_quote_reserve, calculate_vamm_fill, calculate_slippage, is_reducing_change,
+    calculate_leverage, exceeds_open_interest_cap, Config, AccountType, Versioned, Referrer,
+    store_account, CollateralAsset, MAX_COLLATERAL_ASSETS, FeeTier, MAX_FEE_TIERS, RiskTier, MAX_RISK_TIERS, calculate_asset_collateral_value,
+    SPL_TOKEN_PROGRAM_ID, TOKEN_2022_PROGRAM_ID, clamp_to_price_band, is_within_price_band, calculate_ema_decay,
 };
-use crate::{Position, MarketState, calculate_position_health, calculate_unrealized_pnl};
+use crate::cross_margin::{
+    calculate_cross_margin_health, calculate_portfolio_health, calculate_required_collateral_ratio, CrossPosition, PortfolioHealth, UserAccount,
+    MAX_CROSS_MARGIN_POSITIONS, USER_ACCOUNT_SEED,
+};
+use crate::liquidity_pool::{calculate_lp_shares_for_deposit, calculate_lp_withdrawal, PoolState, WithdrawalRequest};
+use crate::oracle::{
+    calculate_median_price, parse_oracle_price, parse_pyth_price, parse_pyth_twap, scale_to_precision, OraclePrice,
+    MAX_ORACLE_STALENESS_SLOTS,
+};
+use crate::orderbook::{Order, OrderBook, OrderSide, MAX_ORDERS};
+use crate::price_feed::PriceFeed;
+use crate::staking::{StakeDiscount, MAX_STAKE_DISCOUNTS};
+use crate::trigger_orders::{QueuedTrigger, TriggerKind, TriggerOrder, TriggerQueue, MAX_QUEUED_TRIGGERS};
+use borsh::BorshSerialize;
 
 #[cfg(test)]
+#[allow(clippy::module_inception)]
 mod tests {
     use super::*;
 
     #[test]
     fn test_position_creation() {
-        let mut position = Position::default();
-        position.owner = Pubkey::new_unique();
-        position.base_amount = 1_000_000_000; // 1 unit long
-        position.collateral = 150_000_000_000; // 150 units collateral
-        position.entry_price = 100_000_000_000; // $100
+        let position = Position {
+            owner: Pubkey::new_unique(),
+            base_amount: 1_000_000_000, // 1 unit long
+            collateral: 150_000_000_000, // 150 units collateral
+            entry_price: 100_000_000_000, // $100
+            ..Default::default()
+        };
         
         assert_eq!(position.base_amount, 1_000_000_000);
         assert_eq!(position.collateral, 150_000_000_000);
@@ -34,6 +51,7 @@ mod tests {
             bump: 255,
             last_funding_slot: 1000,
             mark_price: 100_000_000_000,
+            ..Default::default()
         };
         
         assert_eq!(market_state.funding_index, 0);
@@ -49,6 +67,7 @@ mod tests {
             collateral: 150_000_000_000, // 150 units
             last_funding_index: 0,
             entry_price: 100_000_000_000, // $100
+            ..Default::default()
         };
 
         let mark_price = 100_000_000_000; // $100
@@ -66,6 +85,7 @@ mod tests {
             collateral: 150_000_000_000, // 150 units
             last_funding_index: 0,
             entry_price: 100_000_000_000, // $100
+            ..Default::default()
         };
 
         // Price drops to $120 - position value increases for long
@@ -85,6 +105,7 @@ mod tests {
             collateral: 150_000_000_000,
             last_funding_index: 0,
             entry_price: 100_000_000_000, // $100 entry
+            ..Default::default()
         };
 
         let mark_price = 110_000_000_000; // $110 current
@@ -102,6 +123,7 @@ mod tests {
             collateral: 150_000_000_000,
             last_funding_index: 0,
             entry_price: 100_000_000_000, // $100 entry
+            ..Default::default()
         };
 
         let mark_price = 90_000_000_000; // $90 current
@@ -119,6 +141,7 @@ mod tests {
             collateral: 150_000_000_000,
             last_funding_index: 0,
             entry_price: 100_000_000_000, // $100 entry
+            ..Default::default()
         };
 
         let mark_price = 90_000_000_000; // $90 current
@@ -136,6 +159,7 @@ mod tests {
             collateral: 150_000_000_000,
             last_funding_index: 0,
             entry_price: 100_000_000_000, // $100 entry
+            ..Default::default()
         };
 
         let mark_price = 110_000_000_000; // $110 current
@@ -147,12 +171,13 @@ mod tests {
 
     #[test]
     fn test_funding_calculation() {
-        let mut position = Position {
+        let position = Position {
             owner: Pubkey::new_unique(),
             base_amount: 1_000_000_000, // 1 unit long
             collateral: 150_000_000_000,
             last_funding_index: 0,
             entry_price: 100_000_000_000,
+            ..Default::default()
         };
 
         let funding_index = 1_000_000; // Some accumulated funding
@@ -162,7 +187,8 @@ mod tests {
             * (funding_index as i128))
             / 1_000_000_000i128;
         
-        assert_eq!(funding_payment, 1_000); // 1 unit * 1_000_000 / 1e9 = 0.001
+        // 1e9 (1 unit) * 1e6 / 1e9 = 1e6, i.e. 0.001 quote at 1e9 precision
+        assert_eq!(funding_payment, 1_000_000);
     }
 
     #[test]
@@ -173,6 +199,7 @@ mod tests {
             collateral: 100_000_000_000, // Only 100 units collateral
             last_funding_index: 0,
             entry_price: 100_000_000_000, // $100
+            ..Default::default()
         };
 
         let mark_price = 100_000_000_000; // $100
@@ -191,6 +218,7 @@ mod tests {
             collateral: 100_000_000_000,
             last_funding_index: 0,
             entry_price: 0,
+            ..Default::default()
         };
 
         let mark_price = 100_000_000_000;
@@ -206,10 +234,2443 @@ mod tests {
         let price_100_50 = 100_500_000_000u64; // $100.50
         let size_1_5 = 1_500_000_000i64; // 1.5 units
         
-        // Calculate position value: size * price / 1e9
-        let position_value = ((size_1_5 as u64) * price_100_50) / 1_000_000_000;
+        // Calculate position value: size * price / 1e9, widened first since the
+        // product (~1.5e20) overflows u64
+        let position_value = ((size_1_5 as u128) * (price_100_50 as u128)) / 1_000_000_000;
         
         // Should be 1.5 * 100.50 = 150.75
         assert_eq!(position_value, 150_750_000_000);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_trading_fee_calculation() {
+        // 2 units at $100 = $200 notional, 0.1% fee = $0.20
+        let fee = calculate_trading_fee(2_000_000_000, 100_000_000_000, 1_000_000).unwrap();
+        assert_eq!(fee, 200_000_000);
+
+        // Shorts pay the same fee on the same notional
+        let fee_short = calculate_trading_fee(-2_000_000_000, 100_000_000_000, 1_000_000).unwrap();
+        assert_eq!(fee_short, fee);
+
+        // No size change = no fee
+        assert_eq!(calculate_trading_fee(0, 100_000_000_000, 1_000_000).unwrap(), 0);
+
+        // A higher configured rate scales the fee linearly
+        let fee_high = calculate_trading_fee(2_000_000_000, 100_000_000_000, 5_000_000).unwrap();
+        assert_eq!(fee_high, 1_000_000_000);
+    }
+
+    #[test]
+    fn test_referral_share_calculation() {
+        // 20% of a $0.20 fee = $0.04
+        let share = calculate_referral_share(200_000_000, 200_000_000).unwrap();
+        assert_eq!(share, 40_000_000);
+
+        // Large fees don't overflow the intermediate product
+        let share_large = calculate_referral_share(1_000_000_000_000_000, 200_000_000).unwrap();
+        assert_eq!(share_large, 200_000_000_000_000);
+    }
+
+    #[test]
+    fn test_vamm_price_from_reserves() {
+        let base_reserve = 1_000_000_000_000_000; // 1,000,000 units
+        let quote_reserve = calculate_quote_reserve(base_reserve, 100_000_000_000).unwrap();
+        assert_eq!(quote_reserve, 100_000_000_000_000_000);
+
+        let price = calculate_vamm_price(base_reserve, quote_reserve).unwrap();
+        assert_eq!(price, 100_000_000_000); // $100
+    }
+
+    #[test]
+    fn test_vamm_long_fill_pays_above_spot() {
+        let base_reserve = 1_000_000_000_000; // 1,000 units
+        let quote_reserve = 100_000_000_000_000; // $100 spot
+
+        // Buy 10 units: new base = 990, new quote = k / 990 ≈ 101,010.10
+        let fill = calculate_vamm_fill(base_reserve, quote_reserve, 10_000_000_000).unwrap();
+        assert_eq!(fill.new_base_reserve, 990_000_000_000);
+        assert_eq!(fill.new_quote_reserve, 101_010_101_010_102); // rounded up
+        assert_eq!(fill.quote_amount, 1_010_101_010_102);
+        assert!(fill.fill_price > 100_000_000_000);
+
+        // Spot moves up after the buy
+        let new_price = calculate_vamm_price(fill.new_base_reserve, fill.new_quote_reserve).unwrap();
+        assert!(new_price > fill.fill_price);
+
+        // Slippage ≈ 1%
+        let slippage = calculate_slippage(100_000_000_000, fill.fill_price).unwrap();
+        assert_eq!(slippage, 10_101_010);
+    }
+
+    #[test]
+    fn test_vamm_short_fill_receives_below_spot() {
+        let base_reserve = 1_000_000_000_000;
+        let quote_reserve = 100_000_000_000_000;
+
+        let fill = calculate_vamm_fill(base_reserve, quote_reserve, -10_000_000_000).unwrap();
+        assert_eq!(fill.new_base_reserve, 1_010_000_000_000);
+        assert!(fill.fill_price < 100_000_000_000);
+
+        // Buying back the same size costs more than the short received
+        let unwind = calculate_vamm_fill(fill.new_base_reserve, fill.new_quote_reserve, 10_000_000_000).unwrap();
+        assert_eq!(unwind.new_base_reserve, base_reserve);
+        assert!(unwind.quote_amount >= fill.quote_amount);
+    }
+
+    #[test]
+    fn test_vamm_rejects_draining_base_reserve() {
+        let result = calculate_vamm_fill(1_000_000_000, 100_000_000_000, 1_000_000_000);
+        assert!(result.is_err());
+        assert!(calculate_vamm_fill(1_000_000_000, 100_000_000_000, 0).is_err());
+    }
+
+    #[test]
+    fn test_price_impact_grows_with_size_squared() {
+        use crate::{apply_price_impact, MAX_PRICE_IMPACT};
+
+        let depth = 1_000_000_000_000; // 1,000 units
+        let fill = |base_delta: i64| {
+            let fill = calculate_vamm_fill(1_000_000_000_000_000, 100_000_000_000_000_000, base_delta).unwrap();
+            (fill.fill_price, apply_price_impact(fill, base_delta, depth).unwrap())
+        };
+
+        // 10 units: (10 / 1,000)^2 = 0.01% on top of the curve price
+        let (curve_price, small) = fill(10_000_000_000);
+        assert_eq!(small.price_impact, 100_000);
+        assert_eq!(small.fill_price, curve_price + curve_price / 10_000);
+
+        // 10x the size pays 100x the impact; shorts receive less
+        let (curve_price, large) = fill(-100_000_000_000);
+        assert_eq!(large.price_impact, 10_000_000);
+        assert_eq!(large.fill_price, curve_price - curve_price / 100);
+
+        // Capped, and a zero depth charges nothing
+        assert_eq!(fill(900_000_000_000).1.price_impact, MAX_PRICE_IMPACT);
+        let curve = calculate_vamm_fill(1_000_000_000_000_000, 100_000_000_000_000_000, 10_000_000_000).unwrap();
+        assert_eq!(apply_price_impact(curve.clone(), 10_000_000_000, 0).unwrap(), curve);
+    }
+
+    #[test]
+    fn test_repeg_is_limited_by_fee_pool() {
+        use crate::{calculate_repeg_cost, calculate_repeg_price};
+
+        // Traders net long 10 units: raising the price from $100 to $110 costs $100
+        assert_eq!(calculate_repeg_cost(10_000_000_000, 100_000_000_000, 110_000_000_000).unwrap(), 100_000_000_000);
+        assert_eq!(calculate_repeg_price(100_000_000_000, 110_000_000_000, 10_000_000_000, 100_000_000_000).unwrap(), 110_000_000_000);
+
+        // With $40 in the fee pool the price only moves $4
+        assert_eq!(calculate_repeg_price(100_000_000_000, 110_000_000_000, 10_000_000_000, 40_000_000_000).unwrap(), 104_000_000_000);
+
+        // Lowering it is a gain for the pool and always goes all the way
+        assert_eq!(calculate_repeg_cost(10_000_000_000, 100_000_000_000, 90_000_000_000).unwrap(), -100_000_000_000);
+        assert_eq!(calculate_repeg_price(100_000_000_000, 90_000_000_000, 10_000_000_000, 0).unwrap(), 90_000_000_000);
+    }
+
+    fn sample_order(side: OrderSide, price: u64) -> Order {
+        Order {
+            order_id: 7,
+            owner: Pubkey::new_unique(),
+            position: Pubkey::new_unique(),
+            side,
+            price,
+            base_amount: 2_000_000_000, // 2 units
+            placed_slot: 100,
+        }
+    }
+
+    #[test]
+    fn test_order_side_and_marketability() {
+        let bid = sample_order(OrderSide::Bid, 100_000_000_000);
+        assert_eq!(bid.base_delta().unwrap(), 2_000_000_000);
+        assert!(bid.is_marketable(99_000_000_000)); // cheaper than the limit
+        assert!(bid.is_marketable(100_000_000_000));
+        assert!(!bid.is_marketable(101_000_000_000));
+
+        let ask = sample_order(OrderSide::Ask, 100_000_000_000);
+        assert_eq!(ask.base_delta().unwrap(), -2_000_000_000);
+        assert!(ask.is_marketable(101_000_000_000)); // richer than the limit
+        assert!(!ask.is_marketable(99_000_000_000));
+
+        // Opposite sides cross once the bid reaches the ask; same-side orders never do
+        assert!(bid.crosses(&ask) && ask.crosses(&bid));
+        assert!(!bid.crosses(&sample_order(OrderSide::Ask, 100_000_000_001)));
+        assert!(!bid.crosses(&sample_order(OrderSide::Bid, 1)));
+        assert_eq!(OrderSide::Bid.opposite(), OrderSide::Ask);
+    }
+
+    #[test]
+    fn test_best_crossed_pair_uses_price_time_priority() {
+        let order = |order_id: u64, side: OrderSide, price: u64| Order { order_id, ..sample_order(side, price) };
+        let mut book = OrderBook {
+            orders: vec![
+                order(0, OrderSide::Bid, 99_000_000_000),
+                order(1, OrderSide::Ask, 101_000_000_000),
+            ],
+            ..OrderBook::default()
+        };
+        assert_eq!(book.best_crossed_pair(), None);
+
+        // A higher bid crosses; among equal asks the older one is matched first
+        book.orders.push(order(2, OrderSide::Ask, 100_000_000_000));
+        book.orders.push(order(3, OrderSide::Ask, 100_000_000_000));
+        book.orders.push(order(4, OrderSide::Bid, 100_500_000_000));
+        assert_eq!(book.best_crossed_pair(), Some((4, 2)));
+
+        // Touching prices cross too
+        book.orders.remove(4);
+        book.orders.push(order(5, OrderSide::Bid, 100_000_000_000));
+        assert_eq!(book.best_crossed_pair(), Some((4, 2)));
+    }
+
+    #[test]
+    fn test_cancel_all_removes_only_the_owners_orders() {
+        let maker = Pubkey::new_unique();
+        let order = |order_id: u64, owner: Pubkey| Order { order_id, owner, ..sample_order(OrderSide::Bid, 1) };
+        let mut book = OrderBook {
+            orders: vec![order(0, maker), order(1, Pubkey::new_unique()), order(2, maker)],
+            ..OrderBook::default()
+        };
+
+        assert_eq!(book.cancel_all(&maker), 2);
+        assert_eq!(book.orders.iter().map(|order| order.order_id).collect::<Vec<_>>(), vec![1]);
+        assert_eq!(book.cancel_all(&maker), 0);
+    }
+
+    #[test]
+    fn test_order_book_fits_allocation() {
+        let book = OrderBook {
+            market: Pubkey::new_unique(),
+            next_order_id: MAX_ORDERS as u64,
+            orders: (0..MAX_ORDERS).map(|_| sample_order(OrderSide::Bid, 1)).collect(),
+            bump: 254,
+        };
+        let data = book.try_to_vec().unwrap();
+        assert_eq!(data.len(), OrderBook::LEN);
+
+        // A shrunk book still loads from the full-size allocation
+        let mut account_data = vec![0u8; OrderBook::SPACE];
+        let small_book = OrderBook { orders: vec![sample_order(OrderSide::Ask, 5)], ..book };
+        store_account(&small_book, &mut account_data).unwrap();
+        let loaded = OrderBook::load(&account_data).unwrap();
+        assert_eq!(loaded.orders.len(), 1);
+        assert_eq!(loaded.orders[0].side, OrderSide::Ask);
+    }
+
+    fn sample_trigger(kind: TriggerKind, trigger_price: u64, base_amount: u64) -> TriggerOrder {
+        TriggerOrder {
+            owner: Pubkey::new_unique(),
+            position: Pubkey::new_unique(),
+            market: Pubkey::new_unique(),
+            trigger_id: 1,
+            kind,
+            trigger_price,
+            base_amount,
+            bump: 255,
+            linked_trigger: Pubkey::default(),
+        }
+    }
+
+    #[test]
+    fn test_linked_trigger_fits_its_allocation() {
+        let mut trigger = sample_trigger(TriggerKind::StopLoss, 90_000_000_000, 0);
+        assert!(!trigger.is_linked());
+
+        trigger.linked_trigger = Pubkey::new_unique();
+        assert!(trigger.is_linked());
+        assert_eq!(trigger.try_to_vec().unwrap().len(), TriggerOrder::LEN);
+    }
+
+    #[test]
+    fn test_stop_loss_trigger_direction() {
+        let stop = sample_trigger(TriggerKind::StopLoss, 90_000_000_000, 0);
+
+        // Long stops out when price falls to the trigger
+        assert!(stop.is_triggered(1_000_000_000, 89_000_000_000));
+        assert!(!stop.is_triggered(1_000_000_000, 95_000_000_000));
+
+        // Short stops out when price rises to the trigger
+        assert!(stop.is_triggered(-1_000_000_000, 91_000_000_000));
+        assert!(!stop.is_triggered(-1_000_000_000, 85_000_000_000));
+
+        // Flat positions never trigger
+        assert!(!stop.is_triggered(0, 1));
+    }
+
+    #[test]
+    fn test_take_profit_trigger_direction() {
+        let take_profit = sample_trigger(TriggerKind::TakeProfit, 110_000_000_000, 0);
+
+        assert!(take_profit.is_triggered(1_000_000_000, 110_000_000_000));
+        assert!(!take_profit.is_triggered(1_000_000_000, 105_000_000_000));
+        assert!(take_profit.is_triggered(-1_000_000_000, 100_000_000_000));
+        assert!(!take_profit.is_triggered(-1_000_000_000, 120_000_000_000));
+    }
+
+    #[test]
+    fn test_trigger_reduce_delta() {
+        // Full close of a long
+        let full = sample_trigger(TriggerKind::StopLoss, 1, 0);
+        assert_eq!(full.reduce_delta(3_000_000_000).unwrap(), -3_000_000_000);
+
+        // Partial reduce of a short
+        let partial = sample_trigger(TriggerKind::TakeProfit, 1, 1_000_000_000);
+        assert_eq!(partial.reduce_delta(-3_000_000_000).unwrap(), 1_000_000_000);
+
+        // Reduce size is capped at the position size
+        let oversized = sample_trigger(TriggerKind::StopLoss, 1, 5_000_000_000);
+        assert_eq!(oversized.reduce_delta(2_000_000_000).unwrap(), -2_000_000_000);
+    }
+
+    #[test]
+    fn test_trigger_queue_stays_sorted_and_fits_allocation() {
+        let queued = |trigger_price: u64| QueuedTrigger {
+            trigger: Pubkey::new_unique(),
+            position: Pubkey::new_unique(),
+            kind: TriggerKind::StopLoss,
+            trigger_price,
+        };
+        let mut queue = TriggerQueue { market: Pubkey::new_unique(), entries: Vec::new(), bump: 254 };
+        let (low, high, tie) = (queued(90), queued(110), queued(90));
+        for entry in [high.clone(), low.clone(), queued(100), tie.clone()] {
+            queue.insert(entry).unwrap();
+        }
+
+        // Sorted by price; equal prices keep placement order
+        let prices: Vec<u64> = queue.entries.iter().map(|entry| entry.trigger_price).collect();
+        assert_eq!(prices, [90, 90, 100, 110]);
+        assert_eq!((&queue.entries[0], &queue.entries[1]), (&low, &tie));
+
+        assert!(queue.remove(&high.trigger));
+        assert!(!queue.remove(&high.trigger));
+        assert_eq!(queue.entries.len(), 3);
+
+        // A full queue serializes to exactly LEN and rejects further entries
+        while queue.entries.len() < MAX_QUEUED_TRIGGERS {
+            queue.insert(queued(u64::MAX)).unwrap();
+        }
+        assert_eq!(queue.try_to_vec().unwrap().len(), TriggerQueue::LEN);
+        assert!(queue.insert(queued(1)).is_err());
+    }
+
+    #[test]
+    fn test_reduce_only_changes() {
+        // Partial and full reductions are allowed on both sides
+        assert!(is_reducing_change(3_000_000_000, -1_000_000_000));
+        assert!(is_reducing_change(3_000_000_000, -3_000_000_000));
+        assert!(is_reducing_change(-3_000_000_000, 2_000_000_000));
+
+        // Collateral-only changes don't touch the size
+        assert!(is_reducing_change(3_000_000_000, 0));
+
+        // Increases, flips and opening from flat are rejected
+        assert!(!is_reducing_change(3_000_000_000, 1_000_000_000));
+        assert!(!is_reducing_change(3_000_000_000, -4_000_000_000));
+        assert!(!is_reducing_change(-3_000_000_000, -1));
+        assert!(!is_reducing_change(0, 1_000_000_000));
+    }
+
+    #[test]
+    fn test_growing_changes() {
+        // Opening from flat on either side, or adding to the position's side
+        assert!(crate::is_growing_change(0, -1_000_000_000));
+        assert!(crate::is_growing_change(3_000_000_000, 1_000_000_000));
+        assert!(crate::is_growing_change(-3_000_000_000, -1));
+
+        // Size-less changes, reductions and flips are not increases
+        assert!(!crate::is_growing_change(3_000_000_000, 0));
+        assert!(!crate::is_growing_change(3_000_000_000, -1_000_000_000));
+        assert!(!crate::is_growing_change(-3_000_000_000, 4_000_000_000));
+    }
+
+    #[test]
+    fn test_leverage_calculation() {
+        let position = Position {
+            owner: Pubkey::new_unique(),
+            base_amount: -5_000_000_000, // 5 units short
+            collateral: 100_000_000_000, // 100 units collateral
+            entry_price: 100_000_000_000,
+            ..Default::default()
+        };
+
+        // $500 notional on $100 collateral = 5x
+        let leverage = calculate_leverage(&position, 100_000_000_000).unwrap();
+        assert_eq!(leverage, 5_000_000_000);
+
+        // No collateral = unbounded leverage, no exposure = none
+        let no_collateral = Position { collateral: 0, ..position };
+        assert_eq!(calculate_leverage(&no_collateral, 100_000_000_000).unwrap(), u64::MAX);
+        let flat = Position { base_amount: 0, ..position };
+        assert_eq!(calculate_leverage(&flat, 100_000_000_000).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_open_interest_cap() {
+        let cap = 10_000_000_000; // 10 units
+
+        assert!(!exceeds_open_interest_cap(5_000_000_000, 10_000_000_000, cap)); // up to the cap
+        assert!(exceeds_open_interest_cap(5_000_000_000, 10_000_000_001, cap)); // past the cap
+
+        // Reductions are allowed even while above a lowered cap
+        assert!(!exceeds_open_interest_cap(15_000_000_000, 12_000_000_000, cap));
+        assert!(exceeds_open_interest_cap(15_000_000_000, 16_000_000_000, cap));
+    }
+
+    #[test]
+    fn test_user_notional_cap() {
+        // Up to the cap, and any size without one
+        assert!(crate::validate_user_notional(100_000_000_000, 100_000_000_000).is_ok());
+        assert!(crate::validate_user_notional(100_000_000_001, 100_000_000_000).is_err());
+        assert!(crate::validate_user_notional(u64::MAX, 0).is_ok());
+    }
+
+    #[test]
+    fn test_collateral_ratio_counts_unrealized_pnl_and_funding() {
+        let min_collateral_ratio = 100_000_000; // 10%
+        let market = |mark_price: u64| MarketState { mark_price, ..Default::default() };
+        // 1 unit long from 100 with 10 of collateral: exactly 10% at entry
+        let position = Position {
+            base_amount: 1_000_000_000,
+            collateral: 10_000_000_000,
+            entry_price: 100_000_000_000,
+            ..Default::default()
+        };
+        assert!(crate::validate_collateral_ratio(&position, &market(100_000_000_000), min_collateral_ratio).is_ok());
+
+        // At 95 the bare collateral would still cover 10% of the notional, but half of it is lost
+        assert!(crate::validate_collateral_ratio(&position, &market(95_000_000_000), min_collateral_ratio).is_err());
+        assert!(crate::validate_collateral_ratio(&position, &market(105_000_000_000), min_collateral_ratio).is_ok());
+
+        // Funding still owed comes off the collateral; funding still to be received only adds to
+        // it when it compounds, as it's otherwise paid out as claimable funding
+        let owing = Position { pending_funding: 1_000_000_000, ..position };
+        assert!(crate::validate_collateral_ratio(&owing, &market(100_000_000_000), min_collateral_ratio).is_err());
+        let receiving = Position { collateral: 9_000_000_000, pending_funding: -1_000_000_000, ..position };
+        assert!(crate::validate_collateral_ratio(&receiving, &market(100_000_000_000), min_collateral_ratio).is_err());
+        let compounding = Position { auto_compound_funding: 1, ..receiving };
+        assert!(crate::validate_collateral_ratio(&compounding, &market(100_000_000_000), min_collateral_ratio).is_ok());
+    }
+
+    #[test]
+    fn test_config_serialized_size() {
+        let config = Config {
+            admin: Pubkey::new_unique(),
+            pending_admin: Pubkey::new_unique(),
+            min_collateral_ratio: 1_500_000_000,
+            liquidator_fee_bps: 100,
+            trading_fee: 1_000_000,
+            referral_fee_share: 200_000_000,
+            max_funding_rate_per_slot: 50_000,
+            bump: 255,
+            collateral_assets: vec![CollateralAsset::default(); MAX_COLLATERAL_ASSETS],
+            token_program: TOKEN_2022_PROGRAM_ID,
+            quote_mint: Pubkey::new_unique(),
+            funding_crank_reward: 10_000,
+            max_funding_crank_reward: 100_000,
+            insurance_fund_share: 250_000_000,
+            min_position_size: 1_000_000,
+            dust_collateral: 1_000_000,
+            vault_bump: 254,
+            max_funding_settlement_share: 100_000_000,
+            maker_rebate: 200_000,
+            fee_tiers: vec![FeeTier::default(); MAX_FEE_TIERS],
+            stake_mint: Pubkey::new_unique(),
+            stake_vault: Pubkey::new_unique(),
+            stake_discounts: vec![StakeDiscount::default(); MAX_STAKE_DISCOUNTS],
+            max_positions_per_user: 4,
+            max_user_notional: 1_000_000_000_000_000,
+            restrict_liquidators: true,
+            treasury: Pubkey::new_unique(),
+            market_count: 3,
+            settlement_only: true,
+        };
+        assert_eq!(config.try_to_vec().unwrap().len(), Config::LEN);
+
+        // A config written before collateral assets existed decodes with an empty list once grown
+        let mut data = vec![0u8; Config::SPACE];
+        store_account(&Config { collateral_assets: Vec::new(), ..config }, &mut data).unwrap();
+        assert!(crate::load_account::<Config>(&data).unwrap().collateral_assets.is_empty());
+    }
+
+    #[test]
+    fn test_market_pause_flag_round_trip() {
+        let market_state = MarketState::default();
+        assert!(!market_state.is_paused());
+
+        let mut buffer = [0u64; MarketState::SPACE / 8];
+        let data: &mut [u8] = bytemuck::cast_slice_mut(&mut buffer);
+        *MarketState::init(data).unwrap() = MarketState {
+            paused: 1,
+            version: MarketState::VERSION,
+            ..Default::default()
+        };
+        let decoded = MarketState::load(data).unwrap();
+        assert!(decoded.is_paused());
+    }
+
+    #[test]
+    fn test_zero_copy_position_in_place() {
+        // u64-backed buffer so the account data is 8-byte aligned like on-chain
+        let mut buffer = [0u64; Position::SPACE / 8];
+        let data: &mut [u8] = bytemuck::cast_slice_mut(&mut buffer);
+
+        // Untagged data is rejected until the account is initialized
+        assert!(Position::load_mut(data).is_err());
+        let position = Position::init(data).unwrap();
+        position.version = Position::VERSION;
+        position.base_amount = -5_000_000_000;
+        position.collateral = 42;
+
+        let position = Position::load(data).unwrap();
+        assert_eq!(position.base_amount, -5_000_000_000);
+        assert_eq!(position.collateral, 42);
+        assert_eq!(&data[..8], b"position");
+        assert_eq!(i64::from_le_bytes(data[40..48].try_into().unwrap()), -5_000_000_000);
+    }
+
+    #[test]
+    fn test_zero_copy_rejects_short_data() {
+        let mut buffer = [0u64; MarketState::SPACE / 8];
+        let data: &mut [u8] = bytemuck::cast_slice_mut(&mut buffer);
+        MarketState::init(data).unwrap().version = MarketState::VERSION;
+
+        assert!(MarketState::load(data).is_ok());
+        assert!(MarketState::load(&data[..MarketState::SPACE - 1]).is_err());
+    }
+
+    #[test]
+    fn test_account_discriminators_reject_other_types() {
+        let mut buffer = [0u64; MarketState::SPACE / 8];
+        let data: &mut [u8] = bytemuck::cast_slice_mut(&mut buffer);
+        MarketState::init(data).unwrap();
+
+        // The buffer is large enough for a position, so only the tag can reject it
+        assert!(Position::load(data).is_err());
+        assert!(crate::load_account::<Referrer>(data).is_err());
+
+        let mut referrer_data = vec![0u8; Referrer::SPACE];
+        store_account(&Referrer::default(), &mut referrer_data).unwrap();
+        assert!(crate::load_account::<Referrer>(&referrer_data).is_ok());
+        assert!(crate::load_account::<Config>(&referrer_data).is_err());
+
+        // Every account type has its own tag
+        let tags = [
+            Position::DISCRIMINATOR,
+            MarketState::DISCRIMINATOR,
+            Referrer::DISCRIMINATOR,
+            Config::DISCRIMINATOR,
+            OrderBook::DISCRIMINATOR,
+            TriggerOrder::DISCRIMINATOR,
+            TriggerQueue::DISCRIMINATOR,
+            UserAccount::DISCRIMINATOR,
+            PoolState::DISCRIMINATOR,
+            WithdrawalRequest::DISCRIMINATOR,
+            crate::user_stats::UserStats::DISCRIMINATOR,
+            crate::rewards::RewardsSchedule::DISCRIMINATOR,
+            crate::rewards::UserRewards::DISCRIMINATOR,
+            crate::staking::StakeAccount::DISCRIMINATOR,
+            crate::access::WhitelistEntry::DISCRIMINATOR,
+            crate::access::LiquidatorRegistry::DISCRIMINATOR,
+            crate::access::LeverageOverride::DISCRIMINATOR,
+            PriceFeed::DISCRIMINATOR,
+            crate::market_metrics::MarketMetrics::DISCRIMINATOR,
+            crate::funding_history::FundingHistory::DISCRIMINATOR,
+            crate::price_history::PriceHistory::DISCRIMINATOR,
+        ];
+        for (i, tag) in tags.iter().enumerate() {
+            assert!(tags[i + 1..].iter().all(|other| other != tag));
+        }
+    }
+
+    #[test]
+    fn test_account_lens_match_serialized_sizes() {
+        use crate::access::{LeverageOverride, LiquidatorRegistry, WhitelistEntry, MAX_LIQUIDATORS};
+        use crate::market_metrics::MarketMetrics;
+
+        assert_eq!(Referrer::default().try_to_vec().unwrap().len(), Referrer::LEN);
+        assert_eq!(PriceFeed::default().try_to_vec().unwrap().len(), PriceFeed::LEN);
+        assert_eq!(WhitelistEntry::default().try_to_vec().unwrap().len(), WhitelistEntry::LEN);
+        assert_eq!(LeverageOverride::default().try_to_vec().unwrap().len(), LeverageOverride::LEN);
+        assert_eq!(MarketMetrics::default().try_to_vec().unwrap().len(), MarketMetrics::LEN);
+        let registry = LiquidatorRegistry { liquidators: vec![Pubkey::new_unique(); MAX_LIQUIDATORS], bump: 255 };
+        assert_eq!(registry.try_to_vec().unwrap().len(), LiquidatorRegistry::LEN);
+    }
+
+    #[test]
+    fn test_accounts_shorter_than_their_layout_are_rejected() {
+        use solana_program::program_error::ProgramError;
+
+        let mut referrer_data = vec![0u8; Referrer::SPACE];
+        store_account(&Referrer::default(), &mut referrer_data).unwrap();
+        referrer_data.pop();
+        assert!(matches!(crate::load_account::<Referrer>(&referrer_data), Err(ProgramError::AccountDataTooSmall)));
+        assert_eq!(store_account(&Referrer::default(), &mut referrer_data), Err(ProgramError::AccountDataTooSmall));
+
+        // An older, shorter position is refused until migrate_account grows it
+        let mut buffer = [0u64; Position::SPACE / 8];
+        let data: &mut [u8] = bytemuck::cast_slice_mut(&mut buffer);
+        Position::init(data).unwrap();
+        assert_eq!(Position::load(&data[..Position::SPACE - 8]).err(), Some(ProgramError::AccountDataTooSmall));
+        assert_eq!(Position::init(&mut data[..Position::SPACE - 8]).err(), Some(ProgramError::AccountDataTooSmall));
+    }
+
+    #[test]
+    fn test_outdated_layout_rejected_until_migrated() {
+        let mut buffer = [0u64; Position::SPACE / 8];
+        let data: &mut [u8] = bytemuck::cast_slice_mut(&mut buffer);
+
+        // A tagged account written before versioning reads as v0
+        Position::init(data).unwrap();
+        assert!(Position::load(data).is_err());
+        assert!(Position::load_mut(data).is_err());
+
+        crate::load_pod_mut::<Position>(data).unwrap().version = Position::VERSION;
+        assert_eq!(Position::load(data).unwrap().version, Position::VERSION);
+
+        // The pre-versioning layout was 8 bytes shorter and cannot be borrowed at all
+        assert!(Position::load(&data[..Position::SPACE - 8]).is_err());
+    }
+
+    #[test]
+    fn test_view_instructions_load_accounts() {
+        use solana_program::account_info::AccountInfo;
+
+        let program_id = Pubkey::new_unique();
+        let other_program = Pubkey::new_unique();
+
+        let mut position_buffer = [0u64; Position::SPACE / 8];
+        let position_data: &mut [u8] = bytemuck::cast_slice_mut(&mut position_buffer);
+        *Position::init(position_data).unwrap() = Position {
+            base_amount: 1_000_000_000,
+            collateral: 150_000_000_000,
+            entry_price: 100_000_000_000,
+            version: Position::VERSION,
+            ..Default::default()
+        };
+
+        let mut market_buffer = [0u64; MarketState::SPACE / 8];
+        let market_data: &mut [u8] = bytemuck::cast_slice_mut(&mut market_buffer);
+        *MarketState::init(market_data).unwrap() = MarketState {
+            mark_price: 110_000_000_000,
+            version: MarketState::VERSION,
+            ..Default::default()
+        };
+
+        let (position_key, market_key) = (Pubkey::new_unique(), Pubkey::new_unique());
+        let (mut position_lamports, mut market_lamports) = (0u64, 0u64);
+        let position_acc = AccountInfo::new(
+            &position_key, false, false, &mut position_lamports, position_data, &program_id, false, 0,
+        );
+        let market_acc = AccountInfo::new(
+            &market_key, false, false, &mut market_lamports, market_data, &program_id, false, 0,
+        );
+        let accounts = [position_acc, market_acc];
+
+        assert!(crate::views::get_position_health(&program_id, &accounts).is_ok());
+        assert!(crate::views::get_unrealized_pnl(&program_id, &accounts).is_ok());
+        assert_eq!(
+            crate::views::get_position_health(&other_program, &accounts),
+            Err(solana_program::program_error::ProgramError::IncorrectProgramId)
+        );
+    }
+
+    #[test]
+    fn test_close_position_account_requires_empty_position() {
+        use solana_program::account_info::AccountInfo;
+        use solana_program::program_error::ProgramError;
+
+        let program_id = Pubkey::new_unique();
+        let owner_key = Pubkey::new_unique();
+
+        let mut position_buffer = [0u64; Position::SPACE / 8];
+        let position_data: &mut [u8] = bytemuck::cast_slice_mut(&mut position_buffer);
+        *Position::init(position_data).unwrap() = Position {
+            owner: owner_key,
+            collateral: 1,
+            version: Position::VERSION,
+            ..Default::default()
+        };
+
+        let position_key = Pubkey::new_unique();
+        let (mut owner_lamports, mut position_lamports) = (0u64, 1_000_000u64);
+        let mut owner_data = [0u8; 0];
+        let system_id = Pubkey::default();
+        let owner_acc = AccountInfo::new(
+            &owner_key, true, true, &mut owner_lamports, &mut owner_data, &system_id, false, 0,
+        );
+        let position_acc = AccountInfo::new(
+            &position_key, false, true, &mut position_lamports, position_data, &program_id, false, 0,
+        );
+
+        // Leftover collateral keeps the account alive and its lamports untouched
+        assert_eq!(
+            crate::close_position_account(&program_id, &[owner_acc.clone(), position_acc.clone()]),
+            Err(ProgramError::InvalidArgument)
+        );
+        assert_eq!(position_acc.lamports(), 1_000_000);
+
+        // So does funding still owed past the settlement cap
+        {
+            let mut position_data = position_acc.data.borrow_mut();
+            let position = Position::load_mut(&mut position_data).unwrap();
+            position.collateral = 0;
+            position.pending_funding = 5;
+        }
+        assert_eq!(
+            crate::close_position_account(&program_id, &[owner_acc, position_acc.clone()]),
+            Err(ProgramError::InvalidArgument)
+        );
+        assert_eq!(position_acc.lamports(), 1_000_000);
+    }
+
+    #[test]
+    fn test_asset_collateral_value() {
+        // 2 SOL (9 decimals) at $150 with a 20% haircut = $240
+        let value = calculate_asset_collateral_value(2_000_000_000, 9, 150_000_000_000, 800_000_000).unwrap();
+        assert_eq!(value, 240_000_000_000);
+
+        // 1.5 tokens of a 6-decimal mint at $1 with no haircut
+        let value = calculate_asset_collateral_value(1_500_000, 6, 1_000_000_000, 1_000_000_000).unwrap();
+        assert_eq!(value, 1_500_000_000);
+
+        // Zero weight means the asset doesn't count at all
+        assert_eq!(calculate_asset_collateral_value(1_000_000, 6, 1_000_000_000, 0).unwrap(), 0);
+    }
+
+    fn sample_pyth_account(price: i64, expo: i32, status: u32, publish_slot: u64) -> Vec<u8> {
+        let mut data = vec![0u8; 240];
+        data[0..4].copy_from_slice(&0xa1b2_c3d4u32.to_le_bytes());
+        data[4..8].copy_from_slice(&2u32.to_le_bytes());
+        data[8..12].copy_from_slice(&3u32.to_le_bytes());
+        data[20..24].copy_from_slice(&expo.to_le_bytes());
+        data[208..216].copy_from_slice(&price.to_le_bytes());
+        data[216..224].copy_from_slice(&5_000_000u64.to_le_bytes());
+        data[224..228].copy_from_slice(&status.to_le_bytes());
+        data[232..240].copy_from_slice(&publish_slot.to_le_bytes());
+        data
+    }
+
+    #[test]
+    fn test_parse_pyth_price() {
+        // $150.25 with expo -8 rescales to 1e9 precision
+        let data = sample_pyth_account(15_025_000_000, -8, 1, 1_000);
+        let price = parse_pyth_price(&data, 1_010).unwrap();
+        assert_eq!(price.price, 150_250_000_000);
+        assert_eq!(price.conf, 50_000_000);
+
+        // Stale, halted, negative and non-Pyth accounts are rejected
+        assert!(parse_pyth_price(&data, 1_000 + MAX_ORACLE_STALENESS_SLOTS + 1).is_err());
+        assert!(parse_pyth_price(&sample_pyth_account(15_025_000_000, -8, 2, 1_000), 1_000).is_err());
+        assert!(parse_pyth_price(&sample_pyth_account(-1, -8, 1, 1_000), 1_000).is_err());
+        assert!(parse_pyth_price(&[0u8; 240], 1_000).is_err());
+
+        assert_eq!(scale_to_precision(3, 2).unwrap(), 300_000_000_000);
+        assert_eq!(scale_to_precision(123_456_789_012, -12).unwrap(), 123_456_789);
+    }
+
+    #[test]
+    fn test_parse_oracle_price_reads_pushed_feeds() {
+        let mut data = vec![0u8; PriceFeed::SPACE];
+        let mut feed = PriceFeed { feed_id: 7, ..Default::default() };
+        store_account(&feed, &mut data).unwrap();
+
+        // A feed never pushed to has no price
+        assert!(parse_oracle_price(&data, 1_000).is_err());
+
+        feed.price = 42_000_000_000;
+        feed.conf = 10_000_000;
+        feed.publish_slot = 1_000;
+        store_account(&feed, &mut data).unwrap();
+        let price = parse_oracle_price(&data, 1_010).unwrap();
+        assert_eq!(price, OraclePrice { price: 42_000_000_000, conf: 10_000_000, publish_slot: 1_000 });
+        assert!(parse_oracle_price(&data, 1_000 + MAX_ORACLE_STALENESS_SLOTS + 1).is_err());
+
+        // Pyth accounts still go through the Pyth parser
+        let pyth = sample_pyth_account(15_025_000_000, -8, 1, 1_000);
+        assert_eq!(parse_oracle_price(&pyth, 1_010).unwrap().price, 150_250_000_000);
+    }
+
+    #[test]
+    fn test_transfer_checked_instruction_layout() {
+        let (source, mint, destination, authority) =
+            (Pubkey::new_unique(), Pubkey::new_unique(), Pubkey::new_unique(), Pubkey::new_unique());
+        let ix = crate::create_transfer_checked_instruction(
+            &TOKEN_2022_PROGRAM_ID, &source, &mint, &destination, &authority, 1_500_000, 6,
+        ).unwrap();
+
+        assert_eq!(ix.program_id, TOKEN_2022_PROGRAM_ID);
+        assert_eq!(ix.data[0], 12);
+        assert_eq!(u64::from_le_bytes(ix.data[1..9].try_into().unwrap()), 1_500_000);
+        assert_eq!(ix.data[9], 6);
+
+        let keys: Vec<Pubkey> = ix.accounts.iter().map(|meta| meta.pubkey).collect();
+        assert_eq!(keys, vec![source, mint, destination, authority]);
+        assert!(!ix.accounts[1].is_writable);
+        assert!(ix.accounts[3].is_signer);
+    }
+
+    #[test]
+    fn test_lp_mint_instruction_layouts() {
+        let (mint, account, authority) = (Pubkey::new_unique(), Pubkey::new_unique(), Pubkey::new_unique());
+
+        let ix = crate::create_initialize_mint2_instruction(&SPL_TOKEN_PROGRAM_ID, &mint, &authority, 6);
+        assert_eq!(ix.data[..2], [20, 6]);
+        assert_eq!(ix.data[2..34], *authority.as_ref());
+        assert_eq!(ix.data[34], 0);
+        assert_eq!(ix.data.len(), 35);
+
+        let ix = crate::create_mint_to_checked_instruction(&SPL_TOKEN_PROGRAM_ID, &mint, &account, &authority, 42, 6);
+        assert_eq!((ix.data[0], ix.data[9]), (14, 6));
+        assert_eq!(u64::from_le_bytes(ix.data[1..9].try_into().unwrap()), 42);
+        let keys: Vec<Pubkey> = ix.accounts.iter().map(|meta| meta.pubkey).collect();
+        assert_eq!(keys, vec![mint, account, authority]);
+        assert!(ix.accounts[2].is_signer);
+
+        let ix = crate::create_burn_checked_instruction(&SPL_TOKEN_PROGRAM_ID, &account, &mint, &authority, 42, 6);
+        assert_eq!((ix.data[0], ix.data[9]), (15, 6));
+        let keys: Vec<Pubkey> = ix.accounts.iter().map(|meta| meta.pubkey).collect();
+        assert_eq!(keys, vec![account, mint, authority]);
+        assert!(ix.accounts[2].is_signer);
+    }
+
+    #[test]
+    fn test_quote_mint_must_match_configured_token_program() {
+        use solana_program::account_info::AccountInfo;
+        use solana_program::program_error::ProgramError;
+
+        let quote_mint = Pubkey::new_unique();
+        let config = Config {
+            token_program: TOKEN_2022_PROGRAM_ID,
+            quote_mint,
+            ..Default::default()
+        };
+
+        let mut mint_data = [0u8; 82];
+        mint_data[44] = 6;
+        let mut mint_lamports = 0u64;
+        let mint_acc = AccountInfo::new(
+            &quote_mint, false, false, &mut mint_lamports, &mut mint_data, &TOKEN_2022_PROGRAM_ID, false, 0,
+        );
+
+        let (mut token_2022_lamports, mut legacy_lamports) = (0u64, 0u64);
+        let (mut token_2022_data, mut legacy_data) = ([0u8; 0], [0u8; 0]);
+        let loader = Pubkey::default();
+        let token_2022 = AccountInfo::new(
+            &TOKEN_2022_PROGRAM_ID, false, false, &mut token_2022_lamports, &mut token_2022_data, &loader, true, 0,
+        );
+        let legacy = AccountInfo::new(
+            &SPL_TOKEN_PROGRAM_ID, false, false, &mut legacy_lamports, &mut legacy_data, &loader, true, 0,
+        );
+
+        assert_eq!(crate::validate_quote_mint(&config, &token_2022, &mint_acc), Ok(6));
+        assert_eq!(
+            crate::validate_quote_mint(&config, &legacy, &mint_acc),
+            Err(ProgramError::IncorrectProgramId)
+        );
+
+        // Configs migrated from before the quote mint was recorded must set it first
+        let unset = Config { quote_mint: Pubkey::default(), ..config };
+        assert_eq!(
+            crate::validate_quote_mint(&unset, &token_2022, &mint_acc),
+            Err(ProgramError::UninitializedAccount)
+        );
+    }
+
+    #[test]
+    fn test_token_accounts_must_match_mint_and_authority() {
+        use solana_program::account_info::AccountInfo;
+        use solana_program::program_error::ProgramError;
+
+        let program_id = Pubkey::new_unique();
+        let (vault_key, vault_bump) = Pubkey::find_program_address(&[crate::PDA_SEED], &program_id);
+        let (quote_mint, other_mint) = (Pubkey::new_unique(), Pubkey::new_unique());
+
+        // Token account layout: mint at 0..32, owner at 32..64, state at 108
+        let token_account = |mint: &Pubkey, owner: &Pubkey| {
+            let mut data = [0u8; 165];
+            data[0..32].copy_from_slice(mint.as_ref());
+            data[32..64].copy_from_slice(owner.as_ref());
+            data[108] = 1;
+            data
+        };
+
+        let loader = Pubkey::default();
+        let (mut l0, mut l1, mut l2, mut l3) = (0u64, 0u64, 0u64, 0u64);
+        let mut program_data = [0u8; 0];
+        let token_program = AccountInfo::new(
+            &SPL_TOKEN_PROGRAM_ID, false, false, &mut l0, &mut program_data, &loader, true, 0,
+        );
+        let mut vault_data = token_account(&quote_mint, &vault_key);
+        let vault = AccountInfo::new(&vault_key, false, true, &mut l1, &mut vault_data, &SPL_TOKEN_PROGRAM_ID, false, 0);
+
+        assert!(crate::validate_vault(&program_id, &token_program, &vault, vault_bump, &quote_mint).is_ok());
+        assert_eq!(
+            crate::validate_vault(&program_id, &token_program, &vault, vault_bump, &other_mint),
+            Err(ProgramError::InvalidAccountData)
+        );
+
+        // A vault at the PDA address but owned by someone else is not the program's vault
+        let user_key = Pubkey::new_unique();
+        let mut stolen_data = token_account(&quote_mint, &user_key);
+        let stolen = AccountInfo::new(&vault_key, false, true, &mut l2, &mut stolen_data, &SPL_TOKEN_PROGRAM_ID, false, 0);
+        assert_eq!(
+            crate::validate_vault(&program_id, &token_program, &stolen, vault_bump, &quote_mint),
+            Err(ProgramError::IllegalOwner)
+        );
+
+        // User accounts must live under the token program and be initialized
+        let user_token_key = Pubkey::new_unique();
+        let mut user_data = token_account(&quote_mint, &user_key);
+        user_data[108] = 0;
+        let user_token = AccountInfo::new(&user_token_key, false, true, &mut l3, &mut user_data, &TOKEN_2022_PROGRAM_ID, false, 0);
+        assert_eq!(
+            crate::validate_token_account(&SPL_TOKEN_PROGRAM_ID, &user_token, &quote_mint, None),
+            Err(ProgramError::IncorrectProgramId)
+        );
+        assert_eq!(
+            crate::validate_token_account(&TOKEN_2022_PROGRAM_ID, &user_token, &quote_mint, None),
+            Err(ProgramError::InvalidAccountData)
+        );
+    }
+
+    #[test]
+    fn test_native_sol_deposit_requires_wsol_asset() {
+        use solana_program::account_info::AccountInfo;
+        use solana_program::program_error::ProgramError;
+
+        let program_id = Pubkey::new_unique();
+        let (config_key, bump) = Pubkey::find_program_address(&[crate::CONFIG_SEED], &program_id);
+        let usdc_vault = Pubkey::new_unique();
+        let config = Config {
+            bump,
+            collateral_assets: vec![CollateralAsset { mint: Pubkey::new_unique(), vault: usdc_vault, ..Default::default() }],
+            ..Default::default()
+        };
+        let mut config_data = vec![0u8; Config::SPACE];
+        store_account(&config, &mut config_data).unwrap();
+
+        let user_key = Pubkey::new_unique();
+        let position_key = Pubkey::new_unique();
+        let system_id = Pubkey::default();
+        let (mut l0, mut l1, mut l2, mut l3, mut l4, mut l5) = (0u64, 0u64, 0u64, 0u64, 0u64, 0u64);
+        let (mut d0, mut d1, mut d2, mut d3, mut d5) = ([0u8; 0], [0u8; 0], [0u8; 165], [0u8; 0], [0u8; 0]);
+        let accounts = [
+            AccountInfo::new(&user_key, true, true, &mut l0, &mut d0, &system_id, false, 0),
+            AccountInfo::new(&SPL_TOKEN_PROGRAM_ID, false, false, &mut l1, &mut d1, &system_id, true, 0),
+            AccountInfo::new(&usdc_vault, false, true, &mut l2, &mut d2, &SPL_TOKEN_PROGRAM_ID, false, 0),
+            AccountInfo::new(&position_key, false, true, &mut l3, &mut d3, &program_id, false, 0),
+            AccountInfo::new(&config_key, false, false, &mut l4, &mut config_data, &program_id, false, 0),
+            AccountInfo::new(&system_id, false, false, &mut l5, &mut d5, &system_id, true, 0),
+        ];
+
+        assert_eq!(
+            crate::deposit_native_sol(&program_id, &accounts, 0, 1_000_000_000),
+            Err(ProgramError::InvalidArgument)
+        );
+    }
+
+    #[test]
+    fn test_user_account_fits_allocation() {
+        let user_account = UserAccount {
+            owner: Pubkey::new_unique(),
+            collateral: 1_000_000_000,
+            positions: vec![CrossPosition::default(); MAX_CROSS_MARGIN_POSITIONS],
+            bump: 255,
+        };
+        assert_eq!(user_account.try_to_vec().unwrap().len(), UserAccount::LEN);
+    }
+
+    #[test]
+    fn test_cross_margin_health_nets_pnl_across_markets() {
+        let market = |mark_price: u64| MarketState { mark_price, ..Default::default() };
+        let long = Position { base_amount: 1_000_000_000, entry_price: 100_000_000_000, ..Default::default() };
+        let short = Position { base_amount: -2_000_000_000, entry_price: 50_000_000_000, ..Default::default() };
+
+        // Long +$10 at $110 and short -$10 at $55 net out: equity 100 over 110 + 110 notional
+        let positions = [(long, market(110_000_000_000)), (short, market(55_000_000_000))];
+        let health = calculate_cross_margin_health(100_000_000_000, &positions).unwrap();
+        assert_eq!(health, 454_545_454);
+
+        // Pending funding owed by the long comes out of equity
+        let funded = [(long, MarketState { funding_index: 10_000_000_000, ..market(100_000_000_000) })];
+        assert_eq!(calculate_cross_margin_health(20_000_000_000, &funded).unwrap(), 100_000_000);
+
+        // Losses beyond the collateral floor at zero; no exposure is perfect health
+        assert_eq!(calculate_cross_margin_health(5_000_000_000, &[(long, market(90_000_000_000))]).unwrap(), 0);
+        assert_eq!(calculate_cross_margin_health(0, &[(Position::default(), market(1))]).unwrap(), u64::MAX);
+    }
+
+    #[test]
+    fn test_risk_tiers_raise_required_ratio_with_size() {
+        let mut tiers = [RiskTier::default(); MAX_RISK_TIERS];
+        tiers[0] = RiskTier { min_notional: 100_000_000_000, min_collateral_ratio: 2_000_000_000 };
+        tiers[1] = RiskTier { min_notional: 1_000_000_000_000, min_collateral_ratio: 3_000_000_000 };
+        let market = MarketState { mark_price: 100_000_000_000, risk_tiers: tiers, ..Default::default() };
+
+        // Below the first tier the config minimum applies; never less than it
+        assert_eq!(market.required_collateral_ratio(99_999_999_999, 1_500_000_000), 1_500_000_000);
+        assert_eq!(market.required_collateral_ratio(100_000_000_000, 1_500_000_000), 2_000_000_000);
+        assert_eq!(market.required_collateral_ratio(5_000_000_000_000, 1_500_000_000), 3_000_000_000);
+        assert_eq!(market.required_collateral_ratio(5_000_000_000_000, 4_000_000_000), 4_000_000_000);
+
+        // Cross margin weights each position's requirement by its notional:
+        // $50 at 150% and $150 at 200% need $225 against $200 of notional
+        let small = Position { base_amount: 500_000_000, ..Default::default() };
+        let large = Position { base_amount: -1_500_000_000, ..Default::default() };
+        let required = calculate_required_collateral_ratio(&[(small, market), (large, market)], 1_500_000_000).unwrap();
+        assert_eq!(required, 1_875_000_000);
+        assert_eq!(calculate_required_collateral_ratio(&[], 1_500_000_000).unwrap(), 1_500_000_000);
+    }
+
+    #[test]
+    fn test_mark_price_ema_decays_by_half_life() {
+        assert_eq!(calculate_ema_decay(0, 100), 1_000_000_000);
+        assert_eq!(calculate_ema_decay(50, 100), 750_000_000); // linear between halvings
+        assert_eq!(calculate_ema_decay(100, 100), 500_000_000);
+        assert_eq!(calculate_ema_decay(250, 100), 187_500_000);
+        assert_eq!(calculate_ema_decay(u64::MAX, 1), 0);
+
+        // The first sample takes the mark; later ones close the gap by the decayed weight
+        let mut market = MarketState { mark_price: 100_000_000_000, ema_half_life_slots: 100, ..Default::default() };
+        market.update_mark_price_ema(10).unwrap();
+        assert_eq!((market.mark_price_ema, market.ema_last_slot), (100_000_000_000, 10));
+        market.mark_price = 80_000_000_000;
+        market.update_mark_price_ema(110).unwrap();
+        assert_eq!(market.mark_price_ema, 90_000_000_000);
+
+        // A wick then barely moves it; liquidations only consult it when enabled
+        market.mark_price = 50_000_000_000;
+        market.update_mark_price_ema(111).unwrap();
+        assert!(market.mark_price_ema > 89_000_000_000);
+        assert_eq!(market.liquidation_ema_price(), None);
+        market.ema_liquidation = 1;
+        assert_eq!(market.liquidation_ema_price(), Some(market.mark_price_ema));
+    }
+
+    #[test]
+    fn test_median_price_resists_one_bad_feed() {
+        let feed = |price: u64, conf: u64, publish_slot: u64| OraclePrice { price, conf, publish_slot };
+
+        // A single feed reporting a wild price is outvoted by the other two
+        let mut prices = [feed(100_000_000_000, 5, 10), feed(1_000_000, 1, 12), feed(101_000_000_000, 7, 11)];
+        assert_eq!(calculate_median_price(&mut prices), Some(feed(100_000_000_000, 5, 10)));
+
+        // With two valid feeds the median averages them, keeping the wider confidence and older slot
+        let mut prices = [feed(101_000_000_000, 7, 11), feed(100_000_000_000, 5, 10)];
+        assert_eq!(calculate_median_price(&mut prices), Some(feed(100_500_000_000, 7, 10)));
+
+        assert_eq!(calculate_median_price(&mut []), None);
+    }
+
+    #[test]
+    fn test_portfolio_health_picks_weakest_position() {
+        let market = |mark_price: u64| MarketState { mark_price, ..Default::default() };
+        let long = Position { base_amount: 1_000_000_000, entry_price: 100_000_000_000, ..Default::default() };
+        let short = Position { base_amount: -2_000_000_000, entry_price: 50_000_000_000, ..Default::default() };
+
+        // Long -$5, short -$10: the short is weakest despite its market coming second
+        let positions = [
+            (Position::default(), market(1)),
+            (long, market(95_000_000_000)),
+            (short, market(55_000_000_000)),
+        ];
+        let health = calculate_portfolio_health(100_000_000_000, &positions).unwrap();
+        assert_eq!(health.equity, 85_000_000_000);
+        assert_eq!(health.notional, 205_000_000_000);
+        assert_eq!(health.collateral_ratio, calculate_cross_margin_health(100_000_000_000, &positions).unwrap());
+        assert_eq!(health.weakest, Some(2));
+
+        // Funding owed counts against a position's PnL; ties go to the first
+        let funded = MarketState { funding_index: 10_000_000_000, ..market(100_000_000_000) };
+        let health = calculate_portfolio_health(0, &[(short, market(55_000_000_000)), (long, funded)]).unwrap();
+        assert_eq!(health.equity, -20_000_000_000);
+        assert_eq!(health.collateral_ratio, 0);
+        assert_eq!(health.weakest, Some(0));
+
+        let flat = calculate_portfolio_health(7, &[(Position::default(), market(1))]).unwrap();
+        assert_eq!(flat, PortfolioHealth { equity: 7, notional: 0, collateral_ratio: u64::MAX, weakest: None });
+    }
+
+    #[test]
+    fn test_set_margin_mode_moves_collateral_to_user_account() {
+        use solana_program::account_info::AccountInfo;
+        use solana_program::program_error::ProgramError;
+
+        let program_id = Pubkey::new_unique();
+        let owner_key = Pubkey::new_unique();
+        let (user_account_key, bump) =
+            Pubkey::find_program_address(&[USER_ACCOUNT_SEED, owner_key.as_ref()], &program_id);
+        let mut user_account_data = vec![0u8; UserAccount::SPACE];
+        let user_account = UserAccount { owner: owner_key, collateral: 5, positions: Vec::new(), bump };
+        store_account(&user_account, &mut user_account_data).unwrap();
+
+        // The position must be the owner's PDA in the market passed
+        let market_key = Pubkey::new_unique();
+        let (position_key, position_bump) = crate::position_address(&program_id, &market_key, &owner_key, 0);
+        let mut position_buffer = [0u64; Position::SPACE / 8];
+        let position_data: &mut [u8] = bytemuck::cast_slice_mut(&mut position_buffer);
+        *Position::init(position_data).unwrap() = Position {
+            owner: owner_key,
+            collateral: 100,
+            version: Position::VERSION,
+            bump: position_bump,
+            ..Default::default()
+        };
+        let mut market_buffer = [0u64; MarketState::SPACE / 8];
+        let market_data: &mut [u8] = bytemuck::cast_slice_mut(&mut market_buffer);
+        *MarketState::init(market_data).unwrap() = MarketState {
+            funding_index: 42,
+            version: MarketState::VERSION,
+            ..Default::default()
+        };
+
+        let system_id = Pubkey::default();
+        let (mut l0, mut l1, mut l2, mut l3) = (0u64, 0u64, 0u64, 0u64);
+        let mut owner_data = [0u8; 0];
+        let accounts = [
+            AccountInfo::new(&owner_key, true, false, &mut l0, &mut owner_data, &system_id, false, 0),
+            AccountInfo::new(&position_key, false, true, &mut l1, position_data, &program_id, false, 0),
+            AccountInfo::new(&market_key, false, false, &mut l2, market_data, &program_id, false, 0),
+            AccountInfo::new(&user_account_key, false, true, &mut l3, &mut user_account_data, &program_id, false, 0),
+        ];
+
+        crate::cross_margin::set_margin_mode(&program_id, &accounts, crate::MARGIN_MODE_CROSS).unwrap();
+        {
+            let position_data = accounts[1].data.borrow();
+            let position = Position::load(&position_data).unwrap();
+            assert!(position.is_cross_margin());
+            assert_eq!(position.collateral, 0);
+            assert_eq!(position.last_funding_index, 42);
+
+            let user_account = crate::load_account::<UserAccount>(&accounts[3].data.borrow()).unwrap();
+            assert_eq!(user_account.collateral, 105);
+            assert_eq!(user_account.positions, vec![CrossPosition { position: position_key, market: market_key }]);
+        }
+
+        // Switching again to the same mode is rejected; back to isolated unlinks the position
+        assert_eq!(
+            crate::cross_margin::set_margin_mode(&program_id, &accounts, crate::MARGIN_MODE_CROSS),
+            Err(ProgramError::InvalidArgument)
+        );
+        crate::cross_margin::set_margin_mode(&program_id, &accounts, crate::MARGIN_MODE_ISOLATED).unwrap();
+        let user_account = crate::load_account::<UserAccount>(&accounts[3].data.borrow()).unwrap();
+        assert!(user_account.positions.is_empty());
+        assert_eq!(user_account.collateral, 105);
+    }
+
+    #[test]
+    fn test_sub_accounts_derive_distinct_positions() {
+        let program_id = Pubkey::new_unique();
+        let market = Pubkey::new_unique();
+        let owner = Pubkey::new_unique();
+
+        let (main, _) = crate::position_address(&program_id, &market, &owner, 0);
+        let (hedge, _) = crate::position_address(&program_id, &market, &owner, 1);
+        let (other_market, _) = crate::position_address(&program_id, &Pubkey::new_unique(), &owner, 0);
+        assert_ne!(main, hedge);
+        assert_ne!(main, other_market);
+        assert_eq!(crate::position_address(&program_id, &market, &owner, 1).0, hedge);
+
+        // The id sits in what used to be padding, so older positions read as sub-account 0
+        let position = Position { sub_account_id: 0x0102, ..Default::default() };
+        assert_eq!(bytemuck::bytes_of(&position)[66..68], [0x02, 0x01]);
+    }
+
+    #[test]
+    fn test_stored_bumps_rederive_pdas() {
+        let program_id = Pubkey::new_unique();
+        let market = Pubkey::new_unique();
+        let owner = Pubkey::new_unique();
+
+        let (address, bump) = crate::position_address(&program_id, &market, &owner, 3);
+        let position = Position { owner, sub_account_id: 3, bump, ..Default::default() };
+        assert!(crate::validate_position_address(&program_id, &address, &market, &position).is_ok());
+        assert!(crate::validate_position_address(&program_id, &address, &Pubkey::new_unique(), &position).is_err());
+
+        // A stale bump no longer derives the account
+        let stale = Position { bump: bump.wrapping_sub(1), ..position };
+        assert!(crate::validate_position_address(&program_id, &address, &market, &stale).is_err());
+
+        let (vault, vault_bump) = Pubkey::find_program_address(&[crate::PDA_SEED], &program_id);
+        assert_eq!(crate::vault_pda(&program_id, vault_bump).unwrap(), vault);
+    }
+
+    #[test]
+    fn test_delegate_can_trade_until_revoked() {
+        use solana_program::account_info::AccountInfo;
+        use solana_program::program_error::ProgramError;
+
+        let program_id = Pubkey::new_unique();
+        let (owner_key, bot_key) = (Pubkey::new_unique(), Pubkey::new_unique());
+
+        let mut position_buffer = [0u64; Position::SPACE / 8];
+        let position_data: &mut [u8] = bytemuck::cast_slice_mut(&mut position_buffer);
+        *Position::init(position_data).unwrap() = Position {
+            owner: owner_key,
+            version: Position::VERSION,
+            ..Default::default()
+        };
+        assert!(!Position::load(position_data).unwrap().can_trade(&bot_key));
+        // An unset delegate never matches, even the default pubkey
+        assert!(!Position::load(position_data).unwrap().can_trade(&Pubkey::default()));
+
+        let position_key = Pubkey::new_unique();
+        let system_id = Pubkey::default();
+        let (mut l0, mut l1, mut l2) = (0u64, 0u64, 0u64);
+        let (mut owner_data, mut bot_data) = ([0u8; 0], [0u8; 0]);
+        let owner = AccountInfo::new(&owner_key, true, false, &mut l0, &mut owner_data, &system_id, false, 0);
+        let bot = AccountInfo::new(&bot_key, true, false, &mut l1, &mut bot_data, &system_id, false, 0);
+        let position = AccountInfo::new(&position_key, false, true, &mut l2, position_data, &program_id, false, 0);
+
+        crate::approve_delegate(&program_id, &[owner.clone(), position.clone()], bot_key).unwrap();
+        assert!(Position::load(&position.data.borrow()).unwrap().can_trade(&bot_key));
+
+        // The delegate cannot re-delegate or revoke
+        assert_eq!(
+            crate::approve_delegate(&program_id, &[bot, position.clone()], Pubkey::default()),
+            Err(ProgramError::IllegalOwner)
+        );
+
+        crate::approve_delegate(&program_id, &[owner, position.clone()], Pubkey::default()).unwrap();
+        assert!(!Position::load(&position.data.borrow()).unwrap().can_trade(&bot_key));
+    }
+
+    #[test]
+    fn test_tokenized_position_has_no_outright_owner() {
+        let owner = Pubkey::new_unique();
+        let position = Position { owner, ..Default::default() };
+        assert!(position.is_owner(&owner) && position.can_trade(&owner));
+        assert!(crate::require_position_owner(&position, &owner).is_ok());
+
+        // Once its NFT exists, the opening wallet is just another signer
+        let tokenized = Position { position_mint: Pubkey::new_unique(), ..position };
+        assert!(!tokenized.is_owner(&owner) && !tokenized.can_trade(&owner));
+        assert!(crate::require_position_owner(&tokenized, &owner).is_err());
+    }
+
+    #[test]
+    fn test_funding_crank_reward() {
+        use crate::{calculate_funding_crank_reward, FUNDING_PERIOD_SLOTS};
+
+        // Pro rata per period, then capped
+        assert_eq!(calculate_funding_crank_reward(FUNDING_PERIOD_SLOTS, 10_000, 100_000, u64::MAX), 10_000);
+        assert_eq!(calculate_funding_crank_reward(FUNDING_PERIOD_SLOTS / 2, 10_000, 100_000, u64::MAX), 5_000);
+        assert_eq!(calculate_funding_crank_reward(FUNDING_PERIOD_SLOTS * 50, 10_000, 100_000, u64::MAX), 100_000);
+        assert_eq!(calculate_funding_crank_reward(u64::MAX, u64::MAX, u64::MAX, u64::MAX), u64::MAX);
+
+        // Never more than the fee pool holds
+        assert_eq!(calculate_funding_crank_reward(FUNDING_PERIOD_SLOTS, 10_000, 100_000, 2_500), 2_500);
+        assert_eq!(calculate_funding_crank_reward(0, 10_000, 100_000, u64::MAX), 0);
+    }
+
+    #[test]
+    fn test_liquidation_fee_scales_with_notional() {
+        use crate::{calculate_liquidation_fee, LiquidationFee};
+
+        // 1% of 10,000 notional, a quarter of it to the insurance fund
+        let fee = calculate_liquidation_fee(10_000_000_000, 5_000_000_000, 0, 100, 250_000_000).unwrap();
+        assert_eq!(fee, LiquidationFee { liquidator_reward: 75_000_000, from_insurance: 0, insurance_fee: 25_000_000 });
+        assert_eq!(fee.collateral_charged(), 100_000_000);
+
+        // Near-zero collateral: the insurance fund tops up the liquidator and keeps nothing
+        let fee = calculate_liquidation_fee(10_000_000_000, 10_000_000, 1_000_000_000, 100, 250_000_000).unwrap();
+        assert_eq!(fee, LiquidationFee { liquidator_reward: 75_000_000, from_insurance: 65_000_000, insurance_fee: 0 });
+        assert_eq!(fee.collateral_charged(), 10_000_000);
+
+        // An empty fund limits the reward to the collateral
+        let fee = calculate_liquidation_fee(10_000_000_000, 10_000_000, 0, 100, 250_000_000).unwrap();
+        assert_eq!(fee.liquidator_reward, 10_000_000);
+    }
+
+    #[test]
+    fn test_backstop_entry_price_discount() {
+        use crate::calculate_backstop_entry_price;
+
+        // Taking over 10 units at $100 with a $75 reward: $7.50 better per unit
+        assert_eq!(calculate_backstop_entry_price(10_000_000_000, 100_000_000_000, 75_000_000_000).unwrap(), 92_500_000_000);
+        assert_eq!(calculate_backstop_entry_price(-10_000_000_000, 100_000_000_000, 75_000_000_000).unwrap(), 107_500_000_000);
+
+        // The inherited position is worth exactly the reward at the mark price
+        let position = Position {
+            base_amount: 10_000_000_000,
+            entry_price: calculate_backstop_entry_price(10_000_000_000, 100_000_000_000, 75_000_000_000).unwrap(),
+            ..Default::default()
+        };
+        assert_eq!(calculate_unrealized_pnl(&position, 100_000_000_000).unwrap(), 75_000_000_000);
+
+        assert_eq!(calculate_backstop_entry_price(10_000_000_000, 100_000_000_000, 0).unwrap(), 100_000_000_000);
+    }
+
+    #[test]
+    fn test_realized_pnl_on_reductions() {
+        use crate::calculate_realized_pnl;
+
+        // Closing half of a 10-unit long entered at $100 at $110 realizes $50
+        assert_eq!(calculate_realized_pnl(10_000_000_000, 100_000_000_000, -5_000_000_000, 110_000_000_000).unwrap(), 50_000_000_000);
+        // The same move against a short is a loss
+        assert_eq!(calculate_realized_pnl(-10_000_000_000, 100_000_000_000, 5_000_000_000, 110_000_000_000).unwrap(), -50_000_000_000);
+        // A flip only realizes the part that was open
+        assert_eq!(calculate_realized_pnl(10_000_000_000, 100_000_000_000, -15_000_000_000, 90_000_000_000).unwrap(), -100_000_000_000);
+        // Increases and fresh opens realize nothing
+        assert_eq!(calculate_realized_pnl(10_000_000_000, 100_000_000_000, 5_000_000_000, 110_000_000_000).unwrap(), 0);
+        assert_eq!(calculate_realized_pnl(0, 0, -5_000_000_000, 110_000_000_000).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_position_change_settles_pnl_and_bad_debt() {
+        let mut market_state = MarketState {
+            max_open_interest: u64::MAX,
+            open_interest: 10_000_000_000,
+            long_open_interest: 10_000_000_000,
+            ..Default::default()
+        };
+        let mut position = Position {
+            base_amount: 10_000_000_000,
+            entry_price: 100_000_000_000,
+            collateral: 20_000_000_000,
+            ..Default::default()
+        };
+
+        // Close 4 units at $105: +$20 into collateral
+        crate::apply_position_change(&mut position, &mut market_state, -4_000_000_000, 105_000_000_000).unwrap();
+        assert_eq!(position.collateral, 40_000_000_000);
+        assert_eq!(market_state.total_realized_profit, 20_000_000_000);
+        assert_eq!(position.entry_price, 100_000_000_000);
+
+        // Flip to a 2-unit short at $90: -$60 on the 6 units closed, only $40 collectable
+        crate::apply_position_change(&mut position, &mut market_state, -8_000_000_000, 90_000_000_000).unwrap();
+        assert_eq!(position.collateral, 0);
+        assert_eq!(market_state.total_realized_loss, 40_000_000_000);
+        assert_eq!(market_state.bad_debt, 20_000_000_000);
+        assert_eq!((position.base_amount, position.entry_price), (-2_000_000_000, 90_000_000_000));
+        assert_eq!(market_state.open_interest, 2_000_000_000);
+        assert_eq!(market_state.outstanding_bad_debt, 20_000_000_000);
+    }
+
+    #[test]
+    fn test_insurance_fund_covers_bad_debt_first() {
+        let mut market_state = MarketState {
+            max_open_interest: u64::MAX,
+            open_interest: 10_000_000_000,
+            long_open_interest: 10_000_000_000,
+            insurance_fund: 15_000_000_000,
+            ..Default::default()
+        };
+        let mut position = Position {
+            base_amount: 10_000_000_000,
+            entry_price: 100_000_000_000,
+            collateral: 30_000_000_000,
+            ..Default::default()
+        };
+
+        // Closing at $95 loses $50 on $30 of collateral: the fund pays $15 of the $20 shortfall
+        crate::apply_position_change(&mut position, &mut market_state, -10_000_000_000, 95_000_000_000).unwrap();
+        assert_eq!(position.collateral, 0);
+        assert_eq!(market_state.insurance_fund, 0);
+        assert_eq!(market_state.bad_debt, 20_000_000_000);
+        assert_eq!(market_state.outstanding_bad_debt, 5_000_000_000);
+        assert_eq!(market_state.total_realized_loss, 45_000_000_000);
+
+        // Funding the collateral can't pay is bad debt too
+        let mut collateral = 1_000_000_000;
+        crate::charge_funding_shortfall(&mut collateral, &mut market_state, 3_000_000_000).unwrap();
+        assert_eq!(collateral, 0);
+        assert_eq!(market_state.bad_debt, 22_000_000_000);
+        assert_eq!(market_state.outstanding_bad_debt, 7_000_000_000);
+    }
+
+    #[test]
+    fn test_funding_rate_follows_skew() {
+        use crate::{calculate_skew_funding_rate, FUNDING_SKEW_RATE_PER_SLOT};
+
+        let mut market_state = MarketState { max_open_interest: u64::MAX, ..Default::default() };
+        let mut long = Position { collateral: 50_000_000_000, ..Default::default() };
+        let mut short = Position { collateral: 50_000_000_000, ..Default::default() };
+        crate::apply_position_change(&mut long, &mut market_state, 30_000_000_000, 100_000_000_000).unwrap();
+        crate::apply_position_change(&mut short, &mut market_state, -10_000_000_000, 100_000_000_000).unwrap();
+        assert_eq!((market_state.long_open_interest, market_state.short_open_interest), (30_000_000_000, 10_000_000_000));
+
+        // 3:1 long-heavy: longs pay half the full-skew rate
+        let rate = calculate_skew_funding_rate(market_state.long_open_interest, market_state.short_open_interest).unwrap();
+        assert_eq!(rate, FUNDING_SKEW_RATE_PER_SLOT / 2);
+
+        // Flipping the long to a bigger short makes shorts pay
+        crate::apply_position_change(&mut long, &mut market_state, -50_000_000_000, 100_000_000_000).unwrap();
+        assert_eq!((market_state.long_open_interest, market_state.short_open_interest), (0, 30_000_000_000));
+        assert_eq!(calculate_skew_funding_rate(0, 30_000_000_000).unwrap(), -FUNDING_SKEW_RATE_PER_SLOT);
+
+        // Balanced or empty markets pay nothing
+        assert_eq!(calculate_skew_funding_rate(10_000_000_000, 10_000_000_000).unwrap(), 0);
+        assert_eq!(calculate_skew_funding_rate(0, 0).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_skew_fee_charges_heavy_side_and_rebates_light_side() {
+        use crate::calculate_skew_fee;
+
+        // 0.1% skew fee at $100, market 5 units net long
+        let fee = |base_delta| calculate_skew_fee(5_000_000_000, base_delta, 100_000_000_000, 1_000_000).unwrap();
+        assert_eq!(fee(2_000_000_000), 200_000_000); // longs grow the skew: $0.20 surcharge
+        assert_eq!(fee(-2_000_000_000), -200_000_000); // shorts shrink it: $0.20 rebate
+        assert_eq!(fee(-8_000_000_000), -200_000_000); // 5 units rebated, 3 units charged
+
+        // Surcharges go to the fee pool; rebates come out of it, capped at what it holds
+        let mut market_state = MarketState {
+            max_open_interest: u64::MAX,
+            skew_fee: 1_000_000,
+            fee_pool: 100_000_000,
+            ..Default::default()
+        };
+        let mut long = Position { collateral: 50_000_000_000, ..Default::default() };
+        let mut short = Position { collateral: 50_000_000_000, ..Default::default() };
+        crate::apply_position_change(&mut long, &mut market_state, 5_000_000_000, 100_000_000_000).unwrap();
+        crate::charge_skew_fee(&mut long.collateral, &mut market_state, 5_000_000_000, 100_000_000_000).unwrap();
+        assert_eq!((long.collateral, market_state.fee_pool), (49_500_000_000, 600_000_000));
+
+        // Flipping the skew rebates the 5 units it removes and charges the 5 it adds
+        crate::apply_position_change(&mut short, &mut market_state, -10_000_000_000, 100_000_000_000).unwrap();
+        assert_eq!(crate::charge_skew_fee(&mut short.collateral, &mut market_state, -10_000_000_000, 100_000_000_000).unwrap(), 0);
+
+        // Buying the 5-unit net short back flat earns $0.50, but the pool only holds $0.20
+        market_state.fee_pool = 200_000_000;
+        crate::apply_position_change(&mut long, &mut market_state, 5_000_000_000, 100_000_000_000).unwrap();
+        assert_eq!(crate::charge_skew_fee(&mut long.collateral, &mut market_state, 5_000_000_000, 100_000_000_000).unwrap(), -200_000_000);
+        assert_eq!((long.collateral, market_state.fee_pool), (49_700_000_000, 0));
+    }
+
+    #[test]
+    fn test_socialized_loss_haircuts_winning_side() {
+        let mut market_state = MarketState { max_open_interest: u64::MAX, ..Default::default() };
+        let mut long = Position { collateral: 50_000_000_000, ..Default::default() };
+        let mut short = Position { collateral: 50_000_000_000, ..Default::default() };
+        crate::apply_position_change(&mut long, &mut market_state, 10_000_000_000, 100_000_000_000).unwrap();
+        crate::apply_position_change(&mut short, &mut market_state, -10_000_000_000, 100_000_000_000).unwrap();
+
+        // At $110 longs are $100 up and shorts $100 down; only longs are haircut
+        market_state.mark_price = 110_000_000_000;
+        assert_eq!(crate::calculate_side_pnl(&market_state, market_state.mark_price).unwrap(), (100_000_000_000, -100_000_000_000));
+        market_state.outstanding_bad_debt = 30_000_000_000;
+        assert_eq!(crate::socialize_outstanding_bad_debt(&mut market_state).unwrap(), 30_000_000_000);
+        assert_eq!((market_state.long_loss_index, market_state.short_loss_index), (3_000_000_000, 0));
+        assert_eq!(market_state.outstanding_bad_debt, 0);
+
+        // The haircut is charged at the next settlement as a realized loss
+        crate::apply_funding(&mut long, &mut market_state, 0).unwrap();
+        crate::apply_funding(&mut short, &mut market_state, 0).unwrap();
+        assert_eq!(long.collateral, 20_000_000_000);
+        assert_eq!(long.realized_pnl, -30_000_000_000);
+        assert_eq!(short.collateral, 50_000_000_000);
+        assert_eq!(market_state.total_realized_loss, 30_000_000_000);
+        assert_eq!(crate::calculate_pending_socialized_loss(&long, &market_state).unwrap(), 0);
+
+        // Never more than the winners' profits
+        market_state.outstanding_bad_debt = 500_000_000_000;
+        assert_eq!(crate::socialize_outstanding_bad_debt(&mut market_state).unwrap(), 100_000_000_000);
+        assert_eq!(market_state.outstanding_bad_debt, 400_000_000_000);
+        assert_eq!(market_state.socialized_loss, 130_000_000_000);
+    }
+
+    #[test]
+    fn test_lp_pool_absorbs_trader_pnl() {
+        let mut pool = PoolState { liquidity: 1_000_000, lp_shares: 1_000_000, ..Default::default() };
+        assert_eq!(pool.try_to_vec().unwrap().len(), PoolState::LEN);
+
+        // Traders realize 300 of losses and 100 of profits: the pool nets +200
+        let mut market_state = MarketState { total_realized_loss: 300, total_realized_profit: 100, ..Default::default() };
+        pool.sync(&market_state).unwrap();
+        assert_eq!(pool.liquidity, 1_000_200);
+
+        // Already-synced PnL is not applied twice
+        pool.sync(&market_state).unwrap();
+        assert_eq!(pool.liquidity, 1_000_200);
+
+        // A pool cannot owe more than it holds
+        market_state.total_realized_profit += 2_000_000;
+        pool.sync(&market_state).unwrap();
+        assert_eq!(pool.liquidity, 0);
+    }
+
+    #[test]
+    fn test_lp_share_pricing() {
+        // First deposit mints 1:1
+        assert_eq!(calculate_lp_shares_for_deposit(500, 0, 0).unwrap(), 500);
+
+        // After the pool grows 10%, the same deposit buys fewer shares
+        assert_eq!(calculate_lp_shares_for_deposit(1_100, 1_100_000, 1_000_000).unwrap(), 1_000);
+        assert_eq!(calculate_lp_withdrawal(1_000, 1_100_000, 1_000_000).unwrap(), 1_100);
+
+        // Redeeming everything pays out the whole pool
+        assert_eq!(calculate_lp_withdrawal(1_000_000, 1_100_000, 1_000_000).unwrap(), 1_100_000);
+        assert!(calculate_lp_withdrawal(1_000_001, 1_100_000, 1_000_000).is_err());
+    }
+
+    #[test]
+    fn test_pool_nav_counts_unrealized_trader_pnl() {
+        let mut market_state = MarketState { max_open_interest: u64::MAX, ..Default::default() };
+        let mut long = Position::default();
+        let mut short = Position::default();
+
+        // A 10-unit long at $100 and a 4-unit short at $110
+        crate::apply_position_change(&mut long, &mut market_state, 10_000_000_000, 100_000_000_000).unwrap();
+        crate::apply_position_change(&mut short, &mut market_state, -4_000_000_000, 110_000_000_000).unwrap();
+        assert_eq!(market_state.net_base_amount, 6_000_000_000);
+
+        // At $120 the long is up $200 and the short down $40
+        market_state.mark_price = 120_000_000_000;
+        let total = crate::calculate_total_unrealized_pnl(
+            market_state.net_base_amount, market_state.net_entry_quote, market_state.mark_price,
+        ).unwrap();
+        assert_eq!(total, calculate_unrealized_pnl(&long, 120_000_000_000).unwrap()
+            + calculate_unrealized_pnl(&short, 120_000_000_000).unwrap());
+        assert_eq!(total, 160_000_000_000);
+
+        let pool = PoolState { liquidity: 1_000_000_000_000, lp_shares: 1_000, ..Default::default() };
+        assert_eq!(pool.nav(&market_state).unwrap(), 840_000_000_000);
+
+        // Closing the long removes its exposure from the aggregates
+        crate::apply_position_change(&mut long, &mut market_state, -10_000_000_000, 120_000_000_000).unwrap();
+        assert_eq!(market_state.net_base_amount, -4_000_000_000);
+        assert_eq!(market_state.net_entry_quote, -440_000_000_000);
+    }
+
+    #[test]
+    fn test_epoch_withdrawal_cap() {
+        let mut pool = PoolState {
+            liquidity: 1_000_000,
+            max_epoch_withdrawal_share: 250_000_000,
+            ..Default::default()
+        };
+        assert_eq!(WithdrawalRequest::default().try_to_vec().unwrap().len(), WithdrawalRequest::LEN);
+
+        // The cap is a share of the liquidity when the epoch is first touched
+        pool.roll_withdrawal_epoch(5);
+        assert_eq!(pool.epoch_withdrawal_remaining(), 250_000);
+        pool.epoch_withdrawn += 200_000;
+        pool.liquidity -= 200_000;
+
+        // Rolling within the same epoch keeps the running total
+        pool.roll_withdrawal_epoch(5);
+        assert_eq!(pool.epoch_withdrawal_remaining(), 50_000);
+
+        // A new epoch resets it against the smaller pool
+        pool.roll_withdrawal_epoch(6);
+        assert_eq!(pool.epoch_withdrawal_remaining(), 200_000);
+    }
+
+    #[test]
+    fn test_mark_price_band() {
+        let index = 100_000_000_000; // $100
+        let band = crate::DEFAULT_MARK_PRICE_BAND; // 2%
+
+        assert!(is_within_price_band(101_500_000_000, index, band));
+        assert!(is_within_price_band(98_000_000_000, index, band));
+        assert!(!is_within_price_band(102_000_000_001, index, band));
+        assert!(!is_within_price_band(97_000_000_000, index, band));
+
+        // A mark pushed far from the index is pulled back to the band's edge
+        assert_eq!(clamp_to_price_band(150_000_000_000, index, band), 102_000_000_000);
+        assert_eq!(clamp_to_price_band(50_000_000_000, index, band), 98_000_000_000);
+        assert_eq!(clamp_to_price_band(100_500_000_000, index, band), 100_500_000_000);
+    }
+
+    #[test]
+    fn test_circuit_breaker_trips_on_fast_moves() {
+        let mut market_state = MarketState {
+            breaker_max_move: 100_000_000, // 10%
+            breaker_window_slots: 150,
+            breaker_cooldown_slots: 1_500,
+            ..Default::default()
+        };
+
+        // The first observation only sets the reference price
+        assert_eq!(market_state.observe_index_price(100_000_000_000, 1_000), None);
+        assert_eq!(market_state.observe_index_price(109_000_000_000, 1_100), None);
+        assert!(!market_state.is_reduce_only(1_100));
+
+        // 12% within the window trips it
+        assert_eq!(market_state.observe_index_price(88_000_000_000, 1_150), Some(120_000_000));
+        assert!(market_state.is_reduce_only(1_150));
+        assert!(market_state.is_reduce_only(2_649));
+        assert!(!market_state.is_reduce_only(2_650));
+
+        // The same move spread past the window resets the reference instead
+        assert_eq!(market_state.observe_index_price(100_000_000_000, 3_000), None);
+        assert_eq!(market_state.observe_index_price(88_000_000_000, 3_151), None);
+        assert_eq!(market_state.breaker_reference_price, 88_000_000_000);
+
+        // Disabled breakers never trip
+        market_state.breaker_max_move = 0;
+        assert_eq!(market_state.observe_index_price(1, 3_152), None);
+    }
+
+    #[test]
+    fn test_settled_market_closes_at_settlement_price() {
+        let mut market_state = MarketState { max_open_interest: u64::MAX, ..Default::default() };
+        let mut position = Position { collateral: 500_000_000_000, ..Default::default() };
+        crate::apply_position_change(&mut position, &mut market_state, 10_000_000_000, 100_000_000_000).unwrap();
+        assert!(crate::require_active(&market_state).is_ok());
+
+        market_state.settlement_price = 110_000_000_000;
+        assert!(market_state.is_settled());
+        assert!(crate::require_active(&market_state).is_err());
+
+        // Settling realizes the +$100 at the settlement price and clears the market's exposure
+        crate::apply_position_change(&mut position, &mut market_state, -10_000_000_000, 110_000_000_000).unwrap();
+        assert_eq!(position.collateral, 600_000_000_000);
+        assert_eq!(market_state.open_interest, 0);
+        assert_eq!(market_state.net_base_amount, 0);
+        assert_eq!(market_state.total_realized_profit, 100_000_000_000);
+    }
+
+    #[test]
+    fn test_dated_market_expiry_and_twap() {
+        let mut market_state = MarketState::default();
+        assert!(!market_state.is_dated());
+        assert!(!market_state.is_expired(i64::MAX));
+
+        market_state.expiry_timestamp = 1_700_000_000;
+        assert!(market_state.is_dated());
+        assert!(!market_state.is_expired(1_699_999_999));
+        assert!(market_state.is_expired(1_700_000_000));
+
+        // The settlement TWAP is the feed's EMA price, rescaled like the spot price
+        let mut data = sample_pyth_account(15_025_000_000, -8, 1, 1_000);
+        data[48..56].copy_from_slice(&14_900_000_000i64.to_le_bytes());
+        assert_eq!(parse_pyth_twap(&data, 1_010).unwrap(), 149_000_000_000);
+
+        // Stale or missing averages are rejected
+        assert!(parse_pyth_twap(&data, 1_000 + MAX_ORACLE_STALENESS_SLOTS + 1).is_err());
+        assert!(parse_pyth_twap(&sample_pyth_account(15_025_000_000, -8, 1, 1_000), 1_010).is_err());
+    }
+
+    #[test]
+    fn test_increases_average_the_entry_price() {
+        assert_eq!(crate::calculate_average_entry_price(10_000_000_000, 100_000_000_000, 30_000_000_000, 120_000_000_000).unwrap(),
+                   115_000_000_000);
+
+        let mut market_state = MarketState { max_open_interest: u64::MAX, ..Default::default() };
+        let mut position = Position { collateral: 1_000_000_000_000, ..Default::default() };
+
+        // Scale into a short: 2 at $100, then 2 more at $90
+        crate::apply_position_change(&mut position, &mut market_state, -2_000_000_000, 100_000_000_000).unwrap();
+        crate::apply_position_change(&mut position, &mut market_state, -2_000_000_000, 90_000_000_000).unwrap();
+        assert_eq!(position.entry_price, 95_000_000_000);
+        assert_eq!(market_state.total_realized_profit + market_state.total_realized_loss, 0);
+
+        // Buying 1 back at $85 realizes $10 and keeps the average for the rest
+        crate::apply_position_change(&mut position, &mut market_state, 1_000_000_000, 85_000_000_000).unwrap();
+        assert_eq!(position.entry_price, 95_000_000_000);
+        assert_eq!(market_state.total_realized_profit, 10_000_000_000);
+        assert_eq!(calculate_unrealized_pnl(&position, 85_000_000_000).unwrap(), 30_000_000_000);
+    }
+
+    #[test]
+    fn test_position_tracks_lifetime_pnl_and_funding() {
+        let mut market_state = MarketState { max_open_interest: u64::MAX, ..Default::default() };
+        let mut position = Position { collateral: 1_000_000_000_000, ..Default::default() };
+
+        crate::apply_position_change(&mut position, &mut market_state, 10_000_000_000, 100_000_000_000).unwrap();
+
+        // Longs pay $5 of funding, then receive $2
+        market_state.funding_index = 500_000_000;
+        crate::apply_funding(&mut position, &mut market_state, 0).unwrap();
+        market_state.funding_index = 300_000_000;
+        crate::apply_funding(&mut position, &mut market_state, 0).unwrap();
+        assert_eq!(position.cumulative_funding, -3_000_000_000);
+        assert_eq!(position.claimable_funding, 2_000_000_000);
+
+        // A $20 gain on half, then a $10 loss on the rest
+        crate::apply_position_change(&mut position, &mut market_state, -5_000_000_000, 104_000_000_000).unwrap();
+        crate::apply_position_change(&mut position, &mut market_state, -5_000_000_000, 98_000_000_000).unwrap();
+        assert_eq!(position.realized_pnl, 10_000_000_000);
+        assert_eq!(position.collateral, 1_000_000_000_000 - 5_000_000_000 + 10_000_000_000);
+    }
+
+    #[test]
+    fn test_received_funding_claimable_unless_compounding() {
+        // A short receiving $3 of funding
+        let mut market_state = MarketState { funding_index: 300_000_000, ..Default::default() };
+        let mut position = Position { base_amount: -10_000_000_000, collateral: 100_000_000_000, ..Default::default() };
+
+        crate::apply_funding(&mut position, &mut market_state, 0).unwrap();
+        assert_eq!((position.collateral, position.claimable_funding), (100_000_000_000, 3_000_000_000));
+
+        // Compounding positions take it as collateral; what is already claimable stays so
+        position.auto_compound_funding = 1;
+        market_state.funding_index = 600_000_000;
+        crate::apply_funding(&mut position, &mut market_state, 0).unwrap();
+        assert_eq!((position.collateral, position.claimable_funding), (103_000_000_000, 3_000_000_000));
+        assert_eq!(position.cumulative_funding, 6_000_000_000);
+    }
+
+    #[test]
+    fn test_funding_residual_goes_to_insurance_fund() {
+        // 30 units long against 10 short: at a 0.1 index move longs owe $3 and shorts are owed $1
+        let mut market_state = MarketState { max_open_interest: u64::MAX, ..Default::default() };
+        let mut long = Position { collateral: 100_000_000_000, ..Default::default() };
+        let mut short = Position { collateral: 100_000_000_000, ..Default::default() };
+        crate::apply_position_change(&mut long, &mut market_state, 30_000_000_000, 100_000_000_000).unwrap();
+        crate::apply_position_change(&mut short, &mut market_state, -10_000_000_000, 100_000_000_000).unwrap();
+
+        market_state.funding_index = 100_000_000;
+        assert_eq!(market_state.accrue_side_funding(100_000_000).unwrap(), 2_000_000_000);
+        assert_eq!((market_state.long_funding, market_state.short_funding), (3_000_000_000, -1_000_000_000));
+        assert_eq!((market_state.insurance_fund, market_state.funding_residual), (2_000_000_000, 2_000_000_000));
+
+        // Once both settle, what left the longs is exactly what reached the short and the fund
+        crate::apply_funding(&mut long, &mut market_state, 0).unwrap();
+        crate::apply_funding(&mut short, &mut market_state, 0).unwrap();
+        let held = long.collateral + short.collateral + short.claimable_funding + market_state.insurance_fund;
+        assert_eq!(held, 200_000_000_000);
+    }
+
+    #[test]
+    fn test_funding_payment_capped_per_settlement() {
+        let mut market_state = MarketState { funding_index: 5_000_000_000, ..Default::default() };
+        // Long 10 units owing $50 of funding on $100 of collateral
+        let mut position = Position { base_amount: 10_000_000_000, collateral: 100_000_000_000, ..Default::default() };
+
+        // At most 10% of the collateral is paid; the rest stays owed
+        crate::apply_funding(&mut position, &mut market_state, 100_000_000).unwrap();
+        assert_eq!(position.collateral, 90_000_000_000);
+        assert_eq!(position.pending_funding, 40_000_000_000);
+        assert_eq!(position.cumulative_funding, -10_000_000_000);
+        assert_eq!(crate::calculate_pending_funding(&position, &market_state).unwrap(), 40_000_000_000);
+
+        // The next settlement pays the carried debt first, again capped
+        crate::apply_funding(&mut position, &mut market_state, 100_000_000).unwrap();
+        assert_eq!(position.collateral, 81_000_000_000);
+        assert_eq!(position.pending_funding, 31_000_000_000);
+
+        // An uncapped settlement clears it
+        crate::apply_funding(&mut position, &mut market_state, 0).unwrap();
+        assert_eq!(position.collateral, 50_000_000_000);
+        assert_eq!(position.pending_funding, 0);
+    }
+
+    #[test]
+    fn test_user_stats_accumulate() {
+        use crate::user_stats::UserStats;
+
+        let mut stats = UserStats::default();
+        assert_eq!(stats.try_to_vec().unwrap().len(), UserStats::LEN);
+
+        stats.record_trade(1_000_000_000_000, 1_000_000_000, 0);
+        stats.record_trade(500_000_000_000, 0, 0);
+        stats.record_liquidation(500_000_000_000, 5_000_000_000);
+        assert_eq!(stats.volume, 2_000_000_000_000);
+        assert_eq!(stats.fees_paid, 6_000_000_000);
+        assert_eq!(stats.trade_count, 2);
+        assert_eq!(stats.liquidation_count, 1);
+
+        // Counters saturate instead of failing the trade
+        stats.volume = u64::MAX - 1;
+        stats.record_trade(10, 0, 0);
+        assert_eq!(stats.volume, u64::MAX);
+    }
+
+    #[test]
+    fn test_funding_history_overwrites_the_oldest_record() {
+        use crate::funding_history::{FundingHistory, FundingRecord, MAX_FUNDING_RECORDS};
+
+        // Settle every 10 slots at 2 per slot, so the index grows by 20 a record
+        let record = |i: usize| FundingRecord {
+            slot: 10 * i as u64,
+            funding_rate_per_slot: 2,
+            funding_index: 20 * i as i64,
+            ..Default::default()
+        };
+        let mut history = FundingHistory::default();
+        assert_eq!(history.average_rate_per_slot(), None);
+        history.push(record(0));
+        assert_eq!(history.average_rate_per_slot(), None);
+
+        for i in 1..MAX_FUNDING_RECORDS + 3 {
+            history.push(record(i));
+        }
+        assert_eq!(history.records.len(), MAX_FUNDING_RECORDS);
+        assert_eq!(history.try_to_vec().unwrap().len(), FundingHistory::LEN);
+        let slots: Vec<u64> = history.records_oldest_first().map(|record| record.slot).collect();
+        let expected: Vec<u64> = (3..MAX_FUNDING_RECORDS + 3).map(|i| 10 * i as u64).collect();
+        assert_eq!(slots, expected);
+        assert_eq!(history.average_rate_per_slot(), Some(2));
+    }
+
+    #[test]
+    fn test_price_history_builds_hourly_candles() {
+        use crate::price_history::{Candle, PriceHistory, CANDLE_SECONDS, MAX_CANDLES};
+
+        // Prices within an hour extend its candle; the next hour starts a new one
+        let mut history = PriceHistory::default();
+        history.record(100, 7_200 + 10);
+        history.record(120, 7_200 + 20);
+        history.record(90, 7_200 + 30);
+        history.record(95, 7_200 + 3_599);
+        history.record(105, 10_800);
+        let hour = Candle { start_timestamp: 7_200, open: 100, high: 120, low: 90, close: 95 };
+        assert_eq!(history.candles, vec![hour, Candle { start_timestamp: 10_800, open: 105, high: 105, low: 105, close: 105 }]);
+
+        // A price stamped before the latest candle is dropped
+        history.record(1, 7_300);
+        assert_eq!(history.candles[0], hour);
+
+        // Once full, each new hour overwrites the oldest
+        for hour in 4..MAX_CANDLES as i64 + 6 {
+            history.record(hour as u64, hour * CANDLE_SECONDS);
+        }
+        assert_eq!(history.candles.len(), MAX_CANDLES);
+        assert_eq!(history.try_to_vec().unwrap().len(), PriceHistory::LEN);
+        let starts: Vec<i64> = history.candles_oldest_first().map(|candle| candle.start_timestamp).collect();
+        let expected: Vec<i64> = (6..MAX_CANDLES as i64 + 6).map(|hour| hour * CANDLE_SECONDS).collect();
+        assert_eq!(starts, expected);
+        history.record(7, (MAX_CANDLES as i64 + 5) * CANDLE_SECONDS + 1);
+        assert_eq!(history.candles_oldest_first().last().unwrap().low, 7);
+    }
+
+    #[test]
+    fn test_fee_tiers_follow_rolling_volume() {
+        use crate::user_stats::{UserStats, VOLUME_WINDOW_SECONDS};
+
+        let window = VOLUME_WINDOW_SECONDS;
+        let mut stats = UserStats::default();
+        stats.record_trade(600_000_000_000, 0, 10 * window);
+        assert_eq!(stats.rolling_volume(10 * window), 600_000_000_000);
+
+        // A third of the way into the next window, two thirds of the last window still count
+        stats.record_trade(100_000_000_000, 0, 11 * window + window / 3);
+        assert_eq!(stats.rolling_volume(11 * window + window / 3), 500_000_000_000);
+        // Liquidations don't count toward it
+        stats.record_liquidation(1_000_000_000_000, 0);
+        assert_eq!(stats.rolling_volume(11 * window + window / 3), 500_000_000_000);
+
+        // Idle for more than a window, everything has rolled off
+        assert_eq!(stats.rolling_volume(13 * window), 0);
+
+        let config = Config {
+            trading_fee: 1_000_000,
+            fee_tiers: vec![
+                FeeTier { min_volume: 100_000_000_000, trading_fee: 800_000 },
+                FeeTier { min_volume: 500_000_000_000, trading_fee: 500_000 },
+            ],
+            ..Config::default()
+        };
+        assert_eq!(config.taker_fee(99_999_999_999), 1_000_000);
+        assert_eq!(config.taker_fee(100_000_000_000), 800_000);
+        assert_eq!(config.taker_fee(stats.rolling_volume(11 * window + window / 3)), 500_000);
+        assert_eq!(Config { fee_tiers: Vec::new(), ..config }.taker_fee(u64::MAX), 1_000_000);
+    }
+
+    #[test]
+    fn test_stake_discount_schedule() {
+        use crate::staking::StakeAccount;
+
+        assert_eq!(StakeAccount::default().try_to_vec().unwrap().len(), StakeAccount::LEN);
+
+        let config = Config {
+            stake_discounts: vec![
+                StakeDiscount { min_stake: 1_000, discount: 100_000_000 },
+                StakeDiscount { min_stake: 10_000, discount: 250_000_000 },
+            ],
+            ..Config::default()
+        };
+        assert_eq!(config.stake_discount(0), 0);
+        assert_eq!(config.stake_discount(999), 0);
+        assert_eq!(config.stake_discount(1_000), 100_000_000);
+        assert_eq!(config.stake_discount(50_000), 250_000_000);
+        assert_eq!(Config::default().stake_discount(u64::MAX), 0);
+
+        // A 25% discount off a 0.08% tier fee leaves 0.06%
+        let fee_rate = 800_000;
+        let discounted = fee_rate - crate::math::mul_scaled(fee_rate, config.stake_discount(10_000)).unwrap();
+        assert_eq!(discounted, 600_000);
+    }
+
+    #[test]
+    fn test_trading_rewards_follow_schedule() {
+        use crate::rewards::{RewardsSchedule, UserRewards};
+
+        assert_eq!(RewardsSchedule::default().try_to_vec().unwrap().len(), RewardsSchedule::LEN);
+        assert_eq!(UserRewards::default().try_to_vec().unwrap().len(), UserRewards::LEN);
+
+        // 0.5 reward base units per quote token over slots 100..200, 1,000 units in total
+        let mut schedule = RewardsSchedule {
+            start_slot: 100,
+            end_slot: 200,
+            reward_per_volume: 500_000_000,
+            total_emissions: 1_000,
+            ..RewardsSchedule::default()
+        };
+        let notional = 1_000_000_000_000; // 1,000 quote tokens
+        assert_eq!(schedule.reward_for_volume(notional, 100).unwrap(), 500);
+        assert_eq!(schedule.reward_for_volume(notional, 99).unwrap(), 0);
+        assert_eq!(schedule.reward_for_volume(notional, 200).unwrap(), 0);
+
+        // The last trade within the budget gets what's left
+        schedule.emitted = 800;
+        assert_eq!(schedule.reward_for_volume(notional, 150).unwrap(), 200);
+        schedule.emitted = 1_000;
+        assert_eq!(schedule.reward_for_volume(notional, 150).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_liquidation_price_matches_min_ratio() {
+        use crate::calculate_liquidation_price;
+
+        let market_state = MarketState::default();
+        let long = Position {
+            base_amount: 1_000_000_000,
+            entry_price: 100_000_000_000,
+            collateral: 200_000_000_000,
+            ..Default::default()
+        };
+        let short = Position { base_amount: -1_000_000_000, ..long };
+
+        // At $200 the long holds $300 of equity on $200 notional; the short $180 on $120
+        assert_eq!(calculate_liquidation_price(&long, &market_state, 1_500_000_000).unwrap(), Some(200_000_000_000));
+        assert_eq!(calculate_liquidation_price(&short, &market_state, 1_500_000_000).unwrap(), Some(120_000_000_000));
+
+        // $10 of pending funding owed by the long comes out of its collateral
+        let funded = MarketState { funding_index: 10_000_000_000, ..market_state };
+        assert_eq!(calculate_liquidation_price(&long, &funded, 1_500_000_000).unwrap(), Some(180_000_000_000));
+
+        // Under a 50% minimum a $60-collateral long is liquidated below $80
+        let levered = Position { collateral: 60_000_000_000, ..long };
+        assert_eq!(calculate_liquidation_price(&levered, &market_state, 500_000_000).unwrap(), Some(80_000_000_000));
+
+        // An overcollateralized long never drops below 50%; a flat position has no price
+        assert_eq!(calculate_liquidation_price(&long, &market_state, 500_000_000).unwrap(), None);
+        assert_eq!(calculate_liquidation_price(&Position::default(), &market_state, 500_000_000).unwrap(), None);
+    }
+
+    #[test]
+    fn test_instruction_encoding_keeps_tags() {
+        use crate::instruction::PerpsInstruction;
+        use borsh::BorshDeserialize;
+
+        // Tag + little-endian fields, as the hand-built payloads were laid out
+        let open = PerpsInstruction::OpenPosition {
+            base_delta: -5,
+            collateral_delta: 7,
+            price_limit: 9,
+            flags: crate::OPEN_FLAG_REDUCE_ONLY,
+            sub_account_id: 2,
+        };
+        let mut expected = vec![0u8];
+        expected.extend_from_slice(&(-5i64).to_le_bytes());
+        expected.extend_from_slice(&7u64.to_le_bytes());
+        expected.extend_from_slice(&9u64.to_le_bytes());
+        expected.push(crate::OPEN_FLAG_REDUCE_ONLY);
+        expected.extend_from_slice(&2u16.to_le_bytes());
+        assert_eq!(open.try_to_vec().unwrap(), expected);
+        assert_eq!(PerpsInstruction::try_from_slice(&expected).unwrap(), open);
+
+        assert_eq!(PerpsInstruction::PauseMarket.try_to_vec().unwrap(), vec![14]);
+        assert_eq!(PerpsInstruction::GetLiquidationPrice.try_to_vec().unwrap(), vec![49]);
+
+        // Truncated payloads, trailing bytes and unknown tags are rejected
+        assert!(PerpsInstruction::try_from_slice(&expected[..24]).is_err());
+        assert!(PerpsInstruction::try_from_slice(&[1, 0]).is_err());
+        assert!(PerpsInstruction::try_from_slice(&[255]).is_err());
+    }
+
+    #[test]
+    fn test_unpack_accepts_versioned_and_pre_version_payloads() {
+        use crate::instruction::{PerpsInstruction, INSTRUCTION_VERSION};
+
+        let open = PerpsInstruction::OpenPosition {
+            base_delta: -5,
+            collateral_delta: 7,
+            price_limit: 9,
+            flags: 0,
+            sub_account_id: 2,
+        };
+        let legacy = open.try_to_vec().unwrap();
+        let packed = open.pack();
+        assert_eq!(packed[..2], [0, INSTRUCTION_VERSION]);
+        assert_eq!(packed[2..], legacy[1..]);
+        assert_eq!(PerpsInstruction::unpack(&packed).unwrap(), open);
+        assert_eq!(PerpsInstruction::unpack(&legacy).unwrap(), open);
+
+        assert_eq!(PerpsInstruction::PauseMarket.pack(), vec![14, INSTRUCTION_VERSION]);
+        assert_eq!(PerpsInstruction::unpack(&[14]).unwrap(), PerpsInstruction::PauseMarket);
+
+        // Variable-size payloads must carry the version
+        let backups = PerpsInstruction::SetBackupOracles { backup_oracles: vec![] };
+        assert_eq!(PerpsInstruction::unpack(&backups.pack()).unwrap(), backups);
+        assert!(PerpsInstruction::unpack(&backups.try_to_vec().unwrap()).is_err());
+
+        // Version 1 layouts still decode, except update_params, whose amounts changed units
+        let mut v1 = packed.clone();
+        v1[1] = 1;
+        assert_eq!(PerpsInstruction::unpack(&v1).unwrap(), open);
+        let update_params = PerpsInstruction::UpdateParams {
+            min_collateral_ratio: 1,
+            liquidator_fee_bps: 2,
+            trading_fee: 3,
+            referral_fee_share: 4,
+            max_funding_rate_per_slot: 5,
+            crank_reward: None,
+            insurance_fund_share: None,
+            dust_thresholds: None,
+            max_funding_settlement_share: None,
+            maker_rebate: None,
+            user_limits: None,
+        };
+        let mut v1 = update_params.pack();
+        assert_eq!(PerpsInstruction::unpack(&v1).unwrap(), update_params);
+        v1[1] = 1;
+        assert!(PerpsInstruction::unpack(&v1).is_err());
+
+        // Unknown versions, truncated payloads and empty data are rejected
+        assert!(PerpsInstruction::unpack(&[1, INSTRUCTION_VERSION + 1]).is_err());
+        assert!(PerpsInstruction::unpack(&packed[..20]).is_err());
+        assert!(PerpsInstruction::unpack(&[]).is_err());
+    }
+
+    #[test]
+    fn test_math_rounding_directions() {
+        use crate::math;
+
+        assert_eq!(math::mul_div(10, 10, 3).unwrap(), 33);
+        assert_eq!(math::mul_div_ceil(10, 10, 3).unwrap(), 34);
+        assert_eq!(math::mul_div_ceil(10, 3, 3).unwrap(), 10);
+
+        // Signed division truncates toward zero, so both sides round the same way
+        assert_eq!(math::mul_div_signed(10, 10, 3).unwrap(), 33);
+        assert_eq!(math::mul_div_signed(-10, 10, 3).unwrap(), -33);
+
+        // 1.5 * 2.5 = 3.75 and 1 / 3 = 0.333333333
+        assert_eq!(math::mul_scaled(1_500_000_000, 2_500_000_000).unwrap(), 3_750_000_000);
+        assert_eq!(math::div_scaled(1, 3).unwrap(), 333_333_333);
+        assert_eq!(math::mul_scaled_signed(-1_500_000_000, 3).unwrap(), -4);
+    }
+
+    #[test]
+    fn test_math_rejects_overflow_and_zero_denominators() {
+        use crate::math;
+        use solana_program::program_error::ProgramError;
+
+        assert_eq!(math::mul_div(u128::MAX, 2, 1), Err(ProgramError::InvalidArgument));
+        assert_eq!(math::mul_div(1, 1, 0), Err(ProgramError::InvalidAccountData));
+        assert_eq!(math::mul_div_ceil(1, 1, 0), Err(ProgramError::InvalidAccountData));
+        assert_eq!(math::mul_div_signed(1, 1, 0), Err(ProgramError::InvalidAccountData));
+        assert_eq!(math::mul_div_signed(i128::MIN, 1, -1), Err(ProgramError::InvalidArgument));
+
+        // Products wider than u64 are fine as long as the result fits
+        assert_eq!(math::mul_scaled(u64::MAX, 1_000_000_000).unwrap(), u64::MAX);
+        assert_eq!(math::mul_scaled(u64::MAX, 2_000_000_000), Err(ProgramError::InvalidArgument));
+        assert_eq!(math::div_scaled(1, 0), Err(ProgramError::InvalidAccountData));
+
+        // Ratios saturate instead of failing
+        assert_eq!(math::ratio(1, 0), u64::MAX);
+        assert_eq!(math::ratio(u128::MAX, 1), u64::MAX);
+        assert_eq!(math::ratio(1, 4), 250_000_000);
+    }
+
+    #[test]
+    fn test_market_decimals_normalize_amounts() {
+        use crate::math;
+
+        // Sizes in thousandths of a base token, a 6-decimal quote mint
+        let market_state = MarketState { base_decimals: 3, quote_decimals: 6, ..MarketState::default() };
+        assert_eq!(market_state.size_to_precision(-2_500).unwrap(), -2_500_000_000);
+        assert_eq!(market_state.quote_to_precision(1_500_000).unwrap(), 1_500_000_000);
+
+        // Payouts round down to whole quote units
+        assert_eq!(market_state.quote_from_precision(1_234_567_890).unwrap(), 1_234_567);
+
+        // 9 decimals is the identity; finer mints cannot be normalized
+        assert_eq!(math::to_precision(42, 9).unwrap(), 42);
+        assert_eq!(math::from_precision(42, 9).unwrap(), 42);
+        assert!(math::to_precision(1, 10).is_err());
+        assert!(market_state.size_to_precision(i64::MAX).is_err());
+    }
+
+    #[test]
+    fn test_dust_positions_close_and_sweep() {
+        use crate::{apply_min_position_size, sweep_dust_collateral};
+
+        let min_size = 1_000_000;
+
+        // A reduce that would leave dust closes the whole position
+        assert_eq!(apply_min_position_size(5_000_000, -4_500_000, min_size).unwrap(), -5_000_000);
+        assert_eq!(apply_min_position_size(-5_000_000, 4_500_000, min_size).unwrap(), 5_000_000);
+        assert_eq!(apply_min_position_size(5_000_000, -4_000_000, min_size).unwrap(), -4_000_000);
+        assert_eq!(apply_min_position_size(5_000_000, -5_000_000, min_size).unwrap(), -5_000_000);
+
+        // Opening below the minimum is rejected; deposits leave legacy dust alone
+        assert!(apply_min_position_size(0, 500_000, min_size).is_err());
+        assert_eq!(apply_min_position_size(500_000, 0, min_size).unwrap(), 0);
+
+        // Residual collateral below the threshold goes to the insurance fund once flat
+        let mut market_state = MarketState::default();
+        let mut position = Position { collateral: 400_000, ..Default::default() };
+        assert_eq!(sweep_dust_collateral(&mut position, &mut market_state, 1_000_000).unwrap(), 400_000);
+        assert_eq!((position.collateral, market_state.insurance_fund), (0, 400_000));
+
+        let mut open = Position { base_amount: 1, collateral: 400_000, ..Default::default() };
+        assert_eq!(sweep_dust_collateral(&mut open, &mut market_state, 1_000_000).unwrap(), 0);
+        let mut funded = Position { collateral: 1_000_000, ..Default::default() };
+        assert_eq!(sweep_dust_collateral(&mut funded, &mut market_state, 1_000_000).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_liquidation_base_delta_throttles_to_share() {
+        use crate::liquidation_base_delta;
+
+        // Unthrottled markets close the whole position
+        assert_eq!(liquidation_base_delta(5_000_000, 0).unwrap(), -5_000_000);
+        assert_eq!(liquidation_base_delta(-5_000_000, 1_000_000_000).unwrap(), 5_000_000);
+
+        // Throttled ones close their share, against the position's side
+        assert_eq!(liquidation_base_delta(5_000_000, 500_000_000).unwrap(), -2_500_000);
+        assert_eq!(liquidation_base_delta(-5_000_000, 200_000_000).unwrap(), 1_000_000);
+
+        // A share that rounds to nothing closes what is left
+        assert_eq!(liquidation_base_delta(1, 500_000_000).unwrap(), -1);
+    }
+
+    #[test]
+    fn test_collateral_deposits_are_capped_per_market() {
+        let mut market_state = MarketState { max_total_collateral: 1_000, ..Default::default() };
+        market_state.deposit_collateral(600).unwrap();
+        assert!(market_state.deposit_collateral(401).is_err());
+        assert_eq!(market_state.total_collateral, 600);
+        market_state.deposit_collateral(400).unwrap();
+
+        // Payouts free up room, and profits taken out floor the total at zero
+        market_state.withdraw_collateral(300);
+        market_state.deposit_collateral(300).unwrap();
+        market_state.withdraw_collateral(5_000);
+        assert_eq!(market_state.total_collateral, 0);
+
+        // A position releases everything it deposited, whatever its collateral became
+        let mut position = Position::default();
+        crate::count_deposit(&mut position, &mut market_state, 700).unwrap();
+        assert!(crate::count_deposit(&mut position, &mut market_state, 301).is_err());
+        assert_eq!((position.deposited_collateral, market_state.total_collateral), (700, 700));
+        position.collateral = 50; // lost to fees and losses
+        crate::release_deposits(&mut position, &mut market_state);
+        assert_eq!((position.deposited_collateral, market_state.total_collateral), (0, 0));
+
+        // So does a flat position whose collateral is used up, with or without a dust threshold
+        crate::count_deposit(&mut position, &mut market_state, 700).unwrap();
+        position.collateral = 0;
+        assert_eq!(crate::sweep_dust_collateral(&mut position, &mut market_state, 0).unwrap(), 0);
+        assert_eq!(market_state.total_collateral, 0);
+        crate::count_deposit(&mut position, &mut market_state, 700).unwrap();
+        position.collateral = 10;
+        assert_eq!(crate::sweep_dust_collateral(&mut position, &mut market_state, 100).unwrap(), 10);
+        assert_eq!(market_state.total_collateral, 0);
+
+        // Uncapped markets only count
+        let mut uncapped = MarketState::default();
+        uncapped.deposit_collateral(u64::MAX).unwrap();
+        assert_eq!(uncapped.total_collateral, u64::MAX);
+    }
+
+    #[test]
+    fn test_withdrawals_cool_down_and_are_capped_per_epoch() {
+        let mut market_state = MarketState { withdrawal_cooldown_slots: 100, max_epoch_withdrawal: 1_000, ..Default::default() };
+        let (mut alice, mut bob) = (Position::default(), Position::default());
+
+        // The first payout of an epoch always fits, even past the cap
+        market_state.record_withdrawal(&mut alice, 1_500, 10, 1).unwrap();
+        assert!(market_state.record_withdrawal(&mut bob, 1, 10, 1).is_err());
+        assert_eq!(bob.last_withdrawal_slot, 0);
+
+        // A new epoch resets the cap, but not a position's cooldown
+        assert!(market_state.record_withdrawal(&mut alice, 400, 109, 2).is_err());
+        market_state.record_withdrawal(&mut bob, 600, 109, 2).unwrap();
+        market_state.record_withdrawal(&mut alice, 400, 110, 2).unwrap();
+        assert!(market_state.record_withdrawal(&mut bob, 1, 500, 2).is_err());
+        assert_eq!((market_state.withdrawal_epoch, market_state.epoch_withdrawn), (2, 1_000));
+
+        // Empty payouts are never limited or recorded
+        market_state.record_withdrawal(&mut alice, 0, 111, 2).unwrap();
+        assert_eq!(alice.last_withdrawal_slot, 110);
+    }
+
+    #[cfg(feature = "client")]
+    #[test]
+    fn test_client_flags_and_liquidates_underwater_positions() {
+        use crate::client::{is_liquidatable, liquidate_instruction, liquidatable_positions};
+
+        let oracle = Pubkey::new_unique();
+        let config = Config {
+            min_collateral_ratio: 100_000_000,
+            collateral_assets: vec![
+                CollateralAsset { mint: Pubkey::new_unique(), ..Default::default() },
+                CollateralAsset { mint: Pubkey::new_unique(), oracle, ..Default::default() },
+            ],
+            restrict_liquidators: true,
+            ..Default::default()
+        };
+        let program_id = Pubkey::new_unique();
+        let (_, bump) = Pubkey::find_program_address(&[crate::PDA_SEED], &program_id);
+        let market_state = MarketState { bump, ..Default::default() };
+
+        // A $100 long on $10 collateral sits at the 10% minimum; a drop to $95 sinks it
+        let long = Position { base_amount: 1_000_000_000, entry_price: 100_000_000_000, collateral: 10_000_000_000, ..Default::default() };
+        assert!(!is_liquidatable(&long, &market_state, &config, 100_000_000_000).unwrap());
+        assert!(is_liquidatable(&long, &market_state, &config, 95_000_000_000).unwrap());
+        let cross = Position { margin_mode: crate::MARGIN_MODE_CROSS, ..long };
+        let positions = [(Pubkey::new_unique(), long), (Pubkey::new_unique(), cross)];
+        let flagged = liquidatable_positions(&positions, &market_state, &config, 95_000_000_000);
+        assert_eq!(flagged, vec![&positions[0]]);
+
+        // The instruction passes the oracle of each held asset, then the registry
+        let held = Position { collateral_balances: [0, 5, 0, 0], ..long };
+        let market = Pubkey::new_unique();
+        let liquidate = liquidate_instruction(
+            &program_id, &Pubkey::new_unique(), &Pubkey::new_unique(), &positions[0].0, &held, &market, &market_state, &config,
+        ).unwrap();
+        let trailing: Vec<Pubkey> = liquidate.accounts[9..].iter().map(|meta| meta.pubkey).collect();
+        assert_eq!(trailing, vec![oracle, crate::access::liquidator_registry_address(&program_id).0]);
+    }
+
+    #[cfg(feature = "client")]
+    #[test]
+    fn test_client_tracks_collateral_held_in_the_vault() {
+        use crate::client::{MarketHoldings, VaultHoldings, VaultSolvency};
+        use crate::cross_margin::UserAccount;
+        use crate::liquidity_pool::PoolState;
+
+        // Longs owe 20 of funding and shorts are owed 10; the residual 10 is already in the insurance fund
+        let market = Pubkey::new_unique();
+        let market_state = MarketState { insurance_fund: 15, fee_pool: 7, funding_index: 10, ..Default::default() };
+        let long = Position { base_amount: 2_000_000_000, collateral: 100, claimable_funding: 3, ..Default::default() };
+        let short = Position { base_amount: -1_000_000_000, collateral: 50, maker_rebates: 2, ..Default::default() };
+        let positions = vec![(Pubkey::new_unique(), long), (Pubkey::new_unique(), short)];
+        let mut holdings =
+            VaultHoldings { markets: vec![MarketHoldings { market, market_state, positions }], ..Default::default() };
+        assert_eq!(holdings.tracked_collateral().unwrap(), 22 + 83 + 62);
+
+        // Pools catch up on realized PnL; user accounts and referrers count too
+        holdings.markets[0].market_state.total_realized_loss = 5;
+        holdings.pools.push(PoolState { market, liquidity: 40, ..Default::default() });
+        holdings.user_accounts.push(UserAccount { collateral: 30, ..Default::default() });
+        holdings.referrers.push(Referrer { claimable_fees: 4, ..Default::default() });
+        assert_eq!(holdings.tracked_collateral().unwrap(), 167 + 45 + 30 + 4);
+
+        // An isolated position owing more funding than it holds counts as empty
+        holdings.markets[0].positions[0].1.collateral = 10;
+        assert_eq!(holdings.tracked_collateral().unwrap(), 22 + 3 + 62 + 45 + 30 + 4);
+
+        assert!(VaultSolvency { vault_balance: 167, tracked_collateral: 167 }.is_solvent());
+        assert!(!VaultSolvency { vault_balance: 166, tracked_collateral: 167 }.is_solvent());
+    }
+
+    #[cfg(feature = "keeper")]
+    #[test]
+    fn test_keeper_flags_before_liquidating_in_grace_markets() {
+        use crate::keeper::{position_action, KeeperAction};
+
+        let config = Config { min_collateral_ratio: 100_000_000, ..Default::default() };
+        let healthy_market = MarketState { mark_price: 100_000_000_000, ..Default::default() };
+        let market = MarketState { mark_price: 95_000_000_000, ..Default::default() };
+
+        // The $100 long on $10 is healthy at $100 and liquidated outright at $95 (~5.3%)
+        let long = Position { base_amount: 1_000_000_000, entry_price: 100_000_000_000, collateral: 10_000_000_000, ..Default::default() };
+        assert_eq!(position_action(&long, &healthy_market, &config, 10).unwrap(), None);
+        assert_eq!(position_action(&long, &market, &config, 10).unwrap(), Some(KeeperAction::Liquidate));
+        let throttled = Position { next_liquidation_slot: 11, ..long };
+        assert_eq!(position_action(&throttled, &market, &config, 10).unwrap(), None);
+
+        // Above a 5% floor it is flagged first, then liquidated once the grace period is over
+        let grace = MarketState { liquidation_grace_slots: 100, liquidation_floor_ratio: 50_000_000, ..market };
+        assert_eq!(position_action(&long, &grace, &config, 10).unwrap(), Some(KeeperAction::Flag));
+        let flagged = Position { margin_call_slot: 10, ..long };
+        assert_eq!(position_action(&flagged, &grace, &config, 109).unwrap(), None);
+        assert_eq!(position_action(&flagged, &grace, &config, 110).unwrap(), Some(KeeperAction::Liquidate));
+
+        // Below the floor it doesn't wait
+        let floor = MarketState { liquidation_floor_ratio: 60_000_000, ..grace };
+        assert_eq!(position_action(&long, &floor, &config, 10).unwrap(), Some(KeeperAction::Liquidate));
+    }
+}
+
+/// Property tests of the funding, PnL and collateral ratio math against an
+/// exact i128/u128 reference model, cross-checked in f64
+#[cfg(test)]
+mod properties {
+    use super::*;
+    use proptest::prelude::*;
+
+    const PRECISION: i128 = 1_000_000_000;
+
+    /// Up to $1B per unit, so reference products stay inside i128
+    const MAX_PRICE: u64 = 1_000_000_000_000_000_000;
+
+    /// Up to 1,000,000 units per position
+    const MAX_SIZE: i64 = 1_000_000_000_000_000;
+
+    fn price() -> impl Strategy<Value = u64> {
+        0..=MAX_PRICE
+    }
+
+    fn base_amount() -> impl Strategy<Value = i64> {
+        prop_oneof![-MAX_SIZE..=-1, 1..=MAX_SIZE]
+    }
+
+    fn position(base_amount: i64, collateral: u64, entry_price: u64) -> Position {
+        Position { base_amount, collateral, entry_price, version: Position::VERSION, ..Default::default() }
+    }
+
+    /// size * (mark - entry) / 1e9, signed by direction and rounded toward zero
+    fn reference_pnl(base_amount: i64, entry_price: u64, mark_price: u64) -> i128 {
+        let price_move = mark_price as i128 - entry_price as i128;
+        base_amount.signum() as i128 * (price_move * base_amount.unsigned_abs() as i128 / PRECISION)
+    }
+
+    /// Whether `value` is within one base unit (plus f64 rounding) of `reference`
+    fn close_to(value: i128, reference: f64) -> bool {
+        (value as f64 - reference).abs() <= 1.0 + reference.abs() * 1e-12
+    }
+
+    proptest! {
+        #[test]
+        fn prop_unrealized_pnl_matches_reference(
+            base_amount in base_amount(), entry_price in price(), mark_price in price(),
+        ) {
+            let expected = reference_pnl(base_amount, entry_price, mark_price);
+            match calculate_unrealized_pnl(&position(base_amount, 0, entry_price), mark_price) {
+                Ok(pnl) => {
+                    prop_assert_eq!(pnl as i128, expected);
+                    let pnl_f64 = base_amount as f64 * (mark_price as f64 - entry_price as f64) / 1e9;
+                    prop_assert!(close_to(pnl as i128, pnl_f64), "{} vs {}", pnl, pnl_f64);
+                }
+                // Only PnL that cannot be represented is rejected
+                Err(_) => prop_assert!(i64::try_from(expected).is_err()),
+            }
+        }
+
+        #[test]
+        fn prop_long_and_short_pnl_cancel(
+            size in 1..=MAX_SIZE, entry_price in price(), mark_price in price(),
+        ) {
+            let long = calculate_unrealized_pnl(&position(size, 0, entry_price), mark_price);
+            let short = calculate_unrealized_pnl(&position(-size, 0, entry_price), mark_price);
+            if let (Ok(long), Ok(short)) = (long, short) {
+                prop_assert_eq!(long + short, 0);
+            }
+        }
+
+        #[test]
+        fn prop_closing_at_mark_realizes_unrealized_pnl(
+            base_amount in base_amount(), entry_price in price(), mark_price in price(),
+        ) {
+            let unrealized = calculate_unrealized_pnl(&position(base_amount, 0, entry_price), mark_price);
+            let realized = crate::calculate_realized_pnl(base_amount, entry_price, -base_amount, mark_price);
+            prop_assert_eq!(realized.ok(), unrealized.ok());
+        }
+
+        #[test]
+        fn prop_funding_matches_reference(
+            base_amount in base_amount(),
+            collateral in 0..=u64::MAX / 2,
+            funding_delta in -1_000_000_000_000i64..=1_000_000_000_000,
+        ) {
+            let mut funded = position(base_amount, collateral, 0);
+            let mut market_state = MarketState { funding_index: funding_delta, ..Default::default() };
+            let payment = base_amount as i128 * funding_delta as i128 / PRECISION;
+            prop_assert!(close_to(payment, base_amount as f64 * funding_delta as f64 / 1e9));
+
+            match crate::apply_funding(&mut funded, &mut market_state, 0) {
+                Ok(()) => {
+                    prop_assert_eq!(
+                        funded.collateral as i128 + funded.claimable_funding as i128,
+                        collateral as i128 - payment
+                    );
+                    prop_assert_eq!(funded.cumulative_funding as i128, -payment);
+                    prop_assert_eq!(funded.last_funding_index, funding_delta);
+                }
+                // Only a payment larger than the collateral is rejected
+                Err(error) => {
+                    prop_assert_eq!(error, solana_program::program_error::ProgramError::InsufficientFunds);
+                    prop_assert!(payment > collateral as i128);
+                }
+            }
+        }
+
+        #[test]
+        fn prop_funding_is_exact_or_rejected(
+            base_amount in any::<i64>(), collateral in any::<u64>(), funding_delta in any::<i64>(),
+        ) {
+            let mut funded = position(base_amount, collateral, 0);
+            let mut market_state = MarketState { funding_index: funding_delta, ..Default::default() };
+            let payment = base_amount as i128 * funding_delta as i128 / PRECISION;
+
+            if crate::apply_funding(&mut funded, &mut market_state, 0).is_ok() {
+                prop_assert_eq!(funded.collateral as i128 + funded.claimable_funding as i128, collateral as i128 - payment);
+                prop_assert_eq!(funded.cumulative_funding as i128, -payment);
+            }
+        }
+
+        #[test]
+        fn prop_funding_is_zero_sum(
+            size in 1..=MAX_SIZE,
+            funding_delta in -1_000_000_000_000i64..=1_000_000_000_000,
+        ) {
+            let collateral = u64::MAX / 2;
+            let (mut long, mut short) = (position(size, collateral, 0), position(-size, collateral, 0));
+            let mut market_state = MarketState { funding_index: funding_delta, ..Default::default() };
+            crate::apply_funding(&mut long, &mut market_state, 0).unwrap();
+            crate::apply_funding(&mut short, &mut market_state, 0).unwrap();
+
+            let funds = |position: &Position| position.collateral as i128 + position.claimable_funding as i128;
+            prop_assert_eq!(funds(&long) + funds(&short), 2 * collateral as i128);
+        }
+
+        #[test]
+        fn prop_skewed_funding_balances_with_insurance_fund(
+            long_size in 1..=MAX_SIZE,
+            short_size in 1..=MAX_SIZE,
+            funding_delta in -1_000_000_000i64..=1_000_000_000,
+        ) {
+            let collateral = u64::MAX / 2;
+            let (mut long, mut short) = (position(long_size, collateral, 0), position(-short_size, collateral, 0));
+            let mut market_state = MarketState {
+                funding_index: funding_delta,
+                long_base_amount: long_size,
+                net_base_amount: long_size - short_size,
+                ..Default::default()
+            };
+            market_state.accrue_side_funding(funding_delta).unwrap();
+            crate::apply_funding(&mut long, &mut market_state, 0).unwrap();
+            crate::apply_funding(&mut short, &mut market_state, 0).unwrap();
+
+            let funds = |position: &Position| position.collateral as i128 + position.claimable_funding as i128;
+            let books = funds(&long) + funds(&short) + market_state.insurance_fund as i128;
+            prop_assert_eq!(books - market_state.bad_debt as i128, 2 * collateral as i128);
+        }
+
+        #[test]
+        fn prop_collateral_ratio_matches_reference(
+            base_amount in base_amount(), collateral in any::<u64>(), mark_price in price(),
+        ) {
+            let health = calculate_position_health(&position(base_amount, collateral, 0), mark_price).unwrap();
+            let value = base_amount.unsigned_abs() as u128 * mark_price as u128 / PRECISION as u128;
+            match (collateral as u128 * PRECISION as u128).checked_div(value) {
+                None => prop_assert_eq!(health, u64::MAX),
+                Some(expected) => {
+                    prop_assert_eq!(health, u64::try_from(expected).unwrap_or(u64::MAX));
+                    if value >= PRECISION as u128 && health < u64::MAX {
+                        let ratio_f64 = collateral as f64 * 1e9 / value as f64;
+                        prop_assert!((health as f64 - ratio_f64).abs() <= 1.0 + ratio_f64 * 1e-12);
+                    }
+                }
+            }
+        }
+
+        #[test]
+        fn prop_collateral_ratio_falls_with_price(
+            base_amount in base_amount(), collateral in any::<u64>(), low in price(), high in price(),
+        ) {
+            let (low, high) = (low.min(high), low.max(high));
+            let held = position(base_amount, collateral, 0);
+            prop_assert!(
+                calculate_position_health(&held, high).unwrap() <= calculate_position_health(&held, low).unwrap()
+            );
+        }
+    }
+}
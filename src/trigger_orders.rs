@@ -0,0 +1,716 @@
+//! Stop-loss and take-profit trigger orders
+//!
+//! Each trigger is its own PDA ([TRIGGER_SEED, position, trigger_id]) so a
+//! position can carry several at once. Keepers call `execute_trigger_order`
+//! once the market price crosses the trigger; the position is reduced (or
+//! flattened) through the vAMM and the keeper is paid a small reward out of the
+//! position's collateral. The market's mark price is the trigger reference.
+//!
+//! Every pending trigger is also listed in its market's TriggerQueue PDA
+//! ([TRIGGER_QUEUE_SEED, market_state]), sorted by trigger price, so keepers
+//! can find the triggers near the mark price by reading one account instead of
+//! scanning every trigger and position account.
+//!
+//! A stop-loss and a take-profit of the same position can be linked into a
+//! one-cancels-other pair with `link_trigger_orders`: executing either one
+//! closes the other in the same instruction, so a position can't be reduced
+//! twice by triggers meant as alternatives. Cancelling one of the pair leaves
+//! the other in place, unlinked.
+
+use borsh::{BorshDeserialize, BorshSerialize};
+use shank::ShankAccount;
+use solana_program::{
+    account_info::{next_account_info, AccountInfo},
+    clock::Clock,
+    entrypoint::ProgramResult,
+    msg,
+    program::invoke_signed,
+    program_error::ProgramError,
+    pubkey::Pubkey,
+    system_instruction,
+    sysvar::{rent::Rent, Sysvar},
+};
+
+use crate::{
+    apply_funding, apply_min_position_size, apply_position_change, calculate_trading_fee, charge_skew_fee, close_program_account,
+    create_transfer_checked_instruction, emit_bad_debt, emit_vamm_trade, execute_vamm_trade, load_account, load_config, store_account,
+    require_active, require_hold_elapsed, require_isolated, require_not_settlement_only, require_position_owner, require_vault_covers, sweep_dust_collateral, validate_quote_mint,
+    validate_market_vault, validate_position_address, validate_token_account, AccountType, MarketState, Position, DISCRIMINATOR_LEN, PDA_SEED,
+};
+use crate::math;
+
+/// Seed prefix for trigger order PDAs: [TRIGGER_SEED, position, trigger_id]
+pub const TRIGGER_SEED: &[u8] = b"trigger";
+
+/// Seed prefix for trigger queue PDAs: [TRIGGER_QUEUE_SEED, market_state]
+pub const TRIGGER_QUEUE_SEED: &[u8] = b"trigger_queue";
+
+/// Maximum number of pending triggers per market
+pub const MAX_QUEUED_TRIGGERS: usize = 64;
+
+/// Reward paid to the keeper that executes a trigger (0.1 quote units, 1e9 precision)
+pub const TRIGGER_EXECUTION_REWARD: u64 = 100_000_000;
+
+/// What a trigger order protects
+#[derive(BorshSerialize, BorshDeserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TriggerKind {
+    /// Fires when price moves against the position
+    StopLoss,
+    /// Fires when price moves in favour of the position
+    TakeProfit,
+}
+
+/// A pending stop-loss or take-profit order
+#[derive(BorshSerialize, BorshDeserialize, Debug, Clone, PartialEq, Eq, ShankAccount)]
+pub struct TriggerOrder {
+    /// Position owner who placed the trigger
+    pub owner: Pubkey,
+    /// Position the trigger reduces
+    pub position: Pubkey,
+    /// Market state account the position trades in
+    pub market: Pubkey,
+    /// Client-chosen id, part of the PDA seeds
+    pub trigger_id: u64,
+    /// Stop-loss or take-profit
+    pub kind: TriggerKind,
+    /// Price at which the trigger fires (1e9 precision)
+    pub trigger_price: u64,
+    /// Size to reduce by when fired (0 = the whole position)
+    pub base_amount: u64,
+    /// PDA bump for [TRIGGER_SEED, position, trigger_id]
+    pub bump: u8,
+    /// Other order of its one-cancels-other pair (default = unlinked)
+    pub linked_trigger: Pubkey,
+}
+
+impl AccountType for TriggerOrder {
+    const DISCRIMINATOR: [u8; DISCRIMINATOR_LEN] = *b"trigger\0";
+    /// owner + position + market + trigger_id + kind + trigger_price + base_amount + bump + linked_trigger
+    const LEN: usize = 32 + 32 + 32 + 8 + 1 + 8 + 8 + 1 + 32;
+}
+
+/// A pending trigger as listed in its market's queue
+#[derive(BorshSerialize, BorshDeserialize, Debug, Clone, PartialEq, Eq)]
+pub struct QueuedTrigger {
+    /// Trigger order account
+    pub trigger: Pubkey,
+    /// Position the trigger reduces
+    pub position: Pubkey,
+    /// Stop-loss or take-profit
+    pub kind: TriggerKind,
+    /// Price at which the trigger fires (1e9 precision)
+    pub trigger_price: u64,
+}
+
+impl QueuedTrigger {
+    /// Serialized size: trigger + position + kind + trigger_price
+    pub const LEN: usize = 32 + 32 + 1 + 8;
+}
+
+/// Pending triggers of a single market for keepers to scan
+#[derive(BorshSerialize, BorshDeserialize, Debug, Default, Clone, ShankAccount)]
+pub struct TriggerQueue {
+    /// Market state account this queue belongs to
+    pub market: Pubkey,
+    /// Pending triggers, sorted by trigger price (oldest first among equal prices)
+    pub entries: Vec<QueuedTrigger>,
+    /// PDA bump for [TRIGGER_QUEUE_SEED, market]
+    pub bump: u8,
+}
+
+impl AccountType for TriggerQueue {
+    const DISCRIMINATOR: [u8; DISCRIMINATOR_LEN] = *b"trigqueu";
+    /// market + vec length + MAX_QUEUED_TRIGGERS entries + bump
+    const LEN: usize = 32 + 4 + MAX_QUEUED_TRIGGERS * QueuedTrigger::LEN + 1;
+}
+
+impl TriggerQueue {
+    /// Deserialize from account data; the unused tail of the allocation is ignored
+    pub fn load(data: &[u8]) -> Result<Self, ProgramError> {
+        load_account(data)
+    }
+
+    /// Add an entry, keeping the queue sorted by trigger price
+    pub fn insert(&mut self, entry: QueuedTrigger) -> ProgramResult {
+        if self.entries.len() >= MAX_QUEUED_TRIGGERS {
+            msg!("Trigger queue is full ({} triggers)", MAX_QUEUED_TRIGGERS);
+            return Err(ProgramError::AccountDataTooSmall);
+        }
+
+        let index = self.entries.partition_point(|queued| queued.trigger_price <= entry.trigger_price);
+        self.entries.insert(index, entry);
+        Ok(())
+    }
+
+    /// Remove the entry for `trigger`, returning whether it was queued
+    pub fn remove(&mut self, trigger: &Pubkey) -> bool {
+        let len = self.entries.len();
+        self.entries.retain(|queued| queued.trigger != *trigger);
+        self.entries.len() != len
+    }
+}
+
+impl TriggerOrder {
+    /// Whether the trigger is one of a one-cancels-other pair
+    pub fn is_linked(&self) -> bool {
+        self.linked_trigger != Pubkey::default()
+    }
+
+    /// Whether the trigger fires at `price` for a position of `base_amount`
+    ///
+    /// Longs stop out below the trigger and take profit above it; shorts the
+    /// reverse. A flat position never triggers.
+    pub fn is_triggered(&self, position_base_amount: i64, price: u64) -> bool {
+        match (self.kind, position_base_amount.signum()) {
+            (TriggerKind::StopLoss, 1) | (TriggerKind::TakeProfit, -1) => price <= self.trigger_price,
+            (TriggerKind::StopLoss, -1) | (TriggerKind::TakeProfit, 1) => price >= self.trigger_price,
+            _ => false,
+        }
+    }
+
+    /// Signed size change that reduces a position of `position_base_amount`
+    pub fn reduce_delta(&self, position_base_amount: i64) -> Result<i64, ProgramError> {
+        let position_size = position_base_amount.unsigned_abs();
+        let size = if self.base_amount == 0 {
+            position_size
+        } else {
+            self.base_amount.min(position_size)
+        };
+        let size = i64::try_from(size).map_err(|_| ProgramError::InvalidArgument)?;
+
+        Ok(if position_base_amount > 0 { -size } else { size })
+    }
+}
+
+// ---------------------------------------------------------------------
+// 9️⃣ Place a stop-loss / take-profit trigger
+// ---------------------------------------------------------------------
+pub fn place_trigger_order(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    trigger_id: u64,
+    kind: TriggerKind,
+    trigger_price: u64,
+    base_amount: u64,
+) -> ProgramResult {
+    // Accounts:
+    // 0. [signer, writable] user (position owner, pays for the trigger account)
+    // 1. [] position account
+    // 2. [] market state account
+    // 3. [writable] trigger order account (PDA: [TRIGGER_SEED, position, trigger_id])
+    // 4. [] rent sysvar
+    // 5. [] system program
+    // 6. [writable] trigger queue account (PDA: [TRIGGER_QUEUE_SEED, market_state])
+    let accounts_iter = &mut accounts.iter();
+    let user = next_account_info(accounts_iter)?;
+    let position_acc = next_account_info(accounts_iter)?;
+    let market_state_acc = next_account_info(accounts_iter)?;
+    let trigger_acc = next_account_info(accounts_iter)?;
+    let rent_sysvar = next_account_info(accounts_iter)?;
+    let system_program = next_account_info(accounts_iter)?;
+    let queue_acc = next_account_info(accounts_iter)?;
+
+    if !user.is_signer {
+        msg!("User must be signer");
+        return Err(ProgramError::MissingRequiredSignature);
+    }
+
+    if trigger_price == 0 {
+        msg!("Trigger price must be non-zero");
+        return Err(ProgramError::InvalidArgument);
+    }
+
+    if position_acc.owner != program_id || market_state_acc.owner != program_id {
+        msg!("Position and market state must be owned by the program");
+        return Err(ProgramError::IncorrectProgramId);
+    }
+
+    let position_data = position_acc.try_borrow_data()?;
+    let position = Position::load(&position_data)?;
+    require_position_owner(position, user.key)?;
+    // execute_trigger_order trusts the stored market/position pair
+    validate_position_address(program_id, position_acc.key, market_state_acc.key, position)?;
+
+    require_isolated(position)?;
+
+    // Sizes arrive in the market's base units; triggers store them in 1e9 precision
+    let base_decimals = MarketState::load(&market_state_acc.try_borrow_data()?)?.base_decimals;
+    let base_amount = math::to_precision(base_amount, base_decimals)?;
+
+    let trigger_id_bytes = trigger_id.to_le_bytes();
+    let (expected_trigger, bump) = Pubkey::find_program_address(
+        &[TRIGGER_SEED, position_acc.key.as_ref(), &trigger_id_bytes],
+        program_id,
+    );
+    if *trigger_acc.key != expected_trigger {
+        msg!("Trigger account is not the correct PDA. Expected: {}, Got: {}",
+             expected_trigger, trigger_acc.key);
+        return Err(ProgramError::InvalidArgument);
+    }
+
+    if !trigger_acc.data_is_empty() {
+        msg!("Trigger order {} already exists", trigger_id);
+        return Err(ProgramError::AccountAlreadyInitialized);
+    }
+
+    let rent = Rent::from_account_info(rent_sysvar)?;
+
+    // ---------- Initialize trigger queue if empty ----------
+    if queue_acc.data_is_empty() {
+        let (expected_queue, queue_bump) = Pubkey::find_program_address(
+            &[TRIGGER_QUEUE_SEED, market_state_acc.key.as_ref()],
+            program_id,
+        );
+        if *queue_acc.key != expected_queue {
+            msg!("Trigger queue is not the correct PDA. Expected: {}, Got: {}",
+                 expected_queue, queue_acc.key);
+            return Err(ProgramError::InvalidArgument);
+        }
+
+        let create_queue_ix = system_instruction::create_account(
+            user.key,
+            queue_acc.key,
+            rent.minimum_balance(TriggerQueue::SPACE),
+            TriggerQueue::SPACE as u64,
+            program_id,
+        );
+
+        invoke_signed(&create_queue_ix, &[
+            user.clone(),
+            queue_acc.clone(),
+            system_program.clone(),
+        ], &[&[TRIGGER_QUEUE_SEED, market_state_acc.key.as_ref(), &[queue_bump]]])?;
+
+        let queue = TriggerQueue {
+            market: *market_state_acc.key,
+            entries: Vec::new(),
+            bump: queue_bump,
+        };
+        store_account(&queue, &mut queue_acc.data.borrow_mut())?;
+        msg!("Initialized trigger queue for market: {}", market_state_acc.key);
+    }
+
+    let mut queue = load_trigger_queue(program_id, queue_acc, market_state_acc.key)?;
+    queue.insert(QueuedTrigger {
+        trigger: *trigger_acc.key,
+        position: *position_acc.key,
+        kind,
+        trigger_price,
+    })?;
+
+    let create_trigger_ix = system_instruction::create_account(
+        user.key,
+        trigger_acc.key,
+        rent.minimum_balance(TriggerOrder::SPACE),
+        TriggerOrder::SPACE as u64,
+        program_id,
+    );
+
+    invoke_signed(&create_trigger_ix, &[
+        user.clone(),
+        trigger_acc.clone(),
+        system_program.clone(),
+    ], &[&[TRIGGER_SEED, position_acc.key.as_ref(), &trigger_id_bytes, &[bump]]])?;
+
+    let trigger = TriggerOrder {
+        owner: *user.key,
+        position: *position_acc.key,
+        market: *market_state_acc.key,
+        trigger_id,
+        kind,
+        trigger_price,
+        base_amount,
+        bump,
+        linked_trigger: Pubkey::default(),
+    };
+    store_account(&trigger, &mut trigger_acc.data.borrow_mut())?;
+    store_account(&queue, &mut queue_acc.data.borrow_mut())?;
+
+    msg!("Trigger order placed: id={}, kind={:?}, trigger_price={}, size={}",
+         trigger_id, kind, trigger_price, base_amount);
+
+    Ok(())
+}
+
+// ---------------------------------------------------------------------
+// 🔟 Cancel a trigger order
+// ---------------------------------------------------------------------
+pub fn cancel_trigger_order(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramResult {
+    // Accounts:
+    // 0. [signer, writable] trigger owner (receives the account's rent)
+    // 1. [writable] trigger order account
+    // 2. [writable] trigger queue account of the trigger's market
+    // 3. [writable] the other order of the trigger's one-cancels-other pair (linked triggers only; left unlinked)
+    let accounts_iter = &mut accounts.iter();
+    let user = next_account_info(accounts_iter)?;
+    let trigger_acc = next_account_info(accounts_iter)?;
+    let queue_acc = next_account_info(accounts_iter)?;
+    let linked_acc = next_account_info(accounts_iter).ok();
+
+    if !user.is_signer {
+        msg!("User must be signer");
+        return Err(ProgramError::MissingRequiredSignature);
+    }
+
+    let trigger = load_trigger_order(program_id, trigger_acc)?;
+    if trigger.owner != *user.key {
+        msg!("Trigger owner mismatch. Expected: {}, Got: {}", trigger.owner, user.key);
+        return Err(ProgramError::IllegalOwner);
+    }
+
+    if let Some((linked_acc, mut linked)) = load_linked_trigger(program_id, &trigger, trigger_acc.key, linked_acc)? {
+        linked.linked_trigger = Pubkey::default();
+        store_account(&linked, &mut linked_acc.data.borrow_mut())?;
+        msg!("Trigger order {} unlinked", linked.trigger_id);
+    }
+
+    dequeue_trigger(program_id, queue_acc, &trigger, trigger_acc.key)?;
+    close_program_account(trigger_acc, user)?;
+
+    msg!("Trigger order cancelled: id={}", trigger.trigger_id);
+
+    Ok(())
+}
+
+// ---------------------------------------------------------------------
+// 1️⃣1️⃣ Execute a trigger order (keeper)
+// ---------------------------------------------------------------------
+pub fn execute_trigger_order(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramResult {
+    // Accounts:
+    // 0. [signer] keeper
+    // 1. [] token program
+    // 2. [writable] keeper's token account (to receive the execution reward)
+    // 3. [writable] vault token account (PDA‑owned)
+    // 4. [writable] position account
+    // 5. [writable] market state account
+    // 6. [writable] trigger order account
+    // 7. [writable] trigger owner (receives the trigger account's rent)
+    // 8. [] config account
+    // 9. [] quote mint
+    // 10. [writable] trigger queue account of the market
+    // 11. [writable] the other order of the trigger's one-cancels-other pair (linked triggers only; closed too)
+    let accounts_iter = &mut accounts.iter();
+    let keeper = next_account_info(accounts_iter)?;
+    let token_program = next_account_info(accounts_iter)?;
+    let keeper_token_acc = next_account_info(accounts_iter)?;
+    let vault = next_account_info(accounts_iter)?;
+    let position_acc = next_account_info(accounts_iter)?;
+    let market_state_acc = next_account_info(accounts_iter)?;
+    let trigger_acc = next_account_info(accounts_iter)?;
+    let owner = next_account_info(accounts_iter)?;
+    let config_acc = next_account_info(accounts_iter)?;
+    let quote_mint = next_account_info(accounts_iter)?;
+    let queue_acc = next_account_info(accounts_iter)?;
+    let linked_acc = next_account_info(accounts_iter).ok();
+
+    if !keeper.is_signer {
+        msg!("Keeper must be signer");
+        return Err(ProgramError::MissingRequiredSignature);
+    }
+
+    let config = load_config(program_id, config_acc)?;
+    require_not_settlement_only(&config)?;
+    let quote_decimals = validate_quote_mint(&config, token_program, quote_mint)?;
+    let trigger = load_trigger_order(program_id, trigger_acc)?;
+    if trigger.position != *position_acc.key || trigger.market != *market_state_acc.key {
+        msg!("Trigger order does not belong to this position and market");
+        return Err(ProgramError::InvalidArgument);
+    }
+    if trigger.owner != *owner.key {
+        msg!("Trigger owner mismatch. Expected: {}, Got: {}", trigger.owner, owner.key);
+        return Err(ProgramError::IllegalOwner);
+    }
+    let linked = load_linked_trigger(program_id, &trigger, trigger_acc.key, linked_acc)?;
+
+    if market_state_acc.owner != program_id {
+        msg!("Market state must be owned by the program");
+        return Err(ProgramError::IncorrectProgramId);
+    }
+
+    // The position account can be closed under its triggers; clean them up without a reward
+    if position_acc.owner != program_id {
+        dequeue_trigger(program_id, queue_acc, &trigger, trigger_acc.key)?;
+        close_program_account(trigger_acc, owner)?;
+        close_linked_trigger(program_id, queue_acc, linked, owner)?;
+        msg!("Position account is closed, trigger order {} removed", trigger.trigger_id);
+        return Ok(());
+    }
+
+    validate_token_account(token_program.key, keeper_token_acc, quote_mint.key, None)?;
+
+    let mut market_state_data = market_state_acc.try_borrow_mut_data()?;
+    let market_state = MarketState::load_mut(&mut market_state_data)?;
+    require_active(market_state)?;
+    let (pda, bump) = validate_market_vault(program_id, token_program, vault, market_state, quote_mint)?;
+    let mut position_data = position_acc.try_borrow_mut_data()?;
+    let position = Position::load_mut(&mut position_data)?;
+
+    if position.owner != trigger.owner {
+        msg!("Position owner changed since the trigger was placed");
+        return Err(ProgramError::IllegalOwner);
+    }
+
+    if position.base_amount == 0 {
+        // Nothing left to protect; clean up the stale trigger (and its pair) without paying a reward
+        dequeue_trigger(program_id, queue_acc, &trigger, trigger_acc.key)?;
+        close_program_account(trigger_acc, owner)?;
+        close_linked_trigger(program_id, queue_acc, linked, owner)?;
+        msg!("Position is flat, trigger order {} removed", trigger.trigger_id);
+        return Ok(());
+    }
+
+    require_isolated(position)?;
+    require_hold_elapsed(position, market_state, Clock::get()?.slot)?;
+
+    if !trigger.is_triggered(position.base_amount, market_state.mark_price) {
+        msg!("Trigger not reached: mark_price={}, trigger_price={}",
+             market_state.mark_price, trigger.trigger_price);
+        return Err(ProgramError::InvalidArgument);
+    }
+
+    // ---------- Reduce the position through the vAMM ----------
+    let bad_debt_before = market_state.bad_debt;
+    apply_funding(position, market_state, config.max_funding_settlement_share)?;
+
+    let base_delta = apply_min_position_size(
+        position.base_amount,
+        trigger.reduce_delta(position.base_amount)?,
+        config.min_position_size,
+    )?;
+    let fill = execute_vamm_trade(market_state, base_delta)?;
+    emit_vamm_trade(market_state_acc.key, position_acc.key, base_delta, &fill, market_state);
+    apply_position_change(position, market_state, base_delta, fill.fill_price)?;
+    emit_bad_debt(market_state_acc.key, position_acc.key, market_state, bad_debt_before);
+    charge_skew_fee(&mut position.collateral, market_state, base_delta, fill.fill_price)?;
+
+    let trading_fee = calculate_trading_fee(base_delta, fill.fill_price, config.trading_fee)?
+        .min(position.collateral);
+    position.collateral -= trading_fee;
+    market_state.fee_pool = market_state
+        .fee_pool
+        .checked_add(trading_fee)
+        .ok_or(ProgramError::InvalidArgument)?;
+
+    // ---------- Pay the keeper from the position's collateral ----------
+    let reward = TRIGGER_EXECUTION_REWARD.min(position.collateral);
+    if reward > 0 {
+        let seeds = &[PDA_SEED, &[bump]];
+        let signer_seeds = &[&seeds[..]];
+
+        let transfer_ix = create_transfer_checked_instruction(
+            token_program.key,
+            vault.key,
+            quote_mint.key,
+            keeper_token_acc.key,
+            &pda,
+            market_state.quote_from_precision(reward)?,
+            quote_decimals,
+        )?;
+
+        invoke_signed(&transfer_ix, &[
+            vault.clone(),
+            quote_mint.clone(),
+            keeper_token_acc.clone(),
+            vault.clone(), // PDA authority
+            token_program.clone(),
+        ], signer_seeds)?;
+        require_vault_covers(vault, market_state.reserves(), quote_decimals)?;
+
+        position.collateral -= reward;
+    }
+    sweep_dust_collateral(position, market_state, config.dust_collateral)?;
+
+    if position.base_amount == 0 {
+        position.entry_price = 0;
+    }
+
+    // Persist changes; the other order of a one-cancels-other pair goes with this one
+    dequeue_trigger(program_id, queue_acc, &trigger, trigger_acc.key)?;
+    close_program_account(trigger_acc, owner)?;
+    close_linked_trigger(program_id, queue_acc, linked, owner)?;
+
+    msg!("Trigger order executed: id={}, kind={:?}, fill_price={}, size={}, keeper_reward={}",
+         trigger.trigger_id, trigger.kind, fill.fill_price, base_delta, reward);
+
+    Ok(())
+}
+
+// ---------------------------------------------------------------------
+// 8️⃣6️⃣ Link a stop-loss and a take-profit into a one-cancels-other pair
+// ---------------------------------------------------------------------
+pub fn link_trigger_orders(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramResult {
+    // Accounts:
+    // 0. [signer] trigger owner
+    // 1. [writable] trigger order account
+    // 2. [writable] trigger order account of the opposite kind on the same position
+    let accounts_iter = &mut accounts.iter();
+    let user = next_account_info(accounts_iter)?;
+    let first_acc = next_account_info(accounts_iter)?;
+    let second_acc = next_account_info(accounts_iter)?;
+
+    if !user.is_signer {
+        msg!("User must be signer");
+        return Err(ProgramError::MissingRequiredSignature);
+    }
+
+    if first_acc.key == second_acc.key {
+        msg!("A trigger order can't be linked to itself");
+        return Err(ProgramError::InvalidArgument);
+    }
+
+    let mut first = load_trigger_order(program_id, first_acc)?;
+    let mut second = load_trigger_order(program_id, second_acc)?;
+    for trigger in [&first, &second] {
+        if trigger.owner != *user.key {
+            msg!("Trigger owner mismatch. Expected: {}, Got: {}", trigger.owner, user.key);
+            return Err(ProgramError::IllegalOwner);
+        }
+        if trigger.is_linked() {
+            msg!("Trigger order {} is already linked to {}", trigger.trigger_id, trigger.linked_trigger);
+            return Err(ProgramError::InvalidArgument);
+        }
+    }
+    if first.position != second.position {
+        msg!("Linked trigger orders must reduce the same position");
+        return Err(ProgramError::InvalidArgument);
+    }
+    if first.kind == second.kind {
+        msg!("A one-cancels-other pair is a stop-loss and a take-profit");
+        return Err(ProgramError::InvalidArgument);
+    }
+    // Triggers placed before links existed have no room for one until migrated
+    if first_acc.data_len() < TriggerOrder::SPACE || second_acc.data_len() < TriggerOrder::SPACE {
+        msg!("Trigger orders placed before one-cancels-other links must be grown with migrate_account to be linked");
+        return Err(ProgramError::AccountDataTooSmall);
+    }
+
+    first.linked_trigger = *second_acc.key;
+    second.linked_trigger = *first_acc.key;
+    store_account(&first, &mut first_acc.data.borrow_mut())?;
+    store_account(&second, &mut second_acc.data.borrow_mut())?;
+
+    msg!("Trigger orders linked: {:?} id={} and {:?} id={}",
+         first.kind, first.trigger_id, second.kind, second.trigger_id);
+
+    Ok(())
+}
+
+/// Load a trigger order, checking it is program-owned and sits at its PDA
+fn load_trigger_order(program_id: &Pubkey, trigger_acc: &AccountInfo) -> Result<TriggerOrder, ProgramError> {
+    if trigger_acc.owner != program_id {
+        msg!("Trigger account is not owned by the program");
+        return Err(ProgramError::IncorrectProgramId);
+    }
+
+    // Triggers placed before one-cancels-other links are shorter; their missing tail decodes as unlinked
+    let mut data = trigger_acc.data.borrow().to_vec();
+    if data.len() < TriggerOrder::SPACE {
+        data.resize(TriggerOrder::SPACE, 0);
+    }
+    let trigger = load_account::<TriggerOrder>(&data)?;
+    let expected_trigger = Pubkey::create_program_address(
+        &[TRIGGER_SEED, trigger.position.as_ref(), &trigger.trigger_id.to_le_bytes(), &[trigger.bump]],
+        program_id,
+    )?;
+    if *trigger_acc.key != expected_trigger {
+        msg!("Trigger account is not the correct PDA. Expected: {}, Got: {}",
+             expected_trigger, trigger_acc.key);
+        return Err(ProgramError::InvalidArgument);
+    }
+
+    Ok(trigger)
+}
+
+/// Load the other order of `trigger`'s one-cancels-other pair from `linked_acc`, if it has one
+fn load_linked_trigger<'a, 'b>(
+    program_id: &Pubkey,
+    trigger: &TriggerOrder,
+    trigger_key: &Pubkey,
+    linked_acc: Option<&'a AccountInfo<'b>>,
+) -> Result<Option<(&'a AccountInfo<'b>, TriggerOrder)>, ProgramError> {
+    if !trigger.is_linked() {
+        return Ok(None);
+    }
+
+    let Some(linked_acc) = linked_acc else {
+        msg!("Trigger order {} is linked: pass {} as well", trigger.trigger_id, trigger.linked_trigger);
+        return Err(ProgramError::NotEnoughAccountKeys);
+    };
+    if *linked_acc.key != trigger.linked_trigger {
+        msg!("Linked trigger mismatch. Expected: {}, Got: {}", trigger.linked_trigger, linked_acc.key);
+        return Err(ProgramError::InvalidArgument);
+    }
+    let linked = load_trigger_order(program_id, linked_acc)?;
+    if linked.linked_trigger != *trigger_key {
+        msg!("Trigger order {} is not linked back to {}", linked_acc.key, trigger_key);
+        return Err(ProgramError::InvalidArgument);
+    }
+
+    Ok(Some((linked_acc, linked)))
+}
+
+/// Close the other order of a one-cancels-other pair whose trigger was executed or removed
+fn close_linked_trigger(
+    program_id: &Pubkey,
+    queue_acc: &AccountInfo,
+    linked: Option<(&AccountInfo, TriggerOrder)>,
+    owner: &AccountInfo,
+) -> ProgramResult {
+    let Some((linked_acc, linked)) = linked else {
+        return Ok(());
+    };
+
+    dequeue_trigger(program_id, queue_acc, &linked, linked_acc.key)?;
+    close_program_account(linked_acc, owner)?;
+    msg!("Linked trigger order {} cancelled", linked.trigger_id);
+
+    Ok(())
+}
+
+/// Load a market's trigger queue, checking it is program-owned, sits at its PDA and
+/// belongs to `market`
+fn load_trigger_queue(program_id: &Pubkey, queue_acc: &AccountInfo, market: &Pubkey) -> Result<TriggerQueue, ProgramError> {
+    if queue_acc.owner != program_id {
+        msg!("Trigger queue is not owned by the program");
+        return Err(ProgramError::IncorrectProgramId);
+    }
+
+    let queue = TriggerQueue::load(&queue_acc.data.borrow())?;
+    let expected_queue = Pubkey::create_program_address(
+        &[TRIGGER_QUEUE_SEED, market.as_ref(), &[queue.bump]],
+        program_id,
+    )?;
+    if *queue_acc.key != expected_queue || queue.market != *market {
+        msg!("Trigger queue is not the correct PDA for market {}. Expected: {}, Got: {}",
+             market, expected_queue, queue_acc.key);
+        return Err(ProgramError::InvalidArgument);
+    }
+
+    Ok(queue)
+}
+
+/// Remove a trigger that is being closed from its market's queue
+fn dequeue_trigger(
+    program_id: &Pubkey,
+    queue_acc: &AccountInfo,
+    trigger: &TriggerOrder,
+    trigger_key: &Pubkey,
+) -> ProgramResult {
+    // Triggers placed before queues existed were never listed, and their market may have none yet
+    if queue_acc.data_is_empty() {
+        let (expected_queue, _) =
+            Pubkey::find_program_address(&[TRIGGER_QUEUE_SEED, trigger.market.as_ref()], program_id);
+        if *queue_acc.key != expected_queue {
+            msg!("Trigger queue is not the correct PDA. Expected: {}, Got: {}", expected_queue, queue_acc.key);
+            return Err(ProgramError::InvalidArgument);
+        }
+        return Ok(());
+    }
+
+    let mut queue = load_trigger_queue(program_id, queue_acc, &trigger.market)?;
+    if queue.remove(trigger_key) {
+        store_account(&queue, &mut queue_acc.data.borrow_mut())?;
+    }
+
+    Ok(())
+}
@@ -0,0 +1,201 @@
+//! Per-user trading statistics
+//!
+//! A wallet can opt in to a UserStats account ([USER_STATS_SEED, owner]) that
+//! accumulates its lifetime volume, fees, trades and liquidations. Trading
+//! instructions update it when it is passed among their trailing accounts; it
+//! is recognized by its PDA, so it never has to sit in a fixed slot. Rewards
+//! programs and fee-tier logic can read it directly.
+//!
+//! Trades also count toward a rolling 30-day volume, kept as the volume of the
+//! current and previous fixed 30-day windows: the previous window is weighted by
+//! how much of it still falls within the last 30 days.
+
+use borsh::{BorshDeserialize, BorshSerialize};
+use shank::ShankAccount;
+use solana_program::{
+    account_info::{next_account_info, AccountInfo},
+    entrypoint::ProgramResult,
+    msg,
+    program::invoke_signed,
+    program_error::ProgramError,
+    pubkey::Pubkey,
+    system_instruction,
+    sysvar::{rent::Rent, Sysvar},
+};
+
+use crate::{load_account, probe_account, store_account, AccountType, DISCRIMINATOR_LEN};
+
+/// Seed prefix for user stats PDAs: [USER_STATS_SEED, owner]
+pub const USER_STATS_SEED: &[u8] = b"user_stats";
+
+/// Length of the rolling volume window (30 days, in seconds)
+pub const VOLUME_WINDOW_SECONDS: i64 = 30 * 86_400;
+
+/// A wallet's lifetime trading statistics
+#[derive(BorshSerialize, BorshDeserialize, Debug, Default, Clone, PartialEq, Eq, ShankAccount)]
+pub struct UserStats {
+    /// Wallet whose positions are tracked
+    pub owner: Pubkey,
+    /// Notional traded, including liquidations (quote token)
+    pub volume: u64,
+    /// Trading and liquidation fees paid (quote token)
+    pub fees_paid: u64,
+    /// Opens, increases, reductions and closes
+    pub trade_count: u64,
+    /// Times one of the wallet's positions was liquidated
+    pub liquidation_count: u64,
+    /// PDA bump for [USER_STATS_SEED, owner]
+    pub bump: u8,
+    /// Index of the 30-day window `window_volume` covers (unix time / VOLUME_WINDOW_SECONDS)
+    pub volume_window: i64,
+    /// Notional traded during the current window (quote token)
+    pub window_volume: u64,
+    /// Notional traded during the window before it (quote token)
+    pub previous_window_volume: u64,
+}
+
+impl AccountType for UserStats {
+    const DISCRIMINATOR: [u8; DISCRIMINATOR_LEN] = *b"usrstats";
+    /// owner + four u64 counters + bump + volume_window + two window volumes
+    const LEN: usize = 32 + 8 * 4 + 1 + 8 + 8 * 2;
+}
+
+impl UserStats {
+    /// Count a trade of `notional` that paid `fee` at unix time `now`. Statistics saturate rather
+    /// than fail a trade.
+    pub fn record_trade(&mut self, notional: u64, fee: u64, now: i64) {
+        self.volume = self.volume.saturating_add(notional);
+        self.fees_paid = self.fees_paid.saturating_add(fee);
+        self.trade_count = self.trade_count.saturating_add(1);
+        self.roll_volume_window(now);
+        self.window_volume = self.window_volume.saturating_add(notional);
+    }
+
+    /// Count a liquidation that closed `notional` and charged `fee`
+    pub fn record_liquidation(&mut self, notional: u64, fee: u64) {
+        self.volume = self.volume.saturating_add(notional);
+        self.fees_paid = self.fees_paid.saturating_add(fee);
+        self.liquidation_count = self.liquidation_count.saturating_add(1);
+    }
+
+    /// Notional traded over the 30 days before unix time `now`: the current window's volume
+    /// plus the share of the previous window's that is still within 30 days
+    pub fn rolling_volume(&self, now: i64) -> u64 {
+        let mut stats = self.clone();
+        stats.roll_volume_window(now);
+
+        let remaining = VOLUME_WINDOW_SECONDS - now.rem_euclid(VOLUME_WINDOW_SECONDS);
+        let previous = u128::from(stats.previous_window_volume) * remaining as u128 / VOLUME_WINDOW_SECONDS as u128;
+        stats.window_volume.saturating_add(u64::try_from(previous).unwrap_or(u64::MAX))
+    }
+
+    /// Move the window volumes forward to the window containing `now`
+    fn roll_volume_window(&mut self, now: i64) {
+        let window = now.div_euclid(VOLUME_WINDOW_SECONDS);
+        if window == self.volume_window {
+            return;
+        }
+        self.previous_window_volume = if window == self.volume_window + 1 { self.window_volume } else { 0 };
+        self.window_volume = 0;
+        self.volume_window = window;
+    }
+}
+
+/// User stats PDA of `owner`
+pub fn user_stats_address(program_id: &Pubkey, owner: &Pubkey) -> (Pubkey, u8) {
+    Pubkey::find_program_address(&[USER_STATS_SEED, owner.as_ref()], program_id)
+}
+
+// ---------------------------------------------------------------------
+// 4️⃣7️⃣ Create a wallet's trading statistics account
+// ---------------------------------------------------------------------
+pub fn create_user_stats(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramResult {
+    // Accounts:
+    // 0. [signer, writable] owner (pays for the account)
+    // 1. [writable] user stats account (PDA: [USER_STATS_SEED, owner])
+    // 2. [] rent sysvar
+    // 3. [] system program
+    let accounts_iter = &mut accounts.iter();
+    let owner = next_account_info(accounts_iter)?;
+    let stats_acc = next_account_info(accounts_iter)?;
+    let rent_sysvar = next_account_info(accounts_iter)?;
+    let system_program = next_account_info(accounts_iter)?;
+
+    if !owner.is_signer {
+        msg!("Owner must be signer");
+        return Err(ProgramError::MissingRequiredSignature);
+    }
+
+    let (expected_stats, bump) = user_stats_address(program_id, owner.key);
+    if *stats_acc.key != expected_stats {
+        msg!("User stats account is not the correct PDA. Expected: {}, Got: {}", expected_stats, stats_acc.key);
+        return Err(ProgramError::InvalidArgument);
+    }
+
+    if !stats_acc.data_is_empty() {
+        msg!("User stats account already exists");
+        return Err(ProgramError::AccountAlreadyInitialized);
+    }
+
+    let rent = Rent::from_account_info(rent_sysvar)?;
+    invoke_signed(&system_instruction::create_account(
+        owner.key,
+        stats_acc.key,
+        rent.minimum_balance(UserStats::SPACE),
+        UserStats::SPACE as u64,
+        program_id,
+    ), &[
+        owner.clone(),
+        stats_acc.clone(),
+        system_program.clone(),
+    ], &[&[USER_STATS_SEED, owner.key.as_ref(), &[bump]]])?;
+
+    let stats = UserStats { owner: *owner.key, bump, ..UserStats::default() };
+    store_account(&stats, &mut stats_acc.data.borrow_mut())?;
+
+    msg!("Created user stats account for {}", owner.key);
+
+    Ok(())
+}
+
+/// `owner`'s user stats account among `accounts`, if it was passed
+pub(crate) fn find_user_stats<'a, 'info>(
+    program_id: &Pubkey,
+    owner: &Pubkey,
+    accounts: &[&'a AccountInfo<'info>],
+) -> Option<&'a AccountInfo<'info>> {
+    accounts.iter().copied().find(|account| {
+        if account.owner != program_id {
+            return false;
+        }
+        // Re-derive the PDA from the stored bump rather than searching for it
+        let Some(stats) = account
+            .try_borrow_data()
+            .ok()
+            .and_then(|data| probe_account::<UserStats>(&data))
+        else {
+            return false;
+        };
+        stats.owner == *owner
+            && Pubkey::create_program_address(&[USER_STATS_SEED, owner.as_ref(), &[stats.bump]], program_id)
+                .is_ok_and(|expected_stats| expected_stats == *account.key)
+    })
+}
+
+/// Apply `update` to `owner`'s user stats if the account was passed among `accounts`
+pub(crate) fn update_user_stats(
+    program_id: &Pubkey,
+    owner: &Pubkey,
+    accounts: &[&AccountInfo],
+    update: impl FnOnce(&mut UserStats),
+) -> ProgramResult {
+    let Some(stats_acc) = find_user_stats(program_id, owner, accounts) else {
+        return Ok(());
+    };
+
+    let mut stats = load_account::<UserStats>(&stats_acc.data.borrow())?;
+    update(&mut stats);
+    store_account(&stats, &mut stats_acc.data.borrow_mut())?;
+
+    Ok(())
+}
@@ -0,0 +1,252 @@
+//! Read-only view instructions
+//!
+//! These load the accounts, run the same fixed-point math the program uses
+//! on-chain and hand the result back through `set_return_data`, so clients can
+//! simulate the instruction instead of re-implementing the math. They never
+//! write to any account.
+
+use solana_program::{
+    account_info::{next_account_info, AccountInfo},
+    clock::Clock,
+    entrypoint::ProgramResult,
+    msg,
+    program::set_return_data,
+    program_error::ProgramError,
+    pubkey::Pubkey,
+    sysvar::Sysvar,
+};
+
+use crate::cross_margin::{calculate_portfolio_health, load_portfolio, CrossMargin};
+use crate::events;
+use crate::liquidity_pool::load_pool;
+use crate::math::{self, PRECISION};
+use crate::{
+    calculate_liquidation_price, calculate_notional, calculate_position_health, calculate_unrealized_pnl,
+    isolated_liquidation_health, load_config, validate_position_address, MarketState, Position,
+};
+
+// ---------------------------------------------------------------------
+// 1️⃣9️⃣ View: collateral ratio of a position at the mark price
+// ---------------------------------------------------------------------
+pub fn get_position_health(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramResult {
+    // Accounts:
+    // 0. [] position account
+    // 1. [] market state account
+    //
+    // Returns: collateral ratio as u64 LE (1e9 precision, u64::MAX for a flat position)
+    let (position, mark_price) = load_position_and_mark(program_id, accounts)?;
+
+    let health = calculate_position_health(&position, mark_price)?;
+    set_return_data(&health.to_le_bytes());
+
+    msg!("Position health: {}", health);
+
+    Ok(())
+}
+
+// ---------------------------------------------------------------------
+// 2️⃣0️⃣ View: unrealized PnL of a position at the mark price
+// ---------------------------------------------------------------------
+pub fn get_unrealized_pnl(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramResult {
+    // Accounts:
+    // 0. [] position account
+    // 1. [] market state account
+    //
+    // Returns: unrealized PnL as i64 LE (quote token, 1e9 precision)
+    let (position, mark_price) = load_position_and_mark(program_id, accounts)?;
+
+    let pnl = calculate_unrealized_pnl(&position, mark_price)?;
+    set_return_data(&pnl.to_le_bytes());
+
+    msg!("Unrealized PnL: {}", pnl);
+
+    Ok(())
+}
+
+// ---------------------------------------------------------------------
+// 3️⃣7️⃣ View: net asset value of a market's LP pool
+// ---------------------------------------------------------------------
+pub fn get_pool_nav(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramResult {
+    // Accounts:
+    // 0. [] pool state
+    // 1. [] market state account the pool backs
+    //
+    // Returns: NAV as u64 LE (quote token), then NAV per share as u64 LE
+    // (1e9 precision, 1e9 while no shares exist)
+    let accounts_iter = &mut accounts.iter();
+    let pool_acc = next_account_info(accounts_iter)?;
+    let market_state_acc = next_account_info(accounts_iter)?;
+
+    let (mut pool, market_state) = load_pool(program_id, pool_acc, market_state_acc)?;
+    pool.sync(&market_state)?;
+    let nav = pool.nav(&market_state)?;
+    let nav_per_share = match pool.lp_shares {
+        0 => PRECISION,
+        lp_shares => math::ratio(nav.into(), lp_shares.into()),
+    };
+
+    let mut data = [0u8; 16];
+    data[..8].copy_from_slice(&nav.to_le_bytes());
+    data[8..].copy_from_slice(&nav_per_share.to_le_bytes());
+    set_return_data(&data);
+
+    msg!("Pool NAV: {}, per share: {}", nav, nav_per_share);
+
+    Ok(())
+}
+
+// ---------------------------------------------------------------------
+// 4️⃣8️⃣ View: portfolio health of a cross-margin user account
+// ---------------------------------------------------------------------
+pub fn compute_portfolio_health(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramResult {
+    // Accounts:
+    // 0. [] user account
+    // 1..N. [] position and market state account of each linked position, in account order
+    //
+    // Returns: collateral ratio as u64 LE (1e9 precision, u64::MAX without exposure),
+    // equity as i64 LE and notional as u64 LE (quote token), then the account index
+    // of the weakest position as u8 (u8::MAX without exposure)
+    let accounts_iter = &mut accounts.iter();
+    let user_account_acc = next_account_info(accounts_iter)?;
+    let linked_accs: Vec<&AccountInfo> = accounts_iter.collect();
+
+    let (user_account, positions) = load_portfolio(program_id, user_account_acc, &linked_accs)?;
+    let health = calculate_portfolio_health(user_account.collateral, &positions)?;
+    let weakest = health.weakest.map_or(u8::MAX, |index| index as u8);
+
+    let mut data = [0u8; 25];
+    data[..8].copy_from_slice(&health.collateral_ratio.to_le_bytes());
+    data[8..16].copy_from_slice(&health.equity.to_le_bytes());
+    data[16..24].copy_from_slice(&health.notional.to_le_bytes());
+    data[24] = weakest;
+    set_return_data(&data);
+
+    msg!("Portfolio health: {}, equity: {}, notional: {}, weakest: {}",
+         health.collateral_ratio, health.equity, health.notional, weakest);
+
+    Ok(())
+}
+
+// ---------------------------------------------------------------------
+// 4️⃣9️⃣ View: mark price at which an isolated position is liquidated
+// ---------------------------------------------------------------------
+pub fn get_liquidation_price(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramResult {
+    // Accounts:
+    // 0. [] position account
+    // 1. [] market state account
+    // 2. [] config account
+    //
+    // Returns: liquidation price as u64 LE (1e9 precision, 0 when no price liquidates the position)
+    let accounts_iter = &mut accounts.iter();
+    let position_acc = next_account_info(accounts_iter)?;
+    let market_state_acc = next_account_info(accounts_iter)?;
+    let config_acc = next_account_info(accounts_iter)?;
+
+    if position_acc.owner != program_id || market_state_acc.owner != program_id {
+        msg!("Position and market state must be owned by the program");
+        return Err(ProgramError::IncorrectProgramId);
+    }
+
+    let config = load_config(program_id, config_acc)?;
+    let position = *Position::load(&position_acc.try_borrow_data()?)?;
+    let market_state = *MarketState::load(&market_state_acc.try_borrow_data()?)?;
+
+    // A cross-margined position's liquidation depends on the rest of its account
+    if position.is_cross_margin() {
+        msg!("Cross-margined positions have no single liquidation price; use compute_portfolio_health");
+        return Err(ProgramError::InvalidArgument);
+    }
+
+    // Risk tiers are taken at the position's notional at the current mark
+    let notional = calculate_notional(position.base_amount, market_state.mark_price)?;
+    let required_ratio = market_state.required_collateral_ratio(notional, config.min_collateral_ratio);
+    let liquidation_price = calculate_liquidation_price(&position, &market_state, required_ratio)?
+        .unwrap_or(0);
+    set_return_data(&liquidation_price.to_le_bytes());
+
+    msg!("Liquidation price: {}", liquidation_price);
+
+    Ok(())
+}
+
+// ---------------------------------------------------------------------
+// 7️⃣9️⃣ View: health factor of a position as liquidations measure it
+// ---------------------------------------------------------------------
+pub fn check_health(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramResult {
+    // Accounts:
+    // 0. [] position account
+    // 1. [] market state account the position trades in
+    // 2. [] clock sysvar
+    // 3. [] config account
+    // Isolated positions:
+    //   4..N. [] oracle of each collateral asset the position holds
+    // Cross-margined positions:
+    //   4. [] owner's user account
+    //   5..N. [] position and market state account of every other linked position
+    //
+    // Returns: health factor as u64 LE (1e9 precision; below 1e9 the position is
+    // liquidatable, u64::MAX for a flat position), then the collateral ratio as u64 LE
+    // (1e9 precision)
+    let accounts_iter = &mut accounts.iter();
+    let position_acc = next_account_info(accounts_iter)?;
+    let market_state_acc = next_account_info(accounts_iter)?;
+    let clock_sysvar = next_account_info(accounts_iter)?;
+    let config_acc = next_account_info(accounts_iter)?;
+    let remaining_accs: Vec<&AccountInfo> = accounts_iter.collect();
+
+    if position_acc.owner != program_id || market_state_acc.owner != program_id {
+        msg!("Position and market state must be owned by the program");
+        return Err(ProgramError::IncorrectProgramId);
+    }
+
+    let config = load_config(program_id, config_acc)?;
+    let clock = Clock::from_account_info(clock_sysvar)?;
+    let mut position = *Position::load(&position_acc.try_borrow_data()?)?;
+    let mut market_state = *MarketState::load(&market_state_acc.try_borrow_data()?)?;
+    validate_position_address(program_id, position_acc.key, market_state_acc.key, &position)?;
+
+    // The same math as liquidate, run on copies: pending funding and socialized loss are
+    // settled, asset collateral is weighted at its oracles and PnL is taken at the mark
+    let (collateral_ratio, health_factor) = if position.base_amount == 0 {
+        (u64::MAX, u64::MAX)
+    } else if position.is_cross_margin() {
+        let (cross_margin, _) =
+            CrossMargin::load(program_id, position_acc.key, &position, market_state_acc.key, &remaining_accs, false)?;
+        cross_margin.liquidation_health(&position, &market_state, config.min_collateral_ratio)?
+    } else {
+        isolated_liquidation_health(&mut position, &mut market_state, &config, &remaining_accs, clock.slot)?
+    };
+
+    let mut data = [0u8; 16];
+    data[..8].copy_from_slice(&health_factor.to_le_bytes());
+    data[8..].copy_from_slice(&collateral_ratio.to_le_bytes());
+    set_return_data(&data);
+    events::emit(&events::HealthChecked {
+        market: *market_state_acc.key,
+        position: *position_acc.key,
+        slot: clock.slot,
+        collateral_ratio,
+        health_factor,
+    });
+
+    msg!("Health factor: {}, collateral ratio: {}", health_factor, collateral_ratio);
+
+    Ok(())
+}
+
+/// Copy out a position and its market's mark price for a view
+fn load_position_and_mark(program_id: &Pubkey, accounts: &[AccountInfo]) -> Result<(Position, u64), ProgramError> {
+    let accounts_iter = &mut accounts.iter();
+    let position_acc = next_account_info(accounts_iter)?;
+    let market_state_acc = next_account_info(accounts_iter)?;
+
+    if position_acc.owner != program_id || market_state_acc.owner != program_id {
+        msg!("Position and market state must be owned by the program");
+        return Err(ProgramError::IncorrectProgramId);
+    }
+
+    let position = *Position::load(&position_acc.try_borrow_data()?)?;
+    let mark_price = MarketState::load(&market_state_acc.try_borrow_data()?)?.mark_price;
+
+    Ok((position, mark_price))
+}
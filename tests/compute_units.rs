@@ -0,0 +1,260 @@
+//! Compute units used by the hot-path instructions, measured on the SBF build
+//!
+//! The lifecycle tests run the program natively, where compute isn't metered, so
+//! these load `simple_perps.so` instead and are ignored by default. Build the
+//! program first and run them with:
+//!
+//! ```text
+//! cargo build-sbf && cargo test --test compute_units -- --ignored --nocapture
+//! ```
+//!
+//! Each instruction must leave ORACLE_HEADROOM of the default per-instruction
+//! budget unused, for oracle CPIs a transaction may add around it.
+
+use simple_perps::{
+    instruction::PerpsInstruction, market_address, position_address, user_stats::user_stats_address, CONFIG_SEED, PDA_SEED,
+    SPL_TOKEN_PROGRAM_ID,
+};
+use solana_program_test::{BanksClientError, ProgramTest, ProgramTestContext};
+use solana_sdk::{
+    account::Account,
+    bpf_loader_upgradeable,
+    compute_budget::ComputeBudgetInstruction,
+    instruction::{AccountMeta, Instruction},
+    pubkey::Pubkey,
+    signature::{Keypair, Signer},
+    system_program,
+    sysvar,
+    transaction::Transaction,
+};
+
+/// One quote token (9 decimals, matching the program's 1e9 precision)
+const TOKEN: u64 = 1_000_000_000;
+
+/// Default compute budget of a single instruction
+const INSTRUCTION_BUDGET: u64 = 200_000;
+
+/// Compute units each instruction leaves free for oracle CPIs
+const ORACLE_HEADROOM: u64 = 80_000;
+
+/// SPL Token account and mint layouts
+const TOKEN_ACCOUNT_LEN: usize = 165;
+const MINT_LEN: usize = 82;
+
+struct Bench {
+    context: ProgramTestContext,
+    program_id: Pubkey,
+    mint: Pubkey,
+    vault: Pubkey,
+    config: Pubkey,
+    market: Pubkey,
+    trader: Keypair,
+    token_account: Pubkey,
+}
+
+fn perps_instruction(program_id: Pubkey, instruction: &PerpsInstruction, accounts: Vec<AccountMeta>) -> Instruction {
+    Instruction::new_with_bytes(program_id, &instruction.pack(), accounts)
+}
+
+fn spl_account(data: Vec<u8>) -> Account {
+    Account { lamports: TOKEN, data, owner: SPL_TOKEN_PROGRAM_ID, executable: false, rent_epoch: 0 }
+}
+
+fn mint_account(supply: u64) -> Account {
+    let mut data = vec![0u8; MINT_LEN];
+    data[36..44].copy_from_slice(&supply.to_le_bytes());
+    data[44] = 9; // decimals
+    data[45] = 1; // is_initialized
+    spl_account(data)
+}
+
+/// ProgramData account of an upgradeable program naming `authority` its upgrade authority
+fn program_data_account(authority: &Pubkey) -> Account {
+    let mut data = vec![0u8; 4 + 8 + 1 + 32];
+    data[0..4].copy_from_slice(&3u32.to_le_bytes()); // UpgradeableLoaderState::ProgramData
+    data[12] = 1; // Some(upgrade authority)
+    data[13..45].copy_from_slice(authority.as_ref());
+    Account { lamports: TOKEN, data, owner: bpf_loader_upgradeable::id(), executable: false, rent_epoch: 0 }
+}
+
+fn token_account(mint: &Pubkey, owner: &Pubkey, amount: u64) -> Account {
+    let mut data = vec![0u8; TOKEN_ACCOUNT_LEN];
+    data[0..32].copy_from_slice(mint.as_ref());
+    data[32..64].copy_from_slice(owner.as_ref());
+    data[64..72].copy_from_slice(&amount.to_le_bytes());
+    data[108] = 1; // AccountState::Initialized
+    spl_account(data)
+}
+
+/// Start a bank running the SBF build with a config, market 0 and one funded trader
+async fn setup() -> Bench {
+    let program_id = Pubkey::new_unique();
+    let mut program_test = ProgramTest::new("simple_perps", program_id, None);
+    program_test.prefer_bpf(true);
+
+    let mint = Pubkey::new_unique();
+    let (vault, _) = Pubkey::find_program_address(&[PDA_SEED], &program_id);
+    let trader = Keypair::new();
+    let token_account = Pubkey::new_unique();
+    program_test.add_account(mint, mint_account(11_000 * TOKEN));
+    program_test.add_account(vault, self::token_account(&mint, &vault, 10_000 * TOKEN));
+    // The trader stands in for the deployer: the upgrade authority creates the config
+    let (program_data, _) = Pubkey::find_program_address(&[program_id.as_ref()], &bpf_loader_upgradeable::id());
+    program_test.add_account(program_data, program_data_account(&trader.pubkey()));
+    program_test.add_account(
+        trader.pubkey(),
+        Account { lamports: 10 * TOKEN, owner: system_program::id(), ..Account::default() },
+    );
+    program_test.add_account(token_account, self::token_account(&mint, &trader.pubkey(), 1_000 * TOKEN));
+
+    let context = program_test.start_with_context().await;
+    let (config, _) = Pubkey::find_program_address(&[CONFIG_SEED], &program_id);
+    let (market, _) = market_address(&program_id, 0);
+    let mut bench = Bench { context, program_id, mint, vault, config, market, trader, token_account };
+
+    let payer = bench.context.payer.pubkey();
+    let initialize_config = perps_instruction(
+        program_id,
+        &PerpsInstruction::InitializeConfig { admin: Some(payer) },
+        vec![
+            AccountMeta::new(bench.trader.pubkey(), true),
+            AccountMeta::new(config, false),
+            AccountMeta::new_readonly(sysvar::rent::id(), false),
+            AccountMeta::new_readonly(system_program::id(), false),
+            AccountMeta::new_readonly(mint, false),
+            AccountMeta::new_readonly(program_data, false),
+        ],
+    );
+    let initialize_market = perps_instruction(
+        program_id,
+        &PerpsInstruction::InitializeMarket { market_id: 0, initial_price: 100 * TOKEN, base_decimals: 9 },
+        vec![
+            AccountMeta::new(payer, true),
+            AccountMeta::new(market, false),
+            AccountMeta::new_readonly(sysvar::rent::id(), false),
+            AccountMeta::new_readonly(sysvar::clock::id(), false),
+            AccountMeta::new_readonly(system_program::id(), false),
+            AccountMeta::new(config, false),
+            AccountMeta::new_readonly(SPL_TOKEN_PROGRAM_ID, false),
+            AccountMeta::new_readonly(mint, false),
+        ],
+    );
+    bench.measure("initialize_config", initialize_config, true).await.unwrap();
+    bench.measure("initialize_market", initialize_market, false).await.unwrap();
+
+    bench
+}
+
+impl Bench {
+    /// Run `instruction` alone in a transaction, at the full budget, and return the units it used
+    async fn measure(&mut self, name: &str, instruction: Instruction, trader_signs: bool) -> Result<u64, BanksClientError> {
+        let blockhash = self.context.banks_client.get_latest_blockhash().await.unwrap();
+        let mut signers = vec![&self.context.payer];
+        if trader_signs {
+            signers.push(&self.trader);
+        }
+        let transaction = Transaction::new_signed_with_payer(
+            &[ComputeBudgetInstruction::set_compute_unit_limit(INSTRUCTION_BUDGET as u32), instruction],
+            Some(&self.context.payer.pubkey()),
+            &signers,
+            blockhash,
+        );
+        let outcome = self.context.banks_client.process_transaction_with_metadata(transaction).await?;
+        outcome.result?;
+
+        let units = outcome.metadata.expect("transaction metadata").compute_units_consumed;
+        println!("{name:<32} {units:>7} CU");
+        Ok(units)
+    }
+
+    fn position(&self) -> Pubkey {
+        position_address(&self.program_id, &self.market, &self.trader.pubkey(), 0).0
+    }
+
+    fn open_position(&self, base_delta: i64, collateral_delta: u64, trailing: &[AccountMeta]) -> Instruction {
+        let mut accounts = vec![
+            AccountMeta::new(self.trader.pubkey(), true),
+            AccountMeta::new_readonly(SPL_TOKEN_PROGRAM_ID, false),
+            AccountMeta::new(self.token_account, false),
+            AccountMeta::new(self.vault, false),
+            AccountMeta::new(self.position(), false),
+            AccountMeta::new(self.market, false),
+            AccountMeta::new_readonly(sysvar::rent::id(), false),
+            AccountMeta::new_readonly(sysvar::clock::id(), false),
+            AccountMeta::new_readonly(system_program::id(), false),
+            AccountMeta::new_readonly(self.config, false),
+            AccountMeta::new_readonly(self.mint, false),
+        ];
+        accounts.extend_from_slice(trailing);
+        perps_instruction(
+            self.program_id,
+            &PerpsInstruction::OpenPosition { base_delta, collateral_delta, price_limit: 0, flags: 0, sub_account_id: 0 },
+            accounts,
+        )
+    }
+
+    async fn warp_slots(&mut self, slots: u64) {
+        let slot = self.context.banks_client.get_root_slot().await.unwrap();
+        self.context.warp_to_slot(slot + slots).unwrap();
+    }
+}
+
+#[tokio::test]
+#[ignore = "needs the SBF build: cargo build-sbf"]
+async fn test_position_lifecycle_leaves_oracle_headroom() {
+    let mut bench = setup().await;
+    let owner = bench.trader.pubkey();
+    let (user_stats, _) = user_stats_address(&bench.program_id, &owner);
+
+    let create_user_stats = perps_instruction(
+        bench.program_id,
+        &PerpsInstruction::CreateUserStats,
+        vec![
+            AccountMeta::new(owner, true),
+            AccountMeta::new(user_stats, false),
+            AccountMeta::new_readonly(sysvar::rent::id(), false),
+            AccountMeta::new_readonly(system_program::id(), false),
+        ],
+    );
+    let update_funding = perps_instruction(
+        bench.program_id,
+        &PerpsInstruction::UpdateFunding,
+        vec![
+            AccountMeta::new(bench.market, false),
+            AccountMeta::new_readonly(sysvar::clock::id(), false),
+            AccountMeta::new_readonly(bench.config, false),
+        ],
+    );
+    let close_position = perps_instruction(
+        bench.program_id,
+        &PerpsInstruction::ClosePosition,
+        vec![
+            AccountMeta::new_readonly(owner, true),
+            AccountMeta::new_readonly(SPL_TOKEN_PROGRAM_ID, false),
+            AccountMeta::new(bench.token_account, false),
+            AccountMeta::new(bench.vault, false),
+            AccountMeta::new(bench.position(), false),
+            AccountMeta::new(bench.market, false),
+            AccountMeta::new_readonly(bench.config, false),
+            AccountMeta::new_readonly(bench.mint, false),
+            AccountMeta::new(user_stats, false),
+        ],
+    );
+
+    let stats = [AccountMeta::new(user_stats, false)];
+    let mut measured = vec![];
+    bench.measure("create_user_stats", create_user_stats, true).await.unwrap();
+    let open = bench.open_position(TOKEN as i64, 200 * TOKEN, &stats);
+    measured.push(bench.measure("open_position (new)", open, true).await.unwrap());
+    bench.warp_slots(1).await;
+    let grow = bench.open_position(TOKEN as i64, 200 * TOKEN, &stats);
+    measured.push(bench.measure("open_position (grow)", grow, true).await.unwrap());
+    bench.warp_slots(200).await;
+    measured.push(bench.measure("update_funding", update_funding, false).await.unwrap());
+    bench.warp_slots(1).await;
+    measured.push(bench.measure("close_position", close_position, true).await.unwrap());
+
+    for units in measured {
+        assert!(units <= INSTRUCTION_BUDGET - ORACLE_HEADROOM, "{units} CU leaves less than {ORACLE_HEADROOM} CU of headroom");
+    }
+}
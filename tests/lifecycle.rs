@@ -0,0 +1,2567 @@
+//! End-to-end lifecycle against the SPL Token program in a solana-program-test bank
+//!
+//! Unlike the unit tests in src/tests.rs these run whole transactions, so the
+//! collateral really moves between token accounts and the vault through CPIs.
+
+use borsh::{BorshDeserialize, BorshSerialize};
+use simple_perps::{
+    access::{leverage_override_address, liquidator_registry_address, whitelist_address},
+    cross_margin::USER_ACCOUNT_SEED,
+    funding_history::{funding_history_address, FundingHistory},
+    instruction::PerpsInstruction,
+    market_address,
+    market_metrics::{market_metrics_address, MarketMetrics},
+    orderbook::{
+        OrderBook, OrderOp, OrderSide, MATCH_FILL_REWARD, ORDERBOOK_SEED, ORDER_FLAG_IMMEDIATE_OR_CANCEL, ORDER_FLAG_POST_ONLY,
+    },
+    position_address,
+    position_token::{position_holder_address, position_mint_address},
+    price_feed::{price_feed_address, PriceFeed},
+    price_history::{price_history_address, PriceHistory},
+    trigger_orders::{TriggerKind, TriggerOrder, TriggerQueue, TRIGGER_QUEUE_SEED, TRIGGER_SEED},
+    calculate_price_band, AccountType, Config, MarketState, Position, Versioned, DISCRIMINATOR_LEN,
+    CONFIG_SEED, DEFAULT_LIQUIDATOR_FEE_BPS, DEFAULT_MAX_FUNDING_RATE_PER_SLOT, DEFAULT_MAX_LEVERAGE, DEFAULT_MIN_COLLATERAL_RATIO,
+    DEFAULT_MAX_OPEN_INTEREST, DEFAULT_REFERRAL_FEE_SHARE, DEFAULT_TRADING_FEE, MAX_LIQUIDATION_GRACE_SLOTS, MAX_MIN_HOLD_SLOTS,
+    MIN_LIQUIDATION_SHARE, OPEN_FLAG_SIMULATE, PDA_SEED,
+    SPL_TOKEN_PROGRAM_ID,
+};
+use solana_program_test::{processor, BanksClientError, ProgramTest, ProgramTestContext};
+use solana_sdk::{
+    account::Account,
+    account_info::AccountInfo,
+    bpf_loader_upgradeable,
+    clock::Clock,
+    entrypoint::ProgramResult,
+    instruction::{AccountMeta, Instruction},
+    program::invoke_signed,
+    program_error::ProgramError,
+    pubkey::Pubkey,
+    rent::Rent,
+    signature::{Keypair, Signer},
+    system_program,
+    sysvar,
+    transaction::Transaction,
+};
+
+/// One quote token (9 decimals, matching the program's 1e9 precision)
+const TOKEN: u64 = 1_000_000_000;
+
+/// Position size and collateral (the notional exceeds u64::MAX before scaling)
+const SIZE: i64 = TOKEN as i64;
+const COLLATERAL: u64 = 200 * TOKEN;
+
+/// SPL Token account and mint layouts
+const TOKEN_ACCOUNT_LEN: usize = 165;
+const MINT_LEN: usize = 82;
+
+struct Env {
+    context: ProgramTestContext,
+    program_id: Pubkey,
+    mint: Pubkey,
+    vault: Pubkey,
+    config: Pubkey,
+    market: Pubkey,
+}
+
+struct Trader {
+    keypair: Keypair,
+    token_account: Pubkey,
+}
+
+/// Seed of the mock strategy's authority PDAs: [STRATEGY_SEED, index]
+const STRATEGY_SEED: &[u8] = b"strategy";
+
+/// A caller program standing in for a vault strategy. It forwards its instruction data,
+/// after a leading authority index, to the perps program in accounts[0] with the remaining
+/// accounts, signing for its authority PDA [STRATEGY_SEED, index] as the position owner.
+fn mock_strategy(program_id: &Pubkey, accounts: &[AccountInfo], data: &[u8]) -> ProgramResult {
+    let (&index, perps_data) = data.split_first().ok_or(ProgramError::InvalidInstructionData)?;
+    let (perps_program, perps_accounts) = accounts.split_first().ok_or(ProgramError::NotEnoughAccountKeys)?;
+    let (authority, bump) = Pubkey::find_program_address(&[STRATEGY_SEED, &[index]], program_id);
+    let accounts = perps_accounts
+        .iter()
+        .map(|account| AccountMeta {
+            pubkey: *account.key,
+            is_signer: account.is_signer || *account.key == authority,
+            is_writable: account.is_writable,
+        })
+        .collect();
+    let instruction = Instruction { program_id: *perps_program.key, accounts, data: perps_data.to_vec() };
+    invoke_signed(&instruction, perps_accounts, &[&[STRATEGY_SEED, &[index], &[bump]]])
+}
+
+/// Encode with `PerpsInstruction::pack`, which writes the payload version after the tag
+fn perps_instruction(program_id: Pubkey, instruction: &PerpsInstruction, accounts: Vec<AccountMeta>) -> Instruction {
+    Instruction::new_with_bytes(program_id, &instruction.pack(), accounts)
+}
+
+fn mint_account(supply: u64, decimals: u8) -> Account {
+    let mut data = vec![0u8; MINT_LEN];
+    data[36..44].copy_from_slice(&supply.to_le_bytes());
+    data[44] = decimals;
+    data[45] = 1; // is_initialized
+    Account { lamports: TOKEN, data, owner: SPL_TOKEN_PROGRAM_ID, executable: false, rent_epoch: 0 }
+}
+
+fn token_account(mint: &Pubkey, owner: &Pubkey, amount: u64) -> Account {
+    let mut data = vec![0u8; TOKEN_ACCOUNT_LEN];
+    data[0..32].copy_from_slice(mint.as_ref());
+    data[32..64].copy_from_slice(owner.as_ref());
+    data[64..72].copy_from_slice(&amount.to_le_bytes());
+    data[108] = 1; // AccountState::Initialized
+    Account { lamports: TOKEN, data, owner: SPL_TOKEN_PROGRAM_ID, executable: false, rent_epoch: 0 }
+}
+
+/// ProgramData account of an upgradeable program naming `authority` its upgrade authority
+fn program_data_account(authority: &Pubkey) -> Account {
+    let mut data = vec![0u8; 4 + 8 + 1 + 32];
+    data[0..4].copy_from_slice(&3u32.to_le_bytes()); // UpgradeableLoaderState::ProgramData
+    data[12] = 1; // Some(upgrade authority)
+    data[13..45].copy_from_slice(authority.as_ref());
+    Account { lamports: TOKEN, data, owner: bpf_loader_upgradeable::id(), executable: false, rent_epoch: 0 }
+}
+
+/// `initialize_config` paid for by `payer`, passing the program's ProgramData account
+fn initialize_config_instruction(program_id: Pubkey, payer: Pubkey, mint: Pubkey, admin: Option<Pubkey>) -> Instruction {
+    let (config, _) = Pubkey::find_program_address(&[CONFIG_SEED], &program_id);
+    let (program_data, _) = Pubkey::find_program_address(&[program_id.as_ref()], &bpf_loader_upgradeable::id());
+    perps_instruction(
+        program_id,
+        &PerpsInstruction::InitializeConfig { admin },
+        vec![
+            AccountMeta::new(payer, true),
+            AccountMeta::new(config, false),
+            AccountMeta::new_readonly(sysvar::rent::id(), false),
+            AccountMeta::new_readonly(system_program::id(), false),
+            AccountMeta::new_readonly(mint, false),
+            AccountMeta::new_readonly(program_data, false),
+        ],
+    )
+}
+
+/// Add a funded wallet holding `tokens` native quote units
+fn add_trader(program_test: &mut ProgramTest, mint: &Pubkey, tokens: u64) -> Trader {
+    let keypair = Keypair::new();
+    let token_account = Pubkey::new_unique();
+    program_test.add_account(
+        keypair.pubkey(),
+        Account { lamports: 10 * TOKEN, owner: system_program::id(), ..Account::default() },
+    );
+    program_test.add_account(token_account, self::token_account(mint, &keypair.pubkey(), tokens));
+    Trader { keypair, token_account }
+}
+
+/// Start a bank with a quote mint of `decimals`, an initialized config and no market
+async fn setup(decimals: u8, traders: &[u64]) -> (Env, Vec<Trader>) {
+    setup_with(decimals, traders, |_, _, _| {}).await
+}
+
+/// `setup`, letting `extend` add programs and accounts given the program id and quote mint
+async fn setup_with(
+    decimals: u8,
+    traders: &[u64],
+    extend: impl FnOnce(&mut ProgramTest, &Pubkey, &Pubkey),
+) -> (Env, Vec<Trader>) {
+    let program_id = Pubkey::new_unique();
+    let mut program_test = ProgramTest::new("simple_perps", program_id, processor!(simple_perps::process_instruction));
+    program_test.prefer_bpf(false);
+
+    // The vault starts with a float so winning traders can be paid
+    let mint = Pubkey::new_unique();
+    let (vault, _) = Pubkey::find_program_address(&[PDA_SEED], &program_id);
+    let float = 10_000 * 10u64.pow(decimals.into());
+    program_test.add_account(mint, mint_account(float + traders.iter().sum::<u64>(), decimals));
+    program_test.add_account(vault, token_account(&mint, &vault, float));
+
+    // Whoever deployed the program holds its upgrade authority, and only they can create the config
+    let deployer = add_trader(&mut program_test, &mint, 0);
+    let (program_data, _) = Pubkey::find_program_address(&[program_id.as_ref()], &bpf_loader_upgradeable::id());
+    program_test.add_account(program_data, program_data_account(&deployer.keypair.pubkey()));
+
+    let traders = traders.iter().map(|&tokens| add_trader(&mut program_test, &mint, tokens)).collect();
+    extend(&mut program_test, &program_id, &mint);
+
+    let context = program_test.start_with_context().await;
+    let (config, _) = Pubkey::find_program_address(&[CONFIG_SEED], &program_id);
+    let (market, _) = market_address(&program_id, 0);
+    let mut env = Env { context, program_id, mint, vault, config, market };
+
+    // The tests act as admin with the bank's payer
+    let payer = env.context.payer.pubkey();
+    let initialize_config = initialize_config_instruction(program_id, deployer.keypair.pubkey(), mint, Some(payer));
+    env.send(&[initialize_config], &[&deployer.keypair]).await.unwrap();
+
+    (env, traders)
+}
+
+impl Env {
+    async fn send(&mut self, instructions: &[Instruction], signers: &[&Keypair]) -> Result<(), BanksClientError> {
+        let blockhash = self.context.banks_client.get_latest_blockhash().await.unwrap();
+        let mut all_signers = vec![&self.context.payer];
+        all_signers.extend_from_slice(signers);
+        let transaction = Transaction::new_signed_with_payer(
+            instructions,
+            Some(&self.context.payer.pubkey()),
+            &all_signers,
+            blockhash,
+        );
+        self.context.banks_client.process_transaction(transaction).await
+    }
+
+    /// Simulate `instruction` and return the data it set with `set_return_data`
+    async fn simulate_return_data(&mut self, instruction: Instruction, signers: &[&Keypair]) -> Vec<u8> {
+        let blockhash = self.context.banks_client.get_latest_blockhash().await.unwrap();
+        let mut all_signers = vec![&self.context.payer];
+        all_signers.extend_from_slice(signers);
+        let transaction =
+            Transaction::new_signed_with_payer(&[instruction], Some(&self.context.payer.pubkey()), &all_signers, blockhash);
+        let simulation = self.context.banks_client.simulate_transaction(transaction).await.unwrap();
+        simulation.result.unwrap().unwrap();
+        simulation.simulation_details.unwrap().return_data.unwrap().data
+    }
+
+    /// Move the bank `slots` past its root slot, e.g. out of the hold after a position grows
+    async fn warp_slots(&mut self, slots: u64) {
+        let slot = self.context.banks_client.get_root_slot().await.unwrap();
+        self.context.warp_to_slot(slot + slots).unwrap();
+    }
+
+    async fn account_data(&mut self, address: Pubkey) -> Vec<u8> {
+        self.context.banks_client.get_account(address).await.unwrap().unwrap().data
+    }
+
+    async fn token_balance(&mut self, token_account: Pubkey) -> u64 {
+        let data = self.account_data(token_account).await;
+        u64::from_le_bytes(data[64..72].try_into().unwrap())
+    }
+
+    /// Overwrite a token account's balance, e.g. to stand in for tokens leaking out of the vault
+    async fn set_token_balance(&mut self, token_account: Pubkey, amount: u64) {
+        let mut account = self.context.banks_client.get_account(token_account).await.unwrap().unwrap();
+        account.data[64..72].copy_from_slice(&amount.to_le_bytes());
+        self.context.set_account(&token_account, &account.into());
+    }
+
+    async fn position(&mut self, owner: &Pubkey) -> Position {
+        let address = self.position_address(owner);
+        *Position::load(&self.account_data(address).await).unwrap()
+    }
+
+    async fn market_state(&mut self) -> MarketState {
+        let address = self.market;
+        *MarketState::load(&self.account_data(address).await).unwrap()
+    }
+
+    fn position_address(&self, owner: &Pubkey) -> Pubkey {
+        position_address(&self.program_id, &self.market, owner, 0).0
+    }
+
+    async fn open_position(
+        &mut self,
+        trader: &Trader,
+        base_delta: i64,
+        collateral_delta: u64,
+        price_limit: u64,
+    ) -> Result<(), BanksClientError> {
+        self.open_position_with(trader, base_delta, collateral_delta, price_limit, &[]).await
+    }
+
+    /// `open_position` with `trailing` accounts (referrer, user stats, whitelist entry, ...)
+    async fn open_position_with(
+        &mut self,
+        trader: &Trader,
+        base_delta: i64,
+        collateral_delta: u64,
+        price_limit: u64,
+        trailing: &[AccountMeta],
+    ) -> Result<(), BanksClientError> {
+        let open = self.open_position_instruction(trader, base_delta, collateral_delta, price_limit, 0, trailing);
+        self.send(&[open], &[&trader.keypair]).await
+    }
+
+    fn open_position_instruction(
+        &self,
+        trader: &Trader,
+        base_delta: i64,
+        collateral_delta: u64,
+        price_limit: u64,
+        flags: u8,
+        trailing: &[AccountMeta],
+    ) -> Instruction {
+        let open = PerpsInstruction::OpenPosition { base_delta, collateral_delta, price_limit, flags, sub_account_id: 0 };
+        self.position_change_instruction(trader, &open, trailing)
+    }
+
+    /// `open_position`, `increase_position` or `decrease_position`, which share their accounts
+    fn position_change_instruction(&self, trader: &Trader, change: &PerpsInstruction, trailing: &[AccountMeta]) -> Instruction {
+        let owner = trader.keypair.pubkey();
+        let mut accounts = vec![
+            AccountMeta::new(owner, true),
+            AccountMeta::new_readonly(SPL_TOKEN_PROGRAM_ID, false),
+            AccountMeta::new(trader.token_account, false),
+            AccountMeta::new(self.vault, false),
+            AccountMeta::new(self.position_address(&owner), false),
+            AccountMeta::new(self.market, false),
+            AccountMeta::new_readonly(sysvar::rent::id(), false),
+            AccountMeta::new_readonly(sysvar::clock::id(), false),
+            AccountMeta::new_readonly(system_program::id(), false),
+            AccountMeta::new_readonly(self.config, false),
+            AccountMeta::new_readonly(self.mint, false),
+        ];
+        accounts.extend_from_slice(trailing);
+        perps_instruction(self.program_id, change, accounts)
+    }
+
+    /// Create market 0, signed by `admin`
+    async fn initialize_market(
+        &mut self,
+        admin: &Keypair,
+        initial_price: u64,
+        base_decimals: u8,
+    ) -> Result<(), BanksClientError> {
+        self.initialize_market_id(admin, 0, initial_price, base_decimals).await
+    }
+
+    /// Create the market `market_id`, signed by `admin`; `self.market` keeps pointing at market 0
+    async fn initialize_market_id(
+        &mut self,
+        admin: &Keypair,
+        market_id: u16,
+        initial_price: u64,
+        base_decimals: u8,
+    ) -> Result<(), BanksClientError> {
+        let initialize_market = perps_instruction(
+            self.program_id,
+            &PerpsInstruction::InitializeMarket { market_id, initial_price, base_decimals },
+            vec![
+                AccountMeta::new(admin.pubkey(), true),
+                AccountMeta::new(market_address(&self.program_id, market_id).0, false),
+                AccountMeta::new_readonly(sysvar::rent::id(), false),
+                AccountMeta::new_readonly(sysvar::clock::id(), false),
+                AccountMeta::new_readonly(system_program::id(), false),
+                AccountMeta::new(self.config, false),
+                AccountMeta::new_readonly(SPL_TOKEN_PROGRAM_ID, false),
+                AccountMeta::new_readonly(self.mint, false),
+            ],
+        );
+        self.send(&[initialize_market], &[admin]).await
+    }
+
+    fn orderbook(&self) -> Pubkey {
+        Pubkey::find_program_address(&[ORDERBOOK_SEED, self.market.as_ref()], &self.program_id).0
+    }
+
+    /// Place an order on `trader`'s position; `resting` are the positions an IOC order may match
+    async fn place_order(
+        &mut self,
+        trader: &Trader,
+        side: OrderSide,
+        price: u64,
+        base_amount: u64,
+        flags: u8,
+        resting: &[&Trader],
+    ) -> Result<(), BanksClientError> {
+        self.place_order_with(trader, side, price, base_amount, flags, resting, &[]).await
+    }
+
+    /// `place_order` with `trailing` accounts (whitelist entries, ...) after the resting positions
+    #[allow(clippy::too_many_arguments)]
+    async fn place_order_with(
+        &mut self,
+        trader: &Trader,
+        side: OrderSide,
+        price: u64,
+        base_amount: u64,
+        flags: u8,
+        resting: &[&Trader],
+        trailing: &[AccountMeta],
+    ) -> Result<(), BanksClientError> {
+        let owner = trader.keypair.pubkey();
+        let mut accounts = vec![
+            AccountMeta::new(owner, true),
+            AccountMeta::new(self.position_address(&owner), false),
+            AccountMeta::new(self.market, false),
+            AccountMeta::new(self.orderbook(), false),
+            AccountMeta::new_readonly(sysvar::rent::id(), false),
+            AccountMeta::new_readonly(sysvar::clock::id(), false),
+            AccountMeta::new_readonly(system_program::id(), false),
+            AccountMeta::new_readonly(self.config, false),
+        ];
+        for maker in resting {
+            accounts.push(AccountMeta::new(self.position_address(&maker.keypair.pubkey()), false));
+        }
+        accounts.extend_from_slice(trailing);
+        let place_order = perps_instruction(
+            self.program_id,
+            &PerpsInstruction::PlaceOrder { side, price, base_amount, flags },
+            accounts,
+        );
+        self.send(&[place_order], &[&trader.keypair]).await
+    }
+
+    /// Create keeper-pushed price feed 1 with `authority` and make it the market's index oracle
+    async fn set_pushed_index_oracle(&mut self, admin: &Keypair, authority: &Pubkey) -> Pubkey {
+        let (feed, _) = price_feed_address(&self.program_id, 1);
+        let set_price_feed = perps_instruction(
+            self.program_id,
+            &PerpsInstruction::SetPriceFeed { feed_id: 1, authority: *authority },
+            vec![
+                AccountMeta::new(admin.pubkey(), true),
+                AccountMeta::new_readonly(self.config, false),
+                AccountMeta::new(feed, false),
+                AccountMeta::new_readonly(sysvar::rent::id(), false),
+                AccountMeta::new_readonly(system_program::id(), false),
+            ],
+        );
+        let set_index_oracle = perps_instruction(
+            self.program_id,
+            &PerpsInstruction::SetIndexOracle { index_oracle: feed, mark_price_band: None },
+            vec![
+                AccountMeta::new_readonly(admin.pubkey(), true),
+                AccountMeta::new_readonly(self.config, false),
+                AccountMeta::new(self.market, false),
+            ],
+        );
+        self.send(&[set_price_feed, set_index_oracle], &[admin]).await.unwrap();
+        feed
+    }
+
+    /// PushPrice of `price` observed at `timestamp`, signed by `authority`
+    fn push_price(&self, authority: &Trader, feed: Pubkey, price: u64, timestamp: i64) -> Instruction {
+        perps_instruction(
+            self.program_id,
+            &PerpsInstruction::PushPrice { price, conf: TOKEN / 10, timestamp },
+            vec![
+                AccountMeta::new_readonly(authority.keypair.pubkey(), true),
+                AccountMeta::new(feed, false),
+                AccountMeta::new_readonly(sysvar::clock::id(), false),
+            ],
+        )
+    }
+
+    /// Make the market permissioned, whitelist only
+    async fn make_permissioned(&mut self, admin: &Keypair) {
+        let set_market_access = perps_instruction(
+            self.program_id,
+            &PerpsInstruction::SetMarketAccess { permissioned: true, access_mint: Pubkey::default() },
+            vec![
+                AccountMeta::new_readonly(admin.pubkey(), true),
+                AccountMeta::new_readonly(self.config, false),
+                AccountMeta::new(self.market, false),
+            ],
+        );
+        self.send(&[set_market_access], &[admin]).await.unwrap();
+    }
+
+    /// Whitelist `wallet`, returning its whitelist entry
+    async fn whitelist(&mut self, admin: &Keypair, wallet: &Pubkey) -> Pubkey {
+        let (entry, _) = whitelist_address(&self.program_id, wallet);
+        let whitelist = perps_instruction(
+            self.program_id,
+            &PerpsInstruction::SetWhitelisted { wallet: *wallet, whitelisted: true },
+            vec![
+                AccountMeta::new(admin.pubkey(), true),
+                AccountMeta::new_readonly(self.config, false),
+                AccountMeta::new(entry, false),
+                AccountMeta::new_readonly(sysvar::rent::id(), false),
+                AccountMeta::new_readonly(system_program::id(), false),
+            ],
+        );
+        self.send(&[whitelist], &[admin]).await.unwrap();
+        entry
+    }
+
+    /// UpdateParams at the default parameters but `min_collateral_ratio`, signed by `admin`
+    fn update_min_collateral_ratio(&self, admin: &Pubkey, min_collateral_ratio: u64) -> Instruction {
+        self.update_params(admin, min_collateral_ratio, None)
+    }
+
+    /// UpdateParams at the default parameters but `min_collateral_ratio` and `user_limits`, signed by `admin`
+    fn update_params(&self, admin: &Pubkey, min_collateral_ratio: u64, user_limits: Option<(u16, u64)>) -> Instruction {
+        perps_instruction(
+            self.program_id,
+            &PerpsInstruction::UpdateParams {
+                min_collateral_ratio,
+                liquidator_fee_bps: DEFAULT_LIQUIDATOR_FEE_BPS,
+                trading_fee: DEFAULT_TRADING_FEE,
+                referral_fee_share: DEFAULT_REFERRAL_FEE_SHARE,
+                max_funding_rate_per_slot: DEFAULT_MAX_FUNDING_RATE_PER_SLOT,
+                crank_reward: None,
+                insurance_fund_share: None,
+                dust_thresholds: None,
+                max_funding_settlement_share: None,
+                maker_rebate: None,
+                user_limits,
+            },
+            vec![AccountMeta::new_readonly(*admin, true), AccountMeta::new(self.config, false)],
+        )
+    }
+
+    /// Liquidate `owner`'s isolated position, paying `liquidator`; `trailing` follow the fixed accounts
+    fn liquidate(&self, liquidator: &Trader, owner: &Pubkey, trailing: &[AccountMeta]) -> Instruction {
+        let mut accounts = vec![
+            AccountMeta::new_readonly(liquidator.keypair.pubkey(), true),
+            AccountMeta::new_readonly(SPL_TOKEN_PROGRAM_ID, false),
+            AccountMeta::new(liquidator.token_account, false),
+            AccountMeta::new(self.vault, false),
+            AccountMeta::new(self.position_address(owner), false),
+            AccountMeta::new(self.market, false),
+            AccountMeta::new_readonly(sysvar::clock::id(), false),
+            AccountMeta::new_readonly(self.config, false),
+            AccountMeta::new_readonly(self.mint, false),
+        ];
+        accounts.extend_from_slice(trailing);
+        perps_instruction(self.program_id, &PerpsInstruction::Liquidate, accounts)
+    }
+
+    async fn batch_orders(&mut self, trader: &Trader, ops: Vec<OrderOp>) -> Result<(), BanksClientError> {
+        let owner = trader.keypair.pubkey();
+        let batch = perps_instruction(
+            self.program_id,
+            &PerpsInstruction::BatchOrders { ops },
+            vec![
+                AccountMeta::new(owner, true),
+                AccountMeta::new_readonly(self.position_address(&owner), false),
+                AccountMeta::new_readonly(self.market, false),
+                AccountMeta::new(self.orderbook(), false),
+                AccountMeta::new_readonly(sysvar::rent::id(), false),
+                AccountMeta::new_readonly(sysvar::clock::id(), false),
+                AccountMeta::new_readonly(system_program::id(), false),
+            ],
+        );
+        self.send(&[batch], &[&trader.keypair]).await
+    }
+
+    /// Tokenize `trader`'s position, returning its position token account
+    async fn tokenize_position(&mut self, trader: &Trader) -> Pubkey {
+        let position = self.position_address(&trader.keypair.pubkey());
+        let (position_mint, _) = position_mint_address(&self.program_id, &position);
+        let (holder, _) = position_holder_address(&self.program_id, &position);
+        let tokenize = perps_instruction(
+            self.program_id,
+            &PerpsInstruction::TokenizePosition,
+            vec![
+                AccountMeta::new(trader.keypair.pubkey(), true),
+                AccountMeta::new_readonly(SPL_TOKEN_PROGRAM_ID, false),
+                AccountMeta::new(position, false),
+                AccountMeta::new_readonly(self.market, false),
+                AccountMeta::new(position_mint, false),
+                AccountMeta::new(holder, false),
+                AccountMeta::new_readonly(self.vault, false),
+                AccountMeta::new_readonly(self.config, false),
+                AccountMeta::new_readonly(self.mint, false),
+                AccountMeta::new_readonly(sysvar::rent::id(), false),
+                AccountMeta::new_readonly(system_program::id(), false),
+            ],
+        );
+        self.send(&[tokenize], &[&trader.keypair]).await.unwrap();
+        holder
+    }
+
+    /// Hand `trader`'s position NFT to `new_holder` by making them the owner of its token
+    /// account (SetAuthority, AuthorityType::AccountOwner)
+    async fn hand_over_position_token(&mut self, trader: &Trader, new_holder: &Pubkey) {
+        let position = self.position_address(&trader.keypair.pubkey());
+        let (holder, _) = position_holder_address(&self.program_id, &position);
+        let mut data = vec![6, 2, 1];
+        data.extend_from_slice(new_holder.as_ref());
+        let hand_over = Instruction::new_with_bytes(
+            SPL_TOKEN_PROGRAM_ID,
+            &data,
+            vec![AccountMeta::new(holder, false), AccountMeta::new_readonly(trader.keypair.pubkey(), true)],
+        );
+        self.send(&[hand_over], &[&trader.keypair]).await.unwrap();
+    }
+
+    async fn close_position(&mut self, trader: &Trader) -> Result<(), BanksClientError> {
+        let close = self.close_position_instruction(trader);
+        self.send(&[close], &[&trader.keypair]).await
+    }
+
+    fn close_position_instruction(&self, trader: &Trader) -> Instruction {
+        let owner = trader.keypair.pubkey();
+        perps_instruction(
+            self.program_id,
+            &PerpsInstruction::ClosePosition,
+            vec![
+                AccountMeta::new_readonly(owner, true),
+                AccountMeta::new_readonly(SPL_TOKEN_PROGRAM_ID, false),
+                AccountMeta::new(trader.token_account, false),
+                AccountMeta::new(self.vault, false),
+                AccountMeta::new(self.position_address(&owner), false),
+                AccountMeta::new(self.market, false),
+                AccountMeta::new_readonly(self.config, false),
+                AccountMeta::new_readonly(self.mint, false),
+            ],
+        )
+    }
+}
+
+#[tokio::test]
+async fn test_open_fund_liquidate_and_close() {
+    let (mut env, traders) = setup(9, &[1_000 * TOKEN, 1_000 * TOKEN, 0]).await;
+    let [alice, bob, keeper] = &traders[..] else { unreachable!() };
+    let vault_float = env.token_balance(env.vault).await;
+
+    // Only the config admin (the payer) can create the market
+    assert!(env.initialize_market(&alice.keypair, 100 * TOKEN, 9).await.is_err());
+    let admin = env.context.payer.insecure_clone();
+    env.initialize_market(&admin, 100 * TOKEN, 9).await.unwrap();
+    let market_state = env.market_state().await;
+    assert_eq!((market_state.market_id, market_state.mark_price), (0, 100 * TOKEN));
+
+    // Alice goes long at $100; Bob takes the short side
+    env.open_position(alice, SIZE, COLLATERAL, 0).await.unwrap();
+    env.open_position(bob, -SIZE, COLLATERAL, 0).await.unwrap();
+
+    assert_eq!(env.token_balance(alice.token_account).await, 800 * TOKEN);
+    assert_eq!(env.token_balance(env.vault).await, vault_float + 2 * COLLATERAL);
+    let long = env.position(&alice.keypair.pubkey()).await;
+    assert_eq!(long.base_amount, SIZE);
+    assert!(long.collateral < COLLATERAL, "the trading fee comes out of the collateral");
+    assert_eq!(env.market_state().await.open_interest, 2 * SIZE as u64);
+
+    // An open without collateral fails the margin check and moves nothing
+    assert!(env.open_position(keeper, SIZE, 0, 0).await.is_err());
+
+    // Funding accrues across slots
+    let slot = env.context.banks_client.get_root_slot().await.unwrap();
+    env.context.warp_to_slot(slot + 100).unwrap();
+    let update_funding = perps_instruction(
+        env.program_id,
+        &PerpsInstruction::UpdateFunding,
+        vec![
+            AccountMeta::new(env.market, false),
+            AccountMeta::new_readonly(sysvar::clock::id(), false),
+            AccountMeta::new_readonly(env.config, false),
+        ],
+    );
+    env.send(std::slice::from_ref(&update_funding), &[]).await.unwrap();
+    let market_state = env.market_state().await;
+    assert_eq!(market_state.funding_index, 0, "a balanced market pays no funding");
+
+    // Cross collateral round-trips through the vault
+    let owner = alice.keypair.pubkey();
+    let (user_account, _) = Pubkey::find_program_address(&[USER_ACCOUNT_SEED, owner.as_ref()], &env.program_id);
+    let collateral_accounts = vec![
+        AccountMeta::new_readonly(owner, true),
+        AccountMeta::new_readonly(SPL_TOKEN_PROGRAM_ID, false),
+        AccountMeta::new(alice.token_account, false),
+        AccountMeta::new(env.vault, false),
+        AccountMeta::new(user_account, false),
+        AccountMeta::new_readonly(env.config, false),
+        AccountMeta::new_readonly(env.mint, false),
+    ];
+    let create_user_account = perps_instruction(
+        env.program_id,
+        &PerpsInstruction::CreateUserAccount,
+        vec![
+            AccountMeta::new(owner, true),
+            AccountMeta::new(user_account, false),
+            AccountMeta::new_readonly(sysvar::rent::id(), false),
+            AccountMeta::new_readonly(system_program::id(), false),
+        ],
+    );
+    let deposit = perps_instruction(
+        env.program_id,
+        &PerpsInstruction::DepositCrossCollateral { amount: 50 * TOKEN },
+        collateral_accounts.clone(),
+    );
+    let withdraw = perps_instruction(
+        env.program_id,
+        &PerpsInstruction::WithdrawCrossCollateral { amount: 30 * TOKEN },
+        collateral_accounts,
+    );
+    env.send(&[create_user_account, deposit, withdraw], &[&alice.keypair]).await.unwrap();
+    assert_eq!(env.token_balance(alice.token_account).await, 780 * TOKEN);
+
+    // Tightening the minimum collateral ratio leaves Bob's short liquidatable
+    let update_params = env.update_min_collateral_ratio(&admin.pubkey(), 5 * TOKEN);
+    let liquidate = env.liquidate(keeper, &bob.keypair.pubkey(), &[]);
+    env.send(&[update_params, liquidate], &[&keeper.keypair]).await.unwrap();
+
+    let short = env.position(&bob.keypair.pubkey()).await;
+    assert_eq!(short.base_amount, 0);
+    assert!(env.token_balance(keeper.token_account).await > 0, "the liquidator is paid its fee share");
+    assert_eq!(env.market_state().await.open_interest, SIZE as u64);
+
+    // With only the long left, longs pay the full skew rate
+    let slot = env.context.banks_client.get_root_slot().await.unwrap();
+    env.context.warp_to_slot(slot + 100).unwrap();
+    env.send(&[update_funding], &[]).await.unwrap();
+    assert!(env.market_state().await.funding_index > 0, "longs pay the skew funding rate");
+
+    // Alice closes: she paid funding, and her collateral comes back out of the vault
+    env.close_position(alice).await.unwrap();
+
+    let long = env.position(&owner).await;
+    assert_eq!((long.base_amount, long.collateral), (0, 0));
+    assert!(long.cumulative_funding < 0, "the long paid funding");
+    let returned = env.token_balance(alice.token_account).await - 780 * TOKEN;
+    assert!(returned > 180 * TOKEN && returned < COLLATERAL, "returned {}", returned);
+    assert_eq!(env.market_state().await.open_interest, 0);
+}
+
+#[tokio::test]
+async fn test_six_decimal_quote_market() {
+    // A USDC-like quote mint and a market sized in thousandths of the base token
+    const USDC: u64 = 1_000_000;
+    let (mut env, traders) = setup(6, &[1_000 * USDC]).await;
+    let [alice] = &traders[..] else { unreachable!() };
+    let vault_float = env.token_balance(env.vault).await;
+
+    let admin = env.context.payer.insecure_clone();
+    env.initialize_market(&admin, 100 * TOKEN, 3).await.unwrap();
+    let market_state = env.market_state().await;
+    assert_eq!((market_state.base_decimals, market_state.quote_decimals), (3, 6));
+
+    // One base token (1_000 units) backed by 200 USDC, both held in 1e9 precision
+    env.open_position(alice, 1_000, 200 * USDC, 101 * TOKEN).await.unwrap();
+    assert_eq!(env.token_balance(alice.token_account).await, 800 * USDC);
+    assert_eq!(env.token_balance(env.vault).await, vault_float + 200 * USDC);
+    let long = env.position(&alice.keypair.pubkey()).await;
+    assert_eq!(long.base_amount, TOKEN as i64);
+    assert!(long.collateral < 200 * TOKEN && long.collateral > 199 * TOKEN, "collateral {}", long.collateral);
+    assert_eq!(env.market_state().await.open_interest, TOKEN);
+
+    // The close pays out in USDC units, rounded down, and the vault gives up exactly that
+    env.warp_slots(1).await;
+    env.close_position(alice).await.unwrap();
+    let returned = env.token_balance(alice.token_account).await - 800 * USDC;
+    assert!(returned > 199 * USDC && returned < 200 * USDC, "returned {}", returned);
+    assert_eq!(env.token_balance(env.vault).await, vault_float + 200 * USDC - returned);
+}
+
+#[tokio::test]
+async fn test_market_params_are_set_per_market() {
+    let (mut env, traders) = setup(9, &[1_000 * TOKEN]).await;
+    let [alice] = &traders[..] else { unreachable!() };
+    let admin = env.context.payer.insecure_clone();
+    env.initialize_market(&admin, 100 * TOKEN, 9).await.unwrap();
+    env.initialize_market_id(&admin, 1, 100 * TOKEN, 6).await.unwrap();
+    let (other_market, _) = market_address(&env.program_id, 1);
+
+    // Market 1 counts its base in 6 decimals; market 0 keeps its limits
+    let update_market_params = perps_instruction(
+        env.program_id,
+        &PerpsInstruction::UpdateMarketParams {
+            max_leverage: DEFAULT_MAX_LEVERAGE,
+            max_open_interest: 500_000,
+            price_impact_depth: None,
+            skew_fee: None,
+        },
+        vec![
+            AccountMeta::new_readonly(admin.pubkey(), true),
+            AccountMeta::new_readonly(env.config, false),
+            AccountMeta::new(other_market, false),
+        ],
+    );
+    env.send(&[update_market_params], &[]).await.unwrap();
+    let capped = *MarketState::load(&env.account_data(other_market).await).unwrap();
+    assert_eq!(capped.max_open_interest, TOKEN / 2);
+    let market_state = env.market_state().await;
+    assert_eq!(market_state.max_open_interest, DEFAULT_MAX_OPEN_INTEREST);
+
+    env.open_position(alice, SIZE, COLLATERAL, 0).await.unwrap();
+    env.market = other_market;
+    assert!(env.open_position(alice, 1_000_000, COLLATERAL, 0).await.is_err());
+    env.open_position(alice, 400_000, COLLATERAL, 0).await.unwrap();
+}
+
+#[tokio::test]
+async fn test_crank_match_crossed_orders() {
+    let (mut env, traders) = setup(9, &[1_000 * TOKEN, 1_000 * TOKEN, 0]).await;
+    let [alice, bob, cranker] = &traders[..] else { unreachable!() };
+    let admin = env.context.payer.insecure_clone();
+    env.initialize_market(&admin, 100 * TOKEN, 9).await.unwrap();
+    env.open_position(alice, SIZE, COLLATERAL, 0).await.unwrap();
+    env.open_position(bob, -SIZE, COLLATERAL, 0).await.unwrap();
+
+    // Once their hold is over, Alice offers half her long at $99, then Bob bids $101 to cover half his short
+    env.warp_slots(1).await;
+    let orderbook = env.orderbook();
+    env.place_order(alice, OrderSide::Ask, 99 * TOKEN, SIZE as u64 / 2, 0, &[]).await.unwrap();
+    env.place_order(bob, OrderSide::Bid, 101 * TOKEN, SIZE as u64 / 2, 0, &[]).await.unwrap();
+
+    let crank_match = perps_instruction(
+        env.program_id,
+        &PerpsInstruction::CrankMatch { max_fills: 4 },
+        vec![
+            AccountMeta::new(env.market, false),
+            AccountMeta::new(orderbook, false),
+            AccountMeta::new_readonly(env.config, false),
+            AccountMeta::new_readonly(SPL_TOKEN_PROGRAM_ID, false),
+            AccountMeta::new(cranker.token_account, false),
+            AccountMeta::new(env.vault, false),
+            AccountMeta::new_readonly(env.mint, false),
+            AccountMeta::new(env.position_address(&alice.keypair.pubkey()), false),
+            AccountMeta::new(env.position_address(&bob.keypair.pubkey()), false),
+        ],
+    );
+    let mark_price = env.market_state().await.mark_price;
+    env.send(&[crank_match], &[]).await.unwrap();
+
+    // Both orders fill in full at the maker's (Alice's) price, peer to peer
+    let (long, short) = (env.position(&alice.keypair.pubkey()).await, env.position(&bob.keypair.pubkey()).await);
+    assert_eq!((long.base_amount, short.base_amount), (SIZE / 2, -SIZE / 2));
+    let market_state = env.market_state().await;
+    assert_eq!(market_state.open_interest, SIZE as u64);
+    assert_eq!(market_state.mark_price, mark_price, "crossed fills don't trade against the vAMM");
+    assert!(OrderBook::load(&env.account_data(orderbook).await).unwrap().orders.is_empty());
+    assert_eq!(env.token_balance(cranker.token_account).await, MATCH_FILL_REWARD);
+
+    // Alice made the market: she earned the 0.02% rebate on $49.50 out of Bob's fee
+    assert_eq!(long.maker_rebates, 9_900_000);
+    let alice_balance = env.token_balance(alice.token_account).await;
+    let claim = perps_instruction(
+        env.program_id,
+        &PerpsInstruction::ClaimMakerRebates,
+        vec![
+            AccountMeta::new_readonly(alice.keypair.pubkey(), true),
+            AccountMeta::new_readonly(SPL_TOKEN_PROGRAM_ID, false),
+            AccountMeta::new(alice.token_account, false),
+            AccountMeta::new(env.vault, false),
+            AccountMeta::new(env.position_address(&alice.keypair.pubkey()), false),
+            AccountMeta::new(env.market, false),
+            AccountMeta::new_readonly(env.config, false),
+            AccountMeta::new_readonly(env.mint, false),
+        ],
+    );
+    env.send(&[claim], &[&alice.keypair]).await.unwrap();
+    assert_eq!(env.token_balance(alice.token_account).await, alice_balance + 9_900_000);
+    assert_eq!(env.position(&alice.keypair.pubkey()).await.maker_rebates, 0);
+}
+
+#[tokio::test]
+async fn test_post_only_and_immediate_or_cancel_orders() {
+    let (mut env, traders) = setup(9, &[1_000 * TOKEN, 1_000 * TOKEN]).await;
+    let [alice, bob] = &traders[..] else { unreachable!() };
+    let admin = env.context.payer.insecure_clone();
+    env.initialize_market(&admin, 100 * TOKEN, 9).await.unwrap();
+    env.open_position(alice, SIZE, COLLATERAL, 0).await.unwrap();
+    env.open_position(bob, -SIZE, COLLATERAL, 0).await.unwrap();
+
+    // Alice rests half her long at $99; a post-only bid above it is rejected, one below rests
+    env.place_order(alice, OrderSide::Ask, 99 * TOKEN, SIZE as u64 / 2, 0, &[]).await.unwrap();
+    assert!(env.place_order(bob, OrderSide::Bid, 101 * TOKEN, SIZE as u64, ORDER_FLAG_POST_ONLY, &[]).await.is_err());
+    env.place_order(bob, OrderSide::Bid, 98 * TOKEN, SIZE as u64 / 4, ORDER_FLAG_POST_ONLY, &[]).await.unwrap();
+
+    // Inside the hold his short started, Bob's IOC bid can't cover it and is cancelled unfilled
+    env.place_order(bob, OrderSide::Bid, 101 * TOKEN, SIZE as u64, ORDER_FLAG_IMMEDIATE_OR_CANCEL, &[alice])
+        .await
+        .unwrap();
+    assert_eq!(env.position(&bob.keypair.pubkey()).await.base_amount, -SIZE);
+
+    // Afterwards the same IOC bid takes Alice's half at her price; the rest never rests
+    env.warp_slots(1).await;
+    env.place_order(bob, OrderSide::Bid, 101 * TOKEN, SIZE as u64, ORDER_FLAG_IMMEDIATE_OR_CANCEL, &[alice])
+        .await
+        .unwrap();
+    let (long, short) = (env.position(&alice.keypair.pubkey()).await, env.position(&bob.keypair.pubkey()).await);
+    assert_eq!((long.base_amount, short.base_amount), (SIZE / 2, -SIZE / 2));
+    let orderbook = env.orderbook();
+    let book = OrderBook::load(&env.account_data(orderbook).await).unwrap();
+    assert_eq!(book.orders.len(), 1, "only Bob's post-only bid rests");
+    assert_eq!((book.orders[0].side, book.orders[0].price), (OrderSide::Bid, 98 * TOKEN));
+}
+
+#[tokio::test]
+async fn test_permissioned_market_requires_whitelist() {
+    let (mut env, traders) = setup(9, &[1_000 * TOKEN, 1_000 * TOKEN]).await;
+    let [alice, bob] = &traders[..] else { unreachable!() };
+    let admin = env.context.payer.insecure_clone();
+    env.initialize_market(&admin, 100 * TOKEN, 9).await.unwrap();
+    env.open_position(bob, SIZE, COLLATERAL, 0).await.unwrap();
+    env.make_permissioned(&admin).await;
+
+    // Alice can't open until the admin whitelists her
+    assert!(env.open_position(alice, SIZE, COLLATERAL, 0).await.is_err());
+    let alice_key = alice.keypair.pubkey();
+    let entry = env.whitelist(&admin, &alice_key).await;
+    env.open_position_with(alice, SIZE, COLLATERAL, 0, &[AccountMeta::new_readonly(entry, false)]).await.unwrap();
+    assert_eq!(env.position(&alice_key).await.base_amount, SIZE);
+
+    // Bob, never whitelisted, can still reduce but not grow
+    assert!(env.open_position(bob, SIZE, 0, 0).await.is_err());
+    env.warp_slots(1).await;
+    env.open_position(bob, -SIZE / 2, 0, 0).await.unwrap();
+    assert_eq!(env.position(&bob.keypair.pubkey()).await.base_amount, SIZE / 2);
+}
+
+#[tokio::test]
+async fn test_permissioned_market_gates_orders_that_grow_positions() {
+    let (mut env, traders) = setup(9, &[1_000 * TOKEN, 1_000 * TOKEN]).await;
+    let [alice, bob] = &traders[..] else { unreachable!() };
+    let admin = env.context.payer.insecure_clone();
+    env.initialize_market(&admin, 100 * TOKEN, 9).await.unwrap();
+    env.open_position(bob, SIZE, COLLATERAL, 0).await.unwrap();
+    env.make_permissioned(&admin).await;
+    let entry = env.whitelist(&admin, &alice.keypair.pubkey()).await;
+    env.open_position_with(alice, -SIZE, COLLATERAL, 0, &[AccountMeta::new_readonly(entry, false)]).await.unwrap();
+
+    // Bob, never whitelisted, can't rest a bid that grows his long, but can offer it up
+    assert!(env.place_order(bob, OrderSide::Bid, 99 * TOKEN, SIZE as u64, 0, &[]).await.is_err());
+    env.place_order(bob, OrderSide::Ask, 99 * TOKEN, SIZE as u64, 0, &[]).await.unwrap();
+
+    // Once he has sold half elsewhere, filling the ask would flip him short: the fill drops it
+    env.warp_slots(1).await;
+    env.open_position(bob, -SIZE / 2, 0, 0).await.unwrap();
+    env.place_order(alice, OrderSide::Bid, 101 * TOKEN, SIZE as u64, ORDER_FLAG_IMMEDIATE_OR_CANCEL, &[bob])
+        .await
+        .unwrap();
+    assert_eq!(env.position(&bob.keypair.pubkey()).await.base_amount, SIZE / 2);
+    assert_eq!(env.position(&alice.keypair.pubkey()).await.base_amount, -SIZE);
+    let orderbook = env.orderbook();
+    assert!(OrderBook::load(&env.account_data(orderbook).await).unwrap().orders.is_empty());
+}
+
+#[tokio::test]
+async fn test_order_fills_respect_the_notional_cap() {
+    let (mut env, traders) = setup(9, &[1_000 * TOKEN, 1_000 * TOKEN]).await;
+    let [alice, bob] = &traders[..] else { unreachable!() };
+    let admin = env.context.payer.insecure_clone();
+    env.initialize_market(&admin, 100 * TOKEN, 9).await.unwrap();
+    env.open_position(alice, SIZE, 3 * COLLATERAL, 0).await.unwrap();
+    env.open_position(bob, SIZE, COLLATERAL, 0).await.unwrap();
+    let user_limits = env.update_params(&admin.pubkey(), DEFAULT_MIN_COLLATERAL_RATIO, Some((0, 150 * TOKEN)));
+    env.send(&[user_limits], &[]).await.unwrap();
+    env.warp_slots(1).await;
+
+    // Doubling Alice's $100 long would take her past the $150 cap, so the fill drops her bid
+    env.place_order(alice, OrderSide::Bid, 101 * TOKEN, SIZE as u64, 0, &[]).await.unwrap();
+    env.place_order(bob, OrderSide::Ask, 99 * TOKEN, SIZE as u64, ORDER_FLAG_IMMEDIATE_OR_CANCEL, &[alice])
+        .await
+        .unwrap();
+    assert_eq!(env.position(&alice.keypair.pubkey()).await.base_amount, SIZE);
+    let orderbook = env.orderbook();
+    assert!(OrderBook::load(&env.account_data(orderbook).await).unwrap().orders.is_empty());
+
+    // A quarter more stays under it
+    env.place_order(alice, OrderSide::Bid, 101 * TOKEN, SIZE as u64 / 4, 0, &[]).await.unwrap();
+    env.place_order(bob, OrderSide::Ask, 99 * TOKEN, SIZE as u64, ORDER_FLAG_IMMEDIATE_OR_CANCEL, &[alice])
+        .await
+        .unwrap();
+    assert_eq!(env.position(&alice.keypair.pubkey()).await.base_amount, SIZE * 5 / 4);
+    assert_eq!(env.position(&bob.keypair.pubkey()).await.base_amount, SIZE * 3 / 4);
+}
+
+#[tokio::test]
+async fn test_liquidator_allowlist() {
+    let (mut env, traders) = setup(9, &[1_000 * TOKEN, 1_000 * TOKEN, 0]).await;
+    let [alice, bob, keeper] = &traders[..] else { unreachable!() };
+    let admin = env.context.payer.insecure_clone();
+    env.initialize_market(&admin, 100 * TOKEN, 9).await.unwrap();
+    env.open_position(alice, SIZE, COLLATERAL, 0).await.unwrap();
+    env.open_position(bob, -SIZE, COLLATERAL, 0).await.unwrap();
+
+    let restrict = perps_instruction(
+        env.program_id,
+        &PerpsInstruction::SetLiquidatorAllowlist { enabled: true },
+        vec![AccountMeta::new_readonly(admin.pubkey(), true), AccountMeta::new(env.config, false)],
+    );
+    let update_params = env.update_min_collateral_ratio(&admin.pubkey(), 5 * TOKEN);
+    env.send(&[restrict, update_params], &[]).await.unwrap();
+
+    // Bob's short is liquidatable, but the keeper isn't registered
+    let (registry, _) = liquidator_registry_address(&env.program_id);
+    let bob_key = bob.keypair.pubkey();
+    let liquidate = env.liquidate(keeper, &bob_key, &[AccountMeta::new_readonly(registry, false)]);
+    assert!(env.send(&[env.liquidate(keeper, &bob_key, &[])], &[&keeper.keypair]).await.is_err());
+
+    let register = perps_instruction(
+        env.program_id,
+        &PerpsInstruction::SetLiquidator { liquidator: keeper.keypair.pubkey(), allowed: true },
+        vec![
+            AccountMeta::new(admin.pubkey(), true),
+            AccountMeta::new_readonly(env.config, false),
+            AccountMeta::new(registry, false),
+            AccountMeta::new_readonly(sysvar::rent::id(), false),
+            AccountMeta::new_readonly(system_program::id(), false),
+        ],
+    );
+    env.send(&[register], &[]).await.unwrap();
+
+    // Once registered, the keeper liquidates by passing the registry
+    env.send(&[liquidate], &[&keeper.keypair]).await.unwrap();
+    assert_eq!(env.position(&bob_key).await.base_amount, 0);
+}
+
+#[tokio::test]
+async fn test_settlement_only_mode_lets_positions_wind_down() {
+    let (mut env, traders) = setup(9, &[1_000 * TOKEN, 1_000 * TOKEN, 0]).await;
+    let [alice, bob, keeper] = &traders[..] else { unreachable!() };
+    let admin = env.context.payer.insecure_clone();
+    env.initialize_market(&admin, 100 * TOKEN, 9).await.unwrap();
+    env.open_position(alice, SIZE, COLLATERAL, 0).await.unwrap();
+    env.open_position(bob, -SIZE, COLLATERAL, 0).await.unwrap();
+
+    let settlement_only = |enabled| {
+        perps_instruction(
+            env.program_id,
+            &PerpsInstruction::SetSettlementOnly { enabled },
+            vec![AccountMeta::new_readonly(admin.pubkey(), true), AccountMeta::new(env.config, false)],
+        )
+    };
+    let (enable, disable) = (settlement_only(true), settlement_only(false));
+    env.send(&[enable], &[]).await.unwrap();
+
+    // Nothing grows, but Alice can close and Bob can still be liquidated
+    env.warp_slots(1).await;
+    assert!(env.open_position(alice, SIZE, COLLATERAL, 0).await.is_err());
+    env.close_position(alice).await.unwrap();
+    assert_eq!(env.position(&alice.keypair.pubkey()).await.base_amount, 0);
+
+    let update_params = env.update_min_collateral_ratio(&admin.pubkey(), 5 * TOKEN);
+    env.send(&[update_params], &[]).await.unwrap();
+    let bob_key = bob.keypair.pubkey();
+    env.send(&[env.liquidate(keeper, &bob_key, &[])], &[&keeper.keypair]).await.unwrap();
+    assert_eq!(env.position(&bob_key).await.base_amount, 0);
+
+    // Switching the mode off opens trading again
+    env.send(&[disable], &[]).await.unwrap();
+    env.open_position(alice, SIZE, 3 * COLLATERAL, 0).await.unwrap();
+    assert_eq!(env.position(&alice.keypair.pubkey()).await.base_amount, SIZE);
+}
+
+#[tokio::test]
+async fn test_increase_and_decrease_position() {
+    let (mut env, traders) = setup(9, &[1_000 * TOKEN, 1_000 * TOKEN]).await;
+    let [alice, bob] = &traders[..] else { unreachable!() };
+    let admin = env.context.payer.insecure_clone();
+    env.initialize_market(&admin, 100 * TOKEN, 9).await.unwrap();
+    let owner = alice.keypair.pubkey();
+    let increase = |base_delta| PerpsInstruction::IncreasePosition {
+        base_delta, collateral_delta: COLLATERAL, price_limit: 0, flags: 0, sub_account_id: 0,
+    };
+    let decrease = |base_amount| PerpsInstruction::DecreasePosition { base_amount, price_limit: 0, flags: 0, sub_account_id: 0 };
+
+    // There is nothing to decrease before the position exists
+    let early = env.position_change_instruction(alice, &decrease(SIZE as u64), &[]);
+    assert!(env.send(&[early], &[&alice.keypair]).await.is_err());
+
+    // Alice opens long and grows it; an increase can't trade against her side
+    let open = env.position_change_instruction(alice, &increase(SIZE), &[]);
+    env.send(&[open], &[&alice.keypair]).await.unwrap();
+    let grow = env.position_change_instruction(alice, &increase(SIZE / 2), &[]);
+    env.send(&[grow], &[&alice.keypair]).await.unwrap();
+    let against = env.position_change_instruction(alice, &increase(-SIZE), &[]);
+    assert!(env.send(&[against], &[&alice.keypair]).await.is_err());
+    assert_eq!(env.position(&owner).await.base_amount, 3 * SIZE / 2);
+
+    // Bob's buying lifts the mark, so Alice's decrease sells a third at a profit
+    env.open_position(bob, SIZE, COLLATERAL, 0).await.unwrap();
+    env.warp_slots(1).await;
+    let flip = env.position_change_instruction(alice, &decrease(3 * SIZE as u64), &[]);
+    assert!(env.send(&[flip], &[&alice.keypair]).await.is_err(), "a decrease can't flip the position");
+    let third = env.position_change_instruction(alice, &decrease(SIZE as u64 / 2), &[]);
+    env.send(&[third], &[&alice.keypair]).await.unwrap();
+    let long = env.position(&owner).await;
+    assert_eq!(long.base_amount, SIZE);
+    assert!(long.realized_pnl > 0, "the decrease realizes its PnL");
+
+    // The legacy tag still resizes either way
+    env.open_position(alice, -SIZE, 0, 0).await.unwrap();
+    assert_eq!(env.position(&owner).await.base_amount, 0);
+}
+
+#[tokio::test]
+async fn test_market_metrics_accumulate_trades_and_liquidations() {
+    let (mut env, traders) = setup(9, &[1_000 * TOKEN, 1_000 * TOKEN, 0]).await;
+    let [alice, bob, keeper] = &traders[..] else { unreachable!() };
+    let admin = env.context.payer.insecure_clone();
+    env.initialize_market(&admin, 100 * TOKEN, 9).await.unwrap();
+
+    let (metrics, _) = market_metrics_address(&env.program_id, &env.market);
+    let create = perps_instruction(
+        env.program_id,
+        &PerpsInstruction::CreateMarketMetrics,
+        vec![
+            AccountMeta::new(admin.pubkey(), true),
+            AccountMeta::new_readonly(env.market, false),
+            AccountMeta::new(metrics, false),
+            AccountMeta::new_readonly(sysvar::rent::id(), false),
+            AccountMeta::new_readonly(system_program::id(), false),
+        ],
+    );
+    env.send(&[create], &[]).await.unwrap();
+
+    // Trades that pass the metrics account count toward it; the ones that don't are missed
+    let trailing = [AccountMeta::new(metrics, false)];
+    env.open_position_with(alice, SIZE, COLLATERAL, 0, &trailing).await.unwrap();
+    env.open_position_with(bob, -SIZE, COLLATERAL, 0, &trailing).await.unwrap();
+    env.open_position(alice, SIZE, COLLATERAL, 0).await.unwrap();
+    let read = |data: Vec<u8>| MarketMetrics::deserialize(&mut &data[DISCRIMINATOR_LEN..]).unwrap();
+    let traded = read(env.account_data(metrics).await);
+    assert_eq!(traded.market, env.market);
+    assert!(traded.total_volume > 2 * 99 * TOKEN && traded.total_volume < 2 * 101 * TOKEN);
+    assert!(traded.total_fees > 0);
+    assert_eq!(traded.peak_open_interest, 2 * SIZE as u64);
+    assert_eq!(traded.total_liquidations, 0);
+
+    let update_params = env.update_min_collateral_ratio(&admin.pubkey(), 5 * TOKEN);
+    env.send(&[update_params], &[]).await.unwrap();
+    let liquidate = env.liquidate(keeper, &bob.keypair.pubkey(), &trailing);
+    env.send(&[liquidate], &[&keeper.keypair]).await.unwrap();
+    let liquidated = read(env.account_data(metrics).await);
+    assert_eq!(liquidated.total_liquidations, 1);
+    assert!(liquidated.total_volume > traded.total_volume + 99 * TOKEN);
+    assert!(liquidated.total_fees > traded.total_fees);
+    assert_eq!(liquidated.peak_open_interest, traded.peak_open_interest);
+}
+
+#[tokio::test]
+async fn test_update_funding_records_its_history() {
+    let (mut env, traders) = setup(9, &[1_000 * TOKEN]).await;
+    let [alice] = &traders[..] else { unreachable!() };
+    let admin = env.context.payer.insecure_clone();
+    env.initialize_market(&admin, 100 * TOKEN, 9).await.unwrap();
+    env.open_position(alice, SIZE, COLLATERAL, 0).await.unwrap();
+
+    let (history, _) = funding_history_address(&env.program_id, &env.market);
+    let create = perps_instruction(
+        env.program_id,
+        &PerpsInstruction::CreateFundingHistory,
+        vec![
+            AccountMeta::new(admin.pubkey(), true),
+            AccountMeta::new_readonly(env.market, false),
+            AccountMeta::new(history, false),
+            AccountMeta::new_readonly(sysvar::rent::id(), false),
+            AccountMeta::new_readonly(system_program::id(), false),
+        ],
+    );
+    env.send(&[create], &[]).await.unwrap();
+
+    // The history rides along with or without the keeper reward accounts
+    let update_funding = |trailing: &[AccountMeta]| {
+        let mut accounts = vec![
+            AccountMeta::new(env.market, false),
+            AccountMeta::new_readonly(sysvar::clock::id(), false),
+            AccountMeta::new_readonly(env.config, false),
+        ];
+        accounts.extend_from_slice(trailing);
+        perps_instruction(env.program_id, &PerpsInstruction::UpdateFunding, accounts)
+    };
+    let with_history = update_funding(&[AccountMeta::new(history, false)]);
+    let with_reward = update_funding(&[
+        AccountMeta::new_readonly(SPL_TOKEN_PROGRAM_ID, false),
+        AccountMeta::new(alice.token_account, false),
+        AccountMeta::new(env.vault, false),
+        AccountMeta::new_readonly(env.mint, false),
+        AccountMeta::new(history, false),
+    ]);
+    env.warp_slots(100).await;
+    env.send(&[with_history], &[]).await.unwrap();
+    env.warp_slots(50).await;
+    env.send(&[with_reward], &[]).await.unwrap();
+
+    let history = FundingHistory::deserialize(&mut &env.account_data(history).await[DISCRIMINATOR_LEN..]).unwrap();
+    let market_state = env.market_state().await;
+    assert_eq!(history.records.len(), 2);
+    let (first, latest) = (history.records[0], history.records[1]);
+    assert_eq!(latest.slot, market_state.last_funding_slot);
+    assert_eq!(latest.funding_index, market_state.funding_index);
+    assert_eq!(latest.funding_rate_per_slot, market_state.funding_rate_per_slot);
+    // An all-long market pays the full skew rate, clamped to the cap
+    assert!(latest.funding_rate_per_slot > 0 && latest.premium >= latest.funding_rate_per_slot);
+    let slots = (latest.slot - first.slot) as i64;
+    assert_eq!(history.average_rate_per_slot(), Some((latest.funding_index - first.funding_index) / slots));
+}
+
+#[tokio::test]
+async fn test_price_touching_instructions_build_candles() {
+    let (mut env, traders) = setup(9, &[1_000 * TOKEN, 1_000 * TOKEN]).await;
+    let [alice, bob] = &traders[..] else { unreachable!() };
+    let admin = env.context.payer.insecure_clone();
+    env.initialize_market(&admin, 100 * TOKEN, 9).await.unwrap();
+
+    let (history, _) = price_history_address(&env.program_id, &env.market);
+    let create = perps_instruction(
+        env.program_id,
+        &PerpsInstruction::CreatePriceHistory,
+        vec![
+            AccountMeta::new(admin.pubkey(), true),
+            AccountMeta::new_readonly(env.market, false),
+            AccountMeta::new(history, false),
+            AccountMeta::new_readonly(sysvar::rent::id(), false),
+            AccountMeta::new_readonly(system_program::id(), false),
+        ],
+    );
+    env.send(&[create], &[]).await.unwrap();
+
+    // Alice's long lifts the mark, Bob's larger short drops it, and a funding crank records it again
+    let trailing = [AccountMeta::new(history, false)];
+    env.open_position_with(alice, SIZE, COLLATERAL, 0, &trailing).await.unwrap();
+    let high = env.market_state().await.mark_price;
+    env.open_position_with(bob, -2 * SIZE, 2 * COLLATERAL, 0, &trailing).await.unwrap();
+    let low = env.market_state().await.mark_price;
+    env.warp_slots(1).await;
+    let update_funding = perps_instruction(
+        env.program_id,
+        &PerpsInstruction::UpdateFunding,
+        vec![
+            AccountMeta::new(env.market, false),
+            AccountMeta::new_readonly(sysvar::clock::id(), false),
+            AccountMeta::new_readonly(env.config, false),
+            AccountMeta::new(history, false),
+        ],
+    );
+    env.send(&[update_funding], &[]).await.unwrap();
+    assert!(high > 100 * TOKEN && low < 100 * TOKEN);
+
+    // Usually one candle, two if the test straddles an hour
+    let history = PriceHistory::deserialize(&mut &env.account_data(history).await[DISCRIMINATOR_LEN..]).unwrap();
+    let candles: Vec<_> = history.candles_oldest_first().copied().collect();
+    assert!(!candles.is_empty() && candles.len() <= 2);
+    assert_eq!(candles[0].open, high);
+    assert_eq!(candles.iter().map(|candle| candle.high).max(), Some(high));
+    assert_eq!(candles.iter().map(|candle| candle.low).min(), Some(low));
+    assert_eq!(candles.last().unwrap().close, env.market_state().await.mark_price);
+}
+
+#[tokio::test]
+async fn test_batch_orders_refresh_quotes() {
+    let (mut env, traders) = setup(9, &[1_000 * TOKEN]).await;
+    let [alice] = &traders[..] else { unreachable!() };
+    let admin = env.context.payer.insecure_clone();
+    env.initialize_market(&admin, 100 * TOKEN, 9).await.unwrap();
+    env.open_position(alice, SIZE, COLLATERAL, 0).await.unwrap();
+
+    let quote = |side, price| OrderOp::Place { side, price, base_amount: SIZE as u64 / 4, flags: ORDER_FLAG_POST_ONLY };
+    env.batch_orders(alice, vec![quote(OrderSide::Bid, 99 * TOKEN), quote(OrderSide::Ask, 101 * TOKEN)]).await.unwrap();
+
+    // Refresh both quotes in one instruction: the cancels free room before the new orders rest
+    let refresh = vec![
+        OrderOp::Cancel { order_id: 0 },
+        OrderOp::Cancel { order_id: 1 },
+        quote(OrderSide::Bid, 98 * TOKEN),
+        quote(OrderSide::Ask, 102 * TOKEN),
+    ];
+    env.batch_orders(alice, refresh).await.unwrap();
+    let book = OrderBook::load(&env.account_data(env.orderbook()).await).unwrap();
+    let quotes: Vec<_> = book.orders.iter().map(|order| (order.order_id, order.price)).collect();
+    assert_eq!(quotes, vec![(2, 98 * TOKEN), (3, 102 * TOKEN)]);
+
+    // A failing operation reverts the whole batch
+    let stale = vec![quote(OrderSide::Bid, 97 * TOKEN), OrderOp::Cancel { order_id: 0 }];
+    assert!(env.batch_orders(alice, stale).await.is_err());
+    assert_eq!(OrderBook::load(&env.account_data(env.orderbook()).await).unwrap().orders.len(), 2);
+}
+
+#[tokio::test]
+async fn test_liquidate_many_skips_healthy_positions() {
+    let (mut env, traders) = setup(9, &[1_000 * TOKEN, 1_000 * TOKEN, 1_000 * TOKEN, 0]).await;
+    let [alice, bob, carol, keeper] = &traders[..] else { unreachable!() };
+    let admin = env.context.payer.insecure_clone();
+    env.initialize_market(&admin, 100 * TOKEN, 9).await.unwrap();
+
+    // Bob and Carol short thinly collateralized; Alice's long is backed twice over
+    env.open_position(alice, SIZE, 2 * COLLATERAL, 0).await.unwrap();
+    env.open_position(bob, -SIZE / 2, COLLATERAL / 2, 0).await.unwrap();
+    env.open_position(carol, -SIZE / 2, COLLATERAL / 2, 0).await.unwrap();
+    let update_params = env.update_min_collateral_ratio(&admin.pubkey(), 3 * TOKEN);
+    env.send(&[update_params], &[]).await.unwrap();
+
+    let owners = [alice, bob, carol].map(|trader| trader.keypair.pubkey());
+    let mut accounts = vec![
+        AccountMeta::new_readonly(keeper.keypair.pubkey(), true),
+        AccountMeta::new_readonly(SPL_TOKEN_PROGRAM_ID, false),
+        AccountMeta::new(keeper.token_account, false),
+        AccountMeta::new(env.vault, false),
+        AccountMeta::new(env.market, false),
+        AccountMeta::new_readonly(sysvar::clock::id(), false),
+        AccountMeta::new_readonly(env.config, false),
+        AccountMeta::new_readonly(env.mint, false),
+    ];
+    accounts.extend(owners.iter().map(|owner| AccountMeta::new(env.position_address(owner), false)));
+    let liquidate_many = perps_instruction(env.program_id, &PerpsInstruction::LiquidateMany, accounts);
+    env.send(&[liquidate_many], &[&keeper.keypair]).await.unwrap();
+
+    assert_eq!(env.position(&owners[0]).await.base_amount, SIZE, "the healthy long is skipped");
+    assert_eq!(env.position(&owners[1]).await.base_amount, 0);
+    assert_eq!(env.position(&owners[2]).await.base_amount, 0);
+    assert!(env.token_balance(keeper.token_account).await > 0, "one transfer pays both fee shares");
+}
+
+#[tokio::test]
+async fn test_liquidation_rejects_a_position_from_another_market() {
+    let (mut env, traders) = setup(9, &[1_000 * TOKEN, 0]).await;
+    let [bob, keeper] = &traders[..] else { unreachable!() };
+    let admin = env.context.payer.insecure_clone();
+    env.initialize_market(&admin, 100 * TOKEN, 9).await.unwrap();
+    env.initialize_market_id(&admin, 1, 150 * TOKEN, 9).await.unwrap();
+    let (other_market, _) = market_address(&env.program_id, 1);
+
+    // Bob's short is healthy at market 0's mark, but under the minimum ratio at market 1's
+    env.open_position(bob, -SIZE, COLLATERAL, 0).await.unwrap();
+    let owner = bob.keypair.pubkey();
+    let mut liquidate = env.liquidate(keeper, &owner, &[]);
+    liquidate.accounts[5].pubkey = other_market;
+    assert!(env.send(&[liquidate], &[&keeper.keypair]).await.is_err());
+
+    assert_eq!(env.position(&owner).await.base_amount, -SIZE);
+    let other_market_state = *MarketState::load(&env.account_data(other_market).await).unwrap();
+    assert_eq!(other_market_state.open_interest, 0, "market 1 never saw the position");
+    assert_eq!(env.token_balance(keeper.token_account).await, 0);
+}
+
+#[tokio::test]
+async fn test_backstop_rejects_a_position_from_another_market() {
+    let (mut env, traders) = setup(9, &[1_000 * TOKEN, 1_000 * TOKEN, 1_000 * TOKEN]).await;
+    let [bob, carol, keeper] = &traders[..] else { unreachable!() };
+    let admin = env.context.payer.insecure_clone();
+    env.initialize_market(&admin, 100 * TOKEN, 9).await.unwrap();
+    env.initialize_market_id(&admin, 1, 150 * TOKEN, 9).await.unwrap();
+    let (other_market, _) = market_address(&env.program_id, 1);
+    env.open_position(bob, -SIZE, COLLATERAL, 0).await.unwrap();
+
+    // Market 1, where Bob's short would be under the minimum ratio, has a short of the same
+    // size open, and the keeper has a flat position there
+    let market = std::mem::replace(&mut env.market, other_market);
+    env.open_position(carol, -SIZE, 2 * COLLATERAL, 0).await.unwrap();
+    env.open_position(keeper, 0, COLLATERAL, 0).await.unwrap();
+    let backstop_position = env.position_address(&keeper.keypair.pubkey());
+    env.market = market;
+
+    let owner = bob.keypair.pubkey();
+    let backstop = perps_instruction(
+        env.program_id,
+        &PerpsInstruction::BackstopLiquidate { collateral_delta: COLLATERAL },
+        vec![
+            AccountMeta::new_readonly(keeper.keypair.pubkey(), true),
+            AccountMeta::new_readonly(SPL_TOKEN_PROGRAM_ID, false),
+            AccountMeta::new(keeper.token_account, false),
+            AccountMeta::new(env.vault, false),
+            AccountMeta::new(env.position_address(&owner), false),
+            AccountMeta::new(backstop_position, false),
+            AccountMeta::new(other_market, false),
+            AccountMeta::new_readonly(sysvar::clock::id(), false),
+            AccountMeta::new_readonly(env.config, false),
+            AccountMeta::new_readonly(env.mint, false),
+        ],
+    );
+    assert!(env.send(&[backstop], &[&keeper.keypair]).await.is_err());
+
+    assert_eq!(env.position(&owner).await.base_amount, -SIZE);
+    let taken_over = *Position::load(&env.account_data(backstop_position).await).unwrap();
+    assert_eq!(taken_over.base_amount, 0, "the keeper inherited nothing");
+}
+
+#[tokio::test]
+async fn test_backstop_takeovers_are_gated_like_opens() {
+    let (mut env, traders) = setup(9, &[1_000 * TOKEN, 1_000 * TOKEN]).await;
+    let [bob, keeper] = &traders[..] else { unreachable!() };
+    let admin = env.context.payer.insecure_clone();
+    env.initialize_market(&admin, 100 * TOKEN, 9).await.unwrap();
+    env.open_position(bob, -SIZE, COLLATERAL, 0).await.unwrap();
+    env.open_position(keeper, 0, COLLATERAL, 0).await.unwrap();
+
+    // Bob's short falls under the minimum ratio in a permissioned market that takes half a
+    // position per slot
+    let set_share = perps_instruction(
+        env.program_id,
+        &PerpsInstruction::SetMaxLiquidationShare { max_liquidation_share: TOKEN / 2 },
+        vec![
+            AccountMeta::new_readonly(admin.pubkey(), true),
+            AccountMeta::new_readonly(env.config, false),
+            AccountMeta::new(env.market, false),
+        ],
+    );
+    let update_params = env.update_min_collateral_ratio(&admin.pubkey(), 5 * TOKEN);
+    env.send(&[set_share, update_params], &[]).await.unwrap();
+    env.make_permissioned(&admin).await;
+
+    let owner = bob.keypair.pubkey();
+    let backstop_position = env.position_address(&keeper.keypair.pubkey());
+    let backstop = |env: &Env, trailing: &[AccountMeta]| {
+        let mut accounts = vec![
+            AccountMeta::new_readonly(keeper.keypair.pubkey(), true),
+            AccountMeta::new_readonly(SPL_TOKEN_PROGRAM_ID, false),
+            AccountMeta::new(keeper.token_account, false),
+            AccountMeta::new(env.vault, false),
+            AccountMeta::new(env.position_address(&owner), false),
+            AccountMeta::new(backstop_position, false),
+            AccountMeta::new(env.market, false),
+            AccountMeta::new_readonly(sysvar::clock::id(), false),
+            AccountMeta::new_readonly(env.config, false),
+            AccountMeta::new_readonly(env.mint, false),
+        ];
+        accounts.extend_from_slice(trailing);
+        perps_instruction(env.program_id, &PerpsInstruction::BackstopLiquidate { collateral_delta: COLLATERAL }, accounts)
+    };
+
+    // The keeper can't take on exposure until whitelisted
+    assert!(env.send(&[backstop(&env, &[])], &[&keeper.keypair]).await.is_err());
+    let entry = env.whitelist(&admin, &keeper.keypair.pubkey()).await;
+    let slot = env.context.banks_client.get_sysvar::<Clock>().await.unwrap().slot;
+    env.send(&[backstop(&env, &[AccountMeta::new_readonly(entry, false)])], &[&keeper.keypair]).await.unwrap();
+
+    // Only the market's share changes hands, and the keeper's new short is held like any open
+    assert_eq!(env.position(&owner).await.base_amount, -SIZE / 2);
+    let taken_over = *Position::load(&env.account_data(backstop_position).await).unwrap();
+    assert_eq!(taken_over.base_amount, -SIZE / 2);
+    assert!(taken_over.last_modified_slot >= slot);
+    assert_eq!(taken_over.deposited_collateral, 2 * COLLATERAL);
+    assert_eq!(env.market_state().await.open_interest, SIZE as u64);
+}
+
+#[tokio::test]
+async fn test_positions_only_trade_in_their_market() {
+    let (mut env, traders) = setup(9, &[1_000 * TOKEN, 1_000 * TOKEN]).await;
+    let [alice, bob] = &traders[..] else { unreachable!() };
+    let admin = env.context.payer.insecure_clone();
+    env.initialize_market(&admin, 100 * TOKEN, 9).await.unwrap();
+    env.initialize_market_id(&admin, 1, 150 * TOKEN, 9).await.unwrap();
+    let (other_market, _) = market_address(&env.program_id, 1);
+    let market = std::mem::replace(&mut env.market, other_market);
+    env.open_position(bob, SIZE, 2 * COLLATERAL, 0).await.unwrap();
+    env.market = market;
+
+    // Alice's long from market 0 can't be reduced or closed at market 1's dearer mark
+    env.open_position(alice, SIZE, COLLATERAL, 0).await.unwrap();
+    env.warp_slots(1).await;
+    let decrease = PerpsInstruction::DecreasePosition { base_amount: SIZE as u64 / 2, price_limit: 0, flags: 0, sub_account_id: 0 };
+    let mut decrease = env.position_change_instruction(alice, &decrease, &[]);
+    decrease.accounts[5].pubkey = other_market;
+    assert!(env.send(&[decrease], &[&alice.keypair]).await.is_err());
+    let mut close = env.close_position_instruction(alice);
+    close.accounts[5].pubkey = other_market;
+    assert!(env.send(&[close], &[&alice.keypair]).await.is_err());
+
+    let owner = alice.keypair.pubkey();
+    assert_eq!(env.position(&owner).await.base_amount, SIZE);
+    assert_eq!(env.token_balance(alice.token_account).await, 1_000 * TOKEN - COLLATERAL);
+    env.close_position(alice).await.unwrap();
+}
+
+#[tokio::test]
+async fn test_settle_funding_for_dormant_position() {
+    let (mut env, traders) = setup(9, &[1_000 * TOKEN]).await;
+    let [alice] = &traders[..] else { unreachable!() };
+    let admin = env.context.payer.insecure_clone();
+    env.initialize_market(&admin, 100 * TOKEN, 9).await.unwrap();
+    env.open_position(alice, SIZE, COLLATERAL, 0).await.unwrap();
+    let owner = alice.keypair.pubkey();
+    let collateral = env.position(&owner).await.collateral;
+
+    // A lone long pays the skew rate while it sits untouched
+    let slot = env.context.banks_client.get_root_slot().await.unwrap();
+    env.context.warp_to_slot(slot + 100).unwrap();
+    let update_funding = perps_instruction(
+        env.program_id,
+        &PerpsInstruction::UpdateFunding,
+        vec![
+            AccountMeta::new(env.market, false),
+            AccountMeta::new_readonly(sysvar::clock::id(), false),
+            AccountMeta::new_readonly(env.config, false),
+        ],
+    );
+    let settle_funding = perps_instruction(
+        env.program_id,
+        &PerpsInstruction::SettleFunding,
+        vec![
+            AccountMeta::new(env.position_address(&owner), false),
+            AccountMeta::new(env.market, false),
+            AccountMeta::new_readonly(env.config, false),
+        ],
+    );
+    env.send(&[update_funding, settle_funding], &[]).await.unwrap();
+
+    let long = env.position(&owner).await;
+    assert_eq!(long.last_funding_index, env.market_state().await.funding_index);
+    assert!(long.collateral < collateral, "the long's funding came out of its collateral");
+    assert!(long.cumulative_funding < 0);
+}
+
+#[tokio::test]
+async fn test_received_funding_is_claimable() {
+    let (mut env, traders) = setup(9, &[1_000 * TOKEN, 1_000 * TOKEN]).await;
+    let [alice, bob] = &traders[..] else { unreachable!() };
+    let admin = env.context.payer.insecure_clone();
+    env.initialize_market(&admin, 100 * TOKEN, 9).await.unwrap();
+    env.open_position(alice, 2 * SIZE, 2 * COLLATERAL, 0).await.unwrap();
+    env.open_position(bob, -SIZE, COLLATERAL, 0).await.unwrap();
+    let owner = bob.keypair.pubkey();
+    let collateral = env.position(&owner).await.collateral;
+
+    // The long-heavy skew pays Bob's short funding
+    let settle = |env: &Env| {
+        let update_funding = perps_instruction(
+            env.program_id,
+            &PerpsInstruction::UpdateFunding,
+            vec![
+                AccountMeta::new(env.market, false),
+                AccountMeta::new_readonly(sysvar::clock::id(), false),
+                AccountMeta::new_readonly(env.config, false),
+            ],
+        );
+        let settle_funding = perps_instruction(
+            env.program_id,
+            &PerpsInstruction::SettleFunding,
+            vec![
+                AccountMeta::new(env.position_address(&owner), false),
+                AccountMeta::new(env.market, false),
+                AccountMeta::new_readonly(env.config, false),
+            ],
+        );
+        [update_funding, settle_funding]
+    };
+    env.warp_slots(100).await;
+    let settle_funding = settle(&env);
+    env.send(&settle_funding, &[]).await.unwrap();
+
+    // Received funding accrues beside the margin rather than into it
+    let short = env.position(&owner).await;
+    assert_eq!(short.collateral, collateral);
+    assert!(short.claimable_funding > 0);
+    assert_eq!(short.cumulative_funding, short.claimable_funding as i64);
+
+    // Claiming pays it out and opts in to compounding later funding
+    let balance = env.token_balance(bob.token_account).await;
+    let claim = perps_instruction(
+        env.program_id,
+        &PerpsInstruction::ClaimFunding { auto_compound: true },
+        vec![
+            AccountMeta::new_readonly(owner, true),
+            AccountMeta::new_readonly(SPL_TOKEN_PROGRAM_ID, false),
+            AccountMeta::new(bob.token_account, false),
+            AccountMeta::new(env.vault, false),
+            AccountMeta::new(env.position_address(&owner), false),
+            AccountMeta::new(env.market, false),
+            AccountMeta::new_readonly(env.config, false),
+            AccountMeta::new_readonly(env.mint, false),
+        ],
+    );
+    env.send(&[claim], &[&bob.keypair]).await.unwrap();
+    assert_eq!(env.token_balance(bob.token_account).await, balance + short.claimable_funding);
+    let short = env.position(&owner).await;
+    assert_eq!((short.claimable_funding, short.auto_compound_funding), (0, 1));
+
+    env.warp_slots(100).await;
+    let settle_funding = settle(&env);
+    env.send(&settle_funding, &[]).await.unwrap();
+    let short = env.position(&owner).await;
+    assert_eq!(short.claimable_funding, 0);
+    assert!(short.collateral > collateral, "compounded funding is added to the collateral");
+}
+
+#[tokio::test]
+async fn test_pushed_price_feed_as_index_oracle() {
+    let (mut env, traders) = setup(9, &[1_000 * TOKEN, 0]).await;
+    let [alice, keeper] = &traders[..] else { unreachable!() };
+    let admin = env.context.payer.insecure_clone();
+    env.initialize_market(&admin, 100 * TOKEN, 9).await.unwrap();
+
+    let feed = env.set_pushed_index_oracle(&admin, &keeper.keypair.pubkey()).await;
+
+    // Opens need a price, so the market is closed until the keeper pushes one
+    let feed_meta = [AccountMeta::new_readonly(feed, false)];
+    assert!(env.open_position_with(alice, SIZE, COLLATERAL, 0, &feed_meta).await.is_err());
+
+    let now = env.context.banks_client.get_sysvar::<Clock>().await.unwrap().unix_timestamp;
+    let push_price = |authority: &Trader, timestamp: i64| env.push_price(authority, feed, 100 * TOKEN, timestamp);
+    let (forged_push, push, replayed_push) = (push_price(alice, now), push_price(keeper, now), push_price(keeper, now - 1));
+    assert!(env.send(&[forged_push], &[&alice.keypair]).await.is_err(), "only the price authority pushes");
+    env.send(&[push], &[&keeper.keypair]).await.unwrap();
+    assert!(env.send(&[replayed_push], &[&keeper.keypair]).await.is_err(), "older observations are rejected");
+
+    let pushed = PriceFeed::deserialize(&mut &env.account_data(feed).await[DISCRIMINATOR_LEN..]).unwrap();
+    assert_eq!((pushed.price, pushed.timestamp), (100 * TOKEN, now));
+    env.open_position_with(alice, SIZE, COLLATERAL, 0, &feed_meta).await.unwrap();
+    assert_eq!(env.position(&alice.keypair.pubkey()).await.base_amount, SIZE);
+}
+
+#[tokio::test]
+async fn test_vamm_order_fills_stay_in_the_mark_price_band() {
+    let (mut env, traders) = setup(9, &[1_000 * TOKEN, 0]).await;
+    let [alice, keeper] = &traders[..] else { unreachable!() };
+    let admin = env.context.payer.insecure_clone();
+    env.initialize_market(&admin, 100 * TOKEN, 9).await.unwrap();
+    let feed = env.set_pushed_index_oracle(&admin, &keeper.keypair.pubkey()).await;
+    let now = env.context.banks_client.get_sysvar::<Clock>().await.unwrap().unix_timestamp;
+    let push = env.push_price(keeper, feed, 100 * TOKEN, now - 1);
+    env.send(&[push], &[&keeper.keypair]).await.unwrap();
+    env.open_position_with(alice, SIZE, COLLATERAL, 0, &[AccountMeta::new_readonly(feed, false)]).await.unwrap();
+
+    // The index drops to $95, leaving the $100 mark above its 2% band
+    env.warp_slots(1).await;
+    let push = env.push_price(keeper, feed, 95 * TOKEN, now);
+    env.send(&[push], &[&keeper.keypair]).await.unwrap();
+    env.place_order(alice, OrderSide::Bid, 101 * TOKEN, SIZE as u64, 0, &[]).await.unwrap();
+    env.place_order(alice, OrderSide::Ask, 99 * TOKEN, SIZE as u64 / 2, 0, &[]).await.unwrap();
+
+    // The bid would grow Alice's long off the band, so it waits; the ask reduces and fills,
+    // and the mark is pulled back into the band
+    let orderbook = env.orderbook();
+    let match_orders = perps_instruction(
+        env.program_id,
+        &PerpsInstruction::MatchOrders,
+        vec![
+            AccountMeta::new(env.market, false),
+            AccountMeta::new(orderbook, false),
+            AccountMeta::new_readonly(env.config, false),
+            AccountMeta::new_readonly(feed, false),
+            AccountMeta::new(env.position_address(&alice.keypair.pubkey()), false),
+        ],
+    );
+    env.send(&[match_orders], &[]).await.unwrap();
+    assert_eq!(env.position(&alice.keypair.pubkey()).await.base_amount, SIZE / 2);
+    let market_state = env.market_state().await;
+    let (_, upper) = calculate_price_band(95 * TOKEN, market_state.mark_price_band);
+    assert_eq!(market_state.mark_price, upper);
+    let book = OrderBook::load(&env.account_data(orderbook).await).unwrap();
+    assert_eq!((book.orders.len(), book.orders[0].side), (1, OrderSide::Bid));
+}
+
+#[tokio::test]
+async fn test_strategy_program_opens_positions_via_cpi() {
+    let strategy = Pubkey::new_unique();
+    let authority = |index: u8| Pubkey::find_program_address(&[STRATEGY_SEED, &[index]], &strategy).0;
+    let (wallet_authority, state_authority) = (authority(0), authority(1));
+    let token_accounts = [Pubkey::new_unique(), Pubkey::new_unique()];
+    let (mut env, _) = setup_with(9, &[], |program_test, program_id, mint| {
+        program_test.add_program("mock_strategy", strategy, processor!(mock_strategy));
+        for (owner, token) in [wallet_authority, state_authority].iter().zip(token_accounts) {
+            program_test.add_account(token, token_account(mint, owner, 1_000 * TOKEN));
+        }
+
+        // A data-less PDA pays for its position like a wallet; a PDA holding the strategy's
+        // state can't fund a system transfer, so the strategy funds its position address ahead
+        program_test.add_account(
+            wallet_authority,
+            Account { lamports: 10 * TOKEN, owner: system_program::id(), ..Account::default() },
+        );
+        program_test.add_account(
+            state_authority,
+            Account { lamports: TOKEN, data: vec![1; 64], owner: strategy, ..Account::default() },
+        );
+        let market = market_address(program_id, 0).0;
+        program_test.add_account(
+            position_address(program_id, &market, &state_authority, 0).0,
+            Account { lamports: Rent::default().minimum_balance(Position::SPACE), ..Account::default() },
+        );
+    })
+    .await;
+    let admin = env.context.payer.insecure_clone();
+    env.initialize_market(&admin, 100 * TOKEN, 9).await.unwrap();
+
+    let via_strategy = |env: &Env, index: u8, instruction: &PerpsInstruction, accounts: Vec<AccountMeta>| {
+        let mut data = vec![index];
+        data.extend_from_slice(&instruction.pack());
+        let mut strategy_accounts = vec![AccountMeta::new_readonly(env.program_id, false)];
+        strategy_accounts.extend(accounts);
+        Instruction::new_with_bytes(strategy, &data, strategy_accounts)
+    };
+    let open_accounts = |env: &Env, owner: Pubkey, token: Pubkey| {
+        vec![
+            AccountMeta::new(owner, false),
+            AccountMeta::new_readonly(SPL_TOKEN_PROGRAM_ID, false),
+            AccountMeta::new(token, false),
+            AccountMeta::new(env.vault, false),
+            AccountMeta::new(env.position_address(&owner), false),
+            AccountMeta::new(env.market, false),
+            AccountMeta::new_readonly(sysvar::rent::id(), false),
+            AccountMeta::new_readonly(sysvar::clock::id(), false),
+            AccountMeta::new_readonly(system_program::id(), false),
+            AccountMeta::new_readonly(env.config, false),
+            AccountMeta::new_readonly(env.mint, false),
+        ]
+    };
+    let open = PerpsInstruction::OpenPosition {
+        base_delta: SIZE,
+        collateral_delta: COLLATERAL,
+        price_limit: 0,
+        flags: 0,
+        sub_account_id: 0,
+    };
+
+    // Nobody can open for the PDA without the strategy signing for it
+    let direct_open = perps_instruction(env.program_id, &open, open_accounts(&env, wallet_authority, token_accounts[0]));
+    assert!(env.send(&[direct_open], &[]).await.is_err());
+
+    for (index, (owner, token)) in [wallet_authority, state_authority].into_iter().zip(token_accounts).enumerate() {
+        let strategy_open = via_strategy(&env, index as u8, &open, open_accounts(&env, owner, token));
+        env.send(&[strategy_open], &[]).await.unwrap();
+        let position = env.position(&owner).await;
+        assert_eq!((position.owner, position.base_amount), (owner, SIZE));
+        assert_eq!(env.token_balance(token).await, 1_000 * TOKEN - COLLATERAL);
+    }
+
+    // The strategy closes the same way, and the collateral returns to its token account
+    env.warp_slots(1).await;
+    let close_accounts = vec![
+        AccountMeta::new_readonly(state_authority, false),
+        AccountMeta::new_readonly(SPL_TOKEN_PROGRAM_ID, false),
+        AccountMeta::new(token_accounts[1], false),
+        AccountMeta::new(env.vault, false),
+        AccountMeta::new(env.position_address(&state_authority), false),
+        AccountMeta::new(env.market, false),
+        AccountMeta::new_readonly(env.config, false),
+        AccountMeta::new_readonly(env.mint, false),
+    ];
+    let strategy_close = via_strategy(&env, 1, &PerpsInstruction::ClosePosition, close_accounts);
+    env.send(&[strategy_close], &[]).await.unwrap();
+    assert_eq!(env.position(&state_authority).await.base_amount, 0);
+    assert!(env.token_balance(token_accounts[1]).await > 1_000 * TOKEN - COLLATERAL);
+}
+
+#[tokio::test]
+async fn test_positions_are_held_after_growing() {
+    let (mut env, traders) = setup(9, &[1_000 * TOKEN]).await;
+    let [alice] = &traders[..] else { unreachable!() };
+    let admin = env.context.payer.insecure_clone();
+    env.initialize_market(&admin, 100 * TOKEN, 9).await.unwrap();
+
+    // Even without a configured hold, an open can't be unwound in its own slot
+    env.open_position(alice, SIZE, COLLATERAL, 0).await.unwrap();
+    let opened_at = env.position(&alice.keypair.pubkey()).await.last_modified_slot;
+    assert!(env.open_position(alice, -SIZE / 2, 0, 0).await.is_err());
+    assert!(env.close_position(alice).await.is_err());
+
+    let set_min_hold = |min_hold_slots| {
+        perps_instruction(
+            env.program_id,
+            &PerpsInstruction::SetMinHoldSlots { min_hold_slots },
+            vec![
+                AccountMeta::new_readonly(admin.pubkey(), true),
+                AccountMeta::new_readonly(env.config, false),
+                AccountMeta::new(env.market, false),
+            ],
+        )
+    };
+    let (too_long, hold) = (set_min_hold(MAX_MIN_HOLD_SLOTS + 1), set_min_hold(50));
+    assert!(env.send(&[too_long], &[]).await.is_err());
+    env.send(&[hold], &[]).await.unwrap();
+
+    // Growing restarts the hold; reductions don't, so the position can be unwound in steps
+    env.warp_slots(10).await;
+    env.open_position(alice, SIZE, COLLATERAL, 0).await.unwrap();
+    let grown_at = env.position(&alice.keypair.pubkey()).await.last_modified_slot;
+    assert!(grown_at > opened_at);
+    env.warp_slots(20).await;
+    assert!(env.close_position(alice).await.is_err());
+    env.warp_slots(50).await;
+    env.open_position(alice, -SIZE, 0, 0).await.unwrap();
+    env.close_position(alice).await.unwrap();
+    let position = env.position(&alice.keypair.pubkey()).await;
+    assert_eq!((position.base_amount, position.last_modified_slot), (0, grown_at));
+}
+
+#[tokio::test]
+async fn test_liquidations_are_throttled_per_slot() {
+    let (mut env, traders) = setup(9, &[1_000 * TOKEN, 1_000 * TOKEN, 0]).await;
+    let [alice, bob, keeper] = &traders[..] else { unreachable!() };
+    let admin = env.context.payer.insecure_clone();
+    env.initialize_market(&admin, 100 * TOKEN, 9).await.unwrap();
+    env.open_position(alice, SIZE, 2 * COLLATERAL, 0).await.unwrap();
+    env.open_position(bob, -SIZE, COLLATERAL, 0).await.unwrap();
+
+    let set_share = |max_liquidation_share| {
+        perps_instruction(
+            env.program_id,
+            &PerpsInstruction::SetMaxLiquidationShare { max_liquidation_share },
+            vec![
+                AccountMeta::new_readonly(admin.pubkey(), true),
+                AccountMeta::new_readonly(env.config, false),
+                AccountMeta::new(env.market, false),
+            ],
+        )
+    };
+    let (too_small, half) = (set_share(MIN_LIQUIDATION_SHARE - 1), set_share(TOKEN / 2));
+    assert!(env.send(&[too_small], &[]).await.is_err());
+    let update_params = env.update_min_collateral_ratio(&admin.pubkey(), 5 * TOKEN);
+    env.send(&[half, update_params], &[]).await.unwrap();
+
+    // Half of Bob's short is unwound; the rest waits for the next slot even though it is
+    // still under-collateralized
+    let bob_key = bob.keypair.pubkey();
+    env.send(&[env.liquidate(keeper, &bob_key, &[])], &[&keeper.keypair]).await.unwrap();
+    let short = env.position(&bob_key).await;
+    assert_eq!(short.base_amount, -SIZE / 2);
+    assert!(short.collateral > 0 && short.entry_price > 0);
+    let retry = env.liquidate(keeper, &bob_key, &[AccountMeta::new_readonly(env.mint, false)]);
+    assert!(env.send(&[retry], &[&keeper.keypair]).await.is_err());
+
+    env.warp_slots(1).await;
+    env.send(&[env.liquidate(keeper, &bob_key, &[])], &[&keeper.keypair]).await.unwrap();
+    assert_eq!(env.position(&bob_key).await.base_amount, -SIZE / 4);
+    assert_eq!(env.market_state().await.open_interest, (SIZE + SIZE / 4) as u64);
+}
+
+#[tokio::test]
+async fn test_flagged_positions_get_a_grace_period() {
+    let (mut env, traders) = setup(9, &[1_000 * TOKEN, 1_000 * TOKEN, 1_000 * TOKEN, 0]).await;
+    let [alice, bob, carol, keeper] = &traders[..] else { unreachable!() };
+    let admin = env.context.payer.insecure_clone();
+    env.initialize_market(&admin, 100 * TOKEN, 9).await.unwrap();
+    env.open_position(alice, SIZE, 2 * COLLATERAL, 0).await.unwrap();
+    env.open_position(bob, -SIZE / 2, COLLATERAL / 2, 0).await.unwrap();
+    env.open_position(carol, -SIZE / 2, COLLATERAL / 2, 0).await.unwrap();
+
+    let (program_id, market, config, admin_key) = (env.program_id, env.market, env.config, admin.pubkey());
+    let set_grace = |grace_slots, floor_ratio| {
+        perps_instruction(
+            program_id,
+            &PerpsInstruction::SetLiquidationGrace { grace_slots, floor_ratio },
+            vec![
+                AccountMeta::new_readonly(admin_key, true),
+                AccountMeta::new_readonly(config, false),
+                AccountMeta::new(market, false),
+            ],
+        )
+    };
+    let flag = |owner: &Pubkey, trailing: &[AccountMeta]| {
+        let mut accounts = vec![
+            AccountMeta::new(position_address(&program_id, &market, owner, 0).0, false),
+            AccountMeta::new_readonly(market, false),
+            AccountMeta::new_readonly(sysvar::clock::id(), false),
+            AccountMeta::new_readonly(config, false),
+        ];
+        accounts.extend_from_slice(trailing);
+        perps_instruction(program_id, &PerpsInstruction::FlagForLiquidation, accounts)
+    };
+    let (bob_key, carol_key) = (bob.keypair.pubkey(), carol.keypair.pubkey());
+    let (too_long, no_floor) = (set_grace(MAX_LIQUIDATION_GRACE_SLOTS + 1, TOKEN), set_grace(10, 0));
+    assert!(env.send(&[too_long], &[]).await.is_err());
+    assert!(env.send(&[no_floor], &[]).await.is_err());
+
+    // Healthy positions can't be flagged
+    let (flag_early, grace) = (flag(&bob_key, &[]), set_grace(10, TOKEN));
+    assert!(env.send(&[flag_early], &[]).await.is_err());
+    let update_params = env.update_min_collateral_ratio(&admin.pubkey(), 5 * TOKEN);
+    env.send(&[grace, update_params], &[]).await.unwrap();
+
+    // Both shorts are under-collateralized but above the floor, so they must be flagged first
+    assert!(env.send(&[env.liquidate(keeper, &bob_key, &[])], &[&keeper.keypair]).await.is_err());
+    env.send(&[flag(&bob_key, &[]), flag(&carol_key, &[])], &[]).await.unwrap();
+    let flagged_at = env.position(&bob_key).await.margin_call_slot;
+    assert!(flagged_at > 0);
+    let reflag = flag(&bob_key, &[AccountMeta::new_readonly(env.mint, false)]);
+    assert!(env.send(&[reflag], &[]).await.is_err());
+    let in_grace = env.liquidate(keeper, &bob_key, &[AccountMeta::new_readonly(env.mint, false)]);
+    assert!(env.send(&[in_grace], &[&keeper.keypair]).await.is_err());
+
+    // Carol answers the margin call; Bob is liquidated once the grace period is over
+    env.open_position(carol, 0, COLLATERAL, 0).await.unwrap();
+    assert_eq!(env.position(&carol_key).await.margin_call_slot, 0);
+    env.warp_slots(10).await;
+    env.send(&[env.liquidate(keeper, &bob_key, &[])], &[&keeper.keypair]).await.unwrap();
+    let short = env.position(&bob_key).await;
+    assert_eq!((short.base_amount, short.margin_call_slot), (0, 0));
+    let answered = env.liquidate(keeper, &carol_key, &[AccountMeta::new_readonly(env.mint, false)]);
+    assert!(env.send(&[answered], &[&keeper.keypair]).await.is_err());
+
+    // Below the hard floor, liquidation doesn't wait for a flag
+    let (floor, update_params) = (set_grace(10, 7 * TOKEN), env.update_min_collateral_ratio(&admin.pubkey(), 10 * TOKEN));
+    env.send(&[floor, update_params], &[]).await.unwrap();
+    env.send(&[env.liquidate(keeper, &carol_key, &[])], &[&keeper.keypair]).await.unwrap();
+    assert_eq!(env.position(&carol_key).await.base_amount, 0);
+}
+
+#[tokio::test]
+async fn test_check_health_matches_liquidation() {
+    let (mut env, traders) = setup(9, &[1_000 * TOKEN, 1_000 * TOKEN, 0]).await;
+    let [alice, bob, keeper] = &traders[..] else { unreachable!() };
+    let admin = env.context.payer.insecure_clone();
+    env.initialize_market(&admin, 100 * TOKEN, 9).await.unwrap();
+    env.open_position(alice, SIZE, 2 * COLLATERAL, 0).await.unwrap();
+    env.open_position(bob, -SIZE, COLLATERAL, 0).await.unwrap();
+
+    let bob_key = bob.keypair.pubkey();
+    let check_health = perps_instruction(
+        env.program_id,
+        &PerpsInstruction::CheckHealth,
+        vec![
+            AccountMeta::new_readonly(env.position_address(&bob_key), false),
+            AccountMeta::new_readonly(env.market, false),
+            AccountMeta::new_readonly(sysvar::clock::id(), false),
+            AccountMeta::new_readonly(env.config, false),
+        ],
+    );
+    let health = |data: Vec<u8>| {
+        let (health_factor, collateral_ratio) = data.split_at(8);
+        (u64::from_le_bytes(health_factor.try_into().unwrap()), u64::from_le_bytes(collateral_ratio.try_into().unwrap()))
+    };
+
+    // About 200% collateralized against the 150% default: healthy
+    let (health_factor, collateral_ratio) = health(env.simulate_return_data(check_health.clone(), &[]).await);
+    assert!(collateral_ratio > 19 * TOKEN / 10 && collateral_ratio < 2 * TOKEN, "ratio {}", collateral_ratio);
+    assert_eq!(health_factor, collateral_ratio * 2 / 3);
+    let healthy = env.liquidate(keeper, &bob_key, &[AccountMeta::new_readonly(env.mint, false)]);
+    assert!(env.send(&[healthy], &[&keeper.keypair]).await.is_err());
+
+    // Requiring 500% drops the factor below 1, and the liquidator agrees
+    let update_params = env.update_min_collateral_ratio(&admin.pubkey(), 5 * TOKEN);
+    env.send(&[update_params], &[]).await.unwrap();
+    let (health_factor, collateral_ratio) = health(env.simulate_return_data(check_health, &[]).await);
+    assert_eq!(health_factor, collateral_ratio / 5);
+    env.send(&[env.liquidate(keeper, &bob_key, &[])], &[&keeper.keypair]).await.unwrap();
+}
+
+#[tokio::test]
+async fn test_simulated_open_previews_without_writing() {
+    let (mut env, traders) = setup(9, &[1_000 * TOKEN, 1_000 * TOKEN]).await;
+    let [alice, bob] = &traders[..] else { unreachable!() };
+    let admin = env.context.payer.insecure_clone();
+    env.initialize_market(&admin, 100 * TOKEN, 9).await.unwrap();
+    env.open_position(bob, -SIZE, COLLATERAL, 0).await.unwrap();
+
+    let alice_key = alice.keypair.pubkey();
+    let simulated_open = env.open_position_instruction(alice, SIZE, COLLATERAL, 0, OPEN_FLAG_SIMULATE, &[]);
+    let preview = env.simulate_return_data(simulated_open.clone(), &[&alice.keypair]).await;
+    let field = |i: usize| u64::from_le_bytes(preview[i * 8..i * 8 + 8].try_into().unwrap());
+    let (fill_price, trading_fee, collateral, collateral_ratio, health_factor) =
+        (field(0), field(1), field(3), field(4), field(5));
+    assert_eq!(preview.len(), 48);
+    assert_eq!(collateral, COLLATERAL - trading_fee);
+
+    // Landing the simulation creates no position and moves neither tokens nor the market
+    let market_before = env.market_state().await;
+    env.send(&[simulated_open], &[&alice.keypair]).await.unwrap();
+    let position_key = env.position_address(&alice_key);
+    assert!(env.context.banks_client.get_account(position_key).await.unwrap().is_none());
+    assert_eq!(env.token_balance(alice.token_account).await, 1_000 * TOKEN);
+    assert_eq!(env.market_state().await, market_before);
+
+    // The real open fills as previewed
+    env.open_position(alice, SIZE, COLLATERAL, 0).await.unwrap();
+    let position = env.position(&alice_key).await;
+    assert_eq!((position.entry_price, position.collateral), (fill_price, collateral));
+    let check_health = perps_instruction(
+        env.program_id,
+        &PerpsInstruction::CheckHealth,
+        vec![
+            AccountMeta::new_readonly(position_key, false),
+            AccountMeta::new_readonly(env.market, false),
+            AccountMeta::new_readonly(sysvar::clock::id(), false),
+            AccountMeta::new_readonly(env.config, false),
+        ],
+    );
+    let health = env.simulate_return_data(check_health, &[]).await;
+    assert_eq!(health[..8], health_factor.to_le_bytes());
+    assert_eq!(health[8..], collateral_ratio.to_le_bytes());
+}
+
+#[tokio::test]
+async fn test_protocol_fees_are_withdrawn_to_the_treasury() {
+    let (mut env, traders) = setup(9, &[1_000 * TOKEN, 0]).await;
+    let [alice, treasury] = &traders[..] else { unreachable!() };
+    let admin = env.context.payer.insecure_clone();
+    env.initialize_market(&admin, 100 * TOKEN, 9).await.unwrap();
+    let config_data = env.account_data(env.config).await;
+    assert_eq!(Config::deserialize(&mut &config_data[DISCRIMINATOR_LEN..]).unwrap().market_count, 1);
+
+    env.open_position(alice, SIZE, COLLATERAL, 0).await.unwrap();
+    let fee_pool = env.market_state().await.fee_pool;
+    assert!(fee_pool > 0);
+
+    let (program_id, config, vault, market, mint) = (env.program_id, env.config, env.vault, env.market, env.mint);
+    let withdraw_fees = |amount| {
+        perps_instruction(
+            program_id,
+            &PerpsInstruction::WithdrawFees { amount },
+            vec![
+                AccountMeta::new_readonly(admin.pubkey(), true),
+                AccountMeta::new_readonly(config, false),
+                AccountMeta::new_readonly(SPL_TOKEN_PROGRAM_ID, false),
+                AccountMeta::new(vault, false),
+                AccountMeta::new(market, false),
+                AccountMeta::new(treasury.token_account, false),
+                AccountMeta::new_readonly(mint, false),
+            ],
+        )
+    };
+    assert!(env.send(&[withdraw_fees(fee_pool / 2)], &[]).await.is_err(), "no treasury set yet");
+
+    let set_treasury = perps_instruction(
+        program_id,
+        &PerpsInstruction::SetTreasury,
+        vec![
+            AccountMeta::new_readonly(admin.pubkey(), true),
+            AccountMeta::new(config, false),
+            AccountMeta::new_readonly(treasury.token_account, false),
+        ],
+    );
+    env.send(&[set_treasury], &[]).await.unwrap();
+
+    // A payout that would leave the vault short of the market's own reserves is rejected
+    let vault_balance = env.token_balance(vault).await;
+    env.set_token_balance(vault, fee_pool - 1).await;
+    assert!(env.send(&[withdraw_fees(fee_pool / 2)], &[]).await.is_err());
+    env.set_token_balance(vault, vault_balance).await;
+
+    // The admin can take the whole pool, but no more
+    assert!(env.send(&[withdraw_fees(fee_pool + 1)], &[]).await.is_err());
+    env.send(&[withdraw_fees(fee_pool)], &[]).await.unwrap();
+    assert_eq!(env.token_balance(treasury.token_account).await, fee_pool);
+    assert_eq!(env.market_state().await.fee_pool, 0);
+}
+
+#[tokio::test]
+async fn test_collateral_deposits_are_capped() {
+    let (mut env, traders) = setup(9, &[1_000 * TOKEN, 1_000 * TOKEN]).await;
+    let [alice, bob] = &traders[..] else { unreachable!() };
+    let admin = env.context.payer.insecure_clone();
+    env.initialize_market(&admin, 100 * TOKEN, 9).await.unwrap();
+
+    let set_cap = perps_instruction(
+        env.program_id,
+        &PerpsInstruction::SetMaxTotalCollateral { max_total_collateral: COLLATERAL * 3 / 2 },
+        vec![
+            AccountMeta::new_readonly(admin.pubkey(), true),
+            AccountMeta::new_readonly(env.config, false),
+            AccountMeta::new(env.market, false),
+        ],
+    );
+    env.send(&[set_cap], &[]).await.unwrap();
+
+    // The second deposit would take the market past the cap
+    env.open_position(alice, SIZE, COLLATERAL, 0).await.unwrap();
+    assert_eq!(env.market_state().await.total_collateral, COLLATERAL);
+    assert!(env.open_position(bob, -SIZE, COLLATERAL, 0).await.is_err());
+
+    // Closing releases all of Alice's deposit, including what she lost to fees and slippage
+    env.warp_slots(1).await;
+    env.close_position(alice).await.unwrap();
+    assert_eq!(env.market_state().await.total_collateral, 0);
+    env.open_position(bob, -SIZE, COLLATERAL, 0).await.unwrap();
+
+    // Moving isolated collateral to cross margin releases it too, so it can't be used to fill the cap
+    env.open_position(alice, 0, COLLATERAL / 2, 0).await.unwrap();
+    assert_eq!(env.market_state().await.total_collateral, COLLATERAL * 3 / 2);
+    let owner = alice.keypair.pubkey();
+    let (user_account, _) = Pubkey::find_program_address(&[USER_ACCOUNT_SEED, owner.as_ref()], &env.program_id);
+    let create_user_account = perps_instruction(
+        env.program_id,
+        &PerpsInstruction::CreateUserAccount,
+        vec![
+            AccountMeta::new(owner, true),
+            AccountMeta::new(user_account, false),
+            AccountMeta::new_readonly(sysvar::rent::id(), false),
+            AccountMeta::new_readonly(system_program::id(), false),
+        ],
+    );
+    let set_cross = perps_instruction(
+        env.program_id,
+        &PerpsInstruction::SetMarginMode { margin_mode: 1 },
+        vec![
+            AccountMeta::new_readonly(owner, true),
+            AccountMeta::new(env.position_address(&owner), false),
+            AccountMeta::new(env.market, false),
+            AccountMeta::new(user_account, false),
+        ],
+    );
+    env.send(&[create_user_account, set_cross], &[&alice.keypair]).await.unwrap();
+    assert_eq!(env.market_state().await.total_collateral, COLLATERAL);
+    assert_eq!(env.position(&owner).await.deposited_collateral, 0);
+}
+
+#[tokio::test]
+async fn test_payouts_are_capped_per_epoch() {
+    let (mut env, traders) = setup(9, &[1_000 * TOKEN, 1_000 * TOKEN]).await;
+    let [alice, bob] = &traders[..] else { unreachable!() };
+    let admin = env.context.payer.insecure_clone();
+    env.initialize_market(&admin, 100 * TOKEN, 9).await.unwrap();
+
+    let set_limits = perps_instruction(
+        env.program_id,
+        &PerpsInstruction::SetWithdrawalLimits { cooldown_slots: 10, max_epoch_withdrawal: COLLATERAL / 2 },
+        vec![
+            AccountMeta::new_readonly(admin.pubkey(), true),
+            AccountMeta::new_readonly(env.config, false),
+            AccountMeta::new(env.market, false),
+        ],
+    );
+    env.send(&[set_limits], &[]).await.unwrap();
+
+    env.open_position(alice, SIZE, COLLATERAL, 0).await.unwrap();
+    env.open_position(bob, -SIZE, COLLATERAL, 0).await.unwrap();
+    env.warp_slots(1).await;
+
+    // Alice's close is the epoch's first payout, so it goes through past the cap; Bob's waits
+    let epoch = env.context.banks_client.get_sysvar::<Clock>().await.unwrap().epoch;
+    env.close_position(alice).await.unwrap();
+    assert!(env.close_position(bob).await.is_err());
+    let market_state = env.market_state().await;
+    assert_eq!(market_state.withdrawal_epoch, epoch);
+    assert!(market_state.epoch_withdrawn > market_state.max_epoch_withdrawal);
+    assert!(env.position(&alice.keypair.pubkey()).await.last_withdrawal_slot > 0);
+
+    // The next epoch takes it
+    env.warp_slots(1_000).await;
+    assert!(env.context.banks_client.get_sysvar::<Clock>().await.unwrap().epoch > epoch);
+    env.close_position(bob).await.unwrap();
+    assert_eq!(env.position(&bob.keypair.pubkey()).await.collateral, 0);
+}
+
+#[tokio::test]
+async fn test_cross_withdrawals_count_toward_the_epoch_cap() {
+    let (mut env, traders) = setup(9, &[1_000 * TOKEN, 1_000 * TOKEN]).await;
+    let [alice, bob] = &traders[..] else { unreachable!() };
+    let admin = env.context.payer.insecure_clone();
+    env.initialize_market(&admin, 100 * TOKEN, 9).await.unwrap();
+
+    let set_limits = perps_instruction(
+        env.program_id,
+        &PerpsInstruction::SetWithdrawalLimits { cooldown_slots: 10, max_epoch_withdrawal: COLLATERAL / 2 },
+        vec![
+            AccountMeta::new_readonly(admin.pubkey(), true),
+            AccountMeta::new_readonly(env.config, false),
+            AccountMeta::new(env.market, false),
+        ],
+    );
+    env.send(&[set_limits], &[]).await.unwrap();
+
+    // Bob moves a flat position's collateral to cross margin
+    env.open_position(bob, 0, COLLATERAL, 0).await.unwrap();
+    let owner = bob.keypair.pubkey();
+    let position = env.position_address(&owner);
+    let (user_account, _) = Pubkey::find_program_address(&[USER_ACCOUNT_SEED, owner.as_ref()], &env.program_id);
+    let create_user_account = perps_instruction(
+        env.program_id,
+        &PerpsInstruction::CreateUserAccount,
+        vec![
+            AccountMeta::new(owner, true),
+            AccountMeta::new(user_account, false),
+            AccountMeta::new_readonly(sysvar::rent::id(), false),
+            AccountMeta::new_readonly(system_program::id(), false),
+        ],
+    );
+    let set_cross = perps_instruction(
+        env.program_id,
+        &PerpsInstruction::SetMarginMode { margin_mode: 1 },
+        vec![
+            AccountMeta::new_readonly(owner, true),
+            AccountMeta::new(position, false),
+            AccountMeta::new(env.market, false),
+            AccountMeta::new(user_account, false),
+        ],
+    );
+    env.send(&[create_user_account, set_cross], &[&bob.keypair]).await.unwrap();
+
+    let withdraw = |env: &Env, amount: u64| {
+        perps_instruction(
+            env.program_id,
+            &PerpsInstruction::WithdrawCrossCollateral { amount },
+            vec![
+                AccountMeta::new_readonly(owner, true),
+                AccountMeta::new_readonly(SPL_TOKEN_PROGRAM_ID, false),
+                AccountMeta::new(bob.token_account, false),
+                AccountMeta::new(env.vault, false),
+                AccountMeta::new(user_account, false),
+                AccountMeta::new_readonly(env.config, false),
+                AccountMeta::new_readonly(env.mint, false),
+                AccountMeta::new(position, false),
+                AccountMeta::new(env.market, false),
+            ],
+        )
+    };
+
+    // Alice's close takes the epoch past the cap, so Bob's cross withdrawal waits for the next one
+    env.open_position(alice, SIZE, COLLATERAL, 0).await.unwrap();
+    env.warp_slots(1).await;
+    let epoch = env.context.banks_client.get_sysvar::<Clock>().await.unwrap().epoch;
+    env.close_position(alice).await.unwrap();
+    assert!(env.send(&[withdraw(&env, 10 * TOKEN)], &[&bob.keypair]).await.is_err());
+
+    env.warp_slots(1_000).await;
+    assert!(env.context.banks_client.get_sysvar::<Clock>().await.unwrap().epoch > epoch);
+    let balance = env.token_balance(bob.token_account).await;
+    env.send(&[withdraw(&env, 10 * TOKEN)], &[&bob.keypair]).await.unwrap();
+    assert_eq!(env.token_balance(bob.token_account).await, balance + 10 * TOKEN);
+    assert_eq!(env.market_state().await.epoch_withdrawn, 10 * TOKEN);
+    assert!(env.position(&owner).await.last_withdrawal_slot > 0);
+}
+
+#[tokio::test]
+async fn test_leverage_override_lifts_a_wallets_max_leverage() {
+    let (mut env, traders) = setup(9, &[1_000 * TOKEN, 1_000 * TOKEN]).await;
+    let [alice, bob] = &traders[..] else { unreachable!() };
+    let admin = env.context.payer.insecure_clone();
+    env.initialize_market(&admin, 100 * TOKEN, 9).await.unwrap();
+    env.open_position(bob, SIZE, COLLATERAL, 0).await.unwrap();
+
+    // At a 5% minimum ratio, the market's 10x max leverage is what binds
+    let update_params = env.update_min_collateral_ratio(&admin.pubkey(), TOKEN / 20);
+    env.send(&[update_params], &[]).await.unwrap();
+    assert!(env.open_position(alice, 15 * SIZE, 100 * TOKEN, 0).await.is_err());
+
+    let (leverage_override, _) = leverage_override_address(&env.program_id, &env.market, &alice.keypair.pubkey());
+    let (program_id, config, market) = (env.program_id, env.config, env.market);
+    let set_override = |max_leverage| {
+        perps_instruction(
+            program_id,
+            &PerpsInstruction::SetLeverageOverride { wallet: alice.keypair.pubkey(), max_leverage },
+            vec![
+                AccountMeta::new(admin.pubkey(), true),
+                AccountMeta::new_readonly(config, false),
+                AccountMeta::new_readonly(market, false),
+                AccountMeta::new(leverage_override, false),
+                AccountMeta::new_readonly(sysvar::rent::id(), false),
+                AccountMeta::new_readonly(system_program::id(), false),
+            ],
+        )
+    };
+    env.send(&[set_override(20 * TOKEN)], &[]).await.unwrap();
+
+    // The override only applies when passed along
+    let trailing = [AccountMeta::new_readonly(leverage_override, false)];
+    env.open_position_with(alice, 15 * SIZE, 100 * TOKEN, 0, &trailing).await.unwrap();
+    assert_eq!(env.position(&alice.keypair.pubkey()).await.base_amount, 15 * SIZE);
+
+    // Order fills apply it too, when the taker or cranker passes it
+    env.warp_slots(1).await;
+    let ioc = ORDER_FLAG_IMMEDIATE_OR_CANCEL;
+    env.place_order(alice, OrderSide::Bid, 101 * TOKEN, SIZE as u64, 0, &[]).await.unwrap();
+    env.place_order(bob, OrderSide::Ask, 99 * TOKEN, SIZE as u64, ioc, &[alice]).await.unwrap();
+    assert_eq!(env.position(&alice.keypair.pubkey()).await.base_amount, 15 * SIZE);
+    env.place_order(alice, OrderSide::Bid, 101 * TOKEN, SIZE as u64, 0, &[]).await.unwrap();
+    env.place_order_with(bob, OrderSide::Ask, 99 * TOKEN, SIZE as u64, ioc, &[alice], &trailing).await.unwrap();
+    assert_eq!(env.position(&alice.keypair.pubkey()).await.base_amount, 16 * SIZE);
+
+    // Removing it returns the rent and the market's limit
+    env.send(&[set_override(0)], &[]).await.unwrap();
+    assert!(env.context.banks_client.get_account(leverage_override).await.unwrap().is_none());
+    assert!(env.open_position_with(alice, SIZE, 0, 0, &trailing).await.is_err());
+}
+
+#[tokio::test]
+async fn test_tokenized_position_follows_its_nft() {
+    let (mut env, traders) = setup(9, &[1_000 * TOKEN, 0]).await;
+    let [alice, bob] = &traders[..] else { unreachable!() };
+    let admin = env.context.payer.insecure_clone();
+    env.initialize_market(&admin, 100 * TOKEN, 9).await.unwrap();
+    env.open_position(alice, SIZE, COLLATERAL, 0).await.unwrap();
+
+    let position = env.position_address(&alice.keypair.pubkey());
+    let (position_mint, _) = position_mint_address(&env.program_id, &position);
+    let holder = env.tokenize_position(alice).await;
+    assert_eq!(env.position(&alice.keypair.pubkey()).await.position_mint, position_mint);
+    assert_eq!(env.token_balance(holder).await, 1);
+
+    // Alice hands the NFT to Bob
+    env.hand_over_position_token(alice, &bob.keypair.pubkey()).await;
+    env.warp_slots(1).await;
+
+    // Alice no longer acts for the position; Bob closes it and is paid
+    assert!(env.close_position(alice).await.is_err());
+    let close = perps_instruction(
+        env.program_id,
+        &PerpsInstruction::ClosePosition,
+        vec![
+            AccountMeta::new_readonly(bob.keypair.pubkey(), true),
+            AccountMeta::new_readonly(SPL_TOKEN_PROGRAM_ID, false),
+            AccountMeta::new(bob.token_account, false),
+            AccountMeta::new(env.vault, false),
+            AccountMeta::new(position, false),
+            AccountMeta::new(env.market, false),
+            AccountMeta::new_readonly(env.config, false),
+            AccountMeta::new_readonly(env.mint, false),
+            AccountMeta::new_readonly(holder, false),
+        ],
+    );
+    env.send(&[close], &[&bob.keypair]).await.unwrap();
+    assert_eq!(env.position(&alice.keypair.pubkey()).await.collateral, 0);
+    assert!(env.token_balance(bob.token_account).await > 0);
+}
+
+#[tokio::test]
+async fn test_tokenized_position_holder_claims_funding() {
+    let (mut env, traders) = setup(9, &[1_000 * TOKEN, 1_000 * TOKEN, 0]).await;
+    let [alice, carol, bob] = &traders[..] else { unreachable!() };
+    let admin = env.context.payer.insecure_clone();
+    env.initialize_market(&admin, 100 * TOKEN, 9).await.unwrap();
+    env.open_position(carol, 2 * SIZE, 2 * COLLATERAL, 0).await.unwrap();
+    env.open_position(alice, -SIZE, COLLATERAL, 0).await.unwrap();
+    let holder = env.tokenize_position(alice).await;
+    env.hand_over_position_token(alice, &bob.keypair.pubkey()).await;
+
+    // The long-heavy skew pays Alice's short funding, which accrues as claimable
+    let owner = alice.keypair.pubkey();
+    let position = env.position_address(&owner);
+    env.warp_slots(100).await;
+    let update_funding = perps_instruction(
+        env.program_id,
+        &PerpsInstruction::UpdateFunding,
+        vec![
+            AccountMeta::new(env.market, false),
+            AccountMeta::new_readonly(sysvar::clock::id(), false),
+            AccountMeta::new_readonly(env.config, false),
+        ],
+    );
+    let settle_funding = perps_instruction(
+        env.program_id,
+        &PerpsInstruction::SettleFunding,
+        vec![
+            AccountMeta::new(position, false),
+            AccountMeta::new(env.market, false),
+            AccountMeta::new_readonly(env.config, false),
+        ],
+    );
+    env.send(&[update_funding, settle_funding], &[]).await.unwrap();
+    let claimable = env.position(&owner).await.claimable_funding;
+    assert!(claimable > 0);
+
+    let claim = |env: &Env, signer: &Trader, trailing: &[AccountMeta]| {
+        let mut accounts = vec![
+            AccountMeta::new_readonly(signer.keypair.pubkey(), true),
+            AccountMeta::new_readonly(SPL_TOKEN_PROGRAM_ID, false),
+            AccountMeta::new(signer.token_account, false),
+            AccountMeta::new(env.vault, false),
+            AccountMeta::new(position, false),
+            AccountMeta::new(env.market, false),
+            AccountMeta::new_readonly(env.config, false),
+            AccountMeta::new_readonly(env.mint, false),
+        ];
+        accounts.extend_from_slice(trailing);
+        perps_instruction(env.program_id, &PerpsInstruction::ClaimFunding { auto_compound: true }, accounts)
+    };
+
+    // The opening wallet no longer acts for the position; the NFT holder is paid
+    let alice_claim = claim(&env, alice, &[]);
+    assert!(env.send(&[alice_claim], &[&alice.keypair]).await.is_err());
+    let bob_claim = claim(&env, bob, &[AccountMeta::new_readonly(holder, false)]);
+    env.send(&[bob_claim], &[&bob.keypair]).await.unwrap();
+    assert_eq!(env.token_balance(bob.token_account).await, claimable);
+    let short = env.position(&owner).await;
+    assert_eq!((short.claimable_funding, short.auto_compound_funding), (0, 1));
+}
+
+#[tokio::test]
+async fn test_executing_one_of_an_oco_pair_cancels_the_other() {
+    let (mut env, traders) = setup(9, &[1_000 * TOKEN, 0]).await;
+    let [alice, keeper] = &traders[..] else { unreachable!() };
+    let admin = env.context.payer.insecure_clone();
+    env.initialize_market(&admin, 100 * TOKEN, 9).await.unwrap();
+    env.open_position(alice, SIZE, COLLATERAL, 0).await.unwrap();
+
+    let owner = alice.keypair.pubkey();
+    let position = env.position_address(&owner);
+    let queue = Pubkey::find_program_address(&[TRIGGER_QUEUE_SEED, env.market.as_ref()], &env.program_id).0;
+    let trigger = |trigger_id: u64| {
+        Pubkey::find_program_address(&[TRIGGER_SEED, position.as_ref(), &trigger_id.to_le_bytes()], &env.program_id).0
+    };
+    let (stop_loss, take_profit) = (trigger(1), trigger(2));
+
+    // The long lifted the mark price above 100, so the take-profit is already reached
+    let place = |trigger_id: u64, kind: TriggerKind, trigger_price: u64| {
+        perps_instruction(
+            env.program_id,
+            &PerpsInstruction::PlaceTriggerOrder { trigger_id, kind, trigger_price, base_amount: 0 },
+            vec![
+                AccountMeta::new(owner, true),
+                AccountMeta::new_readonly(position, false),
+                AccountMeta::new_readonly(env.market, false),
+                AccountMeta::new(trigger(trigger_id), false),
+                AccountMeta::new_readonly(sysvar::rent::id(), false),
+                AccountMeta::new_readonly(system_program::id(), false),
+                AccountMeta::new(queue, false),
+            ],
+        )
+    };
+    let link = perps_instruction(
+        env.program_id,
+        &PerpsInstruction::LinkTriggerOrders,
+        vec![
+            AccountMeta::new_readonly(owner, true),
+            AccountMeta::new(stop_loss, false),
+            AccountMeta::new(take_profit, false),
+        ],
+    );
+    let instructions = [place(1, TriggerKind::StopLoss, 50 * TOKEN), place(2, TriggerKind::TakeProfit, 100 * TOKEN), link];
+    env.send(&instructions, &[&alice.keypair]).await.unwrap();
+    env.warp_slots(1).await;
+
+    let mut accounts = vec![
+        AccountMeta::new_readonly(keeper.keypair.pubkey(), true),
+        AccountMeta::new_readonly(SPL_TOKEN_PROGRAM_ID, false),
+        AccountMeta::new(keeper.token_account, false),
+        AccountMeta::new(env.vault, false),
+        AccountMeta::new(position, false),
+        AccountMeta::new(env.market, false),
+        AccountMeta::new(take_profit, false),
+        AccountMeta::new(owner, false),
+        AccountMeta::new_readonly(env.config, false),
+        AccountMeta::new_readonly(env.mint, false),
+        AccountMeta::new(queue, false),
+    ];
+    // The stop-loss must come along to be cancelled
+    let execute = perps_instruction(env.program_id, &PerpsInstruction::ExecuteTriggerOrder, accounts.clone());
+    assert!(env.send(&[execute], &[&keeper.keypair]).await.is_err());
+
+    accounts.push(AccountMeta::new(stop_loss, false));
+    let execute = perps_instruction(env.program_id, &PerpsInstruction::ExecuteTriggerOrder, accounts);
+    env.send(&[execute], &[&keeper.keypair]).await.unwrap();
+
+    assert_eq!(env.position(&owner).await.base_amount, 0);
+    assert!(env.context.banks_client.get_account(take_profit).await.unwrap().is_none());
+    assert!(env.context.banks_client.get_account(stop_loss).await.unwrap().is_none());
+    let queue = TriggerQueue::load(&env.account_data(queue).await).unwrap();
+    assert!(queue.entries.is_empty());
+}
+
+#[tokio::test]
+async fn test_orders_of_a_closed_position_account_are_dropped() {
+    let (mut env, traders) = setup(9, &[1_000 * TOKEN, 1_000 * TOKEN, 0]).await;
+    let [alice, bob, keeper] = &traders[..] else { unreachable!() };
+    let admin = env.context.payer.insecure_clone();
+    env.initialize_market(&admin, 100 * TOKEN, 9).await.unwrap();
+    env.open_position(alice, SIZE, COLLATERAL, 0).await.unwrap();
+    env.open_position(bob, -SIZE, COLLATERAL, 0).await.unwrap();
+
+    // Alice leaves an ask and a stop-loss behind, then closes her position and its account
+    let owner = alice.keypair.pubkey();
+    let position = env.position_address(&owner);
+    let queue = Pubkey::find_program_address(&[TRIGGER_QUEUE_SEED, env.market.as_ref()], &env.program_id).0;
+    let stop_loss = Pubkey::find_program_address(&[TRIGGER_SEED, position.as_ref(), &1u64.to_le_bytes()], &env.program_id).0;
+    env.place_order(alice, OrderSide::Ask, 99 * TOKEN, SIZE as u64 / 2, 0, &[]).await.unwrap();
+    let place_trigger = perps_instruction(
+        env.program_id,
+        &PerpsInstruction::PlaceTriggerOrder { trigger_id: 1, kind: TriggerKind::StopLoss, trigger_price: 50 * TOKEN, base_amount: 0 },
+        vec![
+            AccountMeta::new(owner, true),
+            AccountMeta::new_readonly(position, false),
+            AccountMeta::new_readonly(env.market, false),
+            AccountMeta::new(stop_loss, false),
+            AccountMeta::new_readonly(sysvar::rent::id(), false),
+            AccountMeta::new_readonly(system_program::id(), false),
+            AccountMeta::new(queue, false),
+        ],
+    );
+    env.send(&[place_trigger], &[&alice.keypair]).await.unwrap();
+    env.warp_slots(1).await;
+    env.close_position(alice).await.unwrap();
+    let close_account = perps_instruction(
+        env.program_id,
+        &PerpsInstruction::ClosePositionAccount,
+        vec![AccountMeta::new(owner, true), AccountMeta::new(position, false)],
+    );
+    env.send(&[close_account], &[&alice.keypair]).await.unwrap();
+
+    // Bob's IOC bid drops the orphaned ask instead of stopping at it
+    env.place_order(bob, OrderSide::Bid, 101 * TOKEN, SIZE as u64, ORDER_FLAG_IMMEDIATE_OR_CANCEL, &[alice])
+        .await
+        .unwrap();
+    assert_eq!(env.position(&bob.keypair.pubkey()).await.base_amount, -SIZE);
+    let orderbook = env.orderbook();
+    assert!(OrderBook::load(&env.account_data(orderbook).await).unwrap().orders.is_empty());
+
+    // A keeper clears the stop-loss out of the queue, unpaid
+    let execute = perps_instruction(
+        env.program_id,
+        &PerpsInstruction::ExecuteTriggerOrder,
+        vec![
+            AccountMeta::new_readonly(keeper.keypair.pubkey(), true),
+            AccountMeta::new_readonly(SPL_TOKEN_PROGRAM_ID, false),
+            AccountMeta::new(keeper.token_account, false),
+            AccountMeta::new(env.vault, false),
+            AccountMeta::new(position, false),
+            AccountMeta::new(env.market, false),
+            AccountMeta::new(stop_loss, false),
+            AccountMeta::new(owner, false),
+            AccountMeta::new_readonly(env.config, false),
+            AccountMeta::new_readonly(env.mint, false),
+            AccountMeta::new(queue, false),
+        ],
+    );
+    env.send(&[execute], &[&keeper.keypair]).await.unwrap();
+    assert!(env.context.banks_client.get_account(stop_loss).await.unwrap().is_none());
+    assert!(TriggerQueue::load(&env.account_data(queue).await).unwrap().entries.is_empty());
+    assert_eq!(env.token_balance(keeper.token_account).await, 0);
+}
+
+#[tokio::test]
+async fn test_migrate_account_grows_older_layouts_in_place() {
+    let owner = Pubkey::new_unique();
+    let mut old_accounts = vec![];
+    let (mut env, _) = setup_with(9, &[], |program_test, program_id, _| {
+        let (market, _) = market_address(program_id, 0);
+        let (position, bump) = position_address(program_id, &market, &owner, 0);
+
+        // A v12 position: the current layout without the trailing position_mint
+        let old_position = Position { owner, collateral: 7 * TOKEN, version: 12, bump, ..Position::default() };
+        let mut data = Position::DISCRIMINATOR.to_vec();
+        data.extend_from_slice(&bytemuck::bytes_of(&old_position)[..Position::LEN - 32]);
+        program_test.add_account(position, Account { lamports: TOKEN, data, owner: *program_id, ..Account::default() });
+
+        // A trigger order placed before one-cancels-other links
+        let trigger_id = 1u64;
+        let (trigger, bump) =
+            Pubkey::find_program_address(&[TRIGGER_SEED, position.as_ref(), &trigger_id.to_le_bytes()], program_id);
+        let old_trigger = TriggerOrder {
+            owner,
+            position,
+            market,
+            trigger_id,
+            kind: TriggerKind::StopLoss,
+            trigger_price: 90 * TOKEN,
+            base_amount: 0,
+            bump,
+            linked_trigger: Pubkey::default(),
+        };
+        let mut data = TriggerOrder::DISCRIMINATOR.to_vec();
+        data.extend_from_slice(&old_trigger.try_to_vec().unwrap()[..TriggerOrder::LEN - 32]);
+        program_test.add_account(trigger, Account { lamports: TOKEN / 1_000, data, owner: *program_id, ..Account::default() });
+
+        old_accounts = vec![(position, Position::SPACE), (trigger, TriggerOrder::SPACE)];
+    })
+    .await;
+
+    let payer = env.context.payer.pubkey();
+    let rent = env.context.banks_client.get_rent().await.unwrap();
+    for (address, space) in old_accounts {
+        let migrate = perps_instruction(
+            env.program_id,
+            &PerpsInstruction::MigrateAccount,
+            vec![
+                AccountMeta::new(payer, true),
+                AccountMeta::new(address, false),
+                AccountMeta::new_readonly(sysvar::rent::id(), false),
+                AccountMeta::new_readonly(system_program::id(), false),
+            ],
+        );
+        env.send(&[migrate], &[]).await.unwrap();
+
+        // Reallocated to the current size, with the payer topping the rent up
+        let account = env.context.banks_client.get_account(address).await.unwrap().unwrap();
+        assert_eq!(account.data.len(), space);
+        assert!(rent.is_exempt(account.lamports, space));
+    }
+
+    let position = env.position(&owner).await;
+    assert_eq!((position.version, position.collateral), (Position::VERSION, 7 * TOKEN));
+    assert!(!position.is_tokenized());
+    assert_eq!(position.deposited_collateral, 7 * TOKEN, "older positions count the collateral they hold");
+}
+
+#[tokio::test]
+async fn test_only_the_upgrade_authority_initializes_the_config() {
+    let program_id = Pubkey::new_unique();
+    let mut program_test = ProgramTest::new("simple_perps", program_id, processor!(simple_perps::process_instruction));
+    program_test.prefer_bpf(false);
+    let mint = Pubkey::new_unique();
+    program_test.add_account(mint, mint_account(0, 9));
+    let deployer = add_trader(&mut program_test, &mint, 0);
+    let squatter = add_trader(&mut program_test, &mint, 0);
+    let (program_data, _) = Pubkey::find_program_address(&[program_id.as_ref()], &bpf_loader_upgradeable::id());
+    program_test.add_account(program_data, program_data_account(&deployer.keypair.pubkey()));
+    // A ProgramData lookalike at another address, naming the squatter
+    let lookalike = Pubkey::new_unique();
+    program_test.add_account(lookalike, program_data_account(&squatter.keypair.pubkey()));
+
+    let context = program_test.start_with_context().await;
+    let (config, _) = Pubkey::find_program_address(&[CONFIG_SEED], &program_id);
+    let (market, _) = market_address(&program_id, 0);
+    let (vault, _) = Pubkey::find_program_address(&[PDA_SEED], &program_id);
+    let mut env = Env { context, program_id, mint, vault, config, market };
+
+    // Anyone else racing the deployment is rejected, even with a lookalike ProgramData account
+    let mut front_run = initialize_config_instruction(program_id, squatter.keypair.pubkey(), mint, None);
+    assert!(env.send(&[front_run.clone()], &[&squatter.keypair]).await.is_err());
+    front_run.accounts[5].pubkey = lookalike;
+    assert!(env.send(&[front_run], &[&squatter.keypair]).await.is_err());
+
+    let deployer_key = deployer.keypair.pubkey();
+    env.send(&[initialize_config_instruction(program_id, deployer_key, mint, None)], &[&deployer.keypair]).await.unwrap();
+    let config_data = env.account_data(config).await;
+    assert_eq!(Config::deserialize(&mut &config_data[DISCRIMINATOR_LEN..]).unwrap().admin, deployer_key);
+}